@@ -32,4 +32,10 @@ cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --fe
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f732ze,exti,time-driver-any,exti
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f769ni,exti,time-driver-any,exti
 
+cargo test --manifest-path ./embassy-net/Cargo.toml --features tcp,udp,dns,dhcpv4,proto-ipv4,proto-ipv6,medium-ethernet,medium-ip,multicast,stats,pcap,filter,mdns-responder,mqtt,http-server,websocket,slaac,dhcpv6,autoip,tls
+cargo test --manifest-path ./embassy-net-driver-channel/Cargo.toml
 cargo test --manifest-path ./embassy-net-adin1110/Cargo.toml
+cargo test --manifest-path ./embassy-net-modem/Cargo.toml
+
+cargo test --manifest-path ./embassy-usb-sim/Cargo.toml --features usbip
+cargo test --manifest-path ./embassy-usb-host/Cargo.toml
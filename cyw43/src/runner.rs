@@ -539,7 +539,10 @@ where
                         buf[..packet.len()].copy_from_slice(packet);
                         self.ch.rx_done(packet.len())
                     }
-                    None => warn!("failed to push rxd packet to the channel."),
+                    None => {
+                        warn!("failed to push rxd packet to the channel.");
+                        self.ch.rx_drop();
+                    }
                 }
             }
             _ => {}
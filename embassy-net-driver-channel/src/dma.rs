@@ -0,0 +1,537 @@
+//! Zero-copy channel over packet buffers lent by the driver.
+//!
+//! With the channel from the crate root, the driver copies every received packet into a channel
+//! buffer, and every packet to send out of one. With this channel, the driver lends its own buffers
+//! instead, typically those of its DMA descriptor ring, so packets are never copied between the
+//! driver and the stack:
+//!
+//! - Received packets are passed up with [`RxRunner::rx_push()`], and read in place by the stack.
+//!   Their buffers are handed back by [`RxRunner::rx_reclaim()`], so the driver can give them back
+//!   to the DMA.
+//! - Empty buffers are lent for transmission with [`TxRunner::tx_lend()`], and the stack writes packets
+//!   directly into them. [`TxRunner::tx_reclaim()`] returns them with the length of the packet, ready
+//!   to be handed to the DMA.
+//!
+//! ```rust,ignore
+//! // Give all TX descriptors to the stack, then:
+//! loop {
+//!     match select3(rx.rx_reclaim(), tx.tx_reclaim(), dma.rx_complete()).await {
+//!         Either3::First(buf) => dma.rx_arm(buf),
+//!         Either3::Second((buf, len)) => dma.tx_start(buf, len),
+//!         Either3::Third((buf, len)) => unwrap!(rx.rx_push(buf, len)),
+//!     }
+//!     // Lend TX buffers back once the DMA has sent them.
+//!     while let Some(buf) = dma.tx_completed() {
+//!         unwrap!(tx.tx_lend(buf));
+//!     }
+//! }
+//! ```
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::{Context, Poll};
+
+use embassy_net_driver::{Capabilities, LinkState};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use heapless::Deque;
+
+use crate::{driver, Shared, StateRunner};
+
+/// Channel state.
+///
+/// `N_RX` and `N_TX` are the maximum numbers of buffers lent at once for RX and TX.
+pub struct State<'d, const N_RX: usize, const N_TX: usize> {
+    queues: Mutex<NoopRawMutex, RefCell<Queues<'d, N_RX, N_TX>>>,
+    shared: MaybeUninit<Mutex<NoopRawMutex, RefCell<Shared>>>,
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> State<'d, N_RX, N_TX> {
+    /// Create a new channel state.
+    // Not `const`: the queues hold mutable references, which aren't allowed in const functions
+    // before Rust 1.83.
+    pub fn new() -> Self {
+        Self {
+            queues: Mutex::new(RefCell::new(Queues {
+                rx_full: Deque::new(),
+                rx_empty: Deque::new(),
+                tx_empty: Deque::new(),
+                tx_full: Deque::new(),
+                rx_waker: WakerRegistration::new(),
+                tx_waker: WakerRegistration::new(),
+                stack_waker: WakerRegistration::new(),
+            })),
+            shared: MaybeUninit::uninit(),
+        }
+    }
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> Default for State<'d, N_RX, N_TX> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Queues<'d, const N_RX: usize, const N_TX: usize> {
+    /// Received packets, waiting for the stack.
+    rx_full: Deque<(&'d mut [u8], usize), N_RX>,
+    /// Buffers of received packets processed by the stack, waiting for the driver.
+    rx_empty: Deque<&'d mut [u8], N_RX>,
+    /// Buffers lent for transmission, waiting for the stack.
+    tx_empty: Deque<&'d mut [u8], N_TX>,
+    /// Packets written by the stack, waiting for the driver.
+    tx_full: Deque<(&'d mut [u8], usize), N_TX>,
+    /// Driver waiting for `rx_empty`.
+    rx_waker: WakerRegistration,
+    /// Driver waiting for `tx_full`.
+    tx_waker: WakerRegistration,
+    /// Stack waiting for `rx_full` or `tx_empty`.
+    stack_waker: WakerRegistration,
+}
+
+type QueuesRef<'d, const N_RX: usize, const N_TX: usize> = &'d Mutex<NoopRawMutex, RefCell<Queues<'d, N_RX, N_TX>>>;
+
+/// Channel runner.
+///
+/// Holds the shared state and the driver end of the queues of lent buffers.
+pub struct Runner<'d, const N_RX: usize, const N_TX: usize> {
+    state: StateRunner<'d>,
+    rx: RxRunner<'d, N_RX, N_TX>,
+    tx: TxRunner<'d, N_RX, N_TX>,
+}
+
+/// RX runner.
+///
+/// Lends buffers of received packets to the stack.
+pub struct RxRunner<'d, const N_RX: usize, const N_TX: usize> {
+    queues: QueuesRef<'d, N_RX, N_TX>,
+}
+
+/// TX runner.
+///
+/// Lends buffers for packets to transmit to the stack.
+pub struct TxRunner<'d, const N_RX: usize, const N_TX: usize> {
+    queues: QueuesRef<'d, N_RX, N_TX>,
+    mtu: usize,
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> Runner<'d, N_RX, N_TX> {
+    /// Split the runner into separate runners for controlling state, rx and tx.
+    pub fn split(self) -> (StateRunner<'d>, RxRunner<'d, N_RX, N_TX>, TxRunner<'d, N_RX, N_TX>) {
+        (self.state, self.rx, self.tx)
+    }
+
+    /// Create a state runner sharing the state channel.
+    pub fn state_runner(&self) -> StateRunner<'d> {
+        self.state
+    }
+
+    /// Set the link state.
+    pub fn set_link_state(&mut self, state: LinkState) {
+        self.state.set_link_state(state)
+    }
+
+    /// Set the hardware address.
+    pub fn set_hardware_address(&mut self, address: driver::HardwareAddress) {
+        self.state.set_hardware_address(address)
+    }
+
+    /// Pass a received packet of `len` bytes up the stack, lending its buffer.
+    ///
+    /// Returns the buffer back if `N_RX` buffers are already lent.
+    pub fn rx_push(&mut self, buf: &'d mut [u8], len: usize) -> Result<(), &'d mut [u8]> {
+        self.rx.rx_push(buf, len)
+    }
+
+    /// Wait until the stack is done with a received packet, and return its buffer.
+    pub async fn rx_reclaim(&mut self) -> &'d mut [u8] {
+        self.rx.rx_reclaim().await
+    }
+
+    /// Return the buffer of a received packet the stack is done with, if any.
+    pub fn try_rx_reclaim(&mut self) -> Option<&'d mut [u8]> {
+        self.rx.try_rx_reclaim()
+    }
+
+    /// Poll for the buffer of a received packet the stack is done with.
+    pub fn poll_rx_reclaim(&mut self, cx: &mut Context) -> Poll<&'d mut [u8]> {
+        self.rx.poll_rx_reclaim(cx)
+    }
+
+    /// Lend an empty buffer to the stack, to write a packet to transmit in.
+    ///
+    /// Returns the buffer back if `N_TX` buffers are already lent. Panics if the buffer is smaller than the MTU.
+    pub fn tx_lend(&mut self, buf: &'d mut [u8]) -> Result<(), &'d mut [u8]> {
+        self.tx.tx_lend(buf)
+    }
+
+    /// Wait until the stack has written a packet to transmit, and return its buffer and length.
+    pub async fn tx_reclaim(&mut self) -> (&'d mut [u8], usize) {
+        self.tx.tx_reclaim().await
+    }
+
+    /// Return the buffer and length of a packet to transmit, if any.
+    pub fn try_tx_reclaim(&mut self) -> Option<(&'d mut [u8], usize)> {
+        self.tx.try_tx_reclaim()
+    }
+
+    /// Poll for the buffer and length of a packet to transmit.
+    pub fn poll_tx_reclaim(&mut self, cx: &mut Context) -> Poll<(&'d mut [u8], usize)> {
+        self.tx.poll_tx_reclaim(cx)
+    }
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> RxRunner<'d, N_RX, N_TX> {
+    /// Pass a received packet of `len` bytes up the stack, lending its buffer.
+    ///
+    /// Returns the buffer back if `N_RX` buffers are already lent.
+    pub fn rx_push(&mut self, buf: &'d mut [u8], len: usize) -> Result<(), &'d mut [u8]> {
+        assert!(len <= buf.len());
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            if q.rx_full.len() + q.rx_empty.len() == N_RX {
+                return Err(buf);
+            }
+            // NOTE(unwrap): checked above that there's room.
+            unwrap!(q.rx_full.push_back((buf, len)).ok());
+            q.stack_waker.wake();
+            Ok(())
+        })
+    }
+
+    /// Wait until the stack is done with a received packet, and return its buffer.
+    pub async fn rx_reclaim(&mut self) -> &'d mut [u8] {
+        poll_fn(|cx| self.poll_rx_reclaim(cx)).await
+    }
+
+    /// Return the buffer of a received packet the stack is done with, if any.
+    pub fn try_rx_reclaim(&mut self) -> Option<&'d mut [u8]> {
+        self.queues.lock(|q| q.borrow_mut().rx_empty.pop_front())
+    }
+
+    /// Poll for the buffer of a received packet the stack is done with.
+    pub fn poll_rx_reclaim(&mut self, cx: &mut Context) -> Poll<&'d mut [u8]> {
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            match q.rx_empty.pop_front() {
+                Some(buf) => Poll::Ready(buf),
+                None => {
+                    q.rx_waker.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> TxRunner<'d, N_RX, N_TX> {
+    /// Lend an empty buffer to the stack, to write a packet to transmit in.
+    ///
+    /// Returns the buffer back if `N_TX` buffers are already lent. Panics if the buffer is smaller than the MTU.
+    pub fn tx_lend(&mut self, buf: &'d mut [u8]) -> Result<(), &'d mut [u8]> {
+        assert!(buf.len() >= self.mtu, "tx buffer smaller than the MTU");
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            if q.tx_empty.len() + q.tx_full.len() == N_TX {
+                return Err(buf);
+            }
+            // NOTE(unwrap): checked above that there's room.
+            unwrap!(q.tx_empty.push_back(buf).ok());
+            q.stack_waker.wake();
+            Ok(())
+        })
+    }
+
+    /// Wait until the stack has written a packet to transmit, and return its buffer and length.
+    pub async fn tx_reclaim(&mut self) -> (&'d mut [u8], usize) {
+        poll_fn(|cx| self.poll_tx_reclaim(cx)).await
+    }
+
+    /// Return the buffer and length of a packet to transmit, if any.
+    pub fn try_tx_reclaim(&mut self) -> Option<(&'d mut [u8], usize)> {
+        self.queues.lock(|q| q.borrow_mut().tx_full.pop_front())
+    }
+
+    /// Poll for the buffer and length of a packet to transmit.
+    pub fn poll_tx_reclaim(&mut self, cx: &mut Context) -> Poll<(&'d mut [u8], usize)> {
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            match q.tx_full.pop_front() {
+                Some(p) => Poll::Ready(p),
+                None => {
+                    q.tx_waker.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+/// Create a channel.
+///
+/// Returns a pair of handles for interfacing with the peripheral and the networking stack. Buffers
+/// lent for transmission must be at least `mtu` bytes.
+pub fn new<'d, const N_RX: usize, const N_TX: usize>(
+    state: &'d mut State<'d, N_RX, N_TX>,
+    mtu: usize,
+    hardware_address: driver::HardwareAddress,
+) -> (Runner<'d, N_RX, N_TX>, Device<'d, N_RX, N_TX>) {
+    let mut caps = Capabilities::default();
+    caps.max_transmission_unit = mtu;
+
+    let State { queues, shared } = state;
+    let queues = &*queues;
+    let shared = &*shared.write(Mutex::new(RefCell::new(Shared {
+        link_state: LinkState::Down,
+        hardware_address,
+        waker: WakerRegistration::new(),
+        rx_dropped: 0,
+    })));
+
+    (
+        Runner {
+            state: StateRunner { shared },
+            rx: RxRunner { queues },
+            tx: TxRunner { queues, mtu },
+        },
+        Device { queues, shared, caps },
+    )
+}
+
+/// Channel device.
+///
+/// Holds the shared state and the stack end of the queues of lent buffers.
+pub struct Device<'d, const N_RX: usize, const N_TX: usize> {
+    queues: QueuesRef<'d, N_RX, N_TX>,
+    shared: &'d Mutex<NoopRawMutex, RefCell<Shared>>,
+    caps: Capabilities,
+}
+
+impl<'d, const N_RX: usize, const N_TX: usize> embassy_net_driver::Driver for Device<'d, N_RX, N_TX> {
+    type RxToken<'a>
+        = RxToken<'d, N_RX, N_TX>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'d, N_RX, N_TX>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let ready = self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            let ready = !q.rx_full.is_empty() && !q.tx_empty.is_empty();
+            if !ready {
+                q.stack_waker.register(cx.waker());
+            }
+            ready
+        });
+        ready.then_some((RxToken { queues: self.queues }, TxToken { queues: self.queues }))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        let ready = self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            let ready = !q.tx_empty.is_empty();
+            if !ready {
+                q.stack_waker.register(cx.waker());
+            }
+            ready
+        });
+        ready.then_some(TxToken { queues: self.queues })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.caps.clone()
+    }
+
+    fn hardware_address(&self) -> driver::HardwareAddress {
+        self.shared.lock(|s| s.borrow().hardware_address)
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.waker.register(cx.waker());
+            s.link_state
+        })
+    }
+}
+
+/// A rx token.
+///
+/// Reads a received packet in place, in the buffer lent by the driver.
+pub struct RxToken<'a, const N_RX: usize, const N_TX: usize> {
+    queues: QueuesRef<'a, N_RX, N_TX>,
+}
+
+impl<'a, const N_RX: usize, const N_TX: usize> embassy_net_driver::RxToken for RxToken<'a, N_RX, N_TX> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // NOTE(unwrap): we checked the queue wasn't empty when creating the token.
+        let (buf, len) = unwrap!(self.queues.lock(|q| q.borrow_mut().rx_full.pop_front()));
+        let r = f(&mut buf[..len]);
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            // NOTE(unwrap): the buffer was counted in `rx_full`, so there's room.
+            unwrap!(q.rx_empty.push_back(buf).ok());
+            q.rx_waker.wake();
+        });
+        r
+    }
+}
+
+/// A tx token.
+///
+/// Writes a packet to transmit in place, in a buffer lent by the driver.
+pub struct TxToken<'a, const N_RX: usize, const N_TX: usize> {
+    queues: QueuesRef<'a, N_RX, N_TX>,
+}
+
+impl<'a, const N_RX: usize, const N_TX: usize> embassy_net_driver::TxToken for TxToken<'a, N_RX, N_TX> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // NOTE(unwrap): we checked the queue wasn't empty when creating the token.
+        let buf = unwrap!(self.queues.lock(|q| q.borrow_mut().tx_empty.pop_front()));
+        let r = f(&mut buf[..len]);
+        self.queues.lock(|q| {
+            let q = &mut *q.borrow_mut();
+            // NOTE(unwrap): the buffer was counted in `tx_empty`, so there's room.
+            unwrap!(q.tx_full.push_back((buf, len)).ok());
+            q.tx_waker.wake();
+        });
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::ptr;
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+    use std::boxed::Box;
+
+    use embassy_net_driver::{Driver, RxToken, TxToken};
+
+    use super::*;
+
+    const MTU: usize = 64;
+    const HARDWARE_ADDRESS: driver::HardwareAddress = driver::HardwareAddress::Ethernet([2, 0, 0, 0, 0, 1]);
+
+    static VTABLE: RawWakerVTable =
+        RawWakerVTable::new(|_| RawWaker::new(ptr::null(), &VTABLE), |_| {}, |_| {}, |_| {});
+
+    // The queues drop their references, so the state can't outlive the buffers: leak both.
+    fn leak<T>(x: T) -> &'static mut T {
+        Box::leak(Box::new(x))
+    }
+
+    fn noop_waker() -> Waker {
+        unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn rx_push_consume_reclaim() {
+        let rx_buf = leak([0; MTU]);
+        let tx_buf = leak([0; MTU]);
+        let state = leak(State::<1, 1>::new());
+        let (mut runner, mut device) = new(state, MTU, HARDWARE_ADDRESS);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // Receiving needs a received packet, and a buffer for the answer.
+        rx_buf[..3].copy_from_slice(&[1, 2, 3]);
+        runner.rx_push(rx_buf, 3).unwrap();
+        assert!(device.receive(&mut cx).is_none());
+        runner.tx_lend(tx_buf).unwrap();
+
+        let (rx, _tx) = device.receive(&mut cx).unwrap();
+        assert!(runner.try_rx_reclaim().is_none());
+        rx.consume(|packet| assert_eq!(packet, [1, 2, 3]));
+
+        // The packet was read in place, and its buffer is handed back.
+        let buf = runner.try_rx_reclaim().unwrap();
+        assert_eq!(buf.len(), MTU);
+        assert!(runner.try_rx_reclaim().is_none());
+    }
+
+    #[test]
+    fn tx_lend_write_reclaim() {
+        let tx_bufs = leak([[0; MTU]; 2]);
+        let state = leak(State::<1, 2>::new());
+        let (mut runner, mut device) = new(state, MTU, HARDWARE_ADDRESS);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(device.transmit(&mut cx).is_none());
+        let [a, b] = tx_bufs;
+        runner.tx_lend(a).unwrap();
+        runner.tx_lend(b).unwrap();
+
+        for packet in [&[1, 2][..], &[3, 4, 5]] {
+            let tx = device.transmit(&mut cx).unwrap();
+            tx.consume(packet.len(), |buf| buf.copy_from_slice(packet));
+        }
+        assert!(device.transmit(&mut cx).is_none());
+
+        // Packets are handed to the driver in order, in the lent buffers.
+        let (buf, len) = runner.try_tx_reclaim().unwrap();
+        assert_eq!(&buf[..len], [1, 2]);
+        let (buf, len) = embassy_futures::block_on(runner.tx_reclaim());
+        assert_eq!(&buf[..len], [3, 4, 5]);
+        assert!(runner.try_tx_reclaim().is_none());
+
+        // Once sent, the buffer can be lent again.
+        runner.tx_lend(buf).unwrap();
+        assert!(device.transmit(&mut cx).is_some());
+    }
+
+    #[test]
+    fn queues_full() {
+        let rx_bufs = leak([[0; MTU]; 3]);
+        let tx_bufs = leak([[0; MTU]; 3]);
+        let state = leak(State::<2, 2>::new());
+        let (mut runner, mut device) = new(state, MTU, HARDWARE_ADDRESS);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // At most N buffers are lent at once, and the extra ones are given back.
+        let [rx_a, rx_b, rx_c] = rx_bufs;
+        runner.rx_push(rx_a, 1).unwrap();
+        runner.rx_push(rx_b, 1).unwrap();
+        let rx_c = runner.rx_push(rx_c, 1).unwrap_err();
+        let [tx_a, tx_b, tx_c] = tx_bufs;
+        runner.tx_lend(tx_a).unwrap();
+        runner.tx_lend(tx_b).unwrap();
+        let tx_c = runner.tx_lend(tx_c).unwrap_err();
+
+        // Buffers still count until the driver reclaims them.
+        let (rx, _) = device.receive(&mut cx).unwrap();
+        rx.consume(|_| ());
+        let rx_c = runner.rx_push(rx_c, 1).unwrap_err();
+        runner.try_rx_reclaim().unwrap();
+        runner.rx_push(rx_c, 1).unwrap();
+
+        let tx = device.transmit(&mut cx).unwrap();
+        tx.consume(1, |_| ());
+        let tx_c = runner.tx_lend(tx_c).unwrap_err();
+        runner.try_tx_reclaim().unwrap();
+        runner.tx_lend(tx_c).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn tx_lend_smaller_than_mtu() {
+        let tx_buf = leak([0; MTU - 1]);
+        let state = leak(State::<1, 1>::new());
+        let (mut runner, _device) = new(state, MTU, HARDWARE_ADDRESS);
+        let _ = runner.tx_lend(tx_buf);
+    }
+}
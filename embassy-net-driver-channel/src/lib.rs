@@ -5,6 +5,8 @@
 // must go first!
 mod fmt;
 
+pub mod dma;
+
 use core::cell::RefCell;
 use core::mem::MaybeUninit;
 use core::task::{Context, Poll};
@@ -48,6 +50,7 @@ struct Shared {
     link_state: LinkState,
     waker: WakerRegistration,
     hardware_address: driver::HardwareAddress,
+    rx_dropped: u64,
 }
 
 /// Channel runner.
@@ -72,6 +75,7 @@ pub struct StateRunner<'d> {
 /// Holds the lower end of the channel for passing inbound packets up the stack.
 pub struct RxRunner<'d, const MTU: usize> {
     rx_chan: zerocopy_channel::Sender<'d, NoopRawMutex, PacketBuf<MTU>>,
+    shared: &'d Mutex<NoopRawMutex, RefCell<Shared>>,
 }
 
 /// TX runner.
@@ -86,7 +90,10 @@ impl<'d, const MTU: usize> Runner<'d, MTU> {
     pub fn split(self) -> (StateRunner<'d>, RxRunner<'d, MTU>, TxRunner<'d, MTU>) {
         (
             StateRunner { shared: self.shared },
-            RxRunner { rx_chan: self.rx_chan },
+            RxRunner {
+                rx_chan: self.rx_chan,
+                shared: self.shared,
+            },
             TxRunner { tx_chan: self.tx_chan },
         )
     }
@@ -97,6 +104,7 @@ impl<'d, const MTU: usize> Runner<'d, MTU> {
             StateRunner { shared: self.shared },
             RxRunner {
                 rx_chan: self.rx_chan.borrow(),
+                shared: self.shared,
             },
             TxRunner {
                 tx_chan: self.tx_chan.borrow(),
@@ -154,6 +162,16 @@ impl<'d, const MTU: usize> Runner<'d, MTU> {
         self.rx_chan.send_done();
     }
 
+    /// Mark an inbound packet as dropped because there was no space for it in the channel.
+    ///
+    /// The stack reports the number of dropped packets in its statistics.
+    pub fn rx_drop(&mut self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.rx_dropped = s.rx_dropped.wrapping_add(1);
+        });
+    }
+
     /// Wait until there is space for more outbound packets and return a slice they can be copied into.
     pub async fn tx_buf(&mut self) -> &mut [u8] {
         let p = self.tx_chan.receive().await;
@@ -227,6 +245,16 @@ impl<'d, const MTU: usize> RxRunner<'d, MTU> {
         p.len = len;
         self.rx_chan.send_done();
     }
+
+    /// Mark an inbound packet as dropped because there was no space for it in the channel.
+    ///
+    /// The stack reports the number of dropped packets in its statistics.
+    pub fn rx_drop(&mut self) {
+        self.shared.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            s.rx_dropped = s.rx_dropped.wrapping_add(1);
+        });
+    }
 }
 
 impl<'d, const MTU: usize> TxRunner<'d, MTU> {
@@ -281,6 +309,7 @@ pub fn new<'d, const MTU: usize, const N_RX: usize, const N_TX: usize>(
             link_state: LinkState::Down,
             hardware_address,
             waker: WakerRegistration::new(),
+            rx_dropped: 0,
         })),
     });
 
@@ -368,6 +397,10 @@ impl<'d, const MTU: usize> embassy_net_driver::Driver for Device<'d, MTU> {
             s.link_state
         })
     }
+
+    fn rx_dropped(&self) -> u64 {
+        self.shared.lock(|s| s.borrow().rx_dropped)
+    }
 }
 
 /// A rx token.
@@ -80,6 +80,14 @@ pub trait Driver {
     /// what kind of packet the sent/received bytes are, and determines some behaviors of
     /// the interface. For example, ARP/NDISC address resolution is only done for Ethernet mediums.
     fn hardware_address(&self) -> HardwareAddress;
+
+    /// Get the number of received packets the device dropped because it had no free receive buffer.
+    ///
+    /// The count starts at zero and wraps around on overflow. Devices that don't keep track of
+    /// dropped packets return 0, which is the default.
+    fn rx_dropped(&self) -> u64 {
+        0
+    }
 }
 
 impl<T: ?Sized + Driver> Driver for &mut T {
@@ -107,6 +115,9 @@ impl<T: ?Sized + Driver> Driver for &mut T {
     fn hardware_address(&self) -> HardwareAddress {
         T::hardware_address(self)
     }
+    fn rx_dropped(&self) -> u64 {
+        T::rx_dropped(self)
+    }
 }
 
 /// A token to receive a single network packet.
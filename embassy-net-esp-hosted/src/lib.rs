@@ -289,7 +289,10 @@ where
                     buf[..payload.len()].copy_from_slice(payload);
                     self.ch.rx_done(payload.len())
                 }
-                None => warn!("failed to push rxd packet to the channel."),
+                None => {
+                    warn!("failed to push rxd packet to the channel.");
+                    self.ch.rx_drop();
+                }
             },
             // serial
             2 => {
@@ -0,0 +1,514 @@
+//! AT command client.
+//!
+//! The client sends commands and collects their response, sorting the received lines into
+//! information lines, final result codes and unsolicited result codes (URCs). URCs received
+//! while a command is pending are queued, and can be read afterwards with
+//! [`AtClient::next_urc()`].
+
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{BufRead, Error as _, ErrorKind, Write};
+use heapless::{Deque, Vec};
+
+/// Guard time around the `+++` escape sequence.
+const ESCAPE_GUARD_TIME: Duration = Duration::from_secs(1);
+
+/// Error returned by the AT command client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No final result code was received in time.
+    Timeout,
+    /// The modem answered `ERROR`.
+    Error,
+    /// The modem answered `+CME ERROR: <n>`.
+    CmeError(u16),
+    /// The modem answered `+CMS ERROR: <n>`.
+    CmsError(u16),
+    /// The modem answered `NO CARRIER`.
+    NoCarrier,
+    /// The modem answered `BUSY`.
+    Busy,
+    /// The modem answered `NO ANSWER`.
+    NoAnswer,
+    /// The modem answered `NO DIALTONE`.
+    NoDialtone,
+    /// The modem answered `CONNECT` to a command sent with [`AtClient::send()`], or `OK`
+    /// to a command sent with [`AtClient::dial()`].
+    Unexpected,
+    /// The response didn't fit in the client's buffer.
+    BufferTooSmall,
+    /// Reading from or writing to the serial port failed.
+    Io(ErrorKind),
+}
+
+/// A command, with the time to wait for its final result code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Command<'a> {
+    text: &'a str,
+    timeout: Duration,
+}
+
+impl<'a> Command<'a> {
+    /// Timeout used by [`Command::new()`].
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Create a command, e.g. `AT+CSQ`. The terminating carriage return is added when sending it.
+    pub const fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            timeout: Self::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Set the time to wait for the final result code.
+    ///
+    /// Network related commands, such as `AT+COPS` or dialing, can take minutes to complete.
+    pub const fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Prefix of the information lines answering this command, e.g. `+CSQ` for `AT+CSQ`.
+    fn response_prefix(&self) -> Option<&'a str> {
+        let name = self.text.strip_prefix("AT")?;
+        if !name.starts_with('+') {
+            return None;
+        }
+        let end = name.find(['=', '?']).unwrap_or(name.len());
+        Some(&name[..end])
+    }
+}
+
+impl<'a> From<&'a str> for Command<'a> {
+    fn from(text: &'a str) -> Self {
+        Self::new(text)
+    }
+}
+
+/// AT command client configuration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config<'a> {
+    /// Prefixes of the unsolicited result codes sent by the modem, e.g. `+CREG:` or `RING`.
+    ///
+    /// A line matching one of these is only part of a command's response if it starts with
+    /// the command's name, e.g. `+CREG: 0,1` answering `AT+CREG?`.
+    pub urc_prefixes: &'a [&'a str],
+}
+
+/// Information lines of a successful command's response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response<'a> {
+    text: &'a str,
+}
+
+impl<'a> Response<'a> {
+    /// The information lines, in the order they were received. Empty lines and the command
+    /// echo are not included.
+    pub fn lines(&self) -> impl Iterator<Item = &'a str> {
+        self.text.lines()
+    }
+
+    /// The first line starting with `prefix`, without the prefix and surrounding whitespace.
+    ///
+    /// For example `line("+CSQ:")` returns `"20,99"` for the line `+CSQ: 20,99`.
+    pub fn line(&self, prefix: &str) -> Option<&'a str> {
+        self.lines().find_map(|l| l.strip_prefix(prefix)).map(str::trim)
+    }
+
+    /// The information lines, separated by `\n`.
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+}
+
+/// Final result code of a command.
+enum Final {
+    Ok,
+    Connect,
+}
+
+/// Async AT command client over a serial port.
+///
+/// `N` is the size of the buffers holding a received line, a command's response, and the
+/// queued URCs.
+///
+/// The client reads from the port exactly up to the end of each line, so that after
+/// [`dial()`](Self::dial) the port can be taken back with [`into_inner()`](Self::into_inner)
+/// and handed to a PPP runner without losing any data. The same goes for entering CMUX mode.
+pub struct AtClient<'a, IO, const N: usize = 256> {
+    io: IO,
+    config: Config<'a>,
+    /// The line being received.
+    line: Vec<u8, N>,
+    line_overflow: bool,
+    /// Response to the last command, or the last URC returned.
+    response: Vec<u8, N>,
+    /// Queued URCs, each terminated by `\n`.
+    urcs: Deque<u8, N>,
+}
+
+impl<'a, IO: BufRead + Write, const N: usize> AtClient<'a, IO, N> {
+    /// Create a new client.
+    pub fn new(io: IO, config: Config<'a>) -> Self {
+        Self {
+            io,
+            config,
+            line: Vec::new(),
+            line_overflow: false,
+            response: Vec::new(),
+            urcs: Deque::new(),
+        }
+    }
+
+    /// Send a command and wait for `OK`.
+    ///
+    /// URCs received in the meantime are queued, see [`next_urc()`](Self::next_urc).
+    /// It is allowed to cancel this function's future, the partially received line is kept.
+    pub async fn send<'c>(&mut self, cmd: impl Into<Command<'c>>) -> Result<Response<'_>, Error> {
+        match self.execute(cmd.into()).await? {
+            Final::Ok => Ok(self.response()),
+            Final::Connect => Err(Error::Unexpected),
+        }
+    }
+
+    /// Send a command switching the modem to data mode, e.g. `ATD*99#`, and wait for `CONNECT`.
+    ///
+    /// Once it returns, the bytes received from the port are the data stream: use
+    /// [`into_inner()`](Self::into_inner) or [`io_mut()`](Self::io_mut) to hand the port to
+    /// `embassy-net-ppp`.
+    pub async fn dial<'c>(&mut self, cmd: impl Into<Command<'c>>) -> Result<(), Error> {
+        match self.execute(cmd.into()).await? {
+            Final::Ok => Err(Error::Unexpected),
+            Final::Connect => Ok(()),
+        }
+    }
+
+    /// Switch the modem from data mode back to command mode with the `+++` escape sequence,
+    /// and wait for `OK`.
+    ///
+    /// Data received before the `OK` is discarded.
+    pub async fn escape_data_mode(&mut self) -> Result<(), Error> {
+        Timer::after(ESCAPE_GUARD_TIME).await;
+        self.write(b"+++").await?;
+        Timer::after(ESCAPE_GUARD_TIME).await;
+        self.line.clear();
+        self.line_overflow = false;
+        let res = with_timeout(Command::DEFAULT_TIMEOUT, async {
+            loop {
+                self.read_line().await?;
+                let res = final_result(&self.line);
+                self.line.clear();
+                match res {
+                    Some(Ok(Final::Ok)) => return Ok(()),
+                    Some(Err(e)) => return Err(e),
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        res.unwrap_or(Err(Error::Timeout))
+    }
+
+    /// Wait for the next URC.
+    ///
+    /// Queued URCs are returned first. When no command is pending, every line received is a URC.
+    pub async fn next_urc(&mut self) -> Result<&str, Error> {
+        if !self.pop_urc() {
+            self.read_line().await?;
+            self.response.clear();
+            // Lines are not longer than the response buffer.
+            unwrap!(self.response.extend_from_slice(&self.line));
+            self.line.clear();
+        }
+        Ok(self.response().text)
+    }
+
+    /// Take the next queued URC, without waiting.
+    pub fn try_next_urc(&mut self) -> Option<&str> {
+        self.pop_urc().then(|| self.response().text)
+    }
+
+    /// Get a mutable reference to the serial port.
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Take back the serial port.
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+
+    async fn execute(&mut self, cmd: Command<'_>) -> Result<Final, Error> {
+        debug!("at: sending {}", cmd.text);
+        self.response.clear();
+        self.write(cmd.text.as_bytes()).await?;
+        self.write(b"\r").await?;
+
+        let prefix = cmd.response_prefix();
+        let mut overflow = false;
+        let res = with_timeout(cmd.timeout, async {
+            loop {
+                self.read_line().await?;
+                let line = self.line.as_slice();
+                if let Some(res) = final_result(line) {
+                    self.line.clear();
+                    return match (res, overflow) {
+                        (Ok(_), true) => Err(Error::BufferTooSmall),
+                        (res, _) => res,
+                    };
+                }
+
+                if line == cmd.text.as_bytes() {
+                    // Command echo, ATE1.
+                } else if self.is_urc(line, prefix) {
+                    self.push_urc();
+                } else {
+                    if !self.response.is_empty() {
+                        overflow |= self.response.push(b'\n').is_err();
+                    }
+                    overflow |= self.response.extend_from_slice(line).is_err();
+                }
+                self.line.clear();
+            }
+        })
+        .await;
+
+        let res = res.unwrap_or(Err(Error::Timeout));
+        if let Err(e) = res {
+            debug!("at: {} failed: {:?}", cmd.text, e);
+        }
+        res
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.io.write_all(data).await.map_err(|e| Error::Io(e.kind()))?;
+        self.io.flush().await.map_err(|e| Error::Io(e.kind()))
+    }
+
+    /// Read a non-empty line into `self.line`, without the line terminator.
+    ///
+    /// The bytes are consumed as they are copied to `self.line`, which is cleared by the caller
+    /// once the line is handled, so it's cancel safe.
+    async fn read_line(&mut self) -> Result<(), Error> {
+        loop {
+            let buf = self.io.fill_buf().await.map_err(|e| Error::Io(e.kind()))?;
+            if buf.is_empty() {
+                return Err(Error::Io(ErrorKind::BrokenPipe));
+            }
+            let (chunk, eol) = match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => (&buf[..i], true),
+                None => (buf, false),
+            };
+            for &b in chunk.iter().filter(|&&b| b != b'\r') {
+                self.line_overflow |= self.line.push(b).is_err();
+            }
+            let n = chunk.len() + eol as usize;
+            self.io.consume(n);
+
+            if eol {
+                if self.line_overflow {
+                    warn!("at: line too long, truncated");
+                    self.line_overflow = false;
+                }
+                match core::str::from_utf8(&self.line) {
+                    Ok("") => {}
+                    Ok(_line) => {
+                        trace!("at: received {}", _line);
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        warn!("at: dropping line with invalid UTF-8");
+                        self.line.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_urc(&self, line: &[u8], prefix: Option<&str>) -> bool {
+        let answers_command = prefix.is_some_and(|p| {
+            line.strip_prefix(p.as_bytes())
+                .is_some_and(|rest| rest.first() == Some(&b':'))
+        });
+        !answers_command && self.config.urc_prefixes.iter().any(|p| line.starts_with(p.as_bytes()))
+    }
+
+    /// Queue the line in `self.line` as a URC, dropping the oldest ones if there's no room.
+    fn push_urc(&mut self) {
+        if self.line.len() >= self.urcs.capacity() {
+            warn!("at: URC too long, dropped");
+            return;
+        }
+        while self.urcs.capacity() - self.urcs.len() < self.line.len() + 1 {
+            warn!("at: URC queue full, dropping the oldest");
+            while let Some(b) = self.urcs.pop_front() {
+                if b == b'\n' {
+                    break;
+                }
+            }
+        }
+        for &b in self.line.iter().chain(b"\n") {
+            unwrap!(self.urcs.push_back(b).ok());
+        }
+    }
+
+    /// Move the oldest queued URC to `self.response`. Returns false if there's none.
+    fn pop_urc(&mut self) -> bool {
+        if self.urcs.is_empty() {
+            return false;
+        }
+        self.response.clear();
+        while let Some(b) = self.urcs.pop_front() {
+            if b == b'\n' {
+                break;
+            }
+            unwrap!(self.response.push(b).ok());
+        }
+        true
+    }
+
+    fn response(&self) -> Response<'_> {
+        // Only valid UTF-8 lines are stored.
+        Response {
+            text: unwrap!(core::str::from_utf8(&self.response).ok()),
+        }
+    }
+}
+
+/// Parse a final result code.
+fn final_result(line: &[u8]) -> Option<Result<Final, Error>> {
+    let code = |rest: &[u8]| core::str::from_utf8(rest).ok().and_then(|s| s.trim().parse().ok());
+    let res = match line {
+        b"OK" => Ok(Final::Ok),
+        b"ERROR" => Err(Error::Error),
+        b"NO CARRIER" => Err(Error::NoCarrier),
+        b"BUSY" => Err(Error::Busy),
+        b"NO ANSWER" => Err(Error::NoAnswer),
+        b"NO DIALTONE" => Err(Error::NoDialtone),
+        _ if line == b"CONNECT" || line.starts_with(b"CONNECT ") => Ok(Final::Connect),
+        _ => {
+            if let Some(rest) = line.strip_prefix(b"+CME ERROR:") {
+                Err(code(rest).map_or(Error::Error, Error::CmeError))
+            } else if let Some(rest) = line.strip_prefix(b"+CMS ERROR:") {
+                Err(code(rest).map_or(Error::Error, Error::CmsError))
+            } else {
+                return None;
+            }
+        }
+    };
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_time::Duration;
+    use embedded_io_async::{BufRead, Read, Write};
+
+    use super::*;
+    use crate::test_util::{link, Buffer, Port};
+
+    /// Scripted modem stand-in: read a command, and answer it.
+    async fn expect(modem: &mut Port<'_>, cmd: &str, answer: &str) {
+        let mut line: Vec<u8, 64> = Vec::new();
+        loop {
+            let buf = modem.fill_buf().await.unwrap();
+            let b = buf[0];
+            modem.consume(1);
+            if b == b'\r' {
+                break;
+            }
+            line.push(b).unwrap();
+        }
+        assert_eq!(core::str::from_utf8(&line).unwrap(), cmd);
+        modem.write_all(answer.as_bytes()).await.unwrap();
+    }
+
+    #[test]
+    fn responses_and_urcs() {
+        let (mut a, mut b) = (Buffer::new(), Buffer::new());
+        let (host, mut modem) = link(&mut a, &mut b);
+        let config = Config {
+            urc_prefixes: &["+CREG:", "RING"],
+        };
+        let mut at = AtClient::<_, 128>::new(host, config);
+
+        block_on(join(
+            async {
+                let res = at.send("AT+CSQ").await.unwrap();
+                assert_eq!(res.line("+CSQ:"), Some("20,99"));
+                assert_eq!(res.as_str(), "+CSQ: 20,99\nextra");
+
+                // The command's own prefix is not a URC.
+                let res = at.send("AT+CREG?").await.unwrap();
+                assert_eq!(res.line("+CREG:"), Some("0,1"));
+
+                assert_eq!(at.try_next_urc(), Some("+CREG: 1"));
+                assert_eq!(at.try_next_urc(), None);
+                // When idle, all lines are URCs.
+                assert_eq!(at.next_urc().await, Ok("RING"));
+            },
+            async {
+                expect(
+                    &mut modem,
+                    "AT+CSQ",
+                    "AT+CSQ\r\r\n+CREG: 1\r\n\r\n+CSQ: 20,99\r\nextra\r\n\r\nOK\r\n",
+                )
+                .await;
+                expect(&mut modem, "AT+CREG?", "\r\n+CREG: 0,1\r\n\r\nOK\r\n").await;
+                modem.write_all(b"\r\nRING\r\n").await.unwrap();
+            },
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let (mut a, mut b) = (Buffer::new(), Buffer::new());
+        let (host, mut modem) = link(&mut a, &mut b);
+        let mut at = AtClient::<_, 128>::new(host, Config::default());
+
+        block_on(join(
+            async {
+                assert_eq!(at.send("AT+CPIN?").await, Err(Error::CmeError(10)));
+                assert_eq!(at.send("AT+CMGS").await, Err(Error::CmsError(500)));
+                assert_eq!(at.send("AT+FOO").await, Err(Error::Error));
+                assert_eq!(at.dial("ATD*99#").await, Err(Error::NoCarrier));
+                assert_eq!(at.dial("AT").await, Err(Error::Unexpected));
+                let cmd = Command::new("AT").with_timeout(Duration::from_millis(50));
+                assert_eq!(at.send(cmd).await, Err(Error::Timeout));
+                assert!(at.send("AT").await.is_ok());
+            },
+            async {
+                expect(&mut modem, "AT+CPIN?", "\r\n+CME ERROR: 10\r\n").await;
+                expect(&mut modem, "AT+CMGS", "\r\n+CMS ERROR: 500\r\n").await;
+                expect(&mut modem, "AT+FOO", "\r\nERROR\r\n").await;
+                expect(&mut modem, "ATD*99#", "\r\nNO CARRIER\r\n").await;
+                expect(&mut modem, "AT", "\r\nOK\r\n").await;
+                expect(&mut modem, "AT", "").await;
+                expect(&mut modem, "AT", "\r\nOK\r\n").await;
+            },
+        ));
+    }
+
+    #[test]
+    fn dial_hands_over_data() {
+        let (mut a, mut b) = (Buffer::new(), Buffer::new());
+        let (host, mut modem) = link(&mut a, &mut b);
+        let mut at = AtClient::<_, 128>::new(host, Config::default());
+
+        block_on(join(
+            async {
+                at.dial(Command::new("ATD*99#")).await.unwrap();
+                let mut host = at.into_inner();
+                let mut buf = [0; 4];
+                host.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [0x7e, 0x7f, 0x7d, 0x23]);
+            },
+            expect(&mut modem, "ATD*99#", "\r\nCONNECT 150000000\r\n\x7e\x7f\x7d\x23"),
+        ));
+    }
+}
@@ -0,0 +1,747 @@
+//! CMUX multiplexer (3GPP TS 27.010, basic option).
+//!
+//! Multiplexing splits the modem's serial port in several virtual channels, so that for example
+//! one runs PPP while another is used to send AT commands, e.g. to monitor the signal quality.
+//!
+//! Switch the modem to CMUX mode with the appropriate AT command, usually `AT+CMUX=0`, then
+//! call [`Runner::run()`] with the serial port in a background task. Each [`Channel`]
+//! implements the `embedded-io-async` traits, so it can be used with an
+//! [`AtClient`](crate::at::AtClient) or with `embassy-net-ppp`.
+
+use core::convert::Infallible;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pipe::{Pipe, Reader, Writer};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{BufRead, ErrorType, Read, Write};
+use heapless::Vec;
+
+/// Largest frame information field supported, both sent and received.
+///
+/// The maximum frame size configured on the modem, the `N1` parameter of `AT+CMUX`,
+/// must not be larger.
+pub const MAX_FRAME_SIZE: usize = 256;
+
+const FLAG: u8 = 0xf9;
+const EA: u8 = 0x01;
+const CR: u8 = 0x02;
+const PF: u8 = 0x10;
+
+const SABM: u8 = 0x2f;
+const UA: u8 = 0x63;
+const DM: u8 = 0x0f;
+const DISC: u8 = 0x43;
+const UIH: u8 = 0xef;
+
+// Control channel message types, without the EA and C/R bits.
+const MSG_PN: u8 = 0x80;
+const MSG_CLD: u8 = 0xc0;
+const MSG_TEST: u8 = 0x20;
+const MSG_FCON: u8 = 0xa0;
+const MSG_FCOFF: u8 = 0x60;
+const MSG_MSC: u8 = 0xe0;
+const MSG_NSC: u8 = 0x10;
+
+/// V.24 signals sent in MSC commands: RTC, RTR and DV.
+const MSC_SIGNALS: u8 = 0x8d;
+/// Flow control bit of the MSC V.24 signals.
+const MSC_FC: u8 = 0x02;
+
+/// Frame header and trailer: two flags, address, control, two length bytes and FCS.
+const FRAME_OVERHEAD: usize = 7;
+
+/// CMUX configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config {
+    /// Largest information field sent in a frame, the `N1` parameter of `AT+CMUX`.
+    ///
+    /// Must not be larger than [`MAX_FRAME_SIZE`].
+    pub max_frame_size: usize,
+    /// Time to wait for the modem to acknowledge opening a channel.
+    pub ack_timeout: Duration,
+    /// Number of times opening a channel is attempted.
+    pub retries: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 127,
+            ack_timeout: Duration::from_millis(500),
+            retries: 3,
+        }
+    }
+}
+
+/// Error returned by [`Runner::run`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RunError<E> {
+    /// Reading from the serial port failed.
+    Read(E),
+    /// Writing to the serial port failed.
+    Write(E),
+    /// Reading from the serial port got EOF.
+    Eof,
+    /// The modem didn't acknowledge opening a channel, given by its DLCI (0 for the control channel).
+    Timeout(u8),
+    /// The modem refused to open a channel, given by its DLCI (0 for the control channel).
+    Rejected(u8),
+    /// The modem closed the multiplexer.
+    Closed,
+}
+
+struct ChannelState<const BUF: usize> {
+    rx: Pipe<NoopRawMutex, BUF>,
+    tx: Pipe<NoopRawMutex, BUF>,
+}
+
+/// Internal state for the multiplexer, with `N` channels buffering `BUF` bytes in each direction.
+pub struct State<const N: usize, const BUF: usize> {
+    channels: [ChannelState<BUF>; N],
+    tx_signal: Signal<NoopRawMutex, ()>,
+}
+
+impl<const N: usize, const BUF: usize> State<N, BUF> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            channels: [const {
+                ChannelState {
+                    rx: Pipe::new(),
+                    tx: Pipe::new(),
+                }
+            }; N],
+            tx_signal: Signal::new(),
+        }
+    }
+}
+
+impl<const N: usize, const BUF: usize> Default for State<N, BUF> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A virtual channel, numbered from DLCI 1 in the order returned by [`new()`].
+///
+/// Reading and writing doesn't fail. Data written before the multiplexer is running is sent
+/// once the channel is open.
+pub struct Channel<'d, const BUF: usize> {
+    rx: Reader<'d, NoopRawMutex, BUF>,
+    tx: Writer<'d, NoopRawMutex, BUF>,
+    tx_signal: &'d Signal<NoopRawMutex, ()>,
+}
+
+impl<const BUF: usize> ErrorType for Channel<'_, BUF> {
+    type Error = Infallible;
+}
+
+impl<const BUF: usize> Read for Channel<'_, BUF> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl<const BUF: usize> BufRead for Channel<'_, BUF> {
+    async fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        Ok(self.rx.fill_buf().await)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.rx.consume(amt)
+    }
+}
+
+impl<const BUF: usize> Write for Channel<'_, BUF> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.tx.write(buf).await;
+        self.tx_signal.signal(());
+        Ok(n)
+    }
+}
+
+struct Port<'d, const BUF: usize> {
+    rx: Writer<'d, NoopRawMutex, BUF>,
+    tx: Reader<'d, NoopRawMutex, BUF>,
+}
+
+/// Background runner for the multiplexer.
+///
+/// You must call `.run()` in a background task for the channels to operate.
+pub struct Runner<'d, const N: usize, const BUF: usize> {
+    ports: Vec<Port<'d, BUF>, N>,
+    tx_signal: &'d Signal<NoopRawMutex, ()>,
+}
+
+impl<'d, const N: usize, const BUF: usize> Runner<'d, N, BUF> {
+    /// You must call this in a background task for the channels to operate.
+    ///
+    /// The modem must already be in CMUX mode. The control channel and the virtual channels
+    /// are opened, then data is exchanged until the modem closes the multiplexer or reading
+    /// from or writing to the serial port fails.
+    ///
+    /// Data received on a channel is buffered until it's read. When a channel's buffer is
+    /// full, receiving stalls for all channels.
+    pub async fn run<RW: BufRead + Write>(
+        &mut self,
+        mut rw: RW,
+        config: Config,
+    ) -> Result<Infallible, RunError<RW::Error>> {
+        assert!(config.max_frame_size > 0 && config.max_frame_size <= MAX_FRAME_SIZE);
+
+        let mut decoder = Decoder::new();
+        let mut rx_buf = [0; MAX_FRAME_SIZE];
+        let mut tx_buf = [0; MAX_FRAME_SIZE + FRAME_OVERHEAD];
+
+        for dlci in 0..=N as u8 {
+            open(&mut rw, &mut decoder, &mut rx_buf, dlci, &config).await?;
+        }
+        for dlci in 1..=N as u8 {
+            let msc = [MSG_MSC | CR | EA, 2 << 1 | EA, dlci << 2 | CR | EA, MSC_SIGNALS];
+            send(&mut rw, &mut tx_buf, 0, UIH, &msc).await?;
+        }
+        info!("cmux: {} channels open", N);
+
+        // Whether the modem asked us to stop sending, for all channels and per channel.
+        let mut flow_off = false;
+        let mut channel_flow_off = [false; N];
+
+        loop {
+            // Send what the channels have written, one frame per channel in turn.
+            let mut sent = true;
+            while sent && !flow_off {
+                sent = false;
+                for (i, port) in self.ports.iter_mut().enumerate() {
+                    if channel_flow_off[i] {
+                        continue;
+                    }
+                    let mut data = [0; MAX_FRAME_SIZE];
+                    let Ok(n) = port.tx.try_read(&mut data[..config.max_frame_size]) else {
+                        continue;
+                    };
+                    send(&mut rw, &mut tx_buf, i as u8 + 1, UIH, &data[..n]).await?;
+                    sent = true;
+                }
+            }
+
+            let rx_data = match select(rw.fill_buf(), self.tx_signal.wait()).await {
+                Either::First(Ok([])) => return Err(RunError::Eof),
+                Either::First(Ok(rx_data)) => rx_data,
+                Either::First(Err(e)) => return Err(RunError::Read(e)),
+                Either::Second(()) => continue,
+            };
+
+            let mut frame = None;
+            let mut n = 0;
+            for &b in rx_data {
+                n += 1;
+                frame = decoder.feed(&mut rx_buf, b);
+                if frame.is_some() {
+                    break;
+                }
+            }
+            rw.consume(n);
+            let Some(frame) = frame else { continue };
+
+            let dlci = frame.address >> 2;
+            let info = &rx_buf[..frame.len];
+            match (dlci, frame.control & !PF) {
+                (0, UIH) => {
+                    let Some((kind, value)) = parse_message(info) else {
+                        continue;
+                    };
+                    if kind & CR == 0 {
+                        // A response to one of our commands.
+                        continue;
+                    }
+                    let kind = kind & !(CR | EA);
+                    match kind {
+                        MSG_MSC => {
+                            if let [address, signals, ..] = *value {
+                                let dlci = address >> 2;
+                                if let Some(off) = channel_flow_off.get_mut((dlci as usize).wrapping_sub(1)) {
+                                    *off = signals & MSC_FC != 0;
+                                }
+                            }
+                        }
+                        MSG_FCON => flow_off = false,
+                        MSG_FCOFF => flow_off = true,
+                        MSG_CLD => {
+                            respond(&mut rw, &mut tx_buf, kind, value).await?;
+                            info!("cmux: closed by the modem");
+                            return Err(RunError::Closed);
+                        }
+                        MSG_TEST | MSG_PN => {}
+                        _ => {
+                            debug!("cmux: unsupported control message {:02x}", kind);
+                            let nsc = [MSG_NSC | EA, 1 << 1 | EA, kind | CR | EA];
+                            send(&mut rw, &mut tx_buf, 0, UIH, &nsc).await?;
+                            continue;
+                        }
+                    }
+                    respond(&mut rw, &mut tx_buf, kind, value).await?;
+                }
+                (dlci, UIH) => {
+                    let Some(port) = self.ports.get_mut((dlci as usize).wrapping_sub(1)) else {
+                        continue;
+                    };
+                    // The pipe can't fail: writes wait until the channel reader makes room.
+                    if let Err(e) = port.rx.write_all(info).await {
+                        match e {}
+                    }
+                }
+                (0, DISC) | (0, DM) => {
+                    send(&mut rw, &mut tx_buf, 0, UA | PF, &[]).await?;
+                    info!("cmux: closed by the modem");
+                    return Err(RunError::Closed);
+                }
+                (dlci, DISC) => {
+                    warn!("cmux: channel {} closed by the modem", dlci);
+                    send(&mut rw, &mut tx_buf, dlci, UA | PF, &[]).await?;
+                }
+                (dlci, SABM) => send(&mut rw, &mut tx_buf, dlci, DM | PF, &[]).await?,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Create a multiplexer with `N` channels.
+pub fn new<const N: usize, const BUF: usize>(state: &mut State<N, BUF>) -> (Runner<'_, N, BUF>, [Channel<'_, BUF>; N]) {
+    assert!(N > 0 && N < 63);
+
+    let tx_signal = &state.tx_signal;
+    let mut ports = Vec::new();
+    let mut channels = Vec::<_, N>::new();
+    for c in state.channels.iter_mut() {
+        let (rx_reader, rx_writer) = c.rx.split();
+        let (tx_reader, tx_writer) = c.tx.split();
+        unwrap!(ports
+            .push(Port {
+                rx: rx_writer,
+                tx: tx_reader,
+            })
+            .ok());
+        unwrap!(channels
+            .push(Channel {
+                rx: rx_reader,
+                tx: tx_writer,
+                tx_signal,
+            })
+            .ok());
+    }
+    (Runner { ports, tx_signal }, unwrap!(channels.into_array().ok()))
+}
+
+/// Open a channel with SABM, and wait for the modem's UA.
+async fn open<RW: BufRead + Write>(
+    rw: &mut RW,
+    decoder: &mut Decoder,
+    rx_buf: &mut [u8; MAX_FRAME_SIZE],
+    dlci: u8,
+    config: &Config,
+) -> Result<(), RunError<RW::Error>> {
+    let mut tx_buf = [0; FRAME_OVERHEAD];
+    for _ in 0..config.retries {
+        send(rw, &mut tx_buf, dlci, SABM | PF, &[]).await?;
+        let res = with_timeout(config.ack_timeout, async {
+            loop {
+                let rx_data = match rw.fill_buf().await {
+                    Ok([]) => return Err(RunError::Eof),
+                    Ok(rx_data) => rx_data,
+                    Err(e) => return Err(RunError::Read(e)),
+                };
+                let mut frame = None;
+                let mut n = 0;
+                for &b in rx_data {
+                    n += 1;
+                    frame = decoder.feed(rx_buf, b);
+                    if frame.is_some() {
+                        break;
+                    }
+                }
+                rw.consume(n);
+                match frame {
+                    Some(f) if f.address >> 2 == dlci && f.control & !PF == UA => return Ok(()),
+                    Some(f) if f.address >> 2 == dlci && f.control & !PF == DM => return Err(RunError::Rejected(dlci)),
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        match res {
+            Ok(res) => return res,
+            Err(_) => debug!("cmux: no answer opening channel {}", dlci),
+        }
+    }
+    Err(RunError::Timeout(dlci))
+}
+
+/// Answer a control channel command.
+async fn respond<RW: Write>(rw: &mut RW, tx_buf: &mut [u8], kind: u8, value: &[u8]) -> Result<(), RunError<RW::Error>> {
+    let mut msg = [0; 2 + MAX_FRAME_SIZE];
+    let len = value.len().min(msg.len() - 2);
+    msg[0] = kind | EA;
+    msg[1] = (len as u8) << 1 | EA;
+    msg[2..2 + len].copy_from_slice(&value[..len]);
+    send(rw, tx_buf, 0, UIH, &msg[..2 + len]).await
+}
+
+async fn send<RW: Write>(
+    rw: &mut RW,
+    tx_buf: &mut [u8],
+    dlci: u8,
+    control: u8,
+    info: &[u8],
+) -> Result<(), RunError<RW::Error>> {
+    let n = encode(tx_buf, dlci << 2 | CR | EA, control, info);
+    rw.write_all(&tx_buf[..n]).await.map_err(RunError::Write)?;
+    rw.flush().await.map_err(RunError::Write)
+}
+
+/// Split a control channel message in type and value. Messages with a multi-byte length are ignored.
+fn parse_message(info: &[u8]) -> Option<(u8, &[u8])> {
+    let [kind, len, value @ ..] = info else {
+        return None;
+    };
+    let len = (len >> 1) as usize;
+    if kind & EA == 0 || value.len() < len {
+        return None;
+    }
+    Some((*kind, &value[..len]))
+}
+
+fn crc_update(crc: u8, b: u8) -> u8 {
+    let mut crc = crc ^ b;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 { (crc >> 1) ^ 0xe0 } else { crc >> 1 };
+    }
+    crc
+}
+
+/// Encode a frame in `buf`, returning its length.
+fn encode(buf: &mut [u8], address: u8, control: u8, info: &[u8]) -> usize {
+    let mut header: Vec<u8, 4> = Vec::new();
+    unwrap!(header.extend_from_slice(&[address, control]).ok());
+    if info.len() < 0x80 {
+        unwrap!(header.push((info.len() as u8) << 1 | EA).ok());
+    } else {
+        unwrap!(header
+            .extend_from_slice(&[(info.len() as u8) << 1, (info.len() >> 7) as u8])
+            .ok());
+    }
+
+    let mut n = 0;
+    let mut put = |data: &[u8]| {
+        buf[n..n + data.len()].copy_from_slice(data);
+        n += data.len();
+    };
+    put(&[FLAG]);
+    put(&header);
+    put(info);
+    // For UIH frames the FCS only covers the header.
+    let mut crc = header.iter().fold(0xff, |crc, &b| crc_update(crc, b));
+    if control & !PF != UIH {
+        crc = info.iter().fold(crc, |crc, &b| crc_update(crc, b));
+    }
+    put(&[0xff - crc, FLAG]);
+    n
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+    address: u8,
+    control: u8,
+    /// Length of the information field.
+    len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    /// Waiting for an opening flag.
+    Flag,
+    Address,
+    Control,
+    Length,
+    Length2,
+    Info,
+    Fcs,
+    /// Waiting for the closing flag.
+    End {
+        ok: bool,
+    },
+}
+
+/// Incremental frame decoder.
+struct Decoder {
+    state: DecodeState,
+    address: u8,
+    control: u8,
+    len: usize,
+    pos: usize,
+    crc: u8,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            state: DecodeState::Flag,
+            address: 0,
+            control: 0,
+            len: 0,
+            pos: 0,
+            crc: 0xff,
+        }
+    }
+
+    /// Feed a received byte, storing the information field in `buf`.
+    ///
+    /// Returns the frame when it ends and it's valid. Frames that don't fit in `buf` are dropped.
+    fn feed(&mut self, buf: &mut [u8], b: u8) -> Option<Frame> {
+        match self.state {
+            DecodeState::Flag => {
+                if b == FLAG {
+                    self.state = DecodeState::Address;
+                }
+            }
+            DecodeState::Address => {
+                // Consecutive flags are allowed between frames.
+                if b != FLAG {
+                    self.address = b;
+                    self.crc = crc_update(0xff, b);
+                    self.state = DecodeState::Control;
+                }
+            }
+            DecodeState::Control => {
+                self.control = b;
+                self.crc = crc_update(self.crc, b);
+                self.state = DecodeState::Length;
+            }
+            DecodeState::Length | DecodeState::Length2 => {
+                self.crc = crc_update(self.crc, b);
+                if self.state == DecodeState::Length {
+                    self.len = (b >> 1) as usize;
+                } else {
+                    self.len |= (b as usize) << 7;
+                }
+                self.pos = 0;
+                self.state = if self.state == DecodeState::Length && b & EA == 0 {
+                    DecodeState::Length2
+                } else if self.len == 0 {
+                    DecodeState::Fcs
+                } else {
+                    DecodeState::Info
+                };
+            }
+            DecodeState::Info => {
+                if let Some(slot) = buf.get_mut(self.pos) {
+                    *slot = b;
+                }
+                if self.control & !PF != UIH {
+                    self.crc = crc_update(self.crc, b);
+                }
+                self.pos += 1;
+                if self.pos == self.len {
+                    self.state = DecodeState::Fcs;
+                }
+            }
+            DecodeState::Fcs => {
+                let ok = crc_update(self.crc, b) == 0xcf && self.len <= buf.len();
+                self.state = DecodeState::End { ok };
+            }
+            DecodeState::End { ok } => {
+                if b != FLAG {
+                    self.state = DecodeState::Flag;
+                    return None;
+                }
+                // The closing flag can also open the next frame.
+                self.state = DecodeState::Address;
+                if ok {
+                    return Some(Frame {
+                        address: self.address,
+                        control: self.control,
+                        len: self.len,
+                    });
+                }
+                warn!("cmux: dropping invalid frame");
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_futures::select::{select, Either};
+
+    use super::*;
+    use crate::at::{self, AtClient};
+    use crate::test_util::{link, Buffer, Port};
+
+    /// Scripted modem stand-in, the responder side of the multiplexer.
+    struct Modem<'a> {
+        port: Port<'a>,
+        decoder: Decoder,
+        buf: [u8; MAX_FRAME_SIZE],
+    }
+
+    impl<'a> Modem<'a> {
+        async fn recv(&mut self) -> (u8, u8, Vec<u8, MAX_FRAME_SIZE>) {
+            loop {
+                let rx = self.port.fill_buf().await.unwrap();
+                let b = rx[0];
+                self.port.consume(1);
+                if let Some(f) = self.decoder.feed(&mut self.buf, b) {
+                    assert_eq!(f.address & (CR | EA), CR | EA);
+                    let info = Vec::from_slice(&self.buf[..f.len]).unwrap();
+                    return (f.address >> 2, f.control, info);
+                }
+            }
+        }
+
+        async fn send(&mut self, dlci: u8, control: u8, info: &[u8]) {
+            let mut buf = [0; MAX_FRAME_SIZE + FRAME_OVERHEAD];
+            let n = encode(&mut buf, dlci << 2 | EA, control, info);
+            self.port.write_all(&buf[..n]).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn frame_encoding() {
+        let mut buf = [0; 16];
+        let n = encode(&mut buf, 0x03, SABM | PF, &[]);
+        assert_eq!(buf[..n], [0xf9, 0x03, 0x3f, 0x01, 0x1c, 0xf9]);
+
+        let mut decoder = Decoder::new();
+        let mut info = [0; 8];
+        // Leading garbage and repeated flags are skipped.
+        let frame = [0x55, 0xf9, 0xf9, 0x03, 0x73, 0x01, 0xd7, 0xf9];
+        let res: Option<Frame> = frame.iter().fold(None, |res, &b| res.or(decoder.feed(&mut info, b)));
+        let frame = res.unwrap();
+        assert_eq!((frame.address, frame.control, frame.len), (0x03, UA | PF, 0));
+
+        // Bad FCS.
+        for b in [0xf9, 0x03, 0x73, 0x01, 0xd8, 0xf9] {
+            assert!(decoder.feed(&mut info, b).is_none());
+        }
+
+        // UIH with a two-byte length, the FCS only covers the header.
+        let data = [0x42; 200];
+        let mut buf = [0; 256];
+        let n = encode(&mut buf, 0x07, UIH, &data);
+        let mut info = [0; 256];
+        let res: Option<Frame> = buf[..n].iter().fold(None, |res, &b| res.or(decoder.feed(&mut info, b)));
+        let frame = res.unwrap();
+        assert_eq!((frame.address >> 2, frame.len), (1, 200));
+        assert_eq!(info[..200], data);
+    }
+
+    #[test]
+    fn channels() {
+        let (mut a, mut b) = (Buffer::new(), Buffer::new());
+        let (host, port) = link(&mut a, &mut b);
+        let mut modem = Modem {
+            port,
+            decoder: Decoder::new(),
+            buf: [0; MAX_FRAME_SIZE],
+        };
+
+        let mut state = State::<2, 256>::new();
+        let (mut runner, [ch1, mut ch2]) = new(&mut state);
+        let mut at = AtClient::<_, 128>::new(ch1, at::Config::default());
+
+        let host_fut = async {
+            let res = at.send("AT+CSQ").await.unwrap();
+            assert_eq!(res.line("+CSQ:"), Some("20,99"));
+            ch2.write_all(&[0x7e; 300]).await.unwrap();
+        };
+
+        let modem_fut = async {
+            // SABM on the control channel, then on each channel.
+            for dlci in 0..=2 {
+                assert_eq!(modem.recv().await, (dlci, SABM | PF, Vec::new()));
+                modem.send(dlci, UA | PF, &[]).await;
+            }
+            for dlci in 1..=2 {
+                let (_, control, info) = modem.recv().await;
+                assert_eq!(control, UIH);
+                assert_eq!(info, [MSG_MSC | CR | EA, 0x05, dlci << 2 | CR | EA, MSC_SIGNALS]);
+            }
+
+            // MSC command from the modem, which must be answered.
+            modem
+                .send(0, UIH, &[MSG_MSC | CR | EA, 0x05, 1 << 2 | CR | EA, 0x8d])
+                .await;
+
+            // Traffic on channel 1, and 300 bytes split in frames on channel 2.
+            let mut msc_answered = false;
+            let mut at_answered = false;
+            let mut ch2_data = 0;
+            while !msc_answered || !at_answered || ch2_data < 300 {
+                let (dlci, control, info) = modem.recv().await;
+                assert_eq!(control, UIH);
+                match dlci {
+                    0 => {
+                        assert_eq!(info, [MSG_MSC | EA, 0x05, 1 << 2 | CR | EA, 0x8d]);
+                        msc_answered = true;
+                    }
+                    1 => {
+                        assert_eq!(info, b"AT+CSQ\r");
+                        modem.send(1, UIH, b"\r\n+CSQ: 20,99\r\n\r\nOK\r\n").await;
+                        at_answered = true;
+                    }
+                    2 => {
+                        assert!(info.len() <= 127 && info.iter().all(|&b| b == 0x7e));
+                        ch2_data += info.len();
+                    }
+                    _ => panic!("unexpected channel {}", dlci),
+                }
+            }
+            assert_eq!(ch2_data, 300);
+
+            // Close down, the runner answers and returns.
+            modem.send(0, UIH, &[MSG_CLD | CR | EA, 0x01]).await;
+            assert_eq!(
+                modem.recv().await,
+                (0, UIH, Vec::from_slice(&[MSG_CLD | EA, 0x01]).unwrap())
+            );
+        };
+
+        let res = block_on(async {
+            match select(runner.run(host, Config::default()), join(host_fut, modem_fut)).await {
+                Either::First(res) => res,
+                Either::Second(_) => panic!("runner didn't return"),
+            }
+        });
+        assert!(matches!(res, Err(RunError::Closed)));
+    }
+
+    #[test]
+    fn open_rejected() {
+        let (mut a, mut b) = (Buffer::new(), Buffer::new());
+        let (host, port) = link(&mut a, &mut b);
+        let mut modem = Modem {
+            port,
+            decoder: Decoder::new(),
+            buf: [0; MAX_FRAME_SIZE],
+        };
+
+        let mut state = State::<1, 64>::new();
+        let (mut runner, _channels) = new(&mut state);
+
+        let (res, _) = block_on(join(runner.run(host, Config::default()), async {
+            assert_eq!(modem.recv().await.0, 0);
+            modem.send(0, UA | PF, &[]).await;
+            assert_eq!(modem.recv().await.0, 1);
+            modem.send(1, DM | PF, &[]).await;
+        }));
+        assert!(matches!(res, Err(RunError::Rejected(1))));
+    }
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+// must be first
+mod fmt;
+
+pub mod at;
+pub mod cmux;
+
+#[cfg(test)]
+mod test_util {
+    use core::convert::Infallible;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::pipe::{Pipe, Reader, Writer};
+    use embedded_io_async::{BufRead, ErrorType, Read, Write};
+
+    pub type Buffer = Pipe<NoopRawMutex, 512>;
+
+    /// One end of an in-memory serial link.
+    pub struct Port<'a> {
+        rx: Reader<'a, NoopRawMutex, 512>,
+        tx: Writer<'a, NoopRawMutex, 512>,
+    }
+
+    /// Create both ends of an in-memory serial link.
+    pub fn link<'a>(a: &'a mut Buffer, b: &'a mut Buffer) -> (Port<'a>, Port<'a>) {
+        let (a_rx, a_tx) = a.split();
+        let (b_rx, b_tx) = b.split();
+        (Port { rx: a_rx, tx: b_tx }, Port { rx: b_rx, tx: a_tx })
+    }
+
+    impl ErrorType for Port<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Port<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            Ok(self.rx.read(buf).await)
+        }
+    }
+
+    impl BufRead for Port<'_> {
+        async fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+            Ok(self.rx.fill_buf().await)
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.rx.consume(amt)
+        }
+    }
+
+    impl Write for Port<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            Ok(self.tx.write(buf).await)
+        }
+    }
+}
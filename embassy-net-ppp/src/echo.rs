@@ -0,0 +1,159 @@
+//! LCP echo requests, to detect a dead peer.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::lcp::{self, Builder};
+use crate::{update_stats, Stats};
+
+/// LCP echo configuration.
+///
+/// When the link is up, an Echo-Request is sent every `interval`. If `max_failures`
+/// consecutive requests are not answered, the link is considered dead and brought down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EchoConfig {
+    /// Interval between echo requests.
+    pub interval: Duration,
+    /// Number of consecutive unanswered echo requests after which the link is brought down.
+    pub max_failures: u8,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            max_failures: 3,
+        }
+    }
+}
+
+pub(crate) enum EchoAction {
+    None,
+    /// Send an echo request with this identifier.
+    Send(u8),
+    /// Too many requests were not answered.
+    Dead,
+}
+
+pub(crate) struct Echo {
+    config: EchoConfig,
+    next: Instant,
+    id: u8,
+    pending: bool,
+    failures: u8,
+}
+
+impl Echo {
+    pub fn new(config: EchoConfig) -> Self {
+        Self {
+            config,
+            next: Instant::now() + config.interval,
+            id: 0,
+            pending: false,
+            failures: 0,
+        }
+    }
+
+    pub fn poll_at(&self) -> Instant {
+        self.next
+    }
+
+    pub fn poll(&mut self, stats: &Mutex<NoopRawMutex, Cell<Stats>>) -> EchoAction {
+        let now = Instant::now();
+        if now < self.next {
+            return EchoAction::None;
+        }
+        if self.pending {
+            self.failures += 1;
+            update_stats(stats, |s| s.echo_failures += 1);
+            warn!("ppp: no echo reply ({}/{})", self.failures, self.config.max_failures);
+            if self.failures >= self.config.max_failures {
+                return EchoAction::Dead;
+            }
+        }
+        self.id = self.id.wrapping_add(1);
+        self.pending = true;
+        self.next = now + self.config.interval;
+        EchoAction::Send(self.id)
+    }
+
+    /// Handle a received Echo-Reply. Returns whether it answers our last request.
+    pub fn on_reply(&mut self, id: u8) -> bool {
+        if !self.pending || id != self.id {
+            return false;
+        }
+        self.pending = false;
+        self.failures = 0;
+        true
+    }
+}
+
+/// Build an Echo-Request.
+pub(crate) fn request(id: u8, magic: u32) -> Builder {
+    let mut b = Builder::new(lcp::ECHO_REQUEST, id);
+    b.data(&magic.to_be_bytes());
+    b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(max_failures: u8) -> Echo {
+        // Requests are due on every poll.
+        Echo::new(EchoConfig {
+            interval: Duration::from_ticks(0),
+            max_failures,
+        })
+    }
+
+    #[test]
+    fn replies() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut echo = echo(2);
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(1)));
+        // Only the reply to the last request counts.
+        assert!(!echo.on_reply(0));
+        assert!(echo.on_reply(1));
+        assert!(!echo.on_reply(1));
+
+        // Failures are consecutive.
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(2)));
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(3)));
+        assert!(echo.on_reply(3));
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(4)));
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(5)));
+        assert_eq!(stats.lock(|s| s.get()).echo_failures, 2);
+    }
+
+    #[test]
+    fn dead() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut echo = echo(3);
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(1)));
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(2)));
+        assert!(matches!(echo.poll(&stats), EchoAction::Send(3)));
+        assert!(matches!(echo.poll(&stats), EchoAction::Dead));
+        assert_eq!(stats.lock(|s| s.get()).echo_failures, 3);
+        // A late reply doesn't count.
+        assert!(!echo.on_reply(1));
+    }
+
+    #[test]
+    fn interval() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut echo = Echo::new(EchoConfig::default());
+        assert!(echo.poll_at() > Instant::now());
+        assert!(matches!(echo.poll(&stats), EchoAction::None));
+        assert!(!echo.on_reply(0));
+    }
+
+    #[test]
+    fn request_packet() {
+        assert_eq!(request(7, 0x0102_0304).finish(), [9, 7, 0, 8, 1, 2, 3, 4]);
+    }
+}
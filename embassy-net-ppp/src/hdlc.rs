@@ -0,0 +1,232 @@
+//! HDLC-like framing (RFC 1662), for the frames the runner builds and parses itself.
+
+const FLAG: u8 = 0x7e;
+const ESCAPE: u8 = 0x7d;
+const ADDRESS: u8 = 0xff;
+const CONTROL: u8 = 0x03;
+
+const FCS_INIT: u16 = 0xffff;
+const FCS_GOOD: u16 = 0xf0b8;
+
+fn fcs_update(mut fcs: u16, b: u8) -> u16 {
+    fcs ^= b as u16;
+    for _ in 0..8 {
+        fcs = if fcs & 1 != 0 { (fcs >> 1) ^ 0x8408 } else { fcs >> 1 };
+    }
+    fcs
+}
+
+/// Appends encoded frames to a buffer, so that several can be written at once.
+///
+/// All control characters are escaped, which is always understood by the peer
+/// whatever the negotiated ACCM.
+pub(crate) struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// The encoded frames.
+    pub fn frames(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Append a frame. Returns false, leaving the buffer unchanged, if it doesn't fit.
+    pub fn push(&mut self, protocol: u16, payload: &[u8]) -> bool {
+        let start = self.len;
+        let [p0, p1] = protocol.to_be_bytes();
+        let mut fcs = FCS_INIT;
+        let mut ok = self.put(FLAG);
+        for &b in [ADDRESS, CONTROL, p0, p1].iter().chain(payload) {
+            fcs = fcs_update(fcs, b);
+            ok = ok && self.put_escaped(b);
+        }
+        for b in (!fcs).to_le_bytes() {
+            ok = ok && self.put_escaped(b);
+        }
+        ok = ok && self.put(FLAG);
+        if !ok {
+            self.len = start;
+        }
+        ok
+    }
+
+    fn put_escaped(&mut self, b: u8) -> bool {
+        if b < 0x20 || b == FLAG || b == ESCAPE {
+            self.put(ESCAPE) && self.put(b ^ 0x20)
+        } else {
+            self.put(b)
+        }
+    }
+
+    fn put(&mut self, b: u8) -> bool {
+        let Some(slot) = self.buf.get_mut(self.len) else {
+            return false;
+        };
+        *slot = b;
+        self.len += 1;
+        true
+    }
+}
+
+/// Incremental frame decoder.
+pub(crate) struct Decoder {
+    len: usize,
+    escaped: bool,
+    overflow: bool,
+    fcs: u16,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            escaped: false,
+            overflow: false,
+            fcs: FCS_INIT,
+        }
+    }
+
+    /// Feed a received byte, storing the frame in `buf`.
+    ///
+    /// Returns the length of the frame in `buf`, without the FCS, when a frame
+    /// ends and it's valid. Frames that don't fit in `buf` are dropped.
+    pub fn feed(&mut self, buf: &mut [u8], b: u8) -> Option<usize> {
+        match b {
+            FLAG => {
+                let ok = !self.overflow && self.len >= 4 && self.fcs == FCS_GOOD;
+                let len = self.len;
+                *self = Self::new();
+                ok.then(|| len - 2)
+            }
+            ESCAPE => {
+                self.escaped = true;
+                None
+            }
+            _ => {
+                let b = if self.escaped { b ^ 0x20 } else { b };
+                self.escaped = false;
+                self.fcs = fcs_update(self.fcs, b);
+                match buf.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = b;
+                        self.len += 1;
+                    }
+                    None => self.overflow = true,
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Split a decoded frame into protocol and information fields, handling
+/// address-and-control-field and protocol-field compression.
+pub(crate) fn parse(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let frame = match frame {
+        [ADDRESS, CONTROL, rest @ ..] => rest,
+        _ => frame,
+    };
+    match frame {
+        [p, rest @ ..] if p & 1 == 1 => Some((*p as u16, rest)),
+        [p0, p1, rest @ ..] => Some((u16::from_be_bytes([*p0, *p1]), rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Decode the valid frames in `data`, as `(protocol, information)`.
+    pub(crate) fn decode_all(data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut decoder = Decoder::new();
+        let mut buf = [0; 1600];
+        let mut frames = Vec::new();
+        for &b in data {
+            if let Some(len) = decoder.feed(&mut buf, b) {
+                let (protocol, info) = parse(&buf[..len]).unwrap();
+                frames.push((protocol, info.to_vec()));
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn encode() {
+        let mut buf = [0; 64];
+        let mut encoder = Encoder::new(&mut buf);
+        assert!(encoder.push(0xc021, &[0x09, 0x7e, 0x7d, 0x41]));
+        // Control characters and the flag and escape bytes are escaped, in the FCS too.
+        assert_eq!(
+            encoder.frames(),
+            [0x7e, 0xff, 0x7d, 0x23, 0xc0, 0x21, 0x7d, 0x29, 0x7d, 0x5e, 0x7d, 0x5d, 0x41, 0x7d, 0x23, 0xcf, 0x7e]
+        );
+        let frames = decode_all(encoder.frames());
+        assert_eq!(frames, [(0xc021, [0x09, 0x7e, 0x7d, 0x41].to_vec())]);
+
+        // Frames are appended, and the buffer is left unchanged when one doesn't fit.
+        assert!(encoder.push(0x0021, &[1, 2, 3]));
+        let len = encoder.frames().len();
+        assert!(!encoder.push(0x0021, &[0; 64]));
+        assert_eq!(encoder.frames().len(), len);
+        assert_eq!(decode_all(encoder.frames()).len(), 2);
+        encoder.clear();
+        assert!(encoder.frames().is_empty());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut buf = [0; 64];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder.push(0xc021, &[1, 2, 3, 4]);
+        let frame = encoder.frames().to_vec();
+
+        // Corrupted FCS.
+        let mut corrupted = frame.clone();
+        corrupted[6] ^= 1;
+        assert!(decode_all(&corrupted).is_empty());
+        // Truncated frames, and empty frames between flags.
+        assert!(decode_all(&frame[..5]).is_empty());
+        assert!(decode_all(&[0x7e, 0x7e, 0xff, 0x7e, 0x7d, 0x7e]).is_empty());
+        // A valid frame after garbage is still decoded.
+        let mut data = [0x12, 0x34, 0x7e, 0x55].to_vec();
+        data.extend_from_slice(&frame);
+        assert_eq!(decode_all(&data).len(), 1);
+
+        // Frames that don't fit in the buffer are dropped.
+        let mut decoder = Decoder::new();
+        let mut small = [0; 6];
+        assert!(frame.iter().all(|&b| decoder.feed(&mut small, b).is_none()));
+        // The decoder recovers for the next frame.
+        let mut large = [0; 16];
+        assert_eq!(
+            frame.iter().filter_map(|&b| decoder.feed(&mut large, b)).last(),
+            Some(8)
+        );
+    }
+
+    #[test]
+    fn parse_compressed() {
+        assert_eq!(parse(&[0xff, 0x03, 0xc0, 0x21, 1]), Some((0xc021, &[1][..])));
+        // Address-and-control-field compression.
+        assert_eq!(parse(&[0xc0, 0x21, 1]), Some((0xc021, &[1][..])));
+        // Protocol-field compression.
+        assert_eq!(parse(&[0xff, 0x03, 0x21, 1]), Some((0x0021, &[1][..])));
+        assert_eq!(parse(&[0x21]), Some((0x0021, &[][..])));
+        assert_eq!(parse(&[0xff, 0x03]), None);
+        assert_eq!(parse(&[0xc0]), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Control protocol packets (RFC 1661), shared by LCP and IPCP.
+
+pub(crate) const PROTO_IPV4: u16 = 0x0021;
+pub(crate) const PROTO_LCP: u16 = 0xc021;
+pub(crate) const PROTO_IPCP: u16 = 0x8021;
+
+pub(crate) const CONFIGURE_REQUEST: u8 = 1;
+pub(crate) const CONFIGURE_ACK: u8 = 2;
+pub(crate) const CONFIGURE_NAK: u8 = 3;
+pub(crate) const CONFIGURE_REJECT: u8 = 4;
+pub(crate) const TERMINATE_REQUEST: u8 = 5;
+pub(crate) const TERMINATE_ACK: u8 = 6;
+pub(crate) const CODE_REJECT: u8 = 7;
+pub(crate) const PROTOCOL_REJECT: u8 = 8;
+pub(crate) const ECHO_REQUEST: u8 = 9;
+pub(crate) const ECHO_REPLY: u8 = 10;
+pub(crate) const DISCARD_REQUEST: u8 = 11;
+
+/// Largest control packet built by the runner.
+pub(crate) const MAX_PACKET_LEN: usize = 128;
+
+/// A received control packet.
+pub(crate) struct Packet<'a> {
+    pub code: u8,
+    pub id: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let [code, id, l0, l1, ..] = *buf else {
+            return None;
+        };
+        let len = u16::from_be_bytes([l0, l1]) as usize;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        Some(Self {
+            code,
+            id,
+            data: &buf[4..len],
+        })
+    }
+
+    /// Iterate over the options of a Configure-* packet, as `(type, value)`.
+    ///
+    /// Stops at the first malformed option.
+    pub fn options(&self) -> impl Iterator<Item = (u8, &'a [u8])> {
+        let mut data = self.data;
+        core::iter::from_fn(move || {
+            let [kind, len, ..] = *data else {
+                return None;
+            };
+            let len = len as usize;
+            if len < 2 || len > data.len() {
+                return None;
+            }
+            let value = &data[2..len];
+            data = &data[len..];
+            Some((kind, value))
+        })
+    }
+}
+
+/// Builds a control packet.
+pub(crate) struct Builder {
+    buf: [u8; MAX_PACKET_LEN],
+    len: usize,
+}
+
+impl Builder {
+    pub fn new(code: u8, id: u8) -> Self {
+        let mut buf = [0; MAX_PACKET_LEN];
+        buf[0] = code;
+        buf[1] = id;
+        Self { buf, len: 4 }
+    }
+
+    /// Append raw data. Returns false if it doesn't fit.
+    pub fn data(&mut self, data: &[u8]) -> bool {
+        let Some(dst) = self.buf.get_mut(self.len..self.len + data.len()) else {
+            return false;
+        };
+        dst.copy_from_slice(data);
+        self.len += data.len();
+        true
+    }
+
+    /// Append an option. Returns false if it doesn't fit.
+    pub fn option(&mut self, kind: u8, value: &[u8]) -> bool {
+        self.len + 2 + value.len() <= MAX_PACKET_LEN && self.data(&[kind, 2 + value.len() as u8]) && self.data(value)
+    }
+
+    pub fn finish(&mut self) -> &[u8] {
+        self.buf[2..4].copy_from_slice(&(self.len as u16).to_be_bytes());
+        &self.buf[..self.len]
+    }
+}
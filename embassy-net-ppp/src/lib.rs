@@ -5,17 +5,36 @@
 // must be first
 mod fmt;
 
+mod echo;
+mod hdlc;
+mod lcp;
+mod server;
+
+use core::cell::Cell;
 use core::convert::Infallible;
+use core::future::pending;
 use core::mem::MaybeUninit;
 
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select3, Either3};
 use embassy_net_driver_channel as ch;
 use embassy_net_driver_channel::driver::LinkState;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Timer;
 use embedded_io_async::{BufRead, Write};
 use ppproto::pppos::{BufferFullError, PPPoS, PPPoSAction};
 pub use ppproto::{Config, Ipv4Status};
 
+pub use crate::echo::EchoConfig;
+use crate::echo::{Echo, EchoAction};
+use crate::hdlc::{Decoder, Encoder};
+use crate::lcp::{Packet, PROTO_IPCP, PROTO_IPV4, PROTO_LCP};
+pub use crate::server::ServerConfig;
+use crate::server::{Event, Server};
+
 const MTU: usize = 1500;
+/// Size of the buffer for frames the runner encodes itself: an MTU-sized packet where every byte is escaped.
+const TX_BUF_LEN: usize = 2 * (MTU + 8);
 
 /// Type alias for the embassy-net driver.
 pub type Device<'d> = embassy_net_driver_channel::Device<'d, MTU>;
@@ -23,6 +42,7 @@ pub type Device<'d> = embassy_net_driver_channel::Device<'d, MTU>;
 /// Internal state for the embassy-net integration.
 pub struct State<const N_RX: usize, const N_TX: usize> {
     ch_state: ch::State<MTU, N_RX, N_TX>,
+    stats: Mutex<NoopRawMutex, Cell<Stats>>,
 }
 
 impl<const N_RX: usize, const N_TX: usize> State<N_RX, N_TX> {
@@ -30,6 +50,7 @@ impl<const N_RX: usize, const N_TX: usize> State<N_RX, N_TX> {
     pub const fn new() -> Self {
         Self {
             ch_state: ch::State::new(),
+            stats: Mutex::new(Cell::new(Stats::new())),
         }
     }
 }
@@ -39,9 +60,112 @@ impl<const N_RX: usize, const N_TX: usize> State<N_RX, N_TX> {
 /// You must call `.run()` in a background task for the driver to operate.
 pub struct Runner<'d> {
     ch: ch::Runner<'d, MTU>,
+    stats: &'d Mutex<NoopRawMutex, Cell<Stats>>,
+    echo: Option<EchoConfig>,
+}
+
+/// Link statistics, counted since the driver was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// LCP packets received.
+    pub lcp_rx: u32,
+    /// LCP packets sent.
+    pub lcp_tx: u32,
+    /// IPCP packets received.
+    pub ipcp_rx: u32,
+    /// IPCP packets sent.
+    pub ipcp_tx: u32,
+    /// LCP echo requests sent, see [`Runner::set_echo_config()`].
+    pub echo_requests: u32,
+    /// Replies received to our LCP echo requests.
+    pub echo_replies: u32,
+    /// LCP echo requests that were not answered in time.
+    pub echo_failures: u32,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            lcp_rx: 0,
+            lcp_tx: 0,
+            ipcp_rx: 0,
+            ipcp_tx: 0,
+            echo_requests: 0,
+            echo_replies: 0,
+            echo_failures: 0,
+        }
+    }
+}
+
+/// Reads the link statistics from another task. Obtained with [`Runner::stats_reader()`].
+#[derive(Clone, Copy)]
+pub struct StatsReader<'d> {
+    stats: &'d Mutex<NoopRawMutex, Cell<Stats>>,
+}
+
+impl<'d> StatsReader<'d> {
+    /// Get the current statistics.
+    pub fn get(&self) -> Stats {
+        self.stats.lock(|s| s.get())
+    }
+}
+
+fn update_stats(stats: &Mutex<NoopRawMutex, Cell<Stats>>, f: impl FnOnce(&mut Stats)) {
+    stats.lock(|s| {
+        let mut v = s.get();
+        f(&mut v);
+        s.set(v);
+    })
+}
+
+/// Count a received or transmitted control packet.
+fn count(stats: &Mutex<NoopRawMutex, Cell<Stats>>, protocol: u16, info: &[u8], rx: bool) {
+    update_stats(stats, |s| match (protocol, rx) {
+        (PROTO_LCP, true) => s.lcp_rx += 1,
+        (PROTO_LCP, false) => {
+            s.lcp_tx += 1;
+            if info.first() == Some(&lcp::ECHO_REQUEST) {
+                s.echo_requests += 1;
+            }
+        }
+        (PROTO_IPCP, true) => s.ipcp_rx += 1,
+        (PROTO_IPCP, false) => s.ipcp_tx += 1,
+        _ => {}
+    });
+}
+
+/// Frames built by the runner itself, written to the serial port with [`Tx::flush()`].
+pub(crate) struct Tx<'a> {
+    encoder: Encoder<'a>,
+    stats: &'a Mutex<NoopRawMutex, Cell<Stats>>,
+}
+
+impl<'a> Tx<'a> {
+    fn new(buf: &'a mut [u8], stats: &'a Mutex<NoopRawMutex, Cell<Stats>>) -> Self {
+        Self {
+            encoder: Encoder::new(buf),
+            stats,
+        }
+    }
+
+    pub(crate) fn send(&mut self, protocol: u16, payload: &[u8]) {
+        count(self.stats, protocol, payload, false);
+        if !self.encoder.push(protocol, payload) {
+            warn!("ppp: tx buffer full, dropping frame");
+        }
+    }
+
+    async fn flush<W: Write>(&mut self, w: &mut W) -> Result<(), W::Error> {
+        if !self.encoder.frames().is_empty() {
+            w.write_all(self.encoder.frames()).await?;
+            self.encoder.clear();
+        }
+        Ok(())
+    }
 }
 
-/// Error returned by [`Runner::run`].
+/// Error returned by [`Runner::run`] and [`Runner::run_server`].
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RunError<E> {
@@ -53,9 +177,63 @@ pub enum RunError<E> {
     Eof,
     /// PPP protocol was terminated by the peer
     Terminated,
+    /// The peer did not answer LCP echo requests, see [`Runner::set_echo_config()`].
+    NoEchoReply,
+}
+
+/// Observes the frames exchanged by ppproto, to count the control packets and to match
+/// the replies to our echo requests.
+struct Observer {
+    decoder: Decoder,
+    buf: [u8; lcp::MAX_PACKET_LEN],
+}
+
+impl Observer {
+    const fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            buf: [0; lcp::MAX_PACKET_LEN],
+        }
+    }
+
+    fn observe(&mut self, data: &[u8], rx: bool, stats: &Mutex<NoopRawMutex, Cell<Stats>>, echo: &mut Option<Echo>) {
+        for &b in data {
+            // Data frames don't fit in the buffer, and are dropped by the decoder.
+            let Some(len) = self.decoder.feed(&mut self.buf, b) else {
+                continue;
+            };
+            let Some((protocol, info)) = hdlc::parse(&self.buf[..len]) else {
+                continue;
+            };
+            count(stats, protocol, info, rx);
+            match Packet::parse(info) {
+                Some(p) if rx && protocol == PROTO_LCP && p.code == lcp::ECHO_REPLY => on_echo_reply(stats, echo, p.id),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn on_echo_reply(stats: &Mutex<NoopRawMutex, Cell<Stats>>, echo: &mut Option<Echo>, id: u8) {
+    if echo.as_mut().is_some_and(|e| e.on_reply(id)) {
+        update_stats(stats, |s| s.echo_replies += 1);
+    }
 }
 
 impl<'d> Runner<'d> {
+    /// Send LCP echo requests while the link is up, to detect a dead peer.
+    ///
+    /// When the peer doesn't answer, the run functions return [`RunError::NoEchoReply`].
+    /// Pass `None` to disable, which is the default. Takes effect the next time the link comes up.
+    pub fn set_echo_config(&mut self, config: Option<EchoConfig>) {
+        self.echo = config;
+    }
+
+    /// Get a handle to read the link statistics from another task.
+    pub fn stats_reader(&self) -> StatsReader<'d> {
+        StatsReader { stats: self.stats }
+    }
+
     /// You must call this in a background task for the driver to operate.
     ///
     /// If reading/writing to the underlying serial port fails, the link state
@@ -75,12 +253,19 @@ impl<'d> Runner<'d> {
         let mut ppp = PPPoS::new(config);
         ppp.open().unwrap();
 
+        let stats = self.stats;
+        let echo_config = self.echo;
         let (state_chan, mut rx_chan, mut tx_chan) = self.ch.borrow_split();
         state_chan.set_link_state(LinkState::Down);
         let _ondrop = OnDrop::new(|| state_chan.set_link_state(LinkState::Down));
 
         let mut rx_buf = [0; 2048];
         let mut tx_buf = [0; 2048];
+        let mut echo_buf = [0; 2 * lcp::MAX_PACKET_LEN + 16];
+
+        let mut rx_observer = Observer::new();
+        let mut tx_observer = Observer::new();
+        let mut echo: Option<Echo> = None;
 
         let mut needs_poll = true;
         let mut was_up = false;
@@ -99,12 +284,20 @@ impl<'d> Runner<'d> {
                 Ok((buf, rx_data))
             };
             let tx_fut = tx_chan.tx_buf();
-            match select(rx_fut, tx_fut).await {
-                Either::First(r) => {
+            let echo_at = echo.as_ref().map(|e| e.poll_at());
+            let echo_fut = async {
+                match echo_at {
+                    Some(at) => Timer::at(at).await,
+                    None => pending().await,
+                }
+            };
+            match select3(rx_fut, tx_fut, echo_fut).await {
+                Either3::First(r) => {
                     needs_poll = false;
 
                     let (buf, rx_data) = r?;
                     let n = ppp.consume(rx_data, &mut rx_buf);
+                    rx_observer.observe(&rx_data[..n], true, stats, &mut echo);
                     rw.consume(n);
 
                     match ppp.poll(&mut tx_buf, &mut rx_buf) {
@@ -114,7 +307,10 @@ impl<'d> Runner<'d> {
                             buf[..pkt.len()].copy_from_slice(pkt);
                             rx_chan.rx_done(pkt.len());
                         }
-                        PPPoSAction::Transmit(n) => rw.write_all(&tx_buf[..n]).await.map_err(RunError::Write)?,
+                        PPPoSAction::Transmit(n) => {
+                            tx_observer.observe(&tx_buf[..n], false, stats, &mut echo);
+                            rw.write_all(&tx_buf[..n]).await.map_err(RunError::Write)?
+                        }
                     }
 
                     let status = ppp.status();
@@ -125,23 +321,156 @@ impl<'d> Runner<'d> {
                         ppproto::Phase::Open => {
                             if !was_up {
                                 on_ipv4_up(status.ipv4.unwrap());
+                                echo = echo_config.map(Echo::new);
                             }
                             was_up = true;
                             state_chan.set_link_state(LinkState::Up);
                         }
                         _ => {
                             was_up = false;
+                            echo = None;
                             state_chan.set_link_state(LinkState::Down);
                         }
                     }
                 }
-                Either::Second(pkt) => {
+                Either3::Second(pkt) => {
                     match ppp.send(pkt, &mut tx_buf) {
                         Ok(n) => rw.write_all(&tx_buf[..n]).await.map_err(RunError::Write)?,
                         Err(BufferFullError) => unreachable!(),
                     }
                     tx_chan.tx_done();
                 }
+                Either3::Third(()) => {
+                    let Some(e) = &mut echo else { continue };
+                    match e.poll(stats) {
+                        EchoAction::None => {}
+                        EchoAction::Send(id) => {
+                            // The magic number negotiated by ppproto is not known, zero is allowed.
+                            let mut tx = Tx::new(&mut echo_buf, stats);
+                            tx.send(PROTO_LCP, echo::request(id, 0).finish());
+                            tx.flush(&mut rw).await.map_err(RunError::Write)?;
+                        }
+                        EchoAction::Dead => return Err(RunError::NoEchoReply),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run the server side of the link, for a peer connecting to us, e.g. a host
+    /// using the device as a network adapter over a serial port.
+    ///
+    /// The peer is assigned `config.peer_address`, and is not required to authenticate.
+    /// The link state is set to Up once the IPv4 link is established: configure the stack with
+    /// `config.address` beforehand.
+    ///
+    /// The same rules as for [`run()`](Self::run) apply: it returns on error or when the
+    /// peer terminates the link, and it can be called again to wait for a new connection.
+    pub async fn run_server<RW: BufRead + Write>(
+        &mut self,
+        mut rw: RW,
+        config: ServerConfig,
+    ) -> Result<Infallible, RunError<RW::Error>> {
+        let stats = self.stats;
+        let echo_config = self.echo;
+        let (state_chan, mut rx_chan, mut tx_chan) = self.ch.borrow_split();
+        state_chan.set_link_state(LinkState::Down);
+        let _ondrop = OnDrop::new(|| state_chan.set_link_state(LinkState::Down));
+
+        let mut rx_buf = [0; MTU + 8];
+        let mut tx_buf = [0; TX_BUF_LEN];
+        let mut tx = Tx::new(&mut tx_buf, stats);
+        let mut decoder = Decoder::new();
+        let mut server = Server::new(config);
+        let mut echo: Option<Echo> = None;
+
+        loop {
+            let up = server.is_up();
+            let deadline = [server.poll_at(), echo.as_ref().map(|e| e.poll_at())]
+                .into_iter()
+                .flatten()
+                .min();
+
+            let rx_fut = async {
+                let buf = rx_chan.rx_buf().await;
+                match rw.fill_buf().await {
+                    Ok([]) => Err(RunError::Eof),
+                    Ok(rx_data) => Ok((buf, rx_data)),
+                    Err(e) => Err(RunError::Read(e)),
+                }
+            };
+            let tx_fut = async {
+                match up {
+                    true => tx_chan.tx_buf().await,
+                    false => pending().await,
+                }
+            };
+            let timer_fut = async {
+                match deadline {
+                    Some(at) => Timer::at(at).await,
+                    None => pending().await,
+                }
+            };
+            match select3(rx_fut, tx_fut, timer_fut).await {
+                Either3::First(r) => {
+                    let (buf, rx_data) = r?;
+                    let mut n = 0;
+                    let mut frame = None;
+                    for &b in rx_data {
+                        n += 1;
+                        if let Some(len) = decoder.feed(&mut rx_buf, b) {
+                            frame = Some(len);
+                            break;
+                        }
+                    }
+                    rw.consume(n);
+
+                    let Some((protocol, info)) = frame.and_then(|len| hdlc::parse(&rx_buf[..len])) else {
+                        continue;
+                    };
+                    if protocol == PROTO_IPV4 {
+                        if up && info.len() <= buf.len() {
+                            buf[..info.len()].copy_from_slice(info);
+                            rx_chan.rx_done(info.len());
+                        }
+                        continue;
+                    }
+                    count(stats, protocol, info, true);
+                    match server.handle(protocol, info, &mut tx) {
+                        Event::None => {}
+                        Event::EchoReply(id) => on_echo_reply(stats, &mut echo, id),
+                        Event::Terminated => {
+                            tx.flush(&mut rw).await.map_err(RunError::Write)?;
+                            return Err(RunError::Terminated);
+                        }
+                    }
+                }
+                Either3::Second(pkt) => {
+                    tx.send(PROTO_IPV4, pkt);
+                    tx_chan.tx_done();
+                }
+                Either3::Third(()) => {
+                    server.poll(&mut tx);
+                    if let Some(e) = &mut echo {
+                        match e.poll(stats) {
+                            EchoAction::None => {}
+                            EchoAction::Send(id) => tx.send(PROTO_LCP, echo::request(id, server.magic()).finish()),
+                            EchoAction::Dead => return Err(RunError::NoEchoReply),
+                        }
+                    }
+                }
+            }
+
+            tx.flush(&mut rw).await.map_err(RunError::Write)?;
+
+            if server.is_up() != up {
+                if server.is_up() {
+                    state_chan.set_link_state(LinkState::Up);
+                    echo = echo_config.map(Echo::new);
+                } else {
+                    state_chan.set_link_state(LinkState::Down);
+                    echo = None;
+                }
             }
         }
     }
@@ -151,10 +480,17 @@ impl<'d> Runner<'d> {
 ///
 /// This returns two structs:
 /// - a `Device` that you must pass to the `embassy-net` stack.
-/// - a `Runner`. You must call `.run()` on it in a background task.
+/// - a `Runner`. You must call `.run()` or `.run_server()` on it in a background task.
 pub fn new<'a, const N_RX: usize, const N_TX: usize>(state: &'a mut State<N_RX, N_TX>) -> (Device<'a>, Runner<'a>) {
     let (runner, device) = ch::new(&mut state.ch_state, ch::driver::HardwareAddress::Ip);
-    (device, Runner { ch: runner })
+    (
+        device,
+        Runner {
+            ch: runner,
+            stats: &state.stats,
+            echo: None,
+        },
+    )
 }
 
 struct OnDrop<F: FnOnce()> {
@@ -172,3 +508,96 @@ impl<F: FnOnce()> Drop for OnDrop<F> {
         unsafe { self.f.as_ptr().read()() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+    use crate::echo::EchoAction;
+
+    /// Encode frames into a new buffer.
+    fn frames(buf: &mut [u8], frames: &[(u16, &[u8])]) -> usize {
+        let mut encoder = Encoder::new(buf);
+        for (protocol, payload) in frames {
+            assert!(encoder.push(*protocol, payload));
+        }
+        encoder.frames().len()
+    }
+
+    #[test]
+    fn tx_stats() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut buf = [0; 64];
+        let mut tx = Tx::new(&mut buf, &stats);
+        tx.send(PROTO_LCP, &[lcp::CONFIGURE_REQUEST, 1, 0, 4]);
+        tx.send(PROTO_LCP, &[lcp::ECHO_REQUEST, 2, 0, 8, 0, 0, 0, 0]);
+        tx.send(PROTO_IPCP, &[lcp::CONFIGURE_REQUEST, 1, 0, 4]);
+        tx.send(0x0021, &[0x45, 0, 0, 20]);
+        // Frames that don't fit are still counted as sent.
+        tx.send(PROTO_LCP, &[0; 64]);
+
+        let s = stats.lock(|s| s.get());
+        assert_eq!((s.lcp_tx, s.ipcp_tx, s.echo_requests), (3, 1, 1));
+        assert_eq!((s.lcp_rx, s.ipcp_rx), (0, 0));
+    }
+
+    #[test]
+    fn observer_stats() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut observer = Observer::new();
+        let mut buf = [0; 512];
+        let ip = [0x45; 200];
+        let len = frames(
+            &mut buf,
+            &[
+                (PROTO_LCP, &[lcp::CONFIGURE_REQUEST, 1, 0, 4]),
+                (0x0021, &ip),
+                (PROTO_IPCP, &[lcp::CONFIGURE_ACK, 1, 0, 4]),
+                (PROTO_LCP, &[lcp::ECHO_REQUEST, 2, 0, 8, 0, 0, 0, 0]),
+            ],
+        );
+        // Frames are counted however the data is split.
+        for chunk in buf[..len].chunks(7) {
+            observer.observe(chunk, true, &stats, &mut None);
+        }
+        observer.observe(&buf[..len], false, &stats, &mut None);
+
+        let s = stats.lock(|s| s.get());
+        assert_eq!((s.lcp_rx, s.ipcp_rx), (2, 1));
+        assert_eq!((s.lcp_tx, s.ipcp_tx, s.echo_requests), (2, 1, 1));
+        assert_eq!(s.echo_replies, 0);
+    }
+
+    #[test]
+    fn observer_echo_replies() {
+        let stats = Mutex::new(Cell::new(Stats::new()));
+        let mut observer = Observer::new();
+        let mut echo = Some(Echo::new(EchoConfig {
+            interval: Duration::from_ticks(0),
+            max_failures: 3,
+        }));
+        assert!(matches!(echo.as_mut().unwrap().poll(&stats), EchoAction::Send(1)));
+
+        let mut buf = [0; 64];
+        // A corrupted reply, a reply to another request, and the expected reply.
+        let len = frames(&mut buf, &[(PROTO_LCP, &[lcp::ECHO_REPLY, 9, 0, 8, 0, 0, 0, 0])]);
+        buf[6] ^= 0xff;
+        observer.observe(&buf[..len], true, &stats, &mut echo);
+        let len = frames(&mut buf, &[(PROTO_LCP, &[lcp::ECHO_REPLY, 1, 0, 3])]);
+        observer.observe(&buf[..len], true, &stats, &mut echo);
+        let len = frames(&mut buf, &[(PROTO_LCP, &[lcp::ECHO_REPLY, 2, 0, 8, 0, 0, 0, 0])]);
+        observer.observe(&buf[..len], true, &stats, &mut echo);
+        // Replies we send are not ours to match.
+        let len = frames(&mut buf, &[(PROTO_LCP, &[lcp::ECHO_REPLY, 1, 0, 8, 0, 0, 0, 0])]);
+        observer.observe(&buf[..len], false, &stats, &mut echo);
+        assert_eq!(stats.lock(|s| s.get()).echo_replies, 0);
+
+        observer.observe(&buf[..len], true, &stats, &mut echo);
+        let s = stats.lock(|s| s.get());
+        assert_eq!((s.echo_replies, s.lcp_rx, s.lcp_tx), (1, 3, 1));
+        // Only counted once.
+        observer.observe(&buf[..len], true, &stats, &mut echo);
+        assert_eq!(stats.lock(|s| s.get()).echo_replies, 1);
+    }
+}
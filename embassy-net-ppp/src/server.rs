@@ -0,0 +1,660 @@
+//! Server side of a PPP link: LCP and IPCP negotiation, assigning the peer's address.
+//!
+//! The peer is not required to authenticate.
+
+use core::net::Ipv4Addr;
+
+use embassy_time::{Duration, Instant};
+
+use crate::lcp::{self, Builder, Packet, PROTO_IPCP, PROTO_LCP};
+use crate::{Tx, MTU};
+
+const RESTART_INTERVAL: Duration = Duration::from_secs(3);
+
+const LCP_OPT_MRU: u8 = 1;
+const LCP_OPT_ACCM: u8 = 2;
+const LCP_OPT_MAGIC: u8 = 5;
+
+const IPCP_OPT_ADDRESS: u8 = 3;
+const IPCP_OPT_PRIMARY_DNS: u8 = 129;
+const IPCP_OPT_SECONDARY_DNS: u8 = 131;
+
+/// Configuration of the server side of a PPP link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// Our IPv4 address.
+    pub address: Ipv4Addr,
+    /// IPv4 address assigned to the peer.
+    pub peer_address: Ipv4Addr,
+    /// DNS servers announced to the peer, if it asks for them.
+    pub dns_servers: [Option<Ipv4Addr>; 2],
+}
+
+/// Result of handling a received packet.
+pub(crate) enum Event {
+    None,
+    /// The peer answered one of our echo requests.
+    EchoReply(u8),
+    /// The peer terminated the link.
+    Terminated,
+}
+
+/// Negotiation state of a control protocol.
+#[derive(Clone, Copy, Default)]
+struct Negotiation {
+    /// Identifier of our last Configure-Request.
+    id: u8,
+    ack_received: bool,
+    ack_sent: bool,
+}
+
+impl Negotiation {
+    fn is_open(&self) -> bool {
+        self.ack_received && self.ack_sent
+    }
+}
+
+/// Reply to a Configure-Request, collecting the options to ack, nak or reject.
+struct ConfigureReply {
+    ack: Builder,
+    nak: Builder,
+    reject: Builder,
+    has_nak: bool,
+    has_reject: bool,
+}
+
+impl ConfigureReply {
+    fn new(id: u8) -> Self {
+        Self {
+            ack: Builder::new(lcp::CONFIGURE_ACK, id),
+            nak: Builder::new(lcp::CONFIGURE_NAK, id),
+            reject: Builder::new(lcp::CONFIGURE_REJECT, id),
+            has_nak: false,
+            has_reject: false,
+        }
+    }
+
+    fn ack(&mut self, kind: u8, value: &[u8]) {
+        self.ack.option(kind, value);
+    }
+
+    fn nak(&mut self, kind: u8, value: &[u8]) {
+        self.has_nak |= self.nak.option(kind, value);
+    }
+
+    fn reject(&mut self, kind: u8, value: &[u8]) {
+        self.has_reject |= self.reject.option(kind, value);
+    }
+
+    /// Send the reply. Returns whether all options were acknowledged.
+    fn send(mut self, protocol: u16, tx: &mut Tx<'_>) -> bool {
+        if self.has_reject {
+            tx.send(protocol, self.reject.finish());
+            false
+        } else if self.has_nak {
+            tx.send(protocol, self.nak.finish());
+            false
+        } else {
+            tx.send(protocol, self.ack.finish());
+            true
+        }
+    }
+}
+
+pub(crate) struct Server {
+    config: ServerConfig,
+    magic: u32,
+    send_magic: bool,
+    send_address: bool,
+    lcp: Negotiation,
+    ipcp: Negotiation,
+    next_id: u8,
+    restart_at: Instant,
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            magic: new_magic(0),
+            send_magic: true,
+            send_address: true,
+            lcp: Negotiation::default(),
+            ipcp: Negotiation::default(),
+            next_id: 0,
+            restart_at: Instant::now(),
+        }
+    }
+
+    /// Whether IPv4 packets can be exchanged.
+    pub fn is_up(&self) -> bool {
+        self.lcp.is_open() && self.ipcp.is_open()
+    }
+
+    /// Our magic number, or zero if the peer rejected it.
+    pub fn magic(&self) -> u32 {
+        if self.send_magic {
+            self.magic
+        } else {
+            0
+        }
+    }
+
+    /// When `poll` must be called next.
+    pub fn poll_at(&self) -> Option<Instant> {
+        (!self.is_up()).then_some(self.restart_at)
+    }
+
+    /// Retransmit our Configure-Request if it was not acknowledged in time.
+    pub fn poll(&mut self, tx: &mut Tx<'_>) {
+        if self.is_up() || Instant::now() < self.restart_at {
+            return;
+        }
+        if !self.lcp.ack_received {
+            self.send_lcp_request(tx);
+        } else if self.lcp.is_open() && !self.ipcp.ack_received {
+            self.send_ipcp_request(tx);
+        }
+        self.restart_at = Instant::now() + RESTART_INTERVAL;
+    }
+
+    /// Handle a received frame, other than an IPv4 packet.
+    pub fn handle(&mut self, protocol: u16, info: &[u8], tx: &mut Tx<'_>) -> Event {
+        let lcp_was_open = self.lcp.is_open();
+        let event = match protocol {
+            PROTO_LCP => self.handle_lcp(info, tx),
+            PROTO_IPCP if lcp_was_open => {
+                self.handle_ipcp(info, tx);
+                Event::None
+            }
+            _ if lcp_was_open => {
+                debug!("ppp: rejecting protocol {:04x}", protocol);
+                let mut b = Builder::new(lcp::PROTOCOL_REJECT, self.new_id());
+                b.data(&protocol.to_be_bytes());
+                b.data(&info[..info.len().min(lcp::MAX_PACKET_LEN - 6)]);
+                tx.send(PROTO_LCP, b.finish());
+                Event::None
+            }
+            _ => Event::None,
+        };
+
+        if !lcp_was_open && self.lcp.is_open() {
+            debug!("ppp: LCP open");
+            self.send_ipcp_request(tx);
+        }
+        event
+    }
+
+    fn handle_lcp(&mut self, info: &[u8], tx: &mut Tx<'_>) -> Event {
+        let Some(packet) = Packet::parse(info) else {
+            return Event::None;
+        };
+        match packet.code {
+            lcp::CONFIGURE_REQUEST => {
+                if self.lcp.is_open() {
+                    // The peer restarts the negotiation.
+                    debug!("ppp: LCP renegotiation");
+                    self.lcp = Negotiation::default();
+                    self.ipcp = Negotiation::default();
+                    self.send_lcp_request(tx);
+                }
+                let mut reply = ConfigureReply::new(packet.id);
+                for (kind, value) in packet.options() {
+                    match (kind, value) {
+                        (LCP_OPT_MRU, [m0, m1]) if u16::from_be_bytes([*m0, *m1]) as usize >= MTU => {
+                            reply.ack(kind, value)
+                        }
+                        (LCP_OPT_MRU, [_, _]) => reply.nak(kind, &(MTU as u16).to_be_bytes()),
+                        // All control characters are escaped anyway.
+                        (LCP_OPT_ACCM, [_, _, _, _]) => reply.ack(kind, value),
+                        (LCP_OPT_MAGIC, [m0, m1, m2, m3]) => {
+                            let magic = u32::from_be_bytes([*m0, *m1, *m2, *m3]);
+                            if self.send_magic && magic == self.magic {
+                                // Looped-back link, or the peer picked the same number.
+                                reply.nak(kind, &new_magic(magic).to_be_bytes());
+                            } else {
+                                reply.ack(kind, value);
+                            }
+                        }
+                        // Address-and-control-field and protocol-field compression,
+                        // authentication, and anything else.
+                        _ => reply.reject(kind, value),
+                    }
+                }
+                self.lcp.ack_sent = reply.send(PROTO_LCP, tx);
+            }
+            lcp::CONFIGURE_ACK if packet.id == self.lcp.id => self.lcp.ack_received = true,
+            lcp::CONFIGURE_NAK if packet.id == self.lcp.id => {
+                if packet.options().any(|(kind, _)| kind == LCP_OPT_MAGIC) {
+                    self.magic = new_magic(self.magic);
+                }
+                self.send_lcp_request(tx);
+            }
+            lcp::CONFIGURE_REJECT if packet.id == self.lcp.id => {
+                if packet.options().any(|(kind, _)| kind == LCP_OPT_MAGIC) {
+                    self.send_magic = false;
+                }
+                self.send_lcp_request(tx);
+            }
+            lcp::TERMINATE_REQUEST => {
+                tx.send(PROTO_LCP, Builder::new(lcp::TERMINATE_ACK, packet.id).finish());
+                self.lcp = Negotiation::default();
+                self.ipcp = Negotiation::default();
+                return Event::Terminated;
+            }
+            lcp::ECHO_REQUEST if self.lcp.is_open() => {
+                let mut b = Builder::new(lcp::ECHO_REPLY, packet.id);
+                b.data(&self.magic().to_be_bytes());
+                let data = packet.data.get(4..).unwrap_or(&[]);
+                b.data(&data[..data.len().min(lcp::MAX_PACKET_LEN - 8)]);
+                tx.send(PROTO_LCP, b.finish());
+            }
+            lcp::ECHO_REPLY => return Event::EchoReply(packet.id),
+            lcp::CONFIGURE_ACK
+            | lcp::CONFIGURE_NAK
+            | lcp::CONFIGURE_REJECT
+            | lcp::TERMINATE_ACK
+            | lcp::CODE_REJECT
+            | lcp::PROTOCOL_REJECT
+            | lcp::ECHO_REQUEST
+            | lcp::DISCARD_REQUEST => {}
+            _ => self.send_code_reject(PROTO_LCP, info, tx),
+        }
+        Event::None
+    }
+
+    fn handle_ipcp(&mut self, info: &[u8], tx: &mut Tx<'_>) {
+        let Some(packet) = Packet::parse(info) else {
+            return;
+        };
+        match packet.code {
+            lcp::CONFIGURE_REQUEST => {
+                if self.ipcp.is_open() {
+                    debug!("ppp: IPCP renegotiation");
+                    self.ipcp = Negotiation::default();
+                    self.send_ipcp_request(tx);
+                }
+                let mut reply = ConfigureReply::new(packet.id);
+                for (kind, value) in packet.options() {
+                    let wanted = match kind {
+                        IPCP_OPT_ADDRESS => Some(self.config.peer_address),
+                        IPCP_OPT_PRIMARY_DNS => self.config.dns_servers[0],
+                        IPCP_OPT_SECONDARY_DNS => self.config.dns_servers[1],
+                        _ => None,
+                    };
+                    match wanted {
+                        Some(addr) if value == addr.octets() => reply.ack(kind, value),
+                        Some(addr) if value.len() == 4 => reply.nak(kind, &addr.octets()),
+                        _ => reply.reject(kind, value),
+                    }
+                }
+                self.ipcp.ack_sent = reply.send(PROTO_IPCP, tx);
+            }
+            lcp::CONFIGURE_ACK if packet.id == self.ipcp.id => self.ipcp.ack_received = true,
+            lcp::CONFIGURE_NAK if packet.id == self.ipcp.id => {
+                // The peer suggests another address for us, keep ours.
+                self.send_ipcp_request(tx);
+            }
+            lcp::CONFIGURE_REJECT if packet.id == self.ipcp.id => {
+                if packet.options().any(|(kind, _)| kind == IPCP_OPT_ADDRESS) {
+                    self.send_address = false;
+                }
+                self.send_ipcp_request(tx);
+            }
+            lcp::TERMINATE_REQUEST => {
+                tx.send(PROTO_IPCP, Builder::new(lcp::TERMINATE_ACK, packet.id).finish());
+                self.ipcp = Negotiation::default();
+            }
+            lcp::CONFIGURE_ACK | lcp::CONFIGURE_NAK | lcp::CONFIGURE_REJECT | lcp::TERMINATE_ACK | lcp::CODE_REJECT => {
+            }
+            _ => self.send_code_reject(PROTO_IPCP, info, tx),
+        }
+    }
+
+    fn send_lcp_request(&mut self, tx: &mut Tx<'_>) {
+        self.lcp.id = self.new_id();
+        self.lcp.ack_received = false;
+        let mut b = Builder::new(lcp::CONFIGURE_REQUEST, self.lcp.id);
+        if self.send_magic {
+            b.option(LCP_OPT_MAGIC, &self.magic.to_be_bytes());
+        }
+        tx.send(PROTO_LCP, b.finish());
+        self.restart_at = Instant::now() + RESTART_INTERVAL;
+    }
+
+    fn send_ipcp_request(&mut self, tx: &mut Tx<'_>) {
+        self.ipcp.id = self.new_id();
+        self.ipcp.ack_received = false;
+        let mut b = Builder::new(lcp::CONFIGURE_REQUEST, self.ipcp.id);
+        if self.send_address {
+            b.option(IPCP_OPT_ADDRESS, &self.config.address.octets());
+        }
+        tx.send(PROTO_IPCP, b.finish());
+        self.restart_at = Instant::now() + RESTART_INTERVAL;
+    }
+
+    fn send_code_reject(&mut self, protocol: u16, info: &[u8], tx: &mut Tx<'_>) {
+        let mut b = Builder::new(lcp::CODE_REJECT, self.new_id());
+        b.data(&info[..info.len().min(lcp::MAX_PACKET_LEN - 4)]);
+        tx.send(protocol, b.finish());
+    }
+
+    fn new_id(&mut self) -> u8 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+}
+
+/// Pick a magic number different from `old`, from the current time.
+fn new_magic(old: u32) -> u32 {
+    let t = Instant::now().as_ticks();
+    let mut magic = ((t ^ (t >> 32)) as u32).wrapping_mul(0x9e37_79b9);
+    if magic == 0 || magic == old {
+        magic = old.rotate_left(13) ^ 0x5bd1_e995;
+    }
+    magic
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::blocking_mutex::Mutex;
+
+    use super::*;
+    use crate::hdlc::tests::decode_all;
+    use crate::lcp::*;
+    use crate::Stats;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 53);
+
+    type Frames = Vec<(u16, Vec<u8>)>;
+
+    struct Link {
+        server: Server,
+        stats: Mutex<NoopRawMutex, Cell<Stats>>,
+        buf: [u8; 1024],
+    }
+
+    impl Link {
+        fn new() -> Self {
+            let config = ServerConfig {
+                address: ADDRESS,
+                peer_address: PEER,
+                dns_servers: [Some(DNS), None],
+            };
+            Self {
+                server: Server::new(config),
+                stats: Mutex::new(Cell::new(Stats::new())),
+                buf: [0; 1024],
+            }
+        }
+
+        /// Handle a packet from the peer. Returns the event and the frames sent in response.
+        fn handle(&mut self, protocol: u16, packet: &[u8]) -> (Event, Frames) {
+            let mut tx = Tx::new(&mut self.buf, &self.stats);
+            let event = self.server.handle(protocol, packet, &mut tx);
+            (event, decode_all(tx.encoder.frames()))
+        }
+
+        fn poll(&mut self) -> Frames {
+            let mut tx = Tx::new(&mut self.buf, &self.stats);
+            self.server.poll(&mut tx);
+            decode_all(tx.encoder.frames())
+        }
+
+        fn stats(&self) -> Stats {
+            self.stats.lock(|s| s.get())
+        }
+
+        /// Open LCP and IPCP, with the peer asking for its address.
+        fn open(&mut self) {
+            let sent = self.poll();
+            let lcp_id = sent[0].1[1];
+            self.handle(
+                PROTO_LCP,
+                &packet(CONFIGURE_REQUEST, 1, &[(LCP_OPT_MAGIC, &[1, 2, 3, 4])]),
+            );
+            let (_, sent) = self.handle(PROTO_LCP, &packet(CONFIGURE_ACK, lcp_id, &[]));
+            let ipcp_id = sent[0].1[1];
+            let address = PEER.octets();
+            self.handle(
+                PROTO_IPCP,
+                &packet(CONFIGURE_REQUEST, 2, &[(IPCP_OPT_ADDRESS, &address)]),
+            );
+            self.handle(PROTO_IPCP, &packet(CONFIGURE_ACK, ipcp_id, &[]));
+            assert!(self.server.is_up());
+        }
+    }
+
+    fn packet(code: u8, id: u8, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut b = Builder::new(code, id);
+        for (kind, value) in options {
+            assert!(b.option(*kind, value));
+        }
+        b.finish().to_vec()
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut link = Link::new();
+        assert!(!link.server.is_up());
+
+        // Our LCP request, with a magic number.
+        let sent = link.poll();
+        assert_eq!(sent.len(), 1);
+        let magic = link.server.magic().to_be_bytes();
+        assert_eq!(
+            sent[0],
+            (PROTO_LCP, packet(CONFIGURE_REQUEST, 1, &[(LCP_OPT_MAGIC, &magic)]))
+        );
+
+        // Compression is rejected, before anything else is considered.
+        let accm = [0; 4];
+        let options: [(u8, &[u8]); 4] = [(LCP_OPT_MRU, &[0x05, 0xdc]), (LCP_OPT_ACCM, &accm), (7, &[]), (8, &[])];
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 10, &options));
+        assert_eq!(sent, [(PROTO_LCP, packet(CONFIGURE_REJECT, 10, &options[2..]))]);
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 11, &options[..2]));
+        assert_eq!(sent, [(PROTO_LCP, packet(CONFIGURE_ACK, 11, &options[..2]))]);
+        assert!(!link.server.is_up());
+
+        // IPCP starts once our request is acknowledged too.
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_ACK, 1, &[(LCP_OPT_MAGIC, &magic)]));
+        let address = ADDRESS.octets();
+        assert_eq!(
+            sent,
+            [(
+                PROTO_IPCP,
+                packet(CONFIGURE_REQUEST, 2, &[(IPCP_OPT_ADDRESS, &address)])
+            )]
+        );
+
+        // The peer gets its address and the DNS server it asks for, and other options are rejected.
+        let zero = [0; 4];
+        let options: [(u8, &[u8]); 3] = [
+            (IPCP_OPT_ADDRESS, &zero),
+            (IPCP_OPT_PRIMARY_DNS, &zero),
+            (IPCP_OPT_SECONDARY_DNS, &zero),
+        ];
+        let (_, sent) = link.handle(PROTO_IPCP, &packet(CONFIGURE_REQUEST, 12, &options));
+        assert_eq!(sent, [(PROTO_IPCP, packet(CONFIGURE_REJECT, 12, &options[2..]))]);
+        let (_, sent) = link.handle(PROTO_IPCP, &packet(CONFIGURE_REQUEST, 13, &options[..2]));
+        let (peer, dns) = (PEER.octets(), DNS.octets());
+        let expected = [(IPCP_OPT_ADDRESS, &peer[..]), (IPCP_OPT_PRIMARY_DNS, &dns[..])];
+        assert_eq!(sent, [(PROTO_IPCP, packet(CONFIGURE_NAK, 13, &expected))]);
+        let (_, sent) = link.handle(PROTO_IPCP, &packet(CONFIGURE_REQUEST, 14, &expected));
+        assert_eq!(sent, [(PROTO_IPCP, packet(CONFIGURE_ACK, 14, &expected))]);
+        assert!(!link.server.is_up());
+
+        // Acks to older requests are ignored.
+        link.handle(PROTO_IPCP, &packet(CONFIGURE_ACK, 1, &[]));
+        assert!(!link.server.is_up());
+        link.handle(PROTO_IPCP, &packet(CONFIGURE_ACK, 2, &[]));
+        assert!(link.server.is_up());
+        assert_eq!(link.server.poll_at(), None);
+
+        let stats = link.stats();
+        assert_eq!((stats.lcp_tx, stats.ipcp_tx), (3, 4));
+    }
+
+    #[test]
+    fn retransmission() {
+        let mut link = Link::new();
+        assert!(link.server.poll_at().unwrap() <= Instant::now());
+        assert_eq!(link.poll().len(), 1);
+        // Nothing is sent again before the restart interval.
+        assert!(link.server.poll_at().unwrap() > Instant::now());
+        assert!(link.poll().is_empty());
+    }
+
+    #[test]
+    fn lcp_options() {
+        let mut link = Link::new();
+        link.poll();
+        let magic = link.server.magic();
+
+        // A too small MRU, and our own magic number, are naked.
+        let options: [(u8, &[u8]); 2] = [(LCP_OPT_MRU, &[0x02, 0x00]), (LCP_OPT_MAGIC, &magic.to_be_bytes())];
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 1, &options));
+        let reply = Packet::parse(&sent[0].1).unwrap();
+        assert_eq!(reply.code, CONFIGURE_NAK);
+        let naked: Vec<_> = reply.options().collect();
+        assert_eq!(naked[0], (LCP_OPT_MRU, &[0x05, 0xdc][..]));
+        assert_eq!(naked[1].0, LCP_OPT_MAGIC);
+        assert_ne!(naked[1].1, magic.to_be_bytes());
+
+        // The peer naks our magic number: a new one is picked.
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_NAK, 1, &[(LCP_OPT_MAGIC, &[0; 4])]));
+        assert_ne!(link.server.magic(), magic);
+        let request = Packet::parse(&sent[0].1).unwrap();
+        assert_eq!((request.code, request.id), (CONFIGURE_REQUEST, 2));
+
+        // The peer rejects it: requests go without it.
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REJECT, 2, &[(LCP_OPT_MAGIC, &[0; 4])]));
+        assert_eq!(sent, [(PROTO_LCP, packet(CONFIGURE_REQUEST, 3, &[]))]);
+        assert_eq!(link.server.magic(), 0);
+    }
+
+    #[test]
+    fn echo_and_terminate() {
+        let mut link = Link::new();
+        // Echo requests are only answered once LCP is open.
+        let request = [ECHO_REQUEST, 5, 0, 10, 1, 2, 3, 4, 0xaa, 0xbb];
+        assert!(link.handle(PROTO_LCP, &request).1.is_empty());
+
+        link.open();
+        let magic = link.server.magic().to_be_bytes();
+        let (_, sent) = link.handle(PROTO_LCP, &request);
+        let mut reply = Builder::new(ECHO_REPLY, 5);
+        reply.data(&magic);
+        reply.data(&[0xaa, 0xbb]);
+        assert_eq!(sent, [(PROTO_LCP, reply.finish().to_vec())]);
+
+        let (event, sent) = link.handle(PROTO_LCP, &[ECHO_REPLY, 9, 0, 8, 0, 0, 0, 0]);
+        assert!(matches!(event, Event::EchoReply(9)));
+        assert!(sent.is_empty());
+
+        let (event, sent) = link.handle(PROTO_LCP, &[TERMINATE_REQUEST, 3, 0, 4]);
+        assert!(matches!(event, Event::Terminated));
+        assert_eq!(sent, [(PROTO_LCP, [TERMINATE_ACK, 3, 0, 4].to_vec())]);
+        assert!(!link.server.is_up());
+    }
+
+    #[test]
+    fn renegotiation() {
+        let mut link = Link::new();
+        link.open();
+
+        // The peer restarts IPCP.
+        let address = PEER.octets();
+        let (_, sent) = link.handle(
+            PROTO_IPCP,
+            &packet(CONFIGURE_REQUEST, 20, &[(IPCP_OPT_ADDRESS, &address)]),
+        );
+        assert!(!link.server.is_up());
+        assert_eq!(sent.len(), 2);
+        assert_eq!(Packet::parse(&sent[0].1).unwrap().code, CONFIGURE_REQUEST);
+        assert_eq!(Packet::parse(&sent[1].1).unwrap().code, CONFIGURE_ACK);
+        let id = sent[0].1[1];
+        link.handle(PROTO_IPCP, &packet(CONFIGURE_ACK, id, &[]));
+        assert!(link.server.is_up());
+
+        // The peer restarts LCP: IPCP is negotiated again after it.
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 21, &[]));
+        assert!(!link.server.is_up());
+        assert_eq!(sent[0].0, PROTO_LCP);
+        assert_eq!(Packet::parse(&sent[0].1).unwrap().code, CONFIGURE_REQUEST);
+        assert_eq!(sent[1], (PROTO_LCP, packet(CONFIGURE_ACK, 21, &[])));
+        let (_, sent) = link.handle(
+            PROTO_IPCP,
+            &packet(CONFIGURE_REQUEST, 22, &[(IPCP_OPT_ADDRESS, &address)]),
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn rejects() {
+        let mut link = Link::new();
+        // Before LCP is open, other protocols are ignored.
+        assert!(link.handle(0x8057, &[1, 2, 3]).1.is_empty());
+
+        link.open();
+        let (_, sent) = link.handle(0x8057, &[1, 2, 3]);
+        let reject = Packet::parse(&sent[0].1).unwrap();
+        assert_eq!(reject.code, PROTOCOL_REJECT);
+        assert_eq!(reject.data, [0x80, 0x57, 1, 2, 3]);
+
+        // Unknown codes are rejected.
+        let (_, sent) = link.handle(PROTO_LCP, &[42, 1, 0, 5, 0xff]);
+        let reject = Packet::parse(&sent[0].1).unwrap();
+        assert_eq!(reject.code, CODE_REJECT);
+        assert_eq!(reject.data, [42, 1, 0, 5, 0xff]);
+        let (_, sent) = link.handle(PROTO_IPCP, &[ECHO_REQUEST, 1, 0, 4]);
+        assert_eq!(sent[0].0, PROTO_IPCP);
+        assert_eq!(Packet::parse(&sent[0].1).unwrap().code, CODE_REJECT);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut link = Link::new();
+        link.poll();
+
+        // Packets shorter than their length, or than a header, are ignored.
+        assert!(link
+            .handle(PROTO_LCP, &[CONFIGURE_REQUEST, 1, 0, 10, 1, 4])
+            .1
+            .is_empty());
+        assert!(link.handle(PROTO_LCP, &[CONFIGURE_REQUEST, 1, 0, 3]).1.is_empty());
+        assert!(link.handle(PROTO_LCP, &[CONFIGURE_REQUEST, 1]).1.is_empty());
+        assert!(link.handle(PROTO_LCP, &[]).1.is_empty());
+
+        // Options are read up to the first malformed one.
+        let (_, sent) = link.handle(PROTO_LCP, &[CONFIGURE_REQUEST, 2, 0, 12, 2, 6, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(
+            sent,
+            [(PROTO_LCP, packet(CONFIGURE_ACK, 2, &[(LCP_OPT_ACCM, &[0; 4])]))]
+        );
+        let (_, sent) = link.handle(PROTO_LCP, &[CONFIGURE_REQUEST, 3, 0, 8, 1, 9, 0, 0]);
+        assert_eq!(sent, [(PROTO_LCP, packet(CONFIGURE_ACK, 3, &[]))]);
+
+        // Options with an invalid length are rejected.
+        let (_, sent) = link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 4, &[(LCP_OPT_MRU, &[5])]));
+        assert_eq!(sent, [(PROTO_LCP, packet(CONFIGURE_REJECT, 4, &[(LCP_OPT_MRU, &[5])]))]);
+        link.handle(PROTO_LCP, &packet(CONFIGURE_REQUEST, 5, &[]));
+        link.handle(PROTO_LCP, &packet(CONFIGURE_ACK, 1, &[]));
+        let (_, sent) = link.handle(
+            PROTO_IPCP,
+            &packet(CONFIGURE_REQUEST, 6, &[(IPCP_OPT_ADDRESS, &[10, 0])]),
+        );
+        assert_eq!(
+            sent,
+            [(PROTO_IPCP, packet(CONFIGURE_REJECT, 6, &[(IPCP_OPT_ADDRESS, &[10, 0])]))]
+        );
+    }
+}
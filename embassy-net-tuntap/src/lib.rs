@@ -2,13 +2,15 @@
 #![doc = include_str!("../README.md")]
 use std::io;
 use std::io::{Read, Write};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::task::Context;
 
 use async_io::Async;
-use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState};
+use embassy_net_driver::{Capabilities, Checksum, Driver, HardwareAddress, LinkState};
 use log::*;
 
+mod vnet;
+
 /// Get the MTU of the given interface.
 pub const SIOCGIFMTU: libc::c_ulong = 0x8921;
 /// Get the index of the given interface.
@@ -17,12 +19,18 @@ pub const _SIOCGIFINDEX: libc::c_ulong = 0x8933;
 pub const _ETH_P_ALL: libc::c_short = 0x0003;
 /// Set the interface flags.
 pub const TUNSETIFF: libc::c_ulong = 0x400454CA;
+/// Get the interface name and flags.
+pub const TUNGETIFF: libc::c_ulong = 0x800454D2;
 /// TUN device.
-pub const _IFF_TUN: libc::c_int = 0x0001;
+pub const IFF_TUN: libc::c_int = 0x0001;
 /// TAP device.
 pub const IFF_TAP: libc::c_int = 0x0002;
+/// Several file descriptors (queues) can be attached to the device.
+pub const IFF_MULTI_QUEUE: libc::c_int = 0x0100;
 /// No packet information.
 pub const IFF_NO_PI: libc::c_int = 0x1000;
+/// Packets are prefixed by a `virtio_net_hdr`.
+pub const IFF_VNET_HDR: libc::c_int = 0x4000;
 
 const ETHERNET_HEADER_LEN: usize = 14;
 
@@ -31,13 +39,16 @@ const ETHERNET_HEADER_LEN: usize = 14;
 #[allow(non_camel_case_types)]
 struct ifreq {
     ifr_name: [libc::c_char; libc::IF_NAMESIZE],
-    ifr_data: libc::c_int, /* ifr_ifindex or ifr_mtu */
+    ifr_data: libc::c_int, /* ifr_ifindex, ifr_mtu or ifr_flags */
+    // The kernel copies the whole union, which is larger than `ifr_data`.
+    _padding: [u8; 20],
 }
 
 fn ifreq_for(name: &str) -> ifreq {
     let mut ifreq = ifreq {
         ifr_name: [0; libc::IF_NAMESIZE],
         ifr_data: 0,
+        _padding: [0; 20],
     };
     for (i, byte) in name.as_bytes().iter().enumerate() {
         ifreq.ifr_name[i] = *byte as libc::c_char
@@ -56,11 +67,46 @@ fn ifreq_ioctl(lower: libc::c_int, ifreq: &mut ifreq, cmd: libc::c_ulong) -> io:
     Ok(ifreq.ifr_data)
 }
 
+/// Kind of interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// TUN interface, exchanging IP packets. The driver uses the IP medium.
+    Tun,
+    /// TAP interface, exchanging Ethernet frames. The driver uses the Ethernet medium.
+    Tap,
+}
+
+/// Options for opening a TUN/TAP interface.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Kind of interface.
+    pub mode: Mode,
+    /// Number of queues to open. More than one requires a multi-queue interface:
+    /// if the interface already exists, it must have been created with `multi_queue`.
+    pub queues: usize,
+    /// Enable checksum offload with `IFF_VNET_HDR`: the kernel computes the TCP and UDP
+    /// checksums of the packets sent by the stack.
+    pub vnet_hdr: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Tap,
+            queues: 1,
+            vnet_hdr: false,
+        }
+    }
+}
+
 /// A TUN/TAP device.
 #[derive(Debug)]
 pub struct TunTap {
     fd: libc::c_int,
     mtu: usize,
+    mode: Mode,
+    vnet_hdr: bool,
 }
 
 impl AsRawFd for TunTap {
@@ -70,36 +116,104 @@ impl AsRawFd for TunTap {
 }
 
 impl TunTap {
-    /// Create a new TUN/TAP device.
+    /// Create a new TAP device.
     pub fn new(name: &str) -> io::Result<TunTap> {
+        Self::open(name, &Config::default())
+    }
+
+    /// Open one queue of a TUN/TAP device, creating it if it doesn't exist.
+    ///
+    /// `config.queues` is only used to request a multi-queue device if it's more than one.
+    pub fn open(name: &str, config: &Config) -> io::Result<TunTap> {
         unsafe {
-            let fd = libc::open(
-                "/dev/net/tun\0".as_ptr() as *const libc::c_char,
-                libc::O_RDWR | libc::O_NONBLOCK,
-            );
+            let fd = libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_NONBLOCK);
             if fd == -1 {
                 return Err(io::Error::last_os_error());
             }
+            let device = TunTap {
+                fd,
+                mtu: 0,
+                mode: config.mode,
+                vnet_hdr: config.vnet_hdr,
+            };
 
             let mut ifreq = ifreq_for(name);
-            ifreq.ifr_data = IFF_TAP | IFF_NO_PI;
+            ifreq.ifr_data = IFF_NO_PI;
+            ifreq.ifr_data |= match config.mode {
+                Mode::Tun => IFF_TUN,
+                Mode::Tap => IFF_TAP,
+            };
+            if config.queues > 1 {
+                ifreq.ifr_data |= IFF_MULTI_QUEUE;
+            }
+            if config.vnet_hdr {
+                ifreq.ifr_data |= IFF_VNET_HDR;
+            }
             ifreq_ioctl(fd, &mut ifreq, TUNSETIFF)?;
 
-            let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
-            if socket == -1 {
-                return Err(io::Error::last_os_error());
-            }
+            device.with_mtu(name)
+        }
+    }
 
-            let ip_mtu = ifreq_ioctl(socket, &mut ifreq, SIOCGIFMTU);
-            libc::close(socket);
-            let ip_mtu = ip_mtu? as usize;
+    /// Attach to an existing device, e.g. a persistent interface opened by a privileged process.
+    ///
+    /// `fd` must be attached to the interface already, i.e. `TUNSETIFF` was done on it.
+    /// The mode and the use of `IFF_VNET_HDR` are read from the device.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<TunTap> {
+        let fd = fd.into_raw_fd();
+        let mut device = TunTap {
+            fd,
+            mtu: 0,
+            mode: Mode::Tap,
+            vnet_hdr: false,
+        };
+
+        let mut ifreq = ifreq_for("");
+        ifreq_ioctl(fd, &mut ifreq, TUNGETIFF)?;
+        let flags = ifreq.ifr_data & 0xffff;
+        if flags & IFF_NO_PI == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface has packet information enabled",
+            ));
+        }
+        device.mode = if flags & IFF_TAP != 0 { Mode::Tap } else { Mode::Tun };
+        device.vnet_hdr = flags & IFF_VNET_HDR != 0;
 
-            // SIOCGIFMTU returns the IP MTU (typically 1500 bytes.)
-            // smoltcp counts the entire Ethernet packet in the MTU, so add the Ethernet header size to it.
-            let mtu = ip_mtu + ETHERNET_HEADER_LEN;
+        let len = ifreq.ifr_name.iter().position(|&c| c == 0).unwrap_or(libc::IF_NAMESIZE);
+        let name: Vec<u8> = ifreq.ifr_name[..len].iter().map(|&c| c as u8).collect();
+        let name = String::from_utf8_lossy(&name).into_owned();
+        unsafe { device.with_mtu(&name) }
+    }
+
+    /// The kind of interface.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
 
-            Ok(TunTap { fd, mtu })
+    /// Whether packets are prefixed by a `virtio_net_hdr`.
+    pub fn vnet_hdr(&self) -> bool {
+        self.vnet_hdr
+    }
+
+    unsafe fn with_mtu(mut self, name: &str) -> io::Result<TunTap> {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
+        if socket == -1 {
+            return Err(io::Error::last_os_error());
         }
+
+        let mut ifreq = ifreq_for(name);
+        let ip_mtu = ifreq_ioctl(socket, &mut ifreq, SIOCGIFMTU);
+        libc::close(socket);
+        let ip_mtu = ip_mtu? as usize;
+
+        // SIOCGIFMTU returns the IP MTU (typically 1500 bytes.)
+        // smoltcp counts the entire Ethernet packet in the MTU, so add the Ethernet header size to it.
+        self.mtu = match self.mode {
+            Mode::Tun => ip_mtu,
+            Mode::Tap => ip_mtu + ETHERNET_HEADER_LEN,
+        };
+        Ok(self)
     }
 }
 
@@ -138,17 +252,69 @@ impl io::Write for TunTap {
 }
 
 /// A TUN/TAP device, wrapped in an async interface.
+///
+/// With several queues, received packets are read from all of them in turn.
 pub struct TunTapDevice {
-    device: Async<TunTap>,
+    queues: Vec<Async<TunTap>>,
+    next_rx: usize,
+    mode: Mode,
+    vnet_hdr: bool,
+    mtu: usize,
 }
 
 impl TunTapDevice {
-    /// Create a new TUN/TAP device.
+    /// Create a new TAP device.
     pub fn new(name: &str) -> io::Result<TunTapDevice> {
+        Self::with_config(name, &Config::default())
+    }
+
+    /// Open a TUN/TAP device, creating it if it doesn't exist.
+    pub fn with_config(name: &str, config: &Config) -> io::Result<TunTapDevice> {
+        let queues = (0..config.queues.max(1))
+            .map(|_| TunTap::open(name, config))
+            .collect::<io::Result<Vec<_>>>()?;
+        Self::from_queues(queues)
+    }
+
+    /// Attach to an existing device by file descriptor, see [`TunTap::from_fd()`].
+    pub fn from_fd(fd: OwnedFd) -> io::Result<TunTapDevice> {
+        Self::from_queues([TunTap::from_fd(fd)?])
+    }
+
+    /// Use already opened queues of a device.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no queues, or if they don't have the same mode and `IFF_VNET_HDR` setting.
+    pub fn from_queues(queues: impl IntoIterator<Item = TunTap>) -> io::Result<TunTapDevice> {
+        let queues = queues.into_iter().map(Async::new).collect::<io::Result<Vec<_>>>()?;
+        let first = queues.first().expect("no queues").get_ref();
+        let (mode, vnet_hdr, mtu) = (first.mode, first.vnet_hdr, first.mtu);
+        assert!(
+            queues
+                .iter()
+                .all(|q| q.get_ref().mode == mode && q.get_ref().vnet_hdr == vnet_hdr),
+            "queues have different settings"
+        );
         Ok(Self {
-            device: Async::new(TunTap::new(name)?)?,
+            queues,
+            next_rx: 0,
+            mode,
+            vnet_hdr,
+            mtu,
         })
     }
+
+    fn tx_token(&mut self, queue: usize) -> TxToken<'_> {
+        TxToken {
+            device: &mut self.queues[queue],
+            vnet_hdr: self.vnet_hdr,
+            l3_offset: match self.mode {
+                Mode::Tun => 0,
+                Mode::Tap => ETHERNET_HEADER_LEN,
+            },
+        }
+    }
 }
 
 impl Driver for TunTapDevice {
@@ -162,37 +328,49 @@ impl Driver for TunTapDevice {
         Self: 'a;
 
     fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        let mut buf = vec![0; self.device.get_ref().mtu];
-        loop {
-            match self.device.get_mut().read(&mut buf) {
-                Ok(n) => {
-                    buf.truncate(n);
-                    return Some((
-                        RxToken { buffer: buf },
-                        TxToken {
-                            device: &mut self.device,
-                        },
-                    ));
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    if !self.device.poll_readable(cx).is_ready() {
-                        return None;
+        let header_len = if self.vnet_hdr { vnet::HEADER_LEN } else { 0 };
+        let mut buf = vec![0; header_len + self.mtu];
+        for i in 0..self.queues.len() {
+            let queue = (self.next_rx + i) % self.queues.len();
+            loop {
+                match self.queues[queue].get_mut().read(&mut buf) {
+                    Ok(n) if n < header_len => warn!("dropping packet shorter than the vnet header"),
+                    Ok(n) => {
+                        buf.truncate(n);
+                        let packet = buf.split_off(header_len);
+                        let mut rx = RxToken { buffer: packet };
+                        if header_len > 0 && !vnet::on_rx(&buf, &mut rx.buffer) {
+                            warn!("dropping packet with an invalid vnet header");
+                            buf = vec![0; header_len + self.mtu];
+                            continue;
+                        }
+                        self.next_rx = (queue + 1) % self.queues.len();
+                        return Some((rx, self.tx_token(queue)));
                     }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if !self.queues[queue].poll_readable(cx).is_ready() {
+                            break;
+                        }
+                    }
+                    Err(e) => panic!("read error: {:?}", e),
                 }
-                Err(e) => panic!("read error: {:?}", e),
             }
         }
+        None
     }
 
     fn transmit(&mut self, _cx: &mut Context) -> Option<Self::TxToken<'_>> {
-        Some(TxToken {
-            device: &mut self.device,
-        })
+        Some(self.tx_token(0))
     }
 
     fn capabilities(&self) -> Capabilities {
         let mut caps = Capabilities::default();
-        caps.max_transmission_unit = self.device.get_ref().mtu;
+        caps.max_transmission_unit = self.mtu;
+        if self.vnet_hdr {
+            // Received checksums are still verified, the kernel may not have done it.
+            caps.checksum.tcp = Checksum::Rx;
+            caps.checksum.udp = Checksum::Rx;
+        }
         caps
     }
 
@@ -201,7 +379,10 @@ impl Driver for TunTapDevice {
     }
 
     fn hardware_address(&self) -> HardwareAddress {
-        HardwareAddress::Ethernet([0x02, 0x03, 0x04, 0x05, 0x06, 0x07])
+        match self.mode {
+            Mode::Tun => HardwareAddress::Ip,
+            Mode::Tap => HardwareAddress::Ethernet([0x02, 0x03, 0x04, 0x05, 0x06, 0x07]),
+        }
     }
 }
 
@@ -222,6 +403,8 @@ impl embassy_net_driver::RxToken for RxToken {
 #[doc(hidden)]
 pub struct TxToken<'a> {
     device: &'a mut Async<TunTap>,
+    vnet_hdr: bool,
+    l3_offset: usize,
 }
 
 impl<'a> embassy_net_driver::TxToken for TxToken<'a> {
@@ -229,8 +412,13 @@ impl<'a> embassy_net_driver::TxToken for TxToken<'a> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
+        let header_len = if self.vnet_hdr { vnet::HEADER_LEN } else { 0 };
+        let mut buffer = vec![0; header_len + len];
+        let (header, packet) = buffer.split_at_mut(header_len);
+        let result = f(packet);
+        if self.vnet_hdr {
+            vnet::on_tx(header, packet, self.l3_offset);
+        }
 
         // todo handle WouldBlock with async
         match self.device.get_mut().write(&buffer) {
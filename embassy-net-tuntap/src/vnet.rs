@@ -0,0 +1,120 @@
+//! `virtio_net_hdr` handling, for checksum offload with `IFF_VNET_HDR`.
+//!
+//! Each packet read from or written to the device is prefixed by the header. Sent TCP and UDP
+//! packets carry only the pseudo-header checksum, and the header tells the kernel to complete it.
+
+/// Length of `struct virtio_net_hdr`, the kernel's default header size.
+pub(crate) const HEADER_LEN: usize = 10;
+
+/// The checksum must be completed from `csum_start` to the end of the packet.
+const F_NEEDS_CSUM: u8 = 1;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Add `data` to a ones' complement sum, as big-endian words.
+fn sum(mut acc: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        acc += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [b] = chunks.remainder() {
+        acc += (*b as u32) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+/// Handle the header of a received packet, completing its checksum if the kernel left it partial.
+///
+/// `packet` is the data following the header. Returns false if the header is invalid.
+pub(crate) fn on_rx(header: &[u8], packet: &mut [u8]) -> bool {
+    if header[0] & F_NEEDS_CSUM == 0 {
+        return true;
+    }
+    let start = read_u16(header, 6) as usize;
+    let field = start + read_u16(header, 8) as usize;
+    if field + 2 > packet.len() {
+        return false;
+    }
+    let mut checksum = !fold(sum(0, &packet[start..]));
+    if checksum == 0 {
+        // Zero means no checksum for UDP, and both forms are valid for TCP.
+        checksum = 0xffff;
+    }
+    packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+    true
+}
+
+/// Fill the header of a packet to send.
+///
+/// `l3_offset` is the offset of the IP header in `packet`: 14 for Ethernet frames, 0 for IP packets.
+/// The stack doesn't compute TCP and UDP checksums, so for these the pseudo-header checksum
+/// is stored in the packet and the kernel is asked to complete it.
+pub(crate) fn on_tx(header: &mut [u8], packet: &mut [u8], l3_offset: usize) {
+    header.fill(0);
+    let Some((start, offset)) = checksum_location(packet, l3_offset) else {
+        return;
+    };
+    header[0] = F_NEEDS_CSUM;
+    header[6..8].copy_from_slice(&(start as u16).to_ne_bytes());
+    header[8..10].copy_from_slice(&(offset as u16).to_ne_bytes());
+}
+
+/// Find the TCP or UDP header of `packet`, and store the pseudo-header checksum in its checksum field.
+///
+/// Returns the offset of the TCP or UDP header, and of the checksum field in it.
+fn checksum_location(packet: &mut [u8], l3_offset: usize) -> Option<(usize, usize)> {
+    let ip = packet.get(l3_offset..)?;
+    let version = ip.first()? >> 4;
+    if l3_offset != 0 {
+        let ethertype = u16::from_be_bytes([packet[12], packet[13]]);
+        match (ethertype, version) {
+            (ETHERTYPE_IPV4, 4) | (ETHERTYPE_IPV6, 6) => {}
+            _ => return None,
+        }
+    }
+
+    let (protocol, header_len, pseudo_header) = match version {
+        4 if ip.len() >= 20 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+            let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+            if fragmented || header_len < 20 || total_len < header_len {
+                return None;
+            }
+            let len = (total_len - header_len) as u32;
+            (ip[9], header_len, sum(len + ip[9] as u32, &ip[12..20]))
+        }
+        6 if ip.len() >= 40 => {
+            let len = u16::from_be_bytes([ip[4], ip[5]]) as u32;
+            (ip[6], 40, sum(len + ip[6] as u32, &ip[8..40]))
+        }
+        _ => return None,
+    };
+    let offset = match protocol {
+        PROTO_TCP => 16,
+        PROTO_UDP => 6,
+        _ => return None,
+    };
+
+    let start = l3_offset + header_len;
+    let field = start + offset;
+    packet
+        .get_mut(field..field + 2)?
+        .copy_from_slice(&fold(pseudo_header).to_be_bytes());
+    Some((start, offset))
+}
@@ -0,0 +1,700 @@
+//! IPv4 link-local address autoconfiguration (RFC 3927).
+//!
+//! smoltcp doesn't expose ARP to sockets, so received ARP packets are inspected by an [`ArpWatch`]
+//! called from the driver adapter, and the probes and announcements are sent directly to the driver.
+
+use core::cell::Cell;
+
+use embassy_time::{Duration, Instant};
+use smoltcp::phy::{self, TxToken};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Ipv4Address,
+};
+
+use crate::time::instant_to_smoltcp;
+
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u8 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_CONFLICTS: u32 = 10;
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A conflict seen on the network for the address being probed or claimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Conflict {
+    None,
+    /// Another host is probing the same address.
+    Probe,
+    /// Another host uses the address.
+    Claim,
+}
+
+/// Watches received ARP packets for conflicts with the address being probed or claimed.
+///
+/// It's shared with the driver adapter, so it only uses interior mutability.
+pub(crate) struct ArpWatch {
+    hardware_address: Cell<EthernetAddress>,
+    address: Cell<Option<Ipv4Address>>,
+    conflict: Cell<Conflict>,
+}
+
+impl ArpWatch {
+    pub const fn new() -> Self {
+        Self {
+            hardware_address: Cell::new(EthernetAddress([0; 6])),
+            address: Cell::new(None),
+            conflict: Cell::new(Conflict::None),
+        }
+    }
+
+    fn watch(&self, hardware_address: EthernetAddress, address: Option<Ipv4Address>) {
+        self.hardware_address.set(hardware_address);
+        self.address.set(address);
+        self.conflict.set(Conflict::None);
+    }
+
+    fn take_conflict(&self) -> Conflict {
+        self.conflict.replace(Conflict::None)
+    }
+
+    /// Inspect a received Ethernet frame.
+    pub fn on_rx(&self, frame: &[u8]) {
+        let Some(address) = self.address.get() else {
+            return;
+        };
+        let Ok(frame) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        if frame.ethertype() != EthernetProtocol::Arp {
+            return;
+        }
+        let Ok(ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        }) = ArpPacket::new_checked(frame.payload()).and_then(|p| ArpRepr::parse(&p))
+        else {
+            return;
+        };
+        if source_hardware_addr == self.hardware_address.get() {
+            return;
+        }
+
+        let conflict = if source_protocol_addr == address {
+            Conflict::Claim
+        } else if operation == ArpOperation::Request
+            && source_protocol_addr == Ipv4Address::UNSPECIFIED
+            && target_protocol_addr == address
+        {
+            Conflict::Probe
+        } else {
+            return;
+        };
+        self.conflict.set(self.conflict.get().max(conflict));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Not claiming an address, e.g. because a DHCP lease was obtained.
+    Stopped,
+    /// Waiting before probing, for DHCP or because of too many conflicts.
+    Waiting(Instant),
+    Probing {
+        address: Ipv4Address,
+        sent: u8,
+        next: Instant,
+    },
+    Announcing {
+        address: Ipv4Address,
+        sent: u8,
+        next: Instant,
+    },
+    Bound {
+        address: Ipv4Address,
+    },
+}
+
+/// Link-local address autoconfiguration state of an interface.
+pub(crate) struct AutoIp {
+    hardware_address: EthernetAddress,
+    /// Delay before probing after a reset, to give DHCP a chance.
+    initial_delay: Duration,
+    state: State,
+    conflicts: u32,
+    last_defense: Option<Instant>,
+    rng: u64,
+    /// The address last returned by `poll`.
+    last: Option<Ipv4Address>,
+}
+
+impl AutoIp {
+    pub fn new(hardware_address: EthernetAddress, initial_delay: Duration) -> Self {
+        // Seed with the hardware address, so the same address is picked first after a reboot.
+        let mut seed = [0; 8];
+        seed[2..].copy_from_slice(&hardware_address.0);
+        let mut this = Self {
+            hardware_address,
+            initial_delay,
+            state: State::Stopped,
+            conflicts: 0,
+            last_defense: None,
+            rng: u64::from_be_bytes(seed) | 1,
+            last: None,
+        };
+        this.reset();
+        this
+    }
+
+    /// Start over, probing after the initial delay.
+    pub fn reset(&mut self) {
+        self.state = State::Waiting(Instant::now() + self.initial_delay);
+        self.conflicts = 0;
+        self.last_defense = None;
+        self.last = None;
+    }
+
+    /// Start probing now, e.g. when a DHCP lease was lost.
+    #[cfg(feature = "dhcpv4")]
+    pub fn start(&mut self) {
+        if self.state == State::Stopped {
+            self.state = State::Waiting(Instant::now());
+        }
+    }
+
+    /// Release the address and stop, e.g. when a DHCP lease was obtained.
+    #[cfg(feature = "dhcpv4")]
+    pub fn stop(&mut self) {
+        if self.state != State::Stopped {
+            debug!("IPv4 link-local: stopped");
+        }
+        self.state = State::Stopped;
+        self.last = None;
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state == State::Stopped
+    }
+
+    /// The claimed address.
+    pub fn address(&self) -> Option<Ipv4Address> {
+        match self.state {
+            State::Announcing { address, .. } | State::Bound { address } => Some(address),
+            _ => None,
+        }
+    }
+
+    /// When `poll` must be called next, regardless of received packets.
+    pub fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            State::Waiting(at) => Some(at),
+            State::Probing { next, .. } | State::Announcing { next, .. } => Some(next),
+            State::Stopped | State::Bound { .. } => None,
+        }
+    }
+
+    /// Handle conflicts and timers, and send probes and announcements through `device`.
+    ///
+    /// Returns whether [`address()`](Self::address) changed.
+    pub fn poll<D: phy::Device>(&mut self, watch: &ArpWatch, device: &mut D) -> bool {
+        let now = Instant::now();
+        let conflict = watch.take_conflict();
+
+        match self.state {
+            State::Stopped => {}
+            State::Waiting(at) => {
+                if now >= at {
+                    self.probe_new_address(now);
+                }
+            }
+            State::Probing { address, sent, next } => {
+                if conflict != Conflict::None {
+                    info!("IPv4 link-local: {:?} is in use", address);
+                    self.on_conflict(now);
+                } else if now >= next {
+                    if sent < PROBE_NUM {
+                        if send_arp(device, self.hardware_address, Ipv4Address::UNSPECIFIED, address) {
+                            let next = if sent + 1 == PROBE_NUM {
+                                now + ANNOUNCE_WAIT
+                            } else {
+                                now + self.random_duration(PROBE_MIN, PROBE_MAX)
+                            };
+                            self.state = State::Probing {
+                                address,
+                                sent: sent + 1,
+                                next,
+                            };
+                        }
+                    } else {
+                        self.state = State::Announcing {
+                            address,
+                            sent: 0,
+                            next: now,
+                        };
+                    }
+                }
+            }
+            State::Announcing { address, .. } | State::Bound { address } => {
+                if conflict == Conflict::Claim {
+                    if self.last_defense.is_some_and(|t| now < t + DEFEND_INTERVAL) {
+                        warn!("IPv4 link-local: lost {:?} to another host", address);
+                        self.on_conflict(now);
+                    } else if send_arp(device, self.hardware_address, address, address) {
+                        debug!("IPv4 link-local: defending {:?}", address);
+                        self.last_defense = Some(now);
+                    }
+                }
+            }
+        }
+
+        if let State::Announcing { address, sent, next } = self.state {
+            if now >= next && send_arp(device, self.hardware_address, address, address) {
+                self.state = if sent + 1 == ANNOUNCE_NUM {
+                    State::Bound { address }
+                } else {
+                    State::Announcing {
+                        address,
+                        sent: sent + 1,
+                        next: now + ANNOUNCE_INTERVAL,
+                    }
+                };
+            }
+        }
+
+        let address = match self.state {
+            State::Probing { address, .. } => Some(address),
+            _ => self.address(),
+        };
+        watch.watch(self.hardware_address, address);
+
+        let changed = self.address() != self.last;
+        self.last = self.address();
+        changed
+    }
+
+    fn on_conflict(&mut self, now: Instant) {
+        self.conflicts += 1;
+        self.last_defense = None;
+        if self.conflicts >= MAX_CONFLICTS {
+            self.state = State::Waiting(now + RATE_LIMIT_INTERVAL);
+        } else {
+            self.probe_new_address(now);
+        }
+    }
+
+    fn probe_new_address(&mut self, now: Instant) {
+        // 169.254.1.0 to 169.254.254.255, the first and last 256 addresses are reserved.
+        let offset = (self.next_random() % (254 * 256 - 256)) as u16 + 256;
+        let [hi, lo] = offset.to_be_bytes();
+        let address = Ipv4Address::new(169, 254, hi, lo);
+        debug!("IPv4 link-local: probing {:?}", address);
+        self.state = State::Probing {
+            address,
+            sent: 0,
+            next: now + self.random_duration(Duration::from_ticks(0), PROBE_WAIT),
+        };
+    }
+
+    fn random_duration(&mut self, min: Duration, max: Duration) -> Duration {
+        let span = (max - min).as_ticks();
+        min + Duration::from_ticks(self.next_random() % (span + 1))
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+/// Send an ARP request for `target`, from `sender`. Returns false if the driver had no buffer available.
+///
+/// With an unspecified sender it's a probe, with `sender == target` an announcement.
+fn send_arp<D: phy::Device>(
+    device: &mut D,
+    hardware_address: EthernetAddress,
+    sender: Ipv4Address,
+    target: Ipv4Address,
+) -> bool {
+    let Some(token) = device.transmit(instant_to_smoltcp(Instant::now())) else {
+        return false;
+    };
+    let arp = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Request,
+        source_hardware_addr: hardware_address,
+        source_protocol_addr: sender,
+        target_hardware_addr: EthernetAddress([0; 6]),
+        target_protocol_addr: target,
+    };
+    let eth = EthernetRepr {
+        src_addr: hardware_address,
+        dst_addr: EthernetAddress::BROADCAST,
+        ethertype: EthernetProtocol::Arp,
+    };
+    token.consume(eth.buffer_len() + arp.buffer_len(), |buf| {
+        let mut frame = EthernetFrame::new_unchecked(buf);
+        eth.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use smoltcp::phy::{DeviceCapabilities, Medium, RxToken};
+
+    use super::*;
+
+    const MAC: EthernetAddress = EthernetAddress([2, 0, 0, 0, 0, 1]);
+    const PEER: EthernetAddress = EthernetAddress([2, 0, 0, 0, 0, 9]);
+    const ADDRESS: Ipv4Address = Ipv4Address::new(169, 254, 10, 20);
+
+    /// Device recording the transmitted frames, with no transmit buffer available when `full`.
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<Vec<u8>>,
+        full: bool,
+    }
+
+    struct Token<'a>(&'a mut Vec<Vec<u8>>);
+
+    impl RxToken for Token<'_> {
+        fn consume<R, F: FnOnce(&[u8]) -> R>(self, _f: F) -> R {
+            unreachable!()
+        }
+    }
+
+    impl TxToken for Token<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            let mut frame = vec![0; len];
+            let r = f(&mut frame);
+            self.0.push(frame);
+            r
+        }
+    }
+
+    impl phy::Device for Recorder {
+        type RxToken<'a> = Token<'a>;
+        type TxToken<'a> = Token<'a>;
+
+        fn receive(&mut self, _timestamp: smoltcp::time::Instant) -> Option<(Token<'_>, Token<'_>)> {
+            None
+        }
+
+        fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Token<'_>> {
+            if self.full {
+                None
+            } else {
+                Some(Token(&mut self.sent))
+            }
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut caps = DeviceCapabilities::default();
+            caps.medium = Medium::Ethernet;
+            caps.max_transmission_unit = 1514;
+            caps
+        }
+    }
+
+    impl Recorder {
+        /// The transmitted ARP packets, as `(source, target)` protocol addresses.
+        fn take_arp(&mut self) -> Vec<(Ipv4Address, Ipv4Address)> {
+            self.sent
+                .drain(..)
+                .map(|frame| {
+                    let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+                    assert_eq!(frame.src_addr(), MAC);
+                    assert_eq!(frame.dst_addr(), EthernetAddress::BROADCAST);
+                    assert_eq!(frame.ethertype(), EthernetProtocol::Arp);
+                    match ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap() {
+                        ArpRepr::EthernetIpv4 {
+                            operation: ArpOperation::Request,
+                            source_hardware_addr: MAC,
+                            source_protocol_addr,
+                            target_hardware_addr: EthernetAddress([0, 0, 0, 0, 0, 0]),
+                            target_protocol_addr,
+                        } => (source_protocol_addr, target_protocol_addr),
+                        arp => panic!("unexpected {:?}", arp),
+                    }
+                })
+                .collect()
+        }
+    }
+
+    fn arp(operation: ArpOperation, mac: EthernetAddress, source: Ipv4Address, target: Ipv4Address) -> Vec<u8> {
+        let arp = ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr: mac,
+            source_protocol_addr: source,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: target,
+        };
+        let eth = EthernetRepr {
+            src_addr: mac,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+        let mut buf = vec![0; eth.buffer_len() + arp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        eth.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+        buf
+    }
+
+    fn claim(address: Ipv4Address) -> Vec<u8> {
+        arp(ArpOperation::Reply, PEER, address, address)
+    }
+
+    fn probe(address: Ipv4Address) -> Vec<u8> {
+        arp(ArpOperation::Request, PEER, Ipv4Address::UNSPECIFIED, address)
+    }
+
+    /// Make the pending timer of the state machine expire now.
+    fn expire(autoip: &mut AutoIp) {
+        let now = Instant::now();
+        match &mut autoip.state {
+            State::Waiting(at) => *at = now,
+            State::Probing { next, .. } | State::Announcing { next, .. } => *next = now,
+            State::Stopped | State::Bound { .. } => {}
+        }
+    }
+
+    fn probing(autoip: &AutoIp) -> Option<(Ipv4Address, u8)> {
+        match autoip.state {
+            State::Probing { address, sent, .. } => Some((address, sent)),
+            _ => None,
+        }
+    }
+
+    /// Poll until the address is bound, checking the probes and announcements sent.
+    fn bind(autoip: &mut AutoIp, watch: &ArpWatch, device: &mut Recorder) -> Ipv4Address {
+        expire(autoip);
+        assert!(!autoip.poll(watch, device));
+        let (address, _) = probing(autoip).unwrap();
+        for _ in 0..PROBE_NUM {
+            expire(autoip);
+            assert!(!autoip.poll(watch, device));
+        }
+        assert_eq!(
+            device.take_arp(),
+            [(Ipv4Address::UNSPECIFIED, address); PROBE_NUM as usize]
+        );
+        expire(autoip);
+        assert!(autoip.poll(watch, device));
+        assert_eq!(autoip.address(), Some(address));
+        expire(autoip);
+        assert!(!autoip.poll(watch, device));
+        assert_eq!(autoip.state, State::Bound { address });
+        assert_eq!(device.take_arp(), [(address, address); ANNOUNCE_NUM as usize]);
+        address
+    }
+
+    #[test]
+    fn watch_conflicts() {
+        let watch = ArpWatch::new();
+        // Nothing is reported when no address is watched.
+        watch.on_rx(&claim(ADDRESS));
+        assert_eq!(watch.take_conflict(), Conflict::None);
+
+        watch.watch(MAC, Some(ADDRESS));
+        watch.on_rx(&probe(ADDRESS));
+        assert_eq!(watch.take_conflict(), Conflict::Probe);
+        assert_eq!(watch.take_conflict(), Conflict::None);
+        // Any packet sent from the address is a claim, and claims take precedence over probes.
+        watch.on_rx(&claim(ADDRESS));
+        watch.on_rx(&probe(ADDRESS));
+        assert_eq!(watch.take_conflict(), Conflict::Claim);
+        let other = Ipv4Address::new(169, 254, 1, 1);
+        watch.on_rx(&arp(ArpOperation::Request, PEER, ADDRESS, other));
+        assert_eq!(watch.take_conflict(), Conflict::Claim);
+
+        // Our own packets, requests for the address and packets about other addresses are fine.
+        watch.on_rx(&arp(ArpOperation::Request, MAC, Ipv4Address::UNSPECIFIED, ADDRESS));
+        watch.on_rx(&arp(ArpOperation::Request, MAC, ADDRESS, ADDRESS));
+        watch.on_rx(&arp(ArpOperation::Request, PEER, other, ADDRESS));
+        watch.on_rx(&arp(ArpOperation::Reply, PEER, Ipv4Address::UNSPECIFIED, ADDRESS));
+        watch.on_rx(&claim(other));
+        watch.on_rx(&probe(other));
+        assert_eq!(watch.take_conflict(), Conflict::None);
+
+        // Setting a new address clears the pending conflicts.
+        watch.on_rx(&claim(ADDRESS));
+        watch.watch(MAC, Some(other));
+        assert_eq!(watch.take_conflict(), Conflict::None);
+    }
+
+    #[test]
+    fn watch_malformed() {
+        let watch = ArpWatch::new();
+        watch.watch(MAC, Some(ADDRESS));
+        let frame = claim(ADDRESS);
+
+        // Truncated Ethernet header or ARP packet.
+        for len in [0, 13, 14, 20, frame.len() - 1] {
+            watch.on_rx(&frame[..len]);
+        }
+        // Not ARP.
+        let mut ipv4 = frame.clone();
+        ipv4[12..14].copy_from_slice(&[0x08, 0x00]);
+        watch.on_rx(&ipv4);
+        // Other hardware or protocol types, or address lengths.
+        for (offset, value) in [(14, 0x01), (15, 0x06), (16, 0x86), (18, 8), (19, 16)] {
+            let mut bad = frame.clone();
+            bad[offset] = value;
+            watch.on_rx(&bad);
+        }
+        assert_eq!(watch.take_conflict(), Conflict::None);
+
+        // Trailing padding is fine.
+        let mut padded = frame.clone();
+        padded.resize(60, 0);
+        watch.on_rx(&padded);
+        assert_eq!(watch.take_conflict(), Conflict::Claim);
+    }
+
+    #[test]
+    fn probe_and_announce() {
+        let watch = ArpWatch::new();
+        let mut device = Recorder::default();
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(5));
+        assert!(matches!(autoip.state, State::Waiting(_)));
+        assert!(!autoip.poll(&watch, &mut device));
+        assert!(device.sent.is_empty());
+
+        let address = bind(&mut autoip, &watch, &mut device);
+        let [a, b, c, _] = address.octets();
+        assert_eq!([a, b], [169, 254]);
+        assert!((1..=254).contains(&c));
+        assert_eq!(autoip.poll_at(), None);
+
+        // The same address is picked first after a restart.
+        let mut again = AutoIp::new(MAC, Duration::from_secs(5));
+        expire(&mut again);
+        again.poll(&watch, &mut device);
+        assert_eq!(probing(&again), Some((address, 0)));
+    }
+
+    #[test]
+    fn no_tx_buffer() {
+        let watch = ArpWatch::new();
+        let mut device = Recorder::default();
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(5));
+        expire(&mut autoip);
+        autoip.poll(&watch, &mut device);
+        let (address, _) = probing(&autoip).unwrap();
+
+        // Probes are sent when the driver has a buffer again.
+        device.full = true;
+        expire(&mut autoip);
+        autoip.poll(&watch, &mut device);
+        assert_eq!(probing(&autoip), Some((address, 0)));
+        device.full = false;
+        autoip.poll(&watch, &mut device);
+        assert_eq!(probing(&autoip), Some((address, 1)));
+        assert_eq!(device.take_arp(), [(Ipv4Address::UNSPECIFIED, address)]);
+    }
+
+    #[test]
+    fn probe_conflicts() {
+        let watch = ArpWatch::new();
+        let mut device = Recorder::default();
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(5));
+        expire(&mut autoip);
+        autoip.poll(&watch, &mut device);
+
+        // Another host probing or using the address makes us pick another one.
+        for i in 0..MAX_CONFLICTS - 1 {
+            let (address, _) = probing(&autoip).unwrap();
+            watch.on_rx(&if i % 2 == 0 { probe(address) } else { claim(address) });
+            autoip.poll(&watch, &mut device);
+            let (next, sent) = probing(&autoip).unwrap();
+            assert_ne!(next, address);
+            assert_eq!(sent, 0);
+        }
+        assert_eq!(autoip.conflicts, MAX_CONFLICTS - 1);
+
+        // Too many conflicts, probing is rate limited.
+        let (address, _) = probing(&autoip).unwrap();
+        watch.on_rx(&claim(address));
+        autoip.poll(&watch, &mut device);
+        let State::Waiting(at) = autoip.state else {
+            panic!("not rate limited: {:?}", autoip.state);
+        };
+        assert!(at >= Instant::now() + RATE_LIMIT_INTERVAL - Duration::from_secs(1));
+        // Conflicts reported for the old address are ignored.
+        watch.on_rx(&claim(address));
+        autoip.poll(&watch, &mut device);
+        assert_eq!(autoip.state, State::Waiting(at));
+        assert!(device.sent.is_empty());
+    }
+
+    #[test]
+    fn defend() {
+        let watch = ArpWatch::new();
+        let mut device = Recorder::default();
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(5));
+        let address = bind(&mut autoip, &watch, &mut device);
+
+        // Probes for a bound address are answered by the ARP layer, not a conflict.
+        watch.on_rx(&probe(address));
+        assert!(!autoip.poll(&watch, &mut device));
+        assert!(device.sent.is_empty());
+
+        // The address is defended once.
+        watch.on_rx(&claim(address));
+        assert!(!autoip.poll(&watch, &mut device));
+        assert_eq!(device.take_arp(), [(address, address)]);
+        assert_eq!(autoip.address(), Some(address));
+
+        // It's given up on a second conflict within the defend interval.
+        watch.on_rx(&claim(address));
+        assert!(autoip.poll(&watch, &mut device));
+        assert_eq!(autoip.address(), None);
+        assert_ne!(probing(&autoip).unwrap().0, address);
+        assert!(device.sent.is_empty());
+    }
+
+    #[test]
+    fn defend_later() {
+        let watch = ArpWatch::new();
+        let mut device = Recorder::default();
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(5));
+        let address = bind(&mut autoip, &watch, &mut device);
+
+        watch.on_rx(&claim(address));
+        autoip.poll(&watch, &mut device);
+        // After the defend interval, the address is defended again.
+        autoip.last_defense = Instant::now().checked_sub(DEFEND_INTERVAL);
+        watch.on_rx(&claim(address));
+        assert!(!autoip.poll(&watch, &mut device));
+        assert_eq!(device.take_arp(), [(address, address); 2]);
+        assert_eq!(autoip.state, State::Bound { address });
+    }
+
+    #[test]
+    fn address_range() {
+        let mut autoip = AutoIp::new(MAC, Duration::from_secs(0));
+        for _ in 0..10_000 {
+            autoip.probe_new_address(Instant::now());
+            let (address, _) = probing(&autoip).unwrap();
+            let [a, b, c, _] = address.octets();
+            assert_eq!([a, b], [169, 254]);
+            assert!((1..=254).contains(&c), "{:?}", address);
+        }
+    }
+}
@@ -1,9 +1,94 @@
+use core::marker::PhantomData;
 use core::task::Context;
 
-use embassy_net_driver::{Capabilities, Checksum, Driver, RxToken, TxToken};
+use embassy_net_driver::{Capabilities, Checksum, ChecksumCapabilities, Driver, RxToken, TxToken};
 use smoltcp::phy::{self, Medium};
 use smoltcp::time::Instant;
 
+#[cfg(feature = "autoip")]
+use crate::autoip::ArpWatch;
+#[cfg(feature = "filter")]
+use crate::filter::{self, Action, Filter, Packet};
+#[cfg(feature = "pcap")]
+use crate::pcap::{Capture, Direction, LinkType};
+use crate::stats::Stats;
+
+/// Hooks set on the [`Runner`](crate::Runner), called for every frame exchanged with the driver.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RunnerHooks<'a> {
+    #[cfg(feature = "pcap")]
+    pub capture: Option<&'a dyn Capture>,
+    #[cfg(feature = "filter")]
+    pub filter: Option<&'a dyn Filter>,
+    pub _phantom: PhantomData<&'a ()>,
+}
+
+#[derive(Clone, Copy)]
+struct FrameHooks<'a> {
+    medium: Medium,
+    stats: &'a Stats,
+    #[allow(unused)]
+    runner: RunnerHooks<'a>,
+    #[cfg(feature = "autoip")]
+    arp_watch: Option<&'a ArpWatch>,
+}
+
+impl<'a> FrameHooks<'a> {
+    fn on_rx(&self, frame: &[u8], checksum: &ChecksumCapabilities) {
+        #[cfg(feature = "packet-trace")]
+        trace!("rx: {:?}", frame);
+        self.stats.on_rx(self.medium, frame, checksum);
+        #[cfg(feature = "pcap")]
+        self.capture(Direction::Rx, frame);
+        #[cfg(feature = "autoip")]
+        if let Some(watch) = self.arp_watch {
+            watch.on_rx(frame);
+        }
+    }
+
+    fn on_tx(&self, frame: &[u8]) {
+        #[cfg(feature = "packet-trace")]
+        trace!("tx: {:?}", frame);
+        self.stats.on_tx(frame);
+        #[cfg(feature = "pcap")]
+        self.capture(Direction::Tx, frame);
+    }
+
+    /// Run the packet filter on a frame. Returns whether it's accepted.
+    #[cfg(feature = "filter")]
+    fn accept(&self, direction: filter::Direction, frame: &[u8]) -> bool {
+        let Some(filter) = self.runner.filter else {
+            return true;
+        };
+        match filter.filter(&Packet::parse(self.medium, direction, frame)) {
+            Action::Accept => true,
+            Action::Drop => {
+                match direction {
+                    filter::Direction::Rx => self.stats.on_rx_filtered(),
+                    filter::Direction::Tx => self.stats.on_tx_filtered(),
+                }
+                false
+            }
+        }
+    }
+
+    #[cfg(feature = "pcap")]
+    fn capture(&self, direction: Direction, frame: &[u8]) {
+        let Some(capture) = self.runner.capture else {
+            return;
+        };
+        let link_type = match self.medium {
+            #[cfg(feature = "medium-ethernet")]
+            Medium::Ethernet => LinkType::Ethernet,
+            #[cfg(feature = "medium-ip")]
+            Medium::Ip => LinkType::Ip,
+            #[cfg(feature = "medium-ieee802154")]
+            Medium::Ieee802154 => LinkType::Ieee802154,
+        };
+        capture.capture(embassy_time::Instant::now(), direction, link_type, frame);
+    }
+}
+
 pub(crate) struct DriverAdapter<'d, 'c, T>
 where
     T: Driver,
@@ -12,6 +97,26 @@ where
     pub cx: Option<&'d mut Context<'c>>,
     pub inner: &'d mut T,
     pub medium: Medium,
+    pub stats: &'d Stats,
+    pub hooks: RunnerHooks<'d>,
+    /// Inspects received ARP packets for address conflicts.
+    #[cfg(feature = "autoip")]
+    pub arp_watch: Option<&'d ArpWatch>,
+}
+
+impl<'d, 'c, T> DriverAdapter<'d, 'c, T>
+where
+    T: Driver,
+{
+    fn frame_hooks(&self) -> FrameHooks<'d> {
+        FrameHooks {
+            medium: self.medium,
+            stats: self.stats,
+            runner: self.hooks,
+            #[cfg(feature = "autoip")]
+            arp_watch: self.arp_watch,
+        }
+    }
 }
 
 impl<'d, 'c, T> phy::Device for DriverAdapter<'d, 'c, T>
@@ -19,23 +124,39 @@ where
     T: Driver,
 {
     type RxToken<'a>
-        = RxTokenAdapter<T::RxToken<'a>>
+        = RxTokenAdapter<'a, T::RxToken<'a>>
     where
         Self: 'a;
     type TxToken<'a>
-        = TxTokenAdapter<T::TxToken<'a>>
+        = TxTokenAdapter<'a, T::TxToken<'a>>
     where
         Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        self.inner
-            .receive(unwrap!(self.cx.as_deref_mut()))
-            .map(|(rx, tx)| (RxTokenAdapter(rx), TxTokenAdapter(tx)))
+        let checksum = self.inner.capabilities().checksum;
+        let hooks = self.frame_hooks();
+        self.inner.receive(unwrap!(self.cx.as_deref_mut())).map(|(rx, tx)| {
+            (
+                RxTokenAdapter {
+                    inner: rx,
+                    hooks,
+                    checksum,
+                },
+                TxTokenAdapter { inner: tx, hooks },
+            )
+        })
     }
 
     /// Construct a transmit token.
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        self.inner.transmit(unwrap!(self.cx.as_deref_mut())).map(TxTokenAdapter)
+        let hooks = self.frame_hooks();
+        match self.inner.transmit(unwrap!(self.cx.as_deref_mut())) {
+            Some(tx) => Some(TxTokenAdapter { inner: tx, hooks }),
+            None => {
+                hooks.stats.on_tx_exhausted();
+                None
+            }
+        }
     }
 
     /// Get a description of device capabilities.
@@ -52,6 +173,11 @@ where
         let mut smolcaps = phy::DeviceCapabilities::default();
 
         smolcaps.max_transmission_unit = caps.max_transmission_unit;
+        // Transmitted frames must fit in the buffer they're filtered in.
+        #[cfg(feature = "filter")]
+        {
+            smolcaps.max_transmission_unit = smolcaps.max_transmission_unit.min(filter::TX_BUFFER_LEN);
+        }
         smolcaps.max_burst_size = caps.max_burst_size;
         smolcaps.medium = self.medium;
         smolcaps.checksum.ipv4 = convert(caps.checksum.ipv4);
@@ -70,11 +196,16 @@ where
     }
 }
 
-pub(crate) struct RxTokenAdapter<T>(T)
+pub(crate) struct RxTokenAdapter<'a, T>
 where
-    T: RxToken;
+    T: RxToken,
+{
+    inner: T,
+    hooks: FrameHooks<'a>,
+    checksum: ChecksumCapabilities,
+}
 
-impl<T> phy::RxToken for RxTokenAdapter<T>
+impl<'a, T> phy::RxToken for RxTokenAdapter<'a, T>
 where
     T: RxToken,
 {
@@ -82,19 +213,27 @@ where
     where
         F: FnOnce(&[u8]) -> R,
     {
-        self.0.consume(|buf| {
-            #[cfg(feature = "packet-trace")]
-            trace!("rx: {:?}", buf);
+        self.inner.consume(|buf| {
+            // smoltcp discards empty frames as malformed.
+            #[cfg(feature = "filter")]
+            if !self.hooks.accept(filter::Direction::Rx, buf) {
+                return f(&[]);
+            }
+            self.hooks.on_rx(buf, &self.checksum);
             f(buf)
         })
     }
 }
 
-pub(crate) struct TxTokenAdapter<T>(T)
+pub(crate) struct TxTokenAdapter<'a, T>
 where
-    T: TxToken;
+    T: TxToken,
+{
+    inner: T,
+    hooks: FrameHooks<'a>,
+}
 
-impl<T> phy::TxToken for TxTokenAdapter<T>
+impl<'a, T> phy::TxToken for TxTokenAdapter<'a, T>
 where
     T: TxToken,
 {
@@ -102,11 +241,156 @@ where
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        self.0.consume(len, |buf| {
+        #[cfg(feature = "filter")]
+        if self.hooks.runner.filter.is_some() {
+            return self.consume_filtered(len, f);
+        }
+
+        self.inner.consume(len, |buf| {
             let r = f(buf);
-            #[cfg(feature = "packet-trace")]
-            trace!("tx: {:?}", buf);
+            self.hooks.on_tx(buf);
             r
         })
     }
 }
+
+#[cfg(feature = "filter")]
+impl<'a, T> TxTokenAdapter<'a, T>
+where
+    T: TxToken,
+{
+    /// Render the frame in a scratch buffer, and only hand it to the driver if the filter accepts it.
+    /// Dropped frames are never handed to the driver, whose token is discarded.
+    ///
+    /// Not inlined, so that the scratch buffer is only on the stack when a filter is set.
+    #[inline(never)]
+    fn consume_filtered<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // The MTU given to smoltcp is at most `TX_BUFFER_LEN`, so frames always fit.
+        let mut scratch = [0; filter::TX_BUFFER_LEN];
+        let frame = &mut scratch[..len];
+        let r = f(frame);
+        if self.hooks.accept(filter::Direction::Tx, frame) {
+            self.inner.consume(len, |buf| {
+                buf.copy_from_slice(frame);
+                self.hooks.on_tx(buf);
+            });
+        }
+        r
+    }
+}
+
+#[cfg(all(test, feature = "filter", feature = "medium-ip"))]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    use embassy_net_driver::{HardwareAddress, LinkState};
+    use phy::{Device, TxToken as _};
+
+    use super::*;
+    use crate::filter::RuleTable;
+
+    /// Driver counting the frames it transmits.
+    struct Counting<'a> {
+        sent: &'a Cell<usize>,
+    }
+
+    struct Token<'a> {
+        sent: &'a Cell<usize>,
+    }
+
+    impl RxToken for Token<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, f: F) -> R {
+            f(&mut [])
+        }
+    }
+
+    impl TxToken for Token<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            self.sent.set(self.sent.get() + 1);
+            f(&mut std::vec![0; len])
+        }
+    }
+
+    impl Driver for Counting<'_> {
+        type RxToken<'a>
+            = Token<'a>
+        where
+            Self: 'a;
+        type TxToken<'a>
+            = Token<'a>
+        where
+            Self: 'a;
+
+        fn receive(&mut self, _cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+            None
+        }
+
+        fn transmit(&mut self, _cx: &mut Context) -> Option<Self::TxToken<'_>> {
+            Some(Token { sent: self.sent })
+        }
+
+        fn link_state(&mut self, _cx: &mut Context) -> LinkState {
+            LinkState::Up
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            let mut caps = Capabilities::default();
+            caps.max_transmission_unit = 9000;
+            caps
+        }
+
+        fn hardware_address(&self) -> HardwareAddress {
+            HardwareAddress::Ip
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        const VTABLE: RawWakerVTable =
+            RawWakerVTable::new(|_| RawWaker::new(core::ptr::null(), &VTABLE), |_| {}, |_| {}, |_| {});
+        unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn filtered_tx() {
+        let sent = Cell::new(0);
+        let mut driver = Counting { sent: &sent };
+        let stats = Stats::new();
+        let table = RuleTable::<0>::new(Action::Drop);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut adapter = DriverAdapter {
+            cx: Some(&mut cx),
+            inner: &mut driver,
+            medium: Medium::Ip,
+            stats: &stats,
+            hooks: RunnerHooks {
+                filter: Some(&table),
+                ..Default::default()
+            },
+            #[cfg(feature = "autoip")]
+            arp_watch: None,
+        };
+
+        // Frames larger than the buffer they're filtered in are never rendered.
+        assert_eq!(adapter.capabilities().max_transmission_unit, filter::TX_BUFFER_LEN);
+
+        // Dropped frames aren't handed to the driver.
+        let token = adapter.transmit(Instant::ZERO).unwrap();
+        assert_eq!(token.consume(20, |buf| buf.len()), 20);
+        assert_eq!(sent.get(), 0);
+        assert_eq!(table.counters().tx_dropped, 1);
+        #[cfg(feature = "stats")]
+        assert_eq!(stats.get().tx_filtered, 1);
+
+        table.set_default(Action::Accept);
+        let token = adapter.transmit(Instant::ZERO).unwrap();
+        token.consume(20, |buf| buf.fill(1));
+        assert_eq!(sent.get(), 1);
+    }
+}
@@ -0,0 +1,563 @@
+//! Packet filtering.
+//!
+//! Attach a [`Filter`] to a [`Runner`](crate::Runner) with [`Runner::set_filter()`](crate::Runner::set_filter)
+//! to decide, for every frame exchanged with the driver, whether it's accepted or dropped. Received frames
+//! are filtered before the stack processes them, transmitted frames before they're handed to the driver.
+//!
+//! [`RuleTable`] is a ready-made filter, matching frames against an ordered list of [`Rule`]s.
+//!
+//! For example, to only accept TCP connections to port 443 from the 192.168.1.0/24 subnet, and the traffic
+//! needed to operate the interface:
+//!
+//! ```ignore
+//! static FILTER: RuleTable<4> = RuleTable::new(Action::Drop);
+//! FILTER.push(Rule::new(Action::Accept).direction(Direction::Tx)).unwrap();
+//! FILTER.push(Rule::new(Action::Accept).non_ip()).unwrap();
+//! FILTER.push(
+//!     Rule::new(Action::Accept)
+//!         .protocol(IpProtocol::Tcp)
+//!         .src(Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 0), 24).into())
+//!         .dst_port(443),
+//! ).unwrap();
+//! runner.set_filter(Some(&FILTER));
+//! ```
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::Vec;
+use smoltcp::phy::Medium;
+pub use smoltcp::wire::IpProtocol;
+use smoltcp::wire::{TcpPacket, UdpPacket};
+
+use crate::stats::parse_ip;
+use crate::{IpAddress, IpCidr};
+
+/// Size of the buffer transmitted frames are rendered in before being filtered.
+///
+/// Frames are only handed to the driver once the filter accepted them, so that dropped frames are
+/// never transmitted. With the `filter` feature, the MTU of interfaces is limited to this size.
+pub const TX_BUFFER_LEN: usize = 1536;
+
+/// Direction of a filtered frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// The frame was received from the driver.
+    Rx,
+    /// The frame is about to be handed to the driver for transmission.
+    Tx,
+}
+
+/// Verdict of a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Let the frame through.
+    Accept,
+    /// Drop the frame.
+    Drop,
+}
+
+/// A frame to filter, with its IP layer parsed.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Packet<'a> {
+    /// Direction of the frame.
+    pub direction: Direction,
+    /// The whole frame, as exchanged with the driver.
+    pub frame: &'a [u8],
+    /// Source address, `None` if the frame doesn't carry IP.
+    pub src: Option<IpAddress>,
+    /// Destination address, `None` if the frame doesn't carry IP.
+    pub dst: Option<IpAddress>,
+    /// IP protocol, `None` if the frame doesn't carry IP.
+    pub protocol: Option<IpProtocol>,
+    /// TCP or UDP source port. `None` for other protocols, and for IP fragments.
+    pub src_port: Option<u16>,
+    /// TCP or UDP destination port. `None` for other protocols, and for IP fragments.
+    pub dst_port: Option<u16>,
+}
+
+impl<'a> Packet<'a> {
+    pub(crate) fn parse(medium: Medium, direction: Direction, frame: &'a [u8]) -> Self {
+        let mut packet = Packet {
+            direction,
+            frame,
+            src: None,
+            dst: None,
+            protocol: None,
+            src_port: None,
+            dst_port: None,
+        };
+        let Some(ip) = parse_ip(medium, frame) else {
+            return packet;
+        };
+        packet.src = Some(ip.src);
+        packet.dst = Some(ip.dst);
+        packet.protocol = Some(ip.protocol);
+        if !ip.fragment {
+            let ports = match ip.protocol {
+                IpProtocol::Tcp => TcpPacket::new_checked(ip.payload)
+                    .ok()
+                    .map(|p| (p.src_port(), p.dst_port())),
+                IpProtocol::Udp => UdpPacket::new_checked(ip.payload)
+                    .ok()
+                    .map(|p| (p.src_port(), p.dst_port())),
+                _ => None,
+            };
+            if let Some((src, dst)) = ports {
+                packet.src_port = Some(src);
+                packet.dst_port = Some(dst);
+            }
+        }
+        packet
+    }
+}
+
+/// A packet filter.
+///
+/// This is called synchronously from the network stack's poll loop, for every frame received or
+/// transmitted by the interface, so it must be fast and must not block.
+pub trait Filter {
+    /// Decide whether a frame is accepted or dropped.
+    fn filter(&self, packet: &Packet<'_>) -> Action;
+}
+
+/// A filtering rule: an action, and the conditions a frame must meet for the rule to apply.
+///
+/// A rule without conditions matches every frame. Rules with conditions on the IP layer never match
+/// frames that don't carry IP, such as ARP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rule {
+    action: Action,
+    direction: Option<Direction>,
+    non_ip: bool,
+    protocol: Option<IpProtocol>,
+    src: Option<IpCidr>,
+    dst: Option<IpCidr>,
+    src_ports: Option<(u16, u16)>,
+    dst_ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    /// Create a rule applying `action` to every frame.
+    pub const fn new(action: Action) -> Self {
+        Self {
+            action,
+            direction: None,
+            non_ip: false,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_ports: None,
+            dst_ports: None,
+        }
+    }
+
+    /// Only match frames going in this direction.
+    pub const fn direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Only match frames that don't carry IP, such as ARP.
+    pub const fn non_ip(mut self) -> Self {
+        self.non_ip = true;
+        self
+    }
+
+    /// Only match IP packets of this protocol.
+    pub const fn protocol(mut self, protocol: IpProtocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Only match IP packets with a source address in this subnet.
+    pub const fn src(mut self, cidr: IpCidr) -> Self {
+        self.src = Some(cidr);
+        self
+    }
+
+    /// Only match IP packets with a destination address in this subnet.
+    pub const fn dst(mut self, cidr: IpCidr) -> Self {
+        self.dst = Some(cidr);
+        self
+    }
+
+    /// Only match TCP or UDP packets from this source port.
+    pub const fn src_port(self, port: u16) -> Self {
+        self.src_ports(port, port)
+    }
+
+    /// Only match TCP or UDP packets with a source port between `first` and `last`, inclusive.
+    pub const fn src_ports(mut self, first: u16, last: u16) -> Self {
+        self.src_ports = Some((first, last));
+        self
+    }
+
+    /// Only match TCP or UDP packets to this destination port.
+    pub const fn dst_port(self, port: u16) -> Self {
+        self.dst_ports(port, port)
+    }
+
+    /// Only match TCP or UDP packets with a destination port between `first` and `last`, inclusive.
+    pub const fn dst_ports(mut self, first: u16, last: u16) -> Self {
+        self.dst_ports = Some((first, last));
+        self
+    }
+
+    /// The action applied to matching frames.
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Whether a frame meets the rule's conditions.
+    pub fn matches(&self, packet: &Packet<'_>) -> bool {
+        fn port_in(port: Option<u16>, range: Option<(u16, u16)>) -> bool {
+            match range {
+                None => true,
+                Some((first, last)) => port.is_some_and(|p| first <= p && p <= last),
+            }
+        }
+        fn addr_in(addr: Option<IpAddress>, cidr: Option<IpCidr>) -> bool {
+            match cidr {
+                None => true,
+                Some(cidr) => addr.is_some_and(|a| cidr.contains_addr(&a)),
+            }
+        }
+
+        if self.direction.is_some_and(|d| d != packet.direction) {
+            return false;
+        }
+        if self.non_ip && packet.protocol.is_some() {
+            return false;
+        }
+        if self.protocol.is_some() && self.protocol != packet.protocol {
+            return false;
+        }
+        addr_in(packet.src, self.src)
+            && addr_in(packet.dst, self.dst)
+            && port_in(packet.src_port, self.src_ports)
+            && port_in(packet.dst_port, self.dst_ports)
+    }
+}
+
+/// Counters of a [`RuleTable`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Counters {
+    /// Number of received frames dropped.
+    pub rx_dropped: u64,
+    /// Number of transmitted frames dropped.
+    pub tx_dropped: u64,
+    /// Number of frames that matched no rule, to which the default action was applied.
+    pub default_hits: u64,
+}
+
+struct Table<const N: usize> {
+    rules: Vec<Rule, N>,
+    /// Number of frames that matched each rule.
+    hits: [u64; N],
+    default: Action,
+    counters: Counters,
+}
+
+/// A filter applying the first matching rule of an ordered list, or a default action if none matches.
+///
+/// The rules can be changed while the filter is in use.
+pub struct RuleTable<const N: usize> {
+    table: Mutex<NoopRawMutex, RefCell<Table<N>>>,
+}
+
+impl<const N: usize> RuleTable<N> {
+    /// Create an empty table, applying `default` to every frame.
+    pub const fn new(default: Action) -> Self {
+        Self {
+            table: Mutex::new(RefCell::new(Table {
+                rules: Vec::new(),
+                hits: [0; N],
+                default,
+                counters: Counters {
+                    rx_dropped: 0,
+                    tx_dropped: 0,
+                    default_hits: 0,
+                },
+            })),
+        }
+    }
+
+    /// Append a rule. Returns the rule back if the table is full.
+    pub fn push(&self, rule: Rule) -> Result<(), Rule> {
+        self.with(|t| {
+            t.rules.push(rule)?;
+            t.hits[t.rules.len() - 1] = 0;
+            Ok(())
+        })
+    }
+
+    /// Remove all rules.
+    pub fn clear(&self) {
+        self.with(|t| t.rules.clear())
+    }
+
+    /// Set the action applied to frames that match no rule.
+    pub fn set_default(&self, action: Action) {
+        self.with(|t| t.default = action)
+    }
+
+    /// Get the number of frames that matched the rule at `index`, in the order rules were pushed.
+    pub fn hits(&self, index: usize) -> Option<u64> {
+        self.with(|t| (index < t.rules.len()).then(|| t.hits[index]))
+    }
+
+    /// Get a snapshot of the counters.
+    pub fn counters(&self) -> Counters {
+        self.with(|t| t.counters)
+    }
+
+    /// Reset the counters, and the number of hits of every rule, to zero.
+    pub fn reset_counters(&self) {
+        self.with(|t| {
+            t.counters = Counters::default();
+            t.hits = [0; N];
+        })
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Table<N>) -> R) -> R {
+        self.table.lock(|t| f(&mut t.borrow_mut()))
+    }
+}
+
+impl<const N: usize> Filter for RuleTable<N> {
+    fn filter(&self, packet: &Packet<'_>) -> Action {
+        self.with(|t| {
+            let action = match t.rules.iter().position(|r| r.matches(packet)) {
+                Some(i) => {
+                    t.hits[i] = t.hits[i].wrapping_add(1);
+                    t.rules[i].action
+                }
+                None => {
+                    t.counters.default_hits = t.counters.default_hits.wrapping_add(1);
+                    t.default
+                }
+            };
+            if action == Action::Drop {
+                let c = match packet.direction {
+                    Direction::Rx => &mut t.counters.rx_dropped,
+                    Direction::Tx => &mut t.counters.tx_dropped,
+                };
+                *c = c.wrapping_add(1);
+            }
+            action
+        })
+    }
+}
+
+#[cfg(all(test, feature = "medium-ip", feature = "proto-ipv4"))]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{Ipv4Address, Ipv4Cidr};
+
+    /// IPv4 packet from `10.0.0.<src>` to `10.0.0.<dst>`, with the first 4 bytes of a TCP or UDP header
+    /// holding the ports.
+    fn ipv4(protocol: IpProtocol, src: u8, dst: u8, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let header_len = match protocol {
+            IpProtocol::Tcp => 20,
+            _ => 8,
+        };
+        let len = 20 + header_len;
+        let mut p = std::vec![0; len];
+        p[..20].copy_from_slice(&[
+            0x45,
+            0,
+            0,
+            len as u8,
+            0,
+            0,
+            0,
+            0,
+            64,
+            protocol.into(),
+            0,
+            0,
+            10,
+            0,
+            0,
+            src,
+            10,
+            0,
+            0,
+            dst,
+        ]);
+        p[20..22].copy_from_slice(&src_port.to_be_bytes());
+        p[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        match protocol {
+            // Data offset.
+            IpProtocol::Tcp => p[32] = 5 << 4,
+            // Length.
+            _ => p[24..26].copy_from_slice(&(header_len as u16).to_be_bytes()),
+        }
+        p
+    }
+
+    fn packet(direction: Direction, frame: &[u8]) -> Packet<'_> {
+        Packet::parse(Medium::Ip, direction, frame)
+    }
+
+    fn subnet(last: u8, prefix: u8) -> IpCidr {
+        Ipv4Cidr::new(Ipv4Address::new(10, 0, 0, last), prefix).into()
+    }
+
+    #[test]
+    fn parse() {
+        let frame = ipv4(IpProtocol::Udp, 1, 2, 1000, 53);
+        let p = packet(Direction::Rx, &frame);
+        assert_eq!(p.src, Some(Ipv4Address::new(10, 0, 0, 1).into()));
+        assert_eq!(p.dst, Some(Ipv4Address::new(10, 0, 0, 2).into()));
+        assert_eq!(p.protocol, Some(IpProtocol::Udp));
+        assert_eq!((p.src_port, p.dst_port), (Some(1000), Some(53)));
+
+        let frame = ipv4(IpProtocol::Tcp, 1, 2, 1000, 443);
+        let p = packet(Direction::Rx, &frame);
+        assert_eq!(p.protocol, Some(IpProtocol::Tcp));
+        assert_eq!((p.src_port, p.dst_port), (Some(1000), Some(443)));
+
+        // Ports aren't known for fragments, and for truncated transport headers.
+        let mut frame = ipv4(IpProtocol::Udp, 1, 2, 1000, 53);
+        frame[6] = 0x20;
+        let p = packet(Direction::Rx, &frame);
+        assert_eq!(p.protocol, Some(IpProtocol::Udp));
+        assert_eq!((p.src_port, p.dst_port), (None, None));
+
+        let mut frame = ipv4(IpProtocol::Tcp, 1, 2, 1000, 443);
+        frame.truncate(24);
+        frame[3] = 24;
+        let p = packet(Direction::Rx, &frame);
+        assert_eq!(p.protocol, Some(IpProtocol::Tcp));
+        assert_eq!((p.src_port, p.dst_port), (None, None));
+
+        // Frames that aren't IP.
+        for frame in [&[][..], &[0x45, 0, 0], &[0x10; 40]] {
+            let p = packet(Direction::Rx, frame);
+            assert_eq!((p.src, p.dst, p.protocol, p.src_port), (None, None, None, None));
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let udp = ipv4(IpProtocol::Udp, 1, 2, 1000, 53);
+        let rx = packet(Direction::Rx, &udp);
+        let tx = packet(Direction::Tx, &udp);
+        let non_ip = [0; 40];
+        let non_ip = packet(Direction::Rx, &non_ip);
+
+        let any = Rule::new(Action::Accept);
+        assert!(any.matches(&rx) && any.matches(&tx) && any.matches(&non_ip));
+
+        let rule = Rule::new(Action::Accept).direction(Direction::Tx);
+        assert!(!rule.matches(&rx) && rule.matches(&tx));
+
+        let rule = Rule::new(Action::Accept).non_ip();
+        assert!(!rule.matches(&rx) && rule.matches(&non_ip));
+
+        let rule = Rule::new(Action::Accept).protocol(IpProtocol::Udp);
+        assert!(rule.matches(&rx) && !rule.matches(&non_ip));
+        assert!(!Rule::new(Action::Accept).protocol(IpProtocol::Tcp).matches(&rx));
+
+        assert!(Rule::new(Action::Accept).src(subnet(0, 24)).matches(&rx));
+        assert!(Rule::new(Action::Accept).src(subnet(1, 32)).matches(&rx));
+        assert!(!Rule::new(Action::Accept).src(subnet(2, 32)).matches(&rx));
+        assert!(Rule::new(Action::Accept).dst(subnet(2, 32)).matches(&rx));
+        assert!(!Rule::new(Action::Accept).dst(subnet(1, 32)).matches(&rx));
+        // Conditions on the IP layer never match frames that aren't IP.
+        assert!(!Rule::new(Action::Accept).src(subnet(0, 0)).matches(&non_ip));
+
+        assert!(Rule::new(Action::Accept).src_port(1000).matches(&rx));
+        assert!(!Rule::new(Action::Accept).src_port(53).matches(&rx));
+        assert!(Rule::new(Action::Accept).dst_ports(53, 53).matches(&rx));
+        assert!(Rule::new(Action::Accept).dst_ports(1, 100).matches(&rx));
+        assert!(!Rule::new(Action::Accept).dst_ports(54, 100).matches(&rx));
+        assert!(!Rule::new(Action::Accept).dst_port(53).matches(&non_ip));
+
+        // All conditions must be met.
+        let rule = Rule::new(Action::Accept).protocol(IpProtocol::Udp).dst_port(54);
+        assert!(!rule.matches(&rx));
+    }
+
+    #[test]
+    fn rule_order() {
+        let table = RuleTable::<3>::new(Action::Drop);
+        table.push(Rule::new(Action::Drop).src_port(6666)).unwrap();
+        table.push(Rule::new(Action::Accept).protocol(IpProtocol::Udp)).unwrap();
+        table.push(Rule::new(Action::Drop).dst_port(53)).unwrap();
+        assert!(table.push(Rule::new(Action::Accept)).is_err());
+
+        // The first matching rule wins, even if later ones match too.
+        let frame = ipv4(IpProtocol::Udp, 1, 2, 6666, 53);
+        assert_eq!(table.filter(&packet(Direction::Rx, &frame)), Action::Drop);
+        let frame = ipv4(IpProtocol::Udp, 1, 2, 1000, 53);
+        assert_eq!(table.filter(&packet(Direction::Rx, &frame)), Action::Accept);
+        assert_eq!(table.filter(&packet(Direction::Tx, &frame)), Action::Accept);
+        let frame = ipv4(IpProtocol::Tcp, 1, 2, 1000, 53);
+        assert_eq!(table.filter(&packet(Direction::Tx, &frame)), Action::Drop);
+
+        assert_eq!(table.hits(0), Some(1));
+        assert_eq!(table.hits(1), Some(2));
+        assert_eq!(table.hits(2), Some(1));
+        assert_eq!(table.hits(3), None);
+        assert_eq!(
+            table.counters(),
+            Counters {
+                rx_dropped: 1,
+                tx_dropped: 1,
+                default_hits: 0,
+            }
+        );
+
+        table.reset_counters();
+        assert_eq!(table.hits(1), Some(0));
+        assert_eq!(table.counters(), Counters::default());
+    }
+
+    #[test]
+    fn default_action() {
+        let table = RuleTable::<2>::new(Action::Drop);
+        let frame = ipv4(IpProtocol::Tcp, 1, 2, 1000, 80);
+        let non_ip = [0; 40];
+
+        // Without rules, the default action applies to every frame.
+        assert_eq!(table.filter(&packet(Direction::Rx, &frame)), Action::Drop);
+        assert_eq!(table.filter(&packet(Direction::Tx, &non_ip)), Action::Drop);
+
+        table.push(Rule::new(Action::Accept).dst_port(80)).unwrap();
+        assert_eq!(table.filter(&packet(Direction::Rx, &frame)), Action::Accept);
+        assert_eq!(table.filter(&packet(Direction::Rx, &non_ip)), Action::Drop);
+
+        table.set_default(Action::Accept);
+        assert_eq!(table.filter(&packet(Direction::Rx, &non_ip)), Action::Accept);
+        assert_eq!(
+            table.counters(),
+            Counters {
+                rx_dropped: 2,
+                tx_dropped: 1,
+                default_hits: 4,
+            }
+        );
+
+        // Pushing after clearing restarts the hits of the reused slots.
+        table.clear();
+        assert_eq!(table.hits(0), None);
+        table.push(Rule::new(Action::Drop)).unwrap();
+        assert_eq!(table.hits(0), Some(0));
+        assert_eq!(table.filter(&packet(Direction::Rx, &frame)), Action::Drop);
+        assert_eq!(table.counters().default_hits, 4);
+    }
+}
@@ -0,0 +1,1021 @@
+//! HTTP/1.1 server.
+//!
+//! [`HttpServer`] accepts up to `N` concurrent connections, each with its own socket and buffers,
+//! parses requests into [`Request`]s and passes them to a [`Handler`], which writes the
+//! [`Response`]. Connections are kept alive between requests unless the client asks otherwise.
+//!
+//! The request head and body are parsed in place in a per-connection buffer, so requests that don't
+//! fit are rejected with `431 Request Header Fields Too Large` or `413 Content Too Large`. Request
+//! bodies may be sent with `Content-Length` or chunked, responses can be sent either way.
+//!
+//! A [`Router`] dispatches requests to handlers by method and path:
+//!
+//! ```rust,ignore
+//! struct Hello;
+//!
+//! impl Handler for Hello {
+//!     async fn handle(&self, _request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+//!         response.send(200, &[("Content-Type", "text/plain")], b"Hello!").await
+//!     }
+//! }
+//!
+//! let router = Router::new().route(Method::Get, "/", Hello);
+//! static SERVER: StaticCell<HttpServer<2>> = StaticCell::new();
+//! SERVER.init(HttpServer::new(ServerConfig::default())).run(stack, 80, &router).await;
+//! ```
+
+use embassy_futures::join::join_array;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write as _;
+use heapless::Vec;
+
+pub use crate::tcp::Error;
+use crate::tcp::TcpSocket;
+use crate::Stack;
+
+/// Maximum number of headers parsed in a request. Further headers are ignored.
+pub const MAX_HEADERS: usize = 16;
+
+/// HTTP request method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    /// GET
+    Get,
+    /// HEAD
+    Head,
+    /// POST
+    Post,
+    /// PUT
+    Put,
+    /// DELETE
+    Delete,
+    /// PATCH
+    Patch,
+    /// OPTIONS
+    Options,
+    /// Any other method.
+    Other,
+}
+
+impl Method {
+    fn parse(s: &str) -> Self {
+        match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            _ => Method::Other,
+        }
+    }
+}
+
+/// Request headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Headers<'r> {
+    headers: Vec<(&'r str, &'r str), MAX_HEADERS>,
+}
+
+impl<'r> Headers<'r> {
+    /// Get the value of the first header named `name`, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&'r str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    }
+
+    /// Iterate over the headers, as `(name, value)` pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&'r str, &'r str)> + '_ {
+        self.headers.iter().copied()
+    }
+}
+
+/// An HTTP request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request<'r> {
+    /// Request method.
+    pub method: Method,
+    /// Method as sent by the client, useful when `method` is [`Method::Other`].
+    pub method_str: &'r str,
+    /// Path, without the query string.
+    pub path: &'r str,
+    /// Query string, without the `?`.
+    pub query: Option<&'r str>,
+    /// Request headers.
+    pub headers: Headers<'r>,
+    /// Request body, with any chunked transfer encoding removed.
+    pub body: &'r [u8],
+}
+
+/// Proof that a response was sent, returned by [`Response`] methods.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Completed {
+    /// Whether the connection must be closed after this response.
+    close: bool,
+}
+
+/// Handles requests received by an [`HttpServer`].
+pub trait Handler {
+    /// Handle a request, by sending a response.
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error>;
+}
+
+impl<H: Handler> Handler for &H {
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        H::handle(self, request, response).await
+    }
+}
+
+/// A handler answering all requests with `404 Not Found`. It ends the route list of a [`Router`].
+pub struct NotFound;
+
+impl Handler for NotFound {
+    async fn handle(&self, _request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        response
+            .send(404, &[("Content-Type", "text/plain")], b"Not Found")
+            .await
+    }
+}
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Routes are matched in the order they were added. A path ending with `/*` matches all paths with
+/// that prefix, other paths must match exactly. `GET` routes also match `HEAD` requests. Requests
+/// matching no route go to the fallback handler, [`NotFound`] by default.
+pub struct Router<R = (), F = NotFound> {
+    routes: R,
+    fallback: F,
+}
+
+impl Router<(), NotFound> {
+    /// Create a router without routes.
+    pub const fn new() -> Self {
+        Self {
+            routes: (),
+            fallback: NotFound,
+        }
+    }
+}
+
+impl Default for Router<(), NotFound> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Routes, F: Handler> Router<R, F> {
+    /// Add a route.
+    pub fn route<H: Handler>(self, method: Method, path: &'static str, handler: H) -> Router<Route<H, R>, F> {
+        Router {
+            routes: Route {
+                method,
+                path,
+                handler,
+                previous: self.routes,
+            },
+            fallback: self.fallback,
+        }
+    }
+
+    /// Replace the handler for requests matching no route.
+    pub fn fallback<G: Handler>(self, handler: G) -> Router<R, G> {
+        Router {
+            routes: self.routes,
+            fallback: handler,
+        }
+    }
+}
+
+impl<R: Routes, F: Handler> Handler for Router<R, F> {
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        if self.routes.matches(request) {
+            self.routes.dispatch(request, response).await
+        } else {
+            self.fallback.handle(request, response).await
+        }
+    }
+}
+
+/// The list of routes of a [`Router`].
+pub trait Routes {
+    /// Whether any route matches `request`.
+    fn matches(&self, request: &Request<'_>) -> bool;
+
+    /// Pass `request` to the first matching route.
+    async fn dispatch(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error>;
+}
+
+impl Routes for () {
+    fn matches(&self, _request: &Request<'_>) -> bool {
+        false
+    }
+
+    async fn dispatch(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        NotFound.handle(request, response).await
+    }
+}
+
+/// A route of a [`Router`], following the `previous` routes.
+pub struct Route<H, P> {
+    method: Method,
+    path: &'static str,
+    handler: H,
+    previous: P,
+}
+
+impl<H, P> Route<H, P> {
+    fn matches_self(&self, request: &Request<'_>) -> bool {
+        let method = request.method == self.method || (request.method == Method::Head && self.method == Method::Get);
+        let path = match self.path.strip_suffix("/*") {
+            Some(prefix) => {
+                request.path == prefix
+                    || request
+                        .path
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }
+            None => request.path == self.path,
+        };
+        method && path
+    }
+}
+
+impl<H: Handler, P: Routes> Routes for Route<H, P> {
+    fn matches(&self, request: &Request<'_>) -> bool {
+        self.previous.matches(request) || self.matches_self(request)
+    }
+
+    async fn dispatch(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        if self.previous.matches(request) {
+            self.previous.dispatch(request, response).await
+        } else {
+            self.handler.handle(request, response).await
+        }
+    }
+}
+
+/// The response to a request.
+///
+/// Consume it by calling [`send()`](Response::send) or [`start_chunked()`](Response::start_chunked).
+pub struct Response<'a, 's> {
+    socket: &'a mut TcpSocket<'s>,
+    keep_alive: bool,
+    head: bool,
+}
+
+impl<'a, 's> Response<'a, 's> {
+    /// Send a response with a body.
+    pub async fn send(mut self, status: u16, headers: &[(&str, &str)], body: &[u8]) -> Result<Completed, Error> {
+        let mut len = [0; 20];
+        let len = format_decimal(body.len(), &mut len);
+        self.write_head(status, headers, ("Content-Length", len)).await?;
+        if !self.head {
+            self.socket.write_all(body).await?;
+        }
+        Ok(Completed { close: false })
+    }
+
+    /// Start a response whose body is sent in chunks, for bodies whose length isn't known in advance.
+    pub async fn start_chunked(
+        mut self,
+        status: u16,
+        headers: &[(&str, &str)],
+    ) -> Result<ChunkedWriter<'a, 's>, Error> {
+        self.write_head(status, headers, ("Transfer-Encoding", "chunked"))
+            .await?;
+        Ok(ChunkedWriter {
+            socket: self.socket,
+            head: self.head,
+        })
+    }
+
+    async fn write_head(&mut self, status: u16, headers: &[(&str, &str)], length: (&str, &str)) -> Result<(), Error> {
+        let socket = &mut *self.socket;
+        let mut code = [0; 20];
+        socket.write_all(b"HTTP/1.1 ").await?;
+        socket
+            .write_all(format_decimal(status as usize, &mut code).as_bytes())
+            .await?;
+        socket.write_all(b" ").await?;
+        socket.write_all(reason(status).as_bytes()).await?;
+        socket.write_all(b"\r\n").await?;
+        for (name, value) in headers.iter().chain([&length]) {
+            socket.write_all(name.as_bytes()).await?;
+            socket.write_all(b": ").await?;
+            socket.write_all(value.as_bytes()).await?;
+            socket.write_all(b"\r\n").await?;
+        }
+        if !self.keep_alive {
+            socket.write_all(b"Connection: close\r\n").await?;
+        }
+        socket.write_all(b"\r\n").await
+    }
+}
+
+/// Result of [`Response::upgrade_websocket()`].
+#[cfg(feature = "websocket")]
+pub enum Upgrade<'a, 's, 'b> {
+    /// The connection was upgraded. Return the [`Completed`] once done with the WebSocket, the
+    /// connection is then closed.
+    Accepted(
+        crate::websocket::WebSocket<'b, &'a mut TcpSocket<'s>, crate::websocket::Server>,
+        Completed,
+    ),
+    /// The request isn't a WebSocket handshake, and was answered with `400 Bad Request`.
+    Rejected(Completed),
+}
+
+#[cfg(feature = "websocket")]
+impl<'a, 's> Response<'a, 's> {
+    /// Answer a WebSocket handshake, and switch the connection to the WebSocket protocol.
+    ///
+    /// `buf` is the receive buffer of the WebSocket, see [`WebSocket`](crate::websocket::WebSocket).
+    pub async fn upgrade_websocket<'b>(
+        self,
+        request: &Request<'_>,
+        buf: &'b mut [u8],
+    ) -> Result<Upgrade<'a, 's, 'b>, Error> {
+        use crate::websocket::{accept_key, handshake_key, upgrade_headers, WebSocket};
+
+        let get = request.method == Method::Get;
+        let Some(key) = handshake_key(get, |name| request.headers.get(name)) else {
+            let headers = [("Content-Type", "text/plain"), ("Sec-WebSocket-Version", "13")];
+            let completed = self.send(400, &headers, b"Bad Request").await?;
+            return Ok(Upgrade::Rejected(completed));
+        };
+        let mut accept = [0; 28];
+        let accept = accept_key(key, &mut accept);
+
+        // Not a regular response: there's no body, and the connection is kept open.
+        let socket = &mut *self.socket;
+        socket.write_all(b"HTTP/1.1 101 Switching Protocols\r\n").await?;
+        for (name, value) in upgrade_headers(accept) {
+            socket.write_all(name.as_bytes()).await?;
+            socket.write_all(b": ").await?;
+            socket.write_all(value.as_bytes()).await?;
+            socket.write_all(b"\r\n").await?;
+        }
+        socket.write_all(b"\r\n").await?;
+        socket.flush().await?;
+        Ok(Upgrade::Accepted(
+            WebSocket::upgraded(self.socket, buf),
+            Completed { close: true },
+        ))
+    }
+}
+
+/// Writes the body of a chunked response. Call [`finish()`](ChunkedWriter::finish) when done.
+pub struct ChunkedWriter<'a, 's> {
+    socket: &'a mut TcpSocket<'s>,
+    head: bool,
+}
+
+impl<'a, 's> ChunkedWriter<'a, 's> {
+    /// Send a chunk.
+    pub async fn write_chunk(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() || self.head {
+            return Ok(());
+        }
+        let mut len = [0; 16];
+        let len = format_hex(data.len(), &mut len);
+        self.socket.write_all(len.as_bytes()).await?;
+        self.socket.write_all(b"\r\n").await?;
+        self.socket.write_all(data).await?;
+        self.socket.write_all(b"\r\n").await
+    }
+
+    /// End the response.
+    pub async fn finish(self) -> Result<Completed, Error> {
+        if !self.head {
+            self.socket.write_all(b"0\r\n\r\n").await?;
+        }
+        Ok(Completed { close: false })
+    }
+}
+
+impl<'a, 's> embedded_io_async::ErrorType for ChunkedWriter<'a, 's> {
+    type Error = Error;
+}
+
+impl<'a, 's> embedded_io_async::Write for ChunkedWriter<'a, 's> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_chunk(buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.flush().await
+    }
+}
+
+/// HTTP server configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ServerConfig {
+    /// How long a connection may stay idle, waiting for a request or for request data, before it's closed.
+    pub idle_timeout: Duration,
+    /// How long to wait for the client to close the connection after the last response.
+    pub close_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(10),
+            close_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+struct ConnectionBuffers<const RX_SZ: usize, const TX_SZ: usize, const BUF_SZ: usize> {
+    rx: [u8; RX_SZ],
+    tx: [u8; TX_SZ],
+    request: [u8; BUF_SZ],
+}
+
+/// HTTP server, serving up to `N` connections concurrently.
+///
+/// `RX_SZ` and `TX_SZ` are the sizes of the socket buffers of each connection. `BUF_SZ` is the size
+/// of the request buffer of each connection, which must hold the request head and body.
+pub struct HttpServer<const N: usize, const RX_SZ: usize = 1024, const TX_SZ: usize = 1024, const BUF_SZ: usize = 2048>
+{
+    config: ServerConfig,
+    connections: [ConnectionBuffers<RX_SZ, TX_SZ, BUF_SZ>; N],
+}
+
+impl<const N: usize, const RX_SZ: usize, const TX_SZ: usize, const BUF_SZ: usize> HttpServer<N, RX_SZ, TX_SZ, BUF_SZ> {
+    /// Create a new server.
+    pub const fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            connections: [const {
+                ConnectionBuffers {
+                    rx: [0; RX_SZ],
+                    tx: [0; TX_SZ],
+                    request: [0; BUF_SZ],
+                }
+            }; N],
+        }
+    }
+
+    /// Serve requests on `port`, passing them to `handler`.
+    pub async fn run<H: Handler>(&mut self, stack: Stack<'_>, port: u16, handler: &H) -> ! {
+        let config = self.config;
+        join_array(
+            self.connections
+                .each_mut()
+                .map(|buffers| serve(stack, port, config, handler, buffers)),
+        )
+        .await;
+        unreachable!()
+    }
+}
+
+async fn serve<H: Handler, const RX_SZ: usize, const TX_SZ: usize, const BUF_SZ: usize>(
+    stack: Stack<'_>,
+    port: u16,
+    config: ServerConfig,
+    handler: &H,
+    buffers: &mut ConnectionBuffers<RX_SZ, TX_SZ, BUF_SZ>,
+) -> ! {
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut buffers.rx, &mut buffers.tx);
+        socket.set_timeout(Some(config.idle_timeout));
+        if let Err(e) = socket.accept(port).await {
+            warn!("http: accept error: {:?}", e);
+            continue;
+        }
+
+        if let Err(e) = serve_connection(&mut socket, handler, &mut buffers.request).await {
+            debug!("http: connection error: {:?}", e);
+        }
+
+        socket.close();
+        if with_timeout(config.close_timeout, socket.flush()).await.is_err() {
+            socket.abort();
+        }
+    }
+}
+
+/// Why a connection stopped being served.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum ConnectionError {
+    Tcp(Error),
+    /// The client closed the connection.
+    Closed,
+    /// The client sent a malformed request, or one that doesn't fit in the buffer. An error response was sent.
+    BadRequest,
+}
+
+impl From<Error> for ConnectionError {
+    fn from(e: Error) -> Self {
+        Self::Tcp(e)
+    }
+}
+
+async fn serve_connection<H: Handler>(
+    socket: &mut TcpSocket<'_>,
+    handler: &H,
+    buf: &mut [u8],
+) -> Result<(), ConnectionError> {
+    let mut filled = 0;
+    loop {
+        // Read the request head.
+        let head_len = loop {
+            if let Some(pos) = find(&buf[..filled], b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if filled == buf.len() {
+                return Err(reject(socket, 431).await);
+            }
+            let n = socket.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                return Err(ConnectionError::Closed);
+            }
+            filled += n;
+        };
+
+        let Some(framing) = Head::parse(&buf[..head_len]).and_then(|head| head.framing()) else {
+            return Err(reject(socket, 400).await);
+        };
+        let Framing {
+            keep_alive,
+            chunked,
+            content_length,
+            expect_continue,
+        } = framing;
+
+        // Read the body.
+        if expect_continue {
+            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let (body_end, request_end) = if chunked {
+            read_chunked(socket, buf, head_len, &mut filled).await?
+        } else {
+            let end = match head_len.checked_add(content_length) {
+                Some(end) if end <= buf.len() => end,
+                _ => return Err(reject(socket, 413).await),
+            };
+            while filled < end {
+                let n = socket.read(&mut buf[filled..]).await?;
+                if n == 0 {
+                    return Err(ConnectionError::Closed);
+                }
+                filled += n;
+            }
+            (end, end)
+        };
+
+        let completed;
+        {
+            // The head was parsed before, so this can't fail.
+            let Some(head) = Head::parse(&buf[..head_len]) else {
+                return Err(ConnectionError::BadRequest);
+            };
+            let request = Request {
+                method: head.method,
+                method_str: head.method_str,
+                path: head.path,
+                query: head.query,
+                headers: head.headers,
+                body: &buf[head_len..body_end],
+            };
+            let response = Response {
+                socket: &mut *socket,
+                keep_alive,
+                head: request.method == Method::Head,
+            };
+            completed = handler.handle(&request, response).await?;
+        }
+
+        if !keep_alive || completed.close {
+            return Ok(());
+        }
+        // Keep pipelined requests.
+        buf.copy_within(request_end..filled, 0);
+        filled -= request_end;
+    }
+}
+
+/// Send an error response, for requests that can't be handled.
+async fn reject(socket: &mut TcpSocket<'_>, status: u16) -> ConnectionError {
+    let response = Response {
+        socket,
+        keep_alive: false,
+        head: false,
+    };
+    match response.send(status, &[], reason(status).as_bytes()).await {
+        Ok(_) => ConnectionError::BadRequest,
+        Err(e) => ConnectionError::Tcp(e),
+    }
+}
+
+/// Read a chunked body that starts at `start`, and decode it in place.
+///
+/// Returns the end of the decoded body and the end of the request in `buf`. Malformed bodies, and
+/// bodies that don't fit, are rejected.
+async fn read_chunked(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    start: usize,
+    filled: &mut usize,
+) -> Result<(usize, usize), ConnectionError> {
+    let mut decoder = ChunkDecoder::new(start);
+    loop {
+        match decoder.decode(buf, *filled) {
+            Chunks::Done { body_end, request_end } => return Ok((body_end, request_end)),
+            Chunks::Reject(status) => return Err(reject(socket, status).await),
+            Chunks::Incomplete => {
+                let n = socket.read(&mut buf[*filled..]).await?;
+                if n == 0 {
+                    return Err(ConnectionError::Closed);
+                }
+                *filled += n;
+            }
+        }
+    }
+}
+
+/// Decodes a chunked body in place, as it is received.
+struct ChunkDecoder {
+    /// End of the decoded body.
+    out: usize,
+    /// Start of the next chunk size or trailer line.
+    pos: usize,
+    /// Whether the last chunk was decoded, and only trailers remain.
+    trailers: bool,
+}
+
+/// Result of [`ChunkDecoder::decode()`].
+#[derive(Debug, PartialEq, Eq)]
+enum Chunks {
+    /// The whole body was decoded.
+    Done { body_end: usize, request_end: usize },
+    /// More data is needed.
+    Incomplete,
+    /// The body is malformed or doesn't fit in the buffer, and must be rejected with this status.
+    Reject(u16),
+}
+
+impl ChunkDecoder {
+    fn new(start: usize) -> Self {
+        Self {
+            out: start,
+            pos: start,
+            trailers: false,
+        }
+    }
+
+    /// Decode the chunks received in `buf[..filled]`. Call again with the same buffer once more
+    /// data was received after it.
+    fn decode(&mut self, buf: &mut [u8], filled: usize) -> Chunks {
+        loop {
+            let Some(line_len) = find(&buf[self.pos..filled], b"\r\n") else {
+                return match filled == buf.len() {
+                    true => Chunks::Reject(413),
+                    false => Chunks::Incomplete,
+                };
+            };
+            let line = &buf[self.pos..self.pos + line_len];
+            let data_start = self.pos + line_len + 2;
+
+            if self.trailers {
+                self.pos = data_start;
+                if line.is_empty() {
+                    return Chunks::Done {
+                        body_end: self.out,
+                        request_end: self.pos,
+                    };
+                }
+                continue;
+            }
+
+            let size = line.split(|&b| b == b';').next().unwrap_or(&[]);
+            let Some(size) = core::str::from_utf8(size)
+                .ok()
+                .and_then(|s| usize::from_str_radix(s.trim(), 16).ok())
+            else {
+                return Chunks::Reject(400);
+            };
+            if size == 0 {
+                self.trailers = true;
+                self.pos = data_start;
+                continue;
+            }
+
+            // The chunk data is followed by CRLF. The size comes from the client, so it may overflow.
+            let end = match size.checked_add(2).and_then(|n| data_start.checked_add(n)) {
+                Some(end) if end <= buf.len() => end,
+                _ => return Chunks::Reject(413),
+            };
+            if filled < end {
+                return Chunks::Incomplete;
+            }
+            if &buf[end - 2..end] != b"\r\n" {
+                return Chunks::Reject(400);
+            }
+            buf.copy_within(data_start..data_start + size, self.out);
+            self.out += size;
+            self.pos = end;
+        }
+    }
+}
+
+struct Head<'r> {
+    method: Method,
+    method_str: &'r str,
+    path: &'r str,
+    query: Option<&'r str>,
+    http11: bool,
+    headers: Headers<'r>,
+}
+
+impl<'r> Head<'r> {
+    fn parse(data: &'r [u8]) -> Option<Self> {
+        let data = core::str::from_utf8(data).ok()?;
+        let mut lines = data.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+        let method_str = request_line.next()?;
+        let target = request_line.next()?;
+        let version = request_line.next()?;
+        if request_line.next().is_some() || !version.starts_with("HTTP/1.") {
+            return None;
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            if headers.push((name.trim(), value.trim())).is_err() {
+                warn!("http: too many headers, ignoring {}", name);
+            }
+        }
+
+        Some(Self {
+            method: Method::parse(method_str),
+            method_str,
+            path,
+            query,
+            http11: version != "HTTP/1.0",
+            headers: Headers { headers },
+        })
+    }
+}
+
+/// How the body of a request is sent, and what happens after it.
+struct Framing {
+    keep_alive: bool,
+    chunked: bool,
+    content_length: usize,
+    expect_continue: bool,
+}
+
+impl<'r> Head<'r> {
+    fn framing(&self) -> Option<Framing> {
+        let keep_alive = match self.headers.get("Connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.http11,
+        };
+        let chunked = self
+            .headers
+            .get("Transfer-Encoding")
+            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+        let content_length = match self.headers.get("Content-Length") {
+            Some(v) => v.parse().ok()?,
+            None => 0,
+        };
+        let expect_continue = self
+            .headers
+            .get("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        Some(Framing {
+            keep_alive,
+            chunked,
+            content_length,
+            expect_continue,
+        })
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+fn format_decimal(mut n: usize, buf: &mut [u8; 20]) -> &str {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    // Only ASCII digits were written, so this never fails.
+    core::str::from_utf8(&buf[i..]).unwrap_or("")
+}
+
+fn format_hex(mut n: usize, buf: &mut [u8; 16]) -> &str {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b"0123456789abcdef"[n % 16];
+        n /= 16;
+        if n == 0 {
+            break;
+        }
+    }
+    // Only ASCII digits were written, so this never fails.
+    core::str::from_utf8(&buf[i..]).unwrap_or("")
+}
+
+/// Get the reason phrase for a status code.
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn parse(head: &str) -> Option<Head<'_>> {
+        Head::parse(head.as_bytes())
+    }
+
+    #[test]
+    fn request_line() {
+        let head = parse("GET /index.html?a=1&b HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(head.method, Method::Get);
+        assert_eq!(head.path, "/index.html");
+        assert_eq!(head.query, Some("a=1&b"));
+        assert!(head.http11);
+
+        let head = parse("BREW /pot HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!((head.method, head.method_str), (Method::Other, "BREW"));
+        assert_eq!(head.query, None);
+        assert!(!head.http11);
+
+        assert!(parse("GET /\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/1.1 extra\r\n\r\n").is_none());
+        assert!(parse("GET / HTTP/2\r\n\r\n").is_none());
+        assert!(Head::parse(b"GET /\xff HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn headers() {
+        let head = parse("GET / HTTP/1.1\r\nHost: example.com\r\ncontent-type:  text/plain \r\n\r\n").unwrap();
+        assert_eq!(head.headers.get("host"), Some("example.com"));
+        assert_eq!(head.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(head.headers.get("Accept"), None);
+        assert_eq!(head.headers.iter().count(), 2);
+
+        // Headers past the limit are ignored, malformed headers reject the request.
+        let mut many = std::string::String::from("GET / HTTP/1.1\r\n");
+        for i in 0..MAX_HEADERS + 4 {
+            many += &std::format!("X-{}: {}\r\n", i, i);
+        }
+        many += "\r\n";
+        let head = parse(&many).unwrap();
+        assert_eq!(head.headers.iter().count(), MAX_HEADERS);
+        assert!(parse("GET / HTTP/1.1\r\nNo colon\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn framing() {
+        let framing = |head: &str| parse(head).unwrap().framing();
+
+        let f = framing("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(f.keep_alive && !f.chunked && !f.expect_continue);
+        assert_eq!(f.content_length, 0);
+        assert!(!framing("GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive);
+        assert!(
+            framing("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+                .unwrap()
+                .keep_alive
+        );
+        assert!(
+            !framing("GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap()
+                .keep_alive
+        );
+
+        let f = framing("POST / HTTP/1.1\r\nContent-Length: 42\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert_eq!(f.content_length, 42);
+        assert!(f.expect_continue);
+        assert!(
+            framing("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n")
+                .unwrap()
+                .chunked
+        );
+
+        // The length is checked against the buffer later, it only needs to be a number here.
+        let head = std::format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert_eq!(framing(&head).unwrap().content_length, usize::MAX);
+        assert!(framing("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n").is_none());
+        assert!(framing("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").is_none());
+        assert!(framing("POST / HTTP/1.1\r\nContent-Length: 0x10\r\n\r\n").is_none());
+    }
+
+    /// Decode `body` at offset 4 of a buffer of `len` bytes, feeding it `step` bytes at a time.
+    fn decode(body: &[u8], len: usize, step: usize) -> (Chunks, std::vec::Vec<u8>) {
+        let mut buf = std::vec![0; len];
+        let mut decoder = ChunkDecoder::new(4);
+        let mut filled = 4;
+        loop {
+            let n = step.min(body.len() - (filled - 4)).min(len - filled);
+            buf[filled..filled + n].copy_from_slice(&body[filled - 4..filled - 4 + n]);
+            filled += n;
+            match decoder.decode(&mut buf, filled) {
+                Chunks::Incomplete if n > 0 => {}
+                Chunks::Done { body_end, request_end } => {
+                    assert_eq!(request_end, 4 + body.len());
+                    return (Chunks::Done { body_end, request_end }, buf[4..body_end].to_vec());
+                }
+                r => return (r, std::vec::Vec::new()),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked() {
+        let body = b"3\r\nabc\r\n10;name=value\r\n0123456789abcdef\r\n0\r\nTrailer: x\r\n\r\n";
+        for step in [1, 2, 5, body.len()] {
+            let (result, decoded) = decode(body, 128, step);
+            assert!(matches!(result, Chunks::Done { .. }), "{:?}", result);
+            assert_eq!(decoded, b"abc0123456789abcdef");
+        }
+        let (_, decoded) = decode(b"0\r\n\r\n", 16, 1);
+        assert!(decoded.is_empty());
+
+        // Truncated bodies wait for more data.
+        assert_eq!(decode(b"3\r\nab", 64, 64).0, Chunks::Incomplete);
+        assert_eq!(decode(b"3\r\nabc\r\n0\r\n", 64, 64).0, Chunks::Incomplete);
+
+        // Malformed bodies.
+        assert_eq!(decode(b"x\r\nabc\r\n0\r\n\r\n", 64, 64).0, Chunks::Reject(400));
+        assert_eq!(decode(b"\r\n\r\n", 64, 64).0, Chunks::Reject(400));
+        assert_eq!(decode(b"3\r\nabcd\r\n0\r\n\r\n", 64, 64).0, Chunks::Reject(400));
+        assert_eq!(decode(b"1ffffffffffffffff\r\n", 64, 64).0, Chunks::Reject(400));
+    }
+
+    #[test]
+    fn chunked_oversized() {
+        // Chunks larger than the buffer, including sizes that overflow when adding the offset.
+        assert_eq!(decode(b"40\r\n", 64, 64).0, Chunks::Reject(413));
+        let max = std::format!("{:x}\r\n", usize::MAX);
+        assert_eq!(decode(max.as_bytes(), 64, 64).0, Chunks::Reject(413));
+        let max = std::format!("{:x}\r\n", usize::MAX - 3);
+        assert_eq!(decode(max.as_bytes(), 64, 64).0, Chunks::Reject(413));
+
+        // Size lines and trailers that don't fit.
+        assert_eq!(decode(&[b'0'; 64], 32, 64).0, Chunks::Reject(413));
+        let mut body = b"0\r\n".to_vec();
+        body.extend_from_slice(&[b'x'; 64]);
+        assert_eq!(decode(&body, 32, 64).0, Chunks::Reject(413));
+    }
+}
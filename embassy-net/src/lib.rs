@@ -12,20 +12,39 @@ compile_error!("You must enable at least one of the following features: proto-ip
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+#[cfg(feature = "autoip")]
+mod autoip;
 #[cfg(feature = "dns")]
 pub mod dns;
 mod driver_util;
+#[cfg(feature = "filter")]
+pub mod filter;
+#[cfg(feature = "http-server")]
+pub mod http;
+#[cfg(feature = "mdns-responder")]
+pub mod mdns;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "pcap")]
+pub mod pcap;
 #[cfg(feature = "raw")]
 pub mod raw;
+#[cfg(feature = "slaac")]
+mod slaac;
+mod stats;
 #[cfg(feature = "tcp")]
 pub mod tcp;
 mod time;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(feature = "udp")]
 pub mod udp;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
 use core::cell::RefCell;
 use core::future::{poll_fn, Future};
-use core::mem::MaybeUninit;
+use core::mem::{self, MaybeUninit};
 use core::pin::pin;
 use core::task::{Context, Poll};
 
@@ -58,7 +77,10 @@ pub use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 #[cfg(feature = "proto-ipv6")]
 pub use smoltcp::wire::{Ipv6Address, Ipv6Cidr};
 
-use crate::driver_util::DriverAdapter;
+use crate::driver_util::{DriverAdapter, RunnerHooks};
+#[cfg(feature = "stats")]
+pub use crate::stats::InterfaceStats;
+use crate::stats::Stats;
 use crate::time::{instant_from_smoltcp, instant_to_smoltcp};
 
 const LOCAL_PORT_MIN: u16 = 1025;
@@ -67,15 +89,28 @@ const LOCAL_PORT_MAX: u16 = 65535;
 const MAX_QUERIES: usize = 4;
 #[cfg(feature = "dhcpv4-hostname")]
 const MAX_HOSTNAME_LEN: usize = 32;
+const MAX_ROUTES: usize = 8;
+/// Metric of the default routes implicitly created for interfaces with a gateway.
+///
+/// The interface index is added to it, so earlier interfaces are preferred.
+const IMPLICIT_DEFAULT_ROUTE_METRIC: u32 = 1024;
 
 /// Memory resources needed for a network stack.
-pub struct StackResources<const SOCK: usize> {
-    sockets: MaybeUninit<[SocketStorage<'static>; SOCK]>,
+///
+/// `SOCK` is the number of sockets available on each interface, `IFACES` is the
+/// maximum number of interfaces that can be added to the stack. A socket takes a slot
+/// of the interface it's attached to, so `SOCK` must cover the sockets of the busiest
+/// interface, including the ones the stack itself uses for DHCP and DNS.
+pub struct StackResources<const SOCK: usize, const IFACES: usize = 1> {
+    sockets: MaybeUninit<[[SocketStorage<'static>; SOCK]; IFACES]>,
+    ifaces: MaybeUninit<[Option<InterfaceInner>; IFACES]>,
     inner: MaybeUninit<RefCell<Inner>>,
     #[cfg(feature = "dns")]
-    queries: MaybeUninit<[Option<dns::DnsQuery>; MAX_QUERIES]>,
+    queries: MaybeUninit<[[Option<dns::DnsQuery>; MAX_QUERIES]; IFACES]>,
     #[cfg(feature = "dhcpv4-hostname")]
-    hostname: HostnameResources,
+    hostnames: [HostnameResources; IFACES],
+    #[cfg(feature = "slaac")]
+    slaac: [slaac::Resources; IFACES],
 }
 
 #[cfg(feature = "dhcpv4-hostname")]
@@ -84,23 +119,87 @@ struct HostnameResources {
     data: MaybeUninit<[u8; MAX_HOSTNAME_LEN]>,
 }
 
-impl<const SOCK: usize> StackResources<SOCK> {
+impl<const SOCK: usize, const IFACES: usize> StackResources<SOCK, IFACES> {
     /// Create a new set of stack resources.
     pub const fn new() -> Self {
         Self {
             sockets: MaybeUninit::uninit(),
+            ifaces: MaybeUninit::uninit(),
             inner: MaybeUninit::uninit(),
             #[cfg(feature = "dns")]
             queries: MaybeUninit::uninit(),
             #[cfg(feature = "dhcpv4-hostname")]
-            hostname: HostnameResources {
-                option: MaybeUninit::uninit(),
-                data: MaybeUninit::uninit(),
-            },
+            hostnames: [const {
+                HostnameResources {
+                    option: MaybeUninit::uninit(),
+                    data: MaybeUninit::uninit(),
+                }
+            }; IFACES],
+            #[cfg(feature = "slaac")]
+            slaac: [const { slaac::Resources::new() }; IFACES],
         }
     }
 }
 
+/// Identifier of a network interface within a [`Stack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceId(u8);
+
+impl InterfaceId {
+    /// The primary interface, i.e. the one created by [`new()`].
+    pub const PRIMARY: Self = Self(0);
+
+    /// Get the index of this interface, in the order interfaces were added to the stack.
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// An entry in the stack's routing table.
+///
+/// The routing table decides which interface is used to reach a destination. It is
+/// consulted when a TCP socket that isn't bound to an interface connects, and to pick
+/// the interface used for DNS queries.
+///
+/// Besides the routes added with [`Stack::add_route`], every interface implicitly has
+/// an on-link route for each of its configured subnets, and a default route if its
+/// configuration has a gateway. Routes through interfaces whose link is down are ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Route {
+    /// Destination subnet. Use a prefix length of 0 for a default route.
+    pub destination: IpCidr,
+    /// Interface used to reach the destination.
+    pub interface: InterfaceId,
+    /// Route metric. When several routes with the same prefix length match, the one
+    /// with the lowest metric wins.
+    ///
+    /// Implicit on-link routes have metric 0. Implicit default routes have metric
+    /// 1024 plus the interface index.
+    pub metric: u32,
+}
+
+/// Error returned when binding a socket to an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BindInterfaceError {
+    /// The socket is not in a state where it can change interfaces, e.g. it's connected.
+    InvalidState,
+    /// The interface has no free socket slot.
+    NoFreeSlot,
+}
+
+/// Error returned by [`Stack::add_route`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RouteError {
+    /// The routing table is full.
+    TableFull,
+    /// The route refers to an interface that does not exist.
+    InvalidInterface,
+}
+
 /// Static IP address configuration.
 #[cfg(feature = "proto-ipv4")]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -113,6 +212,17 @@ pub struct StaticConfigV4 {
     pub dns_servers: Vec<Ipv4Address, 3>,
 }
 
+#[cfg(feature = "proto-ipv4")]
+impl StaticConfigV4 {
+    /// Whether the address is an IPv4 link-local address, in 169.254.0.0/16.
+    ///
+    /// With [`ConfigV4::AutoIp`], this tells whether the configuration is the self-assigned
+    /// link-local one or a DHCP lease.
+    pub fn is_link_local(&self) -> bool {
+        self.address.address().is_link_local()
+    }
+}
+
 /// Static IPv6 address configuration
 #[cfg(feature = "proto-ipv6")]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -165,6 +275,57 @@ impl Default for DhcpConfig {
     }
 }
 
+/// IPv4 link-local address autoconfiguration configuration.
+#[cfg(feature = "autoip")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AutoIpConfig {
+    /// Try DHCP first, with this configuration, and fall back to a link-local address if no
+    /// lease is obtained within `dhcp_timeout`.
+    ///
+    /// DHCP keeps running in the background, and the link-local address is dropped when a lease
+    /// is obtained.
+    #[cfg(feature = "dhcpv4")]
+    pub dhcp: Option<DhcpConfig>,
+    /// How long to wait for a DHCP lease before claiming a link-local address.
+    #[cfg(feature = "dhcpv4")]
+    pub dhcp_timeout: embassy_time::Duration,
+}
+
+#[cfg(feature = "autoip")]
+#[cfg_attr(not(feature = "dhcpv4"), allow(clippy::derivable_impls))]
+impl Default for AutoIpConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "dhcpv4")]
+            dhcp: None,
+            #[cfg(feature = "dhcpv4")]
+            dhcp_timeout: embassy_time::Duration::from_secs(5),
+        }
+    }
+}
+
+/// IPv6 stateless address autoconfiguration (SLAAC) configuration.
+#[cfg(feature = "slaac")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SlaacConfig {
+    /// Request the DNS servers with stateless DHCPv6, when routers advertise that other
+    /// configuration is available.
+    #[cfg(feature = "dhcpv6")]
+    pub dhcpv6: bool,
+}
+
+#[cfg(feature = "slaac")]
+impl Default for SlaacConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "dhcpv6")]
+            dhcpv6: true,
+        }
+    }
+}
+
 /// Network stack configuration.
 #[derive(Debug, Clone, Default)]
 #[non_exhaustive]
@@ -213,6 +374,26 @@ impl Config {
             ipv6: ConfigV6::None,
         }
     }
+
+    /// IPv4 configuration with a link-local address, optionally as a fallback for DHCP.
+    #[cfg(feature = "autoip")]
+    pub fn ipv4_autoip(config: AutoIpConfig) -> Self {
+        Self {
+            ipv4: ConfigV4::AutoIp(config),
+            #[cfg(feature = "proto-ipv6")]
+            ipv6: ConfigV6::None,
+        }
+    }
+
+    /// IPv6 configuration with stateless address autoconfiguration.
+    #[cfg(feature = "slaac")]
+    pub fn ipv6_slaac(config: SlaacConfig) -> Self {
+        Self {
+            #[cfg(feature = "proto-ipv4")]
+            ipv4: ConfigV4::None,
+            ipv6: ConfigV6::Slaac(config),
+        }
+    }
 }
 
 /// Network stack IPv4 configuration.
@@ -227,6 +408,12 @@ pub enum ConfigV4 {
     /// Use DHCP to obtain an IP address configuration.
     #[cfg(feature = "dhcpv4")]
     Dhcp(DhcpConfig),
+    /// Claim a link-local address in 169.254.0.0/16 (RFC 3927), probing with ARP that no other
+    /// host uses it and defending it afterwards. Optionally, use DHCP when a lease can be obtained.
+    ///
+    /// This is only supported on Ethernet interfaces.
+    #[cfg(feature = "autoip")]
+    AutoIp(AutoIpConfig),
 }
 
 /// Network stack IPv6 configuration.
@@ -238,34 +425,76 @@ pub enum ConfigV6 {
     None,
     /// Use a static IPv6 address configuration.
     Static(StaticConfigV6),
+    /// Use stateless address autoconfiguration: form the addresses from the prefixes in router
+    /// advertisements, and get the gateway and DNS servers from them.
+    ///
+    /// This uses one socket of the interface, or two with the `dhcpv6` feature.
+    #[cfg(feature = "slaac")]
+    Slaac(SlaacConfig),
 }
 
 /// Network stack runner.
 ///
 /// You must call [`Runner::run()`] in a background task for the network stack to work.
+///
+/// There is one runner per interface: the one returned by [`new()`] drives the primary
+/// interface, the ones returned by [`Stack::add_interface()`] drive additional interfaces.
 pub struct Runner<'d, D: Driver> {
     driver: D,
     stack: Stack<'d>,
+    id: InterfaceId,
+    hooks: RunnerHooks<'d>,
 }
 
 /// Network stack handle
 ///
 /// Use this to create sockets. It's `Copy`, so you can pass
 /// it by value instead of by reference.
+///
+/// Methods that query or change the state of an interface (link state, IP configuration,
+/// hardware address, multicast groups) act on the primary interface. Use
+/// [`Stack::interface()`] to access other interfaces.
 #[derive(Copy, Clone)]
 pub struct Stack<'d> {
     inner: &'d RefCell<Inner>,
 }
 
+/// Handle to a single interface of a [`Stack`].
+///
+/// Obtained with [`Stack::interface()`]. It's `Copy`, so you can pass
+/// it by value instead of by reference.
+#[derive(Copy, Clone)]
+pub struct NetInterface<'d> {
+    stack: Stack<'d>,
+    id: InterfaceId,
+}
+
 pub(crate) struct Inner {
+    pub(crate) ifaces: &'static mut [Option<InterfaceInner>], // Lifetime type-erased.
+    /// Waker used for waiting for link up or config up.
+    state_waker: WakerRegistration,
+    next_local_port: u16,
+    random_seed: u64,
+    routes: Vec<Route, MAX_ROUTES>,
+    /// Socket storage for interfaces that haven't been added yet.
+    spare_sockets: &'static mut [SocketStorage<'static>],
+    sockets_per_iface: usize,
+    #[cfg(feature = "dns")]
+    spare_queries: &'static mut [[Option<dns::DnsQuery>; MAX_QUERIES]],
+    #[cfg(feature = "dns")]
+    dns_waker: WakerRegistration,
+    #[cfg(feature = "dhcpv4-hostname")]
+    spare_hostnames: &'static mut [HostnameResources],
+    #[cfg(feature = "slaac")]
+    spare_slaac: &'static mut [slaac::Resources],
+}
+
+pub(crate) struct InterfaceInner {
     pub(crate) sockets: SocketSet<'static>, // Lifetime type-erased.
     pub(crate) iface: Interface,
     /// Waker used for triggering polls.
     pub(crate) waker: WakerRegistration,
-    /// Waker used for waiting for link up or config up.
-    state_waker: WakerRegistration,
     hardware_address: HardwareAddress,
-    next_local_port: u16,
     link_up: bool,
     #[cfg(feature = "proto-ipv4")]
     static_v4: Option<StaticConfigV4>,
@@ -275,86 +504,81 @@ pub(crate) struct Inner {
     dhcp_socket: Option<SocketHandle>,
     #[cfg(feature = "dns")]
     dns_socket: SocketHandle,
-    #[cfg(feature = "dns")]
-    dns_waker: WakerRegistration,
     #[cfg(feature = "dhcpv4-hostname")]
     hostname: *mut HostnameResources,
+    #[cfg(feature = "slaac")]
+    slaac: Option<slaac::Slaac>,
+    #[cfg(feature = "slaac")]
+    slaac_resources: *mut slaac::Resources,
+    #[cfg(feature = "slaac")]
+    random_seed: u64,
+    #[cfg(feature = "autoip")]
+    autoip: Option<autoip::AutoIp>,
+    #[cfg(feature = "autoip")]
+    arp_watch: autoip::ArpWatch,
+    pub(crate) stats: Stats,
 }
 
 fn _assert_covariant<'a, 'b: 'a>(x: Stack<'b>) -> Stack<'a> {
     x
 }
 
+unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
+    core::mem::transmute(x)
+}
+
 /// Create a new network stack.
-pub fn new<'d, D: Driver, const SOCK: usize>(
+///
+/// The driver becomes the stack's primary interface. More interfaces can be added
+/// later with [`Stack::add_interface()`].
+pub fn new<'d, D: Driver, const SOCK: usize, const IFACES: usize>(
     mut driver: D,
     config: Config,
-    resources: &'d mut StackResources<SOCK>,
+    resources: &'d mut StackResources<SOCK, IFACES>,
     random_seed: u64,
 ) -> (Stack<'d>, Runner<'d, D>) {
-    let (hardware_address, medium) = to_smoltcp_hardware_address(driver.hardware_address());
-    let mut iface_cfg = smoltcp::iface::Config::new(hardware_address);
-    iface_cfg.random_seed = random_seed;
-
-    let iface = Interface::new(
-        iface_cfg,
-        &mut DriverAdapter {
-            inner: &mut driver,
-            cx: None,
-            medium,
-        },
-        instant_to_smoltcp(Instant::now()),
-    );
-
-    unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
-        core::mem::transmute(x)
-    }
-
-    let sockets = resources.sockets.write([SocketStorage::EMPTY; SOCK]);
-    #[allow(unused_mut)]
-    let mut sockets: SocketSet<'static> = SocketSet::new(unsafe { transmute_slice(sockets) });
-
     let next_local_port = (random_seed % (LOCAL_PORT_MAX - LOCAL_PORT_MIN) as u64) as u16 + LOCAL_PORT_MIN;
 
+    let sockets = resources
+        .sockets
+        .write([const { [SocketStorage::EMPTY; SOCK] }; IFACES]);
+    let ifaces = resources.ifaces.write([const { None }; IFACES]);
     #[cfg(feature = "dns")]
-    let dns_socket = sockets.add(dns::Socket::new(
-        &[],
-        managed::ManagedSlice::Borrowed(unsafe {
-            transmute_slice(resources.queries.write([const { None }; MAX_QUERIES]))
-        }),
-    ));
+    let queries = resources
+        .queries
+        .write([const { [const { None }; MAX_QUERIES] }; IFACES]);
 
     let mut inner = Inner {
-        sockets,
-        iface,
-        waker: WakerRegistration::new(),
+        ifaces: unsafe { transmute_slice(ifaces) },
         state_waker: WakerRegistration::new(),
         next_local_port,
-        hardware_address,
-        link_up: false,
-        #[cfg(feature = "proto-ipv4")]
-        static_v4: None,
-        #[cfg(feature = "proto-ipv6")]
-        static_v6: None,
-        #[cfg(feature = "dhcpv4")]
-        dhcp_socket: None,
+        random_seed,
+        routes: Vec::new(),
+        spare_sockets: unsafe { transmute_slice(sockets.as_flattened_mut()) },
+        sockets_per_iface: SOCK,
         #[cfg(feature = "dns")]
-        dns_socket,
+        spare_queries: unsafe { transmute_slice(queries) },
         #[cfg(feature = "dns")]
         dns_waker: WakerRegistration::new(),
         #[cfg(feature = "dhcpv4-hostname")]
-        hostname: &mut resources.hostname,
+        spare_hostnames: unsafe { transmute_slice(&mut resources.hostnames) },
+        #[cfg(feature = "slaac")]
+        spare_slaac: unsafe { transmute_slice(&mut resources.slaac) },
     };
 
-    #[cfg(feature = "proto-ipv4")]
-    inner.set_config_v4(config.ipv4);
-    #[cfg(feature = "proto-ipv6")]
-    inner.set_config_v6(config.ipv6);
-    inner.apply_static_config();
+    let id = inner.add_interface(&mut driver, config);
 
     let inner = &*resources.inner.write(RefCell::new(inner));
     let stack = Stack { inner };
-    (stack, Runner { driver, stack })
+    (
+        stack,
+        Runner {
+            driver,
+            stack,
+            id,
+            hooks: RunnerHooks::default(),
+        },
+    )
 }
 
 fn to_smoltcp_hardware_address(addr: driver::HardwareAddress) -> (HardwareAddress, Medium) {
@@ -388,12 +612,12 @@ impl<'d> Stack<'d> {
 
     /// Get the hardware address of the network interface.
     pub fn hardware_address(&self) -> HardwareAddress {
-        self.with(|i| i.hardware_address)
+        self.primary().hardware_address()
     }
 
     /// Get whether the link is up.
     pub fn is_link_up(&self) -> bool {
-        self.with(|i| i.link_up)
+        self.primary().is_link_up()
     }
 
     /// Get whether the network stack has a valid IP configuration.
@@ -493,35 +717,151 @@ impl<'d> Stack<'d> {
     ///
     /// If using DHCP, this will be None if DHCP hasn't been able to
     /// acquire an IP address, or Some if it has.
+    ///
+    /// If using link-local addressing, this is the claimed link-local address without
+    /// gateway or DNS servers, or the DHCP lease if one was obtained. Use
+    /// [`StaticConfigV4::is_link_local()`] to tell them apart.
     #[cfg(feature = "proto-ipv4")]
     pub fn config_v4(&self) -> Option<StaticConfigV4> {
-        self.with(|i| i.static_v4.clone())
+        self.primary().config_v4()
     }
 
     /// Get the current IPv6 configuration.
     #[cfg(feature = "proto-ipv6")]
     pub fn config_v6(&self) -> Option<StaticConfigV6> {
-        self.with(|i| i.static_v6.clone())
+        self.primary().config_v6()
     }
 
     /// Set the IPv4 configuration.
     #[cfg(feature = "proto-ipv4")]
     pub fn set_config_v4(&self, config: ConfigV4) {
-        self.with_mut(|i| {
-            i.set_config_v4(config);
-            i.apply_static_config();
-        })
+        self.primary().set_config_v4(config)
     }
 
     /// Set the IPv6 configuration.
     #[cfg(feature = "proto-ipv6")]
     pub fn set_config_v6(&self, config: ConfigV6) {
+        self.primary().set_config_v6(config)
+    }
+
+    fn primary(&self) -> NetInterface<'d> {
+        NetInterface {
+            stack: *self,
+            id: InterfaceId::PRIMARY,
+        }
+    }
+
+    /// Add a network interface to the stack.
+    ///
+    /// The returned [`Runner`] must be run in a background task for the interface to work,
+    /// just like the one returned by [`new()`]. Use [`Runner::interface_id()`] to get the
+    /// identifier of the new interface.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stack already has as many interfaces as the `IFACES` parameter of the
+    /// [`StackResources`] it was created with.
+    pub fn add_interface<D: Driver>(&self, mut driver: D, config: Config) -> Runner<'d, D> {
+        let id = self.with_mut(|i| i.add_interface(&mut driver, config));
+        Runner {
+            driver,
+            stack: *self,
+            id,
+            hooks: RunnerHooks::default(),
+        }
+    }
+
+    /// Get a handle to the given interface, or `None` if the stack has no such interface.
+    pub fn interface(&self, id: InterfaceId) -> Option<NetInterface<'d>> {
+        self.with(|i| i.has_interface(id))
+            .then_some(NetInterface { stack: *self, id })
+    }
+
+    /// Iterate over the identifiers of all the interfaces of the stack.
+    pub fn interfaces(&self) -> impl Iterator<Item = InterfaceId> + 'd {
+        let count = self.with(|i| i.ifaces.iter().take_while(|x| x.is_some()).count());
+        (0..count as u8).map(InterfaceId)
+    }
+
+    /// Add a route to the routing table.
+    ///
+    /// If a route to the same destination through the same interface already exists,
+    /// its metric is updated.
+    pub fn add_route(&self, route: Route) -> Result<(), RouteError> {
+        self.with_mut(|i| {
+            if !i.has_interface(route.interface) {
+                return Err(RouteError::InvalidInterface);
+            }
+            if let Some(r) = i
+                .routes
+                .iter_mut()
+                .find(|r| r.destination == route.destination && r.interface == route.interface)
+            {
+                r.metric = route.metric;
+            } else {
+                i.routes.push(route).map_err(|_| RouteError::TableFull)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove a route from the routing table.
+    ///
+    /// Returns `true` if a route to `destination` through `interface` was found and removed.
+    pub fn remove_route(&self, destination: IpCidr, interface: InterfaceId) -> bool {
         self.with_mut(|i| {
-            i.set_config_v6(config);
-            i.apply_static_config();
+            match i
+                .routes
+                .iter()
+                .position(|r| r.destination == destination && r.interface == interface)
+            {
+                Some(pos) => {
+                    i.routes.remove(pos);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Get the routes added with [`Stack::add_route`].
+    ///
+    /// Implicit routes derived from the interfaces' configuration are not included.
+    pub fn routes(&self) -> Vec<Route, MAX_ROUTES> {
+        self.with(|i| i.routes.clone())
+    }
+
+    /// Get the interface that would be used to reach `addr`, if any.
+    pub fn route_to(&self, addr: IpAddress) -> Option<InterfaceId> {
+        self.with(|i| i.route(&addr))
+    }
+
+    /// Get a snapshot of the traffic counters, summed over all interfaces.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> InterfaceStats {
+        self.with(|i| {
+            let mut stats = InterfaceStats::default();
+            for iface in i.ifaces.iter().flatten() {
+                stats.accumulate(&iface.stats.get());
+            }
+            stats
         })
     }
 
+    /// Reset the traffic counters of all interfaces to zero.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.with(|i| i.ifaces.iter().flatten().for_each(|iface| iface.stats.reset()))
+    }
+
+    /// Get the interface currently selected by the default route.
+    ///
+    /// This is the interface with the lowest metric default route whose link is up. If no
+    /// default route is usable, the primary interface is returned.
+    pub fn default_interface(&self) -> InterfaceId {
+        self.with(|i| i.default_interface())
+    }
+
     /// Make a query for a given name and return the corresponding IP addresses.
     #[cfg(feature = "dns")]
     pub async fn dns_query(
@@ -546,12 +886,15 @@ impl<'d> Stack<'d> {
             _ => {}
         }
 
+        let id = self.default_interface();
+
         let query = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
-                match socket.start_query(i.iface.context(), name, qtype) {
+                let iface = unwrap!(i.ifaces[id.index()].as_mut());
+                let socket = iface.sockets.get_mut::<dns::Socket>(iface.dns_socket);
+                match socket.start_query(iface.iface.context(), name, qtype) {
                     Ok(handle) => {
-                        i.waker.wake();
+                        iface.waker.wake();
                         Poll::Ready(Ok(handle))
                     }
                     Err(dns::StartQueryError::NoFreeSlot) => {
@@ -589,16 +932,18 @@ impl<'d> Stack<'d> {
 
         let drop = OnDrop::new(|| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
+                let iface = unwrap!(i.ifaces[id.index()].as_mut());
+                let socket = iface.sockets.get_mut::<dns::Socket>(iface.dns_socket);
                 socket.cancel_query(query);
-                i.waker.wake();
+                iface.waker.wake();
                 i.dns_waker.wake();
             })
         });
 
         let res = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
+                let iface = unwrap!(i.ifaces[id.index()].as_mut());
+                let socket = iface.sockets.get_mut::<dns::Socket>(iface.dns_socket);
                 match socket.get_query_result(query) {
                     Ok(addrs) => {
                         i.dns_waker.wake();
@@ -626,16 +971,138 @@ impl<'d> Stack<'d> {
 #[cfg(feature = "multicast")]
 impl<'d> Stack<'d> {
     /// Join a multicast group.
+    pub fn join_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
+        self.primary().join_multicast_group(addr)
+    }
+
+    /// Leave a multicast group.
+    pub fn leave_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
+        self.primary().leave_multicast_group(addr)
+    }
+
+    /// Get whether the network stack has joined the given multicast group.
+    pub fn has_multicast_group(&self, addr: impl Into<IpAddress>) -> bool {
+        self.primary().has_multicast_group(addr)
+    }
+}
+
+impl<'d> NetInterface<'d> {
+    fn with<R>(&self, f: impl FnOnce(&InterfaceInner) -> R) -> R {
+        self.stack.with(|i| f(i.iface(self.id)))
+    }
+
+    #[cfg(feature = "multicast")]
+    fn with_mut<R>(&self, f: impl FnOnce(&mut InterfaceInner) -> R) -> R {
+        self.stack.with_mut(|i| f(i.iface_mut(self.id)))
+    }
+
+    /// Get the identifier of this interface.
+    pub fn id(&self) -> InterfaceId {
+        self.id
+    }
+
+    /// Get the hardware address of the network interface.
+    pub fn hardware_address(&self) -> HardwareAddress {
+        self.with(|i| i.hardware_address)
+    }
+
+    /// Get whether the link is up.
+    pub fn is_link_up(&self) -> bool {
+        self.with(|i| i.link_up)
+    }
+
+    /// Get whether the interface has a valid IP configuration.
+    pub fn is_config_up(&self) -> bool {
+        self.with(|i| i.is_config_up())
+    }
+
+    /// Wait for the network device to obtain a link signal.
+    pub async fn wait_link_up(&self) {
+        self.stack.wait(|| self.is_link_up()).await
+    }
+
+    /// Wait for the network device to lose link signal.
+    pub async fn wait_link_down(&self) {
+        self.stack.wait(|| !self.is_link_up()).await
+    }
+
+    /// Wait for the interface to obtain a valid IP configuration.
+    pub async fn wait_config_up(&self) {
+        self.stack.wait(|| self.is_config_up()).await
+    }
+
+    /// Wait for the interface to lose a valid IP configuration.
+    pub async fn wait_config_down(&self) {
+        self.stack.wait(|| !self.is_config_up()).await
+    }
+
+    /// Get the current IPv4 configuration.
+    ///
+    /// If using DHCP, this will be None if DHCP hasn't been able to
+    /// acquire an IP address, or Some if it has.
+    ///
+    /// If using link-local addressing, this is the claimed link-local address without
+    /// gateway or DNS servers, or the DHCP lease if one was obtained. Use
+    /// [`StaticConfigV4::is_link_local()`] to tell them apart.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn config_v4(&self) -> Option<StaticConfigV4> {
+        self.with(|i| i.static_v4.clone())
+    }
+
+    /// Get the current IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn config_v6(&self) -> Option<StaticConfigV6> {
+        self.with(|i| i.static_v6.clone())
+    }
+
+    /// Set the IPv4 configuration.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn set_config_v4(&self, config: ConfigV4) {
+        self.stack.with_mut(|i| {
+            let iface = i.iface_mut(self.id);
+            iface.set_config_v4(config);
+            iface.apply_static_config();
+            i.state_waker.wake();
+        })
+    }
+
+    /// Set the IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn set_config_v6(&self, config: ConfigV6) {
+        self.stack.with_mut(|i| {
+            let iface = i.iface_mut(self.id);
+            iface.set_config_v6(config);
+            iface.apply_static_config();
+            i.state_waker.wake();
+        })
+    }
+
+    /// Get a snapshot of the traffic counters of this interface.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> InterfaceStats {
+        self.with(|i| i.stats.get())
+    }
+
+    /// Reset the traffic counters of this interface to zero.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.with(|i| i.stats.reset())
+    }
+
+    /// Join a multicast group.
+    #[cfg(feature = "multicast")]
     pub fn join_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
         self.with_mut(|i| i.iface.join_multicast_group(addr))
     }
 
     /// Leave a multicast group.
+    #[cfg(feature = "multicast")]
     pub fn leave_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
         self.with_mut(|i| i.iface.leave_multicast_group(addr))
     }
 
-    /// Get whether the network stack has joined the given multicast group.
+    /// Get whether the interface has joined the given multicast group.
+    #[cfg(feature = "multicast")]
     pub fn has_multicast_group(&self, addr: impl Into<IpAddress>) -> bool {
         self.with(|i| i.iface.has_multicast_group(addr))
     }
@@ -649,6 +1116,263 @@ impl Inner {
         res
     }
 
+    fn has_interface(&self, id: InterfaceId) -> bool {
+        matches!(self.ifaces.get(id.index()), Some(Some(_)))
+    }
+
+    pub(crate) fn iface(&self, id: InterfaceId) -> &InterfaceInner {
+        unwrap!(self.ifaces[id.index()].as_ref())
+    }
+
+    pub(crate) fn iface_mut(&mut self, id: InterfaceId) -> &mut InterfaceInner {
+        unwrap!(self.ifaces[id.index()].as_mut())
+    }
+
+    fn add_interface<D: Driver>(&mut self, driver: &mut D, config: Config) -> InterfaceId {
+        let index = self.ifaces.iter().position(|x| x.is_none());
+        let index = unwrap!(
+            index,
+            "No free interface slot. Increase the IFACES parameter of StackResources."
+        );
+        let id = InterfaceId(index as u8);
+
+        let (hardware_address, medium) = to_smoltcp_hardware_address(driver.hardware_address());
+        let mut iface_cfg = smoltcp::iface::Config::new(hardware_address);
+        iface_cfg.random_seed = self.random_seed.wrapping_add(index as u64);
+        #[cfg(feature = "slaac")]
+        let random_seed = iface_cfg.random_seed;
+
+        let stats = Stats::new();
+
+        let iface = Interface::new(
+            iface_cfg,
+            &mut DriverAdapter {
+                inner: driver,
+                cx: None,
+                medium,
+                stats: &stats,
+                hooks: RunnerHooks::default(),
+                #[cfg(feature = "autoip")]
+                arp_watch: None,
+            },
+            instant_to_smoltcp(Instant::now()),
+        );
+
+        let (sockets, spare_sockets) = mem::take(&mut self.spare_sockets).split_at_mut(self.sockets_per_iface);
+        self.spare_sockets = spare_sockets;
+        #[allow(unused_mut)]
+        let mut sockets: SocketSet<'static> = SocketSet::new(sockets);
+
+        #[cfg(feature = "dns")]
+        let dns_socket = {
+            let (queries, spare_queries) = unwrap!(mem::take(&mut self.spare_queries).split_first_mut());
+            self.spare_queries = spare_queries;
+            sockets.add(dns::Socket::new(&[], managed::ManagedSlice::Borrowed(&mut queries[..])))
+        };
+
+        #[cfg(feature = "dhcpv4-hostname")]
+        let hostname = {
+            let (hostname, spare_hostnames) = unwrap!(mem::take(&mut self.spare_hostnames).split_first_mut());
+            self.spare_hostnames = spare_hostnames;
+            hostname
+        };
+
+        #[cfg(feature = "slaac")]
+        let slaac_resources = {
+            let (resources, spare_slaac) = unwrap!(mem::take(&mut self.spare_slaac).split_first_mut());
+            self.spare_slaac = spare_slaac;
+            resources
+        };
+
+        let mut iface = InterfaceInner {
+            sockets,
+            iface,
+            waker: WakerRegistration::new(),
+            hardware_address,
+            link_up: false,
+            #[cfg(feature = "proto-ipv4")]
+            static_v4: None,
+            #[cfg(feature = "proto-ipv6")]
+            static_v6: None,
+            #[cfg(feature = "dhcpv4")]
+            dhcp_socket: None,
+            #[cfg(feature = "dns")]
+            dns_socket,
+            #[cfg(feature = "dhcpv4-hostname")]
+            hostname,
+            #[cfg(feature = "slaac")]
+            slaac: None,
+            #[cfg(feature = "slaac")]
+            slaac_resources,
+            #[cfg(feature = "slaac")]
+            random_seed,
+            #[cfg(feature = "autoip")]
+            autoip: None,
+            #[cfg(feature = "autoip")]
+            arp_watch: autoip::ArpWatch::new(),
+            stats,
+        };
+
+        #[cfg(feature = "proto-ipv4")]
+        iface.set_config_v4(config.ipv4);
+        #[cfg(feature = "proto-ipv6")]
+        iface.set_config_v6(config.ipv6);
+        iface.apply_static_config();
+
+        self.ifaces[index] = Some(iface);
+        self.state_waker.wake();
+        id
+    }
+
+    /// Find the interface to use to reach `addr`.
+    pub(crate) fn route(&self, addr: &IpAddress) -> Option<InterfaceId> {
+        let mut best: Option<(u8, u32, InterfaceId)> = None;
+        self.for_each_route(|route| {
+            if !route.destination.contains_addr(addr) {
+                return;
+            }
+            let better = match best {
+                None => true,
+                Some((prefix_len, metric, _)) => {
+                    route.destination.prefix_len() > prefix_len
+                        || (route.destination.prefix_len() == prefix_len && route.metric < metric)
+                }
+            };
+            if better {
+                best = Some((route.destination.prefix_len(), route.metric, route.interface));
+            }
+        });
+        best.map(|(_, _, id)| id)
+    }
+
+    /// Find the interface that has `addr` configured.
+    #[allow(unused)]
+    pub(crate) fn interface_with_addr(&self, addr: &IpAddress) -> Option<InterfaceId> {
+        self.ifaces
+            .iter()
+            .position(|i| i.as_ref().is_some_and(|i| i.iface.has_ip_addr(*addr)))
+            .map(|index| InterfaceId(index as u8))
+    }
+
+    fn default_interface(&self) -> InterfaceId {
+        let mut best: Option<(u32, InterfaceId)> = None;
+        self.for_each_route(|route| {
+            let better = !matches!(best, Some((metric, _)) if metric <= route.metric);
+            if route.destination.prefix_len() == 0 && better {
+                best = Some((route.metric, route.interface));
+            }
+        });
+        best.map_or(InterfaceId::PRIMARY, |(_, id)| id)
+    }
+
+    /// Call `f` for every usable route: the explicit ones and the ones implied by the
+    /// interfaces' configuration, skipping interfaces that are down.
+    fn for_each_route(&self, mut f: impl FnMut(&Route)) {
+        for route in self.routes.iter().filter(|r| self.iface(r.interface).link_up) {
+            f(route);
+        }
+
+        for (index, iface) in self.ifaces.iter().enumerate() {
+            let Some(iface) = iface else { continue };
+            if !iface.link_up {
+                continue;
+            }
+            let interface = InterfaceId(index as u8);
+            let default_metric = IMPLICIT_DEFAULT_ROUTE_METRIC + index as u32;
+
+            #[cfg(feature = "proto-ipv4")]
+            if let Some(config) = &iface.static_v4 {
+                f(&Route {
+                    destination: IpCidr::Ipv4(config.address),
+                    interface,
+                    metric: 0,
+                });
+                if config.gateway.is_some() {
+                    f(&Route {
+                        destination: IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0)),
+                        interface,
+                        metric: default_metric,
+                    });
+                }
+            }
+
+            #[cfg(feature = "proto-ipv6")]
+            if let Some(config) = &iface.static_v6 {
+                f(&Route {
+                    destination: IpCidr::Ipv6(config.address),
+                    interface,
+                    metric: 0,
+                });
+                if config.gateway.is_some() {
+                    f(&Route {
+                        destination: IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0)),
+                        interface,
+                        metric: default_metric,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Move a socket to another interface.
+    ///
+    /// Returns the handle of the socket in the new interface's socket set, or `None`
+    /// if that set is full, in which case the socket is left untouched.
+    #[cfg(any(feature = "tcp", feature = "udp", feature = "raw"))]
+    pub(crate) fn move_socket(
+        &mut self,
+        handle: SocketHandle,
+        from: InterfaceId,
+        to: InterfaceId,
+    ) -> Option<SocketHandle> {
+        if from == to {
+            return Some(handle);
+        }
+        use smoltcp::socket::Socket;
+
+        if self.iface(to).sockets.iter().count() >= self.sockets_per_iface {
+            return None;
+        }
+
+        let socket = self.iface_mut(from).sockets.remove(handle);
+        let target = self.iface_mut(to);
+        let handle = match socket {
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(s) => target.sockets.add(s),
+            #[cfg(feature = "udp")]
+            Socket::Udp(s) => target.sockets.add(s),
+            #[cfg(feature = "raw")]
+            Socket::Raw(s) => target.sockets.add(s),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        target.waker.wake();
+        self.iface_mut(from).waker.wake();
+        Some(handle)
+    }
+
+    fn poll<D: Driver>(&mut self, id: InterfaceId, cx: &mut Context<'_>, driver: &mut D, hooks: RunnerHooks<'_>) {
+        if self.iface_mut(id).poll(cx, driver, hooks) {
+            self.state_waker.wake();
+        }
+    }
+}
+
+impl InterfaceInner {
+    fn is_config_up(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut up = false;
+        #[cfg(feature = "proto-ipv4")]
+        {
+            up |= self.static_v4.is_some();
+        }
+        #[cfg(feature = "proto-ipv6")]
+        {
+            up |= self.static_v6.is_some();
+        }
+        up
+    }
+
     #[cfg(feature = "proto-ipv4")]
     pub fn set_config_v4(&mut self, config: ConfigV4) {
         // Handle static config.
@@ -656,13 +1380,22 @@ impl Inner {
             ConfigV4::None => None,
             #[cfg(feature = "dhcpv4")]
             ConfigV4::Dhcp(_) => None,
+            #[cfg(feature = "autoip")]
+            ConfigV4::AutoIp(_) => None,
             ConfigV4::Static(c) => Some(c),
         };
 
         // Handle DHCP config.
         #[cfg(feature = "dhcpv4")]
-        match config {
-            ConfigV4::Dhcp(c) => {
+        let dhcp = match &config {
+            ConfigV4::Dhcp(c) => Some(c),
+            #[cfg(feature = "autoip")]
+            ConfigV4::AutoIp(c) => c.dhcp.as_ref(),
+            _ => None,
+        };
+        #[cfg(feature = "dhcpv4")]
+        match dhcp {
+            Some(c) => {
                 // Create the socket if it doesn't exist.
                 if self.dhcp_socket.is_none() {
                     let socket = smoltcp::socket::dhcpv4::Socket::new();
@@ -679,7 +1412,7 @@ impl Inner {
 
                 socket.set_outgoing_options(&[]);
                 #[cfg(feature = "dhcpv4-hostname")]
-                if let Some(h) = c.hostname {
+                if let Some(h) = &c.hostname {
                     // safety:
                     // - we just did set_outgoing_options([]) so we know the socket is no longer holding a reference.
                     // - we know this pointer lives for as long as the stack exists, because `new()` borrows
@@ -698,7 +1431,7 @@ impl Inner {
 
                 socket.reset();
             }
-            _ => {
+            None => {
                 // Remove DHCP socket if any.
                 if let Some(socket) = self.dhcp_socket {
                     self.sockets.remove(socket);
@@ -706,14 +1439,60 @@ impl Inner {
                 }
             }
         }
+
+        // Handle link-local config.
+        #[cfg(feature = "autoip")]
+        {
+            self.autoip = None;
+            if let ConfigV4::AutoIp(_c) = &config {
+                #[allow(unreachable_patterns)]
+                match self.hardware_address {
+                    HardwareAddress::Ethernet(mac) => {
+                        #[allow(unused_mut)]
+                        let mut initial_delay = embassy_time::Duration::from_ticks(0);
+                        #[cfg(feature = "dhcpv4")]
+                        if _c.dhcp.is_some() {
+                            initial_delay = _c.dhcp_timeout;
+                        }
+                        self.autoip = Some(autoip::AutoIp::new(mac, initial_delay));
+                    }
+                    _ => warn!("IPv4 link-local addressing is only supported on Ethernet interfaces."),
+                }
+            }
+        }
     }
 
     #[cfg(feature = "proto-ipv6")]
     pub fn set_config_v6(&mut self, config: ConfigV6) {
-        self.static_v6 = match config {
+        // Handle static config.
+        self.static_v6 = match config.clone() {
             ConfigV6::None => None,
+            #[cfg(feature = "slaac")]
+            ConfigV6::Slaac(_) => None,
             ConfigV6::Static(c) => Some(c),
         };
+
+        // Handle SLAAC config.
+        #[cfg(feature = "slaac")]
+        {
+            if let Some(slaac) = self.slaac.take() {
+                slaac.remove(&mut self.sockets);
+            }
+            if let ConfigV6::Slaac(c) = config {
+                // safety: the previous sockets using the resources were just removed, and the
+                // resources live for as long as the stack exists, because `new()` borrows them for `'d`.
+                let slaac = unsafe {
+                    slaac::Slaac::new(
+                        c,
+                        self.hardware_address,
+                        self.random_seed,
+                        self.slaac_resources,
+                        &mut self.sockets,
+                    )
+                };
+                self.slaac = Some(slaac);
+            }
+        }
     }
 
     fn apply_static_config(&mut self) {
@@ -759,6 +1538,14 @@ impl Inner {
             info!("IPv6: DOWN");
         }
 
+        #[cfg(feature = "slaac")]
+        if let Some(link_local) = self.slaac.as_ref().and_then(|s| s.link_local()) {
+            debug!("   Link-local:      {:?}", link_local);
+            if addrs.push(IpCidr::Ipv6(link_local)).is_err() {
+                warn!("No room for the IPv6 link-local address, increase SMOLTCP_IFACE_MAX_ADDR_COUNT.");
+            }
+        }
+
         // Apply addresses
         self.iface.update_ip_addrs(|a| *a = addrs);
 
@@ -789,11 +1576,10 @@ impl Inner {
                 .get_mut::<smoltcp::socket::dns::Socket>(self.dns_socket)
                 .update_servers(&dns_servers[..count]);
         }
-
-        self.state_waker.wake();
     }
 
-    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, driver: &mut D) {
+    /// Poll the interface. Returns whether the link state or the IP configuration changed.
+    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, driver: &mut D, hooks: RunnerHooks<'_>) -> bool {
         self.waker.register(cx.waker());
 
         let (_hardware_addr, medium) = to_smoltcp_hardware_address(driver.hardware_address());
@@ -818,17 +1604,22 @@ impl Inner {
             cx: Some(cx),
             inner: driver,
             medium,
+            stats: &self.stats,
+            hooks,
+            #[cfg(feature = "autoip")]
+            arp_watch: self.autoip.as_ref().map(|_| &self.arp_watch),
         };
         self.iface.poll(timestamp, &mut smoldev, &mut self.sockets);
+        self.stats.on_driver_rx_dropped(driver.rx_dropped());
 
         // Update link up
         let old_link_up = self.link_up;
         self.link_up = driver.link_state(cx) == LinkState::Up;
 
         // Print when changed
-        if old_link_up != self.link_up {
+        let link_changed = old_link_up != self.link_up;
+        if link_changed {
             info!("link_up = {:?}", self.link_up);
-            self.state_waker.wake();
         }
 
         #[allow(unused_mut)]
@@ -847,6 +1638,10 @@ impl Inner {
                     Some(dhcpv4::Event::Deconfigured) => {
                         self.static_v4 = None;
                         apply_config = true;
+                        #[cfg(feature = "autoip")]
+                        if let Some(autoip) = &mut self.autoip {
+                            autoip.start();
+                        }
                     }
                     Some(dhcpv4::Event::Configured(config)) => {
                         self.static_v4 = Some(StaticConfigV4 {
@@ -855,6 +1650,10 @@ impl Inner {
                             dns_servers: config.dns_servers,
                         });
                         apply_config = true;
+                        #[cfg(feature = "autoip")]
+                        if let Some(autoip) = &mut self.autoip {
+                            autoip.stop();
+                        }
                     }
                 }
             } else if old_link_up {
@@ -864,26 +1663,107 @@ impl Inner {
             }
         }
 
+        #[cfg(feature = "autoip")]
+        if let Some(autoip) = &mut self.autoip {
+            if self.link_up {
+                if old_link_up != self.link_up {
+                    autoip.reset();
+                }
+                let mut smoldev = DriverAdapter {
+                    cx: Some(cx),
+                    inner: driver,
+                    medium,
+                    stats: &self.stats,
+                    hooks,
+                    arp_watch: Some(&self.arp_watch),
+                };
+                // Once stopped, the configuration is DHCP's.
+                if autoip.poll(&self.arp_watch, &mut smoldev) && !autoip.is_stopped() {
+                    self.static_v4 = autoip.address().map(|address| StaticConfigV4 {
+                        address: Ipv4Cidr::new(address, 16),
+                        gateway: None,
+                        dns_servers: Vec::new(),
+                    });
+                    apply_config = true;
+                }
+            } else if old_link_up {
+                autoip.reset();
+                self.static_v4 = None;
+                apply_config = true;
+            }
+        }
+
+        #[cfg(feature = "slaac")]
+        if let Some(slaac) = &mut self.slaac {
+            if self.link_up {
+                if old_link_up != self.link_up {
+                    slaac.reset();
+                }
+                if slaac.poll(&mut self.sockets) {
+                    self.static_v6 = slaac.config();
+                    apply_config = true;
+                }
+            } else if old_link_up {
+                slaac.reset();
+                self.static_v6 = None;
+                apply_config = true;
+            }
+        }
+
         if apply_config {
             self.apply_static_config();
         }
 
-        if let Some(poll_at) = self.iface.poll_at(timestamp, &mut self.sockets) {
-            let t = pin!(Timer::at(instant_from_smoltcp(poll_at)));
+        #[allow(unused_mut)]
+        let mut poll_at = self.iface.poll_at(timestamp, &self.sockets).map(instant_from_smoltcp);
+        #[cfg(feature = "slaac")]
+        if let Some(slaac_at) = self.slaac.as_ref().filter(|_| self.link_up).and_then(|s| s.poll_at()) {
+            poll_at = Some(poll_at.map_or(slaac_at, |at| at.min(slaac_at)));
+        }
+        #[cfg(feature = "autoip")]
+        if let Some(autoip_at) = self.autoip.as_ref().filter(|_| self.link_up).and_then(|a| a.poll_at()) {
+            poll_at = Some(poll_at.map_or(autoip_at, |at| at.min(autoip_at)));
+        }
+        if let Some(poll_at) = poll_at {
+            let t = pin!(Timer::at(poll_at));
             if t.poll(cx).is_ready() {
                 cx.waker().wake_by_ref();
             }
         }
+
+        link_changed || apply_config
     }
 }
 
 impl<'d, D: Driver> Runner<'d, D> {
+    /// Get the identifier of the interface driven by this runner.
+    pub fn interface_id(&self) -> InterfaceId {
+        self.id
+    }
+
+    /// Set a sink for a copy of every frame received or transmitted on this interface.
+    ///
+    /// Pass `None` to stop capturing. See the [`pcap`] module for details.
+    #[cfg(feature = "pcap")]
+    pub fn set_capture(&mut self, capture: Option<&'d dyn pcap::Capture>) {
+        self.hooks.capture = capture;
+    }
+
+    /// Set a filter deciding whether each frame received or transmitted on this interface is accepted.
+    ///
+    /// Pass `None` to accept all frames. See the [`filter`] module for details.
+    #[cfg(feature = "filter")]
+    pub fn set_filter(&mut self, filter: Option<&'d dyn filter::Filter>) {
+        self.hooks.filter = filter;
+    }
+
     /// Run the network stack.
     ///
     /// You must call this in a background task, to process network events.
     pub async fn run(&mut self) -> ! {
         poll_fn(|cx| {
-            self.stack.with_mut(|i| i.poll(cx, &mut self.driver));
+            self.stack
+                .with_mut(|i| i.poll(self.id, cx, &mut self.driver, self.hooks));
             Poll::<()>::Pending
         })
         .await;
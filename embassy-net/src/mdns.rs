@@ -0,0 +1,1430 @@
+//! mDNS responder, with DNS-SD service advertisement.
+//!
+//! [`Responder`] answers queries for `<hostname>.local` with the addresses of its interface, and
+//! advertises a list of DNS-SD [`Service`]s, so that devices can be found on the local network
+//! without knowing their address (RFC 6762 and RFC 6763).
+//!
+//! Names are probed for uniqueness before they're announced. If another device already uses one,
+//! the responder picks a new name by appending a number, e.g. `device-2.local` for the hostname or
+//! `Thermostat (2)` for a service instance.
+
+use core::fmt::Write as _;
+
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use heapless::{String, Vec};
+
+use crate::udp::{UdpMetadata, UdpSocket};
+#[cfg(feature = "proto-ipv4")]
+use crate::Ipv4Address;
+#[cfg(feature = "proto-ipv6")]
+use crate::Ipv6Address;
+use crate::{IpAddress, IpEndpoint, NetInterface};
+
+const PORT: u16 = 5353;
+#[cfg(feature = "proto-ipv4")]
+const GROUP_V4: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+#[cfg(feature = "proto-ipv6")]
+const GROUP_V6: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// Maximum number of services advertised by a [`Responder`].
+pub const MAX_SERVICES: usize = 8;
+/// Maximum length of a DNS label, which applies to the hostname and to service instance names.
+pub const MAX_NAME_LEN: usize = 63;
+
+const MAX_PACKET: usize = 1460;
+const MAX_LABELS: usize = 16;
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+const LEGACY_TTL: u32 = 10;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+const CONFLICT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_CONFLICTS: u8 = 15;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const CACHE_FLUSH: u16 = 0x8000;
+const UNICAST_RESPONSE: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK: u16 = 0x7800;
+
+/// A DNS-SD service instance advertised by a [`Responder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Service<'a> {
+    /// Instance name, shown to users when browsing, e.g. `"Living room thermostat"`.
+    ///
+    /// At most [`MAX_NAME_LEN`] bytes.
+    pub instance: &'a str,
+    /// Service type and transport protocol, e.g. `"_http._tcp"`.
+    pub service: &'a str,
+    /// Port the service listens on.
+    pub port: u16,
+    /// TXT record entries, e.g. `&["path=/", "version=1"]`. Each entry is at most 255 bytes.
+    pub txt: &'a [&'a str],
+}
+
+/// mDNS responder.
+///
+/// Create it with an unbound [`UdpSocket`], whose buffers should hold a few packets of up to 1.5 kB,
+/// then call [`run()`](Responder::run) in a background task. It answers on the interface the socket is
+/// attached to, with the addresses configured on that interface.
+pub struct Responder<'a> {
+    socket: UdpSocket<'a>,
+    interface: NetInterface<'a>,
+    hostname: &'a str,
+    services: &'a [Service<'a>],
+    host_name: String<MAX_NAME_LEN>,
+    host_suffix: u32,
+    instance_names: Vec<(String<MAX_NAME_LEN>, u32), MAX_SERVICES>,
+    #[cfg(feature = "proto-ipv4")]
+    addr_v4: Option<Ipv4Address>,
+    #[cfg(feature = "proto-ipv6")]
+    addr_v6: Option<Ipv6Address>,
+    conflicts: u8,
+    rand: u32,
+}
+
+/// A name owned by the responder, which must be unique on the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Owned {
+    Host,
+    Instance(usize),
+}
+
+enum ProbeResult {
+    Continue,
+    Conflict(Owned),
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Record {
+    A,
+    Aaaa,
+    /// `_services._dns-sd._udp.local` PTR for the service type.
+    Enumeration(usize),
+    Ptr(usize),
+    Srv(usize),
+    Txt(usize),
+}
+
+impl Record {
+    fn bit(self) -> u64 {
+        match self {
+            Record::A => 1,
+            Record::Aaaa => 1 << 1,
+            Record::Enumeration(i) => 1 << (2 + 4 * i),
+            Record::Ptr(i) => 1 << (3 + 4 * i),
+            Record::Srv(i) => 1 << (4 + 4 * i),
+            Record::Txt(i) => 1 << (5 + 4 * i),
+        }
+    }
+
+    fn rtype(self) -> u16 {
+        match self {
+            Record::A => TYPE_A,
+            Record::Aaaa => TYPE_AAAA,
+            Record::Enumeration(_) | Record::Ptr(_) => TYPE_PTR,
+            Record::Srv(_) => TYPE_SRV,
+            Record::Txt(_) => TYPE_TXT,
+        }
+    }
+
+    fn unique(self) -> bool {
+        !matches!(self, Record::Enumeration(_) | Record::Ptr(_))
+    }
+
+    fn ttl(self) -> u32 {
+        match self {
+            Record::A | Record::Aaaa | Record::Srv(_) => HOST_TTL,
+            _ => SERVICE_TTL,
+        }
+    }
+
+    fn owner(self) -> Option<Owned> {
+        match self {
+            Record::A | Record::Aaaa => Some(Owned::Host),
+            Record::Srv(i) | Record::Txt(i) => Some(Owned::Instance(i)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Responder<'a> {
+    /// Create a new responder, answering for `<hostname>.local` and advertising `services`.
+    ///
+    /// The responder runs on the interface the socket was bound to with
+    /// [`UdpSocket::bind_interface`], or on the primary interface.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than [`MAX_SERVICES`] services, if a name is longer than
+    /// [`MAX_NAME_LEN`], or if the socket is already bound.
+    pub fn new(mut socket: UdpSocket<'a>, hostname: &'a str, services: &'a [Service<'a>]) -> Self {
+        assert!(services.len() <= MAX_SERVICES);
+        let host_name = unwrap!(String::try_from(hostname).ok(), "hostname too long");
+        let mut instance_names = Vec::new();
+        for service in services {
+            let name = unwrap!(String::try_from(service.instance).ok(), "instance name too long");
+            unwrap!(instance_names.push((name, 1)).ok());
+        }
+
+        // Names are announced with the addresses of a single interface.
+        let interface = socket.interface();
+        unwrap!(socket.bind(PORT));
+        socket.set_hop_limit(Some(255));
+        let interface = unwrap!(socket.stack().interface(interface));
+
+        let mut rand = Instant::now().as_ticks() as u32 | 1;
+        for b in hostname.bytes() {
+            rand = rand.rotate_left(5) ^ b as u32;
+        }
+
+        Self {
+            socket,
+            interface,
+            hostname,
+            services,
+            host_name,
+            host_suffix: 1,
+            instance_names,
+            #[cfg(feature = "proto-ipv4")]
+            addr_v4: None,
+            #[cfg(feature = "proto-ipv6")]
+            addr_v6: None,
+            conflicts: 0,
+            rand,
+        }
+    }
+
+    /// Get the hostname currently in use, without the `.local` suffix.
+    ///
+    /// This differs from the configured hostname after a conflict.
+    pub fn hostname(&self) -> &str {
+        &self.host_name
+    }
+
+    /// Get the instance name currently in use for service `index`.
+    ///
+    /// This differs from the configured instance name after a conflict.
+    pub fn instance_name(&self, index: usize) -> &str {
+        &self.instance_names[index].0
+    }
+
+    /// Run the responder.
+    ///
+    /// This waits for the interface to get an address, probes and announces the names, then
+    /// answers queries. The names are probed again when the addresses of the interface change.
+    pub async fn run(&mut self) -> ! {
+        let mut rx = [0; MAX_PACKET];
+        let mut tx = [0; MAX_PACKET];
+        loop {
+            self.interface.wait_config_up().await;
+            self.update_addresses();
+            self.probe(&mut rx, &mut tx).await;
+            info!("mdns: announcing {}.local", self.host_name.as_str());
+            self.serve(&mut rx, &mut tx).await;
+        }
+    }
+
+    /// Send a goodbye announcement, so that other devices flush their cached records immediately.
+    ///
+    /// Call this before shutting down or leaving the network.
+    pub async fn goodbye(&mut self) {
+        let mut tx = [0; MAX_PACKET];
+        let n = self.write_announcement(&mut tx, Some(0));
+        self.send_multicast(&tx[..n]).await;
+    }
+
+    fn random(&mut self) -> u32 {
+        // xorshift32
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 17;
+        self.rand ^= self.rand << 5;
+        self.rand
+    }
+
+    /// Read the addresses of the interface, and join the multicast groups. Returns whether they changed.
+    fn update_addresses(&mut self) -> bool {
+        #[allow(unused_mut)]
+        let mut changed = false;
+        #[cfg(feature = "proto-ipv4")]
+        {
+            let addr = self.interface.config_v4().map(|c| c.address.address());
+            if addr.is_some() {
+                if let Err(e) = self.interface.join_multicast_group(GROUP_V4) {
+                    warn!("mdns: failed to join multicast group: {:?}", e);
+                }
+            }
+            changed |= addr != self.addr_v4;
+            self.addr_v4 = addr;
+        }
+        #[cfg(feature = "proto-ipv6")]
+        {
+            let addr = self.interface.config_v6().map(|c| c.address.address());
+            if addr.is_some() {
+                if let Err(e) = self.interface.join_multicast_group(GROUP_V6) {
+                    warn!("mdns: failed to join multicast group: {:?}", e);
+                }
+            }
+            changed |= addr != self.addr_v6;
+            self.addr_v6 = addr;
+        }
+        changed
+    }
+
+    fn is_own_address(&self, addr: &IpAddress) -> bool {
+        match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(a) => self.addr_v4 == Some(*a),
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(a) => self.addr_v6 == Some(*a),
+        }
+    }
+
+    async fn probe(&mut self, rx: &mut [u8], tx: &mut [u8]) {
+        'restart: loop {
+            if self.conflicts >= MAX_CONFLICTS {
+                warn!("mdns: too many conflicts, backing off");
+                Timer::after(CONFLICT_BACKOFF).await;
+                self.conflicts = 0;
+            }
+            let delay = self.random() as u64 % PROBE_INTERVAL.as_millis();
+            Timer::after_millis(delay).await;
+
+            for _ in 0..3 {
+                let n = self.write_probe(tx);
+                self.send_multicast(&tx[..n]).await;
+
+                let deadline = Instant::now() + PROBE_INTERVAL;
+                while let Ok(r) = with_deadline(deadline, self.socket.recv_from(rx)).await {
+                    let Ok((n, meta)) = r else { continue };
+                    if self.is_own_address(&meta.endpoint.addr) {
+                        continue;
+                    }
+                    match self.check_probe(&rx[..n]) {
+                        ProbeResult::Continue => {}
+                        ProbeResult::Conflict(owned) => {
+                            self.rename(owned);
+                            continue 'restart;
+                        }
+                        ProbeResult::Lost => {
+                            debug!("mdns: lost simultaneous probe tiebreak");
+                            Timer::after_secs(1).await;
+                            continue 'restart;
+                        }
+                    }
+                }
+            }
+            self.conflicts = 0;
+            return;
+        }
+    }
+
+    async fn serve(&mut self, rx: &mut [u8], tx: &mut [u8]) {
+        let mut announcements = 0;
+        let mut next_announcement = Instant::now();
+        loop {
+            if announcements < 2 && Instant::now() >= next_announcement {
+                let n = self.write_announcement(tx, None);
+                self.send_multicast(&tx[..n]).await;
+                announcements += 1;
+                next_announcement = Instant::now() + ANNOUNCE_INTERVAL;
+            }
+
+            let deadline = if announcements < 2 {
+                next_announcement
+            } else {
+                Instant::now() + ANNOUNCE_INTERVAL
+            };
+            match with_deadline(deadline, self.socket.recv_from(rx)).await {
+                Ok(Ok((n, meta))) => {
+                    if self.is_own_address(&meta.endpoint.addr) {
+                        continue;
+                    }
+                    let data = &rx[..n];
+                    let Some(flags) = u16_at(data, 2) else { continue };
+                    if flags & FLAG_RESPONSE != 0 {
+                        if let Some(owned) = self.check_response(data) {
+                            self.rename(owned);
+                            return;
+                        }
+                    } else if let Some((n, dest)) = self.answer(data, meta, tx) {
+                        if let Err(e) = self.socket.send_to(&tx[..n], dest).await {
+                            warn!("mdns: send failed: {:?}", e);
+                        }
+                    }
+                }
+                Ok(Err(_)) => {}
+                Err(_) => {
+                    if !self.interface.is_config_up() {
+                        return;
+                    }
+                    if self.update_addresses() {
+                        debug!("mdns: addresses changed");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_multicast(&self, data: &[u8]) {
+        #[cfg(feature = "proto-ipv4")]
+        if self.addr_v4.is_some() {
+            if let Err(e) = self.socket.send_to(data, (GROUP_V4, PORT)).await {
+                warn!("mdns: send failed: {:?}", e);
+            }
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if self.addr_v6.is_some() {
+            if let Err(e) = self.socket.send_to(data, (GROUP_V6, PORT)).await {
+                warn!("mdns: send failed: {:?}", e);
+            }
+        }
+    }
+
+    fn rename(&mut self, owned: Owned) {
+        self.conflicts += 1;
+        match owned {
+            Owned::Host => {
+                self.host_suffix += 1;
+                self.host_name = renamed(self.hostname, "-", self.host_suffix, "");
+                info!("mdns: hostname conflict, renamed to {}", self.host_name.as_str());
+            }
+            Owned::Instance(i) => {
+                let (name, suffix) = &mut self.instance_names[i];
+                *suffix += 1;
+                *name = renamed(self.services[i].instance, " (", *suffix, ")");
+                info!("mdns: service name conflict, renamed to {}", name.as_str());
+            }
+        }
+    }
+
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        #[allow(unused_mut)]
+        let mut host = Vec::<Record, 2>::new();
+        #[cfg(feature = "proto-ipv4")]
+        if self.addr_v4.is_some() {
+            let _ = host.push(Record::A);
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if self.addr_v6.is_some() {
+            let _ = host.push(Record::Aaaa);
+        }
+        let services = self.services.iter().enumerate().flat_map(|(i, service)| {
+            // Only enumerate each service type once.
+            let first = !self.services[..i]
+                .iter()
+                .any(|s| s.service.eq_ignore_ascii_case(service.service));
+            let enumeration = first.then_some(Record::Enumeration(i));
+            enumeration
+                .into_iter()
+                .chain([Record::Ptr(i), Record::Srv(i), Record::Txt(i)])
+        });
+        host.into_iter().chain(services)
+    }
+
+    fn name(&self, record: Record) -> Name<'_> {
+        match record {
+            Record::A | Record::Aaaa => self.host_name(),
+            Record::Enumeration(_) => Name {
+                first: None,
+                middle: "_services._dns-sd._udp",
+            },
+            Record::Ptr(i) => Name {
+                first: None,
+                middle: self.services[i].service,
+            },
+            Record::Srv(i) | Record::Txt(i) => self.instance_full_name(i),
+        }
+    }
+
+    fn owned_names(&self) -> impl Iterator<Item = Owned> {
+        core::iter::once(Owned::Host).chain((0..self.services.len()).map(Owned::Instance))
+    }
+
+    fn owned_name(&self, owned: Owned) -> Name<'_> {
+        match owned {
+            Owned::Host => self.host_name(),
+            Owned::Instance(i) => self.instance_full_name(i),
+        }
+    }
+
+    fn host_name(&self) -> Name<'_> {
+        Name {
+            first: Some(&self.host_name),
+            middle: "",
+        }
+    }
+
+    fn instance_full_name(&self, i: usize) -> Name<'_> {
+        Name {
+            first: Some(&self.instance_names[i].0),
+            middle: self.services[i].service,
+        }
+    }
+
+    fn write_rdata(&self, w: &mut Writer, record: Record) -> Option<()> {
+        match record {
+            #[cfg(feature = "proto-ipv4")]
+            Record::A => w.bytes(&self.addr_v4?.octets()),
+            #[cfg(feature = "proto-ipv6")]
+            Record::Aaaa => w.bytes(&self.addr_v6?.octets()),
+            #[allow(unreachable_patterns)]
+            Record::A | Record::Aaaa => None,
+            Record::Enumeration(i) => w.name(Name {
+                first: None,
+                middle: self.services[i].service,
+            }),
+            Record::Ptr(i) => w.name(self.instance_full_name(i)),
+            Record::Srv(i) => {
+                w.u16(0)?;
+                w.u16(0)?;
+                w.u16(self.services[i].port)?;
+                w.name(self.host_name())
+            }
+            Record::Txt(i) => {
+                let txt = self.services[i].txt;
+                if txt.is_empty() {
+                    return w.u8(0);
+                }
+                for entry in txt {
+                    let entry = &entry.as_bytes()[..entry.len().min(255)];
+                    w.u8(entry.len() as u8)?;
+                    w.bytes(entry)?;
+                }
+                Some(())
+            }
+        }
+    }
+
+    fn write_record(&self, w: &mut Writer, record: Record, ttl: u32, cache_flush: bool) -> Option<()> {
+        let class = if cache_flush && record.unique() {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        w.name(self.name(record))?;
+        w.u16(record.rtype())?;
+        w.u16(class)?;
+        w.u32(ttl)?;
+        let len_pos = w.len;
+        w.u16(0)?;
+        self.write_rdata(w, record)?;
+        w.set_u16(len_pos, (w.len - len_pos - 2) as u16);
+        Some(())
+    }
+
+    /// Write the records in `mask`, until the packet is full. Returns the number of records written.
+    fn write_records(&self, w: &mut Writer, mask: u64, ttl: impl Fn(Record) -> u32, cache_flush: bool) -> u16 {
+        let mut count = 0;
+        for record in self.records().filter(|r| mask & r.bit() != 0) {
+            let start = w.len;
+            if self.write_record(w, record, ttl(record), cache_flush).is_none() {
+                w.len = start;
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    fn write_probe(&self, buf: &mut [u8]) -> usize {
+        let mut w = Writer { buf, len: 0 };
+        w.header(0, 0);
+
+        let mut questions = 0;
+        for owned in self.owned_names() {
+            let start = w.len;
+            if w.name(self.owned_name(owned))
+                .and_then(|_| w.u16(TYPE_ANY))
+                .and_then(|_| w.u16(CLASS_IN | UNICAST_RESPONSE))
+                .is_none()
+            {
+                w.len = start;
+                break;
+            }
+            questions += 1;
+        }
+
+        let unique = self.records().filter(|r| r.unique()).fold(0, |m, r| m | r.bit());
+        let authorities = self.write_records(&mut w, unique, Record::ttl, false);
+        w.set_u16(4, questions);
+        w.set_u16(8, authorities);
+        w.len
+    }
+
+    fn write_announcement(&self, buf: &mut [u8], ttl: Option<u32>) -> usize {
+        let mut w = Writer { buf, len: 0 };
+        w.header(0, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+        let all = self.records().fold(0, |m, r| m | r.bit());
+        let answers = self.write_records(&mut w, all, |r| ttl.unwrap_or(r.ttl()), true);
+        w.set_u16(6, answers);
+        w.len
+    }
+
+    /// Check a packet received while probing for conflicts with our names.
+    fn check_probe(&self, data: &[u8]) -> ProbeResult {
+        let Some(header) = Header::parse(data) else {
+            return ProbeResult::Continue;
+        };
+        let Some(mut pos) = skip_questions(data, &header) else {
+            return ProbeResult::Continue;
+        };
+
+        if header.flags & FLAG_RESPONSE != 0 {
+            // Any answer for a name we're probing means it's already taken.
+            let count = header.ancount as usize + header.nscount as usize + header.arcount as usize;
+            for _ in 0..count {
+                let Some((rr, end)) = ResourceRecord::parse(data, pos) else {
+                    break;
+                };
+                pos = end;
+                let mut unique = self.records().filter(|r| r.unique());
+                if let Some(record) = unique.find(|r| rr.name.matches(self.name(*r))) {
+                    return ProbeResult::Conflict(unwrap!(record.owner()));
+                }
+            }
+            return ProbeResult::Continue;
+        }
+
+        // Authority records of a query are the records another host is probing for.
+        for _ in 0..header.ancount {
+            let Some((_, end)) = ResourceRecord::parse(data, pos) else {
+                return ProbeResult::Continue;
+            };
+            pos = end;
+        }
+        for owned in self.owned_names() {
+            if self.loses_tiebreak(data, pos, header.nscount as usize, owned) {
+                return ProbeResult::Lost;
+            }
+        }
+        ProbeResult::Continue
+    }
+
+    /// Break the tie of a simultaneous probe for `owned`, by comparing the records each host probes
+    /// for, sorted by class, type and data (RFC 6762 section 8.2). Returns whether the other host wins.
+    fn loses_tiebreak(&self, data: &[u8], mut pos: usize, count: usize, owned: Owned) -> bool {
+        let name = self.owned_name(owned);
+        let mut theirs = Vec::<(u16, u16, &[u8]), 8>::new();
+        for _ in 0..count {
+            let Some((rr, end)) = ResourceRecord::parse(data, pos) else {
+                break;
+            };
+            pos = end;
+            if rr.name.matches(name) && theirs.push((rr.class & !CACHE_FLUSH, rr.rtype, rr.rdata)).is_err() {
+                break;
+            }
+        }
+        if theirs.is_empty() {
+            return false;
+        }
+
+        let mut bufs = [[0; 320]; 2];
+        let mut ours = Vec::<(u16, u16, &[u8]), 2>::new();
+        let records = self.records().filter(|r| r.owner() == Some(owned));
+        for (record, buf) in records.zip(bufs.iter_mut()) {
+            let mut w = Writer { buf, len: 0 };
+            if self.write_rdata(&mut w, record).is_some() {
+                let Writer { buf, len } = w;
+                let _ = ours.push((CLASS_IN, record.rtype(), &buf[..len]));
+            }
+        }
+
+        theirs.sort_unstable();
+        ours.sort_unstable();
+        // The first differing record decides, and the longer list wins if one is a prefix of the other.
+        theirs.iter().cmp(ours.iter()).is_gt()
+    }
+
+    /// Check a response for records that conflict with ours. Returns the name to rename.
+    fn check_response(&self, data: &[u8]) -> Option<Owned> {
+        let header = Header::parse(data)?;
+        let mut pos = skip_questions(data, &header)?;
+        let count = header.ancount as usize + header.nscount as usize + header.arcount as usize;
+        for _ in 0..count {
+            let (rr, end) = ResourceRecord::parse(data, pos)?;
+            pos = end;
+            for record in self.records() {
+                if rr.rtype != record.rtype() || !rr.name.matches(self.name(record)) {
+                    continue;
+                }
+                let conflict = match record {
+                    Record::A | Record::Aaaa => {
+                        let mut ours = [0; 16];
+                        let mut w = Writer { buf: &mut ours, len: 0 };
+                        self.write_rdata(&mut w, record).is_some() && rr.rdata != &w.buf[..w.len]
+                    }
+                    Record::Srv(i) => match (u16_at(rr.rdata, 4), Labels::parse(data, rr.rdata_pos + 6)) {
+                        (Some(port), Some((target, _))) => {
+                            port != self.services[i].port || !target.matches(self.host_name())
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if conflict {
+                    return record.owner();
+                }
+            }
+        }
+        None
+    }
+
+    /// Build the answer to a query. Returns the length of the answer and where to send it.
+    fn answer(&self, data: &[u8], meta: UdpMetadata, buf: &mut [u8]) -> Option<(usize, IpEndpoint)> {
+        let header = Header::parse(data)?;
+        if header.flags & OPCODE_MASK != 0 {
+            return None;
+        }
+        // Queries not from port 5353 come from simple resolvers, which expect a conventional DNS answer.
+        let legacy = meta.endpoint.port != PORT;
+
+        let mut answers = 0;
+        let mut unicast = true;
+        let mut pos = 12;
+        for _ in 0..header.qdcount {
+            let (name, end) = Labels::parse(data, pos)?;
+            let qtype = u16_at(data, end)?;
+            let qclass = u16_at(data, end + 2)?;
+            pos = end + 4;
+            if !matches!(qclass & !UNICAST_RESPONSE, CLASS_IN | CLASS_ANY) {
+                continue;
+            }
+            unicast &= qclass & UNICAST_RESPONSE != 0;
+            for record in self.records() {
+                if (qtype == record.rtype() || qtype == TYPE_ANY) && name.matches(self.name(record)) {
+                    answers |= record.bit();
+                }
+            }
+        }
+        let questions_end = pos;
+
+        // Known-answer suppression: don't repeat shared records the querier already has.
+        for _ in 0..header.ancount {
+            let Some((rr, end)) = ResourceRecord::parse(data, pos) else {
+                break;
+            };
+            pos = end;
+            let candidates = answers;
+            for record in self.records().filter(|r| !r.unique() && candidates & r.bit() != 0) {
+                if rr.rtype != TYPE_PTR || rr.ttl < record.ttl() / 2 || !rr.name.matches(self.name(record)) {
+                    continue;
+                }
+                let target = match record {
+                    Record::Enumeration(i) => Name {
+                        first: None,
+                        middle: self.services[i].service,
+                    },
+                    Record::Ptr(i) => self.instance_full_name(i),
+                    _ => continue,
+                };
+                if Labels::parse(data, rr.rdata_pos).is_some_and(|(t, _)| t.matches(target)) {
+                    answers &= !record.bit();
+                }
+            }
+        }
+        if answers == 0 {
+            return None;
+        }
+
+        let mut additionals = 0;
+        for record in self.records().filter(|r| answers & r.bit() != 0) {
+            additionals |= match record {
+                Record::Ptr(i) => Record::Srv(i).bit() | Record::Txt(i).bit() | Record::A.bit() | Record::Aaaa.bit(),
+                Record::Srv(_) | Record::A | Record::Aaaa => Record::A.bit() | Record::Aaaa.bit(),
+                _ => 0,
+            };
+        }
+        additionals &= !answers;
+
+        let mut w = Writer { buf, len: 0 };
+        if legacy {
+            // Echo the questions. They start at the same offset, so compressed names stay valid.
+            w.header(header.id, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+            w.bytes(&data[12..questions_end])?;
+            w.set_u16(4, header.qdcount);
+        } else {
+            w.header(0, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+        }
+        let ttl = |r: Record| if legacy { r.ttl().min(LEGACY_TTL) } else { r.ttl() };
+        let an = self.write_records(&mut w, answers, ttl, !legacy);
+        let ar = self.write_records(&mut w, additionals, ttl, !legacy);
+        w.set_u16(6, an);
+        w.set_u16(10, ar);
+
+        let dest = if legacy || unicast {
+            meta.endpoint
+        } else {
+            match meta.endpoint.addr {
+                #[cfg(feature = "proto-ipv4")]
+                IpAddress::Ipv4(_) => IpEndpoint::new(GROUP_V4.into(), PORT),
+                #[cfg(feature = "proto-ipv6")]
+                IpAddress::Ipv6(_) => IpEndpoint::new(GROUP_V6.into(), PORT),
+            }
+        };
+        Some((w.len, dest))
+    }
+}
+
+fn renamed(base: &str, prefix: &str, n: u32, suffix: &str) -> String<MAX_NAME_LEN> {
+    let mut tail = String::<16>::new();
+    let _ = write!(tail, "{}{}{}", prefix, n, suffix);
+    let mut end = base.len().min(MAX_NAME_LEN - tail.len());
+    while !base.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut name = String::new();
+    let _ = name.push_str(&base[..end]);
+    let _ = name.push_str(&tail);
+    name
+}
+
+/// A name in the `.local` domain.
+#[derive(Clone, Copy)]
+struct Name<'n> {
+    /// A single label, which may contain dots.
+    first: Option<&'n str>,
+    /// Dot-separated labels.
+    middle: &'n str,
+}
+
+impl<'n> Name<'n> {
+    fn labels(self) -> impl Iterator<Item = &'n [u8]> {
+        self.first
+            .into_iter()
+            .chain(self.middle.split('.').filter(|l| !l.is_empty()))
+            .chain(["local"])
+            .map(str::as_bytes)
+    }
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn header(&mut self, id: u16, flags: u16) {
+        self.buf[..12].fill(0);
+        self.set_u16(0, id);
+        self.set_u16(2, flags);
+        self.len = 12;
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        self.buf.get_mut(self.len..self.len + data.len())?.copy_from_slice(data);
+        self.len += data.len();
+        Some(())
+    }
+
+    fn u8(&mut self, v: u8) -> Option<()> {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> Option<()> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Option<()> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    fn set_u16(&mut self, pos: usize, v: u16) {
+        self.buf[pos..pos + 2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    fn name(&mut self, name: Name) -> Option<()> {
+        for label in name.labels() {
+            self.u8(label.len().min(MAX_NAME_LEN) as u8)?;
+            self.bytes(&label[..label.len().min(MAX_NAME_LEN)])?;
+        }
+        self.u8(0)
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+struct Header {
+    id: u16,
+    flags: u16,
+    qdcount: u16,
+    ancount: u16,
+    nscount: u16,
+    arcount: u16,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            id: u16_at(data, 0)?,
+            flags: u16_at(data, 2)?,
+            qdcount: u16_at(data, 4)?,
+            ancount: u16_at(data, 6)?,
+            nscount: u16_at(data, 8)?,
+            arcount: u16_at(data, 10)?,
+        })
+    }
+}
+
+fn skip_questions(data: &[u8], header: &Header) -> Option<usize> {
+    let mut pos = 12;
+    for _ in 0..header.qdcount {
+        let (_, end) = Labels::parse(data, pos)?;
+        pos = end + 4;
+    }
+    Some(pos)
+}
+
+/// A name parsed from a packet.
+struct Labels<'p>(Vec<&'p [u8], MAX_LABELS>);
+
+impl<'p> Labels<'p> {
+    /// Parse a possibly compressed name at `pos`. Returns the name and the position after it.
+    fn parse(data: &'p [u8], mut pos: usize) -> Option<(Self, usize)> {
+        let mut labels = Vec::new();
+        let mut end = None;
+        // Bound the number of pointers followed, to reject loops.
+        for _ in 0..MAX_LABELS * 2 {
+            let len = *data.get(pos)? as usize;
+            match len & 0xC0 {
+                0x00 if len == 0 => {
+                    return Some((Self(labels), end.unwrap_or(pos + 1)));
+                }
+                0x00 => {
+                    labels.push(data.get(pos + 1..pos + 1 + len)?).ok()?;
+                    pos += 1 + len;
+                }
+                0xC0 => {
+                    let ptr = u16_at(data, pos)? as usize & 0x3FFF;
+                    end.get_or_insert(pos + 2);
+                    pos = ptr;
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn matches(&self, name: Name) -> bool {
+        let mut labels = name.labels();
+        for l in &self.0 {
+            match labels.next() {
+                Some(n) if n.eq_ignore_ascii_case(l) => {}
+                _ => return false,
+            }
+        }
+        labels.next().is_none()
+    }
+}
+
+struct ResourceRecord<'p> {
+    name: Labels<'p>,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata_pos: usize,
+    rdata: &'p [u8],
+}
+
+impl<'p> ResourceRecord<'p> {
+    fn parse(data: &'p [u8], pos: usize) -> Option<(Self, usize)> {
+        let (name, pos) = Labels::parse(data, pos)?;
+        let rtype = u16_at(data, pos)?;
+        let class = u16_at(data, pos + 2)?;
+        let ttl = u32_at(data, pos + 4)?;
+        let len = u16_at(data, pos + 8)? as usize;
+        let rdata_pos = pos + 10;
+        let rdata = data.get(rdata_pos..rdata_pos + len)?;
+        Some((
+            Self {
+                name,
+                rtype,
+                class,
+                ttl,
+                rdata_pos,
+                rdata,
+            },
+            rdata_pos + len,
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "proto-ipv4", feature = "medium-ip"))]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken, TxToken};
+
+    use super::*;
+    use crate::udp::PacketMetadata;
+    use crate::{Config, StackResources};
+
+    const ADDR: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+    const PEER: (Ipv4Address, u16) = (Ipv4Address::new(10, 0, 0, 2), PORT);
+    const HOST: &[&str] = &["dev", "local"];
+    const SERVICE: &[&str] = &["_http", "_tcp", "local"];
+    const INSTANCE: &[&str] = &["Thermo.1", "_http", "_tcp", "local"];
+    static SERVICES: [Service; 1] = [Service {
+        instance: "Thermo.1",
+        service: "_http._tcp",
+        port: 80,
+        txt: &["a=1"],
+    }];
+
+    /// A driver that never sends or receives anything: packets are handed to the responder directly.
+    struct NoDevice;
+
+    struct Token;
+
+    impl RxToken for Token {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, _f: F) -> R {
+            unreachable!()
+        }
+    }
+
+    impl TxToken for Token {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, _len: usize, _f: F) -> R {
+            unreachable!()
+        }
+    }
+
+    impl Driver for NoDevice {
+        type RxToken<'a> = Token;
+        type TxToken<'a> = Token;
+
+        fn receive(&mut self, _cx: &mut core::task::Context) -> Option<(Token, Token)> {
+            None
+        }
+
+        fn transmit(&mut self, _cx: &mut core::task::Context) -> Option<Token> {
+            None
+        }
+
+        fn link_state(&mut self, _cx: &mut core::task::Context) -> LinkState {
+            LinkState::Down
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        fn hardware_address(&self) -> HardwareAddress {
+            HardwareAddress::Ip
+        }
+    }
+
+    fn responder() -> Responder<'static> {
+        let resources = Box::leak(Box::new(StackResources::<4>::new()));
+        let (stack, runner) = crate::new(NoDevice, Config::default(), resources, 1);
+        Box::leak(Box::new(runner));
+        let socket = UdpSocket::new(
+            stack,
+            Box::leak(Box::new([PacketMetadata::EMPTY; 1])),
+            Box::leak(Box::new([0; 16])),
+            Box::leak(Box::new([PacketMetadata::EMPTY; 1])),
+            Box::leak(Box::new([0; 16])),
+        );
+        let mut responder = Responder::new(socket, "dev", &SERVICES);
+        responder.addr_v4 = Some(ADDR);
+        responder
+    }
+
+    /// A packet crafted by another host.
+    struct Packet(Vec<u8>);
+
+    impl Packet {
+        fn new(id: u16, flags: u16) -> Self {
+            let mut data = vec![0; 12];
+            data[0..2].copy_from_slice(&id.to_be_bytes());
+            data[2..4].copy_from_slice(&flags.to_be_bytes());
+            Self(data)
+        }
+
+        /// Increment the counter of a section: 0 for questions, 1 for answers, 2 for authorities.
+        fn count(mut self, section: usize) -> Self {
+            let pos = 4 + 2 * section;
+            let count = u16_at(&self.0, pos).unwrap() + 1;
+            self.0[pos..pos + 2].copy_from_slice(&count.to_be_bytes());
+            self
+        }
+
+        fn name(mut self, labels: &[&str]) -> Self {
+            for label in labels {
+                self.0.push(label.len() as u8);
+                self.0.extend_from_slice(label.as_bytes());
+            }
+            self.0.push(0);
+            self
+        }
+
+        fn bytes(mut self, data: &[u8]) -> Self {
+            self.0.extend_from_slice(data);
+            self
+        }
+
+        fn question(self, name: &[&str], qtype: u16, qclass: u16) -> Self {
+            let [t0, t1] = qtype.to_be_bytes();
+            let [c0, c1] = qclass.to_be_bytes();
+            self.count(0).name(name).bytes(&[t0, t1, c0, c1])
+        }
+
+        fn record(self, section: usize, name: &[&str], rtype: u16, ttl: u32, rdata: &[u8]) -> Self {
+            self.count(section)
+                .name(name)
+                .bytes(&rtype.to_be_bytes())
+                .bytes(&CLASS_IN.to_be_bytes())
+                .bytes(&ttl.to_be_bytes())
+                .bytes(&(rdata.len() as u16).to_be_bytes())
+                .bytes(rdata)
+        }
+    }
+
+    fn name(labels: &[&str]) -> Vec<u8> {
+        Packet(Vec::new()).name(labels).0
+    }
+
+    /// A record read from a packet sent by the responder.
+    #[derive(Debug, PartialEq)]
+    struct Answer {
+        name: Vec<u8>,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        rdata: Vec<u8>,
+    }
+
+    fn answer(rtype: u16, class: u16, ttl: u32, labels: &[&str], rdata: &[u8]) -> Answer {
+        Answer {
+            name: name(labels),
+            rtype,
+            class,
+            ttl,
+            rdata: rdata.to_vec(),
+        }
+    }
+
+    /// Parse the records of a packet sent by the responder, whose names aren't compressed.
+    fn records(data: &[u8]) -> (Header, Vec<Answer>) {
+        let header = Header::parse(data).unwrap();
+        let mut pos = skip_questions(data, &header).unwrap();
+        let mut records = Vec::new();
+        for _ in 0..header.ancount + header.nscount + header.arcount {
+            let (rr, end) = ResourceRecord::parse(data, pos).unwrap();
+            let name_len = rr.rdata_pos - 10 - pos;
+            records.push(Answer {
+                name: data[pos..pos + name_len].to_vec(),
+                rtype: rr.rtype,
+                class: rr.class,
+                ttl: rr.ttl,
+                rdata: rr.rdata.to_vec(),
+            });
+            pos = end;
+        }
+        assert_eq!(pos, data.len());
+        (header, records)
+    }
+
+    fn ask(
+        responder: &Responder,
+        query: &Packet,
+        from: (Ipv4Address, u16),
+    ) -> Option<(Header, Vec<Answer>, IpEndpoint)> {
+        let mut buf = [0; MAX_PACKET];
+        let (n, dest) = responder.answer(&query.0, from.into(), &mut buf)?;
+        let (header, records) = records(&buf[..n]);
+        Some((header, records, dest))
+    }
+
+    #[test]
+    fn queries() {
+        let r = responder();
+        let group = IpEndpoint::new(GROUP_V4.into(), PORT);
+
+        // Multicast answer, names are case-insensitive.
+        let query = Packet::new(0, 0).question(&["DEV", "Local"], TYPE_A, CLASS_IN);
+        let (header, records, dest) = ask(&r, &query, PEER).unwrap();
+        assert_eq!(header.flags, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+        assert_eq!(
+            records,
+            [answer(TYPE_A, CLASS_IN | CACHE_FLUSH, HOST_TTL, HOST, &ADDR.octets())]
+        );
+        assert_eq!(dest, group);
+
+        // Unicast answer.
+        let query = Packet::new(0, 0).question(HOST, TYPE_ANY, CLASS_IN | UNICAST_RESPONSE);
+        assert_eq!(ask(&r, &query, PEER).unwrap().2, PEER.into());
+
+        // Service browsing, with the records needed to connect as additionals.
+        let query = Packet::new(0, 0).question(SERVICE, TYPE_PTR, CLASS_IN);
+        let (header, records, _) = ask(&r, &query, PEER).unwrap();
+        assert_eq!((header.ancount, header.arcount), (1, 3));
+        assert_eq!(
+            records[0],
+            answer(TYPE_PTR, CLASS_IN, SERVICE_TTL, SERVICE, &name(INSTANCE))
+        );
+        let mut srv = vec![0, 0, 0, 0, 0, 80];
+        srv.extend_from_slice(&name(HOST));
+        assert_eq!(records[1].rtype, TYPE_A);
+        assert_eq!(
+            records[2],
+            answer(TYPE_SRV, CLASS_IN | CACHE_FLUSH, HOST_TTL, INSTANCE, &srv)
+        );
+        assert_eq!(
+            records[3],
+            answer(TYPE_TXT, CLASS_IN | CACHE_FLUSH, SERVICE_TTL, INSTANCE, b"\x03a=1")
+        );
+
+        let query = Packet::new(0, 0).question(&["_services", "_dns-sd", "_udp", "local"], TYPE_PTR, CLASS_ANY);
+        let (_, records, _) = ask(&r, &query, PEER).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].rdata, name(SERVICE));
+
+        // Nothing to answer.
+        let query = Packet::new(0, 0).question(&["other", "local"], TYPE_A, CLASS_IN);
+        assert!(ask(&r, &query, PEER).is_none());
+        let query = Packet::new(0, 0).question(HOST, TYPE_AAAA, CLASS_IN);
+        assert!(ask(&r, &query, PEER).is_none());
+        let query = Packet::new(0, 0).question(HOST, TYPE_A, 3);
+        assert!(ask(&r, &query, PEER).is_none());
+        let query = Packet::new(0, 0x2800).question(HOST, TYPE_A, CLASS_IN);
+        assert!(ask(&r, &query, PEER).is_none());
+    }
+
+    #[test]
+    fn legacy_query() {
+        let r = responder();
+        // Queries from another port get a conventional answer, with the questions and identifier.
+        let from = (PEER.0, 40000);
+        let query = Packet::new(0x1234, 0).question(HOST, TYPE_A, CLASS_IN);
+        let mut buf = [0; MAX_PACKET];
+        let (n, dest) = r.answer(&query.0, from.into(), &mut buf).unwrap();
+        assert_eq!(dest, from.into());
+        let expected = Packet::new(0x1234, FLAG_RESPONSE | FLAG_AUTHORITATIVE).question(HOST, TYPE_A, CLASS_IN);
+        assert_eq!(buf[..6], expected.0[..6]);
+        assert_eq!(buf[12..query.0.len()], expected.0[12..]);
+        let (_, records) = records(&buf[..n]);
+        assert_eq!(records, [answer(TYPE_A, CLASS_IN, LEGACY_TTL, HOST, &ADDR.octets())]);
+    }
+
+    #[test]
+    fn compressed_names() {
+        let r = responder();
+        // The second question points to the `local` label of the first one.
+        let query = Packet::new(0, 0)
+            .question(HOST, TYPE_A, CLASS_IN)
+            .count(0)
+            .bytes(&[5])
+            .bytes(b"_http")
+            .bytes(&[4])
+            .bytes(b"_tcp")
+            .bytes(&[0xC0, 16])
+            .bytes(&[0, 12, 0, 1]);
+        let (header, _, _) = ask(&r, &query, PEER).unwrap();
+        assert_eq!((header.ancount, header.arcount), (2, 2));
+    }
+
+    #[test]
+    fn known_answers() {
+        let r = responder();
+        let query = Packet::new(0, 0).question(SERVICE, TYPE_PTR, CLASS_IN);
+        let known = query.record(1, SERVICE, TYPE_PTR, SERVICE_TTL / 2, &name(INSTANCE));
+        assert!(ask(&r, &known, PEER).is_none());
+
+        // Known answers about to expire, or about other instances, don't suppress the answer.
+        let query = Packet::new(0, 0).question(SERVICE, TYPE_PTR, CLASS_IN);
+        let stale = query.record(1, SERVICE, TYPE_PTR, SERVICE_TTL / 2 - 1, &name(INSTANCE));
+        assert!(ask(&r, &stale, PEER).is_some());
+        let query = Packet::new(0, 0).question(SERVICE, TYPE_PTR, CLASS_IN);
+        let other = query.record(
+            1,
+            SERVICE,
+            TYPE_PTR,
+            SERVICE_TTL,
+            &name(&["Other", "_http", "_tcp", "local"]),
+        );
+        assert!(ask(&r, &other, PEER).is_some());
+
+        // A truncated known answer is ignored.
+        let query = Packet::new(0, 0).question(SERVICE, TYPE_PTR, CLASS_IN);
+        let mut truncated = query.record(1, SERVICE, TYPE_PTR, SERVICE_TTL, &name(INSTANCE));
+        truncated.0.truncate(truncated.0.len() - 3);
+        assert!(ask(&r, &truncated, PEER).is_some());
+    }
+
+    #[test]
+    fn malformed() {
+        let r = responder();
+        let question = |p: Packet| p.count(0);
+        let tail = [0, 1, 0, 1];
+        let mut labels = Packet::new(0, 0).count(0);
+        for _ in 0..MAX_LABELS + 1 {
+            labels = labels.bytes(&[1, b'a']);
+        }
+        let packets = [
+            Packet(Vec::new()),
+            Packet(vec![0; 11]),
+            // Questions missing, or cut.
+            question(Packet::new(0, 0)),
+            question(Packet::new(0, 0)).bytes(&[3, b'd', b'e']),
+            question(Packet::new(0, 0)).name(HOST).bytes(&[0, 1]),
+            // Label with the reserved bits set.
+            question(Packet::new(0, 0)).bytes(&[0x40, b'a', 0]).bytes(&tail),
+            // Pointer loop, and pointers out of the packet.
+            question(Packet::new(0, 0)).bytes(&[0xC0, 12]).bytes(&tail),
+            question(Packet::new(0, 0)).bytes(&[0xC0, 14, 0xC0, 12]).bytes(&tail),
+            question(Packet::new(0, 0)).bytes(&[0xFF, 0xFF]).bytes(&tail),
+            // Too many labels.
+            labels.bytes(&[0]).bytes(&tail),
+            // More questions than the packet holds.
+            Packet::new(0, 0).question(HOST, TYPE_A, CLASS_IN).count(0),
+        ];
+        for p in &packets {
+            assert!(ask(&r, p, PEER).is_none());
+            assert!(matches!(r.check_probe(&p.0), ProbeResult::Continue));
+            let mut response = Packet(p.0.clone());
+            if response.0.len() >= 4 {
+                response.0[2] |= 0x80;
+                assert!(r.check_response(&response.0).is_none());
+            }
+        }
+
+        // Records going past the end of the packet.
+        let mut response = Packet::new(0, FLAG_RESPONSE).record(1, HOST, TYPE_A, 120, &[10, 0, 0, 2]);
+        let len = response.0.len();
+        response.0[len - 6..len - 4].copy_from_slice(&[0xFF, 0xFF]);
+        assert!(r.check_response(&response.0).is_none());
+        assert!(matches!(r.check_probe(&response.0), ProbeResult::Continue));
+        response.0.truncate(len - 8);
+        assert!(r.check_response(&response.0).is_none());
+        assert!(matches!(r.check_probe(&response.0), ProbeResult::Continue));
+    }
+
+    #[test]
+    fn probe() {
+        let r = responder();
+        let mut buf = [0; MAX_PACKET];
+        let n = r.write_probe(&mut buf);
+        let header = Header::parse(&buf[..n]).unwrap();
+        // Questions for the host and instance names, and the unique records as authorities.
+        assert_eq!((header.qdcount, header.ancount, header.nscount), (2, 0, 3));
+        let (_, records) = records(&buf[..n]);
+        assert_eq!(records[0], answer(TYPE_A, CLASS_IN, HOST_TTL, HOST, &ADDR.octets()));
+
+        // Our own probe doesn't conflict.
+        assert!(matches!(r.check_probe(&buf[..n]), ProbeResult::Continue));
+
+        // Any answer for our names is a conflict.
+        let response = Packet::new(0, FLAG_RESPONSE).record(1, INSTANCE, TYPE_TXT, 120, &[0]);
+        assert!(matches!(
+            r.check_probe(&response.0),
+            ProbeResult::Conflict(Owned::Instance(0))
+        ));
+        let response = Packet::new(0, FLAG_RESPONSE).record(3, HOST, TYPE_A, 120, &ADDR.octets());
+        assert!(matches!(r.check_probe(&response.0), ProbeResult::Conflict(Owned::Host)));
+
+        // Simultaneous probes: the lexicographically later data wins.
+        let probe = |addr: [u8; 4]| {
+            Packet::new(0, 0)
+                .question(HOST, TYPE_ANY, CLASS_IN)
+                .record(2, HOST, TYPE_A, 120, &addr)
+        };
+        assert!(matches!(r.check_probe(&probe([10, 0, 0, 2]).0), ProbeResult::Lost));
+        assert!(matches!(r.check_probe(&probe([10, 0, 0, 0]).0), ProbeResult::Continue));
+        // The sorted record sets are compared: identical sets don't conflict, and a longer one wins.
+        let mut srv = vec![0, 0, 0, 0, 0, 80];
+        srv.extend_from_slice(&name(HOST));
+        let probe = Packet::new(0, 0)
+            .question(INSTANCE, TYPE_ANY, CLASS_IN)
+            .record(2, INSTANCE, TYPE_SRV, 120, &srv)
+            .record(2, INSTANCE, TYPE_TXT, 4500, b"\x03a=1");
+        assert!(matches!(r.check_probe(&probe.0), ProbeResult::Continue));
+        let extra = Packet(probe.0.clone()).record(2, INSTANCE, 99, 4500, b"x");
+        assert!(matches!(r.check_probe(&extra.0), ProbeResult::Lost));
+        // A TXT record sorts before the SRV record.
+        let probe = probe.record(2, INSTANCE, TYPE_TXT, 4500, b"\x03b=2");
+        assert!(matches!(r.check_probe(&probe.0), ProbeResult::Continue));
+        // Answers in a query are not probes.
+        let query = Packet::new(0, 0).record(1, HOST, TYPE_A, 120, &[10, 0, 0, 2]);
+        assert!(matches!(r.check_probe(&query.0), ProbeResult::Continue));
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut r = responder();
+        let a = |addr: [u8; 4]| Packet::new(0, FLAG_RESPONSE).record(1, HOST, TYPE_A, 120, &addr);
+        assert_eq!(r.check_response(&a(ADDR.octets()).0), None);
+        assert_eq!(r.check_response(&a([10, 0, 0, 9]).0), Some(Owned::Host));
+
+        let srv = |port: u8, target: &[&str]| {
+            let mut rdata = vec![0, 0, 0, 0, 0, port];
+            rdata.extend_from_slice(&name(target));
+            Packet::new(0, FLAG_RESPONSE).record(1, INSTANCE, TYPE_SRV, 120, &rdata)
+        };
+        assert_eq!(r.check_response(&srv(80, HOST).0), None);
+        assert_eq!(r.check_response(&srv(81, HOST).0), Some(Owned::Instance(0)));
+        assert_eq!(
+            r.check_response(&srv(80, &["other", "local"]).0),
+            Some(Owned::Instance(0))
+        );
+        // A SRV record with truncated data isn't a conflict.
+        let truncated = Packet::new(0, FLAG_RESPONSE).record(1, INSTANCE, TYPE_SRV, 120, &[0, 0, 0]);
+        assert_eq!(r.check_response(&truncated.0), None);
+
+        r.rename(Owned::Host);
+        r.rename(Owned::Instance(0));
+        assert_eq!((r.hostname(), r.instance_name(0)), ("dev-2", "Thermo.1 (2)"));
+        assert_eq!(r.check_response(&a([10, 0, 0, 9]).0), None);
+    }
+
+    #[test]
+    fn rename_truncates() {
+        let long = "a".repeat(MAX_NAME_LEN);
+        let name = renamed(&long, "-", 12, "");
+        assert_eq!(name.len(), MAX_NAME_LEN);
+        assert!(name.ends_with("a-12"));
+
+        let long = "é".repeat(MAX_NAME_LEN / 2);
+        let name = renamed(&long, " (", 2, ")");
+        assert_eq!(name.len(), MAX_NAME_LEN - 1);
+        assert!(name.ends_with("é (2)"));
+    }
+
+    #[test]
+    fn goodbye() {
+        let r = responder();
+        let mut buf = [0; MAX_PACKET];
+        let n = r.write_announcement(&mut buf, Some(0));
+        let (header, answers) = records(&buf[..n]);
+        assert_eq!(header.flags, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+        let types: Vec<_> = answers.iter().map(|r| (r.rtype, r.ttl)).collect();
+        assert_eq!(
+            types,
+            [(TYPE_A, 0), (TYPE_PTR, 0), (TYPE_PTR, 0), (TYPE_SRV, 0), (TYPE_TXT, 0)]
+        );
+
+        // Records that don't fit are left out.
+        let n = r.write_announcement(&mut buf[..60], None);
+        assert_eq!(records(&buf[..n]).1.len(), 1);
+    }
+}
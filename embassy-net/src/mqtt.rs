@@ -0,0 +1,1193 @@
+//! MQTT client.
+//!
+//! [`MqttClient`] speaks MQTT 3.1.1 or 5 over a [`TcpSocket`], with publish and subscribe at QoS 0,
+//! 1 and 2. It doesn't allocate: all buffers are provided by the caller in [`MqttBuffers`].
+//!
+//! The client reconnects automatically: when the connection is lost, the next call waits for the
+//! link and the IP configuration to be up, then reconnects with exponential backoff, and
+//! re-subscribes to [`ClientConfig::subscriptions`]. Keep-alive pings are sent while the client waits
+//! for packets, so [`MqttClient::receive()`] should be called continuously.
+//!
+//! MQTT 5 support is limited to the MQTT 3.1.1 feature set: no properties are sent, and received
+//! properties are ignored.
+
+use core::ops::Range;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+use embedded_io_async::Write as _;
+use heapless::Vec;
+
+use crate::tcp::TcpSocket;
+use crate::{IpEndpoint, Stack};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const UNSUBSCRIBE: u8 = 0xA2;
+const UNSUBACK: u8 = 0xB0;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+/// Room for the fixed header of an outgoing packet: the packet type and up to 4 bytes of length.
+const HEADER_ROOM: usize = 5;
+/// Number of received QoS 2 messages that can await release by the broker at once.
+const MAX_UNRELEASED: usize = 8;
+
+/// MQTT protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Version {
+    /// MQTT 3.1.1
+    V3_1_1,
+    /// MQTT 5
+    V5,
+}
+
+/// Quality of service of a publication or subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    /// The message is delivered at most once, without acknowledgment.
+    AtMostOnce = 0,
+    /// The message is delivered at least once, and acknowledged by the receiver.
+    AtLeastOnce = 1,
+    /// The message is delivered exactly once, with a two-step acknowledgment.
+    ExactlyOnce = 2,
+}
+
+/// Error returned by [`MqttClient`] operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The connection to the broker failed, or no response was received in time.
+    ///
+    /// Operations retry automatically on network errors, so this is only returned by [`MqttClient::connect()`].
+    Network,
+    /// The broker refused the connection, with this CONNACK return or reason code.
+    ConnectionRefused(u8),
+    /// The broker rejected a subscription or publication, with this reason code.
+    Rejected(u8),
+    /// A packet doesn't fit in the packet buffers.
+    BufferTooSmall,
+    /// The broker sent a malformed or unexpected packet.
+    Protocol,
+}
+
+/// Last will message, published by the broker when the client disconnects unexpectedly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Will<'a> {
+    /// Topic to publish to.
+    pub topic: &'a str,
+    /// Message payload.
+    pub payload: &'a [u8],
+    /// Quality of service.
+    pub qos: QoS,
+    /// Whether the broker should retain the message.
+    pub retain: bool,
+}
+
+/// MQTT client configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ClientConfig<'a> {
+    /// Client identifier.
+    pub client_id: &'a str,
+    /// Protocol version.
+    pub version: Version,
+    /// Keep-alive interval. The broker closes the connection if it receives nothing for 1.5 times this.
+    ///
+    /// Zero disables keep-alive: no pings are sent.
+    pub keep_alive: Duration,
+    /// Start a new session on connection, discarding subscriptions and undelivered messages.
+    pub clean_session: bool,
+    /// User name.
+    pub username: Option<&'a str>,
+    /// Password.
+    pub password: Option<&'a [u8]>,
+    /// Last will message.
+    pub will: Option<Will<'a>>,
+    /// Topic filters subscribed to on every connection.
+    pub subscriptions: &'a [(&'a str, QoS)],
+    /// How long to wait for responses from the broker before considering the connection lost.
+    pub timeout: Duration,
+    /// Delay before the first reconnection attempt. It doubles on each failure, up to `max_backoff`.
+    pub min_backoff: Duration,
+    /// Maximum delay between reconnection attempts.
+    pub max_backoff: Duration,
+}
+
+impl<'a> ClientConfig<'a> {
+    /// Create a configuration with default settings: MQTT 3.1.1, 60 s keep-alive, clean session,
+    /// no credentials and no subscriptions.
+    pub const fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            version: Version::V3_1_1,
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+            subscriptions: &[],
+            timeout: Duration::from_secs(10),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A message received from the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Message<'a> {
+    /// Topic the message was published to.
+    pub topic: &'a str,
+    /// Message payload.
+    pub payload: &'a [u8],
+    /// Quality of service the message was delivered with.
+    pub qos: QoS,
+    /// Whether this is a retained message, sent because of a new subscription.
+    pub retain: bool,
+}
+
+/// Buffers for an [`MqttClient`].
+///
+/// `SOCKET_SZ` is the size of each of the TCP socket buffers. `PACKET_SZ` limits the size of the
+/// packets sent and received: received messages that don't fit are dropped.
+pub struct MqttBuffers<const SOCKET_SZ: usize = 1024, const PACKET_SZ: usize = 1024> {
+    socket_rx: [u8; SOCKET_SZ],
+    socket_tx: [u8; SOCKET_SZ],
+    rx: [u8; PACKET_SZ],
+    tx: [u8; PACKET_SZ],
+    pending: [u8; PACKET_SZ],
+}
+
+impl<const SOCKET_SZ: usize, const PACKET_SZ: usize> MqttBuffers<SOCKET_SZ, PACKET_SZ> {
+    /// Create new buffers.
+    pub const fn new() -> Self {
+        Self {
+            socket_rx: [0; SOCKET_SZ],
+            socket_tx: [0; SOCKET_SZ],
+            rx: [0; PACKET_SZ],
+            tx: [0; PACKET_SZ],
+            pending: [0; PACKET_SZ],
+        }
+    }
+}
+
+impl<const SOCKET_SZ: usize, const PACKET_SZ: usize> Default for MqttBuffers<SOCKET_SZ, PACKET_SZ> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A received PUBLISH packet, with ranges relative to the start of the packet.
+#[derive(Clone)]
+struct Publish {
+    len: usize,
+    topic: Range<usize>,
+    payload: Range<usize>,
+    qos: QoS,
+    retain: bool,
+    packet_id: u16,
+}
+
+enum Incoming {
+    Publish(Publish),
+    PubAck(u16, u8),
+    PubRec(u16, u8),
+    PubRel(u16),
+    PubComp(u16, u8),
+    SubAck(u16, u8),
+    UnsubAck(u16),
+    PingResp,
+}
+
+/// The TCP connection, and the packet being received.
+struct Connection<'a> {
+    socket: TcpSocket<'a>,
+    rx: &'a mut [u8],
+    rx_len: usize,
+    /// Length of the packet at the start of `rx`, which is removed on the next read.
+    consumed: usize,
+    /// Bytes left to drop of a received packet that doesn't fit in `rx`.
+    skip: usize,
+    last_tx: Instant,
+}
+
+/// MQTT client.
+pub struct MqttClient<'a> {
+    conn: Connection<'a>,
+    stack: Stack<'a>,
+    broker: IpEndpoint,
+    config: ClientConfig<'a>,
+    tx: &'a mut [u8],
+    pending: &'a mut [u8],
+    pending_publish: Option<Publish>,
+    connected: bool,
+    /// Keep-alive ticker, `None` if keep-alive is disabled.
+    ticker: Option<Ticker>,
+    ping_outstanding: bool,
+    /// Packet identifiers of the QoS 2 messages delivered but not released by the broker yet.
+    unreleased: Vec<u16, MAX_UNRELEASED>,
+    backoff: Duration,
+    next_packet_id: u16,
+}
+
+impl<'a> MqttClient<'a> {
+    /// Create a new client, connecting to the broker at `broker`.
+    ///
+    /// The client doesn't connect until [`connect()`](Self::connect) or another operation is called.
+    pub fn new<const SOCKET_SZ: usize, const PACKET_SZ: usize>(
+        stack: Stack<'a>,
+        broker: impl Into<IpEndpoint>,
+        config: ClientConfig<'a>,
+        buffers: &'a mut MqttBuffers<SOCKET_SZ, PACKET_SZ>,
+    ) -> Self {
+        let mut socket = TcpSocket::new(stack, &mut buffers.socket_rx, &mut buffers.socket_tx);
+        socket.set_timeout(Some(config.keep_alive * 3 / 2 + config.timeout));
+        Self {
+            conn: Connection {
+                socket,
+                rx: &mut buffers.rx,
+                rx_len: 0,
+                consumed: 0,
+                skip: 0,
+                last_tx: Instant::now(),
+            },
+            stack,
+            broker: broker.into(),
+            config,
+            tx: &mut buffers.tx,
+            pending: &mut buffers.pending,
+            pending_publish: None,
+            connected: false,
+            ticker: (config.keep_alive.as_ticks() != 0).then(|| Ticker::every(config.keep_alive / 2)),
+            ping_outstanding: false,
+            unreleased: Vec::new(),
+            backoff: config.min_backoff,
+            next_packet_id: 1,
+        }
+    }
+
+    /// Whether the client is connected to the broker.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Connect to the broker, closing the current connection if any.
+    ///
+    /// This waits for the link and the IP configuration to be up, then makes a single connection
+    /// attempt, and subscribes to [`ClientConfig::subscriptions`].
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.conn.reset();
+        self.stack.wait_link_up().await;
+        self.stack.wait_config_up().await;
+
+        if let Err(e) = self.conn.socket.connect(self.broker).await {
+            warn!("mqtt: connect failed: {:?}", e);
+            return Err(Error::Network);
+        }
+        let range = encode_connect(self.tx, &self.config)?;
+        self.conn.write(&self.tx[range]).await?;
+
+        let (header, body) = match with_timeout(self.config.timeout, self.conn.read_packet()).await {
+            Ok(r) => r?,
+            Err(_) => return Err(Error::Network),
+        };
+        let body = &self.conn.rx[body];
+        if header & 0xF0 != CONNACK || body.len() < 2 {
+            return Err(Error::Protocol);
+        }
+        let code = body[1];
+        if code != 0 {
+            self.conn.socket.abort();
+            return Err(Error::ConnectionRefused(code));
+        }
+
+        debug!("mqtt: connected");
+        self.connected = true;
+        self.ping_outstanding = false;
+        if let Some(ticker) = &mut self.ticker {
+            ticker.reset();
+        }
+        if self.config.clean_session {
+            self.unreleased.clear();
+        }
+        for &(topic, qos) in self.config.subscriptions {
+            self.subscribe_once(topic, qos).await?;
+        }
+        self.backoff = self.config.min_backoff;
+        Ok(())
+    }
+
+    /// Disconnect from the broker.
+    ///
+    /// The client reconnects on the next operation.
+    pub async fn disconnect(&mut self) {
+        if self.connected {
+            self.connected = false;
+            let range = encode_empty(self.tx, DISCONNECT);
+            if self.conn.write(&self.tx[range]).await.is_ok() {
+                let _ = self.conn.socket.flush().await;
+            }
+        }
+        self.conn.socket.close();
+        let _ = self.conn.socket.flush().await;
+        self.conn.reset();
+    }
+
+    /// Publish a message.
+    ///
+    /// With [`QoS::AtLeastOnce`] and [`QoS::ExactlyOnce`], this waits for the broker to acknowledge
+    /// the message, and resumes the exchange if the connection is lost before.
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<(), Error> {
+        let packet_id = match qos {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => self.packet_id(),
+        };
+        let mut dup = false;
+        // With QoS 2, whether the broker received the message, which then only needs to be released.
+        let mut received = false;
+        loop {
+            self.ensure_connected().await?;
+            let r = async {
+                if !received {
+                    let range = encode_publish(
+                        self.tx,
+                        self.config.version,
+                        topic,
+                        payload,
+                        qos,
+                        retain,
+                        dup,
+                        packet_id,
+                    )?;
+                    self.conn.write(&self.tx[range]).await?;
+                    match qos {
+                        QoS::AtMostOnce => return Ok(()),
+                        QoS::AtLeastOnce => {
+                            let reason = self
+                                .wait_for(|i| match i {
+                                    Incoming::PubAck(id, reason) if *id == packet_id => Some(*reason),
+                                    _ => None,
+                                })
+                                .await?;
+                            return check_reason(reason);
+                        }
+                        QoS::ExactlyOnce => {
+                            let reason = self
+                                .wait_for(|i| match i {
+                                    Incoming::PubRec(id, reason) if *id == packet_id => Some(*reason),
+                                    _ => None,
+                                })
+                                .await?;
+                            check_reason(reason)?;
+                            received = true;
+                        }
+                    }
+                }
+
+                let range = encode_ack(self.tx, PUBREL, packet_id);
+                self.conn.write(&self.tx[range]).await?;
+                let reason = self
+                    .wait_for(|i| match i {
+                        Incoming::PubComp(id, reason) if *id == packet_id => Some(*reason),
+                        _ => None,
+                    })
+                    .await?;
+                check_reason(reason)
+            }
+            .await;
+            match r {
+                Err(Error::Network) => {
+                    self.connection_lost();
+                    dup = qos != QoS::AtMostOnce;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Subscribe to a topic filter. Returns the QoS granted by the broker.
+    ///
+    /// The subscription is lost on reconnection if [`ClientConfig::clean_session`] is set. Use
+    /// [`ClientConfig::subscriptions`] for subscriptions that must persist.
+    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<QoS, Error> {
+        loop {
+            self.ensure_connected().await?;
+            match self.subscribe_once(topic, qos).await {
+                Err(Error::Network) => self.connection_lost(),
+                r => return r,
+            }
+        }
+    }
+
+    /// Unsubscribe from a topic filter.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        loop {
+            self.ensure_connected().await?;
+            let packet_id = self.packet_id();
+            let r = async {
+                let range = encode_unsubscribe(self.tx, self.config.version, packet_id, topic)?;
+                self.conn.write(&self.tx[range]).await?;
+                self.wait_for(|i| match i {
+                    Incoming::UnsubAck(id) if *id == packet_id => Some(()),
+                    _ => None,
+                })
+                .await
+            }
+            .await;
+            match r {
+                Err(Error::Network) => self.connection_lost(),
+                r => return r,
+            }
+        }
+    }
+
+    /// Wait for a message from the broker.
+    ///
+    /// Messages with [`QoS::AtLeastOnce`] and [`QoS::ExactlyOnce`] are acknowledged before being
+    /// returned. A [`QoS::ExactlyOnce`] message sent again by the broker before it's released is
+    /// acknowledged again, but not returned twice.
+    pub async fn receive(&mut self) -> Result<Message<'_>, Error> {
+        let from_pending = loop {
+            self.ensure_connected().await?;
+            if self.pending_publish.is_some() {
+                break true;
+            }
+            let r = async {
+                loop {
+                    if let Incoming::Publish(publish) = self.next_incoming().await? {
+                        if self.acknowledge(&publish).await? {
+                            return Ok(publish);
+                        }
+                    }
+                }
+            }
+            .await;
+            match r {
+                Ok(publish) => {
+                    self.pending_publish = Some(publish);
+                    break false;
+                }
+                Err(Error::Network) => self.connection_lost(),
+                Err(e) => return Err(e),
+            }
+        };
+
+        let publish = unwrap!(self.pending_publish.take());
+        let packet = if from_pending { &*self.pending } else { &*self.conn.rx };
+        Ok(Message {
+            topic: core::str::from_utf8(&packet[publish.topic]).map_err(|_| Error::Protocol)?,
+            payload: &packet[publish.payload],
+            qos: publish.qos,
+            retain: publish.retain,
+        })
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    fn connection_lost(&mut self) {
+        if self.connected {
+            warn!("mqtt: connection lost");
+        }
+        self.connected = false;
+    }
+
+    async fn ensure_connected(&mut self) -> Result<(), Error> {
+        while !self.connected {
+            match self.connect().await {
+                Ok(()) => {}
+                Err(Error::Network) => {
+                    debug!("mqtt: reconnecting in {} ms", self.backoff.as_millis());
+                    Timer::after(self.backoff).await;
+                    self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe_once(&mut self, topic: &str, qos: QoS) -> Result<QoS, Error> {
+        let packet_id = self.packet_id();
+        let range = encode_subscribe(self.tx, self.config.version, packet_id, topic, qos)?;
+        self.conn.write(&self.tx[range]).await?;
+        let code = self
+            .wait_for(|i| match i {
+                Incoming::SubAck(id, code) if *id == packet_id => Some(*code),
+                _ => None,
+            })
+            .await?;
+        match code {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            _ => Err(Error::Rejected(code)),
+        }
+    }
+
+    /// Acknowledge a received message. Returns whether it must be delivered, i.e. it's not a
+    /// QoS 2 message that was delivered already.
+    async fn acknowledge(&mut self, publish: &Publish) -> Result<bool, Error> {
+        let (header, deliver) = match publish.qos {
+            QoS::AtMostOnce => return Ok(true),
+            QoS::AtLeastOnce => (PUBACK, true),
+            QoS::ExactlyOnce => {
+                // The message is delivered now, and its identifier kept until the broker releases it.
+                let new = !self.unreleased.contains(&publish.packet_id);
+                if new && self.unreleased.push(publish.packet_id).is_err() {
+                    warn!("mqtt: too many unreleased messages, duplicates may be delivered");
+                }
+                (PUBREC, new)
+            }
+        };
+        let range = encode_ack(self.tx, header, publish.packet_id);
+        self.conn.write(&self.tx[range]).await?;
+        Ok(deliver)
+    }
+
+    /// Wait for the packet selected by `f`, keeping a message received in the meantime for `receive()`.
+    async fn wait_for<T>(&mut self, f: impl Fn(&Incoming) -> Option<T>) -> Result<T, Error> {
+        let deadline = Instant::now() + self.config.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let incoming = match with_timeout(remaining, self.next_incoming()).await {
+                Ok(r) => r?,
+                Err(_) => return Err(Error::Network),
+            };
+            if let Some(r) = f(&incoming) {
+                return Ok(r);
+            }
+            if let Incoming::Publish(publish) = incoming {
+                if self.pending_publish.is_some() {
+                    // Without acknowledgment, a persistent session gets the message again after reconnecting.
+                    warn!("mqtt: dropping message received while waiting for a response");
+                    continue;
+                }
+                if self.acknowledge(&publish).await? {
+                    self.pending[..publish.len].copy_from_slice(&self.conn.rx[..publish.len]);
+                    self.pending_publish = Some(publish);
+                }
+            }
+        }
+    }
+
+    /// Read the next packet, sending keep-alive pings as needed, and answering pings and QoS 2
+    /// releases from the broker.
+    async fn next_incoming(&mut self) -> Result<Incoming, Error> {
+        let period = self.config.keep_alive / 2;
+        loop {
+            let tick = async {
+                match &mut self.ticker {
+                    Some(ticker) => ticker.next().await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select(tick, self.conn.read_packet()).await {
+                Either::First(()) => {
+                    if self.ping_outstanding {
+                        warn!("mqtt: no ping response");
+                        return Err(Error::Network);
+                    }
+                    if Instant::now().saturating_duration_since(self.conn.last_tx) >= period {
+                        let range = encode_empty(self.tx, PINGREQ);
+                        self.conn.write(&self.tx[range]).await?;
+                        self.ping_outstanding = true;
+                    }
+                }
+                Either::Second(r) => {
+                    let (header, body) = r?;
+                    let packet = &self.conn.rx[..body.end];
+                    match parse_incoming(self.config.version, header, packet, body.start)? {
+                        Incoming::PingResp => self.ping_outstanding = false,
+                        Incoming::PubRel(id) => {
+                            self.unreleased.retain(|x| *x != id);
+                            let range = encode_ack(self.tx, PUBCOMP, id);
+                            self.conn.write(&self.tx[range]).await?;
+                        }
+                        incoming => return Ok(incoming),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_reason(reason: u8) -> Result<(), Error> {
+    match reason {
+        0x80.. => Err(Error::Rejected(reason)),
+        _ => Ok(()),
+    }
+}
+
+/// Parse a packet received from the broker. Its body starts at `start`.
+fn parse_incoming(version: Version, header: u8, packet: &[u8], start: usize) -> Result<Incoming, Error> {
+    let v5 = version == Version::V5;
+    let mut r = Reader {
+        data: packet,
+        pos: start,
+    };
+    match header & 0xF0 {
+        PUBLISH => {
+            let qos = match (header >> 1) & 3 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                2 => QoS::ExactlyOnce,
+                _ => return Err(Error::Protocol),
+            };
+            let topic_len = r.u16()? as usize;
+            let topic = r.pos..r.pos + topic_len;
+            r.skip(topic_len)?;
+            let packet_id = if qos != QoS::AtMostOnce { r.u16()? } else { 0 };
+            if v5 {
+                let len = r.varint()?;
+                r.skip(len)?;
+            }
+            Ok(Incoming::Publish(Publish {
+                len: packet.len(),
+                topic,
+                payload: r.pos..packet.len(),
+                qos,
+                retain: header & 1 != 0,
+                packet_id,
+            }))
+        }
+        // MQTT 5 omits the reason code on success.
+        PUBACK => Ok(Incoming::PubAck(r.u16()?, r.u8().unwrap_or(0))),
+        PUBREC => Ok(Incoming::PubRec(r.u16()?, r.u8().unwrap_or(0))),
+        _ if header == PUBREL => Ok(Incoming::PubRel(r.u16()?)),
+        PUBCOMP => Ok(Incoming::PubComp(r.u16()?, r.u8().unwrap_or(0))),
+        SUBACK => {
+            let id = r.u16()?;
+            if v5 {
+                let len = r.varint()?;
+                r.skip(len)?;
+            }
+            Ok(Incoming::SubAck(id, r.u8()?))
+        }
+        UNSUBACK => Ok(Incoming::UnsubAck(r.u16()?)),
+        PINGRESP => Ok(Incoming::PingResp),
+        DISCONNECT => {
+            warn!("mqtt: disconnected by broker");
+            Err(Error::Network)
+        }
+        _ => Err(Error::Protocol),
+    }
+}
+
+impl<'a> Connection<'a> {
+    fn reset(&mut self) {
+        self.socket.abort();
+        self.rx_len = 0;
+        self.consumed = 0;
+        self.skip = 0;
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.socket.write_all(data).await.map_err(|_| Error::Network)?;
+        self.last_tx = Instant::now();
+        Ok(())
+    }
+
+    /// Read a packet into `rx`. Returns its first header byte and the range of its body.
+    ///
+    /// This is cancel-safe: a partially received packet is kept for the next call.
+    async fn read_packet(&mut self) -> Result<(u8, Range<usize>), Error> {
+        if self.consumed > 0 {
+            self.rx.copy_within(self.consumed..self.rx_len, 0);
+            self.rx_len -= self.consumed;
+            self.consumed = 0;
+        }
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min(self.rx_len);
+                self.rx.copy_within(n..self.rx_len, 0);
+                self.rx_len -= n;
+                self.skip -= n;
+            }
+            if self.skip == 0 {
+                if let Some((header_len, body_len)) = parse_fixed_header(&self.rx[..self.rx_len])? {
+                    let len = header_len + body_len;
+                    if len > self.rx.len() {
+                        warn!("mqtt: dropping packet of {} bytes, larger than the buffer", len);
+                        self.skip = len;
+                        continue;
+                    }
+                    if len <= self.rx_len {
+                        self.consumed = len;
+                        return Ok((self.rx[0], header_len..len));
+                    }
+                }
+            }
+            let n = self
+                .socket
+                .read(&mut self.rx[self.rx_len..])
+                .await
+                .map_err(|_| Error::Network)?;
+            if n == 0 {
+                return Err(Error::Network);
+            }
+            self.rx_len += n;
+        }
+    }
+}
+
+/// Parse the fixed header. Returns its length and the length of the packet body, or `None` if incomplete.
+fn parse_fixed_header(data: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut len = 0;
+    for i in 0..4 {
+        let Some(&b) = data.get(1 + i) else {
+            return Ok(None);
+        };
+        len |= ((b & 0x7F) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((2 + i, len)));
+        }
+    }
+    Err(Error::Protocol)
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+    pos: usize,
+}
+
+impl<'d> Reader<'d> {
+    fn u8(&mut self) -> Result<u8, Error> {
+        let b = *self.data.get(self.pos).ok_or(Error::Protocol)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn varint(&mut self) -> Result<usize, Error> {
+        let mut v = 0;
+        for i in 0..4 {
+            let b = self.u8()?;
+            v |= ((b & 0x7F) as usize) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(Error::Protocol)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        if self.pos + n > self.data.len() {
+            return Err(Error::Protocol);
+        }
+        self.pos += n;
+        Ok(())
+    }
+}
+
+/// Writes a packet body after [`HEADER_ROOM`], then prepends the fixed header.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, len: HEADER_ROOM }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.buf
+            .get_mut(self.len..self.len + data.len())
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.len += data.len();
+        Ok(())
+    }
+
+    fn u8(&mut self, v: u8) -> Result<(), Error> {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    /// A length-prefixed string or binary data.
+    fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let len: u16 = data.len().try_into().map_err(|_| Error::BufferTooSmall)?;
+        self.u16(len)?;
+        self.bytes(data)
+    }
+
+    /// Write the fixed header before the body. Returns the range of the whole packet.
+    fn finish(self, header: u8) -> Result<Range<usize>, Error> {
+        let mut len = self.len - HEADER_ROOM;
+        if len >= 1 << 28 {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut encoded = [0; 4];
+        let mut n = 0;
+        loop {
+            encoded[n] = (len & 0x7F) as u8;
+            len >>= 7;
+            if len > 0 {
+                encoded[n] |= 0x80;
+            }
+            n += 1;
+            if len == 0 {
+                break;
+            }
+        }
+        let start = HEADER_ROOM - 1 - n;
+        self.buf[start] = header;
+        self.buf[start + 1..HEADER_ROOM].copy_from_slice(&encoded[..n]);
+        Ok(start..self.len)
+    }
+}
+
+fn encode_empty(buf: &mut [u8], header: u8) -> Range<usize> {
+    buf[0] = header;
+    buf[1] = 0;
+    0..2
+}
+
+fn encode_connect(buf: &mut [u8], config: &ClientConfig) -> Result<Range<usize>, Error> {
+    let v5 = config.version == Version::V5;
+    let mut w = Writer::new(buf);
+    w.data(b"MQTT")?;
+    w.u8(if v5 { 5 } else { 4 })?;
+
+    let mut flags = 0;
+    if config.clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = &config.will {
+        flags |= 0x04 | (will.qos as u8) << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    w.u8(flags)?;
+    w.u16(config.keep_alive.as_secs().min(u16::MAX as u64) as u16)?;
+    if v5 {
+        w.u8(0)?; // properties
+    }
+
+    w.data(config.client_id.as_bytes())?;
+    if let Some(will) = &config.will {
+        if v5 {
+            w.u8(0)?; // will properties
+        }
+        w.data(will.topic.as_bytes())?;
+        w.data(will.payload)?;
+    }
+    if let Some(username) = config.username {
+        w.data(username.as_bytes())?;
+    }
+    if let Some(password) = config.password {
+        w.data(password)?;
+    }
+    w.finish(CONNECT)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_publish(
+    buf: &mut [u8],
+    version: Version,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    dup: bool,
+    packet_id: u16,
+) -> Result<Range<usize>, Error> {
+    let mut w = Writer::new(buf);
+    w.data(topic.as_bytes())?;
+    if qos != QoS::AtMostOnce {
+        w.u16(packet_id)?;
+    }
+    if version == Version::V5 {
+        w.u8(0)?; // properties
+    }
+    w.bytes(payload)?;
+    let header = PUBLISH | (dup as u8) << 3 | (qos as u8) << 1 | retain as u8;
+    w.finish(header)
+}
+
+/// Encode a PUBACK, PUBREC, PUBREL or PUBCOMP packet.
+fn encode_ack(buf: &mut [u8], header: u8, packet_id: u16) -> Range<usize> {
+    buf[0] = header;
+    buf[1] = 2;
+    buf[2..4].copy_from_slice(&packet_id.to_be_bytes());
+    0..4
+}
+
+fn encode_subscribe(
+    buf: &mut [u8],
+    version: Version,
+    packet_id: u16,
+    topic: &str,
+    qos: QoS,
+) -> Result<Range<usize>, Error> {
+    let mut w = Writer::new(buf);
+    w.u16(packet_id)?;
+    if version == Version::V5 {
+        w.u8(0)?; // properties
+    }
+    w.data(topic.as_bytes())?;
+    w.u8(qos as u8)?;
+    w.finish(SUBSCRIBE)
+}
+
+fn encode_unsubscribe(buf: &mut [u8], version: Version, packet_id: u16, topic: &str) -> Result<Range<usize>, Error> {
+    let mut w = Writer::new(buf);
+    w.u16(packet_id)?;
+    if version == Version::V5 {
+        w.u8(0)?; // properties
+    }
+    w.data(topic.as_bytes())?;
+    w.finish(UNSUBSCRIBE)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn encoded(f: impl FnOnce(&mut [u8]) -> Result<Range<usize>, Error>) -> Vec<u8> {
+        let mut buf = [0; 256];
+        let range = f(&mut buf).unwrap();
+        buf[range].to_vec()
+    }
+
+    #[test]
+    fn connect() {
+        let mut config = ClientConfig::new("id");
+        config.keep_alive = Duration::from_secs(30);
+        assert_eq!(
+            encoded(|buf| encode_connect(buf, &config)),
+            [0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 30, 0, 2, b'i', b'd']
+        );
+
+        config.version = Version::V5;
+        config.clean_session = false;
+        config.username = Some("u");
+        config.password = Some(b"p");
+        config.will = Some(Will {
+            topic: "w",
+            payload: b"x",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+        assert_eq!(
+            encoded(|buf| encode_connect(buf, &config)),
+            [
+                0x10, 28, 0, 4, b'M', b'Q', b'T', b'T', 5, 0xEC, 0, 30, 0, 0, 2, b'i', b'd', 0, 0, 1, b'w', 0, 1, b'x',
+                0, 1, b'u', 0, 1, b'p'
+            ]
+        );
+    }
+
+    #[test]
+    fn publish() {
+        let v3 = encoded(|buf| encode_publish(buf, Version::V3_1_1, "a/b", b"hi", QoS::AtMostOnce, true, false, 7));
+        assert_eq!(v3, [0x31, 7, 0, 3, b'a', b'/', b'b', b'h', b'i']);
+        let v3 = encoded(|buf| encode_publish(buf, Version::V3_1_1, "a/b", b"hi", QoS::AtLeastOnce, false, true, 7));
+        assert_eq!(v3, [0x3A, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'h', b'i']);
+        let v5 = encoded(|buf| encode_publish(buf, Version::V5, "a/b", b"hi", QoS::ExactlyOnce, false, false, 7));
+        assert_eq!(v5, [0x34, 10, 0, 3, b'a', b'/', b'b', 0, 7, 0, b'h', b'i']);
+
+        // Bodies of 128 bytes and more have a 2-byte length.
+        let payload = [0x55; 200];
+        let long = encoded(|buf| encode_publish(buf, Version::V3_1_1, "t", &payload, QoS::AtMostOnce, false, false, 0));
+        assert_eq!(long[..6], [0x30, 203, 1, 0, 1, b't']);
+        assert_eq!(long.len(), 206);
+
+        let mut small = [0; 64];
+        assert_eq!(
+            encode_publish(
+                &mut small,
+                Version::V3_1_1,
+                "t",
+                &payload,
+                QoS::AtMostOnce,
+                false,
+                false,
+                0
+            ),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn subscribe_and_acks() {
+        let v3 = encoded(|buf| encode_subscribe(buf, Version::V3_1_1, 0x102, "a/#", QoS::ExactlyOnce));
+        assert_eq!(v3, [0x82, 8, 1, 2, 0, 3, b'a', b'/', b'#', 2]);
+        let v5 = encoded(|buf| encode_subscribe(buf, Version::V5, 0x102, "a/#", QoS::AtLeastOnce));
+        assert_eq!(v5, [0x82, 9, 1, 2, 0, 0, 3, b'a', b'/', b'#', 1]);
+        let v3 = encoded(|buf| encode_unsubscribe(buf, Version::V3_1_1, 3, "a"));
+        assert_eq!(v3, [0xA2, 5, 0, 3, 0, 1, b'a']);
+        let v5 = encoded(|buf| encode_unsubscribe(buf, Version::V5, 3, "a"));
+        assert_eq!(v5, [0xA2, 6, 0, 3, 0, 0, 1, b'a']);
+
+        for header in [PUBACK, PUBREC, PUBREL, PUBCOMP] {
+            assert_eq!(
+                encoded(|buf| Ok(encode_ack(buf, header, 0x1234))),
+                [header, 2, 0x12, 0x34]
+            );
+        }
+        assert_eq!(encoded(|buf| Ok(encode_empty(buf, PINGREQ))), [0xC0, 0]);
+    }
+
+    #[test]
+    fn fixed_header() {
+        assert_eq!(parse_fixed_header(&[]), Ok(None));
+        assert_eq!(parse_fixed_header(&[0x30]), Ok(None));
+        assert_eq!(parse_fixed_header(&[0x30, 0]), Ok(Some((2, 0))));
+        assert_eq!(parse_fixed_header(&[0x30, 127]), Ok(Some((2, 127))));
+        assert_eq!(parse_fixed_header(&[0x30, 0x80]), Ok(None));
+        assert_eq!(parse_fixed_header(&[0x30, 0x80, 1]), Ok(Some((3, 128))));
+        assert_eq!(
+            parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]),
+            Ok(Some((5, 268_435_455)))
+        );
+        // The length takes at most 4 bytes.
+        assert_eq!(
+            parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0]),
+            Err(Error::Protocol)
+        );
+    }
+
+    /// Parse a complete packet.
+    fn parse(version: Version, packet: &[u8]) -> Result<Incoming, Error> {
+        let (header_len, body_len) = parse_fixed_header(packet)?.unwrap();
+        assert_eq!(header_len + body_len, packet.len());
+        parse_incoming(version, packet[0], packet, header_len)
+    }
+
+    #[test]
+    fn incoming_publish() {
+        let packet = [0x33, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'h', b'i'];
+        let Ok(Incoming::Publish(p)) = parse(Version::V3_1_1, &packet) else {
+            panic!()
+        };
+        assert_eq!((p.len, p.topic, p.payload), (11, 4..7, 9..11));
+        assert_eq!((p.qos, p.retain, p.packet_id), (QoS::AtLeastOnce, true, 7));
+
+        // MQTT 5 properties are skipped.
+        let packet = [0x34, 13, 0, 1, b't', 0, 9, 3, 0x01, 0x01, 0x00, b'h', b'e', b'y', b'!'];
+        let Ok(Incoming::Publish(p)) = parse(Version::V5, &packet) else {
+            panic!()
+        };
+        assert_eq!((p.topic, p.payload), (4..5, 11..15));
+        assert_eq!((p.qos, p.retain, p.packet_id), (QoS::ExactlyOnce, false, 9));
+
+        let packet = [0x30, 3, 0, 1, b't'];
+        let Ok(Incoming::Publish(p)) = parse(Version::V3_1_1, &packet) else {
+            panic!()
+        };
+        assert_eq!((p.payload, p.qos, p.packet_id), (5..5, QoS::AtMostOnce, 0));
+
+        // QoS 3 is invalid.
+        assert!(matches!(
+            parse(Version::V3_1_1, &[0x36, 4, 0, 1, b't', 0]),
+            Err(Error::Protocol)
+        ));
+        // Topic longer than the packet.
+        assert!(matches!(
+            parse(Version::V3_1_1, &[0x30, 3, 0, 9, b't']),
+            Err(Error::Protocol)
+        ));
+        // Missing packet identifier.
+        assert!(matches!(
+            parse(Version::V3_1_1, &[0x32, 4, 0, 1, b't', 0]),
+            Err(Error::Protocol)
+        ));
+        // Properties longer than the packet.
+        assert!(matches!(
+            parse(Version::V5, &[0x30, 4, 0, 1, b't', 5]),
+            Err(Error::Protocol)
+        ));
+        // Truncated property length.
+        assert!(matches!(
+            parse(Version::V5, &[0x30, 4, 0, 1, b't', 0x80]),
+            Err(Error::Protocol)
+        ));
+    }
+
+    #[test]
+    fn incoming_acks() {
+        for version in [Version::V3_1_1, Version::V5] {
+            assert!(matches!(parse(version, &[0x40, 2, 0, 5]), Ok(Incoming::PubAck(5, 0))));
+            assert!(matches!(parse(version, &[0x50, 2, 0, 5]), Ok(Incoming::PubRec(5, 0))));
+            assert!(matches!(parse(version, &[0x62, 2, 0, 5]), Ok(Incoming::PubRel(5))));
+            assert!(matches!(parse(version, &[0x70, 2, 0, 5]), Ok(Incoming::PubComp(5, 0))));
+            assert!(matches!(parse(version, &[0xB0, 2, 0, 5]), Ok(Incoming::UnsubAck(5))));
+            assert!(matches!(parse(version, &[0xD0, 0]), Ok(Incoming::PingResp)));
+            assert!(matches!(parse(version, &[0xE0, 0]), Err(Error::Network)));
+            // Truncated packet identifiers.
+            for header in [0x40, 0x50, 0x62, 0x70, 0x90, 0xB0] {
+                assert!(matches!(parse(version, &[header, 1, 0]), Err(Error::Protocol)));
+            }
+        }
+        // MQTT 5 reason codes.
+        assert!(matches!(
+            parse(Version::V5, &[0x40, 3, 0, 5, 0x87]),
+            Ok(Incoming::PubAck(5, 0x87))
+        ));
+        assert!(matches!(
+            parse(Version::V5, &[0x50, 3, 0, 5, 0x10]),
+            Ok(Incoming::PubRec(5, 0x10))
+        ));
+        assert!(matches!(
+            parse(Version::V5, &[0x70, 3, 0, 5, 0x92]),
+            Ok(Incoming::PubComp(5, 0x92))
+        ));
+
+        assert!(matches!(
+            parse(Version::V3_1_1, &[0x90, 3, 0, 5, 1]),
+            Ok(Incoming::SubAck(5, 1))
+        ));
+        assert!(matches!(
+            parse(Version::V5, &[0x90, 4, 0, 5, 0, 2]),
+            Ok(Incoming::SubAck(5, 2))
+        ));
+        assert!(matches!(
+            parse(Version::V5, &[0x90, 7, 0, 5, 3, 0x1F, 0, 0, 0x80]),
+            Ok(Incoming::SubAck(5, 0x80))
+        ));
+        // Missing return code.
+        assert!(matches!(parse(Version::V3_1_1, &[0x90, 2, 0, 5]), Err(Error::Protocol)));
+        assert!(matches!(parse(Version::V5, &[0x90, 3, 0, 5, 0]), Err(Error::Protocol)));
+        // PUBREL with wrong flags, and packets only sent by clients.
+        assert!(matches!(parse(Version::V3_1_1, &[0x60, 2, 0, 5]), Err(Error::Protocol)));
+        assert!(matches!(parse(Version::V3_1_1, &[0x10, 0]), Err(Error::Protocol)));
+        assert!(matches!(parse(Version::V3_1_1, &[0xC0, 0]), Err(Error::Protocol)));
+    }
+}
@@ -0,0 +1,523 @@
+//! Packet capture in pcapng format.
+//!
+//! Attach a [`Capture`] to a [`Runner`](crate::Runner) with [`Runner::set_capture()`](crate::Runner::set_capture)
+//! to get a copy of every frame exchanged with the driver. The frames can be encoded with [`header()`] and
+//! [`write_packet()`], e.g. to a file under `std`, or stored in a [`PcapBuffer`] and read back asynchronously.
+//!
+//! The output can be opened with Wireshark or tcpdump.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::Instant;
+use embedded_io_async::Write;
+
+/// Length of the data written by [`header()`].
+pub const HEADER_LEN: usize = 48;
+
+const PACKET_OVERHEAD: usize = 44;
+const MAX_SNAPLEN: usize = 0xFFFF;
+
+/// Direction of a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// The frame was received from the driver.
+    Rx,
+    /// The frame was handed to the driver for transmission.
+    Tx,
+}
+
+/// Link layer of the captured frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkType {
+    /// Ethernet frames.
+    Ethernet,
+    /// Raw IPv4 or IPv6 packets, without link layer header.
+    Ip,
+    /// IEEE 802.15.4 frames, without FCS.
+    Ieee802154,
+}
+
+impl LinkType {
+    /// The pcap `LINKTYPE_*` value.
+    pub fn code(self) -> u16 {
+        match self {
+            LinkType::Ethernet => 1,
+            LinkType::Ip => 101,
+            LinkType::Ieee802154 => 230,
+        }
+    }
+}
+
+/// A sink for captured frames.
+///
+/// This is called synchronously from the network stack's poll loop, for every frame received or
+/// transmitted by the interface, so it must be fast and must not block.
+pub trait Capture {
+    /// Capture a frame.
+    fn capture(&self, timestamp: Instant, direction: Direction, link_type: LinkType, frame: &[u8]);
+}
+
+/// Get the pcapng section header and interface description, which must precede all packets.
+///
+/// Frames longer than `snaplen` will be truncated by [`write_packet()`].
+pub fn header(link_type: LinkType, snaplen: u32) -> [u8; HEADER_LEN] {
+    let mut buf = [0; HEADER_LEN];
+    // Section header block.
+    buf[0..4].copy_from_slice(&0x0A0D_0D0Au32.to_le_bytes());
+    buf[4..8].copy_from_slice(&28u32.to_le_bytes());
+    buf[8..12].copy_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    buf[12..14].copy_from_slice(&1u16.to_le_bytes());
+    buf[14..16].copy_from_slice(&0u16.to_le_bytes());
+    buf[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    buf[24..28].copy_from_slice(&28u32.to_le_bytes());
+    // Interface description block. The default timestamp resolution is microseconds.
+    buf[28..32].copy_from_slice(&1u32.to_le_bytes());
+    buf[32..36].copy_from_slice(&20u32.to_le_bytes());
+    buf[36..38].copy_from_slice(&link_type.code().to_le_bytes());
+    buf[38..40].copy_from_slice(&0u16.to_le_bytes());
+    buf[40..44].copy_from_slice(&snaplen.to_le_bytes());
+    buf[44..48].copy_from_slice(&20u32.to_le_bytes());
+    buf
+}
+
+/// Get the encoded length of a frame of `len` bytes.
+pub fn packet_len(len: usize, snaplen: u32) -> usize {
+    PACKET_OVERHEAD + padded(len.min(snaplen as usize))
+}
+
+/// Encode a frame as a pcapng enhanced packet block.
+///
+/// The block is passed to `out` in several pieces, for a total of [`packet_len()`] bytes.
+pub fn write_packet(timestamp: Instant, direction: Direction, frame: &[u8], snaplen: u32, mut out: impl FnMut(&[u8])) {
+    let captured = &frame[..frame.len().min(snaplen as usize)];
+    let total = packet_len(frame.len(), snaplen) as u32;
+    let micros = timestamp.as_micros();
+
+    let mut head = [0; 28];
+    head[0..4].copy_from_slice(&6u32.to_le_bytes());
+    head[4..8].copy_from_slice(&total.to_le_bytes());
+    head[8..12].copy_from_slice(&0u32.to_le_bytes());
+    head[12..16].copy_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    head[16..20].copy_from_slice(&(micros as u32).to_le_bytes());
+    head[20..24].copy_from_slice(&(captured.len() as u32).to_le_bytes());
+    head[24..28].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+    out(&head);
+    out(captured);
+    out(&[0; 3][..padded(captured.len()) - captured.len()]);
+
+    let mut tail = [0; 16];
+    // epb_flags option, with the inbound/outbound bits.
+    tail[0..2].copy_from_slice(&2u16.to_le_bytes());
+    tail[2..4].copy_from_slice(&4u16.to_le_bytes());
+    let flags: u32 = match direction {
+        Direction::Rx => 1,
+        Direction::Tx => 2,
+    };
+    tail[4..8].copy_from_slice(&flags.to_le_bytes());
+    // tail[8..12] is the end of options.
+    tail[12..16].copy_from_slice(&total.to_le_bytes());
+    out(&tail);
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Behavior of a [`PcapBuffer`] when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Drop new frames. Use this when streaming the capture with [`PcapBuffer::read()`].
+    Stream,
+    /// Drop the oldest frames, so the buffer always holds the most recent traffic. Use this to
+    /// keep a post-mortem log that's retrieved with [`PcapBuffer::dump()`].
+    Ring,
+}
+
+/// A fixed-size buffer of `N` bytes holding captured frames in pcapng format.
+///
+/// Frames are truncated to fit in the buffer. Frames that can't be stored are counted in
+/// [`dropped()`](PcapBuffer::dropped).
+pub struct PcapBuffer<M: RawMutex, const N: usize> {
+    mode: Mode,
+    state: Mutex<M, RefCell<State<N>>>,
+}
+
+struct State<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+    /// Bytes left to read in the packet at `start`, 0 if `start` is at a packet boundary.
+    reading: usize,
+    link_type: Option<LinkType>,
+    header_pos: usize,
+    dropped: u32,
+    waker: WakerRegistration,
+}
+
+impl<M: RawMutex, const N: usize> PcapBuffer<M, N> {
+    /// Create a new buffer.
+    pub const fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            state: Mutex::new(RefCell::new(State {
+                buf: [0; N],
+                start: 0,
+                len: 0,
+                reading: 0,
+                link_type: None,
+                header_pos: 0,
+                dropped: 0,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    /// Maximum number of bytes captured per frame.
+    pub const fn snaplen(&self) -> u32 {
+        let max = N.saturating_sub(PACKET_OVERHEAD) & !3;
+        if max < MAX_SNAPLEN {
+            max as u32
+        } else {
+            MAX_SNAPLEN as u32
+        }
+    }
+
+    /// Number of frames dropped because the buffer was full.
+    pub fn dropped(&self) -> u32 {
+        self.state.lock(|s| s.borrow().dropped)
+    }
+
+    /// Read the capture as a continuous pcapng stream.
+    ///
+    /// The first read returns the pcapng header, once the link type is known from the first
+    /// captured frame. This waits until data is available, and returns the number of bytes read.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            self.state.lock(|s| {
+                let s = &mut *s.borrow_mut();
+                let Some(link_type) = s.link_type else {
+                    s.waker.register(cx.waker());
+                    return Poll::Pending;
+                };
+                if s.header_pos < HEADER_LEN {
+                    let header = header(link_type, self.snaplen());
+                    let n = buf.len().min(HEADER_LEN - s.header_pos);
+                    buf[..n].copy_from_slice(&header[s.header_pos..][..n]);
+                    s.header_pos += n;
+                    return Poll::Ready(n);
+                }
+                if s.len == 0 {
+                    s.waker.register(cx.waker());
+                    return Poll::Pending;
+                }
+                Poll::Ready(s.read(buf))
+            })
+        })
+        .await
+    }
+
+    /// Write the pcapng header and the frames currently in the buffer to `w`, removing them from the buffer.
+    ///
+    /// Frames captured while this runs are not written, unless they fit in the space freed by the
+    /// frames already written.
+    pub async fn dump<W: Write>(&self, w: &mut W) -> Result<(), W::Error> {
+        let (link_type, mut remaining) = self.state.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            // Skip the rest of a packet partially returned by `read`, so the dump starts at a packet boundary.
+            let skip = s.reading;
+            s.discard(skip);
+            s.reading = 0;
+            (s.link_type, s.len)
+        });
+        let Some(link_type) = link_type else {
+            return Ok(());
+        };
+        w.write_all(&header(link_type, self.snaplen())).await?;
+
+        let mut chunk = [0; 64];
+        while remaining > 0 {
+            let n = self.state.lock(|s| {
+                let s = &mut *s.borrow_mut();
+                let n = chunk.len().min(remaining).min(s.len);
+                s.read(&mut chunk[..n])
+            });
+            if n == 0 {
+                break;
+            }
+            remaining -= n;
+            w.write_all(&chunk[..n]).await?;
+        }
+        w.flush().await
+    }
+}
+
+impl<M: RawMutex, const N: usize> Capture for PcapBuffer<M, N> {
+    fn capture(&self, timestamp: Instant, direction: Direction, link_type: LinkType, frame: &[u8]) {
+        let snaplen = self.snaplen();
+        self.state.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            match s.link_type {
+                None => s.link_type = Some(link_type),
+                Some(l) if l != link_type => {
+                    s.dropped = s.dropped.wrapping_add(1);
+                    return;
+                }
+                Some(_) => {}
+            }
+
+            let needed = packet_len(frame.len(), snaplen);
+            while N - s.len < needed {
+                // A packet the reader has started can't be dropped without corrupting the stream.
+                if self.mode == Mode::Stream || s.len == 0 || s.reading != 0 {
+                    s.dropped = s.dropped.wrapping_add(1);
+                    return;
+                }
+                let len = s.packet_len_at_start();
+                s.discard(len);
+                s.dropped = s.dropped.wrapping_add(1);
+            }
+
+            write_packet(timestamp, direction, frame, snaplen, |data| s.push(data));
+            s.waker.wake();
+        })
+    }
+}
+
+impl<const N: usize> State<N> {
+    fn push(&mut self, data: &[u8]) {
+        let mut pos = (self.start + self.len) % N;
+        for &b in data {
+            self.buf[pos] = b;
+            pos = (pos + 1) % N;
+        }
+        self.len += data.len();
+    }
+
+    fn discard(&mut self, len: usize) {
+        self.start = (self.start + len) % N;
+        self.len -= len;
+    }
+
+    fn packet_len_at_start(&self) -> usize {
+        let mut len = [0; 4];
+        for (i, b) in len.iter_mut().enumerate() {
+            *b = self.buf[(self.start + 4 + i) % N];
+        }
+        u32::from_le_bytes(len) as usize
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() && self.len > 0 {
+            if self.reading == 0 {
+                self.reading = self.packet_len_at_start();
+            }
+            let k = (buf.len() - n).min(self.reading);
+            for b in &mut buf[n..n + k] {
+                *b = self.buf[self.start];
+                self.start = (self.start + 1) % N;
+            }
+            self.len -= k;
+            self.reading -= k;
+            n += k;
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use embassy_futures::{block_on, poll_once};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    struct Output(Vec<u8>);
+
+    impl embedded_io_async::ErrorType for Output {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Write for Output {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    /// Check the structure of a pcapng stream, and return the captured data of each packet.
+    fn packets(data: &[u8], link_type: LinkType) -> Vec<&[u8]> {
+        assert_eq!(data[..HEADER_LEN], header(link_type, u32_at(data, 40)));
+        let mut packets = Vec::new();
+        let mut pos = HEADER_LEN;
+        while pos < data.len() {
+            let block = &data[pos..pos + u32_at(data, pos + 4) as usize];
+            assert_eq!(u32_at(block, 0), 6);
+            assert_eq!(u32_at(block, block.len() - 4) as usize, block.len());
+            let captured = u32_at(block, 20) as usize;
+            assert!(captured <= u32_at(block, 24) as usize);
+            assert_eq!(block.len(), PACKET_OVERHEAD + padded(captured));
+            packets.push(&block[28..28 + captured]);
+            pos += block.len();
+        }
+        assert_eq!(pos, data.len());
+        packets
+    }
+
+    fn dump<const N: usize>(buffer: &PcapBuffer<NoopRawMutex, N>) -> Vec<u8> {
+        let mut out = Output(Vec::new());
+        block_on(buffer.dump(&mut out)).unwrap();
+        out.0
+    }
+
+    #[test]
+    fn encode() {
+        let header = header(LinkType::Ip, 1500);
+        assert_eq!(u32_at(&header, 0), 0x0A0D_0D0A);
+        assert_eq!(u32_at(&header, 8), 0x1A2B_3C4D);
+        assert_eq!(header[36..38], [101, 0]);
+        assert_eq!(u32_at(&header, 40), 1500);
+
+        let mut block = Vec::new();
+        let timestamp = Instant::from_micros(0x1_0000_0002);
+        write_packet(timestamp, Direction::Tx, &[1, 2, 3, 4, 5], 1500, |d| {
+            block.extend_from_slice(d)
+        });
+        assert_eq!(block.len(), packet_len(5, 1500));
+        assert_eq!(
+            block,
+            [
+                6, 0, 0, 0, 52, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0, // header
+                1, 2, 3, 4, 5, 0, 0, 0, // padded data
+                2, 0, 4, 0, 2, 0, 0, 0, 0, 0, 0, 0, 52, 0, 0, 0, // flags and trailer
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_frames() {
+        // Empty frames, and frames longer than the snapshot length.
+        for (len, snaplen, captured) in [
+            (0, 1500, 0),
+            (1, 0, 0),
+            (100, 10, 10),
+            (100, 13, 13),
+            (70000, 65535, 65535),
+        ] {
+            let frame = vec![0xaa; len];
+            let mut block = Vec::new();
+            write_packet(Instant::from_ticks(0), Direction::Rx, &frame, snaplen, |d| {
+                block.extend_from_slice(d)
+            });
+            assert_eq!(block.len(), packet_len(len, snaplen));
+            assert_eq!(block.len(), PACKET_OVERHEAD + padded(captured));
+            assert_eq!(u32_at(&block, 20) as usize, captured);
+            assert_eq!(u32_at(&block, 24) as usize, len);
+            assert_eq!(u32_at(&block, 32 + padded(captured)), 1);
+        }
+    }
+
+    #[test]
+    fn snaplen() {
+        assert_eq!(PcapBuffer::<NoopRawMutex, 0>::new(Mode::Ring).snaplen(), 0);
+        assert_eq!(PcapBuffer::<NoopRawMutex, 47>::new(Mode::Ring).snaplen(), 0);
+        assert_eq!(PcapBuffer::<NoopRawMutex, 256>::new(Mode::Ring).snaplen(), 212);
+        assert_eq!(PcapBuffer::<NoopRawMutex, 0x20000>::new(Mode::Ring).snaplen(), 0xFFFF);
+
+        // A buffer too small for any frame drops everything.
+        let buffer = PcapBuffer::<NoopRawMutex, 16>::new(Mode::Ring);
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ethernet, &[0; 60]);
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(dump(&buffer).len(), HEADER_LEN);
+    }
+
+    #[test]
+    fn ring() {
+        let buffer = PcapBuffer::<NoopRawMutex, 256>::new(Mode::Ring);
+        for i in 0..10u8 {
+            let frame = vec![i; 30 + i as usize];
+            buffer.capture(
+                Instant::from_micros(i as u64),
+                Direction::Rx,
+                LinkType::Ethernet,
+                &frame,
+            );
+        }
+        let out = dump(&buffer);
+        let packets = packets(&out, LinkType::Ethernet);
+        // Only the most recent frames are kept.
+        assert_eq!(packets, [&[7; 37][..], &[8; 38], &[9; 39]]);
+        assert_eq!(buffer.dropped(), 7);
+
+        // An oversized frame is truncated and replaces everything.
+        buffer.capture(Instant::from_ticks(0), Direction::Tx, LinkType::Ethernet, &[0; 1000]);
+        assert_eq!(dump(&buffer).len(), HEADER_LEN + 256);
+        assert_eq!(dump(&buffer).len(), HEADER_LEN);
+    }
+
+    #[test]
+    fn link_type_mismatch() {
+        let buffer = PcapBuffer::<NoopRawMutex, 256>::new(Mode::Ring);
+        assert!(dump(&buffer).is_empty());
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ip, &[0x45; 20]);
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ethernet, &[0; 60]);
+        assert_eq!(buffer.dropped(), 1);
+        assert_eq!(packets(&dump(&buffer), LinkType::Ip), [[0x45; 20]]);
+    }
+
+    #[test]
+    fn stream() {
+        let buffer = PcapBuffer::<NoopRawMutex, 256>::new(Mode::Stream);
+        let mut chunk = [0; 7];
+        // Nothing to read until the link type is known.
+        assert!(poll_once(buffer.read(&mut chunk)).is_pending());
+
+        for i in 0..10u8 {
+            buffer.capture(Instant::from_micros(i as u64), Direction::Rx, LinkType::Ip, &[i; 30]);
+        }
+        // New frames are dropped when full.
+        assert_eq!(buffer.dropped(), 7);
+
+        let mut out = Vec::new();
+        while let Poll::Ready(n) = poll_once(buffer.read(&mut chunk)) {
+            out.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(packets(&out, LinkType::Ip), [[0; 30], [1; 30], [2; 30]]);
+    }
+
+    #[test]
+    fn ring_while_reading() {
+        let buffer = PcapBuffer::<NoopRawMutex, 256>::new(Mode::Ring);
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ip, &[1; 100]);
+        let mut chunk = [0; HEADER_LEN + 10];
+        assert_eq!(block_on(buffer.read(&mut chunk[..HEADER_LEN])), HEADER_LEN);
+        assert_eq!(block_on(buffer.read(&mut chunk[..10])), 10);
+
+        // The packet being read can't be dropped to make room.
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ip, &[2; 100]);
+        assert_eq!(buffer.dropped(), 1);
+
+        // A dump skips the rest of the packet being read.
+        assert_eq!(dump(&buffer).len(), HEADER_LEN);
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ip, &[3; 100]);
+        buffer.capture(Instant::from_ticks(0), Direction::Rx, LinkType::Ip, &[4; 100]);
+        assert_eq!(packets(&dump(&buffer), LinkType::Ip), [[4; 100]]);
+    }
+}
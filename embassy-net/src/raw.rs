@@ -10,7 +10,7 @@ use smoltcp::socket::raw;
 pub use smoltcp::socket::raw::PacketMetadata;
 use smoltcp::wire::{IpProtocol, IpVersion};
 
-use crate::Stack;
+use crate::{BindInterfaceError, InterfaceId, Stack};
 
 /// Error returned by [`RawSocket::recv`] and [`RawSocket::send`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -24,10 +24,14 @@ pub enum RecvError {
 pub struct RawSocket<'a> {
     stack: Stack<'a>,
     handle: SocketHandle,
+    iface: InterfaceId,
 }
 
 impl<'a> RawSocket<'a> {
     /// Create a new Raw socket using the provided stack and buffers.
+    ///
+    /// The socket is attached to the primary interface. Use [`bind_interface`](Self::bind_interface)
+    /// to send and receive through another interface.
     pub fn new<D: Driver>(
         stack: Stack<'a>,
         ip_version: IpVersion,
@@ -37,12 +41,13 @@ impl<'a> RawSocket<'a> {
         tx_meta: &'a mut [PacketMetadata],
         tx_buffer: &'a mut [u8],
     ) -> Self {
+        let iface = InterfaceId::PRIMARY;
         let handle = stack.with_mut(|i| {
             let rx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(rx_meta) };
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(raw::Socket::new(
+            i.iface_mut(iface).sockets.add(raw::Socket::new(
                 ip_version,
                 ip_protocol,
                 raw::PacketBuffer::new(rx_meta, rx_buffer),
@@ -50,11 +55,34 @@ impl<'a> RawSocket<'a> {
             ))
         });
 
-        Self { stack, handle }
+        Self { stack, handle, iface }
+    }
+
+    /// Attach the socket to a network interface.
+    ///
+    /// The socket will only send and receive packets through this interface.
+    pub fn bind_interface(&mut self, interface: InterfaceId) -> Result<(), BindInterfaceError> {
+        match self
+            .stack
+            .with_mut(|i| i.move_socket(self.handle, self.iface, interface))
+        {
+            Some(handle) => {
+                self.handle = handle;
+                self.iface = interface;
+                Ok(())
+            }
+            None => Err(BindInterfaceError::NoFreeSlot),
+        }
+    }
+
+    /// Get the interface the socket is attached to.
+    pub fn interface(&self) -> InterfaceId {
+        self.iface
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut raw::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
+            let i = i.iface_mut(self.iface);
             let socket = i.sockets.get_mut::<raw::Socket>(self.handle);
             let res = f(socket, &mut i.iface);
             i.waker.wake();
@@ -112,7 +140,8 @@ impl<'a> RawSocket<'a> {
 
 impl Drop for RawSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack
+            .with_mut(|i| i.iface_mut(self.iface).sockets.remove(self.handle));
     }
 }
 
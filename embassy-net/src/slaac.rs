@@ -0,0 +1,1153 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862), with optional stateless DHCPv6 (RFC 8415).
+//!
+//! smoltcp doesn't process router advertisements, so this listens to ICMPv6 with a raw socket:
+//! it forms a link-local address, solicits routers, and forms a global address from the first
+//! autonomous /64 prefix advertised. Routers and RDNSS options (RFC 8106) give the default gateway
+//! and the DNS servers. When routers set the "other configuration" flag, the DNS servers are also
+//! requested with a DHCPv6 Information-Request.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::raw;
+#[cfg(feature = "dhcpv6")]
+use smoltcp::socket::udp;
+use smoltcp::wire::{
+    HardwareAddress, Icmpv6Packet, IpProtocol, IpVersion, Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr,
+    IPV6_LINK_LOCAL_ALL_ROUTERS,
+};
+
+use crate::{SlaacConfig, StaticConfigV6};
+
+const ROUTER_SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+const MAX_ROUTER_SOLICITATION_INTERVAL: Duration = Duration::from_secs(3600);
+/// Number of solicitations sent at the regular interval, before backing off.
+const MAX_ROUTER_SOLICITATIONS: u32 = 3;
+/// How long to wait for a neighbor advertisement when checking that an address is unique.
+const DAD_TIMEOUT: Duration = Duration::from_secs(1);
+const TWO_HOURS: Duration = Duration::from_secs(2 * 3600);
+
+const ICMPV6_ROUTER_SOLICIT: u8 = 133;
+const ICMPV6_ROUTER_ADVERT: u8 = 134;
+const ICMPV6_NEIGHBOR_SOLICIT: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERT: u8 = 136;
+
+const NDISC_OPTION_PREFIX_INFO: u8 = 3;
+const NDISC_OPTION_RDNSS: u8 = 25;
+
+const RA_FLAG_MANAGED: u8 = 0x80;
+const RA_FLAG_OTHER: u8 = 0x40;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Socket buffers used by SLAAC, for one interface.
+pub(crate) struct Resources {
+    rx_meta: [raw::PacketMetadata; 2],
+    // Large enough for a router advertisement filling the minimum IPv6 MTU.
+    rx: [u8; 1280],
+    tx_meta: [raw::PacketMetadata; 2],
+    tx: [u8; 128],
+    #[cfg(feature = "dhcpv6")]
+    dhcpv6: dhcpv6::Resources,
+}
+
+impl Resources {
+    pub const fn new() -> Self {
+        Self {
+            rx_meta: [raw::PacketMetadata::EMPTY; 2],
+            rx: [0; 1280],
+            tx_meta: [raw::PacketMetadata::EMPTY; 2],
+            tx: [0; 128],
+            #[cfg(feature = "dhcpv6")]
+            dhcpv6: dhcpv6::Resources::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dad {
+    /// A neighbor solicitation must be sent.
+    Pending,
+    /// Waiting for a neighbor advertisement until the deadline.
+    Probing(Instant),
+    /// No other node uses the address.
+    Done,
+    /// Another node uses the address.
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Address {
+    cidr: Ipv6Cidr,
+    valid_until: Instant,
+    dad: Dad,
+}
+
+impl Address {
+    fn tentative(address: Ipv6Address, valid_until: Instant) -> Self {
+        Self {
+            cidr: Ipv6Cidr::new(address, 64),
+            valid_until,
+            dad: Dad::Pending,
+        }
+    }
+
+    fn assigned(&self) -> Option<Ipv6Cidr> {
+        (self.dad == Dad::Done).then_some(self.cidr)
+    }
+}
+
+/// SLAAC state of an interface.
+pub(crate) struct Slaac {
+    #[allow(unused)]
+    config: SlaacConfig,
+    socket: SocketHandle,
+    interface_id: [u8; 8],
+    link_local: Address,
+    address: Option<Address>,
+    router: Option<(Ipv6Address, Instant)>,
+    dns_servers: Vec<(Ipv6Address, Instant), 3>,
+    /// Number of router solicitations sent, and when to send the next one. `None` once a router answered.
+    solicit: Option<(u32, Instant)>,
+    #[cfg(feature = "dhcpv6")]
+    dhcpv6: Option<dhcpv6::Client>,
+    /// The configuration last returned by `poll`.
+    last: (Option<Ipv6Cidr>, Option<StaticConfigV6>),
+}
+
+impl Slaac {
+    /// Create the sockets used by SLAAC in `sockets`.
+    ///
+    /// # Safety
+    ///
+    /// `resources` must stay valid and unused by anything else until [`Slaac::remove()`] is called.
+    pub unsafe fn new(
+        config: SlaacConfig,
+        hardware_address: HardwareAddress,
+        random_seed: u64,
+        resources: *mut Resources,
+        sockets: &mut SocketSet<'static>,
+    ) -> Self {
+        let resources = &mut *resources;
+        let socket = sockets.add(raw::Socket::new(
+            IpVersion::Ipv6,
+            IpProtocol::Icmpv6,
+            raw::PacketBuffer::new(&mut resources.rx_meta[..], &mut resources.rx[..]),
+            raw::PacketBuffer::new(&mut resources.tx_meta[..], &mut resources.tx[..]),
+        ));
+        let interface_id = interface_id(hardware_address, random_seed);
+        #[cfg(feature = "dhcpv6")]
+        let dhcpv6 = config
+            .dhcpv6
+            .then(|| dhcpv6::Client::new(&mut resources.dhcpv6, sockets, hardware_address, random_seed));
+
+        let mut this = Self {
+            config,
+            socket,
+            interface_id,
+            link_local: Address::tentative(Ipv6Address::UNSPECIFIED, Instant::MAX),
+            address: None,
+            router: None,
+            dns_servers: Vec::new(),
+            solicit: None,
+            #[cfg(feature = "dhcpv6")]
+            dhcpv6,
+            last: (None, None),
+        };
+        this.reset();
+        this
+    }
+
+    /// Remove the sockets used by SLAAC.
+    pub fn remove(self, sockets: &mut SocketSet<'static>) {
+        sockets.remove(self.socket);
+        #[cfg(feature = "dhcpv6")]
+        if let Some(dhcpv6) = self.dhcpv6 {
+            dhcpv6.remove(sockets);
+        }
+    }
+
+    /// Restart autoconfiguration from scratch, e.g. after the link went down.
+    pub fn reset(&mut self) {
+        self.link_local = Address::tentative(self.address_for(0xfe80_0000_0000_0000), Instant::MAX);
+        self.address = None;
+        self.router = None;
+        self.dns_servers.clear();
+        self.solicit = None;
+        #[cfg(feature = "dhcpv6")]
+        if let Some(dhcpv6) = &mut self.dhcpv6 {
+            dhcpv6.reset();
+        }
+        self.last = (None, None);
+    }
+
+    /// The link-local address, once it's known to be unique.
+    pub fn link_local(&self) -> Option<Ipv6Cidr> {
+        self.link_local.assigned()
+    }
+
+    /// The global configuration, once an address was formed from a router advertisement.
+    pub fn config(&self) -> Option<StaticConfigV6> {
+        let address = self.address.as_ref()?.assigned()?;
+        let mut dns_servers = Vec::new();
+        let rdnss = self.dns_servers.iter().map(|(a, _)| *a);
+        #[cfg(feature = "dhcpv6")]
+        let rdnss = rdnss.chain(self.dhcpv6.iter().flat_map(|d| d.dns_servers().iter().copied()));
+        for server in rdnss {
+            if !dns_servers.contains(&server) && dns_servers.push(server).is_err() {
+                break;
+            }
+        }
+        Some(StaticConfigV6 {
+            address,
+            gateway: self.router.map(|(r, _)| r),
+            dns_servers,
+        })
+    }
+
+    /// Process received packets and timers, and queue packets to send.
+    ///
+    /// Returns whether [`link_local()`](Self::link_local) or [`config()`](Self::config) changed.
+    pub fn poll(&mut self, sockets: &mut SocketSet<'static>) -> bool {
+        let now = Instant::now();
+
+        let socket = sockets.get_mut::<raw::Socket>(self.socket);
+        while let Ok(packet) = socket.recv() {
+            self.process(packet, now);
+        }
+
+        self.expire(now);
+
+        // Duplicate address detection.
+        for address in [Some(&mut self.link_local), self.address.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            match address.dad {
+                Dad::Pending if send_neighbor_solicit(socket, address.cidr.address()) => {
+                    address.dad = Dad::Probing(now + DAD_TIMEOUT);
+                }
+                Dad::Probing(deadline) if now >= deadline => {
+                    debug!("IPv6: address {:?} is unique", address.cidr.address());
+                    address.dad = Dad::Done;
+                }
+                _ => {}
+            }
+        }
+
+        // Router solicitation, once the link-local address is settled.
+        let link_local = match self.link_local.dad {
+            Dad::Done => Some(self.link_local.cidr.address()),
+            Dad::Duplicate => None,
+            Dad::Pending | Dad::Probing(_) => return self.changed(),
+        };
+        if let Some((count, at)) = self.solicit {
+            if now >= at && send_router_solicit(socket, link_local) {
+                let interval = if count + 1 < MAX_ROUTER_SOLICITATIONS {
+                    ROUTER_SOLICITATION_INTERVAL
+                } else {
+                    let backoff =
+                        ROUTER_SOLICITATION_INTERVAL * 2u32.pow((count + 2 - MAX_ROUTER_SOLICITATIONS).min(10));
+                    backoff.min(MAX_ROUTER_SOLICITATION_INTERVAL)
+                };
+                self.solicit = Some((count + 1, now + interval));
+            }
+        }
+
+        #[cfg(feature = "dhcpv6")]
+        if let Some(dhcpv6) = &mut self.dhcpv6 {
+            dhcpv6.poll(sockets, now);
+        }
+
+        self.changed()
+    }
+
+    /// When `poll` must be called next, regardless of received packets.
+    pub fn poll_at(&self) -> Option<Instant> {
+        let mut at = Instant::MAX;
+        for address in [Some(&self.link_local), self.address.as_ref()].into_iter().flatten() {
+            match address.dad {
+                Dad::Pending => at = Instant::MIN,
+                Dad::Probing(deadline) => at = at.min(deadline),
+                _ => {}
+            }
+            at = at.min(address.valid_until);
+        }
+        if let Some((_, solicit_at)) = self.solicit {
+            at = at.min(solicit_at);
+        }
+        if let Some((_, until)) = self.router {
+            at = at.min(until);
+        }
+        for (_, until) in &self.dns_servers {
+            at = at.min(*until);
+        }
+        #[cfg(feature = "dhcpv6")]
+        if let Some(dhcp_at) = self.dhcpv6.as_ref().and_then(|d| d.poll_at()) {
+            at = at.min(dhcp_at);
+        }
+        (at != Instant::MAX).then_some(at)
+    }
+
+    fn changed(&mut self) -> bool {
+        let current = (self.link_local(), self.config());
+        let changed = current != self.last;
+        self.last = current;
+        changed
+    }
+
+    fn address_for(&self, prefix: u64) -> Ipv6Address {
+        let mut octets = [0; 16];
+        octets[..8].copy_from_slice(&prefix.to_be_bytes());
+        octets[8..].copy_from_slice(&self.interface_id);
+        Ipv6Address::from(octets)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if self.address.is_some_and(|a| now >= a.valid_until) {
+            info!("IPv6: address {:?} expired", self.address.map(|a| a.cidr));
+            self.address = None;
+        }
+        if self.router.is_some_and(|(_, until)| now >= until) {
+            info!("IPv6: router {:?} expired", self.router.map(|(r, _)| r));
+            self.router = None;
+        }
+        self.dns_servers.retain(|(_, until)| now < *until);
+        if self.router.is_none() && self.address.is_none() && self.solicit.is_none() {
+            self.solicit = Some((0, now));
+        }
+    }
+
+    fn process(&mut self, packet: &[u8], now: Instant) {
+        let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+            return;
+        };
+        if ip.next_header() != IpProtocol::Icmpv6 || ip.hop_limit() != 255 {
+            return;
+        }
+        let (src, dst) = (ip.src_addr(), ip.dst_addr());
+        let Ok(icmp) = Icmpv6Packet::new_checked(ip.payload()) else {
+            return;
+        };
+        if !icmp.verify_checksum(&src, &dst) || icmp.msg_code() != 0 {
+            return;
+        }
+        let data = icmp.into_inner();
+        match data[0] {
+            ICMPV6_ROUTER_ADVERT if is_link_local(&src) && data.len() >= 16 => {
+                self.process_router_advert(src, data, now)
+            }
+            ICMPV6_NEIGHBOR_ADVERT if data.len() >= 24 => {
+                let target = Ipv6Address::from(unwrap!(<[u8; 16]>::try_from(&data[8..24])));
+                for address in [Some(&mut self.link_local), self.address.as_mut()]
+                    .into_iter()
+                    .flatten()
+                {
+                    if address.cidr.address() == target && matches!(address.dad, Dad::Pending | Dad::Probing(_)) {
+                        warn!("IPv6: address {:?} is already used by another node", target);
+                        address.dad = Dad::Duplicate;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn process_router_advert(&mut self, router: Ipv6Address, data: &[u8], now: Instant) {
+        let flags = data[5];
+        let router_lifetime = u16::from_be_bytes([data[6], data[7]]);
+        trace!("IPv6: router advertisement from {:?}", router);
+
+        self.solicit = None;
+        if router_lifetime != 0 {
+            self.router = Some((router, now + Duration::from_secs(router_lifetime as u64)));
+        } else if self.router.is_some_and(|(r, _)| r == router) {
+            self.router = None;
+        }
+
+        for (kind, option) in options(&data[16..]) {
+            match kind {
+                NDISC_OPTION_PREFIX_INFO if option.len() == 30 => {
+                    let prefix_len = option[0];
+                    let autonomous = option[1] & PREFIX_FLAG_AUTONOMOUS != 0;
+                    let valid = u32::from_be_bytes(unwrap!(option[2..6].try_into()));
+                    let preferred = u32::from_be_bytes(unwrap!(option[6..10].try_into()));
+                    let prefix = u64::from_be_bytes(unwrap!(option[14..22].try_into()));
+                    if !autonomous || prefix_len != 64 || preferred > valid || prefix >> 54 == 0x3fa {
+                        continue;
+                    }
+                    let address = self.address_for(prefix);
+                    match &mut self.address {
+                        Some(current) if current.cidr.address() == address => {
+                            current.valid_until = update_valid_until(current.valid_until, valid, now);
+                        }
+                        Some(_) => debug!("IPv6: ignoring prefix {:?}, an address is already configured", address),
+                        None if valid != 0 => {
+                            debug!("IPv6: forming address {:?}", address);
+                            self.address = Some(Address::tentative(address, lifetime_end(valid, now)));
+                        }
+                        None => {}
+                    }
+                }
+                NDISC_OPTION_RDNSS if option.len() >= 22 => {
+                    let lifetime = u32::from_be_bytes(unwrap!(option[2..6].try_into()));
+                    for server in option[6..].chunks_exact(16) {
+                        let server = Ipv6Address::from(unwrap!(<[u8; 16]>::try_from(server)));
+                        self.dns_servers.retain(|(s, _)| *s != server);
+                        if lifetime != 0 && self.dns_servers.push((server, lifetime_end(lifetime, now))).is_err() {
+                            debug!("IPv6: ignoring DNS server {:?}, too many servers", server);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if flags & RA_FLAG_MANAGED != 0 {
+            debug!("IPv6: router advertises stateful DHCPv6, which is not supported");
+        }
+        #[cfg(feature = "dhcpv6")]
+        if flags & (RA_FLAG_MANAGED | RA_FLAG_OTHER) != 0 {
+            if let Some(dhcpv6) = &mut self.dhcpv6 {
+                dhcpv6.start(now);
+            }
+        }
+        #[cfg(not(feature = "dhcpv6"))]
+        let _ = RA_FLAG_OTHER;
+    }
+}
+
+/// Compute the interface identifier, in modified EUI-64 format.
+fn interface_id(hardware_address: HardwareAddress, random_seed: u64) -> [u8; 8] {
+    #[allow(unused_mut)]
+    let mut id = match hardware_address {
+        #[cfg(feature = "medium-ethernet")]
+        HardwareAddress::Ethernet(mac) => {
+            let m = mac.0;
+            [m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]]
+        }
+        #[cfg(feature = "medium-ieee802154")]
+        HardwareAddress::Ieee802154(smoltcp::wire::Ieee802154Address::Extended(a)) => {
+            [a[0] ^ 0x02, a[1], a[2], a[3], a[4], a[5], a[6], a[7]]
+        }
+        #[cfg(feature = "medium-ieee802154")]
+        HardwareAddress::Ieee802154(smoltcp::wire::Ieee802154Address::Short(a)) => [0, 0, 0, 0xff, 0xfe, 0, a[0], a[1]],
+        // No link-layer address to derive it from, pick a random one.
+        #[allow(unreachable_patterns)]
+        _ => {
+            let mut id = mix(random_seed).to_be_bytes();
+            // Clear the universal/local bit, to mark the identifier as local.
+            id[0] &= !0x02;
+            id
+        }
+    };
+    id
+}
+
+/// Scramble the bits of a seed (the splitmix64 finalizer), so similar seeds give unrelated values.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn is_link_local(address: &Ipv6Address) -> bool {
+    address.octets()[..8] == [0xfe, 0x80, 0, 0, 0, 0, 0, 0]
+}
+
+/// Iterate over the options of a neighbor discovery message, as `(type, data)` pairs.
+fn options(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        let len = *data.get(1)? as usize * 8;
+        if len == 0 || len > data.len() {
+            return None;
+        }
+        let (option, rest) = data.split_at(len);
+        data = rest;
+        Some((option[0], &option[2..]))
+    })
+}
+
+fn lifetime_end(seconds: u32, now: Instant) -> Instant {
+    if seconds == u32::MAX {
+        Instant::MAX
+    } else {
+        now + Duration::from_secs(seconds as u64)
+    }
+}
+
+/// Update the valid lifetime of an address, following the rule of RFC 4862 section 5.5.3 e)
+/// that prevents advertisements from shortening it below two hours.
+fn update_valid_until(current: Instant, valid: u32, now: Instant) -> Instant {
+    let received = lifetime_end(valid, now);
+    let remaining = current.saturating_duration_since(now);
+    if received > now + TWO_HOURS || received > current {
+        received
+    } else if remaining <= TWO_HOURS {
+        current
+    } else {
+        now + TWO_HOURS
+    }
+}
+
+/// Queue an ICMPv6 neighbor discovery message, with no options, from `src` to `dst`.
+fn send_ndisc(
+    socket: &mut raw::Socket,
+    src: Ipv6Address,
+    dst: Ipv6Address,
+    kind: u8,
+    target: Option<Ipv6Address>,
+) -> bool {
+    let icmp_len = if target.is_some() { 24 } else { 8 };
+    let repr = Ipv6Repr {
+        src_addr: src,
+        dst_addr: dst,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp_len,
+        hop_limit: 255,
+    };
+    let Ok(buf) = socket.send(repr.buffer_len() + icmp_len) else {
+        return false;
+    };
+    buf.fill(0);
+    repr.emit(&mut Ipv6Packet::new_unchecked(&mut *buf));
+    let icmp = &mut buf[repr.buffer_len()..];
+    icmp[0] = kind;
+    if let Some(target) = target {
+        icmp[8..24].copy_from_slice(&target.octets());
+    }
+    Icmpv6Packet::new_unchecked(icmp).fill_checksum(&src, &dst);
+    true
+}
+
+fn send_router_solicit(socket: &mut raw::Socket, src: Option<Ipv6Address>) -> bool {
+    let src = src.unwrap_or(Ipv6Address::UNSPECIFIED);
+    trace!("IPv6: sending router solicitation");
+    send_ndisc(socket, src, IPV6_LINK_LOCAL_ALL_ROUTERS, ICMPV6_ROUTER_SOLICIT, None)
+}
+
+fn send_neighbor_solicit(socket: &mut raw::Socket, target: Ipv6Address) -> bool {
+    let t = target.octets();
+    let solicited_node = Ipv6Address::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | t[13] as u16,
+        u16::from_be_bytes([t[14], t[15]]),
+    );
+    trace!("IPv6: checking that {:?} is unique", target);
+    send_ndisc(
+        socket,
+        Ipv6Address::UNSPECIFIED,
+        solicited_node,
+        ICMPV6_NEIGHBOR_SOLICIT,
+        Some(target),
+    )
+}
+
+#[cfg(feature = "dhcpv6")]
+mod dhcpv6 {
+    //! Stateless DHCPv6 client, used to get the DNS servers.
+
+    use smoltcp::wire::IpEndpoint;
+
+    use super::*;
+
+    const CLIENT_PORT: u16 = 546;
+    const SERVER_PORT: u16 = 547;
+    const ALL_SERVERS: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+
+    const MSG_REPLY: u8 = 7;
+    const MSG_INFORMATION_REQUEST: u8 = 11;
+
+    const OPTION_CLIENT_ID: u16 = 1;
+    const OPTION_ORO: u16 = 6;
+    const OPTION_ELAPSED_TIME: u16 = 8;
+    const OPTION_DNS_SERVERS: u16 = 23;
+    const OPTION_INFORMATION_REFRESH_TIME: u16 = 32;
+
+    const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+    const MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+    const DEFAULT_REFRESH: Duration = Duration::from_secs(86400);
+    const MIN_REFRESH: Duration = Duration::from_secs(600);
+
+    pub(crate) struct Resources {
+        rx_meta: [udp::PacketMetadata; 2],
+        rx: [u8; 512],
+        tx_meta: [udp::PacketMetadata; 1],
+        tx: [u8; 64],
+    }
+
+    impl Resources {
+        pub const fn new() -> Self {
+            Self {
+                rx_meta: [udp::PacketMetadata::EMPTY; 2],
+                rx: [0; 512],
+                tx_meta: [udp::PacketMetadata::EMPTY; 1],
+                tx: [0; 64],
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum State {
+        Idle,
+        /// Sending Information-Requests, started at the given time.
+        Requesting {
+            started: Instant,
+            next: Instant,
+            timeout: Duration,
+        },
+        /// Got a reply, refresh at the given time.
+        Done {
+            refresh: Instant,
+        },
+    }
+
+    pub(crate) struct Client {
+        socket: SocketHandle,
+        /// DHCP unique identifier, DUID-LL.
+        duid: Vec<u8, 12>,
+        rng: u64,
+        transaction_id: [u8; 3],
+        state: State,
+        dns_servers: Vec<Ipv6Address, 3>,
+    }
+
+    impl Client {
+        pub fn new(
+            resources: &'static mut Resources,
+            sockets: &mut SocketSet<'static>,
+            hardware_address: HardwareAddress,
+            random_seed: u64,
+        ) -> Self {
+            let mut socket = udp::Socket::new(
+                udp::PacketBuffer::new(&mut resources.rx_meta[..], &mut resources.rx[..]),
+                udp::PacketBuffer::new(&mut resources.tx_meta[..], &mut resources.tx[..]),
+            );
+            unwrap!(socket.bind(CLIENT_PORT));
+
+            let mut duid = Vec::new();
+            unwrap!(duid.extend_from_slice(&[0, 3]));
+            match hardware_address {
+                #[cfg(feature = "medium-ethernet")]
+                HardwareAddress::Ethernet(mac) => {
+                    unwrap!(duid.extend_from_slice(&1u16.to_be_bytes()));
+                    unwrap!(duid.extend_from_slice(&mac.0));
+                }
+                #[allow(unreachable_patterns)]
+                _ => {
+                    // EUI-64 hardware type.
+                    unwrap!(duid.extend_from_slice(&27u16.to_be_bytes()));
+                    unwrap!(duid.extend_from_slice(&interface_id(hardware_address, random_seed)));
+                }
+            }
+
+            Self {
+                socket: sockets.add(socket),
+                duid,
+                rng: mix(!random_seed) | 1,
+                transaction_id: [0; 3],
+                state: State::Idle,
+                dns_servers: Vec::new(),
+            }
+        }
+
+        pub fn remove(self, sockets: &mut SocketSet<'static>) {
+            sockets.remove(self.socket);
+        }
+
+        pub fn reset(&mut self) {
+            self.state = State::Idle;
+            self.dns_servers.clear();
+        }
+
+        pub fn dns_servers(&self) -> &[Ipv6Address] {
+            &self.dns_servers
+        }
+
+        /// Start requesting information, if not done already.
+        pub fn start(&mut self, now: Instant) {
+            if self.state == State::Idle {
+                self.request(now);
+            }
+        }
+
+        pub fn poll_at(&self) -> Option<Instant> {
+            match self.state {
+                State::Idle => None,
+                State::Requesting { next, .. } => Some(next),
+                State::Done { refresh } => Some(refresh),
+            }
+        }
+
+        pub fn poll(&mut self, sockets: &mut SocketSet<'static>, now: Instant) {
+            let socket = sockets.get_mut::<udp::Socket>(self.socket);
+            while let Ok((data, _)) = socket.recv() {
+                if let State::Requesting { .. } = self.state {
+                    if let Some((dns_servers, refresh)) = self.parse_reply(data) {
+                        debug!("DHCPv6: DNS servers {:?}", dns_servers);
+                        self.dns_servers = dns_servers;
+                        self.state = State::Done { refresh: now + refresh };
+                    }
+                }
+            }
+
+            match self.state {
+                State::Requesting { started, next, timeout } if now >= next && self.send(socket, now - started) => {
+                    let timeout = (timeout * 2).min(MAX_TIMEOUT);
+                    self.state = State::Requesting {
+                        started,
+                        next: now + timeout,
+                        timeout,
+                    };
+                }
+                State::Done { refresh } if now >= refresh => self.request(now),
+                _ => {}
+            }
+        }
+
+        fn request(&mut self, now: Instant) {
+            // xorshift64
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            self.transaction_id.copy_from_slice(&self.rng.to_be_bytes()[..3]);
+            self.state = State::Requesting {
+                started: now,
+                next: now,
+                timeout: INITIAL_TIMEOUT / 2,
+            };
+        }
+
+        fn send(&self, socket: &mut udp::Socket, elapsed: Duration) -> bool {
+            let len = 4 + 4 + self.duid.len() + 4 + 4 + 4 + 2;
+            let Ok(buf) = socket.send(len, IpEndpoint::new(ALL_SERVERS.into(), SERVER_PORT)) else {
+                return false;
+            };
+            buf[0] = MSG_INFORMATION_REQUEST;
+            buf[1..4].copy_from_slice(&self.transaction_id);
+            let mut pos = 4;
+            let mut option = |code: u16, data: &[&[u8]]| {
+                let len: usize = data.iter().map(|d| d.len()).sum();
+                buf[pos..pos + 2].copy_from_slice(&code.to_be_bytes());
+                buf[pos + 2..pos + 4].copy_from_slice(&(len as u16).to_be_bytes());
+                pos += 4;
+                for d in data {
+                    buf[pos..pos + d.len()].copy_from_slice(d);
+                    pos += d.len();
+                }
+            };
+            option(OPTION_CLIENT_ID, &[&self.duid]);
+            option(
+                OPTION_ORO,
+                &[
+                    &OPTION_DNS_SERVERS.to_be_bytes(),
+                    &OPTION_INFORMATION_REFRESH_TIME.to_be_bytes(),
+                ],
+            );
+            let centiseconds = (elapsed.as_millis() / 10).min(0xffff) as u16;
+            option(OPTION_ELAPSED_TIME, &[&centiseconds.to_be_bytes()]);
+            trace!("DHCPv6: sending Information-Request");
+            true
+        }
+
+        fn parse_reply(&self, data: &[u8]) -> Option<(Vec<Ipv6Address, 3>, Duration)> {
+            if data.len() < 4 || data[0] != MSG_REPLY || data[1..4] != self.transaction_id {
+                return None;
+            }
+            let mut dns_servers = Vec::new();
+            let mut refresh = DEFAULT_REFRESH;
+            let mut options = &data[4..];
+            while options.len() >= 4 {
+                let code = u16::from_be_bytes([options[0], options[1]]);
+                let len = u16::from_be_bytes([options[2], options[3]]) as usize;
+                let value = options.get(4..4 + len)?;
+                match code {
+                    OPTION_DNS_SERVERS => {
+                        for server in value.chunks_exact(16) {
+                            let server = Ipv6Address::from(unwrap!(<[u8; 16]>::try_from(server)));
+                            if dns_servers.push(server).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    OPTION_INFORMATION_REFRESH_TIME if len == 4 => {
+                        let seconds = u32::from_be_bytes(unwrap!(value.try_into()));
+                        refresh = Duration::from_secs(seconds as u64).max(MIN_REFRESH);
+                    }
+                    _ => {}
+                }
+                options = &options[4 + len..];
+            }
+            Some((dns_servers, refresh))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        extern crate std;
+
+        use std::boxed::Box;
+        use std::vec::Vec as StdVec;
+
+        use smoltcp::iface::SocketStorage;
+        use smoltcp::wire::EthernetAddress;
+
+        use super::*;
+
+        const SERVERS: [Ipv6Address; 4] = [
+            Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53),
+            Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x54),
+            Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x55),
+            Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x56),
+        ];
+
+        fn client() -> Client {
+            let storage = Box::leak(Box::new([SocketStorage::EMPTY; 1]));
+            let mut sockets = SocketSet::new(&mut storage[..]);
+            let resources = Box::leak(Box::new(Resources::new()));
+            let hardware_address = HardwareAddress::Ethernet(EthernetAddress([2, 0, 0, 0, 0, 1]));
+            let mut client = Client::new(resources, &mut sockets, hardware_address, 1);
+            client.start(Instant::from_secs(0));
+            client
+        }
+
+        fn reply(client: &Client, options: &[(u16, &[u8])]) -> StdVec<u8> {
+            let mut data = StdVec::from([MSG_REPLY]);
+            data.extend_from_slice(&client.transaction_id);
+            for (code, value) in options {
+                data.extend_from_slice(&code.to_be_bytes());
+                data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                data.extend_from_slice(value);
+            }
+            data
+        }
+
+        #[test]
+        fn parse_reply() {
+            let client = client();
+            assert_eq!(&client.duid[..], [0, 3, 0, 1, 2, 0, 0, 0, 0, 1]);
+
+            let servers: StdVec<u8> = SERVERS.iter().flat_map(|s| s.octets()).collect();
+            // At most 3 servers are kept.
+            let data = reply(&client, &[(OPTION_DNS_SERVERS, &servers)]);
+            let (dns_servers, refresh) = client.parse_reply(&data).unwrap();
+            assert_eq!(dns_servers, SERVERS[..3]);
+            assert_eq!(refresh, DEFAULT_REFRESH);
+
+            // Refresh times are bounded, and unknown options skipped.
+            let data = reply(
+                &client,
+                &[
+                    (99, &[1, 2, 3]),
+                    (OPTION_INFORMATION_REFRESH_TIME, &60u32.to_be_bytes()),
+                ],
+            );
+            assert_eq!(client.parse_reply(&data).unwrap(), (Vec::new(), MIN_REFRESH));
+            let data = reply(&client, &[(OPTION_INFORMATION_REFRESH_TIME, &[0, 0, 0x10])]);
+            assert_eq!(client.parse_reply(&data).unwrap().1, DEFAULT_REFRESH);
+            // Partial addresses are ignored.
+            let data = reply(&client, &[(OPTION_DNS_SERVERS, &servers[..20])]);
+            assert_eq!(client.parse_reply(&data).unwrap().0, SERVERS[..1]);
+        }
+
+        #[test]
+        fn invalid_replies() {
+            let client = client();
+            let mut data = reply(&client, &[(OPTION_DNS_SERVERS, &SERVERS[0].octets())]);
+            assert!(client.parse_reply(&data[..3]).is_none());
+            // Options going past the end of the message.
+            assert!(client.parse_reply(&data[..data.len() - 1]).is_none());
+            data[4..6].copy_from_slice(&[0xff, 0xff]);
+            data[6..8].copy_from_slice(&[0xff, 0xff]);
+            assert!(client.parse_reply(&data).is_none());
+
+            // Other messages, or other transactions.
+            let mut data = reply(&client, &[]);
+            data[0] = MSG_INFORMATION_REQUEST;
+            assert!(client.parse_reply(&data).is_none());
+            let mut data = reply(&client, &[]);
+            data[3] ^= 0xff;
+            assert!(client.parse_reply(&data).is_none());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "medium-ethernet"))]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec as StdVec;
+
+    use smoltcp::iface::SocketStorage;
+    use smoltcp::wire::{EthernetAddress, IPV6_LINK_LOCAL_ALL_NODES};
+
+    use super::*;
+
+    const ROUTER: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const PREFIX: u64 = 0x2001_0db8_0000_0000;
+    const ADDRESS: Ipv6Address = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0xff, 0xfe00, 1);
+    const DNS: Ipv6Address = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53);
+    const NOW: Instant = Instant::from_secs(1000);
+
+    fn slaac() -> Slaac {
+        let storage = Box::leak(Box::new([SocketStorage::EMPTY; 2]));
+        let mut sockets = SocketSet::new(&mut storage[..]);
+        let resources = Box::leak(Box::new(Resources::new()));
+        let hardware_address = HardwareAddress::Ethernet(EthernetAddress([2, 0, 0, 0, 0, 1]));
+        unsafe { Slaac::new(SlaacConfig::default(), hardware_address, 1, resources, &mut sockets) }
+    }
+
+    /// An IPv6 packet carrying an ICMPv6 message, with a valid checksum.
+    fn packet(src: Ipv6Address, hop_limit: u8, icmp: &[u8]) -> StdVec<u8> {
+        let repr = Ipv6Repr {
+            src_addr: src,
+            dst_addr: IPV6_LINK_LOCAL_ALL_NODES,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.len(),
+            hop_limit,
+        };
+        let mut data = vec![0; repr.buffer_len() + icmp.len()];
+        repr.emit(&mut Ipv6Packet::new_unchecked(&mut data[..]));
+        data[repr.buffer_len()..].copy_from_slice(icmp);
+        Icmpv6Packet::new_unchecked(&mut data[repr.buffer_len()..]).fill_checksum(&src, &IPV6_LINK_LOCAL_ALL_NODES);
+        data
+    }
+
+    fn router_advert(flags: u8, router_lifetime: u16, options: &[&[u8]]) -> StdVec<u8> {
+        let mut icmp = vec![ICMPV6_ROUTER_ADVERT, 0, 0, 0, 64, flags];
+        icmp.extend_from_slice(&router_lifetime.to_be_bytes());
+        icmp.extend_from_slice(&[0; 8]);
+        for option in options {
+            icmp.extend_from_slice(option);
+        }
+        packet(ROUTER, 255, &icmp)
+    }
+
+    fn prefix_info(prefix: u64, prefix_len: u8, flags: u8, valid: u32, preferred: u32) -> StdVec<u8> {
+        let mut option = vec![NDISC_OPTION_PREFIX_INFO, 4, prefix_len, flags];
+        option.extend_from_slice(&valid.to_be_bytes());
+        option.extend_from_slice(&preferred.to_be_bytes());
+        option.extend_from_slice(&[0; 4]);
+        option.extend_from_slice(&prefix.to_be_bytes());
+        option.extend_from_slice(&[0; 8]);
+        option
+    }
+
+    fn rdnss(lifetime: u32, servers: &[Ipv6Address]) -> StdVec<u8> {
+        let mut option = vec![NDISC_OPTION_RDNSS, 1 + 2 * servers.len() as u8, 0, 0];
+        option.extend_from_slice(&lifetime.to_be_bytes());
+        for server in servers {
+            option.extend_from_slice(&server.octets());
+        }
+        option
+    }
+
+    fn neighbor_advert(target: Ipv6Address) -> StdVec<u8> {
+        let mut icmp = vec![ICMPV6_NEIGHBOR_ADVERT, 0, 0, 0, 0x20, 0, 0, 0];
+        icmp.extend_from_slice(&target.octets());
+        packet(target, 255, &icmp)
+    }
+
+    /// Finish duplicate address detection of the global address.
+    fn assign(slaac: &mut Slaac) {
+        slaac.address.as_mut().unwrap().dad = Dad::Done;
+    }
+
+    #[test]
+    fn router_advertisement() {
+        let mut slaac = slaac();
+        let prefix = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        let ra = router_advert(0, 1800, &[&prefix, &rdnss(600, &[DNS])]);
+        slaac.process(&ra, NOW);
+        // The address is only used once it's known to be unique.
+        assert_eq!(slaac.address.unwrap().dad, Dad::Pending);
+        assert_eq!(slaac.config(), None);
+        assign(&mut slaac);
+
+        let config = slaac.config().unwrap();
+        assert_eq!(config.address, Ipv6Cidr::new(ADDRESS, 64));
+        assert_eq!(config.gateway, Some(ROUTER));
+        assert_eq!(&config.dns_servers[..], [DNS]);
+        assert_eq!(slaac.router, Some((ROUTER, NOW + Duration::from_secs(1800))));
+        assert_eq!(slaac.solicit, None);
+
+        // Everything expires.
+        slaac.expire(NOW + Duration::from_secs(600));
+        assert!(slaac.dns_servers.is_empty());
+        slaac.expire(NOW + Duration::from_secs(1800));
+        assert_eq!(slaac.router, None);
+        slaac.expire(NOW + Duration::from_secs(3600));
+        assert_eq!(slaac.address, None);
+        assert!(slaac.solicit.is_some());
+    }
+
+    #[test]
+    fn lifetimes_zero() {
+        let mut slaac = slaac();
+        let prefix = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 0, 0);
+        slaac.process(&router_advert(0, 1800, &[&prefix, &rdnss(600, &[DNS])]), NOW);
+        // No address is formed from a prefix that's already invalid.
+        assert_eq!(slaac.address, None);
+
+        // A router lifetime of 0 means it's no longer a default router, an RDNSS lifetime of 0
+        // removes the servers.
+        slaac.process(&router_advert(0, 0, &[&rdnss(0, &[DNS])]), NOW);
+        assert_eq!(slaac.router, None);
+        assert!(slaac.dns_servers.is_empty());
+    }
+
+    #[test]
+    fn dns_servers() {
+        let mut slaac = slaac();
+        let servers: StdVec<_> = (1..=5)
+            .map(|i| Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i))
+            .collect();
+        slaac.process(&router_advert(0, 1800, &[&rdnss(600, &servers)]), NOW);
+        let kept: StdVec<_> = slaac.dns_servers.iter().map(|(s, _)| *s).collect();
+        assert_eq!(kept, servers[..3]);
+
+        // Servers advertised again are refreshed, not duplicated.
+        slaac.process(&router_advert(0, 1800, &[&rdnss(1200, &servers[..1])]), NOW);
+        assert_eq!(slaac.dns_servers.len(), 3);
+        assert!(slaac
+            .dns_servers
+            .contains(&(servers[0], NOW + Duration::from_secs(1200))));
+    }
+
+    #[test]
+    fn ignored_prefixes() {
+        let mut slaac = slaac();
+        let prefixes = [
+            // Not autonomous, not a /64, preferred lifetime above the valid lifetime, link-local.
+            prefix_info(PREFIX, 64, 0, 3600, 1800),
+            prefix_info(PREFIX, 48, PREFIX_FLAG_AUTONOMOUS, 3600, 1800),
+            prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 1800, 3600),
+            prefix_info(0xfe80_0000_0000_0000, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800),
+        ];
+        for prefix in &prefixes {
+            slaac.process(&router_advert(0, 1800, &[prefix]), NOW);
+            assert_eq!(slaac.address, None);
+        }
+
+        // Only the first prefix is used.
+        let first = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        let second = prefix_info(PREFIX + 1, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        slaac.process(&router_advert(0, 1800, &[&first, &second]), NOW);
+        assert_eq!(slaac.address.unwrap().cidr.address(), ADDRESS);
+    }
+
+    #[test]
+    fn malformed_advertisements() {
+        let mut slaac = slaac();
+        let prefix = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        let valid = router_advert(0, 1800, &[&prefix]);
+        let icmp = &valid[40..];
+        let mut packets = vec![
+            // Not from a link-local address, or forwarded by a router.
+            packet(Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 255, icmp),
+            packet(ROUTER, 254, icmp),
+            // Truncated message, or IPv6 header.
+            packet(ROUTER, 255, &icmp[..15]),
+            valid[..30].to_vec(),
+            valid[..valid.len() - 1].to_vec(),
+            vec![],
+        ];
+        // Bad checksum, or code.
+        let mut corrupted = valid.clone();
+        corrupted[50] ^= 0xff;
+        packets.push(corrupted);
+        let mut code = icmp.to_vec();
+        code[1] = 1;
+        packets.push(packet(ROUTER, 255, &code));
+
+        for p in &packets {
+            slaac.process(p, NOW);
+            assert_eq!((slaac.address, slaac.router), (None, None));
+        }
+    }
+
+    #[test]
+    fn malformed_options() {
+        let mut slaac = slaac();
+        let prefix = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        // Options with a length of 0, or past the end of the message, end the parsing.
+        slaac.process(&router_advert(0, 1800, &[&[25, 0, 0, 0, 0, 0, 0, 0], &prefix]), NOW);
+        let mut long = prefix.clone();
+        long[1] = 5;
+        slaac.process(&router_advert(0, 1800, &[&long]), NOW);
+        // Options of the wrong size are skipped.
+        let mut short = prefix.clone();
+        short[1] = 3;
+        short.truncate(24);
+        slaac.process(&router_advert(0, 1800, &[&short, &[25, 1, 0, 0, 0, 0, 2, 0x58]]), NOW);
+        assert_eq!(slaac.address, None);
+        assert!(slaac.dns_servers.is_empty());
+        // The router itself is valid.
+        assert!(slaac.router.is_some());
+
+        slaac.process(&router_advert(0, 1800, &[&short, &prefix]), NOW);
+        assert!(slaac.address.is_some());
+    }
+
+    #[test]
+    fn duplicate_address() {
+        let mut slaac = slaac();
+        let link_local = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0xff, 0xfe00, 1);
+        assert_eq!(slaac.link_local.cidr.address(), link_local);
+
+        // A truncated advertisement, or one for another address, is ignored.
+        slaac.process(&packet(link_local, 255, &neighbor_advert(link_local)[40..60]), NOW);
+        slaac.process(&neighbor_advert(ROUTER), NOW);
+        assert_eq!(slaac.link_local.dad, Dad::Pending);
+
+        slaac.process(&neighbor_advert(link_local), NOW);
+        assert_eq!(slaac.link_local.dad, Dad::Duplicate);
+        assert_eq!(slaac.link_local(), None);
+
+        // Assigned addresses aren't given up.
+        let prefix = prefix_info(PREFIX, 64, PREFIX_FLAG_AUTONOMOUS, 3600, 1800);
+        slaac.process(&router_advert(0, 1800, &[&prefix]), NOW);
+        assign(&mut slaac);
+        slaac.process(&neighbor_advert(ADDRESS), NOW);
+        assert!(slaac.config().is_some());
+    }
+
+    #[test]
+    fn valid_lifetime_update() {
+        let hours = |h: u64| Duration::from_secs(h * 3600);
+        let h = |h: u32| h * 3600;
+        // Longer lifetimes, and lifetimes above two hours, are used as is.
+        assert_eq!(update_valid_until(NOW + hours(1), h(3), NOW), NOW + hours(3));
+        assert_eq!(
+            update_valid_until(NOW + hours(1), 5400, NOW),
+            NOW + Duration::from_secs(5400)
+        );
+        assert_eq!(update_valid_until(NOW + hours(5), h(3), NOW), NOW + hours(3));
+        // Advertisements can't shorten the lifetime below two hours.
+        assert_eq!(update_valid_until(NOW + hours(3), h(1), NOW), NOW + hours(2));
+        assert_eq!(update_valid_until(NOW + hours(1), 0, NOW), NOW + hours(1));
+        assert_eq!(update_valid_until(Instant::MAX, 0, NOW), NOW + hours(2));
+        // Infinite lifetime.
+        assert_eq!(update_valid_until(NOW + hours(1), u32::MAX, NOW), Instant::MAX);
+    }
+
+    #[test]
+    fn interface_ids() {
+        let mac = HardwareAddress::Ethernet(EthernetAddress([0x02, 0x11, 0x22, 0x33, 0x44, 0x55]));
+        assert_eq!(interface_id(mac, 1), [0x00, 0x11, 0x22, 0xff, 0xfe, 0x33, 0x44, 0x55]);
+
+        #[cfg(feature = "medium-ip")]
+        {
+            let a = interface_id(HardwareAddress::Ip, 1);
+            assert_eq!(a, interface_id(HardwareAddress::Ip, 1));
+            assert_ne!(a, interface_id(HardwareAddress::Ip, 2));
+            assert_eq!(a[0] & 0x02, 0);
+        }
+    }
+}
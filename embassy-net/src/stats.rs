@@ -0,0 +1,444 @@
+//! Traffic statistics.
+
+#[cfg(feature = "stats")]
+use core::cell::Cell;
+
+#[cfg(feature = "stats")]
+use embassy_net_driver::Checksum;
+use embassy_net_driver::ChecksumCapabilities;
+use smoltcp::phy::Medium;
+#[cfg(all(feature = "stats", feature = "proto-ipv4"))]
+use smoltcp::wire::Icmpv4Packet;
+#[cfg(all(feature = "stats", feature = "proto-ipv6"))]
+use smoltcp::wire::Icmpv6Packet;
+#[cfg(all(any(feature = "stats", feature = "filter"), feature = "proto-ipv4"))]
+use smoltcp::wire::Ipv4Packet;
+#[cfg(all(any(feature = "stats", feature = "filter"), feature = "proto-ipv6"))]
+use smoltcp::wire::Ipv6Packet;
+#[cfg(all(any(feature = "stats", feature = "filter"), feature = "medium-ethernet"))]
+use smoltcp::wire::{EthernetFrame, EthernetProtocol};
+#[cfg(any(feature = "stats", feature = "filter"))]
+use smoltcp::wire::{IpAddress, IpProtocol};
+#[cfg(feature = "stats")]
+use smoltcp::wire::{TcpPacket, UdpPacket};
+
+/// Traffic counters of a network interface.
+///
+/// Counters start at zero when the interface is added, and wrap around on overflow.
+///
+/// There are no per-connection TCP statistics, such as retransmissions or round-trip time
+/// estimates: smoltcp doesn't expose its TCP retransmission state.
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct InterfaceStats {
+    /// Number of frames received from the driver.
+    pub rx_packets: u64,
+    /// Number of bytes received from the driver.
+    pub rx_bytes: u64,
+    /// Number of received frames the driver dropped because it had no free receive buffer,
+    /// usually because the stack isn't polled often enough to keep up.
+    ///
+    /// This is reported by the driver, see `Driver::rx_dropped()`, and stays 0 for drivers that
+    /// don't keep track of dropped frames. Frames dropped by the stack itself, e.g. for a UDP
+    /// socket whose receive buffer is full, aren't counted: smoltcp doesn't report them.
+    pub rx_dropped: u64,
+    /// Number of frames handed to the driver for transmission.
+    pub tx_packets: u64,
+    /// Number of bytes handed to the driver for transmission.
+    pub tx_bytes: u64,
+    /// Number of times the stack had a packet to send but the driver had no free transmit buffer.
+    ///
+    /// The packet stays queued in its socket and is retried on the next poll, but a high count
+    /// means the driver's transmit queue is too small, and eventually leads to drops and TCP
+    /// retransmissions.
+    pub tx_exhausted: u64,
+    /// Number of received packets with an invalid IPv4, TCP, UDP or ICMP checksum.
+    ///
+    /// Only protocols whose checksum is verified in software are counted. Drivers that offload
+    /// checksum verification to the hardware are expected to drop invalid packets themselves.
+    pub rx_checksum_errors: u64,
+    /// Number of received frames dropped by the packet filter, see `Runner::set_filter()`.
+    ///
+    /// These aren't counted in `rx_packets`.
+    pub rx_filtered: u64,
+    /// Number of frames dropped by the packet filter instead of being transmitted.
+    ///
+    /// These aren't counted in `tx_packets`.
+    pub tx_filtered: u64,
+}
+
+#[cfg(feature = "stats")]
+impl InterfaceStats {
+    pub(crate) fn accumulate(&mut self, other: &InterfaceStats) {
+        self.rx_packets = self.rx_packets.wrapping_add(other.rx_packets);
+        self.rx_bytes = self.rx_bytes.wrapping_add(other.rx_bytes);
+        self.rx_dropped = self.rx_dropped.wrapping_add(other.rx_dropped);
+        self.tx_packets = self.tx_packets.wrapping_add(other.tx_packets);
+        self.tx_bytes = self.tx_bytes.wrapping_add(other.tx_bytes);
+        self.tx_exhausted = self.tx_exhausted.wrapping_add(other.tx_exhausted);
+        self.rx_checksum_errors = self.rx_checksum_errors.wrapping_add(other.rx_checksum_errors);
+        self.rx_filtered = self.rx_filtered.wrapping_add(other.rx_filtered);
+        self.tx_filtered = self.tx_filtered.wrapping_add(other.tx_filtered);
+    }
+}
+
+/// Per-interface statistics state.
+///
+/// This is updated from the driver adapter's tokens, which only get shared references,
+/// hence the interior mutability.
+pub(crate) struct Stats {
+    #[cfg(feature = "stats")]
+    counters: Cell<InterfaceStats>,
+    /// Last value of the driver's dropped frames counter, which isn't reset with the others.
+    #[cfg(feature = "stats")]
+    driver_rx_dropped: Cell<u64>,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "stats")]
+            counters: Cell::new(InterfaceStats::default()),
+            #[cfg(feature = "stats")]
+            driver_rx_dropped: Cell::new(0),
+        }
+    }
+
+    /// Get a snapshot of the counters.
+    #[cfg(feature = "stats")]
+    pub(crate) fn get(&self) -> InterfaceStats {
+        self.counters.get()
+    }
+
+    /// Reset all counters to zero.
+    #[cfg(feature = "stats")]
+    pub(crate) fn reset(&self) {
+        self.counters.set(InterfaceStats::default());
+    }
+
+    #[cfg(feature = "stats")]
+    fn update(&self, f: impl FnOnce(&mut InterfaceStats)) {
+        let mut counters = self.counters.get();
+        f(&mut counters);
+        self.counters.set(counters);
+    }
+
+    /// Called for every frame received from the driver, before the stack processes it.
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    pub(crate) fn on_rx(&self, medium: Medium, frame: &[u8], caps: &ChecksumCapabilities) {
+        #[cfg(feature = "stats")]
+        {
+            self.update(|c| {
+                c.rx_packets = c.rx_packets.wrapping_add(1);
+                c.rx_bytes = c.rx_bytes.wrapping_add(frame.len() as u64);
+            });
+
+            let Some(ip) = parse_ip(medium, frame) else { return };
+            if !ip.checksum_valid(caps) {
+                self.update(|c| c.rx_checksum_errors = c.rx_checksum_errors.wrapping_add(1));
+            }
+        }
+    }
+
+    /// Called for every frame handed to the driver for transmission.
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    pub(crate) fn on_tx(&self, frame: &[u8]) {
+        #[cfg(feature = "stats")]
+        self.update(|c| {
+            c.tx_packets = c.tx_packets.wrapping_add(1);
+            c.tx_bytes = c.tx_bytes.wrapping_add(frame.len() as u64);
+        });
+    }
+
+    /// Called after every poll with the driver's count of dropped received frames.
+    #[cfg_attr(not(feature = "stats"), allow(unused_variables))]
+    pub(crate) fn on_driver_rx_dropped(&self, total: u64) {
+        #[cfg(feature = "stats")]
+        {
+            let dropped = total.wrapping_sub(self.driver_rx_dropped.replace(total));
+            if dropped != 0 {
+                self.update(|c| c.rx_dropped = c.rx_dropped.wrapping_add(dropped));
+            }
+        }
+    }
+
+    /// Called when the stack wanted to transmit but the driver had no free buffer.
+    pub(crate) fn on_tx_exhausted(&self) {
+        #[cfg(feature = "stats")]
+        self.update(|c| c.tx_exhausted = c.tx_exhausted.wrapping_add(1));
+    }
+
+    /// Called when the packet filter dropped a received frame.
+    #[cfg(feature = "filter")]
+    pub(crate) fn on_rx_filtered(&self) {
+        #[cfg(feature = "stats")]
+        self.update(|c| c.rx_filtered = c.rx_filtered.wrapping_add(1));
+    }
+
+    /// Called when the packet filter dropped a frame to transmit.
+    #[cfg(feature = "filter")]
+    pub(crate) fn on_tx_filtered(&self) {
+        #[cfg(feature = "stats")]
+        self.update(|c| c.tx_filtered = c.tx_filtered.wrapping_add(1));
+    }
+}
+
+/// The IP layer of a frame.
+#[cfg(any(feature = "stats", feature = "filter"))]
+pub(crate) struct IpInfo<'a> {
+    pub src: IpAddress,
+    pub dst: IpAddress,
+    pub protocol: IpProtocol,
+    pub payload: &'a [u8],
+    /// Whether this is a fragment of a bigger packet, in which case the payload is incomplete.
+    pub fragment: bool,
+    /// Whether the IP header checksum is valid. Always true for IPv6, which has none.
+    #[cfg(all(feature = "stats", feature = "proto-ipv4"))]
+    pub header_checksum_valid: bool,
+}
+
+#[cfg(feature = "stats")]
+impl<'a> IpInfo<'a> {
+    fn checksum_valid(&self, caps: &ChecksumCapabilities) -> bool {
+        fn verify(c: Checksum) -> bool {
+            matches!(c, Checksum::Both | Checksum::Rx)
+        }
+
+        #[cfg(feature = "proto-ipv4")]
+        if verify(caps.ipv4) && !self.header_checksum_valid {
+            return false;
+        }
+        if self.fragment {
+            return true;
+        }
+
+        match (self.protocol, self.src, self.dst) {
+            (IpProtocol::Tcp, src, dst) if verify(caps.tcp) => {
+                TcpPacket::new_checked(self.payload).map_or(true, |p| p.verify_checksum(&src, &dst))
+            }
+            (IpProtocol::Udp, src, dst) if verify(caps.udp) => {
+                UdpPacket::new_checked(self.payload).map_or(true, |p| p.verify_checksum(&src, &dst))
+            }
+            #[cfg(feature = "proto-ipv4")]
+            (IpProtocol::Icmp, _, _) if verify(caps.icmpv4) => {
+                Icmpv4Packet::new_checked(self.payload).map_or(true, |p| p.verify_checksum())
+            }
+            #[cfg(feature = "proto-ipv6")]
+            (IpProtocol::Icmpv6, IpAddress::Ipv6(src), IpAddress::Ipv6(dst)) if verify(caps.icmpv6) => {
+                Icmpv6Packet::new_checked(self.payload).map_or(true, |p| p.verify_checksum(&src, &dst))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Parse the IP layer of a frame. Returns `None` for frames that don't carry IP.
+#[cfg(any(feature = "stats", feature = "filter"))]
+pub(crate) fn parse_ip(medium: Medium, frame: &[u8]) -> Option<IpInfo<'_>> {
+    let packet: &[u8] = match medium {
+        #[cfg(feature = "medium-ethernet")]
+        Medium::Ethernet => {
+            let frame = EthernetFrame::new_checked(frame).ok()?;
+            match frame.ethertype() {
+                #[cfg(feature = "proto-ipv4")]
+                EthernetProtocol::Ipv4 => frame.payload(),
+                #[cfg(feature = "proto-ipv6")]
+                EthernetProtocol::Ipv6 => frame.payload(),
+                _ => return None,
+            }
+        }
+        #[cfg(feature = "medium-ip")]
+        Medium::Ip => frame,
+        #[allow(unreachable_patterns)]
+        _ => return None,
+    };
+
+    match packet.first()? >> 4 {
+        #[cfg(feature = "proto-ipv4")]
+        4 => {
+            let p = Ipv4Packet::new_checked(packet).ok()?;
+            Some(IpInfo {
+                src: p.src_addr().into(),
+                dst: p.dst_addr().into(),
+                protocol: p.next_header(),
+                fragment: p.more_frags() || p.frag_offset() != 0,
+                #[cfg(feature = "stats")]
+                header_checksum_valid: p.verify_checksum(),
+                payload: p.payload(),
+            })
+        }
+        #[cfg(feature = "proto-ipv6")]
+        6 => {
+            let p = Ipv6Packet::new_checked(packet).ok()?;
+            Some(IpInfo {
+                src: p.src_addr().into(),
+                dst: p.dst_addr().into(),
+                protocol: p.next_header(),
+                fragment: false,
+                #[cfg(all(feature = "stats", feature = "proto-ipv4"))]
+                header_checksum_valid: true,
+                payload: p.payload(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(
+    test,
+    feature = "stats",
+    feature = "tcp",
+    feature = "proto-ipv4",
+    feature = "medium-ip"
+))]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use smoltcp::wire::{Ipv4Address, TcpSeqNumber};
+
+    use super::*;
+
+    const LOCAL: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+    const REMOTE: Ipv4Address = Ipv4Address::new(10, 0, 0, 2);
+
+    fn ipv4(src: Ipv4Address, dst: Ipv4Address, protocol: IpProtocol, payload_len: usize) -> Vec<u8> {
+        let mut frame = vec![0; 20 + payload_len];
+        let mut ip = Ipv4Packet::new_unchecked(&mut frame[..]);
+        ip.set_version(4);
+        ip.set_header_len(20);
+        ip.set_total_len((20 + payload_len) as u16);
+        ip.set_hop_limit(64);
+        ip.set_next_header(protocol);
+        ip.set_src_addr(src);
+        ip.set_dst_addr(dst);
+        ip.fill_checksum();
+        frame
+    }
+
+    /// A TCP segment. `outgoing` is from the local to the remote endpoint.
+    fn tcp(outgoing: bool, seq: i32, ack: Option<i32>, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, src_port, dst_port) = match outgoing {
+            true => (LOCAL, REMOTE, 1234, 80),
+            false => (REMOTE, LOCAL, 80, 1234),
+        };
+        let mut frame = ipv4(src, dst, IpProtocol::Tcp, 20 + payload.len());
+        let mut tcp = TcpPacket::new_unchecked(&mut frame[20..]);
+        tcp.set_src_port(src_port);
+        tcp.set_dst_port(dst_port);
+        tcp.set_seq_number(TcpSeqNumber(seq));
+        tcp.set_ack_number(TcpSeqNumber(ack.unwrap_or(0)));
+        tcp.set_header_len(20);
+        tcp.set_syn(syn);
+        tcp.set_ack(ack.is_some());
+        tcp.set_window_len(1024);
+        tcp.payload_mut().copy_from_slice(payload);
+        tcp.fill_checksum(&src.into(), &dst.into());
+        frame
+    }
+
+    fn udp(payload: &[u8]) -> Vec<u8> {
+        let mut frame = ipv4(REMOTE, LOCAL, IpProtocol::Udp, 8 + payload.len());
+        let mut udp = UdpPacket::new_unchecked(&mut frame[20..]);
+        udp.set_src_port(53);
+        udp.set_dst_port(1234);
+        udp.set_len(8 + payload.len() as u16);
+        udp.payload_mut().copy_from_slice(payload);
+        udp.fill_checksum(&REMOTE.into(), &LOCAL.into());
+        frame
+    }
+
+    #[test]
+    fn counters() {
+        let stats = Stats::new();
+        let caps = ChecksumCapabilities::default();
+        let frame = udp(b"hello");
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        stats.on_tx(&frame[..10]);
+        stats.on_tx_exhausted();
+        stats.on_driver_rx_dropped(3);
+        stats.on_driver_rx_dropped(3);
+        #[cfg(feature = "filter")]
+        {
+            stats.on_rx_filtered();
+            stats.on_tx_filtered();
+        }
+
+        let s = stats.get();
+        assert_eq!((s.rx_packets, s.rx_bytes), (2, 2 * frame.len() as u64));
+        assert_eq!((s.tx_packets, s.tx_bytes), (1, 10));
+        assert_eq!((s.tx_exhausted, s.rx_checksum_errors, s.rx_dropped), (1, 0, 3));
+        #[cfg(feature = "filter")]
+        assert_eq!((s.rx_filtered, s.tx_filtered), (1, 1));
+
+        stats.reset();
+        assert_eq!(stats.get(), InterfaceStats::default());
+
+        // Only the frames the driver dropped since the reset are counted, including across a wraparound.
+        stats.on_driver_rx_dropped(5);
+        assert_eq!(stats.get().rx_dropped, 2);
+        stats.on_driver_rx_dropped(u64::MAX);
+        stats.reset();
+        stats.on_driver_rx_dropped(1);
+        assert_eq!(stats.get().rx_dropped, 2);
+    }
+
+    #[test]
+    fn accumulate_wraps() {
+        let mut total = InterfaceStats {
+            rx_packets: u64::MAX,
+            ..Default::default()
+        };
+        let other = InterfaceStats {
+            rx_packets: 2,
+            tx_bytes: 5,
+            ..Default::default()
+        };
+        total.accumulate(&other);
+        assert_eq!((total.rx_packets, total.tx_bytes), (1, 5));
+    }
+
+    #[test]
+    fn checksum_errors() {
+        let stats = Stats::new();
+        let caps = ChecksumCapabilities::default();
+
+        // Corrupted IPv4 header, TCP segment and UDP datagram.
+        let mut frame = udp(b"hello");
+        frame[8] ^= 0xff;
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        let mut frame = tcp(false, 0, None, true, &[]);
+        frame[30] ^= 0xff;
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        let mut frame = udp(b"hello");
+        *frame.last_mut().unwrap() ^= 0xff;
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        assert_eq!(stats.get().rx_checksum_errors, 3);
+
+        // Checksums verified by the hardware aren't checked again.
+        let mut offload = ChecksumCapabilities::default();
+        offload.udp = Checksum::Tx;
+        stats.on_rx(Medium::Ip, &frame, &offload);
+        assert_eq!(stats.get().rx_checksum_errors, 3);
+
+        // Truncated and malformed packets, and fragments, are counted but not checked.
+        let frame = udp(b"hello");
+        stats.on_rx(Medium::Ip, &frame[..24], &caps);
+        stats.on_rx(Medium::Ip, &frame[..10], &caps);
+        stats.on_rx(Medium::Ip, &[], &caps);
+        stats.on_rx(Medium::Ip, &[0x60; 8], &caps);
+        let mut frame = udp(b"hello");
+        let mut ip = Ipv4Packet::new_unchecked(&mut frame[..]);
+        ip.set_more_frags(true);
+        ip.fill_checksum();
+        *frame.last_mut().unwrap() ^= 0xff;
+        stats.on_rx(Medium::Ip, &frame, &caps);
+        let s = stats.get();
+        assert_eq!((s.rx_packets, s.rx_checksum_errors), (9, 3));
+    }
+}
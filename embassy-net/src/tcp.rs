@@ -7,6 +7,9 @@
 //!
 //! Incoming connections when no socket is listening are rejected. To accept many incoming
 //! connections, create many sockets and put them all into listening mode.
+//!
+//! A listening socket that isn't bound to an interface accepts connections arriving on any
+//! interface of the stack, as long as that interface has a free socket slot.
 
 use core::future::poll_fn;
 use core::mem;
@@ -19,7 +22,7 @@ pub use smoltcp::socket::tcp::State;
 use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
 
 use crate::time::duration_to_smoltcp;
-use crate::Stack;
+use crate::{BindInterfaceError, InterfaceId, Stack};
 
 /// Error returned by TcpSocket read/write functions.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -43,6 +46,8 @@ pub enum ConnectError {
     TimedOut,
     /// No route to host.
     NoRoute,
+    /// The interface routing to the host has no free socket slot.
+    NoFreeSlot,
 }
 
 /// Error returned by [`TcpSocket::accept`].
@@ -55,11 +60,14 @@ pub enum AcceptError {
     InvalidPort,
     /// The remote host rejected the connection with a RST packet.
     ConnectionReset,
+    /// The interface with the address to listen on has no free socket slot.
+    NoFreeSlot,
 }
 
 /// A TCP socket.
 pub struct TcpSocket<'a> {
     io: TcpIo<'a>,
+    bound_interface: bool,
 }
 
 /// The reader half of a TCP socket.
@@ -155,19 +163,48 @@ impl<'a> TcpWriter<'a> {
 
 impl<'a> TcpSocket<'a> {
     /// Create a new TCP socket on the given stack, with the given buffers.
+    ///
+    /// The socket starts out on the primary interface. When connecting, it moves to the
+    /// interface selected by the stack's routing table, unless it was bound to an interface
+    /// with [`bind_interface`](Self::bind_interface).
     pub fn new(stack: Stack<'a>, rx_buffer: &'a mut [u8], tx_buffer: &'a mut [u8]) -> Self {
+        let iface = InterfaceId::PRIMARY;
         let handle = stack.with_mut(|i| {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(tcp::Socket::new(
+            i.iface_mut(iface).sockets.add(tcp::Socket::new(
                 tcp::SocketBuffer::new(rx_buffer),
                 tcp::SocketBuffer::new(tx_buffer),
             ))
         });
 
         Self {
-            io: TcpIo { stack: stack, handle },
+            io: TcpIo { stack, iface, handle },
+            bound_interface: false,
+        }
+    }
+
+    /// Bind the socket to a network interface.
+    ///
+    /// The socket will only send and receive through this interface: connections are
+    /// made through it regardless of the routing table, and [`accept`](Self::accept)
+    /// only accepts connections arriving on it.
+    ///
+    /// The socket must be closed.
+    pub fn bind_interface(&mut self, interface: InterfaceId) -> Result<(), BindInterfaceError> {
+        if self.state() != State::Closed {
+            return Err(BindInterfaceError::InvalidState);
+        }
+        if !self.io.move_to(interface) {
+            return Err(BindInterfaceError::NoFreeSlot);
         }
+        self.bound_interface = true;
+        Ok(())
+    }
+
+    /// Get the interface the socket is currently attached to.
+    pub fn interface(&self) -> InterfaceId {
+        self.io.iface
     }
 
     /// Return the maximum number of bytes inside the recv buffer.
@@ -224,6 +261,16 @@ impl<'a> TcpSocket<'a> {
     where
         T: Into<IpEndpoint>,
     {
+        let remote_endpoint = remote_endpoint.into();
+
+        if !self.bound_interface && self.state() == State::Closed {
+            if let Some(iface) = self.io.stack.route_to(remote_endpoint.addr) {
+                if !self.io.move_to(iface) {
+                    return Err(ConnectError::NoFreeSlot);
+                }
+            }
+        }
+
         let local_port = self.io.stack.with_mut(|i| i.get_local_port());
 
         match {
@@ -252,10 +299,26 @@ impl<'a> TcpSocket<'a> {
     /// Accept a connection from a remote host.
     ///
     /// This function puts the socket in listening mode, and waits until a connection is received.
+    ///
+    /// Unless the socket was bound to an interface with [`bind_interface`](Self::bind_interface),
+    /// it listens on the interface that has the address of `local_endpoint`, or on the primary
+    /// interface if `local_endpoint` has no address.
     pub async fn accept<T>(&mut self, local_endpoint: T) -> Result<(), AcceptError>
     where
         T: Into<IpListenEndpoint>,
     {
+        let local_endpoint = local_endpoint.into();
+
+        if !self.bound_interface && self.state() == State::Closed {
+            let iface = local_endpoint
+                .addr
+                .and_then(|addr| self.io.stack.with(|i| i.interface_with_addr(&addr)))
+                .unwrap_or(InterfaceId::PRIMARY);
+            if !self.io.move_to(iface) {
+                return Err(AcceptError::NoFreeSlot);
+            }
+        }
+
         match self.io.with_mut(|s, _| s.listen(local_endpoint)) {
             Ok(()) => {}
             Err(tcp::ListenError::InvalidState) => return Err(AcceptError::InvalidState),
@@ -397,7 +460,9 @@ impl<'a> TcpSocket<'a> {
 
 impl<'a> Drop for TcpSocket<'a> {
     fn drop(&mut self) {
-        self.io.stack.with_mut(|i| i.sockets.remove(self.io.handle));
+        self.io.stack.with_mut(|i| {
+            i.iface_mut(self.io.iface).sockets.remove(self.io.handle);
+        });
     }
 }
 
@@ -416,12 +481,14 @@ fn _assert_covariant_writer<'a, 'b: 'a>(x: TcpWriter<'b>) -> TcpWriter<'a> {
 #[derive(Copy, Clone)]
 struct TcpIo<'a> {
     stack: Stack<'a>,
+    iface: InterfaceId,
     handle: SocketHandle,
 }
 
 impl<'d> TcpIo<'d> {
     fn with<R>(&self, f: impl FnOnce(&tcp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
+            let i = i.iface(self.iface);
             let socket = i.sockets.get::<tcp::Socket>(self.handle);
             f(socket, &i.iface)
         })
@@ -429,6 +496,7 @@ impl<'d> TcpIo<'d> {
 
     fn with_mut<R>(&mut self, f: impl FnOnce(&mut tcp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
+            let i = i.iface_mut(self.iface);
             let socket = i.sockets.get_mut::<tcp::Socket>(self.handle);
             let res = f(socket, &mut i.iface);
             i.waker.wake();
@@ -436,6 +504,18 @@ impl<'d> TcpIo<'d> {
         })
     }
 
+    /// Move the socket to another interface. Returns false if the interface has no free socket slot.
+    fn move_to(&mut self, iface: InterfaceId) -> bool {
+        match self.stack.with_mut(|i| i.move_socket(self.handle, self.iface, iface)) {
+            Some(handle) => {
+                self.iface = iface;
+                self.handle = handle;
+                true
+            }
+            None => false,
+        }
+    }
+
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         poll_fn(move |cx| {
             // CAUTION: smoltcp semantics around EOF are different to what you'd expect
@@ -589,6 +669,7 @@ mod embedded_io_impls {
                 ConnectError::ConnectionReset => embedded_io_async::ErrorKind::ConnectionReset,
                 ConnectError::TimedOut => embedded_io_async::ErrorKind::TimedOut,
                 ConnectError::NoRoute => embedded_io_async::ErrorKind::NotConnected,
+                ConnectError::NoFreeSlot => embedded_io_async::ErrorKind::OutOfMemory,
                 ConnectError::InvalidState => embedded_io_async::ErrorKind::Other,
             }
         }
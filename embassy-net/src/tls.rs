@@ -0,0 +1,201 @@
+//! TLS 1.3 client connections, built on [`embedded-tls`](https://crates.io/crates/embedded-tls).
+//!
+//! [`TlsStream`] runs TLS over any `embedded-io-async` transport, such as a [`TcpSocket`] or a
+//! [`TcpConnection`](crate::tcp::client::TcpConnection), and implements the `embedded-io-async`
+//! [`Read`] and [`Write`] traits itself.
+//!
+//! The server certificate is verified by the [`TlsVerifier`] passed to [`Provider::new()`]. Skipping
+//! the verification takes an explicit [`Provider::dangerous_no_verification()`]. Sessions can be
+//! resumed without a full handshake using a pre-shared key, see [`TlsConfig::with_psk()`].
+//!
+//! Only the client side of the handshake is supported, and sessions can't be resumed with the tickets
+//! sent by the server, as `embedded-tls` implements neither.
+
+use core::marker::PhantomData;
+
+use embedded_io_async::{ErrorType, Read, Write};
+pub use embedded_tls::{
+    Aes128GcmSha256, Aes256GcmSha384, Certificate, CryptoProvider, NoVerify, TlsCipherSuite, TlsConfig, TlsError,
+    TlsVerifier,
+};
+use embedded_tls::{TlsConnection, TlsContext};
+use rand_core::CryptoRngCore;
+
+use crate::tcp::{ConnectError, TcpSocket};
+use crate::IpEndpoint;
+
+/// Size of a buffer that can hold any TLS record: 16 KiB of plaintext, plus the record header and
+/// the expansion due to encryption.
+pub const RECORD_BUFFER_LEN: usize = 16384 + 256;
+
+/// Record buffers for a [`TlsStream`].
+///
+/// The read buffer must always be able to hold a full record, because the peer chooses the size of
+/// the records it sends. The write buffer only limits the size of the records sent, so it can be
+/// smaller, down to a few hundred bytes (but the handshake messages must fit in it).
+pub struct TlsBuffers<const TX_SZ: usize = RECORD_BUFFER_LEN> {
+    read: [u8; RECORD_BUFFER_LEN],
+    write: [u8; TX_SZ],
+}
+
+impl<const TX_SZ: usize> TlsBuffers<TX_SZ> {
+    /// Create new buffers.
+    pub const fn new() -> Self {
+        Self {
+            read: [0; RECORD_BUFFER_LEN],
+            write: [0; TX_SZ],
+        }
+    }
+}
+
+/// Cryptography provider for a [`TlsStream`], with a random number generator and a certificate verifier.
+pub struct Provider<CipherSuite, R, V> {
+    rng: R,
+    verifier: V,
+    _phantom: PhantomData<CipherSuite>,
+}
+
+impl<CipherSuite, R, V> Provider<CipherSuite, R, V> {
+    /// Create a provider that verifies the server certificate with `verifier`.
+    pub fn new(rng: R, verifier: V) -> Self {
+        Self {
+            rng,
+            verifier,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<CipherSuite, R> Provider<CipherSuite, R, NoVerify> {
+    /// Create a provider that accepts any server certificate.
+    ///
+    /// This is insecure, as anyone in the path to the server can impersonate it. Only use it for
+    /// testing, e.g. against a server with a self-signed certificate.
+    pub fn dangerous_no_verification(rng: R) -> Self {
+        Self::new(rng, NoVerify)
+    }
+}
+
+impl<CipherSuite, R, V> CryptoProvider for Provider<CipherSuite, R, V>
+where
+    CipherSuite: TlsCipherSuite,
+    R: CryptoRngCore,
+    V: TlsVerifier<CipherSuite>,
+{
+    type CipherSuite = CipherSuite;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+/// Error returned by [`TlsStream::connect`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The TCP connection failed.
+    Connect(ConnectError),
+    /// The TLS handshake failed.
+    Tls(TlsError),
+}
+
+/// A TLS connection over an `embedded-io-async` transport.
+pub struct TlsStream<'a, T, CipherSuite = Aes128GcmSha256>
+where
+    T: Read + Write,
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    conn: TlsConnection<'a, T, CipherSuite>,
+}
+
+impl<'a, T, CipherSuite> TlsStream<'a, T, CipherSuite>
+where
+    T: Read + Write,
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    /// Create a TLS stream over an established transport.
+    ///
+    /// The stream must be opened with [`open()`](Self::open) before use.
+    pub fn new<const TX_SZ: usize>(transport: T, buffers: &'a mut TlsBuffers<TX_SZ>) -> Self {
+        Self {
+            conn: TlsConnection::new(transport, &mut buffers.read, &mut buffers.write),
+        }
+    }
+
+    /// Perform the TLS handshake.
+    ///
+    /// Set the server name in `config` with [`TlsConfig::with_server_name()`], it's used
+    /// both for SNI and for certificate verification.
+    pub async fn open<P>(&mut self, config: &TlsConfig<'_>, provider: P) -> Result<(), TlsError>
+    where
+        P: CryptoProvider<CipherSuite = CipherSuite>,
+    {
+        self.conn.open(TlsContext::new(config, provider)).await
+    }
+
+    /// Send a `close_notify` alert and return the transport.
+    ///
+    /// The transport is returned on error too, so it can still be closed.
+    pub async fn close(self) -> Result<T, (T, TlsError)> {
+        self.conn.close().await
+    }
+}
+
+impl<'a, 's, CipherSuite> TlsStream<'a, TcpSocket<'s>, CipherSuite>
+where
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    /// Connect `socket` to `remote_endpoint` and perform the TLS handshake.
+    pub async fn connect<const TX_SZ: usize, P>(
+        mut socket: TcpSocket<'s>,
+        buffers: &'a mut TlsBuffers<TX_SZ>,
+        remote_endpoint: impl Into<IpEndpoint>,
+        config: &TlsConfig<'_>,
+        provider: P,
+    ) -> Result<Self, Error>
+    where
+        P: CryptoProvider<CipherSuite = CipherSuite>,
+    {
+        socket.connect(remote_endpoint).await.map_err(Error::Connect)?;
+        let mut stream = Self::new(socket, buffers);
+        stream.open(config, provider).await.map_err(Error::Tls)?;
+        Ok(stream)
+    }
+}
+
+impl<'a, T, CipherSuite> ErrorType for TlsStream<'a, T, CipherSuite>
+where
+    T: Read + Write,
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    type Error = TlsError;
+}
+
+impl<'a, T, CipherSuite> Read for TlsStream<'a, T, CipherSuite>
+where
+    T: Read + Write,
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.conn.read(buf).await
+    }
+}
+
+impl<'a, T, CipherSuite> Write for TlsStream<'a, T, CipherSuite>
+where
+    T: Read + Write,
+    CipherSuite: TlsCipherSuite + 'static,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.conn.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.conn.flush().await
+    }
+}
@@ -9,7 +9,7 @@ use smoltcp::socket::udp;
 pub use smoltcp::socket::udp::{PacketMetadata, UdpMetadata};
 use smoltcp::wire::IpListenEndpoint;
 
-use crate::Stack;
+use crate::{BindInterfaceError, InterfaceId, Stack};
 
 /// Error returned by [`UdpSocket::bind`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -19,6 +19,8 @@ pub enum BindError {
     InvalidState,
     /// No route to host.
     NoRoute,
+    /// The interface with the address to bind to has no free socket slot.
+    NoFreeSlot,
 }
 
 /// Error returned by [`UdpSocket::recv_from`] and [`UdpSocket::send_to`].
@@ -43,10 +45,15 @@ pub enum RecvError {
 pub struct UdpSocket<'a> {
     stack: Stack<'a>,
     handle: SocketHandle,
+    iface: InterfaceId,
 }
 
 impl<'a> UdpSocket<'a> {
     /// Create a new UDP socket using the provided stack and buffers.
+    ///
+    /// The socket is attached to the primary interface, until it's bound to another one with
+    /// [`bind_interface`](Self::bind_interface) or [`bind`](Self::bind). It only sends and receives
+    /// datagrams through the interface it's attached to.
     pub fn new(
         stack: Stack<'a>,
         rx_meta: &'a mut [PacketMetadata],
@@ -54,27 +61,75 @@ impl<'a> UdpSocket<'a> {
         tx_meta: &'a mut [PacketMetadata],
         tx_buffer: &'a mut [u8],
     ) -> Self {
+        let iface = InterfaceId::PRIMARY;
         let handle = stack.with_mut(|i| {
             let rx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(rx_meta) };
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(udp::Socket::new(
+            i.iface_mut(iface).sockets.add(udp::Socket::new(
                 udp::PacketBuffer::new(rx_meta, rx_buffer),
                 udp::PacketBuffer::new(tx_meta, tx_buffer),
             ))
         });
 
-        Self { stack, handle }
+        Self { stack, handle, iface }
+    }
+
+    /// Attach the socket to a network interface.
+    ///
+    /// The socket will only send and receive datagrams through this interface.
+    ///
+    /// The socket must not be open yet.
+    pub fn bind_interface(&mut self, interface: InterfaceId) -> Result<(), BindInterfaceError> {
+        if self.is_open() {
+            return Err(BindInterfaceError::InvalidState);
+        }
+        match self
+            .stack
+            .with_mut(|i| i.move_socket(self.handle, self.iface, interface))
+        {
+            Some(handle) => {
+                self.handle = handle;
+                self.iface = interface;
+                Ok(())
+            }
+            None => Err(BindInterfaceError::NoFreeSlot),
+        }
+    }
+
+    #[cfg(feature = "mdns-responder")]
+    pub(crate) fn stack(&self) -> Stack<'a> {
+        self.stack
+    }
+
+    /// Get the interface the socket is attached to.
+    pub fn interface(&self) -> InterfaceId {
+        self.iface
     }
 
     /// Bind the socket to a local endpoint.
+    ///
+    /// If the endpoint has an address, the socket is attached to the interface
+    /// that owns that address.
     pub fn bind<T>(&mut self, endpoint: T) -> Result<(), BindError>
     where
         T: Into<IpListenEndpoint>,
     {
         let mut endpoint = endpoint.into();
 
+        if self.is_open() {
+            return Err(BindError::InvalidState);
+        }
+
+        if let Some(addr) = endpoint.addr {
+            if let Some(iface) = self.stack.with(|i| i.interface_with_addr(&addr)) {
+                if self.bind_interface(iface).is_err() {
+                    return Err(BindError::NoFreeSlot);
+                }
+            }
+        }
+
         if endpoint.port == 0 {
             // If user didn't specify port allocate a dynamic port.
             endpoint.port = self.stack.with_mut(|i| i.get_local_port());
@@ -89,6 +144,7 @@ impl<'a> UdpSocket<'a> {
 
     fn with<R>(&self, f: impl FnOnce(&udp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
+            let i = i.iface(self.iface);
             let socket = i.sockets.get::<udp::Socket>(self.handle);
             f(socket, &i.iface)
         })
@@ -96,6 +152,7 @@ impl<'a> UdpSocket<'a> {
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut udp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
+            let i = i.iface_mut(self.iface);
             let socket = i.sockets.get_mut::<udp::Socket>(self.handle);
             let res = f(socket, &mut i.iface);
             i.waker.wake();
@@ -295,7 +352,8 @@ impl<'a> UdpSocket<'a> {
 
 impl Drop for UdpSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack
+            .with_mut(|i| i.iface_mut(self.iface).sockets.remove(self.handle));
     }
 }
 
@@ -0,0 +1,1208 @@
+//! WebSocket client and server (RFC 6455).
+//!
+//! [`WebSocket`] runs over any `embedded-io-async` stream, such as a
+//! [`TcpSocket`](crate::tcp::TcpSocket) or a TLS stream. The connection is opened with
+//! [`WebSocket::connect()`] on the client side, and with [`WebSocket::accept()`] on the server
+//! side. With the `http-server` feature, an [`HttpServer`](crate::http::HttpServer) handler can also
+//! upgrade a request with [`Response::upgrade_websocket()`](crate::http::Response::upgrade_websocket).
+//!
+//! Received messages are reassembled from their fragments in a buffer provided by the caller, so
+//! messages that don't fit are rejected with [`Error::BufferTooSmall`]. Pings are answered
+//! automatically while receiving, so [`WebSocket::receive()`] should be called continuously.
+//!
+//! ```rust,ignore
+//! let mut buf = [0; 1024];
+//! let config = ClientConfig::new("example.com", "/live");
+//! let mut ws = WebSocket::connect(&mut socket, &mut buf, rng, &config).await?;
+//! ws.send_text("hello").await?;
+//! match ws.receive().await? {
+//!     Message::Text(text) => info!("received {}", text),
+//!     Message::Binary(data) => info!("received {} bytes", data.len()),
+//! }
+//! ws.close(CLOSE_NORMAL, "").await?;
+//! ```
+//!
+//! Extensions, such as per-message compression, aren't supported.
+
+use embedded_io_async::{ErrorKind, Read, ReadExactError, Write};
+use rand_core::RngCore;
+
+/// Status code of a normal closure.
+pub const CLOSE_NORMAL: u16 = 1000;
+/// Status code of an endpoint going away, such as a server shutting down.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+/// Status code of a closure due to a protocol error.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Status code of a closure due to a text message that isn't valid UTF-8.
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+/// Status code of a closure due to a message too big to process.
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Maximum payload length of control frames, including pings and close frames.
+pub const MAX_CONTROL_LEN: usize = 125;
+
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Error returned by [`WebSocket`] operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying stream failed, or was closed without a close frame.
+    Io(ErrorKind),
+    /// The server answered the handshake with this HTTP status code instead of `101 Switching Protocols`.
+    Rejected(u16),
+    /// The handshake is malformed, or isn't a WebSocket handshake.
+    Handshake,
+    /// The peer violated the protocol. The connection was closed.
+    Protocol,
+    /// A received message or handshake doesn't fit in the buffer. The connection was closed.
+    BufferTooSmall,
+    /// The connection is closed, with the status code sent by the peer, if any.
+    Closed(Option<u16>),
+}
+
+fn io_error<E: embedded_io_async::Error>(e: E) -> Error {
+    Error::Io(e.kind())
+}
+
+fn read_exact_error<E: embedded_io_async::Error>(e: ReadExactError<E>) -> Error {
+    match e {
+        ReadExactError::UnexpectedEof => Error::Io(ErrorKind::BrokenPipe),
+        ReadExactError::Other(e) => io_error(e),
+    }
+}
+
+/// Type of a data message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    /// UTF-8 text.
+    Text,
+    /// Binary data.
+    Binary,
+}
+
+/// A received message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Message<'a> {
+    /// A text message.
+    Text(&'a str),
+    /// A binary message.
+    Binary(&'a [u8]),
+}
+
+/// WebSocket client configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct ClientConfig<'a> {
+    /// Host name of the server, sent in the `Host` header.
+    pub host: &'a str,
+    /// Path of the WebSocket endpoint, with the query string if any.
+    pub path: &'a str,
+    /// Additional headers sent with the handshake, such as `Origin`, `Authorization` or
+    /// `Sec-WebSocket-Protocol`.
+    pub headers: &'a [(&'a str, &'a str)],
+}
+
+impl<'a> ClientConfig<'a> {
+    /// Create a configuration without additional headers.
+    pub const fn new(host: &'a str, path: &'a str) -> Self {
+        Self {
+            host,
+            path,
+            headers: &[],
+        }
+    }
+}
+
+mod sealed {
+    pub trait Sealed {
+        /// Whether frames sent by this side are masked.
+        const MASKS: bool;
+
+        fn mask_key(&mut self) -> [u8; 4];
+    }
+}
+
+/// Side of a WebSocket connection, [`Client`] or [`Server`].
+pub trait Role: sealed::Sealed {}
+
+/// Client side of a WebSocket connection, whose frames are masked with keys drawn from `R`.
+pub struct Client<R> {
+    rng: R,
+}
+
+impl<R: RngCore> sealed::Sealed for Client<R> {
+    const MASKS: bool = true;
+
+    fn mask_key(&mut self) -> [u8; 4] {
+        self.rng.next_u32().to_ne_bytes()
+    }
+}
+
+impl<R: RngCore> Role for Client<R> {}
+
+/// Server side of a WebSocket connection.
+pub struct Server {
+    _private: (),
+}
+
+impl sealed::Sealed for Server {
+    const MASKS: bool = false;
+
+    fn mask_key(&mut self) -> [u8; 4] {
+        [0; 4]
+    }
+}
+
+impl Role for Server {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Open,
+    /// We sent a close frame, and wait for the peer's.
+    Closing,
+    Closed,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: usize,
+}
+
+/// A WebSocket connection over the stream `T`.
+///
+/// `buf` holds received data: the handshake, then each message being reassembled. It limits the size
+/// of the messages that can be received, but not of those sent.
+pub struct WebSocket<'b, T, R: Role> {
+    io: T,
+    buf: &'b mut [u8],
+    /// Received data not parsed yet is in `buf[start..end]`.
+    start: usize,
+    end: usize,
+    role: R,
+    state: State,
+    /// Whether a fragmented message is being sent.
+    fragmenting: bool,
+}
+
+impl<'b, T: Read + Write, R: RngCore> WebSocket<'b, T, Client<R>> {
+    /// Open a connection to a server, by sending the handshake over `io`.
+    ///
+    /// `rng` provides the keys masking every frame sent, which must be unpredictable.
+    pub async fn connect(io: T, buf: &'b mut [u8], mut rng: R, config: &ClientConfig<'_>) -> Result<Self, Error> {
+        let mut nonce = [0; 16];
+        rng.fill_bytes(&mut nonce);
+        let mut key = [0; 24];
+        let key = base64(&nonce, &mut key);
+
+        let mut ws = Self::new(io, buf, Client { rng });
+        let headers = [
+            ("Host", config.host),
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Key", key),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        ws.write_head(
+            &["GET ", config.path, " HTTP/1.1"],
+            headers.iter().chain(config.headers),
+        )
+        .await?;
+
+        let head_len = ws.read_head().await?;
+        let head = core::str::from_utf8(&ws.buf[..head_len]).map_err(|_| Error::Handshake)?;
+        let mut status_line = head.split("\r\n").next().unwrap_or("").split(' ');
+        if !status_line.next().is_some_and(|v| v.starts_with("HTTP/1.")) {
+            return Err(Error::Handshake);
+        }
+        let status: u16 = status_line
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or(Error::Handshake)?;
+        if status != 101 {
+            return Err(Error::Rejected(status));
+        }
+        let mut expected = [0; 28];
+        let valid = header(head, "Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+            && header(head, "Connection").is_some_and(|v| has_token(v, "upgrade"))
+            && header(head, "Sec-WebSocket-Accept") == Some(accept_key(key, &mut expected));
+        if !valid {
+            return Err(Error::Handshake);
+        }
+        ws.start = head_len;
+        Ok(ws)
+    }
+}
+
+impl<'b, T: Read + Write> WebSocket<'b, T, Server> {
+    /// Accept a connection from a client, by reading its handshake from `io` and answering it.
+    ///
+    /// Requests that aren't a WebSocket handshake are answered with `400 Bad Request`. To serve
+    /// other HTTP requests on the same port, use the `http-server` feature instead.
+    pub async fn accept(io: T, buf: &'b mut [u8]) -> Result<Self, Error> {
+        let mut ws = Self::new(io, buf, Server { _private: () });
+        let head_len = ws.read_head().await?;
+        let head = core::str::from_utf8(&ws.buf[..head_len]).map_err(|_| Error::Handshake)?;
+        let get = head.starts_with("GET ");
+        let mut accept = [0; 28];
+        let Some(accept) = handshake_key(get, |name| header(head, name)).map(|key| accept_key(key, &mut accept)) else {
+            let headers = [("Sec-WebSocket-Version", "13"), ("Content-Length", "0")];
+            ws.write_head(&["HTTP/1.1 400 Bad Request"], headers.iter()).await?;
+            return Err(Error::Handshake);
+        };
+        ws.write_head(&["HTTP/1.1 101 Switching Protocols"], upgrade_headers(accept).iter())
+            .await?;
+        ws.start = head_len;
+        Ok(ws)
+    }
+
+    /// Create a server connection whose handshake was already answered.
+    #[cfg(feature = "http-server")]
+    pub(crate) fn upgraded(io: T, buf: &'b mut [u8]) -> Self {
+        Self::new(io, buf, Server { _private: () })
+    }
+}
+
+impl<'b, T: Read + Write, R: Role> WebSocket<'b, T, R> {
+    fn new(io: T, buf: &'b mut [u8], role: R) -> Self {
+        Self {
+            io,
+            buf,
+            start: 0,
+            end: 0,
+            role,
+            state: State::Open,
+            fragmenting: false,
+        }
+    }
+
+    /// Receive a message.
+    ///
+    /// Pings are answered, and pongs ignored, while waiting for it. If the peer closes the connection,
+    /// the close frame is answered and [`Error::Closed`] is returned.
+    pub async fn receive(&mut self) -> Result<Message<'_>, Error> {
+        if self.state == State::Closed {
+            return Err(Error::Closed(None));
+        }
+        let mut len = 0;
+        let mut message_type = None;
+        loop {
+            let frame = self.read_frame_header(len).await?;
+            if frame.opcode & 0x8 != 0 {
+                self.handle_control(&frame, len).await?;
+                continue;
+            }
+
+            match (frame.opcode, message_type) {
+                (OP_TEXT, None) => message_type = Some(MessageType::Text),
+                (OP_BINARY, None) => message_type = Some(MessageType::Binary),
+                (OP_CONTINUATION, Some(_)) => {}
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await),
+            }
+            // `len` fits in the buffer, but `frame.len` comes from the peer and may be huge.
+            if frame.len > self.buf.len() - len {
+                return Err(self.fail(CLOSE_TOO_BIG, Error::BufferTooSmall).await);
+            }
+            self.read_payload(len, frame.len).await?;
+            if let Some(key) = frame.mask {
+                apply_mask(&mut self.buf[len..len + frame.len], key, 0);
+            }
+            len += frame.len;
+
+            if frame.fin {
+                // In the closing handshake, messages are only read to find the peer's close frame.
+                if self.state == State::Closing {
+                    len = 0;
+                    message_type = None;
+                    continue;
+                }
+                let text = message_type == Some(MessageType::Text);
+                if text && core::str::from_utf8(&self.buf[..len]).is_err() {
+                    return Err(self.fail(CLOSE_INVALID_DATA, Error::Protocol).await);
+                }
+                let data = &self.buf[..len];
+                return Ok(match text {
+                    // Safety: validated above.
+                    true => Message::Text(unsafe { core::str::from_utf8_unchecked(data) }),
+                    false => Message::Binary(data),
+                });
+            }
+        }
+    }
+
+    /// Send a text message.
+    pub async fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        self.send(MessageType::Text, text.as_bytes()).await
+    }
+
+    /// Send a binary message.
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send(MessageType::Binary, data).await
+    }
+
+    /// Send a message.
+    ///
+    /// Panics if a fragmented message is being sent with [`send_fragment()`](Self::send_fragment).
+    pub async fn send(&mut self, message_type: MessageType, data: &[u8]) -> Result<(), Error> {
+        assert!(!self.fragmenting, "fragmented message in progress");
+        self.send_frame(opcode(message_type), true, data).await
+    }
+
+    /// Send a fragment of a message, for messages whose length isn't known in advance.
+    ///
+    /// The message ends with the fragment sent with `last` set. The `message_type` of the following
+    /// fragments is ignored. Pings and pongs may be sent between fragments, but not other messages.
+    pub async fn send_fragment(&mut self, message_type: MessageType, data: &[u8], last: bool) -> Result<(), Error> {
+        let op = if self.fragmenting {
+            OP_CONTINUATION
+        } else {
+            opcode(message_type)
+        };
+        self.send_frame(op, last, data).await?;
+        self.fragmenting = !last;
+        Ok(())
+    }
+
+    /// Send a ping. The peer answers with a pong, which is ignored by [`receive()`](Self::receive).
+    ///
+    /// Panics if `data` is longer than [`MAX_CONTROL_LEN`].
+    pub async fn ping(&mut self, data: &[u8]) -> Result<(), Error> {
+        assert!(data.len() <= MAX_CONTROL_LEN);
+        self.send_frame(OP_PING, true, data).await
+    }
+
+    /// Close the connection, with a status code and a reason.
+    ///
+    /// Waits for the peer to answer with its own close frame, discarding the messages received
+    /// in the meantime. Panics if `reason` is longer than 123 bytes.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        assert!(reason.len() <= MAX_CONTROL_LEN - 2);
+        if self.state == State::Open {
+            self.send_close(Some(code), reason.as_bytes()).await?;
+        }
+        loop {
+            match self.receive().await {
+                Ok(_) => {}
+                Err(Error::Closed(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    async fn send_frame(&mut self, opcode: u8, fin: bool, data: &[u8]) -> Result<(), Error> {
+        if self.state != State::Open {
+            return Err(Error::Closed(None));
+        }
+        self.write_frame(opcode, fin, data).await
+    }
+
+    async fn send_close(&mut self, code: Option<u16>, reason: &[u8]) -> Result<(), Error> {
+        let mut payload = [0; MAX_CONTROL_LEN];
+        let len = match code {
+            Some(code) => {
+                payload[..2].copy_from_slice(&code.to_be_bytes());
+                payload[2..2 + reason.len()].copy_from_slice(reason);
+                2 + reason.len()
+            }
+            None => 0,
+        };
+        self.state = match self.state {
+            State::Open => State::Closing,
+            _ => State::Closed,
+        };
+        self.write_frame(OP_CLOSE, true, &payload[..len]).await
+    }
+
+    /// Close the connection after an error of the peer, without waiting for its answer.
+    async fn fail(&mut self, code: u16, error: Error) -> Error {
+        if self.state == State::Open {
+            // The connection is being dropped anyway, so errors don't matter.
+            let _ = self.send_close(Some(code), &[]).await;
+        }
+        self.state = State::Closed;
+        error
+    }
+
+    async fn handle_control(&mut self, frame: &FrameHeader, len: usize) -> Result<(), Error> {
+        if !frame.fin || frame.len > MAX_CONTROL_LEN {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await);
+        }
+        // Read the payload after the message being reassembled, or in a separate buffer if it doesn't fit.
+        let mut payload = [0; MAX_CONTROL_LEN];
+        if len + frame.len <= self.buf.len() {
+            self.read_payload(len, frame.len).await?;
+            payload[..frame.len].copy_from_slice(&self.buf[len..len + frame.len]);
+        } else {
+            let have = frame.len.min(self.end - self.start);
+            payload[..have].copy_from_slice(&self.buf[self.start..self.start + have]);
+            self.start += have;
+            self.io
+                .read_exact(&mut payload[have..frame.len])
+                .await
+                .map_err(read_exact_error)?;
+        }
+        let payload = &mut payload[..frame.len];
+        if let Some(key) = frame.mask {
+            apply_mask(payload, key, 0);
+        }
+
+        match frame.opcode {
+            OP_PING => {
+                if self.state == State::Open {
+                    self.write_frame(OP_PONG, true, payload).await?;
+                }
+                Ok(())
+            }
+            OP_PONG => Ok(()),
+            OP_CLOSE => {
+                if payload.len() == 1 {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await);
+                }
+                let code = (payload.len() >= 2).then(|| u16::from_be_bytes([payload[0], payload[1]]));
+                if self.state == State::Open {
+                    self.send_close(code, &[]).await?;
+                }
+                self.state = State::Closed;
+                Err(Error::Closed(code))
+            }
+            _ => Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await),
+        }
+    }
+
+    async fn write_frame(&mut self, opcode: u8, fin: bool, data: &[u8]) -> Result<(), Error> {
+        let mut header = [0; 14];
+        header[0] = if fin { FIN | opcode } else { opcode };
+        let mask_bit = if R::MASKS { MASKED } else { 0 };
+        let mut n = match data.len() {
+            len @ 0..=125 => {
+                header[1] = mask_bit | len as u8;
+                2
+            }
+            len @ 126..=0xffff => {
+                header[1] = mask_bit | 126;
+                header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                4
+            }
+            len => {
+                header[1] = mask_bit | 127;
+                header[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+                10
+            }
+        };
+
+        if !R::MASKS {
+            self.io.write_all(&header[..n]).await.map_err(io_error)?;
+            self.io.write_all(data).await.map_err(io_error)?;
+            return self.io.flush().await.map_err(io_error);
+        }
+
+        let key = self.role.mask_key();
+        header[n..n + 4].copy_from_slice(&key);
+        n += 4;
+        self.io.write_all(&header[..n]).await.map_err(io_error)?;
+        let mut chunk = [0; 64];
+        for (i, data) in data.chunks(chunk.len()).enumerate() {
+            let chunk = &mut chunk[..data.len()];
+            chunk.copy_from_slice(data);
+            apply_mask(chunk, key, i * 64);
+            self.io.write_all(chunk).await.map_err(io_error)?;
+        }
+        self.io.flush().await.map_err(io_error)
+    }
+
+    /// Read a frame header. `keep` is the length of the message being reassembled at the start of `buf`.
+    async fn read_frame_header(&mut self, keep: usize) -> Result<FrameHeader, Error> {
+        self.fill(keep, 2).await?;
+        let [b0, b1] = [self.buf[self.start], self.buf[self.start + 1]];
+        let masked = b1 & MASKED != 0;
+        let ext = match b1 & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let header_len = 2 + ext + if masked { 4 } else { 0 };
+        self.fill(keep, header_len).await?;
+
+        // Extensions aren't negotiated, so the reserved bits must be clear. Clients must mask, servers must not.
+        if b0 & 0x70 != 0 || masked == R::MASKS {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await);
+        }
+        let h = &self.buf[self.start..self.start + header_len];
+        let len = match ext {
+            2 => u16::from_be_bytes([h[2], h[3]]) as u64,
+            8 => u64::from_be_bytes([h[2], h[3], h[4], h[5], h[6], h[7], h[8], h[9]]),
+            _ => (b1 & 0x7f) as u64,
+        };
+        let mask = masked.then(|| [h[2 + ext], h[3 + ext], h[4 + ext], h[5 + ext]]);
+        // The most significant bit of 64-bit lengths must be 0 (RFC 6455 section 5.2).
+        if len >> 63 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, Error::Protocol).await);
+        }
+        self.start += header_len;
+
+        Ok(FrameHeader {
+            fin: b0 & FIN != 0,
+            opcode: b0 & 0x0f,
+            mask,
+            // Lengths that don't fit in usize are too big for the buffer anyway.
+            len: usize::try_from(len).unwrap_or(usize::MAX),
+        })
+    }
+
+    /// Read a payload of `len` bytes to `buf[at..]`. `at + len` must fit in `buf`.
+    async fn read_payload(&mut self, at: usize, len: usize) -> Result<(), Error> {
+        let have = len.min(self.end - self.start);
+        self.buf.copy_within(self.start..self.start + have, at);
+        self.start += have;
+        if have < len {
+            // No received data is left, so the rest is read in place.
+            self.io
+                .read_exact(&mut self.buf[at + have..at + len])
+                .await
+                .map_err(read_exact_error)?;
+            self.start = at + len;
+            self.end = at + len;
+        }
+        Ok(())
+    }
+
+    /// Make sure at least `need` received bytes are available, moving them after the first `keep` bytes of `buf` if needed.
+    async fn fill(&mut self, keep: usize, need: usize) -> Result<(), Error> {
+        while self.end - self.start < need {
+            if self.start + need > self.buf.len() {
+                if keep + need > self.buf.len() {
+                    return Err(self.fail(CLOSE_TOO_BIG, Error::BufferTooSmall).await);
+                }
+                self.buf.copy_within(self.start..self.end, keep);
+                self.end -= self.start - keep;
+                self.start = keep;
+            }
+            let n = self.io.read(&mut self.buf[self.end..]).await.map_err(io_error)?;
+            if n == 0 {
+                self.state = State::Closed;
+                return Err(Error::Io(ErrorKind::BrokenPipe));
+            }
+            self.end += n;
+        }
+        Ok(())
+    }
+
+    /// Read an HTTP head at the start of `buf`. Returns its length, the data after it is kept in `buf[..end]`.
+    async fn read_head(&mut self) -> Result<usize, Error> {
+        loop {
+            if let Some(pos) = self.buf[..self.end].windows(4).position(|w| w == b"\r\n\r\n") {
+                return Ok(pos + 4);
+            }
+            if self.end == self.buf.len() {
+                return Err(Error::BufferTooSmall);
+            }
+            let n = self.io.read(&mut self.buf[self.end..]).await.map_err(io_error)?;
+            if n == 0 {
+                return Err(Error::Io(ErrorKind::BrokenPipe));
+            }
+            self.end += n;
+        }
+    }
+
+    async fn write_head<'h>(
+        &mut self,
+        first_line: &[&str],
+        headers: impl Iterator<Item = &'h (&'h str, &'h str)>,
+    ) -> Result<(), Error> {
+        for part in first_line {
+            self.io.write_all(part.as_bytes()).await.map_err(io_error)?;
+        }
+        self.io.write_all(b"\r\n").await.map_err(io_error)?;
+        for (name, value) in headers {
+            for part in [name.as_bytes(), b": ", value.as_bytes(), b"\r\n"] {
+                self.io.write_all(part).await.map_err(io_error)?;
+            }
+        }
+        self.io.write_all(b"\r\n").await.map_err(io_error)?;
+        self.io.flush().await.map_err(io_error)
+    }
+}
+
+fn opcode(message_type: MessageType) -> u8 {
+    match message_type {
+        MessageType::Text => OP_TEXT,
+        MessageType::Binary => OP_BINARY,
+    }
+}
+
+/// XOR `data` with the masking `key`, `offset` being the position of `data` in the payload.
+fn apply_mask(data: &mut [u8], key: [u8; 4], offset: usize) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= key[(offset + i) % 4];
+    }
+}
+
+/// Get the value of the header `name` in an HTTP head.
+fn header<'h>(head: &'h str, name: &str) -> Option<&'h str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// Whether a comma-separated header value contains `token`, ignoring case.
+fn has_token(value: &str, token: &str) -> bool {
+    value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Check that a request is a WebSocket handshake, and get its `Sec-WebSocket-Key`.
+pub(crate) fn handshake_key<'h>(get: bool, header: impl Fn(&str) -> Option<&'h str>) -> Option<&'h str> {
+    let valid = get
+        && header("Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        && header("Connection").is_some_and(|v| has_token(v, "upgrade"))
+        && header("Sec-WebSocket-Version") == Some("13");
+    valid.then(|| header("Sec-WebSocket-Key")).flatten()
+}
+
+/// The headers of the response accepting a handshake.
+pub(crate) fn upgrade_headers(accept: &str) -> [(&str, &str); 3] {
+    [
+        ("Upgrade", "websocket"),
+        ("Connection", "Upgrade"),
+        ("Sec-WebSocket-Accept", accept),
+    ]
+}
+
+/// Compute the `Sec-WebSocket-Accept` value answering a `Sec-WebSocket-Key`.
+pub(crate) fn accept_key<'o>(key: &str, out: &'o mut [u8; 28]) -> &'o str {
+    base64(&sha1(&[key.as_bytes(), GUID]), out)
+}
+
+fn base64<'o>(input: &[u8], out: &'o mut [u8]) -> &'o str {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut n = 0;
+    for chunk in input.chunks(3) {
+        let v = u32::from_be_bytes([
+            0,
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ]);
+        for i in 0..4 {
+            out[n + i] = if i <= chunk.len() {
+                ALPHABET[(v >> (18 - 6 * i)) as usize & 0x3f]
+            } else {
+                b'='
+            };
+        }
+        n += 4;
+    }
+    // Only characters of the base64 alphabet were written, so this never fails.
+    core::str::from_utf8(&out[..n]).unwrap_or("")
+}
+
+/// SHA-1 of the concatenation of `parts`. Only used for the handshake, where its weaknesses don't matter.
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut block = [0; 64];
+    let mut fill = 0;
+    let mut len: u64 = 0;
+    for &b in parts.iter().flat_map(|p| p.iter()) {
+        block[fill] = b;
+        fill += 1;
+        len += 1;
+        if fill == 64 {
+            sha1_block(&mut h, &block);
+            fill = 0;
+        }
+    }
+
+    block[fill] = 0x80;
+    block[fill + 1..].fill(0);
+    if fill >= 56 {
+        sha1_block(&mut h, &block);
+        block.fill(0);
+    }
+    block[56..].copy_from_slice(&(len * 8).to_be_bytes());
+    sha1_block(&mut h, &block);
+
+    let mut out = [0; 20];
+    for (o, h) in out.chunks_exact_mut(4).zip(h) {
+        o.copy_from_slice(&h.to_be_bytes());
+    }
+    out
+}
+
+fn sha1_block(h: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (w, b) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *w = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *h;
+    for (i, w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5A827999),
+            20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+        let t = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = t;
+    }
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+        *h = h.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::pipe::Pipe;
+
+    use super::*;
+
+    type Buffer = Pipe<NoopRawMutex, 4096>;
+
+    /// In-memory stream. Reading past the data written so far is an end of stream.
+    struct Stream<'a> {
+        rx: &'a Buffer,
+        tx: &'a Buffer,
+    }
+
+    impl embedded_io_async::ErrorType for Stream<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Stream<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            Ok(self.rx.try_read(buf).unwrap_or(0))
+        }
+    }
+
+    impl Write for Stream<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            Ok(self.tx.try_write(buf).unwrap())
+        }
+    }
+
+    /// Generator returning the same number every time, so that masking keys are known.
+    struct Constant(u32);
+
+    impl RngCore for Constant {
+        fn next_u32(&mut self) -> u32 {
+            self.0
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 as u64
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(self.0 as u8);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    const KEY: Option<[u8; 4]> = Some([0x37, 0xfa, 0x21, 0x3d]);
+
+    fn frame(opcode: u8, fin: bool, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let mut frame = std::vec![if fin { FIN | opcode } else { opcode }];
+        let mask_bit = if mask.is_some() { MASKED } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(key) => {
+                frame.extend_from_slice(&key);
+                frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            None => frame.extend_from_slice(payload),
+        }
+        frame
+    }
+
+    /// Masked frame header with a 64-bit length, whatever the length.
+    fn long_header(opcode: u8, fin: bool, len: u64) -> Vec<u8> {
+        let mut header = std::vec![if fin { FIN | opcode } else { opcode }, MASKED | 127];
+        header.extend_from_slice(&len.to_be_bytes());
+        header.extend_from_slice(&KEY.unwrap());
+        header
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        frame(OP_CLOSE, true, None, &code.to_be_bytes())
+    }
+
+    /// Take the data written to `buffer`.
+    fn written(buffer: &Buffer) -> Vec<u8> {
+        let mut data = std::vec![0; buffer.len()];
+        let n = buffer.try_read(&mut data).unwrap_or(0);
+        data.truncate(n);
+        data
+    }
+
+    /// Server side of a new connection whose handshake is done. The client writes to `rx`.
+    fn server<'a>(rx: &'a Buffer, tx: &'a Buffer, buf: &'a mut [u8]) -> WebSocket<'a, Stream<'a>, Server> {
+        // Drop the data left by the previous connection.
+        rx.clear();
+        tx.clear();
+        WebSocket::new(Stream { rx, tx }, buf, Server { _private: () })
+    }
+
+    #[test]
+    fn handshake() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 256];
+        block_on(async {
+            // Example from RFC 6455 section 1.3, followed by the masked "Hello" from section 5.7.
+            rx.try_write(
+                b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                  Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n\
+                  \x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58",
+            )
+            .unwrap();
+            let mut ws = WebSocket::accept(Stream { rx: &rx, tx: &tx }, &mut buf).await.unwrap();
+            assert_eq!(
+                written(&tx),
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+            );
+            assert_eq!(ws.receive().await, Ok(Message::Text("Hello")));
+        });
+
+        // Requests that aren't handshakes are rejected.
+        let mut buf = [0; 256];
+        block_on(async {
+            rx.try_write(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let result = WebSocket::accept(Stream { rx: &rx, tx: &tx }, &mut buf).await;
+            assert!(matches!(result, Err(Error::Handshake)));
+            assert!(written(&tx).starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        });
+
+        // The client checks the accept key, computed from its random nonce.
+        let mut buf = [0; 256];
+        block_on(async {
+            let mut key = [0; 24];
+            let key = base64(&[0x42; 16], &mut key);
+            let mut accept = [0; 28];
+            let accept = accept_key(key, &mut accept);
+            for (accept, ok) in [(accept, true), ("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", false)] {
+                let response = std::format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept
+                );
+                rx.try_write(response.as_bytes()).unwrap();
+                let config = ClientConfig::new("example.com", "/");
+                let result = WebSocket::connect(Stream { rx: &rx, tx: &tx }, &mut buf, Constant(0x42), &config).await;
+                assert_eq!(result.is_ok(), ok);
+                let request = written(&tx);
+                assert!(request.starts_with(b"GET / HTTP/1.1\r\nHost: example.com\r\n"));
+                assert!(request.windows(key.len()).any(|w| w == key.as_bytes()));
+            }
+
+            rx.try_write(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let config = ClientConfig::new("example.com", "/");
+            let result = WebSocket::connect(Stream { rx: &rx, tx: &tx }, &mut buf, Constant(0x42), &config).await;
+            assert!(matches!(result, Err(Error::Rejected(404))));
+        });
+    }
+
+    #[test]
+    fn masking() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 256];
+        let key = 0x0403_0201u32;
+        let mut ws = WebSocket::new(Stream { rx: &rx, tx: &tx }, &mut buf, Client { rng: Constant(key) });
+        block_on(async {
+            // Client frames are masked, over several chunks of the payload.
+            let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+            ws.send_binary(&payload).await.unwrap();
+            assert_eq!(written(&tx), frame(OP_BINARY, true, Some(key.to_ne_bytes()), &payload));
+
+            // Servers must not mask their frames, and clients must.
+            rx.try_write(&frame(OP_TEXT, true, None, b"hi")).unwrap();
+            assert_eq!(ws.receive().await, Ok(Message::Text("hi")));
+            rx.try_write(&frame(OP_TEXT, true, KEY, b"hi")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+            assert_eq!(
+                written(&tx),
+                frame(
+                    OP_CLOSE,
+                    true,
+                    Some(key.to_ne_bytes()),
+                    &CLOSE_PROTOCOL_ERROR.to_be_bytes()
+                )
+            );
+        });
+
+        let mut buf = [0; 256];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            ws.send_text("hi").await.unwrap();
+            assert_eq!(written(&tx), frame(OP_TEXT, true, None, b"hi"));
+            rx.try_write(&frame(OP_TEXT, true, None, b"hi")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+            assert_eq!(written(&tx), close_frame(CLOSE_PROTOCOL_ERROR));
+        });
+    }
+
+    #[test]
+    fn frame_headers() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 1024];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            // 7-bit, 16-bit and 64-bit lengths.
+            for len in [0, 125, 126, 1000] {
+                let payload = std::vec![0xa5; len];
+                rx.try_write(&frame(OP_BINARY, true, KEY, &payload)).unwrap();
+                assert_eq!(ws.receive().await, Ok(Message::Binary(&payload[..])));
+            }
+            let mut data = long_header(OP_BINARY, true, 3);
+            data.extend(b"abc".iter().enumerate().map(|(i, b)| b ^ KEY.unwrap()[i]));
+            rx.try_write(&data).unwrap();
+            assert_eq!(ws.receive().await, Ok(Message::Binary(b"abc")));
+            assert_eq!(written(&tx), b"");
+        });
+
+        // Reserved bits must be clear.
+        let mut buf = [0; 1024];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            let mut data = frame(OP_TEXT, true, KEY, b"x");
+            data[0] |= 0x40;
+            rx.try_write(&data).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+            assert_eq!(written(&tx), close_frame(CLOSE_PROTOCOL_ERROR));
+        });
+
+        // Unknown opcodes.
+        let mut buf = [0; 1024];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(0x3, true, KEY, b"x")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+        });
+    }
+
+    #[test]
+    fn oversized_frames() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+
+        // The most significant bit of 64-bit lengths must be clear.
+        let mut buf = [0; 64];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&long_header(OP_BINARY, true, u64::MAX)).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+            assert_eq!(written(&tx), close_frame(CLOSE_PROTOCOL_ERROR));
+            assert_eq!(ws.send_text("x").await, Err(Error::Closed(None)));
+        });
+
+        // A frame bigger than the buffer.
+        let mut buf = [0; 64];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(OP_BINARY, true, KEY, &[0; 65])).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::BufferTooSmall));
+            assert_eq!(written(&tx), close_frame(CLOSE_TOO_BIG));
+        });
+
+        // Fragments whose total length overflows, with the largest valid length.
+        let mut buf = [0; 64];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(OP_BINARY, false, KEY, &[0; 10])).unwrap();
+            rx.try_write(&long_header(OP_CONTINUATION, true, u64::MAX >> 1))
+                .unwrap();
+            assert_eq!(ws.receive().await, Err(Error::BufferTooSmall));
+            assert_eq!(written(&tx), close_frame(CLOSE_TOO_BIG));
+        });
+    }
+
+    #[test]
+    fn fragmentation() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 256];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            // Fragments are reassembled, and may split UTF-8 characters.
+            rx.try_write(&frame(OP_TEXT, false, KEY, b"h\xc3")).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, false, KEY, b"\xa9llo ")).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, true, KEY, b"world")).unwrap();
+            assert_eq!(ws.receive().await, Ok(Message::Text("h\u{e9}llo world")));
+
+            ws.send_fragment(MessageType::Binary, b"a", false).await.unwrap();
+            ws.send_fragment(MessageType::Text, b"b", false).await.unwrap();
+            ws.send_fragment(MessageType::Text, b"c", true).await.unwrap();
+            let mut expected = frame(OP_BINARY, false, None, b"a");
+            expected.extend(frame(OP_CONTINUATION, false, None, b"b"));
+            expected.extend(frame(OP_CONTINUATION, true, None, b"c"));
+            assert_eq!(written(&tx), expected);
+
+            // Invalid UTF-8 is only detected once the message is complete.
+            rx.try_write(&frame(OP_TEXT, false, KEY, b"\xc3")).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, true, KEY, b"(")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+            assert_eq!(written(&tx), close_frame(CLOSE_INVALID_DATA));
+        });
+
+        // A continuation without a message to continue.
+        let mut buf = [0; 256];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(OP_CONTINUATION, true, KEY, b"x")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+        });
+
+        // A new message in the middle of a fragmented one.
+        let mut buf = [0; 256];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(OP_BINARY, false, KEY, b"x")).unwrap();
+            rx.try_write(&frame(OP_TEXT, true, KEY, b"y")).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Protocol));
+        });
+    }
+
+    #[test]
+    fn control_frames_between_fragments() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 200];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            // Pings are answered, and pongs ignored, while reassembling a message bigger than 64
+            // bytes so that data is moved around.
+            let part = [b'x'; 90];
+            rx.try_write(&frame(OP_BINARY, false, KEY, &part)).unwrap();
+            rx.try_write(&frame(OP_PING, true, KEY, b"ping!")).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, false, KEY, &part)).unwrap();
+            rx.try_write(&frame(OP_PONG, true, KEY, b"pong")).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, true, KEY, b"end")).unwrap();
+            let Ok(Message::Binary(message)) = ws.receive().await else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(message.len(), 183);
+            assert!(message.ends_with(b"xxend"));
+            assert_eq!(written(&tx), frame(OP_PONG, true, None, b"ping!"));
+
+            // A ping that doesn't fit after the message being reassembled is read separately.
+            rx.try_write(&frame(OP_BINARY, false, KEY, &[b'y'; 190])).unwrap();
+            rx.try_write(&frame(OP_PING, true, KEY, &[b'p'; 20])).unwrap();
+            rx.try_write(&frame(OP_CONTINUATION, true, KEY, b"z")).unwrap();
+            let Ok(Message::Binary(message)) = ws.receive().await else {
+                panic!("expected a binary message");
+            };
+            assert_eq!(message.len(), 191);
+            assert_eq!(written(&tx), frame(OP_PONG, true, None, &[b'p'; 20]));
+
+            // A close frame ends the message, and is answered.
+            rx.try_write(&frame(OP_TEXT, false, KEY, b"abc")).unwrap();
+            rx.try_write(&frame(OP_CLOSE, true, KEY, &CLOSE_GOING_AWAY.to_be_bytes()))
+                .unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Closed(Some(CLOSE_GOING_AWAY))));
+            assert_eq!(written(&tx), close_frame(CLOSE_GOING_AWAY));
+            assert_eq!(ws.receive().await, Err(Error::Closed(None)));
+        });
+
+        // Control frames can't be fragmented, or longer than 125 bytes.
+        for data in [
+            frame(OP_PING, false, KEY, b"x"),
+            frame(OP_PING, true, KEY, &[0; 126]),
+            frame(OP_CLOSE, true, KEY, &[0x03]),
+        ] {
+            let mut buf = [0; 200];
+            let mut ws = server(&rx, &tx, &mut buf);
+            block_on(async {
+                rx.try_write(&frame(OP_BINARY, false, KEY, b"x")).unwrap();
+                rx.try_write(&data).unwrap();
+                assert_eq!(ws.receive().await, Err(Error::Protocol));
+                assert_eq!(written(&tx), close_frame(CLOSE_PROTOCOL_ERROR));
+            });
+        }
+    }
+
+    #[test]
+    fn closing_handshake() {
+        let (rx, tx) = (Buffer::new(), Buffer::new());
+        let mut buf = [0; 200];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            // Messages received before the peer's close frame are discarded.
+            rx.try_write(&frame(OP_TEXT, true, KEY, b"late")).unwrap();
+            rx.try_write(&frame(OP_CLOSE, true, KEY, &CLOSE_NORMAL.to_be_bytes()))
+                .unwrap();
+            assert_eq!(ws.close(CLOSE_NORMAL, "bye").await, Ok(()));
+            let mut expected = CLOSE_NORMAL.to_be_bytes().to_vec();
+            expected.extend_from_slice(b"bye");
+            assert_eq!(written(&tx), frame(OP_CLOSE, true, None, &expected));
+            assert_eq!(ws.send_text("x").await, Err(Error::Closed(None)));
+        });
+
+        // The stream ending without a close frame.
+        let mut buf = [0; 200];
+        let mut ws = server(&rx, &tx, &mut buf);
+        block_on(async {
+            rx.try_write(&frame(OP_TEXT, true, KEY, b"ab")[..4]).unwrap();
+            assert_eq!(ws.receive().await, Err(Error::Io(ErrorKind::BrokenPipe)));
+        });
+    }
+
+    #[test]
+    fn mask() {
+        let key = [1, 2, 3, 4];
+        let mut data = [0; 10];
+        apply_mask(&mut data[..3], key, 0);
+        apply_mask(&mut data[3..], key, 3);
+        assert_eq!(data, [1, 2, 3, 4, 1, 2, 3, 4, 1, 2]);
+    }
+}
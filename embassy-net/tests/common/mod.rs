@@ -0,0 +1,124 @@
+//! In-memory network link between two stacks.
+#![allow(dead_code)]
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::task::Context;
+
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources, StaticConfigV4};
+use embassy_net_driver::{Capabilities, Driver, HardwareAddress, LinkState, RxToken, TxToken};
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// One end of a link. Packets transmitted by one end are received by the other.
+pub struct Loopback {
+    /// Packets waiting to be received by this end.
+    pub rx: Queue,
+    /// Packets transmitted by this end.
+    pub tx: Queue,
+    /// Link state reported to the stack.
+    pub link_up: Rc<Cell<bool>>,
+    hardware_address: HardwareAddress,
+}
+
+/// Create a link between two IP interfaces.
+pub fn pair() -> (Loopback, Loopback) {
+    let a = Queue::default();
+    let b = Queue::default();
+    (
+        Loopback {
+            rx: a.clone(),
+            tx: b.clone(),
+            link_up: Rc::new(Cell::new(true)),
+            hardware_address: HardwareAddress::Ip,
+        },
+        Loopback {
+            rx: b,
+            tx: a,
+            link_up: Rc::new(Cell::new(true)),
+            hardware_address: HardwareAddress::Ip,
+        },
+    )
+}
+
+/// Create a link between two Ethernet interfaces, with the MAC addresses `02:00:00:00:00:01` and
+/// `02:00:00:00:00:02`.
+pub fn ethernet_pair() -> (Loopback, Loopback) {
+    let (mut a, mut b) = pair();
+    a.hardware_address = HardwareAddress::Ethernet([2, 0, 0, 0, 0, 1]);
+    b.hardware_address = HardwareAddress::Ethernet([2, 0, 0, 0, 0, 2]);
+    (a, b)
+}
+
+/// Static configuration with the address `10.0.0.<last>/24`.
+pub fn ipv4_config(last: u8) -> Config {
+    Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(10, 0, 0, last), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    })
+}
+
+/// Leak stack resources, so that the stack can be used for the rest of the test.
+pub fn resources<const SOCK: usize>() -> &'static mut StackResources<SOCK> {
+    Box::leak(Box::new(StackResources::new()))
+}
+
+/// Leak stack resources for a stack with several interfaces.
+pub fn multi_resources<const SOCK: usize, const IFACES: usize>() -> &'static mut StackResources<SOCK, IFACES> {
+    Box::leak(Box::new(StackResources::new()))
+}
+
+pub struct Rx(Vec<u8>);
+
+pub struct Tx(Queue);
+
+impl RxToken for Rx {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(mut self, f: F) -> R {
+        f(&mut self.0)
+    }
+}
+
+impl TxToken for Tx {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let r = f(&mut packet);
+        self.0.borrow_mut().push_back(packet);
+        r
+    }
+}
+
+impl Driver for Loopback {
+    type RxToken<'a> = Rx;
+    type TxToken<'a> = Tx;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Rx, Tx)> {
+        // The other end doesn't know how to wake this one, so keep polling.
+        cx.waker().wake_by_ref();
+        let packet = self.rx.borrow_mut().pop_front()?;
+        Some((Rx(packet), Tx(self.tx.clone())))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Tx> {
+        cx.waker().wake_by_ref();
+        Some(Tx(self.tx.clone()))
+    }
+
+    fn link_state(&mut self, _cx: &mut Context) -> LinkState {
+        match self.link_up.get() {
+            true => LinkState::Up,
+            false => LinkState::Down,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut caps = Capabilities::default();
+        caps.max_transmission_unit = 1500;
+        caps
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        self.hardware_address
+    }
+}
@@ -0,0 +1,98 @@
+#![cfg(all(
+    feature = "filter",
+    feature = "stats",
+    feature = "udp",
+    feature = "proto-ipv4",
+    feature = "medium-ip"
+))]
+
+mod common;
+
+use embassy_futures::block_on;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::filter::{Action, Direction, IpProtocol, Rule, RuleTable};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Ipv4Cidr};
+use embassy_time::{Duration, Timer};
+
+#[test]
+fn rules() {
+    let (device_a, device_b) = common::pair();
+    let (stack_a, mut runner_a) = embassy_net::new(device_a, common::ipv4_config(1), common::resources::<4>(), 1);
+    let (stack_b, mut runner_b) = embassy_net::new(device_b, common::ipv4_config(2), common::resources::<4>(), 2);
+
+    // `a` transmits everything, and only receives UDP to ports 1000-1001 from its subnet.
+    let table_a: &'static RuleTable<2> = Box::leak(Box::new(RuleTable::new(Action::Drop)));
+    table_a
+        .push(Rule::new(Action::Accept).direction(Direction::Tx))
+        .unwrap();
+    table_a
+        .push(
+            Rule::new(Action::Accept)
+                .protocol(IpProtocol::Udp)
+                .src(Ipv4Cidr::new(Ipv4Address::new(10, 0, 0, 0), 24).into())
+                .dst_ports(1000, 1001),
+        )
+        .unwrap();
+    runner_a.set_filter(Some(table_a));
+    // `b` never transmits to port 2000.
+    let table_b: &'static RuleTable<1> = Box::leak(Box::new(RuleTable::new(Action::Accept)));
+    table_b
+        .push(Rule::new(Action::Drop).direction(Direction::Tx).dst_port(2000))
+        .unwrap();
+    runner_b.set_filter(Some(table_b));
+
+    let test = async {
+        let mut buffers: [_; 4] = core::array::from_fn(|_| {
+            (
+                [PacketMetadata::EMPTY; 4],
+                [0; 256],
+                [PacketMetadata::EMPTY; 4],
+                [0; 256],
+            )
+        });
+        let [(m0, b0, m1, b1), (m2, b2, m3, b3), (m4, b4, m5, b5), (m6, b6, m7, b7)] = &mut buffers;
+        let mut accepted = UdpSocket::new(stack_a, m0, b0, m1, b1);
+        accepted.bind(1001).unwrap();
+        let mut blocked = UdpSocket::new(stack_a, m2, b2, m3, b3);
+        blocked.bind(1500).unwrap();
+        let mut unsent = UdpSocket::new(stack_a, m4, b4, m5, b5);
+        unsent.bind(2000).unwrap();
+        let mut b = UdpSocket::new(stack_b, m6, b6, m7, b7);
+        b.bind(5000).unwrap();
+
+        let a_addr = Ipv4Address::new(10, 0, 0, 1);
+        b.send_to(b"blocked", (a_addr, 1500)).await.unwrap();
+        b.send_to(b"unsent", (a_addr, 2000)).await.unwrap();
+        b.send_to(b"hello", (a_addr, 1001)).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, _) = accepted.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        accepted
+            .send_to(b"back", (Ipv4Address::new(10, 0, 0, 2), 5000))
+            .await
+            .unwrap();
+        let (n, _) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"back");
+
+        Timer::after_millis(50).await;
+        assert!(!blocked.may_recv() && !unsent.may_recv());
+
+        // The frame dropped by `b` never reached `a`: it would have matched no rule there.
+        let counters = table_a.counters();
+        assert_eq!(counters.rx_dropped, 1);
+        assert_eq!(counters.default_hits, 1);
+        assert_eq!(table_a.hits(1), Some(1));
+        assert_eq!(table_b.counters().tx_dropped, 1);
+        assert_eq!(stack_a.stats().rx_filtered, 1);
+        assert_eq!(stack_b.stats().tx_filtered, 1);
+        assert_eq!(stack_b.stats().tx_packets, 2);
+    };
+
+    let r = block_on(select3(
+        runner_a.run(),
+        runner_b.run(),
+        select(test, Timer::after(Duration::from_secs(5))),
+    ));
+    assert!(matches!(r, Either3::Third(Either::First(()))), "timed out");
+}
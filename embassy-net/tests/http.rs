@@ -0,0 +1,147 @@
+#![cfg(all(feature = "http-server", feature = "proto-ipv4", feature = "medium-ip"))]
+
+mod common;
+
+use std::future::Future;
+
+use embassy_futures::block_on;
+use embassy_futures::select::{select, select3};
+use embassy_net::http::{Completed, Error, Handler, HttpServer, Method, Request, Response, Router, ServerConfig};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Ipv4Address, Stack};
+use embedded_io_async::Write;
+
+const SERVER: (Ipv4Address, u16) = (Ipv4Address::new(10, 0, 0, 2), 80);
+
+struct Hello;
+
+impl Handler for Hello {
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        let body = format!("hello {}", request.query.unwrap_or(""));
+        response
+            .send(200, &[("Content-Type", "text/plain")], body.as_bytes())
+            .await
+    }
+}
+
+struct Echo;
+
+impl Handler for Echo {
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        let mut writer = response.start_chunked(200, &[]).await?;
+        writer.write_all(request.body).await?;
+        writer.write_all(request.path.as_bytes()).await?;
+        writer.finish().await
+    }
+}
+
+/// Send `request` on a new connection, and read the response until the server closes the connection.
+async fn exchange(stack: Stack<'_>, request: &[u8]) -> String {
+    let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+    let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+    socket.connect(SERVER).await.unwrap();
+    socket.write_all(request).await.unwrap();
+
+    let mut response = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        match socket.read(&mut buf).await.unwrap() {
+            0 => break,
+            n => response.extend_from_slice(&buf[..n]),
+        }
+    }
+    socket.close();
+    socket.flush().await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+/// Run an HTTP server on a stack, and `client` on another stack linked to it.
+fn run<F: Future<Output = ()>>(client: impl FnOnce(Stack<'static>) -> F) {
+    let (client_device, server_device) = common::pair();
+    let (client_stack, mut client_runner) =
+        embassy_net::new(client_device, common::ipv4_config(1), common::resources::<4>(), 1);
+    let (server_stack, mut server_runner) =
+        embassy_net::new(server_device, common::ipv4_config(2), common::resources::<4>(), 2);
+
+    let router = Router::new()
+        .route(Method::Get, "/", Hello)
+        .route(Method::Post, "/echo/*", Echo);
+    let server = Box::leak(Box::new(HttpServer::<2>::new(ServerConfig::default())));
+
+    block_on(select3(
+        client_runner.run(),
+        server_runner.run(),
+        select(server.run(server_stack, SERVER.1, &router), client(client_stack)),
+    ));
+}
+
+#[test]
+fn requests() {
+    run(|stack| async move {
+        // Pipelined requests on a kept-alive connection, with both kinds of bodies.
+        let response = exchange(
+            stack,
+            b"GET /?x=1 HTTP/1.1\r\nHost: a\r\n\r\n\
+              HEAD / HTTP/1.1\r\n\r\n\
+              POST /echo/z HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext\r\nde\r\n0\r\n\r\n\
+              GET /nope HTTP/1.1\r\n\r\n\
+              POST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nwxyz",
+        )
+        .await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nhello x=1\
+             HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\n\
+             HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabcde\r\n7\r\n/echo/z\r\n0\r\n\r\n\
+             HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nNot Found\
+             HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n4\r\nwxyz\r\n5\r\n/echo\r\n0\r\n\r\n"
+        );
+
+        // HTTP/1.0 connections are closed after the response.
+        let response = exchange(stack, b"GET / HTTP/1.0\r\n\r\n").await;
+        assert!(response.ends_with("Connection: close\r\n\r\nhello "), "{}", response);
+    });
+}
+
+#[test]
+fn rejected_requests() {
+    run(|stack| async move {
+        let cases: [(&[u8], &str); 6] = [
+            (b"GET /\r\n\r\n", "400 Bad Request"),
+            (b"POST /echo/ HTTP/1.1\r\nContent-Length: x\r\n\r\n", "400 Bad Request"),
+            (
+                b"POST /echo/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                "400 Bad Request",
+            ),
+            // Bodies larger than the request buffer, including lengths that overflow when added
+            // to the length of the head.
+            (
+                b"POST /echo/ HTTP/1.1\r\nContent-Length: 4096\r\n\r\n",
+                "413 Content Too Large",
+            ),
+            (
+                b"POST /echo/ HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n",
+                "413 Content Too Large",
+            ),
+            (
+                b"POST /echo/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+                "413 Content Too Large",
+            ),
+        ];
+        for (request, status) in cases {
+            let response = exchange(stack, request).await;
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{:?}: {}",
+                String::from_utf8_lossy(request),
+                response
+            );
+        }
+
+        // Heads larger than the request buffer.
+        let mut request = b"GET / HTTP/1.1\r\nX-Large: ".to_vec();
+        request.resize(3000, b'a');
+        let response = exchange(stack, &request).await;
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+    });
+}
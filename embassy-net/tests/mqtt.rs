@@ -0,0 +1,254 @@
+#![cfg(all(feature = "mqtt", feature = "proto-ipv4", feature = "medium-ip"))]
+
+mod common;
+
+use std::future::Future;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::mqtt::{ClientConfig, MqttBuffers, MqttClient, QoS, Version};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+
+const BROKER: (Ipv4Address, u16) = (Ipv4Address::new(10, 0, 0, 2), 1883);
+
+/// Read a whole packet.
+async fn read_packet(socket: &mut TcpSocket<'_>) -> Vec<u8> {
+    let mut packet = vec![0];
+    socket.read_exact(&mut packet).await.unwrap();
+    let mut len = 0;
+    for i in 0..4 {
+        let mut b = [0];
+        socket.read_exact(&mut b).await.unwrap();
+        packet.push(b[0]);
+        len |= ((b[0] & 0x7F) as usize) << (7 * i);
+        if b[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let start = packet.len();
+    packet.resize(start + len, 0);
+    socket.read_exact(&mut packet[start..]).await.unwrap();
+    packet
+}
+
+async fn write(socket: &mut TcpSocket<'_>, data: &[u8]) {
+    socket.write_all(data).await.unwrap();
+    socket.flush().await.unwrap();
+}
+
+/// Accept a connection, and return the CONNECT packet.
+async fn accept(socket: &mut TcpSocket<'_>) -> Vec<u8> {
+    socket.accept(BROKER.1).await.unwrap();
+    let connect = read_packet(socket).await;
+    assert_eq!(connect[0], 0x10);
+    // CONNACK, with no properties for MQTT 5.
+    if connect[8] == 5 {
+        write(socket, &[0x20, 3, 0, 0, 0]).await;
+    } else {
+        write(socket, &[0x20, 2, 0, 0]).await;
+    }
+    connect
+}
+
+/// Drop the connection.
+async fn abort(socket: &mut TcpSocket<'_>) {
+    socket.abort();
+    let _ = socket.flush().await;
+}
+
+/// Packet identifier of a PUBLISH packet with a topic of `topic_len` bytes and a 1-byte length.
+fn packet_id(publish: &[u8], topic_len: usize) -> [u8; 2] {
+    [publish[4 + topic_len], publish[5 + topic_len]]
+}
+
+fn client(stack: Stack<'static>, config: ClientConfig<'static>) -> MqttClient<'static> {
+    let buffers = Box::leak(Box::new(MqttBuffers::<1024, 256>::new()));
+    MqttClient::new(stack, BROKER, config, buffers)
+}
+
+/// Run `broker` on a stack, and `client` on another stack linked to it, until both complete.
+fn run<B: Future<Output = ()>, C: Future<Output = ()>>(
+    broker: impl FnOnce(TcpSocket<'static>) -> B,
+    client: impl FnOnce(Stack<'static>) -> C,
+) {
+    let (client_device, broker_device) = common::pair();
+    let (client_stack, mut client_runner) =
+        embassy_net::new(client_device, common::ipv4_config(1), common::resources::<4>(), 1);
+    let (broker_stack, mut broker_runner) =
+        embassy_net::new(broker_device, common::ipv4_config(2), common::resources::<4>(), 2);
+    let socket = TcpSocket::new(
+        broker_stack,
+        Box::leak(Box::new([0; 4096])),
+        Box::leak(Box::new([0; 4096])),
+    );
+
+    let result = block_on(select3(
+        client_runner.run(),
+        broker_runner.run(),
+        with_timeout(Duration::from_secs(10), join(broker(socket), client(client_stack))),
+    ));
+    assert!(matches!(result, Either3::Third(Ok(_))), "timed out");
+}
+
+#[test]
+fn qos1_and_keep_alive() {
+    run(
+        |mut s| async move {
+            let connect = accept(&mut s).await;
+            // Protocol level 4, clean session, keep-alive of 2 s, client identifier.
+            assert_eq!(connect[8..], [4, 0x02, 0, 2, 0, 4, b't', b'e', b's', b't']);
+
+            let subscribe = read_packet(&mut s).await;
+            assert_eq!(subscribe[0], 0x82);
+            assert_eq!(subscribe[4..], [0, 3, b'a', b'/', b'#', 1]);
+            // A message published before the SUBACK is kept for later.
+            write(&mut s, &[0x32, 10, 0, 3, b'a', b'/', b'b', 0, 7, b'h', b'i', b'!']).await;
+            write(&mut s, &[0x90, 3, subscribe[2], subscribe[3], 1]).await;
+            assert_eq!(read_packet(&mut s).await, [0x40, 2, 0, 7]);
+
+            let publish = read_packet(&mut s).await;
+            assert_eq!(publish[0], 0x32);
+            assert_eq!(publish[2..9], [0, 5, b'a', b'/', b'v', b'a', b'l']);
+            assert_eq!(publish[11..], *b"42");
+            let id = packet_id(&publish, 5);
+            write(&mut s, &[0x40, 2, id[0], id[1]]).await;
+
+            write(&mut s, &[0x30, 7, 0, 3, b'x', b'/', b'y', b'o', b'k']).await;
+
+            // A ping is sent when nothing was sent for half the keep-alive interval.
+            let pings = async {
+                loop {
+                    assert_eq!(read_packet(&mut s).await, [0xC0, 0]);
+                    write(&mut s, &[0xD0, 0]).await;
+                }
+            };
+            let _ = with_timeout(Duration::from_secs(3), pings).await;
+        },
+        |stack| async move {
+            let mut config = ClientConfig::new("test");
+            config.keep_alive = Duration::from_secs(2);
+            config.subscriptions = &[("a/#", QoS::AtLeastOnce)];
+            let mut c = client(stack, config);
+            c.connect().await.unwrap();
+            c.publish("a/val", b"42", QoS::AtLeastOnce, false).await.unwrap();
+
+            let m = c.receive().await.unwrap();
+            assert_eq!((m.topic, m.payload, m.qos), ("a/b", &b"hi!"[..], QoS::AtLeastOnce));
+            let m = c.receive().await.unwrap();
+            assert_eq!((m.topic, m.payload, m.qos), ("x/y", &b"ok"[..], QoS::AtMostOnce));
+
+            // Ping responses keep the connection up.
+            assert!(with_timeout(Duration::from_secs(3), c.receive()).await.is_err());
+            assert!(c.is_connected());
+        },
+    );
+}
+
+#[test]
+fn qos2() {
+    run(
+        |mut s| async move {
+            let connect = accept(&mut s).await;
+            assert_eq!(connect[8], 5);
+
+            // Client to broker.
+            let publish = read_packet(&mut s).await;
+            assert_eq!(publish[0], 0x34);
+            // Topic, packet identifier, no properties, payload.
+            assert_eq!(publish[2..5], [0, 1, b't']);
+            assert_eq!(publish[7..], [0, b'4', b'2']);
+            let id = packet_id(&publish, 1);
+            write(&mut s, &[0x50, 2, id[0], id[1]]).await;
+            assert_eq!(read_packet(&mut s).await, [0x62, 2, id[0], id[1]]);
+            write(&mut s, &[0x70, 2, id[0], id[1]]).await;
+
+            // Broker to client.
+            let message = [0x34, 8, 0, 1, b'u', 0, 9, 0, b'h', b'i'];
+            write(&mut s, &message).await;
+            assert_eq!(read_packet(&mut s).await, [0x50, 2, 0, 9]);
+            // Sent again before the release: acknowledged but not delivered again.
+            write(&mut s, &[message[0] | 0x08]).await;
+            write(&mut s, &message[1..]).await;
+            assert_eq!(read_packet(&mut s).await, [0x50, 2, 0, 9]);
+            write(&mut s, &[0x62, 2, 0, 9]).await;
+            assert_eq!(read_packet(&mut s).await, [0x70, 2, 0, 9]);
+            write(&mut s, &[0x30, 7, 0, 1, b'v', 0, b'e', b'n', b'd']).await;
+        },
+        |stack| async move {
+            let mut config = ClientConfig::new("test");
+            config.version = Version::V5;
+            let mut c = client(stack, config);
+            c.publish("t", b"42", QoS::ExactlyOnce, false).await.unwrap();
+
+            let m = c.receive().await.unwrap();
+            assert_eq!((m.topic, m.payload, m.qos), ("u", &b"hi"[..], QoS::ExactlyOnce));
+            let m = c.receive().await.unwrap();
+            assert_eq!((m.topic, m.payload, m.qos), ("v", &b"end"[..], QoS::AtMostOnce));
+        },
+    );
+}
+
+#[test]
+fn reconnect() {
+    run(
+        |mut s| async move {
+            accept(&mut s).await;
+            let publish = read_packet(&mut s).await;
+            assert_eq!(publish[0], 0x32);
+            abort(&mut s).await;
+
+            // The message is sent again with the DUP flag.
+            let connect = accept(&mut s).await;
+            // No clean session.
+            assert_eq!(connect[9], 0);
+            let publish = read_packet(&mut s).await;
+            assert_eq!(publish[0], 0x3A);
+            let id = packet_id(&publish, 1);
+            write(&mut s, &[0x40, 2, id[0], id[1]]).await;
+
+            // Once received at QoS 2, only the release is sent again.
+            let publish = read_packet(&mut s).await;
+            assert_eq!(publish[0], 0x34);
+            let id = packet_id(&publish, 1);
+            write(&mut s, &[0x50, 2, id[0], id[1]]).await;
+            assert_eq!(read_packet(&mut s).await, [0x62, 2, id[0], id[1]]);
+            abort(&mut s).await;
+
+            accept(&mut s).await;
+            assert_eq!(read_packet(&mut s).await, [0x62, 2, id[0], id[1]]);
+            write(&mut s, &[0x70, 2, id[0], id[1]]).await;
+        },
+        |stack| async move {
+            let mut config = ClientConfig::new("test");
+            config.clean_session = false;
+            config.min_backoff = Duration::from_millis(100);
+            config.timeout = Duration::from_secs(1);
+            let mut c = client(stack, config);
+            c.publish("t", b"1", QoS::AtLeastOnce, false).await.unwrap();
+            c.publish("t", b"2", QoS::ExactlyOnce, false).await.unwrap();
+        },
+    );
+}
+
+#[test]
+fn keep_alive_disabled() {
+    run(
+        |mut s| async move {
+            let connect = accept(&mut s).await;
+            assert_eq!(connect[10..12], [0, 0]);
+            assert!(with_timeout(Duration::from_secs(2), read_packet(&mut s)).await.is_err());
+        },
+        |stack| async move {
+            let mut config = ClientConfig::new("test");
+            config.keep_alive = Duration::from_secs(0);
+            let mut c = client(stack, config);
+            c.connect().await.unwrap();
+            assert!(with_timeout(Duration::from_secs(2), c.receive()).await.is_err());
+            assert!(c.is_connected());
+        },
+    );
+}
@@ -0,0 +1,329 @@
+#![cfg(all(feature = "tcp", feature = "udp", feature = "proto-ipv4", feature = "medium-ip"))]
+
+mod common;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::rc::Rc;
+
+use embassy_futures::block_on;
+use embassy_futures::join::{join, join3};
+use embassy_futures::select::select;
+use embassy_net::tcp::{AcceptError, ConnectError, TcpSocket};
+use embassy_net::udp::{BindError, PacketMetadata, UdpSocket};
+use embassy_net::{
+    BindInterfaceError, Config, InterfaceId, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Route, Stack, StaticConfigV4,
+};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+
+fn config(address: [u8; 4], prefix_len: u8, gateway: Option<[u8; 4]>) -> Config {
+    Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::from(address), prefix_len),
+        gateway: gateway.map(Ipv4Address::from),
+        dns_servers: Default::default(),
+    })
+}
+
+fn addr(a: [u8; 4]) -> IpAddress {
+    IpAddress::Ipv4(Ipv4Address::from(a))
+}
+
+fn cidr(a: [u8; 4], prefix_len: u8) -> IpCidr {
+    IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::from(a), prefix_len))
+}
+
+/// Link state switches of the interfaces of the stack, see [`run`].
+type Links = [Rc<Cell<bool>>; 3];
+
+/// Run `test` with a stack that has 3 interfaces:
+/// - 10.0.0.1/24, with a gateway,
+/// - 10.0.1.1/24, with a gateway,
+/// - 172.16.0.1/16, without gateway.
+///
+/// The far end of each link is a stack with the next address of the subnet.
+fn run<F: Future<Output = ()>>(test: impl FnOnce(Stack<'static>, [Stack<'static>; 3], Links) -> F) {
+    let [(d0, p0), (d1, p1), (d2, p2)] = [common::pair(), common::pair(), common::pair()];
+    let links = [d0.link_up.clone(), d1.link_up.clone(), d2.link_up.clone()];
+
+    let (stack, mut r0) = embassy_net::new(
+        d0,
+        config([10, 0, 0, 1], 24, Some([10, 0, 0, 254])),
+        common::multi_resources::<6, 3>(),
+        1,
+    );
+    let mut r1 = stack.add_interface(d1, config([10, 0, 1, 1], 24, Some([10, 0, 1, 254])));
+    let mut r2 = stack.add_interface(d2, config([172, 16, 0, 1], 16, None));
+
+    let (peer0, mut pr0) = embassy_net::new(p0, config([10, 0, 0, 2], 24, None), common::resources::<4>(), 2);
+    let (peer1, mut pr1) = embassy_net::new(p1, config([10, 0, 1, 2], 24, None), common::resources::<4>(), 3);
+    let (peer2, mut pr2) = embassy_net::new(p2, config([172, 16, 0, 2], 16, None), common::resources::<4>(), 4);
+
+    block_on(select(
+        join(
+            join3(r0.run(), r1.run(), r2.run()),
+            join3(pr0.run(), pr1.run(), pr2.run()),
+        ),
+        async {
+            for id in stack.interfaces() {
+                stack.interface(id).unwrap().wait_link_up().await;
+            }
+            test(stack, [peer0, peer1, peer2], links).await
+        },
+    ));
+}
+
+fn udp_socket(stack: Stack<'static>) -> UdpSocket<'static> {
+    UdpSocket::new(
+        stack,
+        Box::leak(Box::new([PacketMetadata::EMPTY; 4])),
+        Box::leak(Box::new([0; 1024])),
+        Box::leak(Box::new([PacketMetadata::EMPTY; 4])),
+        Box::leak(Box::new([0; 1024])),
+    )
+}
+
+fn tcp_socket(stack: Stack<'static>) -> TcpSocket<'static> {
+    TcpSocket::new(stack, Box::leak(Box::new([0; 1024])), Box::leak(Box::new([0; 1024])))
+}
+
+#[test]
+fn route_lookup() {
+    run(|stack, peers, links| async move {
+        let ids: Vec<InterfaceId> = stack.interfaces().collect();
+        assert_eq!(ids.len(), 3);
+        assert!(peers[0].interface(ids[2]).is_none());
+
+        // On-link routes.
+        assert_eq!(stack.route_to(addr([10, 0, 0, 5])), Some(ids[0]));
+        assert_eq!(stack.route_to(addr([10, 0, 1, 5])), Some(ids[1]));
+        assert_eq!(stack.route_to(addr([172, 16, 200, 1])), Some(ids[2]));
+        // The implicit default route of the first interface has the lowest metric.
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), Some(ids[0]));
+        assert_eq!(stack.default_interface(), ids[0]);
+
+        // The longest prefix wins, then the lowest metric.
+        for (destination, interface, metric) in [
+            (cidr([10, 0, 0, 0], 8), ids[2], 10),
+            (cidr([10, 0, 0, 0], 8), ids[1], 5),
+            (cidr([10, 2, 0, 0], 16), ids[2], 100),
+        ] {
+            stack
+                .add_route(Route {
+                    destination,
+                    interface,
+                    metric,
+                })
+                .unwrap();
+        }
+        assert_eq!(stack.route_to(addr([10, 0, 0, 5])), Some(ids[0]));
+        assert_eq!(stack.route_to(addr([10, 1, 0, 1])), Some(ids[1]));
+        assert_eq!(stack.route_to(addr([10, 2, 0, 1])), Some(ids[2]));
+
+        // Adding an existing route updates its metric.
+        stack
+            .add_route(Route {
+                destination: cidr([10, 0, 0, 0], 8),
+                interface: ids[2],
+                metric: 1,
+            })
+            .unwrap();
+        assert_eq!(stack.routes().len(), 3);
+        assert_eq!(stack.route_to(addr([10, 1, 0, 1])), Some(ids[2]));
+        assert!(stack.remove_route(cidr([10, 0, 0, 0], 8), ids[2]));
+        assert!(!stack.remove_route(cidr([10, 0, 0, 0], 8), ids[2]));
+        assert_eq!(stack.route_to(addr([10, 1, 0, 1])), Some(ids[1]));
+
+        // An explicit default route takes over the implicit ones.
+        stack
+            .add_route(Route {
+                destination: cidr([0, 0, 0, 0], 0),
+                interface: ids[2],
+                metric: 100,
+            })
+            .unwrap();
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), Some(ids[2]));
+        assert_eq!(stack.default_interface(), ids[2]);
+
+        // Routes through interfaces whose link is down are ignored.
+        links[2].set(false);
+        stack.interface(ids[2]).unwrap().wait_link_down().await;
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), Some(ids[0]));
+        assert_eq!(stack.route_to(addr([172, 16, 200, 1])), Some(ids[0]));
+        assert_eq!(stack.default_interface(), ids[0]);
+        links[0].set(false);
+        stack.interface(ids[0]).unwrap().wait_link_down().await;
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), Some(ids[1]));
+        links[1].set(false);
+        stack.interface(ids[1]).unwrap().wait_link_down().await;
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), None);
+        assert_eq!(stack.default_interface(), InterfaceId::PRIMARY);
+
+        links[2].set(true);
+        stack.interface(ids[2]).unwrap().wait_link_up().await;
+        assert_eq!(stack.route_to(addr([8, 8, 8, 8])), Some(ids[2]));
+    });
+}
+
+#[test]
+fn udp_interface() {
+    run(|stack, peers, _| async move {
+        let ids: Vec<InterfaceId> = stack.interfaces().collect();
+
+        // Without an address, the socket stays on the primary interface.
+        let mut socket = udp_socket(stack);
+        socket.bind(1000).unwrap();
+        assert_eq!(socket.interface(), InterfaceId::PRIMARY);
+        assert_eq!(socket.bind_interface(ids[1]), Err(BindInterfaceError::InvalidState));
+
+        let mut peer_sockets = peers.map(udp_socket);
+        for peer in &mut peer_sockets {
+            peer.bind(2000).unwrap();
+        }
+        let peer_addrs = [[10, 0, 0, 2], [10, 0, 1, 2], [172, 16, 0, 2]];
+        let local_addrs = [[10, 0, 0, 1], [10, 0, 1, 1], [172, 16, 0, 1]];
+
+        // Binding to an address attaches the socket to the interface that has it.
+        let mut sockets: Vec<UdpSocket> = local_addrs
+            .iter()
+            .map(|local| {
+                let mut socket = udp_socket(stack);
+                socket.bind((Ipv4Address::from(*local), 1001)).unwrap();
+                socket
+            })
+            .collect();
+        let interfaces: Vec<InterfaceId> = sockets.iter().map(|s| s.interface()).collect();
+        assert_eq!(interfaces, ids);
+
+        for ((socket, peer), (peer_addr, local)) in sockets
+            .iter_mut()
+            .zip(&peer_sockets)
+            .zip(peer_addrs.into_iter().zip(local_addrs))
+        {
+            socket.send_to(&peer_addr, (addr(peer_addr), 2000)).await.unwrap();
+            let mut buf = [0; 16];
+            let (n, meta) = with_timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(meta.endpoint.addr, addr(local));
+            peer.send_to(&buf[..n], meta).await.unwrap();
+
+            let (n, meta) = with_timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], &peer_addr);
+            assert_eq!(meta.endpoint.addr, addr(peer_addr));
+        }
+    });
+}
+
+#[test]
+fn tcp_interface() {
+    run(|stack, peers, _| async move {
+        let ids: Vec<InterfaceId> = stack.interfaces().collect();
+
+        let server = async {
+            let mut a = tcp_socket(stack);
+            let mut b = tcp_socket(stack);
+            let mut c = tcp_socket(stack);
+            c.bind_interface(ids[2]).unwrap();
+            let (ra, rb, rc) = join3(
+                // Without an address, the socket listens on the primary interface.
+                a.accept(80),
+                b.accept((Ipv4Address::new(10, 0, 1, 1), 80)),
+                c.accept(80),
+            )
+            .await;
+            ra.unwrap();
+            rb.unwrap();
+            rc.unwrap();
+            assert_eq!([a.interface(), b.interface(), c.interface()], [ids[0], ids[1], ids[2]]);
+
+            for socket in [&mut a, &mut b, &mut c] {
+                let mut buf = [0; 1];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+                socket.flush().await.unwrap();
+            }
+        };
+
+        let clients = async {
+            let mut sockets = [tcp_socket(peers[0]), tcp_socket(peers[1]), tcp_socket(peers[2])];
+            let remotes = [[10, 0, 0, 1], [10, 0, 1, 1], [172, 16, 0, 1]];
+            for (i, (socket, remote)) in sockets.iter_mut().zip(remotes).enumerate() {
+                socket.connect((addr(remote), 80)).await.unwrap();
+                socket.write_all(&[i as u8]).await.unwrap();
+            }
+            for (i, socket) in sockets.iter_mut().enumerate() {
+                let mut buf = [0; 1];
+                socket.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [i as u8]);
+            }
+        };
+
+        with_timeout(Duration::from_secs(5), join(server, clients))
+            .await
+            .unwrap();
+
+        // Connecting moves the socket to the interface of the route.
+        let listener = async {
+            let mut socket = tcp_socket(peers[2]);
+            socket.accept(90).await.unwrap();
+        };
+        let client = async {
+            let mut socket = tcp_socket(stack);
+            socket.connect((addr([172, 16, 0, 2]), 90)).await.unwrap();
+            assert_eq!(socket.interface(), ids[2]);
+        };
+        with_timeout(Duration::from_secs(5), join(listener, client))
+            .await
+            .unwrap();
+    });
+}
+
+#[test]
+fn no_free_slot() {
+    run(|stack, _, _| async move {
+        let ids: Vec<InterfaceId> = stack.interfaces().collect();
+
+        // Fill the socket set of the last interface.
+        let mut sockets = Vec::new();
+        loop {
+            let mut socket = udp_socket(stack);
+            match socket.bind_interface(ids[2]) {
+                Ok(()) => sockets.push(socket),
+                Err(e) => {
+                    assert_eq!(e, BindInterfaceError::NoFreeSlot);
+                    assert_eq!(socket.interface(), InterfaceId::PRIMARY);
+                    break;
+                }
+            }
+        }
+
+        let mut socket = udp_socket(stack);
+        assert_eq!(
+            socket.bind((Ipv4Address::new(172, 16, 0, 1), 1000)),
+            Err(BindError::NoFreeSlot)
+        );
+        let mut socket = tcp_socket(stack);
+        assert_eq!(
+            socket.connect((addr([172, 16, 0, 2]), 80)).await,
+            Err(ConnectError::NoFreeSlot)
+        );
+        assert_eq!(
+            socket.accept((Ipv4Address::new(172, 16, 0, 1), 80)).await,
+            Err(AcceptError::NoFreeSlot)
+        );
+        assert_eq!(socket.interface(), InterfaceId::PRIMARY);
+
+        // Closing a socket frees its slot.
+        sockets.pop();
+        let listener = async {
+            socket.accept((Ipv4Address::new(172, 16, 0, 1), 80)).await.unwrap();
+        };
+        with_timeout(Duration::from_millis(100), listener).await.unwrap_err();
+        assert_eq!(socket.interface(), ids[2]);
+    });
+}
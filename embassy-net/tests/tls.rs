@@ -0,0 +1,136 @@
+#![cfg(all(feature = "tls", feature = "proto-ipv4", feature = "medium-ip"))]
+
+mod common;
+
+use std::io::{Read as _, Write as _};
+use std::sync::Arc;
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::select3;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::tls::{Aes128GcmSha256, Provider, TlsBuffers, TlsConfig, TlsStream};
+use embassy_net::{Ipv4Address, Stack};
+use embedded_io_async::{Read, Write};
+use rand_core::{CryptoRng, RngCore};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection};
+
+/// Certificate of `example.com` and `*.example.com`, with an ECDSA P-256 key.
+const SERVER_CERT: &[u8] = include_bytes!("certs/server.der");
+const SERVER_KEY: &[u8] = include_bytes!("certs/server-key.der");
+
+const SERVER: (Ipv4Address, u16) = (Ipv4Address::new(10, 0, 0, 2), 443);
+
+/// Not random at all, but good enough for tests.
+struct TestRng(u64);
+
+impl RngCore for TestRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for TestRng {}
+
+fn server_config() -> Arc<ServerConfig> {
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(SERVER_CERT)],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(SERVER_KEY)),
+        )
+        .unwrap();
+    Arc::new(config)
+}
+
+/// Accept a TLS connection with rustls, and echo what is received until the client closes it.
+async fn echo_server(stack: Stack<'_>) {
+    let (mut rx, mut tx) = ([0; 8192], [0; 8192]);
+    let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+    socket.accept(SERVER.1).await.unwrap();
+
+    let mut conn = ServerConnection::new(server_config()).unwrap();
+    let mut buf = [0; 4096];
+    loop {
+        while conn.wants_write() {
+            let mut records = Vec::new();
+            conn.write_tls(&mut records).unwrap();
+            socket.write_all(&records).await.unwrap();
+        }
+
+        let n = socket.read(&mut buf).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        let mut received = &buf[..n];
+        while !received.is_empty() {
+            conn.read_tls(&mut received).unwrap();
+            let state = conn.process_new_packets().unwrap();
+            let mut data = vec![0; state.plaintext_bytes_to_read()];
+            conn.reader().read_exact(&mut data).unwrap();
+            conn.writer().write_all(&data).unwrap();
+        }
+    }
+    socket.close();
+    socket.flush().await.unwrap();
+}
+
+/// Connect to the echo server, and check that data is echoed back.
+async fn echo_client(stack: Stack<'_>) {
+    let mut buffers = Box::new(TlsBuffers::<4096>::new());
+    let (mut rx, mut tx) = ([0; 8192], [0; 8192]);
+    let socket = TcpSocket::new(stack, &mut rx, &mut tx);
+
+    let config = TlsConfig::new().with_server_name("example.com");
+    let provider = Provider::<Aes128GcmSha256, _, _>::dangerous_no_verification(TestRng(1));
+    let mut stream = TlsStream::connect(socket, &mut buffers, SERVER, &config, provider)
+        .await
+        .unwrap();
+
+    // More than fits in a record of the write buffer.
+    let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+    stream.write_all(&data).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut received = vec![0; data.len()];
+    stream.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, data);
+
+    let mut socket = stream.close().await.map_err(|(_, e)| e).unwrap();
+    socket.close();
+    socket.flush().await.unwrap();
+}
+
+#[test]
+fn handshake_with_rustls() {
+    let (client_device, server_device) = common::pair();
+    let (client_stack, mut client_runner) =
+        embassy_net::new(client_device, common::ipv4_config(1), common::resources::<2>(), 1);
+    let (server_stack, mut server_runner) =
+        embassy_net::new(server_device, common::ipv4_config(2), common::resources::<2>(), 2);
+
+    block_on(select3(
+        client_runner.run(),
+        server_runner.run(),
+        join(echo_server(server_stack), echo_client(client_stack)),
+    ));
+}
@@ -337,6 +337,7 @@ impl<'d, T: Instance> driver::Driver<'d> for Driver<'d, T> {
             Bus {
                 phantom: PhantomData,
                 inited: false,
+                disconnected: false,
                 ep_out: self.ep_out,
             },
             ControlPipe {
@@ -352,10 +353,30 @@ pub struct Bus<'d, T: Instance> {
     phantom: PhantomData<&'d mut T>,
     ep_out: [EndpointData; EP_COUNT],
     inited: bool,
+    /// D+ was released by `force_reset`, and must be pulled up again once the host has seen the disconnect.
+    disconnected: bool,
 }
 
 impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
     async fn poll(&mut self) -> Event {
+        if self.disconnected {
+            // Keep D+ released until the SIE sees the bus in SE0 long enough to flag a bus reset. That's
+            // longer than the hubs need to detect the disconnect, and doesn't need a timer.
+            poll_fn(|cx| {
+                BUS_WAKER.register(cx.waker());
+                let regs = T::regs();
+                if regs.sie_status().read().bus_reset() {
+                    regs.sie_status().write(|w| w.set_bus_reset(true));
+                    return Poll::Ready(());
+                }
+                regs.inte().write_set(|w| w.set_bus_reset(true));
+                Poll::Pending
+            })
+            .await;
+            T::regs().sie_ctrl().modify(|w| w.set_pullup_en(true));
+            self.disconnected = false;
+        }
+
         poll_fn(move |cx| {
             BUS_WAKER.register(cx.waker());
 
@@ -486,6 +507,17 @@ impl<'d, T: Instance> driver::Bus for Bus<'d, T> {
 
     async fn disable(&mut self) {}
 
+    fn force_reset(&mut self) -> Result<(), Unsupported> {
+        // Release D+, so that the host sees a disconnect and enumerates the device again. It's pulled
+        // up again by the next `poll`, without blocking here. Clear any stale bus reset first, as `poll`
+        // waits for the one caused by the disconnect.
+        let regs = T::regs();
+        regs.sie_status().write(|w| w.set_bus_reset(true));
+        regs.sie_ctrl().modify(|w| w.set_pullup_en(false));
+        self.disconnected = true;
+        Ok(())
+    }
+
     async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
         Err(Unsupported)
     }
@@ -538,6 +570,24 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, In> {
         .await;
         trace!("wait_enabled IN OK");
     }
+
+    async fn stall(&mut self) -> Result<(), Unsupported> {
+        let index = self.info.addr.index();
+        let ctrl = T::dpram().ep_in_buffer_control(index);
+        ctrl.modify(|w| w.set_stall(true));
+        // The bus clears the stall on CLEAR_FEATURE(ENDPOINT_HALT), and wakes the endpoint.
+        poll_fn(|cx| {
+            EP_IN_WAKERS[index].register(cx.waker());
+            let enabled = T::dpram().ep_in_control(index - 1).read().enable();
+            if enabled && ctrl.read().stall() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
@@ -560,6 +610,24 @@ impl<'d, T: Instance> driver::Endpoint for Endpoint<'d, T, Out> {
         .await;
         trace!("wait_enabled OUT OK");
     }
+
+    async fn stall(&mut self) -> Result<(), Unsupported> {
+        let index = self.info.addr.index();
+        let ctrl = T::dpram().ep_out_buffer_control(index);
+        ctrl.modify(|w| w.set_stall(true));
+        // The bus clears the stall on CLEAR_FEATURE(ENDPOINT_HALT), and wakes the endpoint.
+        poll_fn(|cx| {
+            EP_OUT_WAKERS[index].register(cx.waker());
+            let enabled = T::dpram().ep_out_control(index - 1).read().enable();
+            if enabled && ctrl.read().stall() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+        Ok(())
+    }
 }
 
 impl<'d, T: Instance> driver::EndpointOut for Endpoint<'d, T, Out> {
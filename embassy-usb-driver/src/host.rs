@@ -0,0 +1,107 @@
+//! Host driver traits.
+//!
+//! These are implemented by USB controllers that support host mode, and used by `embassy-usb-host`
+//! to enumerate and talk to the device connected to the root port.
+
+use crate::EndpointInfo;
+
+/// Speed of a device.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    /// Low speed, 1.5 Mbit/s.
+    Low,
+    /// Full speed, 12 Mbit/s.
+    Full,
+    /// High speed, 480 Mbit/s.
+    High,
+}
+
+/// Event returned by [`HostDriver::wait_for_device_event`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceEvent {
+    /// A device was connected to the root port, with the given speed.
+    Connected(Speed),
+    /// The device was disconnected from the root port.
+    Disconnected,
+}
+
+/// Main USB host driver trait.
+///
+/// Implement this to add support for the host mode of a new hardware platform. The driver manages
+/// a single root port, without hubs.
+pub trait HostDriver {
+    /// Type of the pipes for this driver.
+    type Pipe: Pipe;
+
+    /// Wait for a device to be connected to or disconnected from the root port.
+    ///
+    /// Connections should be reported once the connection is stable, after the debounce interval
+    /// of 100 ms.
+    async fn wait_for_device_event(&mut self) -> DeviceEvent;
+
+    /// Reset the bus, and enable the root port.
+    ///
+    /// It returns after the reset recovery time, when the device can be addressed at address 0.
+    async fn bus_reset(&mut self);
+
+    /// Allocate a pipe to an endpoint of the device at `address`.
+    ///
+    /// Control pipes are allocated with a `ep_type` of [`Control`](crate::EndpointType::Control),
+    /// and handle both directions. The other ones only handle the direction of their endpoint.
+    ///
+    /// Pipes are freed when dropped. Allocating a new control pipe is how the host starts using
+    /// the address it assigned to the device, or its actual max packet size.
+    fn alloc_pipe(&mut self, address: u8, endpoint: &EndpointInfo) -> Result<Self::Pipe, PipeAllocError>;
+}
+
+/// Pipe to an endpoint of a device.
+///
+/// The driver handles the data toggles and the retries of the transactions the device NAKs.
+pub trait Pipe {
+    /// Get the endpoint the pipe is for.
+    fn info(&self) -> &EndpointInfo;
+
+    /// Run a control transfer reading data from the device, with the SETUP packet `setup`.
+    ///
+    /// Returns the length of the data, which is at most the length of `buf`.
+    async fn control_in(&mut self, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize, PipeError>;
+
+    /// Run a control transfer writing `data` to the device, with the SETUP packet `setup`.
+    async fn control_out(&mut self, setup: &[u8; 8], data: &[u8]) -> Result<(), PipeError>;
+
+    /// Read a single packet from an IN endpoint, and return its length.
+    ///
+    /// Interrupt endpoints are polled at their interval until the device sends a packet.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, PipeError>;
+
+    /// Write a single packet to an OUT endpoint.
+    async fn write(&mut self, data: &[u8]) -> Result<(), PipeError>;
+}
+
+/// Allocating a pipe failed.
+///
+/// This can be due to running out of hardware channels, or because the hardware doesn't support
+/// the requested endpoint.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PipeAllocError;
+
+/// Errors returned by the transfers of a [`Pipe`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PipeError {
+    /// The device stalled the endpoint, or rejected the control request.
+    Stall,
+
+    /// The device didn't answer, or its answer was corrupted.
+    Timeout,
+
+    /// The received packet is too long to fit in `buf`, or the packet to be written is longer than
+    /// the max packet size.
+    BufferOverflow,
+
+    /// The device was disconnected.
+    Disconnected,
+}
@@ -3,6 +3,8 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+pub mod host;
+
 /// Direction of USB traffic. Note that in the USB standard the direction is always indicated from
 /// the perspective of the host, which is backward for devices, but the standard directions are used
 /// for consistency.
@@ -225,6 +227,21 @@ pub trait Endpoint {
 
     /// Wait for the endpoint to be enabled.
     async fn wait_enabled(&mut self);
+
+    /// Set the STALL condition on the endpoint, and wait for it to be cleared.
+    ///
+    /// Classes use this to report errors on their data endpoints. The condition is cleared when the
+    /// host sends a `CLEAR_FEATURE(ENDPOINT_HALT)` request, or when the endpoint is disabled.
+    ///
+    /// The default implementation just returns `Unsupported`.
+    ///
+    /// # Errors
+    ///
+    /// * [`Unsupported`](crate::Unsupported) - This driver doesn't support stalling endpoints
+    ///   outside of the [`Bus`].
+    async fn stall(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
 }
 
 /// OUT Endpoint trait.
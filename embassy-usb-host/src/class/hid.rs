@@ -0,0 +1,97 @@
+//! HID keyboard driver.
+//!
+//! This drives keyboards with the boot protocol, which every USB keyboard supports, so that the
+//! reports don't need parsing their report descriptor.
+
+use embassy_usb::class::hid::report::{KeyboardLeds, KeyboardModifiers, KeyboardReport};
+use embassy_usb_driver::host::{HostDriver, Pipe};
+use embassy_usb_driver::{Direction, EndpointType};
+
+use crate::{Device, Error, Recipient, Request, RequestType};
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_BOOT: u8 = 0x01;
+const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+
+const PROTOCOL_BOOT: u16 = 0;
+const REPORT_TYPE_OUTPUT: u16 = 2;
+
+const BOOT_REPORT_LEN: usize = 8;
+
+/// Keyboard using the boot protocol.
+pub struct Keyboard<'a, 'd, D: HostDriver> {
+    device: &'a mut Device<'d, D>,
+    interface: u8,
+    pipe: D::Pipe,
+}
+
+impl<'a, 'd, D: HostDriver> Keyboard<'a, 'd, D> {
+    /// Find the boot keyboard interface of `device`, and switch it to the boot protocol.
+    ///
+    /// Returns [`Error::Unsupported`] if the device isn't a keyboard.
+    pub async fn new(device: &'a mut Device<'d, D>) -> Result<Self, Error> {
+        let iface = device
+            .configuration()
+            .find_interface(USB_CLASS_HID, HID_SUBCLASS_BOOT, HID_PROTOCOL_KEYBOARD)
+            .ok_or(Error::Unsupported)?;
+        let ep = iface
+            .find_endpoint(EndpointType::Interrupt, Direction::In)
+            .ok_or(Error::Unsupported)?;
+        let interface = iface.number;
+
+        device
+            .control_out(class_request(HID_REQ_SET_PROTOCOL, PROTOCOL_BOOT, interface), &[])
+            .await?;
+        // Only report changes. Keyboards may not support this, so errors are ignored.
+        let _ = device
+            .control_out(class_request(HID_REQ_SET_IDLE, 0, interface), &[])
+            .await;
+
+        let pipe = device.alloc_pipe(&ep)?;
+        Ok(Self {
+            device,
+            interface,
+            pipe,
+        })
+    }
+
+    /// Wait for the keyboard to send a report, when keys are pressed or released.
+    pub async fn read_report(&mut self) -> Result<KeyboardReport, Error> {
+        let mut buf = [0; BOOT_REPORT_LEN];
+        let n = self.pipe.read(&mut buf).await?;
+        // Short reports have fewer keys.
+        buf[n..].fill(0);
+
+        let mut keycodes = [0; 6];
+        keycodes.copy_from_slice(&buf[2..]);
+        Ok(KeyboardReport {
+            modifiers: KeyboardModifiers(buf[0]),
+            keycodes,
+        })
+    }
+
+    /// Set the LEDs of the keyboard.
+    pub async fn set_leds(&mut self, leds: KeyboardLeds) -> Result<(), Error> {
+        let req = Request {
+            length: 1,
+            ..class_request(HID_REQ_SET_REPORT, REPORT_TYPE_OUTPUT << 8, self.interface)
+        };
+        self.device.control_out(req, &[leds.0]).await
+    }
+}
+
+fn class_request(request: u8, value: u16, interface: u8) -> Request {
+    Request {
+        direction: Direction::Out,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index: interface as u16,
+        length: 0,
+    }
+}
@@ -0,0 +1,3 @@
+//! Drivers for well-known USB classes.
+pub mod hid;
+pub mod msc;
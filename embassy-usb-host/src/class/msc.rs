@@ -0,0 +1,342 @@
+//! Mass storage driver.
+//!
+//! This drives USB drives with the Bulk-Only Transport and the SCSI command set, reading and
+//! writing blocks of their logical units.
+
+use embassy_usb_driver::host::{HostDriver, Pipe, PipeError};
+use embassy_usb_driver::{Direction, EndpointType};
+
+use crate::descriptor::EndpointDescriptor;
+use crate::{read_transfer, Device, Recipient, Request, RequestType};
+
+const USB_CLASS_MSC: u8 = 0x08;
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+
+/// Error returned by [`MassStorage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A transfer failed, or the device isn't a mass storage device.
+    Usb(crate::Error),
+    /// The device failed the command. The cause is given by [`MassStorage::request_sense`].
+    CommandFailed,
+    /// The device didn't follow the protocol, and was reset.
+    PhaseError,
+}
+
+impl From<crate::Error> for Error {
+    fn from(e: crate::Error) -> Self {
+        Self::Usb(e)
+    }
+}
+
+impl From<PipeError> for Error {
+    fn from(e: PipeError) -> Self {
+        Self::Usb(crate::Error::Pipe(e))
+    }
+}
+
+/// Identification of a logical unit, returned by [`MassStorage::inquiry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Inquiry {
+    /// Vendor identification, padded with spaces.
+    pub vendor: [u8; 8],
+    /// Product identification, padded with spaces.
+    pub product: [u8; 16],
+    /// Product revision, padded with spaces.
+    pub revision: [u8; 4],
+    /// Whether the medium is removable.
+    pub removable: bool,
+}
+
+/// Capacity of a logical unit, returned by [`MassStorage::read_capacity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capacity {
+    /// Number of blocks.
+    pub block_count: u32,
+    /// Size of a block in bytes.
+    pub block_size: u32,
+}
+
+/// Sense data of a failed command, returned by [`MassStorage::request_sense`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sense {
+    /// Sense key, such as 0x02 for "not ready".
+    pub key: u8,
+    /// Additional sense code.
+    pub asc: u8,
+    /// Additional sense code qualifier.
+    pub ascq: u8,
+}
+
+enum Data<'b> {
+    None,
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+/// Mass storage device using the Bulk-Only Transport.
+pub struct MassStorage<'a, 'd, D: HostDriver> {
+    device: &'a mut Device<'d, D>,
+    interface: u8,
+    max_lun: u8,
+    tag: u32,
+    ep_in: EndpointDescriptor,
+    ep_out: EndpointDescriptor,
+    pipe_in: D::Pipe,
+    pipe_out: D::Pipe,
+}
+
+impl<'a, 'd, D: HostDriver> MassStorage<'a, 'd, D> {
+    /// Find the mass storage interface of `device`, and read its number of logical units.
+    ///
+    /// Returns [`crate::Error::Unsupported`] if the device isn't a mass storage device.
+    pub async fn new(device: &'a mut Device<'d, D>) -> Result<Self, Error> {
+        let iface = device
+            .configuration()
+            .find_interface(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT)
+            .ok_or(crate::Error::Unsupported)?;
+        let ep_in = iface
+            .find_endpoint(EndpointType::Bulk, Direction::In)
+            .ok_or(crate::Error::Unsupported)?;
+        let ep_out = iface
+            .find_endpoint(EndpointType::Bulk, Direction::Out)
+            .ok_or(crate::Error::Unsupported)?;
+        let interface = iface.number;
+
+        // Devices with a single logical unit may stall the request.
+        let req = class_request(Direction::In, REQ_GET_MAX_LUN, interface, 1);
+        let mut max_lun = [0];
+        let max_lun = match device.control_in(req, &mut max_lun).await {
+            Ok(1) => max_lun[0],
+            Ok(_) | Err(crate::Error::Pipe(PipeError::Stall)) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let pipe_in = device.alloc_pipe(&ep_in)?;
+        let pipe_out = device.alloc_pipe(&ep_out)?;
+        Ok(Self {
+            device,
+            interface,
+            max_lun,
+            tag: 0,
+            ep_in,
+            ep_out,
+            pipe_in,
+            pipe_out,
+        })
+    }
+
+    /// Get the number of the last logical unit. Logical units are numbered from 0.
+    pub fn max_lun(&self) -> u8 {
+        self.max_lun
+    }
+
+    /// Identify a logical unit.
+    pub async fn inquiry(&mut self, lun: u8) -> Result<Inquiry, Error> {
+        let mut buf = [0; 36];
+        let cb = [INQUIRY, 0, 0, 0, buf.len() as u8, 0];
+        let n = self.command(lun, &cb, Data::In(&mut buf)).await?;
+        if n < buf.len() {
+            return Err(Error::PhaseError);
+        }
+        Ok(Inquiry {
+            vendor: buf[8..16].try_into().unwrap(),
+            product: buf[16..32].try_into().unwrap(),
+            revision: buf[32..36].try_into().unwrap(),
+            removable: buf[1] & 0x80 != 0,
+        })
+    }
+
+    /// Check whether a logical unit is ready, with its medium present.
+    ///
+    /// Returns [`Error::CommandFailed`] if it isn't.
+    pub async fn test_unit_ready(&mut self, lun: u8) -> Result<(), Error> {
+        let cb = [TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        self.command(lun, &cb, Data::None).await.map(drop)
+    }
+
+    /// Get the cause of the last command of a logical unit that failed.
+    pub async fn request_sense(&mut self, lun: u8) -> Result<Sense, Error> {
+        let mut buf = [0; 18];
+        let cb = [REQUEST_SENSE, 0, 0, 0, buf.len() as u8, 0];
+        let n = self.command(lun, &cb, Data::In(&mut buf)).await?;
+        if n < 14 {
+            return Err(Error::PhaseError);
+        }
+        Ok(Sense {
+            key: buf[2] & 0x0f,
+            asc: buf[12],
+            ascq: buf[13],
+        })
+    }
+
+    /// Read the capacity of a logical unit.
+    pub async fn read_capacity(&mut self, lun: u8) -> Result<Capacity, Error> {
+        let mut buf = [0; 8];
+        let cb = [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let n = self.command(lun, &cb, Data::In(&mut buf)).await?;
+        if n < buf.len() {
+            return Err(Error::PhaseError);
+        }
+        let last_lba = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        Ok(Capacity {
+            block_count: last_lba.wrapping_add(1),
+            block_size: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+        })
+    }
+
+    /// Read `blocks` blocks starting at `lba`. `buf` must hold exactly that many blocks.
+    pub async fn read_blocks(&mut self, lun: u8, lba: u32, blocks: u16, buf: &mut [u8]) -> Result<(), Error> {
+        let cb = rw_10(READ_10, lba, blocks);
+        let len = buf.len();
+        match self.command(lun, &cb, Data::In(buf)).await? {
+            n if n == len => Ok(()),
+            _ => Err(Error::PhaseError),
+        }
+    }
+
+    /// Write `blocks` blocks starting at `lba`. `data` must hold exactly that many blocks.
+    pub async fn write_blocks(&mut self, lun: u8, lba: u32, blocks: u16, data: &[u8]) -> Result<(), Error> {
+        let cb = rw_10(WRITE_10, lba, blocks);
+        self.command(lun, &cb, Data::Out(data)).await.map(drop)
+    }
+
+    /// Run a command, with its data stage. Returns the length of the data transferred.
+    async fn command(&mut self, lun: u8, cb: &[u8], data: Data<'_>) -> Result<usize, Error> {
+        assert!(lun <= self.max_lun, "msc: no such logical unit");
+        self.tag = self.tag.wrapping_add(1);
+
+        let (len, flags) = match &data {
+            Data::None => (0, 0),
+            Data::In(buf) => (buf.len(), 0x80),
+            Data::Out(data) => (data.len(), 0x00),
+        };
+        let mut cbw = [0; CBW_LEN];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[13] = lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        let mps = self.ep_out.max_packet_size as usize;
+        for packet in cbw.chunks(mps) {
+            self.pipe_out.write(packet).await?;
+        }
+
+        // The device stalls the data stage when it has less data than the host asked for, or
+        // rejects the data. The command status tells what happened.
+        let result = match data {
+            Data::None => Ok(0),
+            Data::In(buf) => read_transfer(&mut self.pipe_in, buf).await,
+            Data::Out(data) => self.write_data(data).await.map(|()| data.len()),
+        };
+        let transferred = match result {
+            Ok(n) => n,
+            Err(PipeError::Stall) => {
+                let ep = if flags == 0x80 { self.ep_in } else { self.ep_out };
+                self.clear_halt(ep).await?;
+                0
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut csw = [0; CSW_LEN];
+        let n = match self.pipe_in.read(&mut csw).await {
+            // The status can be stalled once, after data the device didn't have.
+            Err(PipeError::Stall) => {
+                self.clear_halt(self.ep_in).await?;
+                self.pipe_in.read(&mut csw).await?
+            }
+            res => res?,
+        };
+
+        let signature = u32::from_le_bytes(csw[0..4].try_into().unwrap());
+        let tag = u32::from_le_bytes(csw[4..8].try_into().unwrap());
+        let residue = u32::from_le_bytes(csw[8..12].try_into().unwrap()) as usize;
+        if n != CSW_LEN || signature != CSW_SIGNATURE || tag != self.tag {
+            warn!("msc: invalid command status");
+            self.reset_recovery().await?;
+            return Err(Error::PhaseError);
+        }
+        match csw[12] {
+            CSW_STATUS_PASSED => Ok(transferred.min(len.saturating_sub(residue))),
+            CSW_STATUS_FAILED => Err(Error::CommandFailed),
+            _ => {
+                self.reset_recovery().await?;
+                Err(Error::PhaseError)
+            }
+        }
+    }
+
+    /// Write the data stage of a command, without ending it with a zero-length packet: the
+    /// device knows its length.
+    async fn write_data(&mut self, data: &[u8]) -> Result<(), PipeError> {
+        let mps = self.ep_out.max_packet_size as usize;
+        for packet in data.chunks(mps) {
+            self.pipe_out.write(packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn clear_halt(&mut self, ep: EndpointDescriptor) -> Result<(), Error> {
+        self.device.clear_halt(ep.address).await?;
+        // A new pipe starts with the reset data toggle, like the endpoint.
+        match ep.address.direction() {
+            Direction::In => self.pipe_in = self.device.alloc_pipe(&ep)?,
+            Direction::Out => self.pipe_out = self.device.alloc_pipe(&ep)?,
+        }
+        Ok(())
+    }
+
+    /// Reset the device after it got out of sync with the host.
+    async fn reset_recovery(&mut self) -> Result<(), Error> {
+        let req = class_request(Direction::Out, REQ_BULK_ONLY_RESET, self.interface, 0);
+        self.device.control_out(req, &[]).await?;
+        self.clear_halt(self.ep_in).await?;
+        self.clear_halt(self.ep_out).await
+    }
+}
+
+fn rw_10(op: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cb = [0; 10];
+    cb[0] = op;
+    cb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cb
+}
+
+fn class_request(direction: Direction, request: u8, interface: u8, length: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value: 0,
+        index: interface as u16,
+        length,
+    }
+}
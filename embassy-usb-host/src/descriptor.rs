@@ -0,0 +1,265 @@
+//! Parsing of the descriptors of devices.
+
+use embassy_usb::descriptor::descriptor_type;
+pub use embassy_usb::descriptor_reader::ReadError;
+use embassy_usb::descriptor_reader::Reader;
+use embassy_usb_driver::host::Speed;
+use embassy_usb_driver::{Direction, EndpointAddress, EndpointInfo, EndpointType};
+
+/// Device descriptor.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceDescriptor {
+    /// USB version, in BCD.
+    pub usb_version: u16,
+    /// Class of the device, or 0 if each interface has its own class.
+    pub device_class: u8,
+    /// Subclass of the device.
+    pub device_sub_class: u8,
+    /// Protocol of the device.
+    pub device_protocol: u8,
+    /// Max packet size of the control endpoint.
+    pub max_packet_size_0: u8,
+    /// Vendor ID.
+    pub vendor_id: u16,
+    /// Product ID.
+    pub product_id: u16,
+    /// Release number of the device, in BCD.
+    pub device_release: u16,
+    /// Index of the manufacturer string, or 0 if there is none.
+    pub manufacturer: u8,
+    /// Index of the product string, or 0 if there is none.
+    pub product: u8,
+    /// Index of the serial number string, or 0 if there is none.
+    pub serial_number: u8,
+    /// Number of configurations.
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    /// Length of the descriptor.
+    pub const LEN: usize = 18;
+
+    /// Parse a device descriptor.
+    pub fn parse(data: &[u8]) -> Result<Self, ReadError> {
+        let mut r = Reader::new(data);
+        let (kind, mut r) = r.read_descriptors().next().ok_or(ReadError)??;
+        if kind != descriptor_type::DEVICE {
+            return Err(ReadError);
+        }
+        Ok(Self {
+            usb_version: r.read_u16()?,
+            device_class: r.read_u8()?,
+            device_sub_class: r.read_u8()?,
+            device_protocol: r.read_u8()?,
+            max_packet_size_0: r.read_u8()?,
+            vendor_id: r.read_u16()?,
+            product_id: r.read_u16()?,
+            device_release: r.read_u16()?,
+            manufacturer: r.read_u8()?,
+            product: r.read_u8()?,
+            serial_number: r.read_u8()?,
+            num_configurations: r.read_u8()?,
+        })
+    }
+}
+
+/// Configuration descriptor, followed by the descriptors of its interfaces and endpoints.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigurationDescriptor<'a> {
+    /// Value of the configuration, used to select it.
+    pub value: u8,
+    /// Number of interfaces.
+    pub num_interfaces: u8,
+    /// Index of the configuration string, or 0 if there is none.
+    pub name: u8,
+    /// Attributes: self-powered and remote wakeup bits.
+    pub attributes: u8,
+    /// Max power consumption, in units of 2 mA.
+    pub max_power: u8,
+    /// The whole configuration, starting with the configuration descriptor.
+    pub data: &'a [u8],
+}
+
+impl<'a> ConfigurationDescriptor<'a> {
+    /// Length of the configuration descriptor itself.
+    pub const LEN: usize = 9;
+
+    /// Parse a configuration, and check that all its descriptors are well-formed.
+    ///
+    /// `data` must hold the whole configuration, as given by its total length.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ReadError> {
+        let mut r = Reader::new(data);
+        let mut descriptors = r.read_descriptors();
+        let (kind, mut header) = descriptors.next().ok_or(ReadError)??;
+        if kind != descriptor_type::CONFIGURATION {
+            return Err(ReadError);
+        }
+        let total_length = header.read_u16()? as usize;
+        if total_length != data.len() {
+            return Err(ReadError);
+        }
+        let desc = Self {
+            num_interfaces: header.read_u8()?,
+            value: header.read_u8()?,
+            name: header.read_u8()?,
+            attributes: header.read_u8()?,
+            max_power: header.read_u8()?,
+            data,
+        };
+        for res in descriptors {
+            res?;
+        }
+        Ok(desc)
+    }
+
+    /// Total length of the configuration, read from the configuration descriptor in `header`.
+    pub fn total_length(header: &[u8]) -> Result<usize, ReadError> {
+        match header {
+            [_, descriptor_type::CONFIGURATION, lo, hi, ..] => Ok(u16::from_le_bytes([*lo, *hi]) as usize),
+            _ => Err(ReadError),
+        }
+    }
+
+    /// Iterate over the interfaces, including each alternate setting.
+    pub fn interfaces(&self) -> impl Iterator<Item = InterfaceDescriptor<'a>> {
+        let mut r = Reader::new(self.data);
+        core::iter::from_fn(move || loop {
+            // The descriptors were checked when parsing.
+            let (kind, mut data) = r.read_descriptors().next()?.ok()?;
+            if kind == descriptor_type::INTERFACE {
+                return Some(InterfaceDescriptor {
+                    number: data.read_u8().ok()?,
+                    alternate_setting: data.read_u8().ok()?,
+                    num_endpoints: data.read_u8().ok()?,
+                    class: data.read_u8().ok()?,
+                    sub_class: data.read_u8().ok()?,
+                    protocol: data.read_u8().ok()?,
+                    name: data.read_u8().ok()?,
+                    rest: r.clone(),
+                });
+            }
+        })
+    }
+
+    /// Find the first interface matching `class`, `sub_class` and `protocol`, in its default
+    /// alternate setting.
+    pub fn find_interface(&self, class: u8, sub_class: u8, protocol: u8) -> Option<InterfaceDescriptor<'a>> {
+        self.interfaces().find(|iface| {
+            iface.alternate_setting == 0
+                && (iface.class, iface.sub_class, iface.protocol) == (class, sub_class, protocol)
+        })
+    }
+}
+
+/// Interface descriptor, for one alternate setting.
+#[derive(Clone)]
+pub struct InterfaceDescriptor<'a> {
+    /// Number of the interface.
+    pub number: u8,
+    /// Alternate setting.
+    pub alternate_setting: u8,
+    /// Number of endpoints.
+    pub num_endpoints: u8,
+    /// Class.
+    pub class: u8,
+    /// Subclass.
+    pub sub_class: u8,
+    /// Protocol.
+    pub protocol: u8,
+    /// Index of the interface string, or 0 if there is none.
+    pub name: u8,
+    rest: Reader<'a>,
+}
+
+impl<'a> InterfaceDescriptor<'a> {
+    /// Iterate over the descriptors following the interface descriptor, up to the next interface.
+    ///
+    /// Each one is the descriptor type, and the data following it. This includes the
+    /// class-specific descriptors, and the endpoint descriptors.
+    pub fn descriptors(&self) -> impl Iterator<Item = (u8, &'a [u8])> {
+        let mut r = self.rest.clone();
+        core::iter::from_fn(move || {
+            let (kind, mut data) = r.read_descriptors().next()?.ok()?;
+            if matches!(kind, descriptor_type::INTERFACE | descriptor_type::IAD) {
+                return None;
+            }
+            let len = data.remaining();
+            Some((kind, data.read_slice(len).ok()?))
+        })
+    }
+
+    /// Iterate over the endpoints.
+    pub fn endpoints(&self) -> impl Iterator<Item = EndpointDescriptor> + 'a {
+        self.descriptors().filter_map(|(kind, data)| match kind {
+            descriptor_type::ENDPOINT => EndpointDescriptor::parse(data).ok(),
+            _ => None,
+        })
+    }
+
+    /// Find the first endpoint of type `ep_type` in direction `dir`.
+    pub fn find_endpoint(&self, ep_type: EndpointType, dir: Direction) -> Option<EndpointDescriptor> {
+        self.endpoints()
+            .find(|ep| ep.ep_type == ep_type && ep.address.direction() == dir)
+    }
+}
+
+/// Endpoint descriptor.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EndpointDescriptor {
+    /// Address of the endpoint.
+    pub address: EndpointAddress,
+    /// Type of the endpoint.
+    pub ep_type: EndpointType,
+    /// Max packet size, in bytes.
+    pub max_packet_size: u16,
+    /// Polling interval, in the units of the speed of the device.
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    /// Parse the data of an endpoint descriptor, following its type.
+    fn parse(data: &[u8]) -> Result<Self, ReadError> {
+        let mut r = Reader::new(data);
+        let address = EndpointAddress::from(r.read_u8()?);
+        let ep_type = match r.read_u8()? & 0b11 {
+            0b00 => EndpointType::Control,
+            0b01 => EndpointType::Isochronous,
+            0b10 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        };
+        Ok(Self {
+            address,
+            ep_type,
+            // The upper bits are the number of additional transactions per microframe.
+            max_packet_size: r.read_u16()? & 0x7ff,
+            interval: r.read_u8()?,
+        })
+    }
+
+    /// Information about the endpoint, to allocate a pipe to it.
+    ///
+    /// The interval of interrupt and isochronous endpoints is in frames at full and low speed,
+    /// and is an exponent of microframes at high speed. It is converted to milliseconds.
+    pub fn info(&self, speed: Speed) -> EndpointInfo {
+        let interval_ms = match (self.ep_type, speed) {
+            (EndpointType::Bulk | EndpointType::Control, _) => 0,
+            (EndpointType::Interrupt, Speed::Low | Speed::Full) => self.interval,
+            (EndpointType::Isochronous, Speed::Low | Speed::Full) => {
+                (1u32 << (self.interval.clamp(1, 16) - 1)).min(255) as u8
+            }
+            (_, Speed::High) => {
+                let microframes = 1u32 << (self.interval.clamp(1, 16) - 1);
+                (microframes / 8).clamp(1, 255) as u8
+            }
+        };
+        EndpointInfo {
+            addr: self.address,
+            ep_type: self.ep_type,
+            max_packet_size: self.max_packet_size,
+            interval_ms,
+        }
+    }
+}
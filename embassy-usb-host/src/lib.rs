@@ -0,0 +1,368 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+pub use embassy_usb::control::{Recipient, Request, RequestType};
+pub use embassy_usb_driver as driver;
+
+pub mod class;
+pub mod descriptor;
+
+use embassy_usb::descriptor::{descriptor_type, lang_id};
+use embassy_usb_driver::host::{DeviceEvent, HostDriver, Pipe, PipeAllocError, PipeError, Speed};
+use embassy_usb_driver::{Direction, EndpointAddress, EndpointInfo, EndpointType};
+
+use crate::descriptor::{ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, ReadError};
+
+/// Address assigned to the device by [`UsbHost::enumerate`].
+pub const DEVICE_ADDRESS: u8 = 1;
+
+/// Error returned by the host and the class drivers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A transfer failed.
+    Pipe(PipeError),
+    /// The driver couldn't allocate a pipe.
+    PipeAlloc,
+    /// The descriptors of the device are malformed.
+    InvalidDescriptor,
+    /// The buffer is too small for the configuration descriptors of the device.
+    BufferTooSmall,
+    /// The device doesn't have the interface or endpoints needed by the class driver.
+    Unsupported,
+    /// The direction or the length of a control request doesn't match its transfer.
+    InvalidRequest,
+}
+
+impl From<PipeError> for Error {
+    fn from(e: PipeError) -> Self {
+        Self::Pipe(e)
+    }
+}
+
+impl From<PipeAllocError> for Error {
+    fn from(_: PipeAllocError) -> Self {
+        Self::PipeAlloc
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(_: ReadError) -> Self {
+        Self::InvalidDescriptor
+    }
+}
+
+/// USB host, managing the device connected to the root port of a [`HostDriver`].
+pub struct UsbHost<D: HostDriver> {
+    driver: D,
+    /// Speed of the connected device.
+    connected: Option<Speed>,
+}
+
+impl<D: HostDriver> UsbHost<D> {
+    /// Create a new `UsbHost`.
+    pub const fn new(driver: D) -> Self {
+        Self {
+            driver,
+            connected: None,
+        }
+    }
+
+    /// Wait for a device to be connected.
+    ///
+    /// Returns immediately if one already is.
+    pub async fn wait_connected(&mut self) -> Speed {
+        loop {
+            if let Some(speed) = self.connected {
+                return speed;
+            }
+            self.poll_event().await;
+        }
+    }
+
+    async fn poll_event(&mut self) {
+        match self.driver.wait_for_device_event().await {
+            DeviceEvent::Connected(speed) => {
+                debug!("usb host: device connected, {:?} speed", speed);
+                self.connected = Some(speed);
+            }
+            DeviceEvent::Disconnected => {
+                debug!("usb host: device disconnected");
+                self.connected = None;
+            }
+        }
+    }
+
+    /// Wait for a device to be connected, and enumerate it.
+    ///
+    /// The device is reset, given the address [`DEVICE_ADDRESS`], and configured with its first
+    /// configuration. Its descriptors are read into `buf`, which must be large enough for the
+    /// whole configuration.
+    ///
+    /// If enumerating fails, calling this again resets the device and retries.
+    pub async fn enumerate<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<Device<'a, D>, Error> {
+        let speed = self.wait_connected().await;
+        self.driver.bus_reset().await;
+
+        // The host doesn't know the max packet size of the control endpoint yet, so it reads
+        // the beginning of the device descriptor, which includes it, in a single packet.
+        let mut control = self.driver.alloc_pipe(0, &control_info(8))?;
+        let mut desc = [0; DeviceDescriptor::LEN];
+        let req = get_descriptor(descriptor_type::DEVICE, 0, 0, 8);
+        let n = control.control_in(&req.to_bytes(), &mut desc[..8]).await?;
+        let max_packet_size_0 = match desc[..n] {
+            [_, descriptor_type::DEVICE, _, _, _, _, _, mps] if matches!(mps, 8 | 16 | 32 | 64) => mps,
+            _ => return Err(Error::InvalidDescriptor),
+        };
+
+        control
+            .control_out(&set_address(DEVICE_ADDRESS).to_bytes(), &[])
+            .await?;
+        drop(control);
+        let mut control = self
+            .driver
+            .alloc_pipe(DEVICE_ADDRESS, &control_info(max_packet_size_0 as u16))?;
+
+        let req = get_descriptor(descriptor_type::DEVICE, 0, 0, DeviceDescriptor::LEN as u16);
+        let n = control.control_in(&req.to_bytes(), &mut desc).await?;
+        let descriptor = DeviceDescriptor::parse(&desc[..n])?;
+        debug!(
+            "usb host: device {:04x}:{:04x}",
+            descriptor.vendor_id, descriptor.product_id
+        );
+
+        let header_len = ConfigurationDescriptor::LEN;
+        if buf.len() < header_len {
+            return Err(Error::BufferTooSmall);
+        }
+        let req = get_descriptor(descriptor_type::CONFIGURATION, 0, 0, header_len as u16);
+        let n = control.control_in(&req.to_bytes(), &mut buf[..header_len]).await?;
+        let total_length = ConfigurationDescriptor::total_length(&buf[..n])?;
+        if total_length > buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        let req = get_descriptor(descriptor_type::CONFIGURATION, 0, 0, total_length as u16);
+        let n = control.control_in(&req.to_bytes(), &mut buf[..total_length]).await?;
+        let configuration = ConfigurationDescriptor::parse(&buf[..n])?;
+
+        control
+            .control_out(&set_configuration(configuration.value).to_bytes(), &[])
+            .await?;
+        debug!("usb host: configuration {} selected", configuration.value);
+
+        Ok(Device {
+            host: self,
+            control,
+            speed,
+            descriptor,
+            configuration,
+        })
+    }
+}
+
+/// Enumerated device, returned by [`UsbHost::enumerate`].
+pub struct Device<'a, D: HostDriver> {
+    host: &'a mut UsbHost<D>,
+    control: D::Pipe,
+    speed: Speed,
+    descriptor: DeviceDescriptor,
+    configuration: ConfigurationDescriptor<'a>,
+}
+
+impl<'a, D: HostDriver> Device<'a, D> {
+    /// Get the speed of the device.
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Get the device descriptor.
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    /// Get the selected configuration, with its interfaces and endpoints.
+    pub fn configuration(&self) -> ConfigurationDescriptor<'a> {
+        self.configuration
+    }
+
+    /// Run a control transfer reading data from the device.
+    ///
+    /// At most `req.length` bytes are read. Returns the length of the data.
+    ///
+    /// Returns [`Error::InvalidRequest`] if `req` isn't an IN request.
+    pub async fn control_in(&mut self, req: Request, buf: &mut [u8]) -> Result<usize, Error> {
+        if req.direction != Direction::In {
+            return Err(Error::InvalidRequest);
+        }
+        let len = buf.len().min(req.length as usize);
+        Ok(self.control.control_in(&req.to_bytes(), &mut buf[..len]).await?)
+    }
+
+    /// Run a control transfer writing `data` to the device.
+    ///
+    /// Returns [`Error::InvalidRequest`] if `req` isn't an OUT request, or if the length of `data`
+    /// isn't `req.length`.
+    pub async fn control_out(&mut self, req: Request, data: &[u8]) -> Result<(), Error> {
+        if req.direction != Direction::Out || data.len() != req.length as usize {
+            return Err(Error::InvalidRequest);
+        }
+        Ok(self.control.control_out(&req.to_bytes(), data).await?)
+    }
+
+    /// Read a string descriptor in US English, and decode it into `buf`.
+    ///
+    /// Characters that don't fit in `buf` are dropped.
+    pub async fn get_string<'b>(&mut self, index: u8, buf: &'b mut [u8]) -> Result<&'b str, Error> {
+        let mut desc = [0; 255];
+        let req = get_descriptor(descriptor_type::STRING, index, lang_id::ENGLISH_US, desc.len() as u16);
+        let n = self.control_in(req, &mut desc).await?;
+        let len = match desc[..n] {
+            [len, descriptor_type::STRING, ..] if (2..=n).contains(&(len as usize)) => len as usize,
+            _ => return Err(Error::InvalidDescriptor),
+        };
+
+        // An odd trailing byte isn't a whole UTF-16 code unit, and is ignored.
+        let utf16 = desc[2..len].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+        let mut len = 0;
+        for c in char::decode_utf16(utf16) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            if len + c.len_utf8() > buf.len() {
+                break;
+            }
+            len += c.encode_utf8(&mut buf[len..]).len();
+        }
+        // Only whole characters were written.
+        Ok(core::str::from_utf8(&buf[..len]).unwrap())
+    }
+
+    /// Select the alternate setting of an interface.
+    pub async fn set_interface(&mut self, interface: u8, alternate_setting: u8) -> Result<(), Error> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Interface,
+            Request::SET_INTERFACE,
+            alternate_setting as u16,
+            interface as u16,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Clear the halt condition of an endpoint, after it stalled.
+    ///
+    /// The pipe to the endpoint must be reset too: it is easiest to allocate a new one.
+    pub async fn clear_halt(&mut self, ep: EndpointAddress) -> Result<(), Error> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Endpoint,
+            Request::CLEAR_FEATURE,
+            Request::FEATURE_ENDPOINT_HALT,
+            u8::from(ep) as u16,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Allocate a pipe to an endpoint of the device.
+    pub fn alloc_pipe(&mut self, endpoint: &EndpointDescriptor) -> Result<D::Pipe, Error> {
+        let info = endpoint.info(self.speed);
+        Ok(self.host.driver.alloc_pipe(DEVICE_ADDRESS, &info)?)
+    }
+
+    /// Wait for the device to be disconnected.
+    pub async fn wait_disconnected(&mut self) {
+        while self.host.connected.is_some() {
+            self.host.poll_event().await;
+        }
+    }
+}
+
+/// Read a transfer from an IN pipe: packets until a short packet, or until `buf` is full.
+///
+/// Returns the length of the data. The length of `buf` should be a multiple of the max packet
+/// size, or the last packet may not fit.
+pub async fn read_transfer<P: Pipe>(pipe: &mut P, buf: &mut [u8]) -> Result<usize, PipeError> {
+    let mps = pipe.info().max_packet_size as usize;
+    let mut len = 0;
+    while len < buf.len() {
+        let end = buf.len().min(len + mps);
+        let n = pipe.read(&mut buf[len..end]).await?;
+        len += n;
+        if n < mps {
+            break;
+        }
+    }
+    Ok(len)
+}
+
+/// Write `data` to an OUT pipe as a single transfer.
+///
+/// The data is split in packets of the max packet size, and ends with a short packet, which is
+/// zero-length if needed.
+pub async fn write_transfer<P: Pipe>(pipe: &mut P, data: &[u8]) -> Result<(), PipeError> {
+    let mps = pipe.info().max_packet_size as usize;
+    for packet in data.chunks(mps) {
+        pipe.write(packet).await?;
+    }
+    if data.len() % mps == 0 {
+        pipe.write(&[]).await?;
+    }
+    Ok(())
+}
+
+fn control_info(max_packet_size: u16) -> EndpointInfo {
+    EndpointInfo {
+        addr: EndpointAddress::from(0),
+        ep_type: EndpointType::Control,
+        max_packet_size,
+        interval_ms: 0,
+    }
+}
+
+fn standard(direction: Direction, recipient: Recipient, request: u8, value: u16, index: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Standard,
+        recipient,
+        request,
+        value,
+        index,
+        length: 0,
+    }
+}
+
+fn get_descriptor(descriptor_type: u8, index: u8, language_id: u16, length: u16) -> Request {
+    Request {
+        length,
+        ..standard(
+            Direction::In,
+            Recipient::Device,
+            Request::GET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            language_id,
+        )
+    }
+}
+
+fn set_address(address: u8) -> Request {
+    standard(
+        Direction::Out,
+        Recipient::Device,
+        Request::SET_ADDRESS,
+        address as u16,
+        0,
+    )
+}
+
+fn set_configuration(configuration: u8) -> Request {
+    standard(
+        Direction::Out,
+        Recipient::Device,
+        Request::SET_CONFIGURATION,
+        configuration as u16,
+        0,
+    )
+}
@@ -0,0 +1,453 @@
+use std::sync::{Arc, Mutex};
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::class::hid::report::{InputReport, KeyboardLeds, KeyboardModifiers, KeyboardReport};
+use embassy_usb::class::hid::usage::keyboard;
+use embassy_usb::class::hid::{self, HidProtocolMode, HidWriter, ReportId, RequestHandler};
+use embassy_usb::class::msc::{self, BlockDevice, MscClass};
+use embassy_usb::control::{OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::host::{DeviceEvent, HostDriver, Pipe, PipeAllocError, PipeError, Speed};
+use embassy_usb::driver::{Direction, EndpointInfo};
+use embassy_usb::{Builder, Config};
+use embassy_usb_host::class::hid::Keyboard;
+use embassy_usb_host::class::msc::{Capacity, MassStorage, Sense};
+use embassy_usb_host::{Error, UsbHost, DEVICE_ADDRESS};
+use embassy_usb_sim::HostController;
+
+fn config() -> Config<'static> {
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("Host test");
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+    config
+}
+
+#[test]
+fn enumerate() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_acm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    let mut usb_host = UsbHost::new(HostController::new(host.clone()));
+    let mut buf = [0; 256];
+
+    block_on(select(usb.run(), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        assert_eq!(host.address(), DEVICE_ADDRESS);
+        assert_eq!(device.speed(), Speed::Full);
+
+        let descriptor = *device.descriptor();
+        assert_eq!((descriptor.vendor_id, descriptor.product_id), (0xc0de, 0xcafe));
+        assert_eq!(descriptor.max_packet_size_0, 64);
+        assert_eq!(descriptor.num_configurations, 1);
+        let mut name = [0; 32];
+        assert_eq!(
+            device.get_string(descriptor.manufacturer, &mut name).await,
+            Ok("Embassy")
+        );
+        assert_eq!(device.get_string(descriptor.product, &mut name).await, Ok("Host test"));
+        // Strings are truncated to whole characters.
+        assert_eq!(device.get_string(descriptor.product, &mut name[..4]).await, Ok("Host"));
+
+        // CDC-ACM has a communication interface with a notification endpoint, and a data
+        // interface with bulk endpoints.
+        let configuration = device.configuration();
+        assert_eq!(configuration.num_interfaces, 2);
+        let interfaces: Vec<_> = configuration
+            .interfaces()
+            .map(|iface| (iface.number, iface.class, iface.endpoints().count()))
+            .collect();
+        assert_eq!(interfaces, [(0, 0x02, 1), (1, 0x0a, 2)]);
+        // The communication interface has its functional descriptors before its endpoint.
+        let comm = configuration.interfaces().next().unwrap();
+        assert!(comm.descriptors().any(|(kind, _)| kind == 0x24));
+
+        // Pipes exchange data with the class.
+        let data = configuration.interfaces().nth(1).unwrap();
+        let ep = data.endpoints().find(|ep| ep.address.is_out()).unwrap();
+        let mut pipe = device.alloc_pipe(&ep).unwrap();
+        let mut packet = [0; 64];
+        let (written, read) = join(
+            embassy_usb_host::write_transfer(&mut pipe, b"hello"),
+            class.read_packet(&mut packet),
+        )
+        .await;
+        written.unwrap();
+        assert_eq!(&packet[..read.unwrap()], b"hello");
+
+        // Unplugging the device ends its transfers.
+        host.power_off();
+        device.wait_disconnected().await;
+        assert_eq!(pipe.write(b"hello").await, Err(PipeError::Disconnected));
+        drop(device);
+
+        // It is enumerated again when plugged back in.
+        host.power_on();
+        let device = usb_host.enumerate(&mut buf).await.unwrap();
+        assert_eq!(device.configuration().num_interfaces, 2);
+    }));
+}
+
+/// Host controller answering GET_DESCRIPTOR requests for strings with a crafted descriptor.
+struct BadStrings {
+    inner: HostController,
+    string: Arc<Mutex<Vec<u8>>>,
+}
+
+impl HostDriver for BadStrings {
+    type Pipe = BadStringsPipe;
+
+    async fn wait_for_device_event(&mut self) -> DeviceEvent {
+        self.inner.wait_for_device_event().await
+    }
+
+    async fn bus_reset(&mut self) {
+        self.inner.bus_reset().await
+    }
+
+    fn alloc_pipe(&mut self, address: u8, endpoint: &EndpointInfo) -> Result<Self::Pipe, PipeAllocError> {
+        Ok(BadStringsPipe {
+            inner: self.inner.alloc_pipe(address, endpoint)?,
+            string: self.string.clone(),
+        })
+    }
+}
+
+struct BadStringsPipe {
+    inner: embassy_usb_sim::Pipe,
+    string: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Pipe for BadStringsPipe {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn control_in(&mut self, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize, PipeError> {
+        // GET_DESCRIPTOR(STRING) for any string but the language list.
+        if setup[1] == 0x06 && setup[3] == 0x03 && setup[2] != 0 {
+            let string = self.string.lock().unwrap();
+            let n = string.len().min(buf.len());
+            buf[..n].copy_from_slice(&string[..n]);
+            return Ok(n);
+        }
+        self.inner.control_in(setup, buf).await
+    }
+
+    async fn control_out(&mut self, setup: &[u8; 8], data: &[u8]) -> Result<(), PipeError> {
+        self.inner.control_out(setup, data).await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        self.inner.read(buf).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), PipeError> {
+        self.inner.write(data).await
+    }
+}
+
+#[test]
+fn malformed_strings() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_acm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let _class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    let string = Arc::new(Mutex::new(Vec::new()));
+    let mut usb_host = UsbHost::new(BadStrings {
+        inner: HostController::new(host),
+        string: string.clone(),
+    });
+    let mut buf = [0; 256];
+
+    block_on(select(usb.run(), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        let index = device.descriptor().product;
+        let mut name = [0; 32];
+
+        let cases: [(&[u8], Result<&str, Error>); 8] = [
+            (b"\x04\x03a\x00", Ok("a")),
+            (b"\x02\x03", Ok("")),
+            // An odd trailing byte is ignored.
+            (b"\x05\x03a\x00b", Ok("a")),
+            // bLength is too short to hold the header, or longer than the data.
+            (b"\x00\x03", Err(Error::InvalidDescriptor)),
+            (b"\x01\x03a\x00", Err(Error::InvalidDescriptor)),
+            (b"\x08\x03a\x00", Err(Error::InvalidDescriptor)),
+            // Wrong descriptor type, and truncated descriptors.
+            (b"\x04\x02a\x00", Err(Error::InvalidDescriptor)),
+            (b"\x04", Err(Error::InvalidDescriptor)),
+        ];
+        for (desc, expected) in cases {
+            *string.lock().unwrap() = desc.to_vec();
+            assert_eq!(device.get_string(index, &mut name).await, expected, "{:02x?}", desc);
+        }
+    }));
+}
+
+#[test]
+fn invalid_control_requests() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut usb = builder.build();
+
+    let mut usb_host = UsbHost::new(HostController::new(host));
+    let mut buf = [0; 256];
+
+    block_on(select(usb.run(), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        let get_status = Request {
+            direction: Direction::In,
+            request_type: RequestType::Standard,
+            recipient: Recipient::Device,
+            request: Request::GET_STATUS,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let mut status = [0; 2];
+        assert_eq!(device.control_in(get_status, &mut status).await, Ok(2));
+
+        // Requests in the wrong direction, or with the wrong data length, are rejected instead of
+        // being sent.
+        let out = Request {
+            direction: Direction::Out,
+            ..get_status
+        };
+        assert_eq!(device.control_in(out, &mut status).await, Err(Error::InvalidRequest));
+        assert_eq!(
+            device.control_out(get_status, &status).await,
+            Err(Error::InvalidRequest)
+        );
+        assert_eq!(device.control_out(out, &[0]).await, Err(Error::InvalidRequest));
+
+        // The device is still usable.
+        assert_eq!(device.control_in(get_status, &mut status).await, Ok(2));
+    }));
+}
+
+#[derive(Clone, Default)]
+struct Leds {
+    set: Arc<Mutex<Vec<u8>>>,
+}
+
+impl RequestHandler for Leds {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        assert_eq!(id, ReportId::Out(0));
+        self.set.lock().unwrap().extend_from_slice(data);
+        OutResponse::Accepted
+    }
+}
+
+#[test]
+fn hid_keyboard() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = hid::State::new();
+    let mut leds = Leds::default();
+    let set_leds = leds.set.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut writer = HidWriter::<_, 8>::new(
+        &mut builder,
+        &mut state,
+        hid::Config {
+            report_descriptor: KeyboardReport::descriptor(),
+            request_handler: Some(&mut leds),
+            poll_ms: 10,
+            max_packet_size: 8,
+            boot_protocol: KeyboardReport::BOOT_PROTOCOL,
+        },
+    );
+    let mut usb = builder.build();
+
+    let mut usb_host = UsbHost::new(HostController::new(host));
+    let mut buf = [0; 256];
+
+    block_on(select(usb.run(), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        let mut keyboard = Keyboard::new(&mut device).await.unwrap();
+        assert_eq!(writer.protocol(), HidProtocolMode::Boot);
+
+        let report = KeyboardReport::new(KeyboardModifiers::LEFT_SHIFT, &[keyboard::A, keyboard::B]);
+        let (written, read) = join(writer.write_report(&report), keyboard.read_report()).await;
+        written.unwrap();
+        assert_eq!(read, Ok(report));
+
+        keyboard.set_leds(KeyboardLeds(0x02)).await.unwrap();
+    }));
+
+    assert_eq!(*set_leds.lock().unwrap(), [0x02]);
+}
+
+#[test]
+fn hid_keyboard_unsupported() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_acm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let _class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    let mut usb_host = UsbHost::new(HostController::new(host));
+    let mut buf = [0; 256];
+
+    block_on(select(usb.run(), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        assert!(matches!(Keyboard::new(&mut device).await, Err(Error::Unsupported)));
+    }));
+}
+
+const BLOCK_SIZE: usize = 512;
+const BLOCK_COUNT: usize = 16;
+
+struct RamDisk(Vec<u8>);
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        BLOCK_COUNT as u32
+    }
+
+    async fn read(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), msc::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.0[start..start + buf.len()]);
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u32, buf: &[u8]) -> Result<(), msc::Error> {
+        let start = lba as usize * BLOCK_SIZE;
+        self.0[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test]
+fn mass_storage() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = msc::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = MscClass::new(&mut builder, &mut state, msc::Config::default());
+    let mut usb = builder.build();
+
+    let mut disk = [RamDisk(vec![0; BLOCK_SIZE * BLOCK_COUNT])];
+    let mut block_buf = [0; BLOCK_SIZE * 2];
+    let mut usb_host = UsbHost::new(HostController::new(host));
+    let mut buf = [0; 256];
+
+    block_on(select3(usb.run(), class.run(&mut disk, &mut block_buf), async {
+        let mut device = usb_host.enumerate(&mut buf).await.unwrap();
+        let mut storage = MassStorage::new(&mut device).await.unwrap();
+        assert_eq!(storage.max_lun(), 0);
+
+        let inquiry = storage.inquiry(0).await.unwrap();
+        assert_eq!(&inquiry.vendor, b"Embassy ");
+        assert_eq!(&inquiry.product, b"Mass storage    ");
+        assert!(!inquiry.removable);
+
+        storage.test_unit_ready(0).await.unwrap();
+        assert_eq!(
+            storage.read_capacity(0).await,
+            Ok(Capacity {
+                block_count: BLOCK_COUNT as u32,
+                block_size: BLOCK_SIZE as u32,
+            })
+        );
+
+        // Transfers span several packets, and several blocks of the device's buffer.
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        storage.write_blocks(0, 2, 3, &data).await.unwrap();
+        let mut read = vec![0; BLOCK_SIZE * 4];
+        storage.read_blocks(0, 1, 4, &mut read).await.unwrap();
+        assert!(read[..BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(read[BLOCK_SIZE..], data);
+
+        // Out of range commands fail, with the cause in the sense data.
+        let mut block = [0; BLOCK_SIZE];
+        assert_eq!(
+            storage.read_blocks(0, BLOCK_COUNT as u32, 1, &mut block).await,
+            Err(embassy_usb_host::class::msc::Error::CommandFailed)
+        );
+        assert_eq!(
+            storage.request_sense(0).await,
+            Ok(Sense {
+                key: 0x05,
+                asc: 0x21,
+                ascq: 0x00,
+            })
+        );
+    }));
+
+    assert_eq!(disk[0].0[BLOCK_SIZE * 2], 0);
+    assert_eq!(disk[0].0[BLOCK_SIZE * 2 + 1], 1);
+}
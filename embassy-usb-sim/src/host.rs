@@ -0,0 +1,517 @@
+use std::sync::Arc;
+use std::task::Poll;
+
+use embassy_usb::control::{Recipient, Request, RequestType};
+use embassy_usb::descriptor::{descriptor_type, lang_id};
+use embassy_usb_driver::host::{DeviceEvent, HostDriver, PipeAllocError, PipeError, Speed};
+use embassy_usb_driver::{Direction, EndpointAddress, EndpointInfo, EndpointType, Event};
+
+use crate::{ControlState, Shared};
+
+/// Address assigned by [`Host::enumerate`].
+const DEVICE_ADDRESS: u8 = 1;
+
+/// Error of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The device stalled the endpoint, or rejected the control request.
+    Stall,
+    /// The endpoint isn't enabled, or the bus was reset during the transfer.
+    Disabled,
+}
+
+/// Descriptors read by [`Host::enumerate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptors {
+    /// Device descriptor.
+    pub device: Vec<u8>,
+    /// First configuration descriptor, followed by the descriptors of its interfaces and endpoints.
+    pub configuration: Vec<u8>,
+}
+
+/// Host side of the simulated bus.
+///
+/// Handles can be cloned, so that different tasks use different endpoints concurrently. Control
+/// transfers are queued and run one at a time.
+///
+/// Transfers only complete while the device side runs: [`UsbDevice::run`](embassy_usb::UsbDevice::run)
+/// for the control endpoint, and the classes for the others.
+#[derive(Clone)]
+pub struct Host {
+    shared: Arc<Shared>,
+}
+
+impl Host {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    fn event(&self, event: Event) {
+        self.shared.update(|s| s.events.push_back(event));
+    }
+
+    /// Connect the device to the bus power.
+    pub fn power_on(&self) {
+        self.event(Event::PowerDetected);
+    }
+
+    /// Disconnect the device from the bus power.
+    pub fn power_off(&self) {
+        self.event(Event::PowerRemoved);
+    }
+
+    /// Reset the bus.
+    ///
+    /// The device loses its address and its endpoints are disabled. A control transfer in progress
+    /// fails with [`TransferError::Disabled`].
+    pub fn reset(&self) {
+        self.shared.update(|s| {
+            s.address = 0;
+            let busy = s.control.busy;
+            s.control = ControlState {
+                busy,
+                status: busy.then_some(Err(TransferError::Disabled)),
+                ..Default::default()
+            };
+            for ep in s.endpoints() {
+                ep.enabled = false;
+                ep.stalled = false;
+                ep.packet = None;
+            }
+            s.events.push_back(Event::Reset);
+        });
+    }
+
+    /// Suspend the bus.
+    pub fn suspend(&self) {
+        self.event(Event::Suspend);
+    }
+
+    /// Resume the bus after it was suspended.
+    pub fn resume(&self) {
+        self.event(Event::Resume);
+    }
+
+    /// Whether the device enabled its side of the bus.
+    pub fn is_enabled(&self) -> bool {
+        self.shared.read(|s| s.enabled)
+    }
+
+    /// Address of the device, 0 until the host assigns one.
+    pub fn address(&self) -> u8 {
+        self.shared.read(|s| s.address)
+    }
+
+    /// Whether the device signaled a remote wakeup since the last call.
+    pub fn take_remote_wakeup(&self) -> bool {
+        self.shared.update(|s| std::mem::take(&mut s.remote_wakeup))
+    }
+
+    /// Whether the device soft-disconnected from the bus since the last call.
+    ///
+    /// A host enumerates the device again after it reconnects.
+    pub fn take_disconnect(&self) -> bool {
+        self.shared.update(|s| std::mem::take(&mut s.disconnected))
+    }
+
+    /// Information about an endpoint the device allocated.
+    pub fn endpoint_info(&self, ep: EndpointAddress) -> Option<EndpointInfo> {
+        self.shared.update(|s| s.endpoint(ep).map(|ep| ep.info))
+    }
+
+    /// Whether an endpoint is enabled.
+    pub fn is_endpoint_enabled(&self, ep: EndpointAddress) -> bool {
+        self.shared.update(|s| s.endpoint(ep).is_some_and(|ep| ep.enabled))
+    }
+
+    /// Whether an endpoint is stalled.
+    pub fn is_endpoint_stalled(&self, ep: EndpointAddress) -> bool {
+        self.shared.update(|s| s.endpoint(ep).is_some_and(|ep| ep.stalled))
+    }
+
+    /// Run a control transfer reading data from the device.
+    ///
+    /// The device sends at most `req.length` bytes.
+    pub async fn control_in(&self, req: Request) -> Result<Vec<u8>, TransferError> {
+        assert_eq!(req.direction, Direction::In, "control_in needs an IN request");
+        self.control(req, &[]).await
+    }
+
+    /// Run a control transfer writing data to the device.
+    ///
+    /// The length of `data` must be `req.length`.
+    pub async fn control_out(&self, req: Request, data: &[u8]) -> Result<(), TransferError> {
+        assert_eq!(req.direction, Direction::Out, "control_out needs an OUT request");
+        assert_eq!(data.len(), req.length as usize, "data length doesn't match the request");
+        self.control(req, data).await.map(drop)
+    }
+
+    async fn control(&self, req: Request, data: &[u8]) -> Result<Vec<u8>, TransferError> {
+        self.shared
+            .wait(|s| {
+                if s.control.busy {
+                    return Poll::Pending;
+                }
+                assert_ne!(s.control_max_packet_size, 0, "the device isn't built");
+                s.control = ControlState {
+                    busy: true,
+                    setup: Some(req.to_bytes()),
+                    data_out: data.chunks(s.control_max_packet_size).map(<[u8]>::to_vec).collect(),
+                    ..Default::default()
+                };
+                Poll::Ready(())
+            })
+            .await;
+
+        self.shared
+            .wait(|s| match s.control.status.take() {
+                Some(status) => {
+                    let data = std::mem::take(&mut s.control.data_in);
+                    s.control = ControlState::default();
+                    Poll::Ready(status.map(|()| data))
+                }
+                None => Poll::Pending,
+            })
+            .await
+    }
+
+    /// Read a descriptor with a GET_DESCRIPTOR request.
+    pub async fn get_descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let req = standard(
+            Direction::In,
+            Recipient::Device,
+            Request::GET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            language_id,
+            length,
+        );
+        self.control_in(req).await
+    }
+
+    /// Read a string descriptor, in US English.
+    pub async fn get_string(&self, index: u8) -> Result<String, TransferError> {
+        let desc = self
+            .get_descriptor(descriptor_type::STRING, index, lang_id::ENGLISH_US, 255)
+            .await?;
+        let utf16: Vec<u16> = desc[2..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Ok(String::from_utf16_lossy(&utf16))
+    }
+
+    /// Assign an address to the device.
+    pub async fn set_address(&self, address: u8) -> Result<(), TransferError> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Device,
+            Request::SET_ADDRESS,
+            address as u16,
+            0,
+            0,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Select a configuration, or unconfigure the device with 0.
+    pub async fn set_configuration(&self, configuration: u8) -> Result<(), TransferError> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Device,
+            Request::SET_CONFIGURATION,
+            configuration as u16,
+            0,
+            0,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Select the alternate setting of an interface.
+    pub async fn set_interface(&self, interface: u8, alternate_setting: u8) -> Result<(), TransferError> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Interface,
+            Request::SET_INTERFACE,
+            alternate_setting as u16,
+            interface as u16,
+            0,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Clear the halt condition of an endpoint.
+    pub async fn clear_halt(&self, ep: EndpointAddress) -> Result<(), TransferError> {
+        let req = standard(
+            Direction::Out,
+            Recipient::Endpoint,
+            Request::CLEAR_FEATURE,
+            Request::FEATURE_ENDPOINT_HALT,
+            u8::from(ep) as u16,
+            0,
+        );
+        self.control_out(req, &[]).await
+    }
+
+    /// Enumerate the device like a host does when it is plugged in.
+    ///
+    /// The device is powered and reset, and reads its descriptors while assigning it an address.
+    /// It is then configured with its first configuration.
+    pub async fn enumerate(&self) -> Result<Descriptors, TransferError> {
+        self.power_on();
+        self.reset();
+
+        // The host doesn't know the maximum packet size of the control endpoint yet, so it
+        // only reads the beginning of the device descriptor, which includes it.
+        self.get_descriptor(descriptor_type::DEVICE, 0, 0, 64).await?;
+        self.reset();
+        self.set_address(DEVICE_ADDRESS).await?;
+        let device = self.get_descriptor(descriptor_type::DEVICE, 0, 0, 18).await?;
+
+        let header = self.get_descriptor(descriptor_type::CONFIGURATION, 0, 0, 9).await?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration = self
+            .get_descriptor(descriptor_type::CONFIGURATION, 0, 0, total_length)
+            .await?;
+
+        self.set_configuration(configuration[5]).await?; // bConfigurationValue
+        Ok(Descriptors { device, configuration })
+    }
+
+    fn max_packet_size(&self, ep: EndpointAddress) -> usize {
+        let info = self.endpoint_info(ep).expect("no such endpoint");
+        info.max_packet_size as usize
+    }
+
+    /// Write a packet to an OUT endpoint.
+    ///
+    /// It waits for the device to read the previous packet.
+    pub async fn write(&self, ep: EndpointAddress, data: &[u8]) -> Result<(), TransferError> {
+        assert!(ep.is_out(), "write needs an OUT endpoint");
+        assert!(data.len() <= self.max_packet_size(ep), "packet too large");
+        self.shared
+            .wait(|s| {
+                let ep = s.endpoint(ep).unwrap();
+                if ep.stalled {
+                    Poll::Ready(Err(TransferError::Stall))
+                } else if !ep.enabled {
+                    Poll::Ready(Err(TransferError::Disabled))
+                } else if ep.packet.is_some() {
+                    Poll::Pending
+                } else {
+                    ep.packet = Some(data.to_vec());
+                    Poll::Ready(Ok(()))
+                }
+            })
+            .await
+    }
+
+    /// Read a packet from an IN endpoint.
+    pub async fn read(&self, ep: EndpointAddress) -> Result<Vec<u8>, TransferError> {
+        assert!(ep.is_in(), "read needs an IN endpoint");
+        self.shared
+            .wait(|s| match s.endpoint(ep).expect("no such endpoint") {
+                ep if ep.stalled => Poll::Ready(Err(TransferError::Stall)),
+                ep if !ep.enabled => Poll::Ready(Err(TransferError::Disabled)),
+                ep => match ep.packet.take() {
+                    Some(packet) => Poll::Ready(Ok(packet)),
+                    None => Poll::Pending,
+                },
+            })
+            .await
+    }
+
+    /// Read a packet from an IN endpoint if the device wrote one, without waiting.
+    pub fn try_read(&self, ep: EndpointAddress) -> Result<Option<Vec<u8>>, TransferError> {
+        assert!(ep.is_in(), "read needs an IN endpoint");
+        self.shared.update(|s| match s.endpoint(ep).expect("no such endpoint") {
+            ep if ep.stalled => Err(TransferError::Stall),
+            ep if !ep.enabled => Err(TransferError::Disabled),
+            ep => Ok(ep.packet.take()),
+        })
+    }
+
+    /// Write data to an OUT endpoint as a single transfer.
+    ///
+    /// The data is split in packets of the maximum packet size of the endpoint, and ends with a
+    /// short packet, which is zero-length if needed.
+    pub async fn write_transfer(&self, ep: EndpointAddress, data: &[u8]) -> Result<(), TransferError> {
+        let max_packet_size = self.max_packet_size(ep);
+        for packet in data.chunks(max_packet_size) {
+            self.write(ep, packet).await?;
+        }
+        if data.len() % max_packet_size == 0 {
+            self.write(ep, &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Read a transfer from an IN endpoint: packets until a short packet, or `max_len` bytes.
+    pub async fn read_transfer(&self, ep: EndpointAddress, max_len: usize) -> Result<Vec<u8>, TransferError> {
+        let max_packet_size = self.max_packet_size(ep);
+        let mut data = Vec::new();
+        loop {
+            let packet = self.read(ep).await?;
+            data.extend_from_slice(&packet);
+            if packet.len() < max_packet_size || data.len() >= max_len {
+                return Ok(data);
+            }
+        }
+    }
+}
+
+/// Host controller of the simulated bus, implementing the host driver traits.
+///
+/// It runs `embassy-usb-host` against the simulated device. The device is connected at full
+/// speed once it enables the bus, and disconnected when it disables it or soft-disconnects.
+pub struct HostController {
+    host: Host,
+    connected: bool,
+}
+
+impl HostController {
+    /// Create a host controller on the host side of the bus, powering the device.
+    pub fn new(host: Host) -> Self {
+        host.power_on();
+        Self { host, connected: false }
+    }
+}
+
+impl HostDriver for HostController {
+    type Pipe = Pipe;
+
+    async fn wait_for_device_event(&mut self) -> DeviceEvent {
+        let connected = self.connected;
+        let event = self
+            .host
+            .shared
+            .wait(|s| {
+                // Soft-disconnects only matter to a connected device.
+                let disconnected = std::mem::take(&mut s.disconnected) && connected;
+                match (connected, s.enabled) {
+                    (true, _) if disconnected => Poll::Ready(DeviceEvent::Disconnected),
+                    (true, false) => Poll::Ready(DeviceEvent::Disconnected),
+                    (false, true) => Poll::Ready(DeviceEvent::Connected(Speed::Full)),
+                    _ => Poll::Pending,
+                }
+            })
+            .await;
+        self.connected = matches!(event, DeviceEvent::Connected(_));
+        event
+    }
+
+    async fn bus_reset(&mut self) {
+        self.host.reset();
+    }
+
+    fn alloc_pipe(&mut self, address: u8, endpoint: &EndpointInfo) -> Result<Pipe, PipeAllocError> {
+        Ok(Pipe {
+            host: self.host.clone(),
+            address,
+            info: *endpoint,
+        })
+    }
+}
+
+/// Pipe of a [`HostController`].
+///
+/// Transfers to another address than the device's time out, like on a real bus.
+pub struct Pipe {
+    host: Host,
+    address: u8,
+    info: EndpointInfo,
+}
+
+impl Pipe {
+    /// Check that the device is there, at the address of the pipe.
+    fn check(&self) -> Result<(), PipeError> {
+        self.host.shared.read(|s| {
+            if !s.enabled || s.disconnected {
+                Err(PipeError::Disconnected)
+            } else if s.address != self.address {
+                Err(PipeError::Timeout)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn error(&self, e: TransferError) -> PipeError {
+        match (e, self.check()) {
+            (TransferError::Stall, _) => PipeError::Stall,
+            (TransferError::Disabled, Err(e)) => e,
+            // The device doesn't answer on disabled endpoints.
+            (TransferError::Disabled, Ok(())) => PipeError::Timeout,
+        }
+    }
+
+    /// Check that the endpoint of the pipe exists on the device.
+    fn check_endpoint(&self) -> Result<(), PipeError> {
+        self.check()?;
+        match self.host.endpoint_info(self.info.addr) {
+            Some(info) if info.ep_type == self.info.ep_type => Ok(()),
+            _ => Err(PipeError::Timeout),
+        }
+    }
+}
+
+impl embassy_usb_driver::host::Pipe for Pipe {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn control_in(&mut self, setup: &[u8; 8], buf: &mut [u8]) -> Result<usize, PipeError> {
+        assert_eq!(self.info.ep_type, EndpointType::Control, "not a control pipe");
+        self.check()?;
+        let data = self
+            .host
+            .control_in(Request::parse(setup))
+            .await
+            .map_err(|e| self.error(e))?;
+        let buf = buf.get_mut(..data.len()).ok_or(PipeError::BufferOverflow)?;
+        buf.copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn control_out(&mut self, setup: &[u8; 8], data: &[u8]) -> Result<(), PipeError> {
+        assert_eq!(self.info.ep_type, EndpointType::Control, "not a control pipe");
+        self.check()?;
+        self.host
+            .control_out(Request::parse(setup), data)
+            .await
+            .map_err(|e| self.error(e))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, PipeError> {
+        self.check_endpoint()?;
+        let packet = self.host.read(self.info.addr).await.map_err(|e| self.error(e))?;
+        let buf = buf.get_mut(..packet.len()).ok_or(PipeError::BufferOverflow)?;
+        buf.copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), PipeError> {
+        self.check_endpoint()?;
+        if data.len() > self.info.max_packet_size as usize {
+            return Err(PipeError::BufferOverflow);
+        }
+        self.host.write(self.info.addr, data).await.map_err(|e| self.error(e))
+    }
+}
+
+fn standard(direction: Direction, recipient: Recipient, request: u8, value: u16, index: u16, length: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Standard,
+        recipient,
+        request,
+        value,
+        index,
+        length,
+    }
+}
@@ -0,0 +1,437 @@
+#![doc = include_str!("../README.md")]
+#![warn(missing_docs)]
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use embassy_usb_driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType, Event, Unsupported,
+};
+
+mod host;
+pub use host::{Descriptors, Host, HostController, Pipe, TransferError};
+#[cfg(feature = "usbip")]
+pub mod usbip;
+
+/// Number of endpoints in each direction, besides the control endpoint.
+pub const ENDPOINT_COUNT: usize = 15;
+
+/// Create a simulated USB bus, with the driver for the device side, and the host side.
+pub fn new() -> (Driver, Host) {
+    let shared = Arc::new(Shared::default());
+    (Driver { shared: shared.clone() }, Host::new(shared))
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+}
+
+impl Shared {
+    /// Access the state, waking all the tasks waiting for it to change.
+    fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let (r, wakers) = {
+            let mut state = self.state.lock().unwrap();
+            let r = f(&mut state);
+            (r, std::mem::take(&mut state.wakers))
+        };
+        wakers.into_iter().for_each(Waker::wake);
+        r
+    }
+
+    /// Access the state, without changing it.
+    fn read<R>(&self, f: impl FnOnce(&State) -> R) -> R {
+        f(&self.state.lock().unwrap())
+    }
+
+    /// Poll `f` until it is ready. It must only change the state when it is.
+    async fn wait<R>(&self, mut f: impl FnMut(&mut State) -> Poll<R>) -> R {
+        poll_fn(|cx: &mut Context<'_>| {
+            let mut state = self.state.lock().unwrap();
+            match f(&mut state) {
+                Poll::Ready(r) => {
+                    let wakers = std::mem::take(&mut state.wakers);
+                    drop(state);
+                    wakers.into_iter().for_each(Waker::wake);
+                    Poll::Ready(r)
+                }
+                Poll::Pending => {
+                    if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                        state.wakers.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[derive(Default)]
+struct State {
+    wakers: Vec<Waker>,
+
+    /// Maximum packet size of the control endpoint, 0 until the driver is started.
+    control_max_packet_size: usize,
+    /// Whether the device enabled the bus.
+    enabled: bool,
+    events: VecDeque<Event>,
+    address: u8,
+    remote_wakeup: bool,
+    /// Whether the device soft-disconnected from the bus.
+    disconnected: bool,
+    control: ControlState,
+
+    ep_in: Vec<EndpointState>,
+    ep_out: Vec<EndpointState>,
+}
+
+impl State {
+    fn endpoint(&mut self, addr: EndpointAddress) -> Option<&mut EndpointState> {
+        let eps = match addr.direction() {
+            Direction::In => &mut self.ep_in,
+            Direction::Out => &mut self.ep_out,
+        };
+        eps.get_mut(addr.index().checked_sub(1)?)
+    }
+
+    fn endpoints(&mut self) -> impl Iterator<Item = &mut EndpointState> {
+        self.ep_in.iter_mut().chain(self.ep_out.iter_mut())
+    }
+}
+
+#[derive(Default)]
+struct ControlState {
+    /// Whether a control transfer is in progress.
+    busy: bool,
+    /// SETUP packet waiting for the device.
+    setup: Option<[u8; 8]>,
+    /// Packets of the DATA OUT stage waiting for the device.
+    data_out: VecDeque<Vec<u8>>,
+    /// Data of the DATA IN stage sent by the device so far.
+    data_in: Vec<u8>,
+    /// Result of the STATUS stage, once done.
+    status: Option<Result<(), TransferError>>,
+}
+
+struct EndpointState {
+    info: EndpointInfo,
+    enabled: bool,
+    stalled: bool,
+    /// Packet in the endpoint buffer.
+    packet: Option<Vec<u8>>,
+}
+
+/// Device side of the simulated bus.
+pub struct Driver {
+    shared: Arc<Shared>,
+}
+
+impl Driver {
+    fn alloc(
+        &mut self,
+        dir: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Endpoint, EndpointAllocError> {
+        self.shared.update(|s| {
+            let eps = match dir {
+                Direction::In => &mut s.ep_in,
+                Direction::Out => &mut s.ep_out,
+            };
+            if eps.len() == ENDPOINT_COUNT {
+                return Err(EndpointAllocError);
+            }
+            let info = EndpointInfo {
+                addr: EndpointAddress::from_parts(eps.len() + 1, dir),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            };
+            eps.push(EndpointState {
+                info,
+                enabled: false,
+                stalled: false,
+                packet: None,
+            });
+            Ok(Endpoint {
+                shared: self.shared.clone(),
+                info,
+            })
+        })
+    }
+}
+
+impl<'a> embassy_usb_driver::Driver<'a> for Driver {
+    type EndpointOut = Endpoint;
+    type EndpointIn = Endpoint;
+    type ControlPipe = ControlPipe;
+    type Bus = Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.alloc(Direction::Out, ep_type, max_packet_size, interval_ms)
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.alloc(Direction::In, ep_type, max_packet_size, interval_ms)
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        self.shared
+            .update(|s| s.control_max_packet_size = control_max_packet_size as usize);
+        (
+            Bus {
+                shared: self.shared.clone(),
+            },
+            ControlPipe {
+                shared: self.shared,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+/// Bus of the simulated device.
+pub struct Bus {
+    shared: Arc<Shared>,
+}
+
+impl embassy_usb_driver::Bus for Bus {
+    async fn enable(&mut self) {
+        self.shared.update(|s| s.enabled = true);
+    }
+
+    async fn disable(&mut self) {
+        self.shared.update(|s| {
+            s.enabled = false;
+            for ep in s.endpoints() {
+                ep.enabled = false;
+                ep.packet = None;
+            }
+        });
+    }
+
+    async fn poll(&mut self) -> Event {
+        self.shared
+            .wait(|s| match s.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => Poll::Pending,
+            })
+            .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        self.shared.update(|s| {
+            if let Some(ep) = s.endpoint(ep_addr) {
+                ep.enabled = enabled;
+                if !enabled {
+                    ep.packet = None;
+                }
+            }
+        });
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        self.shared.update(|s| {
+            if let Some(ep) = s.endpoint(ep_addr) {
+                ep.stalled = stalled;
+            }
+        });
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        self.shared.update(|s| s.endpoint(ep_addr).is_some_and(|ep| ep.stalled))
+    }
+
+    fn force_reset(&mut self) -> Result<(), Unsupported> {
+        self.shared.update(|s| {
+            s.disconnected = true;
+            for ep in s.endpoints() {
+                ep.enabled = false;
+                ep.packet = None;
+            }
+        });
+        Ok(())
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.shared.update(|s| s.remote_wakeup = true);
+        Ok(())
+    }
+}
+
+/// Endpoint of the simulated device.
+pub struct Endpoint {
+    shared: Arc<Shared>,
+    info: EndpointInfo,
+}
+
+impl embassy_usb_driver::Endpoint for Endpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        let addr = self.info.addr;
+        self.shared
+            .wait(|s| match s.endpoint(addr).unwrap().enabled {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            })
+            .await
+    }
+
+    async fn stall(&mut self) -> Result<(), Unsupported> {
+        let addr = self.info.addr;
+        // A packet written to an IN endpoint is sent before it stalls. A packet received on an OUT
+        // endpoint but not read yet is dropped.
+        self.shared
+            .wait(|s| {
+                let ep = s.endpoint(addr).unwrap();
+                if addr.is_in() && ep.enabled && ep.packet.is_some() {
+                    return Poll::Pending;
+                }
+                ep.stalled = true;
+                if addr.is_out() {
+                    ep.packet = None;
+                }
+                Poll::Ready(())
+            })
+            .await;
+        self.shared
+            .wait(|s| {
+                let ep = s.endpoint(addr).unwrap();
+                match ep.stalled && ep.enabled {
+                    true => Poll::Pending,
+                    false => Poll::Ready(()),
+                }
+            })
+            .await;
+        Ok(())
+    }
+}
+
+impl embassy_usb_driver::EndpointOut for Endpoint {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let addr = self.info.addr;
+        self.shared
+            .wait(|s| {
+                let ep = s.endpoint(addr).unwrap();
+                if !ep.enabled {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                match &ep.packet {
+                    Some(packet) if packet.len() > buf.len() => Poll::Ready(Err(EndpointError::BufferOverflow)),
+                    Some(_) => {
+                        let packet = ep.packet.take().unwrap();
+                        buf[..packet.len()].copy_from_slice(&packet);
+                        Poll::Ready(Ok(packet.len()))
+                    }
+                    None => Poll::Pending,
+                }
+            })
+            .await
+    }
+}
+
+impl embassy_usb_driver::EndpointIn for Endpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        let addr = self.info.addr;
+        self.shared
+            .wait(|s| {
+                let ep = s.endpoint(addr).unwrap();
+                if !ep.enabled {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                match ep.packet {
+                    Some(_) => Poll::Pending,
+                    None => {
+                        ep.packet = Some(buf.to_vec());
+                        Poll::Ready(Ok(()))
+                    }
+                }
+            })
+            .await
+    }
+}
+
+/// Control pipe of the simulated device.
+pub struct ControlPipe {
+    shared: Arc<Shared>,
+    max_packet_size: usize,
+}
+
+impl ControlPipe {
+    fn status(&mut self, status: Result<(), TransferError>) {
+        self.shared.update(|s| s.control.status = Some(status));
+    }
+}
+
+impl embassy_usb_driver::ControlPipe for ControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        self.shared
+            .wait(|s| match s.control.setup.take() {
+                Some(setup) => Poll::Ready(setup),
+                None => Poll::Pending,
+            })
+            .await
+    }
+
+    async fn data_out(&mut self, buf: &mut [u8], _first: bool, _last: bool) -> Result<usize, EndpointError> {
+        self.shared
+            .wait(|s| match s.control.data_out.pop_front() {
+                Some(packet) if packet.len() > buf.len() => Poll::Ready(Err(EndpointError::BufferOverflow)),
+                Some(packet) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Poll::Ready(Ok(packet.len()))
+                }
+                None => Poll::Pending,
+            })
+            .await
+    }
+
+    async fn data_in(&mut self, data: &[u8], _first: bool, last: bool) -> Result<(), EndpointError> {
+        self.shared.update(|s| {
+            s.control.data_in.extend_from_slice(data);
+            if last {
+                s.control.status = Some(Ok(()));
+            }
+        });
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.status(Ok(()));
+    }
+
+    async fn reject(&mut self) {
+        self.status(Err(TransferError::Stall));
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        self.shared.update(|s| {
+            s.address = addr;
+            s.control.status = Some(Ok(()));
+        });
+    }
+}
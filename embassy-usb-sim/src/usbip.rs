@@ -0,0 +1,507 @@
+//! Export the simulated device over [USB/IP](https://docs.kernel.org/usb/usbip_protocol.html).
+//!
+//! The [`Server`] drives the [`Host`] side of the bus on behalf of a remote USB/IP client, so that
+//! the device can be attached to a Linux host with `vhci-hcd`, and used by its real drivers:
+//!
+//! ```text
+//! modprobe vhci-hcd
+//! usbip list -r 127.0.0.1
+//! usbip attach -r 127.0.0.1 -b 1-1
+//! ```
+//!
+//! Isochronous transfers are not supported.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::task::{Poll, Waker};
+
+use async_io::Async;
+use embassy_futures::select::{select, Either};
+use embassy_usb::control::Request;
+use embassy_usb_driver::{Direction, EndpointAddress, EndpointType};
+use futures_lite::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Descriptors, Host, TransferError};
+
+/// Default TCP port of USB/IP servers.
+pub const PORT: u16 = 3240;
+
+const VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const CMD_SUBMIT: u32 = 0x0001;
+const CMD_UNLINK: u32 = 0x0002;
+const RET_SUBMIT: u32 = 0x0003;
+const RET_UNLINK: u32 = 0x0004;
+
+const DIR_IN: u32 = 1;
+const URB_ZERO_PACKET: u32 = 0x0040;
+
+// Linux error codes, as URB statuses.
+const EPIPE: i32 = 32;
+const EINVAL: i32 = 22;
+const EOVERFLOW: i32 = 75;
+const ECONNRESET: i32 = 104;
+const ESHUTDOWN: i32 = 108;
+
+/// Speed the device is reported at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// Low speed, 1.5 Mbit/s.
+    Low,
+    /// Full speed, 12 Mbit/s.
+    Full,
+    /// High speed, 480 Mbit/s.
+    High,
+}
+
+/// USB/IP server configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Bus ID of the device, given to `usbip attach -b`.
+    pub busid: String,
+    /// Speed the device is reported at.
+    pub speed: Speed,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            busid: "1-1".into(),
+            speed: Speed::Full,
+        }
+    }
+}
+
+/// USB/IP server, exporting the device of a simulated bus.
+pub struct Server {
+    host: Host,
+    config: Config,
+}
+
+impl Server {
+    /// Create a server for the device on the other side of `host`.
+    pub fn new(host: Host, config: Config) -> Self {
+        Self { host, config }
+    }
+
+    /// Listen for USB/IP clients on a TCP address, and serve them one at a time.
+    ///
+    /// The device is attached for as long as the connection that imported it stays open, so
+    /// clients listing devices in the meantime wait until it is detached.
+    pub async fn listen(&self, addr: impl Into<SocketAddr>) -> io::Result<()> {
+        let listener = Async::<TcpListener>::bind(addr)?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            log::info!("USB/IP client connected from {}", peer);
+            if let Err(e) = self.serve(stream).await {
+                log::warn!("USB/IP connection failed: {}", e);
+            }
+        }
+    }
+
+    /// Serve a single USB/IP client connection.
+    ///
+    /// The client lists the exported devices or imports the device. Once imported, the device
+    /// is re-enumerated, and transfers run until the client disconnects.
+    pub async fn serve(&self, stream: impl AsyncRead + AsyncWrite + Unpin) -> io::Result<()> {
+        let (mut reader, mut writer) = futures_lite::io::split(stream);
+
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).await?;
+        let code = u16::from_be_bytes([header[2], header[3]]);
+        match code {
+            OP_REQ_DEVLIST => {
+                let descriptors = self.enumerate().await?;
+                let mut reply = op_header(OP_REP_DEVLIST, 0);
+                reply.extend_from_slice(&1u32.to_be_bytes());
+                self.write_device(&mut reply, &descriptors);
+                for interface in interfaces(&descriptors.configuration) {
+                    reply.extend_from_slice(&[interface[5], interface[6], interface[7], 0]);
+                }
+                writer.write_all(&reply).await?;
+                writer.flush().await
+            }
+            OP_REQ_IMPORT => {
+                let mut busid = [0; 32];
+                reader.read_exact(&mut busid).await?;
+                if c_str(&busid) != self.config.busid.as_bytes() {
+                    log::warn!("USB/IP client imports unknown bus ID");
+                    writer.write_all(&op_header(OP_REP_IMPORT, 1)).await?;
+                    return writer.flush().await;
+                }
+
+                let descriptors = self.enumerate().await?;
+                let mut reply = op_header(OP_REP_IMPORT, 0);
+                self.write_device(&mut reply, &descriptors);
+                writer.write_all(&reply).await?;
+                writer.flush().await?;
+                log::info!("USB/IP device attached");
+
+                let connection = Connection::new(self.host.clone());
+                match select(connection.receive(&mut reader), connection.reply(&mut writer)).await {
+                    Either::First(r) | Either::Second(r) => r,
+                }
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown USB/IP operation {:#06x}", code),
+            )),
+        }
+    }
+
+    async fn enumerate(&self) -> io::Result<Descriptors> {
+        self.host
+            .enumerate()
+            .await
+            .map_err(|e| io::Error::other(format!("enumeration failed: {:?}", e)))
+    }
+
+    /// Append the description of the device, shared by the device list and import replies.
+    fn write_device(&self, buf: &mut Vec<u8>, descriptors: &Descriptors) {
+        let device = &descriptors.device;
+        let configuration = &descriptors.configuration;
+        let speed: u32 = match self.config.speed {
+            Speed::Low => 1,
+            Speed::Full => 2,
+            Speed::High => 3,
+        };
+
+        let mut path = [0; 256];
+        let path_str = format!("/sys/devices/platform/embassy-usb-sim/usb1/{}", self.config.busid);
+        path[..path_str.len()].copy_from_slice(path_str.as_bytes());
+        let mut busid = [0; 32];
+        busid[..self.config.busid.len()].copy_from_slice(self.config.busid.as_bytes());
+
+        buf.extend_from_slice(&path);
+        buf.extend_from_slice(&busid);
+        buf.extend_from_slice(&1u32.to_be_bytes()); // bus number
+        buf.extend_from_slice(&(self.host.address() as u32).to_be_bytes());
+        buf.extend_from_slice(&speed.to_be_bytes());
+        for field in [8, 10, 12] {
+            // idVendor, idProduct and bcdDevice are little-endian in descriptors.
+            buf.extend_from_slice(&[device[field + 1], device[field]]);
+        }
+        buf.extend_from_slice(&[
+            device[4],        // bDeviceClass
+            device[5],        // bDeviceSubClass
+            device[6],        // bDeviceProtocol
+            configuration[5], // bConfigurationValue
+            device[17],       // bNumConfigurations
+            configuration[4], // bNumInterfaces
+        ]);
+    }
+}
+
+fn op_header(code: u16, status: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf.extend_from_slice(&code.to_be_bytes());
+    buf.extend_from_slice(&status.to_be_bytes());
+    buf
+}
+
+fn c_str(buf: &[u8]) -> &[u8] {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    &buf[..len]
+}
+
+/// Interface descriptors of the first alternate setting of each interface.
+fn interfaces(configuration: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = configuration;
+    std::iter::from_fn(move || loop {
+        let len = *rest.first()? as usize;
+        if len < 2 || len > rest.len() {
+            return None;
+        }
+        let (descriptor, next) = rest.split_at(len);
+        rest = next;
+        if descriptor[1] == embassy_usb::descriptor::descriptor_type::INTERFACE && len >= 9 && descriptor[3] == 0 {
+            return Some(descriptor);
+        }
+    })
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// URB submitted by the client.
+struct Submit {
+    seqnum: u32,
+    ep: EndpointAddress,
+    flags: u32,
+    length: usize,
+    /// Number of isochronous packets, which are unsupported.
+    iso_packets: u32,
+    setup: [u8; 8],
+    data: Vec<u8>,
+}
+
+/// Transfer of an URB, resulting in its actual length and the data read, or a negative error code.
+type Transfer = Pin<Box<dyn Future<Output = Result<(usize, Vec<u8>), i32>>>>;
+
+/// State of an attached device.
+///
+/// URBs are queued per endpoint, and run in order. Those of different endpoints run concurrently.
+struct Connection {
+    host: Host,
+    inner: RefCell<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    waker: Option<Waker>,
+    /// URBs waiting for the previous ones of their endpoint, by endpoint address.
+    queued: BTreeMap<u8, VecDeque<Submit>>,
+    /// URB being transferred, by endpoint address.
+    active: BTreeMap<u8, (u32, Transfer)>,
+    /// Replies waiting to be sent.
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl Inner {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Connection {
+    fn new(host: Host) -> Self {
+        Self {
+            host,
+            inner: RefCell::new(Inner::default()),
+        }
+    }
+
+    /// Receive commands from the client, until it disconnects.
+    async fn receive(&self, reader: &mut (impl AsyncRead + Unpin)) -> io::Result<()> {
+        let mut header = [0; 48];
+        loop {
+            match reader.read_exact(&mut header).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    log::info!("USB/IP device detached");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+
+            let command = be_u32(&header, 0);
+            let seqnum = be_u32(&header, 4);
+            let direction = match be_u32(&header, 12) {
+                DIR_IN => Direction::In,
+                _ => Direction::Out,
+            };
+            let ep = EndpointAddress::from_parts(be_u32(&header, 16) as usize, direction);
+
+            match command {
+                CMD_SUBMIT => {
+                    let length = be_u32(&header, 24) as usize;
+                    let mut submit = Submit {
+                        seqnum,
+                        ep,
+                        flags: be_u32(&header, 20),
+                        length,
+                        iso_packets: be_u32(&header, 32),
+                        setup: header[40..48].try_into().unwrap(),
+                        data: Vec::new(),
+                    };
+                    if direction == Direction::Out {
+                        submit.data = vec![0; length];
+                        reader.read_exact(&mut submit.data).await?;
+                    }
+                    // Skip the isochronous packet descriptors. `0xffffffff` means non-isochronous.
+                    if submit.iso_packets != 0xffff_ffff {
+                        let mut descriptors = vec![0; submit.iso_packets as usize * 16];
+                        reader.read_exact(&mut descriptors).await?;
+                    }
+
+                    let mut inner = self.inner.borrow_mut();
+                    inner.queued.entry(queue(ep)).or_default().push_back(submit);
+                    inner.wake();
+                }
+                CMD_UNLINK => {
+                    let unlinked = be_u32(&header, 20);
+                    let mut inner = self.inner.borrow_mut();
+                    let status = if inner.unlink(unlinked) { -ECONNRESET } else { 0 };
+
+                    let mut reply = ret_header(RET_UNLINK, seqnum);
+                    reply.extend_from_slice(&status.to_be_bytes());
+                    reply.extend_from_slice(&[0; 24]);
+                    inner.replies.push_back(reply);
+                    inner.wake();
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown USB/IP command {:#x}", command),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Run the transfers, and send their results to the client.
+    async fn reply(&self, writer: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        loop {
+            let replies = poll_fn(|cx| {
+                let mut inner = self.inner.borrow_mut();
+                let inner = &mut *inner;
+                inner.waker = Some(cx.waker().clone());
+
+                for (&queue, submits) in inner.queued.iter_mut() {
+                    if inner.active.contains_key(&queue) {
+                        continue;
+                    }
+                    if let Some(submit) = submits.pop_front() {
+                        inner.active.insert(queue, (submit.seqnum, self.transfer(submit)));
+                    }
+                }
+
+                let mut done = Vec::new();
+                for (&queue, (seqnum, transfer)) in inner.active.iter_mut() {
+                    if let Poll::Ready(result) = transfer.as_mut().poll(cx) {
+                        inner.replies.push_back(ret_submit(*seqnum, result));
+                        done.push(queue);
+                    }
+                }
+                // The next URBs of these endpoints start once the replies are sent.
+                for queue in &done {
+                    inner.active.remove(queue);
+                }
+
+                match inner.replies.is_empty() {
+                    true => Poll::Pending,
+                    false => Poll::Ready(std::mem::take(&mut inner.replies)),
+                }
+            })
+            .await;
+
+            for reply in replies {
+                writer.write_all(&reply).await?;
+            }
+            writer.flush().await?;
+        }
+    }
+
+    fn transfer(&self, submit: Submit) -> Transfer {
+        let host = self.host.clone();
+        Box::pin(async move {
+            if submit.iso_packets != 0 && submit.iso_packets != 0xffff_ffff {
+                log::warn!("USB/IP isochronous transfers are not supported");
+                return Err(-EINVAL);
+            }
+
+            if submit.ep.index() == 0 {
+                let req = Request::parse(&submit.setup);
+                return match req.direction {
+                    Direction::In => host.control_in(req).await.map(|data| (data.len(), data)),
+                    Direction::Out => host
+                        .control_out(req, &submit.data)
+                        .await
+                        .map(|()| (submit.data.len(), Vec::new())),
+                }
+                .map_err(status);
+            }
+
+            let Some(info) = host.endpoint_info(submit.ep) else {
+                return Err(-EPIPE);
+            };
+            if info.ep_type == EndpointType::Isochronous {
+                log::warn!("USB/IP isochronous transfers are not supported");
+                return Err(-EINVAL);
+            }
+            let max_packet_size = info.max_packet_size as usize;
+
+            match submit.ep.direction() {
+                Direction::In => {
+                    let data = host.read_transfer(submit.ep, submit.length).await.map_err(status)?;
+                    match data.len() > submit.length {
+                        true => Err(-EOVERFLOW),
+                        false => Ok((data.len(), data)),
+                    }
+                }
+                Direction::Out => {
+                    for packet in submit.data.chunks(max_packet_size) {
+                        host.write(submit.ep, packet).await.map_err(status)?;
+                    }
+                    let zlp = submit.flags & URB_ZERO_PACKET != 0 && submit.data.len() % max_packet_size == 0;
+                    if submit.data.is_empty() || zlp {
+                        host.write(submit.ep, &[]).await.map_err(status)?;
+                    }
+                    Ok((submit.data.len(), Vec::new()))
+                }
+            }
+        })
+    }
+}
+
+impl Inner {
+    /// Cancel a URB, returning whether it was still pending.
+    fn unlink(&mut self, seqnum: u32) -> bool {
+        for submits in self.queued.values_mut() {
+            if let Some(i) = submits.iter().position(|s| s.seqnum == seqnum) {
+                submits.remove(i);
+                return true;
+            }
+        }
+        let active = self.active.iter().find(|(_, (s, _))| *s == seqnum).map(|(&q, _)| q);
+        if let Some(queue) = active {
+            // Dropping the transfer cancels it. Data already transferred is lost.
+            self.active.remove(&queue);
+            return true;
+        }
+        false
+    }
+}
+
+/// Key of the URB queue of an endpoint. Control transfers share a single one.
+fn queue(ep: EndpointAddress) -> u8 {
+    match ep.index() {
+        0 => 0,
+        _ => ep.into(),
+    }
+}
+
+fn status(e: TransferError) -> i32 {
+    match e {
+        TransferError::Stall => -EPIPE,
+        TransferError::Disabled => -ESHUTDOWN,
+    }
+}
+
+fn ret_header(command: u32, seqnum: u32) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&command.to_be_bytes());
+    buf.extend_from_slice(&seqnum.to_be_bytes());
+    // Device ID, direction and endpoint are unused in replies.
+    buf.extend_from_slice(&[0; 12]);
+    buf
+}
+
+fn ret_submit(seqnum: u32, result: Result<(usize, Vec<u8>), i32>) -> Vec<u8> {
+    let (status, length, data) = match result {
+        Ok((length, data)) => (0, length, data),
+        Err(status) => (status, 0, Vec::new()),
+    };
+    let mut buf = ret_header(RET_SUBMIT, seqnum);
+    buf.extend_from_slice(&status.to_be_bytes());
+    buf.extend_from_slice(&(length as u32).to_be_bytes());
+    buf.extend_from_slice(&0u32.to_be_bytes()); // start frame
+    buf.extend_from_slice(&0u32.to_be_bytes()); // number of isochronous packets
+    buf.extend_from_slice(&0u32.to_be_bytes()); // error count
+    buf.extend_from_slice(&[0; 8]); // padding
+    buf.extend_from_slice(&data);
+    buf
+}
@@ -0,0 +1,1429 @@
+use std::sync::{Arc, Mutex};
+
+use embassy_futures::block_on;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass, ParityType, StopBits};
+use embassy_usb::class::hid::descriptor::{Usage, UsagePage};
+use embassy_usb::class::hid::report::{InputReport, MouseReport};
+use embassy_usb::class::hid::{self, HidProtocolMode, HidReaderWriter, HidWriter, ReportId, RequestHandler};
+use embassy_usb::control::{OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Direction, EndpointAddress, EndpointError};
+use embassy_usb::{Builder, Config};
+use embassy_usb_sim::TransferError;
+
+fn config() -> Config<'static> {
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+    config
+}
+
+fn class_request(direction: Direction, request: u8, value: u16, index: u16, length: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+/// Split a configuration descriptor into its descriptors, checking that they fill it exactly.
+fn all_descriptors(configuration: &[u8]) -> Vec<&[u8]> {
+    let mut descriptors = Vec::new();
+    let mut rest = configuration;
+    while !rest.is_empty() {
+        assert!(rest[0] >= 2 && rest[0] as usize <= rest.len(), "bad descriptor length");
+        let (desc, tail) = rest.split_at(rest[0] as usize);
+        descriptors.push(desc);
+        rest = tail;
+    }
+    descriptors
+}
+
+/// First interface, interface count, class, subclass and protocol of the interface association
+/// descriptors.
+fn functions(configuration: &[u8]) -> Vec<[u8; 5]> {
+    all_descriptors(configuration)
+        .into_iter()
+        .filter(|d| d[1] == 0x0b)
+        .map(|d| [d[2], d[3], d[4], d[5], d[6]])
+        .collect()
+}
+
+/// Number, alternate setting, class, subclass and protocol of the interface descriptors.
+fn interfaces(configuration: &[u8]) -> Vec<[u8; 5]> {
+    all_descriptors(configuration)
+        .into_iter()
+        .filter(|d| d[1] == 0x04)
+        .map(|d| [d[2], d[3], d[5], d[6], d[7]])
+        .collect()
+}
+
+/// Address, attributes and maximum packet size of the endpoint descriptors.
+fn endpoints(configuration: &[u8]) -> Vec<(u8, u8, u16)> {
+    all_descriptors(configuration)
+        .into_iter()
+        .filter(|d| d[1] == 0x05)
+        .map(|d| (d[2], d[3], u16::from_le_bytes([d[4], d[5]])))
+        .collect()
+}
+
+#[test]
+fn cdc_acm_line_coding() {
+    const SET_LINE_CODING: u8 = 0x20;
+    const GET_LINE_CODING: u8 = 0x21;
+    const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_acm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        host.enumerate().await.unwrap();
+
+        // 115200 baud, 2 stop bits, even parity, 7 data bits.
+        let coding = [0x00, 0xc2, 0x01, 0x00, 2, 2, 7];
+        let req = class_request(Direction::Out, SET_LINE_CODING, 0, 0, 7);
+        host.control_out(req, &coding).await.unwrap();
+        let req = class_request(Direction::In, GET_LINE_CODING, 0, 0, 7);
+        assert_eq!(host.control_in(req).await.unwrap(), coding);
+
+        let line_coding = class.line_coding();
+        assert_eq!(line_coding.data_rate(), 115200);
+        assert_eq!(line_coding.stop_bits(), StopBits::Two);
+        assert_eq!(line_coding.parity_type(), ParityType::Even);
+        assert_eq!(line_coding.data_bits(), 7);
+
+        assert!(!class.dtr());
+        let req = class_request(Direction::Out, SET_CONTROL_LINE_STATE, 0x0003, 0, 0);
+        host.control_out(req, &[]).await.unwrap();
+        assert!(class.dtr());
+        assert!(class.rts());
+
+        // Data goes through the data interface endpoints.
+        let data_in = EndpointAddress::from_parts(2, Direction::In);
+        let (written, read) = join(class.write_packet(b"hello"), host.read(data_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), b"hello");
+    }));
+}
+
+type Report = (ReportId, Vec<u8>);
+
+#[derive(Clone, Default)]
+struct Reports {
+    set: Arc<Mutex<Vec<Report>>>,
+}
+
+impl RequestHandler for Reports {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        self.set.lock().unwrap().push((id, data.to_vec()));
+        OutResponse::Accepted
+    }
+}
+
+#[test]
+fn hid_reports() {
+    const HID_REQ_SET_REPORT: u8 = 0x09;
+    const HID_DESC_DESCTYPE_HID_REPORT: u16 = 0x22;
+    // Vendor-defined, with 8 bytes input and output reports.
+    const REPORT_DESCRIPTOR: &[u8] = &[
+        0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x08, 0x09, 0x01,
+        0x81, 0x02, 0x09, 0x01, 0x91, 0x02, 0xc0,
+    ];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = hid::State::new();
+    // SET_REPORT requests go to the handler in the config, reports on the OUT endpoint to the reader's.
+    let mut reports = Reports::default();
+    let mut control_reports = reports.clone();
+    let set_reports = reports.set.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let hid = HidReaderWriter::<_, 8, 8>::new(
+        &mut builder,
+        &mut state,
+        hid::Config {
+            report_descriptor: REPORT_DESCRIPTOR,
+            request_handler: Some(&mut control_reports),
+            poll_ms: 10,
+            max_packet_size: 8,
+            boot_protocol: hid::HidBootProtocol::None,
+        },
+    );
+    let (reader, mut writer) = hid.split();
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        host.enumerate().await.unwrap();
+
+        let req = Request {
+            direction: Direction::In,
+            request_type: RequestType::Standard,
+            recipient: Recipient::Interface,
+            request: Request::GET_DESCRIPTOR,
+            value: HID_DESC_DESCTYPE_HID_REPORT << 8,
+            index: 0,
+            length: 256,
+        };
+        assert_eq!(host.control_in(req).await.unwrap(), REPORT_DESCRIPTOR);
+
+        let report_in = EndpointAddress::from_parts(1, Direction::In);
+        let (written, read) = join(writer.write(&[1, 2, 3, 4, 5, 6, 7, 8]), host.read(report_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // Output reports come with SET_REPORT requests, or on the OUT endpoint.
+        let report_out = EndpointAddress::from_parts(1, Direction::Out);
+        select(reader.run(false, &mut reports), async {
+            let req = class_request(Direction::Out, HID_REQ_SET_REPORT, 0x0200, 0, 2);
+            host.control_out(req, &[0xaa, 0x55]).await.unwrap();
+            host.write(report_out, &[8, 7, 6, 5, 4, 3, 2, 1]).await.unwrap();
+            // Let the reader handle the report.
+            embassy_futures::yield_now().await;
+        })
+        .await;
+    }));
+
+    assert_eq!(
+        *set_reports.lock().unwrap(),
+        [
+            (ReportId::Out(0), vec![0xaa, 0x55]),
+            (ReportId::Out(0), vec![8, 7, 6, 5, 4, 3, 2, 1]),
+        ]
+    );
+}
+
+#[test]
+fn hid_boot_protocol() {
+    const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+    const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = hid::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut writer = HidWriter::<_, 8>::new(
+        &mut builder,
+        &mut state,
+        hid::Config {
+            report_descriptor: MouseReport::descriptor(),
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+            boot_protocol: MouseReport::BOOT_PROTOCOL,
+        },
+    );
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+        // The interface has the boot subclass and the mouse protocol.
+        let interface = descriptors.configuration.windows(2).position(|w| w == [9, 4]).unwrap();
+        assert_eq!(
+            descriptors.configuration[interface + 5..interface + 8],
+            [0x03, 0x01, 0x02]
+        );
+
+        let report_in = EndpointAddress::from_parts(1, Direction::In);
+        let report = MouseReport {
+            buttons: 0x09,
+            x: -1,
+            y: 2,
+            wheel: 3,
+            pan: -4,
+        };
+
+        // Report protocol by default.
+        let req = class_request(Direction::In, HID_REQ_GET_PROTOCOL, 0, 0, 1);
+        assert_eq!(host.control_in(req).await.unwrap(), [1]);
+        assert_eq!(writer.protocol(), HidProtocolMode::Report);
+        let (written, read) = join(writer.write_report(&report), host.read(report_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), [0x09, 0xff, 2, 3, 0xfc]);
+
+        // Boot protocol reports have buttons 1 to 3 and the movement only.
+        let req = class_request(Direction::Out, HID_REQ_SET_PROTOCOL, 0, 0, 0);
+        host.control_out(req, &[]).await.unwrap();
+        let req = class_request(Direction::In, HID_REQ_GET_PROTOCOL, 0, 0, 1);
+        assert_eq!(host.control_in(req).await.unwrap(), [0]);
+        assert_eq!(writer.protocol(), HidProtocolMode::Boot);
+        let (written, read) = join(writer.write_report(&report), host.read(report_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), [0x01, 0xff, 2]);
+
+        // A bus reset restores the report protocol.
+        host.reset();
+        host.enumerate().await.unwrap();
+        assert_eq!(writer.protocol(), HidProtocolMode::Report);
+    }));
+}
+
+#[test]
+fn hid_report_descriptor_builder() {
+    use embassy_usb::class::hid::descriptor::{CollectionType, MainItemFlags, ReportDescriptor};
+    use embassy_usb::class::hid::usage::{generic_desktop, keyboard, led};
+
+    // The keyboard example of the HID specification, appendix B.1.
+    static KEYBOARD: ReportDescriptor<64> = ReportDescriptor::new()
+        .usage(generic_desktop::KEYBOARD)
+        .collection(CollectionType::Application)
+        .usage_minimum(keyboard::LEFT_CONTROL)
+        .usage_maximum(keyboard::RIGHT_GUI)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_size(1)
+        .report_count(8)
+        .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+        .report_count(1)
+        .report_size(8)
+        .input(MainItemFlags::CONSTANT)
+        .report_count(5)
+        .report_size(1)
+        .usage_minimum(led::NUM_LOCK)
+        .usage_maximum(led::KANA)
+        .output(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+        .report_count(1)
+        .report_size(3)
+        .output(MainItemFlags::CONSTANT)
+        .report_count(6)
+        .report_size(8)
+        .logical_minimum(0)
+        .logical_maximum(101)
+        .usage_minimum(keyboard::NO_EVENT)
+        .usage_maximum(Usage::new(UsagePage::KEYBOARD, 101))
+        .input(MainItemFlags::DATA_ARRAY_ABSOLUTE)
+        .end_collection();
+
+    assert_eq!(
+        KEYBOARD.as_bytes(),
+        [
+            0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01,
+            0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01,
+            0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65,
+            0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+        ]
+    );
+
+    // Usages of another page than the pending ones are written as extended usages.
+    let desc = ReportDescriptor::<16>::new()
+        .usage(generic_desktop::X)
+        .usage(Usage::new(UsagePage::CONSUMER, 0x238))
+        .input(MainItemFlags::DATA_VARIABLE_RELATIVE);
+    assert_eq!(
+        desc.as_bytes(),
+        [0x05, 0x01, 0x09, 0x30, 0x0b, 0x38, 0x02, 0x0c, 0x00, 0x81, 0x06]
+    );
+}
+
+#[test]
+fn uac2() {
+    use embassy_usb::class::uac2::{
+        self, category, terminal_type, ClockSource, Entity, FeatureUnit, Format, InputTerminal, OutputTerminal,
+        StreamConfig, Uac2Class, VolumeRange,
+    };
+
+    const CUR: u8 = 0x01;
+    const RANGE: u8 = 0x02;
+    const SAM_FREQ: u16 = 0x0100;
+    const MUTE: u16 = 0x0100;
+    const VOLUME: u16 = 0x0200;
+
+    // Speaker path: USB streaming 2 -> feature unit 3 -> speaker 4. Microphone path: 5 -> USB streaming 6.
+    static ENTITIES: [Entity; 6] = [
+        Entity::ClockSource(ClockSource {
+            id: 1,
+            sample_rates: &[48_000, 44_100],
+        }),
+        Entity::InputTerminal(InputTerminal {
+            id: 2,
+            terminal_type: terminal_type::USB_STREAMING,
+            clock: 1,
+            channels: 2,
+            channel_config: 0x3,
+        }),
+        Entity::FeatureUnit(FeatureUnit {
+            id: 3,
+            source: 2,
+            channels: 2,
+            mute: true,
+            volume: Some(VolumeRange {
+                min: -40 * 256,
+                max: 0,
+                resolution: 256,
+            }),
+        }),
+        Entity::OutputTerminal(OutputTerminal {
+            id: 4,
+            terminal_type: terminal_type::SPEAKER,
+            source: 3,
+            clock: 1,
+        }),
+        Entity::InputTerminal(InputTerminal {
+            id: 5,
+            terminal_type: terminal_type::MICROPHONE,
+            clock: 1,
+            channels: 1,
+            channel_config: 0,
+        }),
+        Entity::OutputTerminal(OutputTerminal {
+            id: 6,
+            terminal_type: terminal_type::USB_STREAMING,
+            source: 5,
+            clock: 1,
+        }),
+    ];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 512];
+    let mut control_buf = [0; 64];
+    let mut state = uac2::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = Uac2Class::new(
+        &mut builder,
+        &mut state,
+        uac2::Config {
+            category: category::HEADSET,
+            entities: &ENTITIES,
+            high_speed: false,
+        },
+    );
+    let controls = class.controls();
+    let (mut speaker, mut feedback) = class.stream_out(&StreamConfig {
+        terminal: 2,
+        channels: 2,
+        channel_config: 0x3,
+        formats: &[
+            Format {
+                subslot_size: 2,
+                bit_resolution: 16,
+            },
+            Format {
+                subslot_size: 3,
+                bit_resolution: 24,
+            },
+        ],
+        max_packet_size: 49 * 6,
+    });
+    let mut microphone = class.stream_in(&StreamConfig {
+        terminal: 6,
+        channels: 1,
+        channel_config: 0,
+        formats: &[Format {
+            subslot_size: 2,
+            bit_resolution: 16,
+        }],
+        max_packet_size: 49 * 2,
+    });
+    drop(class);
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // An audio function with a control interface, and two streaming interfaces with an
+        // alternate setting per format.
+        let configuration = &descriptors.configuration;
+        assert_eq!(functions(configuration), [[0, 3, 0x01, 0x00, 0x20]]);
+        assert_eq!(
+            interfaces(configuration),
+            [
+                [0, 0, 0x01, 0x01, 0x20],
+                [1, 0, 0x01, 0x02, 0x20],
+                [1, 1, 0x01, 0x02, 0x20],
+                [1, 2, 0x01, 0x02, 0x20],
+                [2, 0, 0x01, 0x02, 0x20],
+                [2, 1, 0x01, 0x02, 0x20]
+            ]
+        );
+        // Asynchronous isochronous data endpoints, and the feedback endpoint of the OUT stream.
+        assert_eq!(
+            endpoints(configuration),
+            [
+                (0x01, 0x05, 294),
+                (0x81, 0x11, 3),
+                (0x01, 0x05, 294),
+                (0x81, 0x11, 3),
+                (0x82, 0x05, 98)
+            ]
+        );
+
+        // The header of the control interface gives the length of the topology descriptors.
+        let topology: Vec<&[u8]> = all_descriptors(configuration)
+            .into_iter()
+            .skip(3)
+            .take_while(|d| d[1] == 0x24)
+            .collect();
+        let subtypes: Vec<(u8, u8)> = topology.iter().skip(1).map(|d| (d[2], d[3])).collect();
+        assert_eq!(
+            subtypes,
+            [(0x0a, 1), (0x02, 2), (0x06, 3), (0x03, 4), (0x02, 5), (0x03, 6)]
+        );
+        let total_len: usize = topology.iter().map(|d| d.len()).sum();
+        assert_eq!(topology[0][2..5], [0x01, 0x00, 0x02]);
+        assert_eq!(topology[0][5], category::HEADSET);
+        assert_eq!(u16::from_le_bytes([topology[0][6], topology[0][7]]), total_len as u16);
+
+        // Sample rates of the clock source.
+        let req = class_request(Direction::In, CUR, SAM_FREQ, 0x0100, 4);
+        assert_eq!(host.control_in(req).await.unwrap(), 48_000u32.to_le_bytes());
+        let req = class_request(Direction::In, RANGE, SAM_FREQ, 0x0100, 64);
+        let range = host.control_in(req).await.unwrap();
+        assert_eq!(range.len(), 2 + 2 * 12);
+        assert_eq!(range[..2], [2, 0]);
+        assert_eq!(range[14..18], 44_100u32.to_le_bytes());
+        assert_eq!(range[18..22], 44_100u32.to_le_bytes());
+        let req = class_request(Direction::Out, CUR, SAM_FREQ, 0x0100, 4);
+        host.control_out(req, &44_100u32.to_le_bytes()).await.unwrap();
+        controls.changed().await;
+        assert_eq!(controls.sample_rate(1), Some(44_100));
+        assert_eq!(
+            host.control_out(req, &32_000u32.to_le_bytes()).await,
+            Err(TransferError::Stall)
+        );
+        let short = class_request(Direction::Out, CUR, SAM_FREQ, 0x0100, 2);
+        assert_eq!(host.control_out(short, &[0x44, 0xac]).await, Err(TransferError::Stall));
+        assert_eq!(controls.sample_rate(1), Some(44_100));
+
+        // Mute and volume of the feature unit channels, with the volume clamped to its range.
+        let req = class_request(Direction::Out, CUR, MUTE | 1, 0x0300, 1);
+        host.control_out(req, &[1]).await.unwrap();
+        assert_eq!(controls.mute(3, 1), Some(true));
+        assert_eq!(controls.mute(3, 2), Some(false));
+        let req = class_request(Direction::Out, CUR, VOLUME, 0x0300, 2);
+        host.control_out(req, &(-50 * 256i16).to_le_bytes()).await.unwrap();
+        assert_eq!(controls.volume(3, 0), Some(-40 * 256));
+        let req = class_request(Direction::In, CUR, VOLUME, 0x0300, 2);
+        assert_eq!(host.control_in(req).await.unwrap(), (-40 * 256i16).to_le_bytes());
+        let req = class_request(Direction::In, RANGE, VOLUME, 0x0300, 8);
+        assert_eq!(host.control_in(req).await.unwrap(), [1, 0, 0x00, 0xd8, 0, 0, 0, 1]);
+
+        // Requests for channels, entities or controls that don't exist are rejected.
+        let req = class_request(Direction::Out, CUR, MUTE | 3, 0x0300, 1);
+        assert_eq!(host.control_out(req, &[1]).await, Err(TransferError::Stall));
+        let req = class_request(Direction::In, CUR, MUTE, 0x0900, 1);
+        assert_eq!(host.control_in(req).await, Err(TransferError::Stall));
+        let req = class_request(Direction::In, CUR, VOLUME, 0x0200, 2);
+        assert_eq!(host.control_in(req).await, Err(TransferError::Stall));
+        assert_eq!(controls.mute(3, 3), None);
+        assert_eq!(controls.volume(4, 0), None);
+
+        // Selecting an alternate setting starts the stream with its format.
+        let data_out = EndpointAddress::from_parts(1, Direction::Out);
+        let feedback_in = EndpointAddress::from_parts(1, Direction::In);
+        let data_in = EndpointAddress::from_parts(2, Direction::In);
+        assert_eq!(speaker.format(), None);
+        assert!(!host.is_endpoint_enabled(data_out));
+        host.set_interface(1, 2).await.unwrap();
+        assert_eq!(speaker.format(), Some(1));
+        assert_eq!(feedback.format(), Some(1));
+        assert!(host.is_endpoint_enabled(data_out));
+
+        let samples: Vec<u8> = (0..44 * 6).map(|i| i as u8).collect();
+        let mut buf = [0; 294];
+        let (n, written) = join(speaker.read_packet(&mut buf), host.write(data_out, &samples)).await;
+        written.unwrap();
+        assert_eq!(&buf[..n.unwrap()], samples);
+
+        // Full speed feedback is in the 10.14 format: 44.1 samples per frame.
+        let (written, read) = join(feedback.write(feedback.nominal(44_100)), host.read(feedback_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), [0x66, 0x06, 0x0b]);
+
+        host.set_interface(2, 1).await.unwrap();
+        assert_eq!(microphone.format(), Some(0));
+        let (written, read) = join(microphone.write_packet(&samples[..88]), host.read(data_in)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), samples[..88]);
+
+        host.set_interface(1, 0).await.unwrap();
+        assert_eq!(speaker.format(), None);
+        assert!(!host.is_endpoint_enabled(data_out));
+        assert_eq!(microphone.format(), Some(0));
+    }));
+}
+
+#[test]
+fn cdc_ecm() {
+    use embassy_usb::class::cdc_ecm::{self, CdcEcmClass};
+
+    const SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+    const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+    const MAC: [u8; 6] = [0x02, 0x12, 0x34, 0x56, 0x78, 0x9a];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_ecm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let class = CdcEcmClass::new(&mut builder, &mut state, MAC, 64);
+    let (mut sender, mut receiver) = class.split();
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // A communication interface with a notification endpoint, and a data interface with the
+        // bulk endpoints in alternate setting 1.
+        let configuration = &descriptors.configuration;
+        assert_eq!(functions(configuration), [[0, 2, 0x02, 0x06, 0x00]]);
+        assert_eq!(
+            interfaces(configuration),
+            [
+                [0, 0, 0x02, 0x06, 0x00],
+                [1, 0, 0x0a, 0x00, 0x00],
+                [1, 1, 0x0a, 0x00, 0x00]
+            ]
+        );
+        assert_eq!(
+            endpoints(configuration),
+            [(0x81, 0x03, 16), (0x01, 0x02, 64), (0x82, 0x02, 64)]
+        );
+
+        // The Ethernet functional descriptor refers to the MAC address string.
+        let union = all_descriptors(configuration)
+            .into_iter()
+            .find(|d| d[1] == 0x24 && d[2] == 0x06)
+            .unwrap();
+        assert_eq!(union[3..], [0, 1]);
+        let ethernet = all_descriptors(configuration)
+            .into_iter()
+            .find(|d| d[1] == 0x24 && d[2] == 0x0f)
+            .unwrap();
+        assert_eq!(host.get_string(ethernet[3]).await.unwrap(), "02123456789A");
+        assert_eq!(u16::from_le_bytes([ethernet[8], ethernet[9]]), 1514);
+
+        let req = class_request(Direction::Out, SET_ETHERNET_PACKET_FILTER, 0x000f, 0, 0);
+        host.control_out(req, &[]).await.unwrap();
+        let req = class_request(Direction::Out, SET_ETHERNET_MULTICAST_FILTERS, 0, 0, 0);
+        assert_eq!(host.control_out(req, &[]).await, Err(TransferError::Stall));
+
+        // The device notifies the connection and its speed once the data interface is enabled.
+        let notify = EndpointAddress::from_parts(1, Direction::In);
+        let data_out = EndpointAddress::from_parts(1, Direction::Out);
+        let data_in = EndpointAddress::from_parts(2, Direction::In);
+        assert!(!host.is_endpoint_enabled(data_out));
+        host.set_interface(1, 1).await.unwrap();
+        let (connected, (connection, speed)) = join(receiver.wait_connection(), async {
+            (host.read(notify).await.unwrap(), host.read(notify).await.unwrap())
+        })
+        .await;
+        connected.unwrap();
+        assert_eq!(connection, [0xa1, 0x00, 0x01, 0x00, 1, 0, 0, 0]);
+        assert_eq!(speed[..8], [0xa1, 0x2a, 0x00, 0x00, 1, 0, 8, 0]);
+        assert_eq!(speed[8..12], 12_000_000u32.to_le_bytes());
+        assert_eq!(speed[12..], 12_000_000u32.to_le_bytes());
+
+        // Each frame is a transfer, ended by a short packet, which is zero-length if needed.
+        let mut buf = [0; 1514];
+        for len in [60, 64, 1514] {
+            let frame: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let (n, written) = join(receiver.read_packet(&mut buf), host.write_transfer(data_out, &frame)).await;
+            written.unwrap();
+            assert_eq!(&buf[..n.unwrap()], frame);
+            let (written, read) = join(sender.write_packet(&frame), host.read_transfer(data_in, 2048)).await;
+            written.unwrap();
+            assert_eq!(read.unwrap(), frame);
+        }
+
+        // Frames too large for the buffer are dropped, and empty transfers ignored.
+        let mut small = [0; 100];
+        let (n, ()) = join(receiver.read_packet(&mut small), async {
+            host.write_transfer(data_out, &[0xff; 150]).await.unwrap();
+            host.write(data_out, &[]).await.unwrap();
+            host.write_transfer(data_out, &[1, 2, 3]).await.unwrap();
+        })
+        .await;
+        assert_eq!(&small[..n.unwrap()], [1, 2, 3]);
+
+        // Disabling the data interface stops the transfers.
+        host.set_interface(1, 0).await.unwrap();
+        assert_eq!(receiver.read_packet(&mut buf).await, Err(EndpointError::Disabled));
+    }));
+}
+
+#[test]
+fn rndis() {
+    use embassy_usb::class::rndis::{self, RndisClass};
+
+    const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+    const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+    const MSG_PACKET: u32 = 0x0000_0001;
+    const MSG_INITIALIZE: u32 = 0x0000_0002;
+    const MSG_QUERY: u32 = 0x0000_0004;
+    const MSG_SET: u32 = 0x0000_0005;
+    const MSG_RESET: u32 = 0x0000_0006;
+    const MSG_KEEPALIVE: u32 = 0x0000_0008;
+    const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+    const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+    const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010e;
+    const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+    const STATUS_NOT_SUPPORTED: u32 = 0xc000_00bb;
+    const STATUS_INVALID_DATA: u32 = 0xc001_0015;
+    const MAC: [u8; 6] = [0x02, 0x12, 0x34, 0x56, 0x78, 0x9a];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 128];
+    let mut state = rndis::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let class = RndisClass::new(&mut builder, &mut state, MAC, 64);
+    let (mut sender, mut receiver, mut notifier) = class.split();
+    let mut usb = builder.build();
+
+    // A message made of little endian fields, the second one being its length.
+    fn message(msg_type: u32, fields: &[u32], data: &[u8]) -> Vec<u8> {
+        let len = 8 + fields.len() * 4 + data.len();
+        let mut msg: Vec<u8> = [msg_type, len as u32]
+            .iter()
+            .chain(fields)
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        msg.extend_from_slice(data);
+        msg
+    }
+    fn fields(msg: &[u8]) -> Vec<u32> {
+        msg.chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    block_on(select3(usb.run(), notifier.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // A communication interface with the RNDIS class codes, and a data interface.
+        let configuration = &descriptors.configuration;
+        assert_eq!(functions(configuration), [[0, 2, 0xe0, 0x01, 0x03]]);
+        assert_eq!(
+            interfaces(configuration),
+            [[0, 0, 0xe0, 0x01, 0x03], [1, 0, 0x0a, 0x00, 0x00]]
+        );
+        assert_eq!(
+            endpoints(configuration),
+            [(0x81, 0x03, 8), (0x01, 0x02, 64), (0x82, 0x02, 64)]
+        );
+
+        let notify = EndpointAddress::from_parts(1, Direction::In);
+        let data_out = EndpointAddress::from_parts(1, Direction::Out);
+        let data_in = EndpointAddress::from_parts(2, Direction::In);
+        let host = &host;
+        let get_response = || async move {
+            let req = class_request(Direction::In, GET_ENCAPSULATED_RESPONSE, 0, 0, 256);
+            host.control_in(req).await.unwrap()
+        };
+        // Send a control message, and read its response once notified.
+        let send = |msg: Vec<u8>| async move {
+            let req = class_request(Direction::Out, SEND_ENCAPSULATED_COMMAND, 0, 0, msg.len() as u16);
+            host.control_out(req, &msg).await.unwrap();
+            assert_eq!(host.read(notify).await.unwrap(), [1, 0, 0, 0, 0, 0, 0, 0]);
+            get_response().await
+        };
+
+        let response = send(message(MSG_INITIALIZE, &[1, 1, 0, 0x4000], &[])).await;
+        assert_eq!(fields(&response), [0x8000_0002, 52, 1, 0, 1, 0, 1, 0, 1, 1600, 0, 0, 0]);
+
+        let response = send(message(MSG_QUERY, &[2, OID_GEN_SUPPORTED_LIST, 0, 20, 0], &[])).await;
+        let response = fields(&response);
+        assert_eq!(response[..6], [0x8000_0004, 24 + 27 * 4, 2, 0, 27 * 4, 16]);
+        assert_eq!(response[6], OID_GEN_SUPPORTED_LIST);
+        let response = send(message(MSG_QUERY, &[3, OID_GEN_MAXIMUM_FRAME_SIZE, 0, 20, 0], &[])).await;
+        assert_eq!(fields(&response), [0x8000_0004, 28, 3, 0, 4, 16, 1500]);
+        let response = send(message(MSG_QUERY, &[4, OID_802_3_PERMANENT_ADDRESS, 0, 20, 0], &[])).await;
+        assert_eq!(response[24..], MAC);
+        let response = send(message(MSG_QUERY, &[5, 0x0001_0203, 0, 20, 0], &[])).await;
+        assert_eq!(fields(&response), [0x8000_0004, 24, 5, STATUS_NOT_SUPPORTED, 0, 0]);
+        let response = send(message(MSG_KEEPALIVE, &[6], &[])).await;
+        assert_eq!(fields(&response), [0x8000_0008, 16, 6, 0]);
+
+        // Sets with a buffer out of the message are invalid.
+        let set_filter = |id: u32, offset: u32| {
+            message(
+                MSG_SET,
+                &[id, OID_GEN_CURRENT_PACKET_FILTER, 4, offset, 0],
+                &0x0fu32.to_le_bytes(),
+            )
+        };
+        let response = send(set_filter(7, 200)).await;
+        assert_eq!(fields(&response), [0x8000_0005, 16, 7, STATUS_INVALID_DATA]);
+        let response = send(message(MSG_SET, &[8, 0x0001_0203, 4, 20, 0], &[0; 4])).await;
+        assert_eq!(fields(&response), [0x8000_0005, 16, 8, STATUS_NOT_SUPPORTED]);
+
+        // Truncated and unknown messages have no response: a single zero byte is read instead.
+        for msg in [vec![0x02, 0x00], message(0x1234, &[9], &[])] {
+            let req = class_request(Direction::Out, SEND_ENCAPSULATED_COMMAND, 0, 0, msg.len() as u16);
+            host.control_out(req, &msg).await.unwrap();
+            assert_eq!(get_response().await, [0]);
+        }
+
+        // The device is connected once the host sets a packet filter.
+        let (response, ()) = join(send(set_filter(10, 20)), receiver.wait_connection()).await;
+        assert_eq!(fields(&response), [0x8000_0005, 16, 10, 0]);
+
+        // Frames are wrapped in packet messages.
+        let mut buf = [0; 1514];
+        for len in [60, 20, 1514] {
+            let frame: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let msg = message(MSG_PACKET, &[36, len as u32, 0, 0, 0, 0, 0, 0, 0], &frame);
+            let (n, written) = join(receiver.read_packet(&mut buf), host.write_transfer(data_out, &msg)).await;
+            written.unwrap();
+            assert_eq!(&buf[..n.unwrap()], frame);
+
+            let (written, read) = join(sender.write_packet(&frame), host.read_transfer(data_in, 2048)).await;
+            written.unwrap();
+            assert_eq!(read.unwrap(), msg);
+        }
+
+        // Padding, other messages, and packets pointing out of the message are dropped.
+        let (n, ()) = join(receiver.read_packet(&mut buf), async {
+            host.write_transfer(data_out, &[0]).await.unwrap();
+            host.write_transfer(data_out, &[0; 30]).await.unwrap();
+            let msg = message(MSG_KEEPALIVE, &[36, 4, 0, 0, 0, 0, 0, 0, 0], &[1, 2, 3, 4]);
+            host.write_transfer(data_out, &msg).await.unwrap();
+            let msg = message(MSG_PACKET, &[36, 5, 0, 0, 0, 0, 0, 0, 0], &[1, 2, 3, 4]);
+            host.write_transfer(data_out, &msg).await.unwrap();
+            let msg = message(MSG_PACKET, &[0xffff_fff0, 4, 0, 0, 0, 0, 0, 0, 0], &[1, 2, 3, 4]);
+            host.write_transfer(data_out, &msg).await.unwrap();
+            let msg = message(MSG_PACKET, &[36, 4, 0, 0, 0, 0, 0, 0, 0], &[5, 6, 7, 8]);
+            host.write_transfer(data_out, &msg).await.unwrap();
+        })
+        .await;
+        assert_eq!(&buf[..n.unwrap()], [5, 6, 7, 8]);
+
+        let response = send(message(MSG_RESET, &[0], &[])).await;
+        assert_eq!(fields(&response), [0x8000_0006, 16, 0, 1]);
+    }));
+}
+
+#[test]
+fn uvc_bulk_stream() {
+    use embassy_usb::class::uvc::{self, Encoding, Format, Frame, Settings, Transfer, UvcClass};
+
+    const SET_CUR: u8 = 0x01;
+    const GET_CUR: u8 = 0x81;
+    const GET_MAX: u8 = 0x83;
+    const VS_PROBE_CONTROL: u16 = 0x0100;
+    const VS_COMMIT_CONTROL: u16 = 0x0200;
+
+    static FRAMES: [Frame; 2] = [
+        Frame {
+            width: 8,
+            height: 4,
+            frame_intervals: &[333_333, 666_666],
+        },
+        Frame {
+            width: 4,
+            height: 2,
+            frame_intervals: &[333_333],
+        },
+    ];
+    static FORMATS: [Format; 1] = [Format {
+        encoding: Encoding::YUY2,
+        frames: &FRAMES,
+    }];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 512];
+    let mut control_buf = [0; 64];
+    let mut state = uvc::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let config = uvc::Config {
+        formats: &FORMATS,
+        transfer: Transfer::Bulk,
+        max_packet_size: 64,
+    };
+    let mut class = UvcClass::new(&mut builder, &mut state, config);
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // A video interface collection, with a control and a streaming interface.
+        let configuration = &descriptors.configuration;
+        assert_eq!(functions(configuration), [[0, 2, uvc::USB_VIDEO_CLASS, 0x03, 0x00]]);
+        assert_eq!(
+            interfaces(configuration),
+            [
+                [0, 0, uvc::USB_VIDEO_CLASS, 0x01, 0x00],
+                [1, 0, uvc::USB_VIDEO_CLASS, 0x02, 0x00]
+            ]
+        );
+        assert_eq!(endpoints(configuration), [(0x81, 0x02, 64)]);
+
+        // The streaming interface describes the format and its frames, and the header gives their
+        // total length.
+        let class_descriptors: Vec<&[u8]> = all_descriptors(configuration)
+            .into_iter()
+            .skip_while(|d| d[1] != 0x04 || d[2] != 1)
+            .filter(|d| d[1] == 0x24)
+            .collect();
+        let subtypes: Vec<u8> = class_descriptors.iter().map(|d| d[2]).collect();
+        assert_eq!(subtypes, [0x01, 0x04, 0x05, 0x05, 0x0d]);
+        let total_len: usize = class_descriptors.iter().map(|d| d.len()).sum();
+        assert_eq!(
+            u16::from_le_bytes([class_descriptors[0][4], class_descriptors[0][5]]),
+            total_len as u16
+        );
+        assert_eq!(class_descriptors[0][6], 0x81); // bEndpointAddress
+
+        // The maximum probe is the largest frame at the largest interval.
+        let req = class_request(Direction::In, GET_MAX, VS_PROBE_CONTROL, 1, 34);
+        let max = host.control_in(req).await.unwrap();
+        assert_eq!(max.len(), 34);
+        assert_eq!(max[2], 1); // bFormatIndex
+        assert_eq!(max[3], 1); // bFrameIndex
+        assert_eq!(u32::from_le_bytes(max[4..8].try_into().unwrap()), 333_333);
+        assert_eq!(u32::from_le_bytes(max[18..22].try_into().unwrap()), 8 * 4 * 2);
+
+        // Probing the second frame at 20 frames per second snaps to its only interval.
+        let mut probe = [0; 34];
+        probe[2] = 1;
+        probe[3] = 2;
+        probe[4..8].copy_from_slice(&500_000u32.to_le_bytes());
+        let req = class_request(Direction::Out, SET_CUR, VS_PROBE_CONTROL, 1, 34);
+        host.control_out(req, &probe).await.unwrap();
+        let req = class_request(Direction::In, GET_CUR, VS_PROBE_CONTROL, 1, 34);
+        let probe = host.control_in(req).await.unwrap();
+        assert_eq!(probe[3], 2);
+        assert_eq!(u32::from_le_bytes(probe[4..8].try_into().unwrap()), 333_333);
+        assert_eq!(u32::from_le_bytes(probe[18..22].try_into().unwrap()), 4 * 2 * 2);
+        assert_eq!(u32::from_le_bytes(probe[22..26].try_into().unwrap()), 64);
+        assert_eq!(class.settings(), None);
+
+        let req = class_request(Direction::Out, SET_CUR, VS_COMMIT_CONTROL, 1, 34);
+        host.control_out(req, &probe).await.unwrap();
+        let settings = class.wait_streaming().await;
+        assert_eq!(
+            settings,
+            Settings {
+                format: 0,
+                frame: 1,
+                frame_interval: 333_333,
+            }
+        );
+
+        // Frames are split in packets with a payload header, and the frame ID toggles.
+        let ep = EndpointAddress::from_parts(1, Direction::In);
+        let frame = [0x55; 100];
+        let read_frame = async {
+            let mut packets = Vec::new();
+            loop {
+                let packet = host.read(ep).await.unwrap();
+                let end = packet[1] & 0x02 != 0;
+                packets.push(packet);
+                if end {
+                    return packets;
+                }
+            }
+        };
+        let (written, packets) = join(class.write_frame(&frame), read_frame).await;
+        written.unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].len(), 64);
+        assert_eq!(packets[0][..2], [2, 0x80]);
+        assert_eq!(packets[1][..2], [2, 0x82]);
+        let data: Vec<u8> = packets.iter().flat_map(|p| p[2..].iter().copied()).collect();
+        assert_eq!(data, frame);
+
+        let (written, packet) = join(class.write_frame(&frame[..10]), host.read(ep)).await;
+        written.unwrap();
+        assert_eq!(packet.unwrap()[..2], [2, 0x83]);
+    }));
+}
+
+#[test]
+fn uvc_isochronous_stream() {
+    use embassy_usb::class::uvc::{self, Encoding, Format, Frame, Transfer, UvcClass};
+
+    static FRAMES: [Frame; 1] = [Frame {
+        width: 16,
+        height: 16,
+        frame_intervals: &[333_333],
+    }];
+    static FORMATS: [Format; 1] = [Format {
+        encoding: Encoding::Mjpeg,
+        frames: &FRAMES,
+    }];
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 512];
+    let mut control_buf = [0; 64];
+    let mut state = uvc::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let config = uvc::Config {
+        formats: &FORMATS,
+        transfer: Transfer::Isochronous,
+        max_packet_size: 256,
+    };
+    let mut class = UvcClass::new(&mut builder, &mut state, config);
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+        let configuration = &descriptors.configuration;
+        // The isochronous endpoint is only in alternate setting 1 of the streaming interface.
+        assert_eq!(
+            interfaces(configuration),
+            [
+                [0, 0, uvc::USB_VIDEO_CLASS, 0x01, 0x00],
+                [1, 0, uvc::USB_VIDEO_CLASS, 0x02, 0x00],
+                [1, 1, uvc::USB_VIDEO_CLASS, 0x02, 0x00]
+            ]
+        );
+        assert_eq!(endpoints(configuration), [(0x81, 0x05, 256)]);
+
+        let ep = EndpointAddress::from_parts(1, Direction::In);
+        assert!(!host.is_endpoint_enabled(ep));
+
+        // The stream starts with the bandwidth of alternate setting 1, with the default settings.
+        host.set_interface(1, 1).await.unwrap();
+        let settings = class.wait_streaming().await;
+        assert_eq!(settings.frame_interval, 333_333);
+        assert!(host.is_endpoint_enabled(ep));
+
+        host.set_interface(1, 0).await.unwrap();
+        assert_eq!(class.settings(), None);
+        assert!(class.write_frame(&[0xff, 0xd8]).await.is_err());
+    }));
+}
+
+#[test]
+fn printer() {
+    use embassy_usb::class::printer::{self, PortStatus, PrinterClass};
+
+    const GET_DEVICE_ID: u8 = 0x00;
+    const GET_PORT_STATUS: u8 = 0x01;
+    const SOFT_RESET: u8 = 0x02;
+    const DEVICE_ID: &str = "MFG:Embassy;MDL:Label printer;CMD:ZPL;CLS:PRINTER;";
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = printer::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let config = printer::Config {
+        device_id: DEVICE_ID,
+        max_packet_size: 64,
+    };
+    let mut class = PrinterClass::new(&mut builder, &mut state, config);
+    let mut usb = builder.build();
+
+    block_on(select(usb.run(), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // One bidirectional printer interface, with a bulk endpoint in each direction.
+        let configuration = &descriptors.configuration;
+        assert_eq!(functions(configuration), [[0, 1, 0x07, 0x01, 0x02]]);
+        assert_eq!(interfaces(configuration), [[0, 0, 0x07, 0x01, 0x02]]);
+        assert_eq!(endpoints(configuration), [(0x01, 0x02, 64), (0x81, 0x02, 64)]);
+
+        // The device ID starts with its big endian length, which includes the length itself.
+        let req = class_request(Direction::In, GET_DEVICE_ID, 0, 0, 1024);
+        let id = host.control_in(req).await.unwrap();
+        assert_eq!(id[..2], ((DEVICE_ID.len() + 2) as u16).to_be_bytes());
+        assert_eq!(&id[2..], DEVICE_ID.as_bytes());
+
+        let req = class_request(Direction::In, GET_PORT_STATUS, 0, 0, 1);
+        assert_eq!(host.control_in(req).await.unwrap(), [0x18]);
+        class.set_port_status(PortStatus {
+            paper_empty: true,
+            ..Default::default()
+        });
+        assert_eq!(host.control_in(req).await.unwrap(), [0x38]);
+
+        // Transfers are read until a short packet.
+        let data_out = EndpointAddress::from_parts(1, Direction::Out);
+        let data: Vec<u8> = (0..150).collect();
+        let mut buf = [0; 256];
+        let (n, written) = join(class.read(&mut buf), host.write_transfer(data_out, &data)).await;
+        written.unwrap();
+        assert_eq!(&buf[..n.unwrap()], data);
+
+        // Status data is sent as a single transfer.
+        let data_in = EndpointAddress::from_parts(1, Direction::In);
+        let (written, read) = join(class.write(&data[..128]), host.read_transfer(data_in, 1024)).await;
+        written.unwrap();
+        assert_eq!(read.unwrap(), data[..128]);
+
+        // A soft reset aborts the current job.
+        let req = class_request(Direction::Out, SOFT_RESET, 0, 0, 0);
+        host.control_out(req, &[]).await.unwrap();
+        assert_eq!(class.read(&mut buf).await, Err(printer::Error::Reset));
+    }));
+}
+
+struct TestCard {
+    powered: bool,
+}
+
+impl embassy_usb::class::ccid::SmartCard for TestCard {
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, embassy_usb::class::ccid::Error> {
+        self.powered = true;
+        atr[..2].copy_from_slice(&[0x3b, 0x00]);
+        Ok(2)
+    }
+
+    async fn power_off(&mut self) {
+        self.powered = false;
+    }
+
+    async fn transfer(
+        &mut self,
+        command: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, embassy_usb::class::ccid::Error> {
+        assert!(self.powered);
+        // Answer with the data of the command, reversed, and the success status word.
+        let data = &command[5..];
+        for (r, c) in response.iter_mut().zip(data.iter().rev()) {
+            *r = *c;
+        }
+        response[data.len()..data.len() + 2].copy_from_slice(&[0x90, 0x00]);
+        Ok(data.len() + 2)
+    }
+}
+
+#[test]
+fn ccid() {
+    use embassy_usb::class::ccid::{self, CcidClass};
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = ccid::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let config = ccid::Config {
+        removable: true,
+        ..Default::default()
+    };
+    let mut class = CcidClass::new(&mut builder, &mut state, config);
+    let mut usb = builder.build();
+
+    let mut card = TestCard { powered: false };
+    let mut buf = [0; 2 * ccid::MIN_MESSAGE_LEN];
+
+    block_on(select3(usb.run(), class.run(&mut card, &mut buf), async {
+        let descriptors = host.enumerate().await.unwrap();
+
+        // One smart card interface, with its class descriptor, the bulk endpoints, and the
+        // interrupt endpoint of removable readers.
+        let configuration = &descriptors.configuration;
+        assert_eq!(interfaces(configuration), [[0, 0, 0x0b, 0x00, 0x00]]);
+        assert_eq!(
+            endpoints(configuration),
+            [(0x01, 0x02, 64), (0x81, 0x02, 64), (0x82, 0x03, 2)]
+        );
+        let class_descriptor = all_descriptors(configuration)
+            .into_iter()
+            .find(|d| d[1] == 0x21)
+            .unwrap();
+        assert_eq!(class_descriptor.len(), 54);
+        assert_eq!(class_descriptor[2..4], [0x10, 0x01]); // bcdCCID
+        assert_eq!(
+            u32::from_le_bytes(class_descriptor[44..48].try_into().unwrap()),
+            ccid::MIN_MESSAGE_LEN as u32
+        );
+
+        let bulk_out = EndpointAddress::from_parts(1, Direction::Out);
+        let bulk_in = EndpointAddress::from_parts(1, Direction::In);
+        let notify_in = EndpointAddress::from_parts(2, Direction::In);
+
+        let command = |message_type: u8, slot: u8, seq: u8, data: &[u8]| {
+            let mut message = vec![message_type];
+            message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            message.extend_from_slice(&[slot, seq, 0, 0, 0]);
+            message.extend_from_slice(data);
+            message
+        };
+        let host = &host;
+        let exchange = |message: Vec<u8>| async move {
+            host.write_transfer(bulk_out, &message).await.unwrap();
+            host.read_transfer(bulk_in, 1024).await.unwrap()
+        };
+
+        // The host gets the state of the slot when it connects.
+        assert_eq!(host.read(notify_in).await.unwrap(), [0x50, 0x03]);
+
+        // GetSlotStatus: card present and inactive.
+        let response = exchange(command(0x65, 0, 1, &[])).await;
+        assert_eq!(response, [0x81, 0, 0, 0, 0, 0, 1, 0x01, 0, 0x03]);
+
+        // IccPowerOn returns the ATR in a DataBlock.
+        let response = exchange(command(0x62, 0, 2, &[])).await;
+        assert_eq!(response, [0x80, 2, 0, 0, 0, 0, 2, 0x00, 0, 0, 0x3b, 0x00]);
+
+        // XfrBlock, with a command APDU spanning several packets.
+        let mut apdu = vec![0x00, 0xca, 0x00, 0x00, 100];
+        apdu.extend(0..100);
+        let response = exchange(command(0x6f, 0, 3, &apdu)).await;
+        assert_eq!(response[..10], [0x80, 102, 0, 0, 0, 0, 3, 0x00, 0, 0]);
+        assert!(response[10..110].iter().eq((0..100).rev().collect::<Vec<u8>>().iter()));
+        assert_eq!(response[110..], [0x90, 0x00]);
+
+        // Commands to other slots fail, with the offset of bSlot as error.
+        let response = exchange(command(0x65, 1, 4, &[])).await;
+        assert_eq!(response, [0x81, 0, 0, 0, 0, 1, 4, 0x40, 5, 0]);
+
+        // Unsupported commands fail with the response type they expect.
+        let response = exchange(command(0x6b, 0, 5, &[])).await;
+        assert_eq!(response, [0x83, 0, 0, 0, 0, 0, 5, 0x40, 0, 0]);
+
+        // IccPowerOff returns the slot status, with the card inactive.
+        let response = exchange(command(0x63, 0, 6, &[])).await;
+        assert_eq!(response, [0x81, 0, 0, 0, 0, 0, 6, 0x01, 0, 0x03]);
+
+        // XfrBlock fails when the card is not powered.
+        let response = exchange(command(0x6f, 0, 7, &apdu)).await;
+        assert_eq!(response[..10], [0x80, 0, 0, 0, 0, 0, 7, 0x41, 0xfe, 0]);
+    }));
+}
+
+struct RamDisk {
+    blocks: Vec<[u8; 512]>,
+}
+
+impl embassy_usb::class::msc::BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    async fn read(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), embassy_usb::class::msc::Error> {
+        for (block, chunk) in self.blocks[lba as usize..].iter().zip(buf.chunks_mut(512)) {
+            chunk.copy_from_slice(block);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u32, buf: &[u8]) -> Result<(), embassy_usb::class::msc::Error> {
+        for (block, chunk) in self.blocks[lba as usize..].iter_mut().zip(buf.chunks(512)) {
+            block.copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn msc() {
+    use embassy_usb::class::msc::{self, MscClass};
+
+    const GET_MAX_LUN: u8 = 0xfe;
+    const BULK_ONLY_RESET: u8 = 0xff;
+    const INQUIRY: u8 = 0x12;
+    const TEST_UNIT_READY: u8 = 0x00;
+    const READ_10: u8 = 0x28;
+    const WRITE_10: u8 = 0x2a;
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = msc::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let config = msc::Config {
+        vendor: "Embassy",
+        product: "RAM disk",
+        ..Default::default()
+    };
+    let mut class = MscClass::new(&mut builder, &mut state, config);
+    let mut usb = builder.build();
+
+    let mut disk = [RamDisk {
+        blocks: vec![[0; 512]; 16],
+    }];
+    let mut buf = [0; 1024];
+
+    block_on(select3(usb.run(), class.run(&mut disk, &mut buf), async {
+        let descriptors = host.enumerate().await.unwrap();
+        let bulk_out = EndpointAddress::from_parts(1, Direction::Out);
+        let bulk_in = EndpointAddress::from_parts(1, Direction::In);
+
+        // One SCSI Bulk-Only interface, with a bulk endpoint in each direction.
+        let configuration = &descriptors.configuration;
+        assert_eq!(interfaces(configuration), [[0, 0, 0x08, 0x06, 0x50]]);
+        assert_eq!(endpoints(configuration), [(0x01, 0x02, 64), (0x81, 0x02, 64)]);
+
+        let req = class_request(Direction::In, GET_MAX_LUN, 0, 0, 1);
+        assert_eq!(host.control_in(req).await.unwrap(), [0]);
+
+        let host = &host;
+        let command = |tag: u32, data_len: u32, data_in: bool, cb: &[u8]| {
+            let mut cbw = vec![0x55, 0x53, 0x42, 0x43];
+            cbw.extend_from_slice(&tag.to_le_bytes());
+            cbw.extend_from_slice(&data_len.to_le_bytes());
+            cbw.extend_from_slice(&[if data_in { 0x80 } else { 0x00 }, 0, cb.len() as u8]);
+            cbw.extend_from_slice(cb);
+            cbw.resize(31, 0);
+            async move { host.write(bulk_out, &cbw).await.unwrap() }
+        };
+        // Returns the residue and the status.
+        let status = |tag: u32| async move {
+            let csw = host.read(bulk_in).await.unwrap();
+            assert_eq!(csw.len(), 13);
+            assert_eq!(csw[..4], [0x55, 0x53, 0x42, 0x53]);
+            assert_eq!(csw[4..8], tag.to_le_bytes());
+            (u32::from_le_bytes(csw[8..12].try_into().unwrap()), csw[12])
+        };
+        let rw = |op: u8, lba: u32, blocks: u16| {
+            let mut cb = [0; 10];
+            cb[0] = op;
+            cb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cb[7..9].copy_from_slice(&blocks.to_be_bytes());
+            cb
+        };
+
+        command(1, 36, true, &[INQUIRY, 0, 0, 0, 36, 0]).await;
+        let inquiry = host.read_transfer(bulk_in, 36).await.unwrap();
+        assert_eq!(inquiry.len(), 36);
+        assert_eq!(inquiry[0], 0x00);
+        assert_eq!(&inquiry[8..36], b"Embassy RAM disk        0.1 ");
+        assert_eq!(status(1).await, (0, 0));
+
+        // WRITE(10) and READ(10) of 2 blocks, in packets.
+        let data: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        command(2, 1024, false, &rw(WRITE_10, 3, 2)).await;
+        for packet in data.chunks(64) {
+            host.write(bulk_out, packet).await.unwrap();
+        }
+        assert_eq!(status(2).await, (0, 0));
+        command(3, 1024, true, &rw(READ_10, 3, 2)).await;
+        assert_eq!(host.read_transfer(bulk_in, 1024).await.unwrap(), data);
+        assert_eq!(status(3).await, (0, 0));
+
+        // The host expects more data than sent: the IN endpoint stalls after the data.
+        command(4, 64, true, &[INQUIRY, 0, 0, 0, 64, 0]).await;
+        assert_eq!(host.read_transfer(bulk_in, 64).await.unwrap().len(), 36);
+        assert_eq!(host.read(bulk_in).await, Err(TransferError::Stall));
+        host.clear_halt(bulk_in).await.unwrap();
+        assert_eq!(status(4).await, (28, 0));
+
+        // The host sends data the device doesn't expect: the OUT endpoint stalls.
+        command(5, 64, false, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]).await;
+        assert_eq!(host.write_transfer(bulk_out, &[0; 64]).await, Err(TransferError::Stall));
+        host.clear_halt(bulk_out).await.unwrap();
+        assert_eq!(status(5).await, (64, 0));
+
+        // The host expects data for a WRITE: phase error.
+        command(6, 512, true, &rw(WRITE_10, 0, 1)).await;
+        assert_eq!(host.read(bulk_in).await, Err(TransferError::Stall));
+        host.clear_halt(bulk_in).await.unwrap();
+        assert_eq!(status(6).await, (512, 2));
+
+        // After an invalid command block, both endpoints stay stalled until a Reset Recovery.
+        host.write(bulk_out, &[0x55; 31]).await.unwrap();
+        assert_eq!(host.read(bulk_in).await, Err(TransferError::Stall));
+        assert!(host.is_endpoint_stalled(bulk_out));
+        host.clear_halt(bulk_in).await.unwrap();
+        host.clear_halt(bulk_out).await.unwrap();
+        assert_eq!(host.read(bulk_in).await, Err(TransferError::Stall));
+
+        let req = class_request(Direction::Out, BULK_ONLY_RESET, 0, 0, 0);
+        host.control_out(req, &[]).await.unwrap();
+        host.clear_halt(bulk_in).await.unwrap();
+        host.clear_halt(bulk_out).await.unwrap();
+        command(7, 1024, true, &rw(READ_10, 3, 2)).await;
+        assert_eq!(host.read_transfer(bulk_in, 1024).await.unwrap(), data);
+        assert_eq!(status(7).await, (0, 0));
+    }));
+}
@@ -0,0 +1,466 @@
+use std::sync::{Arc, Mutex};
+
+use embassy_futures::block_on;
+use embassy_futures::select::select;
+use embassy_usb::control::{Recipient, Request, RequestType};
+use embassy_usb::descriptor::descriptor_type;
+use embassy_usb::driver::{Direction, Endpoint, EndpointAddress, EndpointIn, EndpointOut};
+use embassy_usb::{Builder, Config, Handler, ReconnectError, UsbDevice};
+use embassy_usb_sim::TransferError;
+
+#[derive(Default)]
+struct Recorder {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl Handler for Recorder {
+    fn enabled(&mut self, enabled: bool) {
+        self.events.lock().unwrap().push(format!("enabled {enabled}"));
+    }
+
+    fn reset(&mut self) {
+        self.events.lock().unwrap().push("reset".into());
+    }
+
+    fn addressed(&mut self, addr: u8) {
+        self.events.lock().unwrap().push(format!("addressed {addr}"));
+    }
+
+    fn configured(&mut self, configured: bool) {
+        self.events.lock().unwrap().push(format!("configured {configured}"));
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        self.events.lock().unwrap().push(format!("suspended {suspended}"));
+    }
+
+    fn set_alternate_setting(&mut self, iface: embassy_usb::types::InterfaceNumber, alternate_setting: u8) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("interface {} alt {alternate_setting}", iface.0));
+    }
+}
+
+fn config() -> Config<'static> {
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("Simulated device");
+    config.serial_number = Some("12345678");
+    config.supports_remote_wakeup = true;
+    config
+}
+
+/// Run the device until `f` completes.
+fn run<'d, D: embassy_usb::driver::Driver<'d>>(usb: &mut UsbDevice<'d, D>, f: impl core::future::Future<Output = ()>) {
+    block_on(select(usb.run(), f));
+}
+
+#[test]
+fn enumeration() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut recorder = Recorder::default();
+    let events = recorder.events.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    builder.handler(&mut recorder);
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    alt.endpoint_bulk_out(64);
+    alt.endpoint_bulk_in(64);
+    drop(func);
+    let mut usb = builder.build();
+
+    run(&mut usb, async {
+        let descriptors = host.enumerate().await.unwrap();
+        assert_eq!(descriptors.device.len(), 18);
+        assert_eq!(&descriptors.device[8..12], &[0xde, 0xc0, 0xfe, 0xca]);
+        assert_eq!(descriptors.configuration.len(), 9 + 9 + 7 + 7);
+        assert_eq!(host.address(), 1);
+        assert!(host.is_enabled());
+
+        assert_eq!(
+            host.get_string(descriptors.device[15]).await.unwrap(),
+            "Simulated device"
+        );
+        assert_eq!(host.get_string(descriptors.device[16]).await.unwrap(), "12345678");
+
+        let ep_out = EndpointAddress::from_parts(1, Direction::Out);
+        let ep_in = EndpointAddress::from_parts(1, Direction::In);
+        assert!(host.is_endpoint_enabled(ep_out));
+        assert!(host.is_endpoint_enabled(ep_in));
+
+        // Unsupported requests are stalled.
+        let req = Request {
+            direction: Direction::In,
+            request_type: RequestType::Vendor,
+            recipient: Recipient::Device,
+            request: 0x42,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        assert_eq!(host.control_in(req).await, Err(TransferError::Stall));
+
+        host.set_interface(0, 0).await.unwrap();
+        host.set_configuration(0).await.unwrap();
+        assert!(!host.is_endpoint_enabled(ep_in));
+    });
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "enabled true",
+            "reset",
+            "interface 0 alt 0",
+            "reset",
+            "interface 0 alt 0",
+            "addressed 1",
+            "configured true",
+            "interface 0 alt 0",
+            "configured false",
+        ]
+    );
+}
+
+#[test]
+fn suspend_and_remote_wakeup() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut recorder = Recorder::default();
+    let events = recorder.events.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    builder.handler(&mut recorder);
+    let mut usb = builder.build();
+
+    block_on(async {
+        select(usb.run(), async {
+            host.enumerate().await.unwrap();
+            let req = Request {
+                direction: Direction::Out,
+                request_type: RequestType::Standard,
+                recipient: Recipient::Device,
+                request: Request::SET_FEATURE,
+                value: Request::FEATURE_DEVICE_REMOTE_WAKEUP,
+                index: 0,
+                length: 0,
+            };
+            host.control_out(req, &[]).await.unwrap();
+            host.suspend();
+        })
+        .await;
+
+        // Let the device handle the suspend, then wake the host up.
+        usb.run_until_suspend().await;
+        assert!(!host.take_remote_wakeup());
+        usb.remote_wakeup().await.unwrap();
+        assert!(host.take_remote_wakeup());
+    });
+
+    let events = events.lock().unwrap();
+    assert_eq!(events[events.len() - 2..], ["suspended true", "suspended false"]);
+}
+
+#[test]
+fn endpoints() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    let mut ep_out = alt.endpoint_bulk_out(8);
+    let mut ep_in = alt.endpoint_bulk_in(8);
+    drop(func);
+    let mut usb = builder.build();
+
+    // Echo the packets received back to the host.
+    let echo = async {
+        let mut buf = [0; 8];
+        loop {
+            ep_out.wait_enabled().await;
+            while let Ok(n) = ep_out.read(&mut buf).await {
+                let _ = ep_in.write(&buf[..n]).await;
+            }
+        }
+    };
+
+    run(&mut usb, async {
+        select(echo, async {
+            let out = EndpointAddress::from_parts(1, Direction::Out);
+            let inp = EndpointAddress::from_parts(1, Direction::In);
+            assert_eq!(host.write(out, b"abc").await, Err(TransferError::Disabled));
+            host.enumerate().await.unwrap();
+
+            let reader = host.clone();
+            let (written, read) = embassy_futures::join::join(
+                host.write_transfer(out, b"hello, simulated world!"),
+                reader.read_transfer(inp, 64),
+            )
+            .await;
+            written.unwrap();
+            assert_eq!(read.unwrap(), b"hello, simulated world!");
+
+            // Exactly a packet long: the transfer ends with a zero-length packet.
+            let (written, read) =
+                embassy_futures::join::join(host.write_transfer(out, b"12345678"), reader.read_transfer(inp, 64)).await;
+            written.unwrap();
+            assert_eq!(read.unwrap(), b"12345678");
+        })
+        .await;
+    });
+}
+
+#[test]
+fn multiple_configurations() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut recorder1 = Recorder::default();
+    let mut recorder2 = Recorder::default();
+    let events1 = recorder1.events.clone();
+    let events2 = recorder2.events.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    builder.handler(&mut recorder1);
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    alt.endpoint_bulk_out(64);
+    drop(func);
+
+    assert_eq!(builder.configuration(), 2);
+    builder.handler(&mut recorder2);
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    iface.alt_setting(0xff, 0, 0, None);
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    alt.endpoint_bulk_in(64);
+    drop(func);
+    let mut usb = builder.build();
+
+    let get_configuration = Request {
+        direction: Direction::In,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: Request::GET_CONFIGURATION,
+        value: 0,
+        index: 0,
+        length: 1,
+    };
+
+    run(&mut usb, async {
+        let ep_out = EndpointAddress::from_parts(1, Direction::Out);
+        let ep_in = EndpointAddress::from_parts(1, Direction::In);
+
+        let descriptors = host.enumerate().await.unwrap();
+        assert_eq!(descriptors.device[17], 2);
+        assert_eq!(descriptors.configuration[5], 1);
+        assert_eq!(host.control_in(get_configuration).await.unwrap(), [1]);
+        assert!(host.is_endpoint_enabled(ep_out));
+
+        let configuration = host
+            .get_descriptor(descriptor_type::CONFIGURATION, 1, 0, 64)
+            .await
+            .unwrap();
+        assert_eq!(configuration.len(), 9 + 9 + 9 + 7);
+        assert_eq!(configuration[4], 1); // bNumInterfaces
+        assert_eq!(configuration[5], 2); // bConfigurationValue
+        assert_eq!(
+            host.get_descriptor(descriptor_type::CONFIGURATION, 2, 0, 64).await,
+            Err(TransferError::Stall)
+        );
+
+        host.set_configuration(2).await.unwrap();
+        assert_eq!(host.control_in(get_configuration).await.unwrap(), [2]);
+        assert!(!host.is_endpoint_enabled(ep_out));
+        assert!(!host.is_endpoint_enabled(ep_in));
+        host.set_interface(0, 1).await.unwrap();
+        assert!(host.is_endpoint_enabled(ep_in));
+
+        // Interface 0 of the second configuration only has two alternate settings.
+        assert_eq!(host.set_interface(0, 2).await, Err(TransferError::Stall));
+        assert_eq!(host.set_interface(1, 0).await, Err(TransferError::Stall));
+        assert_eq!(host.set_configuration(3).await, Err(TransferError::Stall));
+
+        // Back to the first configuration: the alternate setting of the second one is unused.
+        host.set_configuration(1).await.unwrap();
+        assert!(host.is_endpoint_enabled(ep_out));
+        assert!(!host.is_endpoint_enabled(ep_in));
+    });
+
+    let events1 = events1.lock().unwrap();
+    let events2 = events2.lock().unwrap();
+    assert!(!events1.contains(&"interface 0 alt 1".to_string()));
+    assert_eq!(
+        events2[events2.len() - 3..],
+        ["configured true", "interface 0 alt 1", "configured false"]
+    );
+}
+
+#[test]
+fn switching_configurations() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut recorder1 = Recorder::default();
+    let mut recorder2 = Recorder::default();
+    let events1 = recorder1.events.clone();
+    let events2 = recorder2.events.clone();
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    builder.handler(&mut recorder1);
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    let ep1 = alt.endpoint_bulk_out(64).info().addr;
+    drop(func);
+    builder.configuration();
+    builder.handler(&mut recorder2);
+    let mut func = builder.function(0xff, 0, 0);
+    let mut iface = func.interface();
+    let mut alt = iface.alt_setting(0xff, 0, 0, None);
+    let ep2 = alt.endpoint_bulk_out(64).info().addr;
+    drop(func);
+    let mut usb = builder.build();
+
+    // Each configuration has its own endpoints.
+    assert_ne!(ep1, ep2);
+
+    let take = |events: &Arc<Mutex<Vec<String>>>| {
+        let mut events = events.lock().unwrap();
+        let configured: Vec<String> = events.iter().filter(|e| e.starts_with("configured")).cloned().collect();
+        events.clear();
+        configured
+    };
+
+    run(&mut usb, async {
+        host.enumerate().await.unwrap();
+        assert!(host.is_endpoint_enabled(ep1) && !host.is_endpoint_enabled(ep2));
+        assert_eq!(take(&events1), ["configured true"]);
+        assert!(take(&events2).is_empty());
+
+        // Only the handlers of the configurations left and selected are notified.
+        host.set_configuration(2).await.unwrap();
+        assert!(!host.is_endpoint_enabled(ep1) && host.is_endpoint_enabled(ep2));
+        assert_eq!(take(&events1), ["configured false"]);
+        assert_eq!(take(&events2), ["configured true"]);
+
+        // Selecting the current configuration again doesn't leave it.
+        host.set_configuration(2).await.unwrap();
+        assert!(take(&events1).is_empty());
+        assert_eq!(take(&events2), ["configured true"]);
+
+        host.set_configuration(0).await.unwrap();
+        assert!(!host.is_endpoint_enabled(ep1) && !host.is_endpoint_enabled(ep2));
+        assert!(take(&events1).is_empty());
+        assert_eq!(take(&events2), ["configured false"]);
+
+        // Unconfiguring an unconfigured device notifies nobody.
+        host.set_configuration(0).await.unwrap();
+        assert!(take(&events1).is_empty());
+        assert!(take(&events2).is_empty());
+
+        host.set_configuration(1).await.unwrap();
+        assert!(host.is_endpoint_enabled(ep1) && !host.is_endpoint_enabled(ep2));
+        assert_eq!(take(&events1), ["configured true"]);
+        assert!(take(&events2).is_empty());
+    });
+}
+
+#[test]
+fn reconnect() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut builder = Builder::new(
+        driver,
+        config(),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut func = builder.function(0xff, 0, 0);
+    func.interface().alt_setting(0xff, 0, 0, None);
+    drop(func);
+    let service = builder.configuration();
+    let mut func = builder.function(0xff, 0x01, 0);
+    func.interface().alt_setting(0xff, 0x01, 0, None);
+    func.interface().alt_setting(0xff, 0x01, 0, None);
+    drop(func);
+    let mut usb = builder.build();
+
+    run(&mut usb, async {
+        let descriptors = host.enumerate().await.unwrap();
+        assert_eq!(descriptors.device[17], 2);
+        assert_eq!(descriptors.configuration[5], 1);
+    });
+
+    // Present only the service configuration.
+    assert_eq!(usb.reconnect(Some(3)), Err(ReconnectError::InvalidConfiguration));
+    assert!(!host.take_disconnect());
+    usb.reconnect(Some(service)).unwrap();
+    assert!(host.take_disconnect());
+
+    run(&mut usb, async {
+        let descriptors = host.enumerate().await.unwrap();
+        assert_eq!(descriptors.device[17], 1);
+        assert_eq!(descriptors.configuration[4], 2); // bNumInterfaces
+        assert_eq!(descriptors.configuration[5], service);
+        assert_eq!(host.set_configuration(1).await, Err(TransferError::Stall));
+    });
+
+    // Present all of them again.
+    usb.reconnect(None).unwrap();
+    assert!(host.take_disconnect());
+
+    run(&mut usb, async {
+        let descriptors = host.enumerate().await.unwrap();
+        assert_eq!(descriptors.device[17], 2);
+        assert_eq!(descriptors.configuration[5], 1);
+    });
+}
@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+use embassy_boot::{BlockingFirmwareUpdater, FirmwareUpdaterConfig};
+use embassy_futures::block_on;
+use embassy_futures::select::select;
+use embassy_usb::control::{Recipient, Request, RequestType};
+use embassy_usb::driver::Direction;
+use embassy_usb::{Builder, Config};
+use embassy_usb_dfu::consts::DfuAttributes;
+use embassy_usb_dfu::{usb_dfu, Control, Reset};
+use embassy_usb_sim::{Host, TransferError};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+const BLOCK_SIZE: usize = 64;
+
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+const STATE_DFU_IDLE: u8 = 2;
+const STATE_DNLOAD_SYNC: u8 = 3;
+const STATE_DNLOAD_IDLE: u8 = 5;
+const STATE_DFU_ERROR: u8 = 10;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_ERR_UNKNOWN: u8 = 0x0e;
+
+/// NOR flash in memory, shared with the test so it can be inspected after the device has reset.
+#[derive(Clone)]
+struct Flash(Rc<RefCell<Vec<u8>>>);
+
+impl Flash {
+    fn new(size: usize) -> Self {
+        Self(Rc::new(RefCell::new(vec![0xff; size])))
+    }
+
+    fn check(&self, offset: u32, len: usize) -> Result<usize, NorFlashErrorKind> {
+        let offset = offset as usize;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.0.borrow().len() => Ok(offset),
+            _ => Err(NorFlashErrorKind::OutOfBounds),
+        }
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.0.borrow()[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.borrow().len()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 256;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if from > to || from as usize % Self::ERASE_SIZE != 0 || to as usize % Self::ERASE_SIZE != 0 {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        let from = self.check(from, (to - from) as usize)?;
+        self.0.borrow_mut()[from..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if offset as usize % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        let offset = self.check(offset, bytes.len())?;
+        self.0.borrow_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Resets by unwinding out of the device, so the test can catch it.
+struct SysReset;
+
+impl Reset for SysReset {
+    fn sys_reset() -> ! {
+        std::panic::panic_any(SysReset)
+    }
+}
+
+fn dfu_request(direction: Direction, request: u8, value: u16, length: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index: 0,
+        length,
+    }
+}
+
+async fn dnload(host: &Host, block: u16, data: &[u8]) -> Result<(), TransferError> {
+    let req = dfu_request(Direction::Out, DFU_DNLOAD, block, data.len() as u16);
+    host.control_out(req, data).await
+}
+
+/// Status and state reported by DFU_GETSTATUS.
+async fn get_status(host: &Host) -> (u8, u8) {
+    let status = host
+        .control_in(dfu_request(Direction::In, DFU_GETSTATUS, 0, 6))
+        .await
+        .unwrap();
+    assert_eq!(status.len(), 6);
+    (status[0], status[4])
+}
+
+async fn get_state(host: &Host) -> u8 {
+    let state = host
+        .control_in(dfu_request(Direction::In, DFU_GETSTATE, 0, 1))
+        .await
+        .unwrap();
+    state[0]
+}
+
+async fn control_out(host: &Host, request: u8) {
+    host.control_out(dfu_request(Direction::Out, request, 0, 0), &[])
+        .await
+        .unwrap();
+}
+
+#[test]
+fn dfu_download() {
+    let dfu = Flash::new(1024);
+    let state = Flash::new(256);
+    let mut aligned = [0; 4];
+    let updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig {
+            dfu: dfu.clone(),
+            state: state.clone(),
+        },
+        &mut aligned,
+    );
+    let mut control = Control::<_, _, SysReset, BLOCK_SIZE>::new(updater, DfuAttributes::CAN_DOWNLOAD);
+
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut builder = Builder::new(
+        driver,
+        Config::new(0xc0de, 0xcafe),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    usb_dfu(&mut builder, &mut control);
+    let mut usb = builder.build();
+
+    let firmware: Vec<u8> = (0..2 * BLOCK_SIZE as u8).collect();
+
+    let reset = catch_unwind(AssertUnwindSafe(|| {
+        block_on(select(usb.run(), async {
+            host.enumerate().await.unwrap();
+            assert_eq!(get_state(&host).await, STATE_DFU_IDLE);
+
+            // The status poll reports the sync state, then moves on to accept the next block.
+            dnload(&host, 0, &firmware[..BLOCK_SIZE]).await.unwrap();
+            assert_eq!(get_state(&host).await, STATE_DNLOAD_SYNC);
+            assert_eq!(get_status(&host).await, (STATUS_OK, STATE_DNLOAD_SYNC));
+            assert_eq!(get_state(&host).await, STATE_DNLOAD_IDLE);
+
+            // Aborting drops the download, so the next block is out of sequence.
+            control_out(&host, DFU_ABORT).await;
+            assert_eq!(get_state(&host).await, STATE_DFU_IDLE);
+            let err = dnload(&host, 1, &firmware[BLOCK_SIZE..]).await;
+            assert_eq!(err, Err(TransferError::Stall));
+            assert_eq!(get_status(&host).await, (STATUS_ERR_UNKNOWN, STATE_DFU_ERROR));
+
+            // The error sticks until it is cleared.
+            assert_eq!(get_status(&host).await, (STATUS_ERR_UNKNOWN, STATE_DFU_ERROR));
+            control_out(&host, DFU_CLRSTATUS).await;
+            assert_eq!(get_status(&host).await, (STATUS_OK, STATE_DFU_IDLE));
+
+            // Start again from the first block.
+            for (block, data) in firmware.chunks(BLOCK_SIZE).enumerate() {
+                dnload(&host, block as u16, data).await.unwrap();
+                assert_eq!(get_status(&host).await, (STATUS_OK, STATE_DNLOAD_SYNC));
+            }
+
+            // A zero length download starts the manifestation, and the device resets once the host
+            // polls for its status.
+            dnload(&host, 2, &[]).await.unwrap();
+            get_status(&host).await;
+            unreachable!("the device didn't reset");
+        }))
+    }));
+
+    assert!(reset.unwrap_err().is::<SysReset>());
+    assert_eq!(dfu.0.borrow()[..firmware.len()], firmware[..]);
+    // The bootloader swaps in the new firmware on the next boot.
+    assert_eq!(state.0.borrow()[..4], [0xf0; 4]);
+}
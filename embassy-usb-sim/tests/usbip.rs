@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::os::unix::net::UnixStream;
+
+use async_io::Async;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select3};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, Config};
+use embassy_usb_sim::usbip::{self, Server};
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+const SET_LINE_CODING: u8 = 0x20;
+
+/// USB/IP client, playing the part of `usbip` and `vhci-hcd`.
+struct Client {
+    stream: Async<UnixStream>,
+    /// Sequence numbers of the IN URBs, whose replies include data.
+    ins: HashSet<u32>,
+}
+
+impl Client {
+    async fn op(&mut self, code: u16, data: &[u8]) -> Vec<u8> {
+        let mut request = vec![0x01, 0x11];
+        request.extend_from_slice(&code.to_be_bytes());
+        request.extend_from_slice(&[0; 4]);
+        request.extend_from_slice(data);
+        self.stream.write_all(&request).await.unwrap();
+
+        let mut reply = Vec::new();
+        self.stream.read_to_end(&mut reply).await.unwrap();
+        reply
+    }
+
+    async fn submit(&mut self, seqnum: u32, ep: u8, length: u32, setup: [u8; 8], data: &[u8]) {
+        if ep & 0x80 != 0 {
+            self.ins.insert(seqnum);
+        }
+        let mut cmd = Vec::new();
+        for field in [
+            1,
+            seqnum,
+            0x0001_0001,
+            (ep >> 7) as u32,
+            (ep & 0x7f) as u32,
+            0,
+            length,
+            0,
+        ] {
+            cmd.extend_from_slice(&field.to_be_bytes());
+        }
+        cmd.extend_from_slice(&0xffff_ffffu32.to_be_bytes()); // not isochronous
+        cmd.extend_from_slice(&0u32.to_be_bytes()); // interval
+        cmd.extend_from_slice(&setup);
+        cmd.extend_from_slice(data);
+        self.stream.write_all(&cmd).await.unwrap();
+    }
+
+    async fn unlink(&mut self, seqnum: u32, unlinked: u32) {
+        let mut cmd = Vec::new();
+        for field in [2, seqnum, 0x0001_0001, 0, 0, unlinked] {
+            cmd.extend_from_slice(&field.to_be_bytes());
+        }
+        cmd.extend_from_slice(&[0; 24]);
+        self.stream.write_all(&cmd).await.unwrap();
+    }
+
+    /// Receive a reply, returning its command, sequence number, status and data.
+    async fn reply(&mut self) -> (u32, u32, i32, Vec<u8>) {
+        let mut header = [0; 48];
+        self.stream.read_exact(&mut header).await.unwrap();
+        let field = |i: usize| u32::from_be_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (command, seqnum, status) = (field(0), field(1), field(5) as i32);
+        let mut data = Vec::new();
+        if command == 3 && self.ins.contains(&seqnum) {
+            data = vec![0; field(6) as usize];
+            self.stream.read_exact(&mut data).await.unwrap();
+        }
+        (command, seqnum, status, data)
+    }
+}
+
+#[test]
+fn usbip() {
+    let (driver, host) = embassy_usb_sim::new();
+    let mut config_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut state = cdc_acm::State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        Config::new(0xc0de, 0xcafe),
+        &mut config_descriptor,
+        &mut [],
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let mut usb = builder.build();
+
+    // Echo data back, until the line coding changes.
+    let echo = async {
+        let mut buf = [0; 64];
+        loop {
+            class.wait_connection().await;
+            loop {
+                match class.read_packet(&mut buf).await {
+                    Ok(n) => match class.write_packet(&buf[..n]).await {
+                        Ok(()) => {}
+                        Err(EndpointError::Disabled) => break,
+                        Err(e) => panic!("{:?}", e),
+                    },
+                    Err(EndpointError::Disabled) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        }
+    };
+
+    let server = Server::new(host, usbip::Config::default());
+
+    async_io::block_on(select3(usb.run(), echo, async {
+        // List the devices.
+        let (stream, server_stream) = Async::<UnixStream>::pair().unwrap();
+        let mut client = Client {
+            stream,
+            ins: HashSet::new(),
+        };
+        let (served, reply) = join(server.serve(server_stream), async {
+            let reply = client.op(0x8005, &[]).await;
+            drop(client);
+            reply
+        })
+        .await;
+        served.unwrap();
+        assert_eq!(reply[..4], [0x01, 0x11, 0x00, 0x05]);
+        assert_eq!(reply[8..12], 1u32.to_be_bytes());
+        let device = &reply[12..];
+        assert_eq!(&device[256..260], b"1-1\0");
+        assert_eq!(device[300..304], [0xc0, 0xde, 0xca, 0xfe]);
+        assert_eq!(device[311], 2, "bNumInterfaces");
+        // Communications and data interfaces.
+        assert_eq!(device[312..320], [0x02, 0x02, 0x00, 0, 0x0a, 0x00, 0x00, 0]);
+
+        // Import the device, and transfer data.
+        let (stream, server_stream) = Async::<UnixStream>::pair().unwrap();
+        let mut client = Client {
+            stream,
+            ins: HashSet::new(),
+        };
+        let served = server.serve(server_stream);
+        let client = async {
+            let mut busid = [0; 32];
+            busid[..3].copy_from_slice(b"1-1");
+            let mut request = vec![0x01, 0x11, 0x80, 0x03, 0, 0, 0, 0];
+            request.extend_from_slice(&busid);
+            client.stream.write_all(&request).await.unwrap();
+            let mut reply = [0; 8 + 312];
+            client.stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[..8], [0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0]);
+
+            // GET_DESCRIPTOR(DEVICE).
+            client
+                .submit(1, 0x80, 18, [0x80, 0x06, 0x00, 0x01, 0, 0, 18, 0], &[])
+                .await;
+            let (command, seqnum, status, data) = client.reply().await;
+            assert_eq!((command, seqnum, status), (3, 1, 0));
+            assert_eq!(data.len(), 18);
+            assert_eq!(data[8..12], [0xde, 0xc0, 0xfe, 0xca]);
+
+            // SET_CONTROL_LINE_STATE with DTR, to connect the echo.
+            client
+                .submit(2, 0x00, 0, [0x21, 0x22, 0x01, 0x00, 0, 0, 0, 0], &[])
+                .await;
+            assert_eq!(client.reply().await, (3, 2, 0, Vec::new()));
+
+            // Data is echoed on the bulk endpoints.
+            client.submit(3, 0x82, 64, [0; 8], &[]).await;
+            client.submit(4, 0x01, 5, [0; 8], b"hello").await;
+            let mut replies = [client.reply().await, client.reply().await];
+            replies.sort_by_key(|r| r.1);
+            assert_eq!(replies, [(3, 3, 0, b"hello".to_vec()), (3, 4, 0, Vec::new())]);
+
+            // A pending read is unlinked.
+            client.submit(5, 0x82, 64, [0; 8], &[]).await;
+            client.unlink(6, 5).await;
+            assert_eq!(client.reply().await, (4, 6, -104, Vec::new()));
+
+            // A rejected request stalls.
+            let setup = [0x21, SET_LINE_CODING, 0, 0, 9, 0, 7, 0];
+            client.submit(7, 0x00, 7, setup, &[0; 7]).await;
+            assert_eq!(client.reply().await, (3, 7, -32, Vec::new()));
+        };
+        match select(served, client).await {
+            embassy_futures::select::Either::First(r) => panic!("server stopped: {:?}", r),
+            embassy_futures::select::Either::Second(()) => {}
+        }
+    }));
+}
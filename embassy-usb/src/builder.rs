@@ -5,7 +5,7 @@ use crate::descriptor::{BosWriter, DescriptorWriter, SynchronizationType, UsageT
 use crate::driver::{Driver, Endpoint, EndpointInfo, EndpointType};
 use crate::msos::{DeviceLevelDescriptor, FunctionLevelDescriptor, MsOsDescriptorWriter};
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::{Handler, Interface, UsbDevice, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START};
+use crate::{Handler, Interface, UsbDevice, CONFIGURATION_VALUE, MAX_INTERFACE_COUNT, STRING_INDEX_CUSTOM_START};
 
 #[derive(Debug, Copy, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -122,10 +122,15 @@ impl<'a> Config<'a> {
 /// [`UsbDevice`] builder.
 pub struct Builder<'d, D: Driver<'d>> {
     config: Config<'d>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
     control_buf: &'d mut [u8],
 
+    /// Value of the configuration being built.
+    configuration: u8,
+    /// Index in `interfaces` of the first interface of the configuration being built.
+    first_interface: usize,
+
     driver: D,
     next_string_index: u8,
 
@@ -169,7 +174,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         let mut config_descriptor = DescriptorWriter::new(config_descriptor_buf);
         let mut bos_descriptor = BosWriter::new(DescriptorWriter::new(bos_descriptor_buf));
 
-        config_descriptor.configuration(&config);
+        config_descriptor.configuration(&config, CONFIGURATION_VALUE);
         bos_descriptor.bos();
 
         Builder {
@@ -178,6 +183,8 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
             interfaces: Vec::new(),
             handlers: Vec::new(),
             control_buf,
+            configuration: CONFIGURATION_VALUE,
+            first_interface: 0,
             next_string_index: STRING_INDEX_CUSTOM_START,
 
             config_descriptor,
@@ -218,6 +225,28 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
         self.control_buf.len()
     }
 
+    /// Starts a new configuration, and returns its value.
+    ///
+    /// [`Builder::new`] starts the first configuration, with value [`CONFIGURATION_VALUE`]. Functions,
+    /// interfaces and handlers added after this call belong to the new configuration, and interface
+    /// numbers start again from 0.
+    ///
+    /// Endpoints are not reused between configurations: the endpoints of each configuration are
+    /// allocated from the driver like the others, so the hardware must have enough endpoints for
+    /// all the configurations together.
+    ///
+    /// The host selects one of the configurations with a SET_CONFIGURATION request. The ones presented
+    /// to it can be changed at runtime with [`UsbDevice::reconnect`].
+    pub fn configuration(&mut self) -> u8 {
+        self.config_descriptor.end_configuration();
+        self.msos_descriptor.end_configuration();
+
+        self.configuration += 1;
+        self.first_interface = self.interfaces.len();
+        self.config_descriptor.configuration(&self.config, self.configuration);
+        self.configuration
+    }
+
     /// Add an USB function.
     ///
     /// If [`Config::composite_with_iads`] is set, this will add an IAD descriptor
@@ -225,7 +254,7 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// If it's not set, no IAD descriptor is added.
     pub fn function(&mut self, class: u8, subclass: u8, protocol: u8) -> FunctionBuilder<'_, 'd, D> {
-        let first_interface = InterfaceNumber::new(self.interfaces.len() as u8);
+        let first_interface = InterfaceNumber::new((self.interfaces.len() - self.first_interface) as u8);
         let iface_count_index = if self.config.composite_with_iads {
             self.config_descriptor
                 .iad(first_interface, 0, class, subclass, protocol);
@@ -247,9 +276,12 @@ impl<'d, D: Driver<'d>> Builder<'d, D> {
     ///
     /// The Handler is called on some USB bus events, and to handle all control requests not already
     /// handled by the USB stack.
+    ///
+    /// If the device has several configurations, the handler only gets the alternate setting changes and
+    /// the interface control requests of the configuration it was added in.
     pub fn handler(&mut self, handler: &'d mut dyn Handler) {
         assert!(
-            self.handlers.push(handler).is_ok(),
+            self.handlers.push((self.configuration, handler)).is_ok(),
             "embassy-usb: handler list full. Increase the `max_handler_count` compile-time setting. Current value: {}",
             MAX_HANDLER_COUNT
         );
@@ -302,13 +334,14 @@ impl<'a, 'd, D: Driver<'d>> Drop for FunctionBuilder<'a, 'd, D> {
 impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
     /// Add an interface to the function.
     ///
-    /// Interface numbers are guaranteed to be allocated consecutively, starting from 0.
+    /// Interface numbers are guaranteed to be allocated consecutively, starting from 0 in each
+    /// configuration.
     pub fn interface(&mut self) -> InterfaceBuilder<'_, 'd, D> {
         if let Some(i) = self.iface_count_index {
             self.builder.config_descriptor.buf[i] += 1;
         }
 
-        let number = self.builder.interfaces.len() as _;
+        let number = (self.builder.interfaces.len() - self.builder.first_interface) as _;
         let iface = Interface {
             current_alt_setting: 0,
             num_alt_settings: 0,
@@ -329,7 +362,9 @@ impl<'a, 'd, D: Driver<'d>> FunctionBuilder<'a, 'd, D> {
     /// Add an MS OS 2.0 Function Level Feature Descriptor.
     pub fn msos_feature<T: FunctionLevelDescriptor>(&mut self, desc: T) {
         if !self.builder.msos_descriptor.is_in_config_subset() {
-            self.builder.msos_descriptor.configuration(0);
+            // MS OS configuration subsets are numbered by configuration index.
+            let index = self.builder.configuration - CONFIGURATION_VALUE;
+            self.builder.msos_descriptor.configuration(index);
         }
 
         if !self.builder.msos_descriptor.is_in_function_subset() {
@@ -372,7 +407,8 @@ impl<'a, 'd, D: Driver<'d>> InterfaceBuilder<'a, 'd, D> {
     ) -> InterfaceAltBuilder<'_, 'd, D> {
         let number = self.next_alt_setting_number;
         self.next_alt_setting_number += 1;
-        self.builder.interfaces[self.interface_number.0 as usize].num_alt_settings += 1;
+        let index = self.builder.first_interface + self.interface_number.0 as usize;
+        self.builder.interfaces[index].num_alt_settings += 1;
 
         self.builder.config_descriptor.interface_alt(
             self.interface_number,
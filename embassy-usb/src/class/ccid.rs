@@ -0,0 +1,621 @@
+//! CCID class implementation, aka USB smart card reader.
+//!
+//! This implements a reader with a single slot, exchanging short APDUs with the card. The card can
+//! be a real one behind an ISO 7816 interface, or emulated by the firmware, like in security tokens.
+//! It is accessed through the [`SmartCard`] trait.
+//!
+//! Hosts set the T=1 protocol parameters and power the card on before sending it commands. The
+//! reader reports the parameters the host asks for, but it's up to the card to use them.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_CCID: u8 = 0x0b;
+
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL: u8 = 0x00;
+
+const CCID_DESCRIPTOR_TYPE: u8 = 0x21;
+const BCD_CCID: u16 = 0x0110;
+
+const REQ_ABORT: u8 = 0x01;
+
+const PC_TO_RDR_SET_PARAMETERS: u8 = 0x61;
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+const PC_TO_RDR_SECURE: u8 = 0x69;
+const PC_TO_RDR_ESCAPE: u8 = 0x6b;
+const PC_TO_RDR_GET_PARAMETERS: u8 = 0x6c;
+const PC_TO_RDR_RESET_PARAMETERS: u8 = 0x6d;
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6f;
+const PC_TO_RDR_ABORT: u8 = 0x72;
+const PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x73;
+
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+const RDR_TO_PC_PARAMETERS: u8 = 0x82;
+const RDR_TO_PC_ESCAPE: u8 = 0x83;
+const RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY: u8 = 0x84;
+const RDR_TO_PC_NOTIFY_SLOT_CHANGE: u8 = 0x50;
+
+/// Errors of failed commands, or the offset of the invalid field of the message.
+const ERROR_CMD_NOT_SUPPORTED: u8 = 0x00;
+const ERROR_BAD_LENGTH: u8 = 1;
+const ERROR_BAD_SLOT: u8 = 5;
+const ERROR_BAD_LEVEL_PARAMETER: u8 = 8;
+const ERROR_HW_ERROR: u8 = 0xfb;
+const ERROR_ICC_MUTE: u8 = 0xfe;
+
+const HEADER_LEN: usize = 10;
+
+/// Features of the reader: it handles the whole protocol negotiation with the card, and exchanges
+/// short APDUs with the host.
+const FEATURES: u32 = 0x0002_00fe;
+
+/// T=1 parameters: Fi/Di, checksum, guard time, waiting integers, clock stop, IFSC and NAD.
+const T1_PARAMETERS: [u8; 7] = [0x11, 0x10, 0x00, 0x4d, 0x00, 0xfe, 0x00];
+
+/// Size of the largest message, with a short command APDU: header, CLA, INS, P1, P2, Lc, 255 bytes
+/// of data, and Le.
+pub const MIN_MESSAGE_LEN: usize = HEADER_LEN + 261;
+
+/// Error returned by a [`SmartCard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The card doesn't respond.
+    Mute,
+    /// The card or its interface failed.
+    Hardware,
+}
+
+impl Error {
+    fn code(self) -> u8 {
+        match self {
+            Self::Mute => ERROR_ICC_MUTE,
+            Self::Hardware => ERROR_HW_ERROR,
+        }
+    }
+}
+
+/// A smart card, in the slot of the reader.
+pub trait SmartCard {
+    /// Whether the card is present in the slot.
+    ///
+    /// Cards emulated by the firmware are always present.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Waits for the card to be inserted or removed.
+    ///
+    /// It's only called if the reader is [`Config::removable`].
+    async fn wait_presence_changed(&mut self) {
+        core::future::pending().await
+    }
+
+    /// Powers the card on, and writes its answer to reset to `atr`.
+    ///
+    /// Returns the length of the answer to reset.
+    async fn power_on(&mut self, atr: &mut [u8]) -> Result<usize, Error>;
+
+    /// Powers the card off.
+    async fn power_off(&mut self);
+
+    /// Sends a command APDU to the card, and writes the response APDU to `response`.
+    ///
+    /// Returns the length of the response APDU, including its status word.
+    async fn transfer(&mut self, command: &[u8], response: &mut [u8]) -> Result<usize, Error>;
+}
+
+/// Configuration for the CCID class.
+pub struct Config {
+    /// Whether cards can be inserted and removed.
+    ///
+    /// Removable readers have an interrupt endpoint to notify the host when it happens. Security
+    /// tokens, with a card that is always present, don't need it.
+    pub removable: bool,
+
+    /// Size of the largest message exchanged with the host, at least [`MIN_MESSAGE_LEN`] and the
+    /// max packet size.
+    ///
+    /// The buffer passed to [`CcidClass::run`] must be able to hold two of them.
+    pub max_message_len: u16,
+
+    /// Max packet size for both the bulk IN and OUT endpoints.
+    ///
+    /// This is 64 for full speed devices, and 512 for high speed ones.
+    pub max_packet_size: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            removable: false,
+            max_message_len: MIN_MESSAGE_LEN as u16,
+            max_packet_size: 64,
+        }
+    }
+}
+
+/// Internal state for the CCID class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                reset: Cell::new(false),
+                waker: RefCell::new(WakerRegistration::new()),
+            },
+        }
+    }
+}
+
+/// Shared data between Control and CcidClass
+struct ControlShared {
+    reset: Cell<bool>,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    fn signal_reset(&self) {
+        self.reset.set(true);
+        self.waker.borrow_mut().wake();
+    }
+
+    async fn wait_reset(&self) {
+        poll_fn(|cx| {
+            if self.reset.replace(false) {
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.signal_reset();
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            // Commands are executed as soon as they are received, so there is never one to abort.
+            // The host then sends PC_to_RDR_Abort on the bulk endpoint, which is answered normally.
+            REQ_ABORT => Some(OutResponse::Accepted),
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        // GET_CLOCK_FREQUENCIES and GET_DATA_RATES are only used by readers with a list of them.
+        Some(InResponse::Rejected)
+    }
+}
+
+/// State of the card, reported in the status of the responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IccStatus {
+    Active = 0,
+    Inactive = 1,
+    NotPresent = 2,
+}
+
+/// Header of a message from the host.
+struct Header {
+    message_type: u8,
+    data_len: usize,
+    slot: u8,
+    seq: u8,
+    /// Message specific bytes.
+    params: [u8; 3],
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Self {
+        Self {
+            message_type: buf[0],
+            data_len: u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize,
+            slot: buf[5],
+            seq: buf[6],
+            params: [buf[7], buf[8], buf[9]],
+        }
+    }
+}
+
+/// Result of a command, with the type of its response.
+struct Response {
+    message_type: u8,
+    /// Error code, or `None` if the command succeeded.
+    error: Option<u8>,
+    /// Message specific byte of the header.
+    specific: u8,
+    data_len: usize,
+}
+
+impl Response {
+    fn ok(message_type: u8, specific: u8, data_len: usize) -> Self {
+        Self {
+            message_type,
+            error: None,
+            specific,
+            data_len,
+        }
+    }
+
+    fn failed(message_type: u8, error: u8) -> Self {
+        Self {
+            message_type,
+            error: Some(error),
+            specific: 0,
+            data_len: 0,
+        }
+    }
+}
+
+/// USB smart card reader.
+pub struct CcidClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    notify_ep: Option<D::EndpointIn>,
+    max_message_len: usize,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> CcidClass<'d, D> {
+    /// Creates a new `CcidClass`.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config) -> Self {
+        assert!(
+            config.max_message_len as usize >= MIN_MESSAGE_LEN,
+            "ccid: max_message_len too small for short APDUs"
+        );
+        assert!(
+            config.max_message_len >= config.max_packet_size,
+            "ccid: max_message_len smaller than a packet"
+        );
+
+        let mut func = builder.function(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_CCID, CCID_SUBCLASS, CCID_PROTOCOL, None);
+
+        let mut desc = [0; 52];
+        desc[0..2].copy_from_slice(&BCD_CCID.to_le_bytes());
+        desc[2] = 0x00; // bMaxSlotIndex
+        desc[3] = 0x07; // bVoltageSupport: 5V, 3V and 1.8V
+        desc[4..8].copy_from_slice(&0x0000_0002u32.to_le_bytes()); // dwProtocols: T=1
+        desc[8..12].copy_from_slice(&4000u32.to_le_bytes()); // dwDefaultClock, in kHz
+        desc[12..16].copy_from_slice(&4000u32.to_le_bytes()); // dwMaximumClock
+        desc[16] = 0; // bNumClockSupported
+        desc[17..21].copy_from_slice(&10752u32.to_le_bytes()); // dwDataRate, in bps
+        desc[21..25].copy_from_slice(&10752u32.to_le_bytes()); // dwMaxDataRate
+        desc[25] = 0; // bNumDataRatesSupported
+        desc[26..30].copy_from_slice(&254u32.to_le_bytes()); // dwMaxIFSD
+        desc[30..34].copy_from_slice(&0u32.to_le_bytes()); // dwSynchProtocols
+        desc[34..38].copy_from_slice(&0u32.to_le_bytes()); // dwMechanical
+        desc[38..42].copy_from_slice(&FEATURES.to_le_bytes()); // dwFeatures
+        desc[42..46].copy_from_slice(&(config.max_message_len as u32).to_le_bytes()); // dwMaxCCIDMessageLength
+        desc[46] = 0xff; // bClassGetResponse: echo the class of the APDU
+        desc[47] = 0xff; // bClassEnvelope
+        desc[48..50].copy_from_slice(&0u16.to_le_bytes()); // wLcdLayout: no LCD
+        desc[50] = 0; // bPINSupport: no PIN pad
+        desc[51] = 1; // bMaxCCIDBusySlots
+        alt.descriptor(CCID_DESCRIPTOR_TYPE, &desc);
+
+        let read_ep = alt.endpoint_bulk_out(config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(config.max_packet_size);
+        let notify_ep = config.removable.then(|| alt.endpoint_interrupt_in(2, 16));
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        Self {
+            read_ep,
+            write_ep,
+            notify_ep,
+            max_message_len: config.max_message_len as usize,
+            shared: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Serve the host's commands, with `card` in the slot.
+    ///
+    /// `buf` is used for the messages, and must be able to hold twice the `max_message_len` of the
+    /// [`Config`]. The card is powered off when the device is reset or deconfigured.
+    pub async fn run<C: SmartCard>(&mut self, card: &mut C, buf: &mut [u8]) -> ! {
+        assert!(buf.len() >= 2 * self.max_message_len, "ccid: buffer too small");
+        let (buf, scratch) = buf.split_at_mut(self.max_message_len);
+
+        let shared = self.shared;
+        let mut powered = false;
+        loop {
+            self.read_ep.wait_enabled().await;
+            match select(self.serve(card, &mut powered, buf, scratch), shared.wait_reset()).await {
+                Either::First(EndpointError::Disabled) => debug!("ccid: disabled"),
+                Either::First(EndpointError::BufferOverflow) => warn!("ccid: unexpected packet size"),
+                Either::Second(()) => debug!("ccid: reset"),
+            }
+            if powered {
+                card.power_off().await;
+                powered = false;
+            }
+        }
+    }
+
+    /// Execute commands until an endpoint error.
+    async fn serve<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        powered: &mut bool,
+        buf: &mut [u8],
+        scratch: &mut [u8],
+    ) -> EndpointError {
+        let mps = self.max_packet_size() as usize;
+        let mut present = card.is_present();
+        // Hosts expect the state of the slot when they connect.
+        let mut notify = self.notify_ep.is_some();
+
+        loop {
+            // Wait for the first packet of a command, watching the card meanwhile. Notifications
+            // don't hold commands back if the host is slow to read them.
+            let Self { read_ep, notify_ep, .. } = self;
+            let slot = async {
+                loop {
+                    let Some(ep) = notify_ep.as_mut() else {
+                        return core::future::pending::<EndpointError>().await;
+                    };
+                    if notify {
+                        // Slot 0 is present (bit 0) and changed (bit 1).
+                        if let Err(e) = ep.write(&[RDR_TO_PC_NOTIFY_SLOT_CHANGE, 0x02 | present as u8]).await {
+                            return e;
+                        }
+                        notify = false;
+                    }
+                    card.wait_presence_changed().await;
+                    if card.is_present() != present {
+                        present = !present;
+                        if !present {
+                            *powered = false;
+                        }
+                        notify = true;
+                    }
+                }
+            };
+            let n = match select(read_ep.read(&mut buf[..mps]), slot).await {
+                Either::First(Ok(n)) => n,
+                Either::First(Err(e)) | Either::Second(e) => return e,
+            };
+
+            if let Err(e) = self.command(card, powered, buf, scratch, n).await {
+                return e;
+            }
+        }
+    }
+
+    /// Receive the rest of a command whose first packet is `len` bytes of `buf`, execute it, and
+    /// send its response.
+    async fn command<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        powered: &mut bool,
+        buf: &mut [u8],
+        scratch: &mut [u8],
+        mut len: usize,
+    ) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        if len < HEADER_LEN {
+            warn!("ccid: message too short");
+            return Ok(());
+        }
+        let header = Header::parse(buf);
+        trace!("ccid: command {:02x}, {} bytes", header.message_type, header.data_len);
+
+        // Receive the rest of the message, discarding what doesn't fit in the buffer.
+        let total_len = HEADER_LEN.saturating_add(header.data_len);
+        let mut received = len;
+        let mut packet_len = len;
+        while packet_len == mps && received < total_len {
+            packet_len = if buf.len() - len >= mps {
+                let n = self.read_ep.read(&mut buf[len..len + mps]).await?;
+                len += n;
+                n
+            } else {
+                self.read_ep.read(&mut scratch[..mps]).await?
+            };
+            received += packet_len;
+        }
+
+        let status = if !card.is_present() {
+            *powered = false;
+            IccStatus::NotPresent
+        } else if *powered {
+            IccStatus::Active
+        } else {
+            IccStatus::Inactive
+        };
+
+        let response = if received != total_len || len != received {
+            debug!("ccid: bad message length");
+            Response::failed(response_type(header.message_type), ERROR_BAD_LENGTH)
+        } else if header.slot != 0 {
+            Response::failed(response_type(header.message_type), ERROR_BAD_SLOT)
+        } else {
+            self.execute(card, powered, status, &header, buf, scratch).await
+        };
+
+        // Report the state of the card after the command.
+        let status = match (status, *powered) {
+            (IccStatus::NotPresent, _) => IccStatus::NotPresent,
+            (_, true) => IccStatus::Active,
+            (_, false) => IccStatus::Inactive,
+        };
+        let (command_status, error) = match response.error {
+            None => (0, 0),
+            Some(error) => (1, error),
+        };
+        buf[0] = response.message_type;
+        buf[1..5].copy_from_slice(&(response.data_len as u32).to_le_bytes());
+        buf[5] = header.slot;
+        buf[6] = header.seq;
+        buf[7] = command_status << 6 | status as u8;
+        buf[8] = error;
+        buf[9] = response.specific;
+
+        // Send the response as a single transfer, ending with a short packet.
+        let len = HEADER_LEN + response.data_len;
+        for packet in buf[..len].chunks(mps) {
+            self.write_ep.write(packet).await?;
+        }
+        if len % mps == 0 {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Execute a command, with its data after the header in `buf`. The data of the response replaces it.
+    async fn execute<C: SmartCard>(
+        &mut self,
+        card: &mut C,
+        powered: &mut bool,
+        status: IccStatus,
+        header: &Header,
+        buf: &mut [u8],
+        scratch: &mut [u8],
+    ) -> Response {
+        let data_len = header.data_len;
+        match header.message_type {
+            PC_TO_RDR_GET_SLOT_STATUS | PC_TO_RDR_ABORT => Response::ok(RDR_TO_PC_SLOT_STATUS, clock_status(status), 0),
+            PC_TO_RDR_ICC_POWER_ON => {
+                if status == IccStatus::NotPresent {
+                    return Response::failed(RDR_TO_PC_DATA_BLOCK, ERROR_ICC_MUTE);
+                }
+                match card.power_on(&mut buf[HEADER_LEN..]).await {
+                    Ok(n) => {
+                        *powered = true;
+                        Response::ok(RDR_TO_PC_DATA_BLOCK, 0, n)
+                    }
+                    Err(e) => {
+                        debug!("ccid: power on failed: {:?}", e);
+                        *powered = false;
+                        Response::failed(RDR_TO_PC_DATA_BLOCK, e.code())
+                    }
+                }
+            }
+            PC_TO_RDR_ICC_POWER_OFF => {
+                if *powered {
+                    card.power_off().await;
+                    *powered = false;
+                }
+                let status = match status {
+                    IccStatus::NotPresent => IccStatus::NotPresent,
+                    _ => IccStatus::Inactive,
+                };
+                Response::ok(RDR_TO_PC_SLOT_STATUS, clock_status(status), 0)
+            }
+            PC_TO_RDR_XFR_BLOCK => {
+                if status != IccStatus::Active {
+                    return Response::failed(RDR_TO_PC_DATA_BLOCK, ERROR_ICC_MUTE);
+                }
+                // Only short APDUs are exchanged, so they are never chained.
+                if header.params[1] != 0 || header.params[2] != 0 {
+                    return Response::failed(RDR_TO_PC_DATA_BLOCK, ERROR_BAD_LEVEL_PARAMETER);
+                }
+                // The command is moved out of the way of the response.
+                let command = &mut scratch[..data_len];
+                command.copy_from_slice(&buf[HEADER_LEN..HEADER_LEN + data_len]);
+                match card.transfer(command, &mut buf[HEADER_LEN..]).await {
+                    Ok(n) => Response::ok(RDR_TO_PC_DATA_BLOCK, 0, n),
+                    Err(e) => {
+                        debug!("ccid: transfer failed: {:?}", e);
+                        Response::failed(RDR_TO_PC_DATA_BLOCK, e.code())
+                    }
+                }
+            }
+            PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => {
+                if status == IccStatus::NotPresent {
+                    return Response::failed(RDR_TO_PC_PARAMETERS, ERROR_ICC_MUTE);
+                }
+                buf[HEADER_LEN..HEADER_LEN + T1_PARAMETERS.len()].copy_from_slice(&T1_PARAMETERS);
+                // bProtocolNum: T=1
+                Response::ok(RDR_TO_PC_PARAMETERS, 1, T1_PARAMETERS.len())
+            }
+            _ => {
+                debug!("ccid: unsupported command {:02x}", header.message_type);
+                Response::failed(response_type(header.message_type), ERROR_CMD_NOT_SUPPORTED)
+            }
+        }
+    }
+}
+
+/// Type of the response to a message.
+fn response_type(message_type: u8) -> u8 {
+    match message_type {
+        PC_TO_RDR_ICC_POWER_ON | PC_TO_RDR_XFR_BLOCK | PC_TO_RDR_SECURE => RDR_TO_PC_DATA_BLOCK,
+        PC_TO_RDR_GET_PARAMETERS | PC_TO_RDR_RESET_PARAMETERS | PC_TO_RDR_SET_PARAMETERS => RDR_TO_PC_PARAMETERS,
+        PC_TO_RDR_ESCAPE => RDR_TO_PC_ESCAPE,
+        PC_TO_RDR_SET_DATA_RATE_AND_CLOCK_FREQUENCY => RDR_TO_PC_DATA_RATE_AND_CLOCK_FREQUENCY,
+        _ => RDR_TO_PC_SLOT_STATUS,
+    }
+}
+
+/// bClockStatus of the slot status: running, or stopped in an unknown state.
+fn clock_status(status: IccStatus) -> u8 {
+    match status {
+        IccStatus::Active => 0x00,
+        _ => 0x03,
+    }
+}
@@ -0,0 +1,395 @@
+//! HID report descriptor builder.
+//!
+//! [`ReportDescriptor`] writes a report descriptor item by item. All its methods are `const fn`, so
+//! descriptors can be built at compile time and stored in a `static`:
+//!
+//! ```
+//! use embassy_usb::class::hid::descriptor::{CollectionType, MainItemFlags, ReportDescriptor};
+//! use embassy_usb::class::hid::usage::{self, generic_desktop};
+//!
+//! // A mouse with three buttons.
+//! static MOUSE: ReportDescriptor<64> = ReportDescriptor::new()
+//!     .usage(generic_desktop::MOUSE)
+//!     .collection(CollectionType::Application)
+//!     .usage_minimum(usage::button(1))
+//!     .usage_maximum(usage::button(3))
+//!     .logical_minimum(0)
+//!     .logical_maximum(1)
+//!     .report_size(1)
+//!     .report_count(3)
+//!     .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+//!     .report_size(5)
+//!     .report_count(1)
+//!     .input(MainItemFlags::CONSTANT)
+//!     .usage(generic_desktop::X)
+//!     .usage(generic_desktop::Y)
+//!     .logical_minimum(-127)
+//!     .logical_maximum(127)
+//!     .report_size(8)
+//!     .report_count(2)
+//!     .input(MainItemFlags::DATA_VARIABLE_RELATIVE)
+//!     .end_collection();
+//!
+//! let report_descriptor: &'static [u8] = MOUSE.as_bytes();
+//! # assert_eq!(report_descriptor[..4], [0x05, 0x01, 0x09, 0x02]);
+//! ```
+//!
+//! Usages are typed: each [`Usage`] knows its [`UsagePage`], and the builder emits the Usage Page
+//! items as needed.
+
+use core::ops::BitOr;
+
+/// Usage page, the high 16 bits of a usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UsagePage(pub u16);
+
+impl UsagePage {
+    /// Generic Desktop page.
+    pub const GENERIC_DESKTOP: Self = Self(0x01);
+    /// Simulation Controls page.
+    pub const SIMULATION_CONTROLS: Self = Self(0x02);
+    /// Game Controls page.
+    pub const GAME_CONTROLS: Self = Self(0x05);
+    /// Generic Device Controls page.
+    pub const GENERIC_DEVICE_CONTROLS: Self = Self(0x06);
+    /// Keyboard/Keypad page.
+    pub const KEYBOARD: Self = Self(0x07);
+    /// LED page.
+    pub const LED: Self = Self(0x08);
+    /// Button page.
+    pub const BUTTON: Self = Self(0x09);
+    /// Ordinal page.
+    pub const ORDINAL: Self = Self(0x0a);
+    /// Consumer page.
+    pub const CONSUMER: Self = Self(0x0c);
+    /// Digitizers page.
+    pub const DIGITIZERS: Self = Self(0x0d);
+
+    /// Vendor-defined page `n`, in the range 0xFF00 to 0xFFFF.
+    pub const fn vendor_defined(n: u8) -> Self {
+        Self(0xff00 | n as u16)
+    }
+}
+
+/// Usage, identifying what a control or collection is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Usage {
+    /// Usage page.
+    pub page: UsagePage,
+    /// Usage ID within the page.
+    pub id: u16,
+}
+
+impl Usage {
+    /// Create a usage from its page and ID.
+    pub const fn new(page: UsagePage, id: u16) -> Self {
+        Self { page, id }
+    }
+}
+
+/// Type of a collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum CollectionType {
+    /// Group of axes.
+    Physical = 0x00,
+    /// Top-level collection, identifying a device function.
+    Application = 0x01,
+    /// Group of data items forming a composite data structure.
+    Logical = 0x02,
+    /// Wraps all the fields of a report.
+    Report = 0x03,
+    /// Array of selectors.
+    NamedArray = 0x04,
+    /// Modifies the meaning of the usage it contains.
+    UsageSwitch = 0x05,
+    /// Modifies the meaning of the usage attached to the encompassing collection.
+    UsageModifier = 0x06,
+}
+
+/// Flags of the Input, Output and Feature main items.
+///
+/// The flags with a zero value (`DATA`, `ARRAY`, `ABSOLUTE`, ...) document the default of each bit.
+/// Combine flags with `|`, or with [`MainItemFlags::union`] in `const` contexts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MainItemFlags(pub u16);
+
+impl MainItemFlags {
+    /// The field is modifiable data.
+    pub const DATA: Self = Self(0);
+    /// The field is constant, e.g. padding.
+    pub const CONSTANT: Self = Self(1 << 0);
+    /// Each field holds the index of an active usage.
+    pub const ARRAY: Self = Self(0);
+    /// Each field holds the value of one usage.
+    pub const VARIABLE: Self = Self(1 << 1);
+    /// Values are absolute.
+    pub const ABSOLUTE: Self = Self(0);
+    /// Values are relative to the previous report.
+    pub const RELATIVE: Self = Self(1 << 2);
+    /// Values roll over at the logical extremes.
+    pub const WRAP: Self = Self(1 << 3);
+    /// Values are not linear to the measured quantity.
+    pub const NON_LINEAR: Self = Self(1 << 4);
+    /// The control does not return to a preferred state when released.
+    pub const NO_PREFERRED: Self = Self(1 << 5);
+    /// The control has a state outside the logical range, meaning no meaningful data.
+    pub const NULL_STATE: Self = Self(1 << 6);
+    /// The value may change without the host writing it. Not valid for Input items.
+    pub const VOLATILE: Self = Self(1 << 7);
+    /// The field is a stream of bytes rather than a bit field.
+    pub const BUFFERED_BYTES: Self = Self(1 << 8);
+
+    /// Variable absolute data, e.g. buttons or joystick axes.
+    pub const DATA_VARIABLE_ABSOLUTE: Self = Self::DATA.union(Self::VARIABLE).union(Self::ABSOLUTE);
+    /// Variable relative data, e.g. mouse movements.
+    pub const DATA_VARIABLE_RELATIVE: Self = Self::DATA.union(Self::VARIABLE).union(Self::RELATIVE);
+    /// Array of absolute data, e.g. the keys pressed on a keyboard.
+    pub const DATA_ARRAY_ABSOLUTE: Self = Self::DATA.union(Self::ARRAY).union(Self::ABSOLUTE);
+
+    /// Combine two sets of flags.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOr for MainItemFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+// Item prefixes, without the size bits.
+const MAIN_INPUT: u8 = 0x80;
+const MAIN_OUTPUT: u8 = 0x90;
+const MAIN_COLLECTION: u8 = 0xa0;
+const MAIN_FEATURE: u8 = 0xb0;
+const MAIN_END_COLLECTION: u8 = 0xc0;
+const GLOBAL_USAGE_PAGE: u8 = 0x04;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x14;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x24;
+const GLOBAL_PHYSICAL_MINIMUM: u8 = 0x34;
+const GLOBAL_PHYSICAL_MAXIMUM: u8 = 0x44;
+const GLOBAL_UNIT_EXPONENT: u8 = 0x54;
+const GLOBAL_UNIT: u8 = 0x64;
+const GLOBAL_REPORT_SIZE: u8 = 0x74;
+const GLOBAL_REPORT_ID: u8 = 0x84;
+const GLOBAL_REPORT_COUNT: u8 = 0x94;
+const LOCAL_USAGE: u8 = 0x08;
+const LOCAL_USAGE_MINIMUM: u8 = 0x18;
+const LOCAL_USAGE_MAXIMUM: u8 = 0x28;
+
+/// HID report descriptor builder, writing into a buffer of `N` bytes.
+///
+/// Building panics if the descriptor does not fit in the buffer, which is a compile-time error when
+/// building in a `const` or `static`.
+#[derive(Clone)]
+pub struct ReportDescriptor<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Usage page in effect, 0 when unset.
+    usage_page: u16,
+    /// Whether local usage items were written since the last main item.
+    usages_pending: bool,
+    depth: u8,
+}
+
+impl<const N: usize> Default for ReportDescriptor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReportDescriptor<N> {
+    /// Create an empty report descriptor.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            usage_page: 0,
+            usages_pending: false,
+            depth: 0,
+        }
+    }
+
+    /// Length of the descriptor written so far.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether nothing was written yet.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the descriptor bytes.
+    ///
+    /// Panics if a collection is still open.
+    pub const fn as_bytes(&self) -> &[u8] {
+        core::assert!(self.depth == 0, "unclosed HID collection");
+        self.buf.split_at(self.len).0
+    }
+
+    /// Append raw bytes, for items not covered by the builder.
+    ///
+    /// The builder does not interpret them: Usage Page items written this way are not tracked.
+    pub const fn raw(mut self, bytes: &[u8]) -> Self {
+        core::assert!(self.len + bytes.len() <= N, "HID report descriptor buffer too small");
+        let mut i = 0;
+        while i < bytes.len() {
+            self.buf[self.len] = bytes[i];
+            self.len += 1;
+            i += 1;
+        }
+        self
+    }
+
+    /// Write an item with unsigned data, in the fewest bytes.
+    const fn item_unsigned(self, prefix: u8, value: u32) -> Self {
+        let b = value.to_le_bytes();
+        if value <= 0xff {
+            self.raw(&[prefix | 1, b[0]])
+        } else if value <= 0xffff {
+            self.raw(&[prefix | 2, b[0], b[1]])
+        } else {
+            self.raw(&[prefix | 3, b[0], b[1], b[2], b[3]])
+        }
+    }
+
+    /// Write an item with signed data, in the fewest bytes.
+    const fn item_signed(self, prefix: u8, value: i32) -> Self {
+        let b = value.to_le_bytes();
+        if value >= i8::MIN as i32 && value <= i8::MAX as i32 {
+            self.raw(&[prefix | 1, b[0]])
+        } else if value >= i16::MIN as i32 && value <= i16::MAX as i32 {
+            self.raw(&[prefix | 2, b[0], b[1]])
+        } else {
+            self.raw(&[prefix | 3, b[0], b[1], b[2], b[3]])
+        }
+    }
+
+    const fn main_item(mut self, prefix: u8, flags: MainItemFlags) -> Self {
+        self.usages_pending = false;
+        self.item_unsigned(prefix, flags.0 as u32)
+    }
+
+    /// Write a local usage item. The usage page is switched if no other usage is pending, otherwise
+    /// the usage is written with its page (an extended usage).
+    const fn local_usage(mut self, prefix: u8, usage: Usage) -> Self {
+        if usage.page.0 != self.usage_page {
+            if self.usages_pending {
+                self.usages_pending = true;
+                return self.item_unsigned(prefix, (usage.page.0 as u32) << 16 | usage.id as u32);
+            }
+            self = self.usage_page(usage.page);
+        }
+        self.usages_pending = true;
+        self.item_unsigned(prefix, usage.id as u32)
+    }
+
+    /// Input item, describing fields of input reports.
+    pub const fn input(self, flags: MainItemFlags) -> Self {
+        self.main_item(MAIN_INPUT, flags)
+    }
+
+    /// Output item, describing fields of output reports.
+    pub const fn output(self, flags: MainItemFlags) -> Self {
+        self.main_item(MAIN_OUTPUT, flags)
+    }
+
+    /// Feature item, describing fields of feature reports.
+    pub const fn feature(self, flags: MainItemFlags) -> Self {
+        self.main_item(MAIN_FEATURE, flags)
+    }
+
+    /// Open a collection, closed by [`end_collection`](Self::end_collection).
+    pub const fn collection(mut self, collection: CollectionType) -> Self {
+        self.usages_pending = false;
+        self.depth += 1;
+        self.raw(&[MAIN_COLLECTION | 1, collection as u8])
+    }
+
+    /// Close the innermost open collection.
+    pub const fn end_collection(mut self) -> Self {
+        core::assert!(self.depth > 0, "no HID collection to close");
+        self.usages_pending = false;
+        self.depth -= 1;
+        self.raw(&[MAIN_END_COLLECTION])
+    }
+
+    /// Usage Page item.
+    ///
+    /// [`usage`](Self::usage) and the other local items write it as needed, so it rarely needs to be
+    /// written explicitly.
+    pub const fn usage_page(mut self, page: UsagePage) -> Self {
+        self.usage_page = page.0;
+        self.item_unsigned(GLOBAL_USAGE_PAGE, page.0 as u32)
+    }
+
+    /// Logical Minimum item, the minimum value of the following fields.
+    pub const fn logical_minimum(self, value: i32) -> Self {
+        self.item_signed(GLOBAL_LOGICAL_MINIMUM, value)
+    }
+
+    /// Logical Maximum item, the maximum value of the following fields.
+    pub const fn logical_maximum(self, value: i32) -> Self {
+        self.item_signed(GLOBAL_LOGICAL_MAXIMUM, value)
+    }
+
+    /// Physical Minimum item, the physical value of the logical minimum.
+    pub const fn physical_minimum(self, value: i32) -> Self {
+        self.item_signed(GLOBAL_PHYSICAL_MINIMUM, value)
+    }
+
+    /// Physical Maximum item, the physical value of the logical maximum.
+    pub const fn physical_maximum(self, value: i32) -> Self {
+        self.item_signed(GLOBAL_PHYSICAL_MAXIMUM, value)
+    }
+
+    /// Unit Exponent item, the base 10 exponent of the physical values.
+    pub const fn unit_exponent(self, exponent: i8) -> Self {
+        self.item_signed(GLOBAL_UNIT_EXPONENT, exponent as i32)
+    }
+
+    /// Unit item, the unit of the physical values as nibbles of system and exponents.
+    pub const fn unit(self, unit: u32) -> Self {
+        self.item_unsigned(GLOBAL_UNIT, unit)
+    }
+
+    /// Report Size item, the size in bits of the following fields.
+    pub const fn report_size(self, bits: u32) -> Self {
+        self.item_unsigned(GLOBAL_REPORT_SIZE, bits)
+    }
+
+    /// Report ID item, prefixing the following fields' reports with `id`.
+    pub const fn report_id(self, id: u8) -> Self {
+        core::assert!(id != 0, "HID report ID 0 is reserved");
+        self.item_unsigned(GLOBAL_REPORT_ID, id as u32)
+    }
+
+    /// Report Count item, the number of the following fields.
+    pub const fn report_count(self, count: u32) -> Self {
+        self.item_unsigned(GLOBAL_REPORT_COUNT, count)
+    }
+
+    /// Usage item, for the next field or collection.
+    pub const fn usage(self, usage: Usage) -> Self {
+        self.local_usage(LOCAL_USAGE, usage)
+    }
+
+    /// Usage Minimum item, the first usage of a range.
+    pub const fn usage_minimum(self, usage: Usage) -> Self {
+        self.local_usage(LOCAL_USAGE_MINIMUM, usage)
+    }
+
+    /// Usage Maximum item, the last usage of a range.
+    pub const fn usage_maximum(self, usage: Usage) -> Self {
+        self.local_usage(LOCAL_USAGE_MAXIMUM, usage)
+    }
+}
@@ -1,8 +1,11 @@
 //! USB HID (Human Interface Device) class implementation.
+//!
+//! Report descriptors can be written with the [`descriptor`] builder, or taken from the built-in
+//! [`report`] types, which also implement the boot protocol of keyboards and mice.
 
 use core::mem::MaybeUninit;
 use core::ops::Range;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
 #[cfg(feature = "usbd-hid")]
 use ssmarshal::serialize;
@@ -14,9 +17,15 @@ use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
 use crate::types::InterfaceNumber;
 use crate::{Builder, Handler};
 
+pub mod descriptor;
+pub mod report;
+pub mod usage;
+
+use report::InputReport;
+
 const USB_CLASS_HID: u8 = 0x03;
 const USB_SUBCLASS_NONE: u8 = 0x00;
-const USB_PROTOCOL_NONE: u8 = 0x00;
+const USB_SUBCLASS_BOOT: u8 = 0x01;
 
 // HID
 const HID_DESC_DESCTYPE_HID: u8 = 0x21;
@@ -48,6 +57,45 @@ pub struct Config<'d> {
 
     /// Max packet size for both the IN and OUT endpoints.
     pub max_packet_size: u16,
+
+    /// Boot protocol supported by the device.
+    ///
+    /// Devices supporting a boot protocol can be used by hosts that don't parse report descriptors,
+    /// such as BIOSes. The host selects the protocol with SET_PROTOCOL, see [`HidWriter::protocol`].
+    pub boot_protocol: HidBootProtocol,
+}
+
+/// Boot protocol supported by a HID interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum HidBootProtocol {
+    /// No boot protocol.
+    None = 0,
+    /// Boot keyboard, sending 8-byte input reports and receiving 1-byte LED output reports.
+    Keyboard = 1,
+    /// Boot mouse, sending 3-byte input reports.
+    Mouse = 2,
+}
+
+/// Protocol selected by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum HidProtocolMode {
+    /// Boot protocol, with the fixed report format of the [`HidBootProtocol`].
+    Boot = 0,
+    /// Report protocol, with the reports described by the report descriptor. This is the default.
+    Report = 1,
+}
+
+impl HidProtocolMode {
+    fn load(protocol: &AtomicU8) -> Self {
+        match protocol.load(Ordering::Relaxed) {
+            0 => HidProtocolMode::Boot,
+            _ => HidProtocolMode::Report,
+        }
+    }
 }
 
 /// Report ID
@@ -77,6 +125,7 @@ impl ReportId {
 pub struct State<'d> {
     control: MaybeUninit<Control<'d>>,
     out_report_offset: AtomicUsize,
+    protocol: AtomicU8,
 }
 
 impl<'d> Default for State<'d> {
@@ -91,6 +140,7 @@ impl<'d> State<'d> {
         State {
             control: MaybeUninit::uninit(),
             out_report_offset: AtomicUsize::new(0),
+            protocol: AtomicU8::new(HidProtocolMode::Report as u8),
         }
     }
 }
@@ -106,13 +156,19 @@ fn build<'d, D: Driver<'d>>(
     state: &'d mut State<'d>,
     config: Config<'d>,
     with_out_endpoint: bool,
-) -> (Option<D::EndpointOut>, D::EndpointIn, &'d AtomicUsize) {
+) -> (Option<D::EndpointOut>, D::EndpointIn, &'d AtomicUsize, &'d AtomicU8) {
     let len = config.report_descriptor.len();
 
-    let mut func = builder.function(USB_CLASS_HID, USB_SUBCLASS_NONE, USB_PROTOCOL_NONE);
+    let subclass = match config.boot_protocol {
+        HidBootProtocol::None => USB_SUBCLASS_NONE,
+        _ => USB_SUBCLASS_BOOT,
+    };
+    let protocol = config.boot_protocol as u8;
+
+    let mut func = builder.function(USB_CLASS_HID, subclass, protocol);
     let mut iface = func.interface();
     let if_num = iface.interface_number();
-    let mut alt = iface.alt_setting(USB_CLASS_HID, USB_SUBCLASS_NONE, USB_PROTOCOL_NONE, None);
+    let mut alt = iface.alt_setting(USB_CLASS_HID, subclass, protocol, None);
 
     // HID descriptor
     alt.descriptor(
@@ -147,10 +203,12 @@ fn build<'d, D: Driver<'d>>(
         config.report_descriptor,
         config.request_handler,
         &state.out_report_offset,
+        config.boot_protocol,
+        &state.protocol,
     ));
     builder.handler(control);
 
-    (ep_out, ep_in, &state.out_report_offset)
+    (ep_out, ep_in, &state.out_report_offset, &state.protocol)
 }
 
 impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWriter<'d, D, READ_N, WRITE_N> {
@@ -160,14 +218,15 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
     /// HID reports, consider using [`HidWriter::new`] instead, which allocates an IN endpoint only.
     ///
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, offset) = build(builder, state, config, true);
+        let (ep_out, ep_in, offset, protocol) = build(builder, state, config, true);
 
         Self {
             reader: HidReader {
                 ep_out: ep_out.unwrap(),
                 offset,
+                protocol,
             },
-            writer: HidWriter { ep_in },
+            writer: HidWriter { ep_in, protocol },
         }
     }
 
@@ -188,11 +247,23 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
         self.writer.write_serialize(r).await
     }
 
+    /// Writes a built-in input report, in the format of the protocol selected by the host.
+    pub async fn write_report<R: InputReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        self.writer.write_report(report).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         self.writer.write(report).await
     }
 
+    /// Gets the protocol selected by the host.
+    ///
+    /// See [`HidWriter::protocol`].
+    pub fn protocol(&self) -> HidProtocolMode {
+        self.writer.protocol()
+    }
+
     /// Reads an output report from the Interrupt Out pipe.
     ///
     /// See [`HidReader::read`].
@@ -206,6 +277,7 @@ impl<'d, D: Driver<'d>, const READ_N: usize, const WRITE_N: usize> HidReaderWrit
 /// You can obtain a `HidWriter` using [`HidReaderWriter::split`].
 pub struct HidWriter<'d, D: Driver<'d>, const N: usize> {
     ep_in: D::EndpointIn,
+    protocol: &'d AtomicU8,
 }
 
 /// USB HID reader.
@@ -214,6 +286,7 @@ pub struct HidWriter<'d, D: Driver<'d>, const N: usize> {
 pub struct HidReader<'d, D: Driver<'d>, const N: usize> {
     ep_out: D::EndpointOut,
     offset: &'d AtomicUsize,
+    protocol: &'d AtomicU8,
 }
 
 /// Error when reading a HID report.
@@ -249,11 +322,19 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
     /// of CPU on the device & bandwidth on the bus. A value of 10 is reasonable for
     /// high performance uses, and a value of 255 is good for best-effort usecases.
     pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
-        let (ep_out, ep_in, _offset) = build(builder, state, config, false);
+        let (ep_out, ep_in, _offset, protocol) = build(builder, state, config, false);
 
         assert!(ep_out.is_none());
 
-        Self { ep_in }
+        Self { ep_in, protocol }
+    }
+
+    /// Gets the protocol selected by the host.
+    ///
+    /// This is [`HidProtocolMode::Report`] unless the device supports a boot protocol, and the host
+    /// selected it. In boot protocol, input reports must have the boot format.
+    pub fn protocol(&self) -> HidProtocolMode {
+        HidProtocolMode::load(self.protocol)
     }
 
     /// Waits for the interrupt in endpoint to be enabled.
@@ -271,6 +352,13 @@ impl<'d, D: Driver<'d>, const N: usize> HidWriter<'d, D, N> {
         self.write(&buf[0..size]).await
     }
 
+    /// Writes a built-in input report, in the format of the protocol selected by the host.
+    pub async fn write_report<R: InputReport>(&mut self, report: &R) -> Result<(), EndpointError> {
+        let mut buf: [u8; N] = [0; N];
+        let size = report.serialize(self.protocol(), &mut buf);
+        self.write(&buf[0..size]).await
+    }
+
     /// Writes `report` to its interrupt endpoint.
     pub async fn write(&mut self, report: &[u8]) -> Result<(), EndpointError> {
         assert!(report.len() <= N);
@@ -295,6 +383,13 @@ impl<'d, D: Driver<'d>, const N: usize> HidReader<'d, D, N> {
         self.ep_out.wait_enabled().await;
     }
 
+    /// Gets the protocol selected by the host.
+    ///
+    /// See [`HidWriter::protocol`].
+    pub fn protocol(&self) -> HidProtocolMode {
+        HidProtocolMode::load(self.protocol)
+    }
+
     /// Delivers output reports from the Interrupt Out pipe to `handler`.
     ///
     /// If `use_report_ids` is true, the first byte of the report will be used as
@@ -413,6 +508,8 @@ struct Control<'d> {
     report_descriptor: &'d [u8],
     request_handler: Option<&'d mut dyn RequestHandler>,
     out_report_offset: &'d AtomicUsize,
+    boot_protocol: HidBootProtocol,
+    protocol: &'d AtomicU8,
     hid_descriptor: [u8; 9],
 }
 
@@ -422,12 +519,16 @@ impl<'d> Control<'d> {
         report_descriptor: &'d [u8],
         request_handler: Option<&'d mut dyn RequestHandler>,
         out_report_offset: &'d AtomicUsize,
+        boot_protocol: HidBootProtocol,
+        protocol: &'d AtomicU8,
     ) -> Self {
         Control {
             if_num,
             report_descriptor,
             request_handler,
             out_report_offset,
+            boot_protocol,
+            protocol,
             hid_descriptor: [
                 // Length of buf inclusive of size prefix
                 9,
@@ -453,6 +554,7 @@ impl<'d> Control<'d> {
 impl<'d> Handler for Control<'d> {
     fn reset(&mut self) {
         self.out_report_offset.store(0, Ordering::Release);
+        self.protocol.store(HidProtocolMode::Report as u8, Ordering::Relaxed);
     }
 
     fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
@@ -481,14 +583,17 @@ impl<'d> Handler for Control<'d> {
                 (Ok(id), Some(handler)) => Some(handler.set_report(id, data)),
                 _ => Some(OutResponse::Rejected),
             },
-            HID_REQ_SET_PROTOCOL => {
-                if req.value == 1 {
-                    Some(OutResponse::Accepted)
-                } else {
+            HID_REQ_SET_PROTOCOL => match req.value {
+                0 if self.boot_protocol == HidBootProtocol::None => {
                     warn!("HID Boot Protocol is unsupported.");
-                    Some(OutResponse::Rejected) // UNSUPPORTED: Boot Protocol
+                    Some(OutResponse::Rejected)
                 }
-            }
+                0 | 1 => {
+                    self.protocol.store(req.value as u8, Ordering::Relaxed);
+                    Some(OutResponse::Accepted)
+                }
+                _ => Some(OutResponse::Rejected),
+            },
             _ => Some(OutResponse::Rejected),
         }
     }
@@ -539,8 +644,7 @@ impl<'d> Handler for Control<'d> {
                         }
                     }
                     HID_REQ_GET_PROTOCOL => {
-                        // UNSUPPORTED: Boot Protocol
-                        buf[0] = 1;
+                        buf[0] = self.protocol.load(Ordering::Relaxed);
                         Some(InResponse::Accepted(&buf[0..1]))
                     }
                     _ => Some(InResponse::Rejected),
@@ -0,0 +1,410 @@
+//! Built-in report types for common devices.
+//!
+//! Each report type comes with its report descriptor, and serializes to both the report protocol
+//! and, for keyboards and mice, the boot protocol. Use it with [`HidWriter::write_report`], which
+//! picks the protocol selected by the host:
+//!
+//! ```ignore
+//! let config = hid::Config {
+//!     report_descriptor: KeyboardReport::descriptor(),
+//!     request_handler: None,
+//!     poll_ms: 10,
+//!     max_packet_size: 8,
+//!     boot_protocol: KeyboardReport::BOOT_PROTOCOL,
+//! };
+//! let mut writer = HidWriter::<_, 8>::new(&mut builder, &mut state, config);
+//! // ...
+//! writer.write_report(&KeyboardReport::new(KeyboardModifiers::NONE, &[keyboard::A])).await?;
+//! ```
+//!
+//! [`HidWriter::write_report`]: super::HidWriter::write_report
+
+use core::ops::BitOr;
+
+use super::descriptor::{CollectionType, MainItemFlags, ReportDescriptor, Usage, UsagePage};
+use super::usage::{self, consumer, generic_desktop, keyboard, led};
+use super::{HidBootProtocol, HidProtocolMode};
+
+/// An input report with a built-in report descriptor.
+pub trait InputReport {
+    /// Boot protocol the report supports, for [`Config::boot_protocol`](super::Config::boot_protocol).
+    const BOOT_PROTOCOL: HidBootProtocol;
+
+    /// Report descriptor, for [`Config::report_descriptor`](super::Config::report_descriptor).
+    fn descriptor() -> &'static [u8];
+
+    /// Serialize the report in the format of `protocol` to `buf`, returning its length.
+    ///
+    /// Panics if `buf` is too small.
+    fn serialize(&self, protocol: HidProtocolMode, buf: &mut [u8]) -> usize;
+}
+
+/// Copy a descriptor into an array of its exact size.
+const fn exact<const N: usize, const M: usize>(desc: &ReportDescriptor<N>) -> [u8; M] {
+    let bytes = desc.as_bytes();
+    core::assert!(bytes.len() == M);
+    let mut out = [0; M];
+    let mut i = 0;
+    while i < M {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+/// Modifier keys of a [`KeyboardReport`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardModifiers(pub u8);
+
+impl KeyboardModifiers {
+    /// No modifier.
+    pub const NONE: Self = Self(0);
+    /// Left Control.
+    pub const LEFT_CONTROL: Self = Self(1 << 0);
+    /// Left Shift.
+    pub const LEFT_SHIFT: Self = Self(1 << 1);
+    /// Left Alt.
+    pub const LEFT_ALT: Self = Self(1 << 2);
+    /// Left GUI.
+    pub const LEFT_GUI: Self = Self(1 << 3);
+    /// Right Control.
+    pub const RIGHT_CONTROL: Self = Self(1 << 4);
+    /// Right Shift.
+    pub const RIGHT_SHIFT: Self = Self(1 << 5);
+    /// Right Alt.
+    pub const RIGHT_ALT: Self = Self(1 << 6);
+    /// Right GUI.
+    pub const RIGHT_GUI: Self = Self(1 << 7);
+}
+
+impl BitOr for KeyboardModifiers {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Keyboard input report, with modifiers and up to 6 keys pressed at once.
+///
+/// The report has the boot protocol layout in both protocols. The keyboard's LEDs are set by output
+/// reports, parsed with [`KeyboardLeds::from_report`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardReport {
+    /// Modifier keys pressed.
+    pub modifiers: KeyboardModifiers,
+    /// Keycodes of the other keys pressed, from the [`keyboard`](usage::keyboard) usage page. Unused
+    /// entries are 0.
+    pub keycodes: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// Create a report with `modifiers` and `keys` pressed.
+    ///
+    /// If more than 6 keys are pressed, the report signals an error roll over, as required by the HID
+    /// specification. Keys that are modifiers set the corresponding bit of the modifiers instead.
+    pub fn new(mut modifiers: KeyboardModifiers, keys: &[Usage]) -> Self {
+        let mut keycodes = [0; 6];
+        let mut n = 0;
+        let mut roll_over = false;
+        for key in keys {
+            debug_assert!(key.page == UsagePage::KEYBOARD);
+            match key.id {
+                0xe0..=0xe7 => modifiers.0 |= 1 << (key.id - 0xe0),
+                id if n < keycodes.len() => {
+                    keycodes[n] = id as u8;
+                    n += 1;
+                }
+                _ => roll_over = true,
+            }
+        }
+        if roll_over {
+            keycodes = [keyboard::ERROR_ROLL_OVER.id as u8; 6];
+        }
+        Self { modifiers, keycodes }
+    }
+}
+
+const KEYBOARD_BUILDER: ReportDescriptor<128> = ReportDescriptor::new()
+    .usage(generic_desktop::KEYBOARD)
+    .collection(CollectionType::Application)
+    // Modifiers
+    .usage_minimum(keyboard::LEFT_CONTROL)
+    .usage_maximum(keyboard::RIGHT_GUI)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(8)
+    .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+    // Reserved
+    .report_size(8)
+    .report_count(1)
+    .input(MainItemFlags::CONSTANT)
+    // LEDs
+    .usage_minimum(led::NUM_LOCK)
+    .usage_maximum(led::KANA)
+    .report_size(1)
+    .report_count(5)
+    .output(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+    .report_size(3)
+    .report_count(1)
+    .output(MainItemFlags::CONSTANT)
+    // Keycodes
+    .usage_minimum(keyboard::NO_EVENT)
+    .usage_maximum(Usage::new(UsagePage::KEYBOARD, 0xff))
+    .logical_minimum(0)
+    .logical_maximum(0xff)
+    .report_size(8)
+    .report_count(6)
+    .input(MainItemFlags::DATA_ARRAY_ABSOLUTE)
+    .end_collection();
+static KEYBOARD_DESCRIPTOR: [u8; KEYBOARD_BUILDER.len()] = exact(&KEYBOARD_BUILDER);
+
+impl InputReport for KeyboardReport {
+    const BOOT_PROTOCOL: HidBootProtocol = HidBootProtocol::Keyboard;
+
+    fn descriptor() -> &'static [u8] {
+        &KEYBOARD_DESCRIPTOR
+    }
+
+    fn serialize(&self, _protocol: HidProtocolMode, buf: &mut [u8]) -> usize {
+        buf[0] = self.modifiers.0;
+        buf[1] = 0;
+        buf[2..8].copy_from_slice(&self.keycodes);
+        8
+    }
+}
+
+/// Keyboard LED state, set by the host in output reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyboardLeds(pub u8);
+
+impl KeyboardLeds {
+    /// Parse the output report of a [`KeyboardReport`] keyboard.
+    ///
+    /// Returns `None` if the report is empty.
+    pub fn from_report(report: &[u8]) -> Option<Self> {
+        report.first().map(|&leds| Self(leds))
+    }
+
+    /// Whether the Num Lock LED is on.
+    pub const fn num_lock(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// Whether the Caps Lock LED is on.
+    pub const fn caps_lock(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// Whether the Scroll Lock LED is on.
+    pub const fn scroll_lock(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// Whether the Compose LED is on.
+    pub const fn compose(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// Whether the Kana LED is on.
+    pub const fn kana(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+}
+
+/// Mouse input report, with 5 buttons, movement, a wheel and horizontal scrolling.
+///
+/// In boot protocol, only the first 3 buttons and the movement are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    /// Buttons pressed, button 1 (primary) in bit 0.
+    pub buttons: u8,
+    /// Horizontal movement, positive to the right.
+    pub x: i8,
+    /// Vertical movement, positive downwards.
+    pub y: i8,
+    /// Wheel movement, positive away from the user.
+    pub wheel: i8,
+    /// Horizontal scrolling, positive to the right.
+    pub pan: i8,
+}
+
+const MOUSE_BUILDER: ReportDescriptor<128> = ReportDescriptor::new()
+    .usage(generic_desktop::MOUSE)
+    .collection(CollectionType::Application)
+    .usage(generic_desktop::POINTER)
+    .collection(CollectionType::Physical)
+    // Buttons
+    .usage_minimum(usage::button(1))
+    .usage_maximum(usage::button(5))
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(5)
+    .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+    .report_size(3)
+    .report_count(1)
+    .input(MainItemFlags::CONSTANT)
+    // Movement and wheel
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::WHEEL)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(3)
+    .input(MainItemFlags::DATA_VARIABLE_RELATIVE)
+    // Horizontal scrolling
+    .usage(consumer::AC_PAN)
+    .report_count(1)
+    .input(MainItemFlags::DATA_VARIABLE_RELATIVE)
+    .end_collection()
+    .end_collection();
+static MOUSE_DESCRIPTOR: [u8; MOUSE_BUILDER.len()] = exact(&MOUSE_BUILDER);
+
+impl InputReport for MouseReport {
+    const BOOT_PROTOCOL: HidBootProtocol = HidBootProtocol::Mouse;
+
+    fn descriptor() -> &'static [u8] {
+        &MOUSE_DESCRIPTOR
+    }
+
+    fn serialize(&self, protocol: HidProtocolMode, buf: &mut [u8]) -> usize {
+        match protocol {
+            HidProtocolMode::Boot => {
+                buf[..3].copy_from_slice(&[self.buttons & 0x07, self.x as u8, self.y as u8]);
+                3
+            }
+            HidProtocolMode::Report => {
+                buf[..5].copy_from_slice(&[
+                    self.buttons & 0x1f,
+                    self.x as u8,
+                    self.y as u8,
+                    self.wheel as u8,
+                    self.pan as u8,
+                ]);
+                5
+            }
+        }
+    }
+}
+
+/// Gamepad input report, with 16 buttons, two sticks and a hat switch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadReport {
+    /// Buttons pressed, button 1 in bit 0.
+    pub buttons: u16,
+    /// Left stick, horizontal axis.
+    pub x: i8,
+    /// Left stick, vertical axis.
+    pub y: i8,
+    /// Right stick, horizontal axis.
+    pub z: i8,
+    /// Right stick, vertical axis.
+    pub rz: i8,
+    /// Hat switch direction, 0 for up and clockwise in steps of 45 degrees, or `None` if centered.
+    pub hat: Option<u8>,
+}
+
+const GAMEPAD_BUILDER: ReportDescriptor<128> = ReportDescriptor::new()
+    .usage(generic_desktop::GAMEPAD)
+    .collection(CollectionType::Application)
+    // Buttons
+    .usage_minimum(usage::button(1))
+    .usage_maximum(usage::button(16))
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(16)
+    .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+    // Sticks
+    .usage(generic_desktop::X)
+    .usage(generic_desktop::Y)
+    .usage(generic_desktop::Z)
+    .usage(generic_desktop::RZ)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(4)
+    .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE)
+    // Hat switch, in degrees
+    .usage(generic_desktop::HAT_SWITCH)
+    .logical_minimum(0)
+    .logical_maximum(7)
+    .physical_minimum(0)
+    .physical_maximum(315)
+    .unit(0x14)
+    .report_size(4)
+    .report_count(1)
+    .input(MainItemFlags::DATA_VARIABLE_ABSOLUTE.union(MainItemFlags::NULL_STATE))
+    .unit(0)
+    .input(MainItemFlags::CONSTANT)
+    .end_collection();
+static GAMEPAD_DESCRIPTOR: [u8; GAMEPAD_BUILDER.len()] = exact(&GAMEPAD_BUILDER);
+
+impl InputReport for GamepadReport {
+    const BOOT_PROTOCOL: HidBootProtocol = HidBootProtocol::None;
+
+    fn descriptor() -> &'static [u8] {
+        &GAMEPAD_DESCRIPTOR
+    }
+
+    fn serialize(&self, _protocol: HidProtocolMode, buf: &mut [u8]) -> usize {
+        let hat = match self.hat {
+            Some(hat) if hat < 8 => hat,
+            _ => 0x0f,
+        };
+        let [b0, b1] = self.buttons.to_le_bytes();
+        buf[..7].copy_from_slice(&[b0, b1, self.x as u8, self.y as u8, self.z as u8, self.rz as u8, hat]);
+        7
+    }
+}
+
+/// Consumer control input report, with one media or application control active at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConsumerControlReport {
+    /// Usage ID of the active control, from the [`consumer`](usage::consumer) usage page, or 0 if
+    /// none is active.
+    pub usage_id: u16,
+}
+
+impl ConsumerControlReport {
+    /// Create a report with the `usage` control active.
+    pub const fn new(usage: Usage) -> Self {
+        core::assert!(usage.page.0 == UsagePage::CONSUMER.0);
+        Self { usage_id: usage.id }
+    }
+}
+
+const CONSUMER_CONTROL_BUILDER: ReportDescriptor<32> = ReportDescriptor::new()
+    .usage(consumer::CONSUMER_CONTROL)
+    .collection(CollectionType::Application)
+    .usage_minimum(Usage::new(UsagePage::CONSUMER, 0))
+    .usage_maximum(Usage::new(UsagePage::CONSUMER, 0x3ff))
+    .logical_minimum(0)
+    .logical_maximum(0x3ff)
+    .report_size(16)
+    .report_count(1)
+    .input(MainItemFlags::DATA_ARRAY_ABSOLUTE)
+    .end_collection();
+static CONSUMER_CONTROL_DESCRIPTOR: [u8; CONSUMER_CONTROL_BUILDER.len()] = exact(&CONSUMER_CONTROL_BUILDER);
+
+impl InputReport for ConsumerControlReport {
+    const BOOT_PROTOCOL: HidBootProtocol = HidBootProtocol::None;
+
+    fn descriptor() -> &'static [u8] {
+        &CONSUMER_CONTROL_DESCRIPTOR
+    }
+
+    fn serialize(&self, _protocol: HidProtocolMode, buf: &mut [u8]) -> usize {
+        buf[..2].copy_from_slice(&self.usage_id.to_le_bytes());
+        2
+    }
+}
@@ -0,0 +1,269 @@
+//! Typed HID usages, from the HID Usage Tables.
+//!
+//! Only the most common usages are listed. Others can be created with [`Usage::new`].
+
+use super::descriptor::{Usage, UsagePage};
+
+macro_rules! usages {
+    ($page:expr; $($(#[$attr:meta])* $name:ident = $id:expr;)*) => {
+        $(
+            $(#[$attr])*
+            pub const $name: Usage = Usage::new($page, $id);
+        )*
+    };
+}
+
+/// Button `n` of the Button page. Button 0 means no button pressed.
+pub const fn button(n: u16) -> Usage {
+    Usage::new(UsagePage::BUTTON, n)
+}
+
+/// Generic Desktop page.
+pub mod generic_desktop {
+    use super::*;
+
+    usages! { UsagePage::GENERIC_DESKTOP;
+        /// Pointer collection.
+        POINTER = 0x01;
+        /// Mouse application collection.
+        MOUSE = 0x02;
+        /// Joystick application collection.
+        JOYSTICK = 0x04;
+        /// Gamepad application collection.
+        GAMEPAD = 0x05;
+        /// Keyboard application collection.
+        KEYBOARD = 0x06;
+        /// Keypad application collection.
+        KEYPAD = 0x07;
+        /// Multi-axis controller application collection.
+        MULTI_AXIS_CONTROLLER = 0x08;
+        /// X axis.
+        X = 0x30;
+        /// Y axis.
+        Y = 0x31;
+        /// Z axis.
+        Z = 0x32;
+        /// Rotation around the X axis.
+        RX = 0x33;
+        /// Rotation around the Y axis.
+        RY = 0x34;
+        /// Rotation around the Z axis.
+        RZ = 0x35;
+        /// Slider.
+        SLIDER = 0x36;
+        /// Dial.
+        DIAL = 0x37;
+        /// Wheel.
+        WHEEL = 0x38;
+        /// Hat switch.
+        HAT_SWITCH = 0x39;
+        /// System control application collection.
+        SYSTEM_CONTROL = 0x80;
+        /// System power down.
+        SYSTEM_POWER_DOWN = 0x81;
+        /// System sleep.
+        SYSTEM_SLEEP = 0x82;
+        /// System wake up.
+        SYSTEM_WAKE_UP = 0x83;
+    }
+}
+
+/// Keyboard/Keypad page.
+///
+/// The usage IDs are the keycodes of keyboard reports.
+pub mod keyboard {
+    use super::*;
+
+    usages! { UsagePage::KEYBOARD;
+        /// No key pressed.
+        NO_EVENT = 0x00;
+        /// Too many keys pressed.
+        ERROR_ROLL_OVER = 0x01;
+        /// a and A.
+        A = 0x04;
+        /// b and B.
+        B = 0x05;
+        /// c and C.
+        C = 0x06;
+        /// d and D.
+        D = 0x07;
+        /// e and E.
+        E = 0x08;
+        /// f and F.
+        F = 0x09;
+        /// g and G.
+        G = 0x0a;
+        /// h and H.
+        H = 0x0b;
+        /// i and I.
+        I = 0x0c;
+        /// j and J.
+        J = 0x0d;
+        /// k and K.
+        K = 0x0e;
+        /// l and L.
+        L = 0x0f;
+        /// m and M.
+        M = 0x10;
+        /// n and N.
+        N = 0x11;
+        /// o and O.
+        O = 0x12;
+        /// p and P.
+        P = 0x13;
+        /// q and Q.
+        Q = 0x14;
+        /// r and R.
+        R = 0x15;
+        /// s and S.
+        S = 0x16;
+        /// t and T.
+        T = 0x17;
+        /// u and U.
+        U = 0x18;
+        /// v and V.
+        V = 0x19;
+        /// w and W.
+        W = 0x1a;
+        /// x and X.
+        X = 0x1b;
+        /// y and Y.
+        Y = 0x1c;
+        /// z and Z.
+        Z = 0x1d;
+        /// 1 and !.
+        KEY_1 = 0x1e;
+        /// 2 and @.
+        KEY_2 = 0x1f;
+        /// 3 and #.
+        KEY_3 = 0x20;
+        /// 4 and $.
+        KEY_4 = 0x21;
+        /// 5 and %.
+        KEY_5 = 0x22;
+        /// 6 and ^.
+        KEY_6 = 0x23;
+        /// 7 and &.
+        KEY_7 = 0x24;
+        /// 8 and *.
+        KEY_8 = 0x25;
+        /// 9 and (.
+        KEY_9 = 0x26;
+        /// 0 and ).
+        KEY_0 = 0x27;
+        /// Enter.
+        ENTER = 0x28;
+        /// Escape.
+        ESCAPE = 0x29;
+        /// Backspace.
+        BACKSPACE = 0x2a;
+        /// Tab.
+        TAB = 0x2b;
+        /// Spacebar.
+        SPACE = 0x2c;
+        /// Caps Lock.
+        CAPS_LOCK = 0x39;
+        /// F1.
+        F1 = 0x3a;
+        /// F2.
+        F2 = 0x3b;
+        /// F3.
+        F3 = 0x3c;
+        /// F4.
+        F4 = 0x3d;
+        /// F5.
+        F5 = 0x3e;
+        /// F6.
+        F6 = 0x3f;
+        /// F7.
+        F7 = 0x40;
+        /// F8.
+        F8 = 0x41;
+        /// F9.
+        F9 = 0x42;
+        /// F10.
+        F10 = 0x43;
+        /// F11.
+        F11 = 0x44;
+        /// F12.
+        F12 = 0x45;
+        /// Right arrow.
+        RIGHT_ARROW = 0x4f;
+        /// Left arrow.
+        LEFT_ARROW = 0x50;
+        /// Down arrow.
+        DOWN_ARROW = 0x51;
+        /// Up arrow.
+        UP_ARROW = 0x52;
+        /// Left Control.
+        LEFT_CONTROL = 0xe0;
+        /// Left Shift.
+        LEFT_SHIFT = 0xe1;
+        /// Left Alt.
+        LEFT_ALT = 0xe2;
+        /// Left GUI.
+        LEFT_GUI = 0xe3;
+        /// Right Control.
+        RIGHT_CONTROL = 0xe4;
+        /// Right Shift.
+        RIGHT_SHIFT = 0xe5;
+        /// Right Alt.
+        RIGHT_ALT = 0xe6;
+        /// Right GUI.
+        RIGHT_GUI = 0xe7;
+    }
+}
+
+/// LED page.
+pub mod led {
+    use super::*;
+
+    usages! { UsagePage::LED;
+        /// Num Lock.
+        NUM_LOCK = 0x01;
+        /// Caps Lock.
+        CAPS_LOCK = 0x02;
+        /// Scroll Lock.
+        SCROLL_LOCK = 0x03;
+        /// Compose.
+        COMPOSE = 0x04;
+        /// Kana.
+        KANA = 0x05;
+    }
+}
+
+/// Consumer page.
+pub mod consumer {
+    use super::*;
+
+    usages! { UsagePage::CONSUMER;
+        /// Consumer control application collection.
+        CONSUMER_CONTROL = 0x01;
+        /// Next track.
+        SCAN_NEXT_TRACK = 0xb5;
+        /// Previous track.
+        SCAN_PREVIOUS_TRACK = 0xb6;
+        /// Stop.
+        STOP = 0xb7;
+        /// Eject.
+        EJECT = 0xb8;
+        /// Play/pause.
+        PLAY_PAUSE = 0xcd;
+        /// Mute.
+        MUTE = 0xe2;
+        /// Volume up.
+        VOLUME_INCREMENT = 0xe9;
+        /// Volume down.
+        VOLUME_DECREMENT = 0xea;
+        /// Launch the calculator.
+        AL_CALCULATOR = 0x192;
+        /// Browser home.
+        AC_HOME = 0x223;
+        /// Browser back.
+        AC_BACK = 0x224;
+        /// Browser forward.
+        AC_FORWARD = 0x225;
+        /// Horizontal scrolling.
+        AC_PAN = 0x238;
+    }
+}
@@ -1,6 +1,13 @@
 //! Implementations of well-known USB classes.
+pub mod ccid;
 pub mod cdc_acm;
+pub mod cdc_ecm;
 pub mod cdc_ncm;
 pub mod hid;
 pub mod midi;
+pub mod msc;
+pub mod printer;
+pub mod rndis;
+pub mod uac2;
+pub mod uvc;
 pub mod web_usb;
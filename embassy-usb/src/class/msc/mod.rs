@@ -0,0 +1,613 @@
+//! Mass storage class implementation, aka USB drive.
+//!
+//! This implements the Bulk-Only Transport, with the SCSI command set. Each logical unit of the
+//! device is backed by a [`BlockDevice`], such as an SD card or a flash partition.
+//!
+//! Errors are reported by stalling the bulk endpoints, as the Bulk-Only Transport requires: when the
+//! device has less data to send than the host asked for, or doesn't expect the data the host sends,
+//! it stalls the corresponding endpoint and reports the difference in the command status. After an
+//! invalid command block, both endpoints stay stalled until the host does a Reset Recovery.
+//!
+//! With drivers that can't stall endpoints (see [`Endpoint::stall`]), transfers to the host end with
+//! a short packet instead, unexpected data is discarded, and invalid command blocks are ignored.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use self::scsi::{Phase, Sense};
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, Unsupported};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+mod scsi;
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_MSC: u8 = 0x08;
+
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+
+const REQ_GET_MAX_LUN: u8 = 0xfe;
+const REQ_BULK_ONLY_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+
+/// Maximum number of logical units.
+pub const MAX_LUNS: usize = 16;
+
+/// Error returned by a [`BlockDevice`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The medium is not present.
+    NotPresent,
+    /// The medium is write-protected.
+    WriteProtected,
+    /// The blocks couldn't be read or written.
+    Io,
+}
+
+/// A block device, exposed to the host as a logical unit.
+///
+/// To expose block devices of different types, wrap them in an enum implementing this trait.
+pub trait BlockDevice {
+    /// Size of a block in bytes, usually 512.
+    ///
+    /// It must be a multiple of the max packet size of the endpoints.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks of the device.
+    fn block_count(&self) -> u32;
+
+    /// Whether the medium is present.
+    ///
+    /// Returning `false` reports an empty drive, for example when an SD card is removed from its slot.
+    fn is_present(&self) -> bool {
+        true
+    }
+
+    /// Whether the medium is write-protected.
+    ///
+    /// When it is, the host mounts the drive read-only, and writes are rejected.
+    fn is_write_protected(&self) -> bool {
+        false
+    }
+
+    /// Read blocks starting at `lba`. The length of `buf` is a multiple of the block size.
+    async fn read(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Write blocks starting at `lba`. The length of `buf` is a multiple of the block size.
+    async fn write(&mut self, lba: u32, buf: &[u8]) -> Result<(), Error>;
+
+    /// Write cached data to the medium.
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Configuration for the mass storage class.
+pub struct Config<'a> {
+    /// Vendor identification reported to the host, up to 8 ASCII characters.
+    pub vendor: &'a str,
+
+    /// Product identification reported to the host, up to 16 ASCII characters.
+    pub product: &'a str,
+
+    /// Product revision reported to the host, up to 4 ASCII characters.
+    pub revision: &'a str,
+
+    /// Whether the media are removable, like SD cards. Hosts then check regularly whether one is present.
+    pub removable: bool,
+
+    /// Number of logical units, from 1 to 16.
+    pub lun_count: u8,
+
+    /// Max packet size for both the IN and OUT endpoints.
+    ///
+    /// This is 64 for full speed devices, and 512 for high speed ones.
+    pub max_packet_size: u16,
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self {
+            vendor: "Embassy",
+            product: "Mass storage",
+            revision: "0.1",
+            removable: false,
+            lun_count: 1,
+            max_packet_size: 64,
+        }
+    }
+}
+
+/// Identification of the logical units, reported by INQUIRY.
+struct Identification {
+    /// Vendor, product and revision, padded with spaces.
+    strings: [u8; 28],
+    removable: bool,
+}
+
+impl Identification {
+    fn new(config: &Config<'_>) -> Self {
+        let mut strings = [b' '; 28];
+        for (field, s) in [
+            (0..8, config.vendor),
+            (8..24, config.product),
+            (24..28, config.revision),
+        ] {
+            let len = s.len().min(field.len());
+            strings[field.start..field.start + len].copy_from_slice(&s.as_bytes()[..len]);
+        }
+        Self {
+            strings,
+            removable: config.removable,
+        }
+    }
+}
+
+/// Internal state for the mass storage class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                reset: Cell::new(false),
+                waker: RefCell::new(WakerRegistration::new()),
+            },
+        }
+    }
+}
+
+/// Shared data between Control and MscClass
+struct ControlShared {
+    reset: Cell<bool>,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    fn signal_reset(&self) {
+        self.reset.set(true);
+        self.waker.borrow_mut().wake();
+    }
+
+    async fn wait_reset(&self) {
+        poll_fn(|cx| {
+            if self.reset.replace(false) {
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    max_lun: u8,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.signal_reset();
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_BULK_ONLY_RESET => {
+                debug!("msc: bulk-only reset");
+                self.shared.signal_reset();
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.if_num.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_MAX_LUN => {
+                buf[0] = self.max_lun;
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Command block wrapper, sent by the host to start a command.
+struct Cbw {
+    tag: u32,
+    data_len: u32,
+    data_in: bool,
+    lun: u8,
+    cb: [u8; 16],
+}
+
+impl Cbw {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() != CBW_LEN || u32::from_le_bytes(buf[0..4].try_into().unwrap()) != CBW_SIGNATURE {
+            return None;
+        }
+        let cb_len = buf[14] as usize;
+        if buf[13] >= MAX_LUNS as u8 || !(1..=16).contains(&cb_len) {
+            return None;
+        }
+        let mut cb = [0; 16];
+        cb[..cb_len].copy_from_slice(&buf[15..15 + cb_len]);
+        Some(Self {
+            tag: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            data_len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            data_in: buf[12] & 0x80 != 0,
+            lun: buf[13],
+            cb,
+        })
+    }
+}
+
+/// Status of a command, reported in the command status wrapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum Status {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// USB mass storage device.
+pub struct MscClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    id: Identification,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> MscClass<'d, D> {
+    /// Creates a new `MscClass`.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'_>) -> Self {
+        assert!(
+            (1..=MAX_LUNS).contains(&(config.lun_count as usize)),
+            "msc: there must be 1 to 16 logical units"
+        );
+
+        let mut func = builder.function(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_MSC, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BOT, None);
+        let read_ep = alt.endpoint_bulk_out(config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(config.max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            max_lun: config.lun_count - 1,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        Self {
+            read_ep,
+            write_ep,
+            id: Identification::new(&config),
+            shared: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Serve the host's commands, with `luns` as logical units.
+    ///
+    /// `luns` should have as many elements as `lun_count` in the [`Config`]; commands to other
+    /// logical units fail. `buf` is used to transfer blocks, and must be able to hold at least one.
+    /// Bigger buffers allow transferring several blocks at once, which is faster.
+    pub async fn run<B: BlockDevice>(&mut self, luns: &mut [B], buf: &mut [u8]) -> ! {
+        let mps = self.max_packet_size() as usize;
+        for device in luns.iter() {
+            let block_size = device.block_size();
+            assert!(
+                block_size % mps == 0,
+                "msc: block size must be a multiple of the max packet size"
+            );
+            assert!(buf.len() >= block_size, "msc: buffer too small for a block");
+        }
+        assert!(buf.len() >= 64, "msc: buffer too small");
+
+        let shared = self.shared;
+        let mut senses = [Sense::NONE; MAX_LUNS];
+        loop {
+            self.read_ep.wait_enabled().await;
+            match select(self.command(luns, &mut senses, buf), shared.wait_reset()).await {
+                Either::First(Ok(())) => {}
+                Either::First(Err(EndpointError::Disabled)) => debug!("msc: disabled"),
+                Either::First(Err(EndpointError::BufferOverflow)) => warn!("msc: unexpected packet size"),
+                Either::Second(()) => {
+                    // Whatever the host sent for the interrupted command is lost, start again from
+                    // the next command block.
+                }
+            }
+        }
+    }
+
+    /// Receive a command block, execute it, and send its status.
+    async fn command<B: BlockDevice>(
+        &mut self,
+        luns: &mut [B],
+        senses: &mut [Sense; MAX_LUNS],
+        buf: &mut [u8],
+    ) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        let n = self.read_ep.read(&mut buf[..mps]).await?;
+        let Some(cbw) = Cbw::parse(&buf[..n]) else {
+            warn!("msc: invalid command block wrapper");
+            // Without stalls, the command block is ignored.
+            let _ = self.stall_until_reset().await;
+            return Ok(());
+        };
+        trace!("msc: command {:02x} on lun {}", cbw.cb[0], cbw.lun);
+
+        let lun = cbw.lun as usize;
+        let sense = &mut senses[lun];
+        let (result, device) = match luns.get_mut(lun) {
+            Some(device) => (scsi::execute(&cbw.cb, device, sense, &self.id, buf).await, Some(device)),
+            None => (scsi::execute_unsupported(&cbw.cb, &self.id, buf), None),
+        };
+
+        let (status, residue) = match (result, device) {
+            (Ok(Phase::In(len)), _) => self.data_in(&cbw, len, buf).await?,
+            (Ok(Phase::Read { lba, blocks }), Some(device)) => self.read(&cbw, device, lba, blocks, sense, buf).await?,
+            (Ok(Phase::Write { lba, blocks }), Some(device)) => {
+                self.write(&cbw, device, lba, blocks, sense, buf).await?
+            }
+            (Ok(_), _) => self.no_data(&cbw, Status::Passed, buf).await?,
+            (Err(e), _) => {
+                debug!("msc: command {:02x} failed: {:?}", cbw.cb[0], e);
+                *sense = e;
+                self.no_data(&cbw, Status::Failed, buf).await?
+            }
+        };
+
+        let mut csw = [0; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&cbw.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status as u8;
+        self.write_ep.write(&csw).await
+    }
+
+    /// Data phase of a command without data.
+    async fn no_data(&mut self, cbw: &Cbw, status: Status, buf: &mut [u8]) -> Result<(Status, u32), EndpointError> {
+        if cbw.data_in {
+            self.end_in(0, cbw.data_len).await?;
+        } else {
+            self.end_out(0, cbw.data_len, buf).await?;
+        }
+        Ok((status, cbw.data_len))
+    }
+
+    /// Data phase of a command sending the first `len` bytes of `buf`.
+    async fn data_in(&mut self, cbw: &Cbw, len: usize, buf: &mut [u8]) -> Result<(Status, u32), EndpointError> {
+        if cbw.data_len == 0 {
+            return Ok((Status::PhaseError, 0));
+        }
+        if !cbw.data_in {
+            self.end_out(0, cbw.data_len, buf).await?;
+            return Ok((Status::PhaseError, cbw.data_len));
+        }
+        if len as u32 > cbw.data_len {
+            self.send(&buf[..cbw.data_len as usize]).await?;
+            return Ok((Status::PhaseError, 0));
+        }
+        self.send(&buf[..len]).await?;
+        self.end_in(len as u32, cbw.data_len).await?;
+        Ok((Status::Passed, cbw.data_len - len as u32))
+    }
+
+    /// Data phase of READ: send blocks read from the device.
+    async fn read<B: BlockDevice>(
+        &mut self,
+        cbw: &Cbw,
+        device: &mut B,
+        mut lba: u32,
+        blocks: u32,
+        sense: &mut Sense,
+        buf: &mut [u8],
+    ) -> Result<(Status, u32), EndpointError> {
+        let block_size = device.block_size();
+        let len = blocks as u64 * block_size as u64;
+        if !cbw.data_in && cbw.data_len > 0 {
+            self.end_out(0, cbw.data_len, buf).await?;
+            return Ok((Status::PhaseError, cbw.data_len));
+        }
+        if (cbw.data_len as u64) < len {
+            self.end_in(0, cbw.data_len).await?;
+            return Ok((Status::PhaseError, cbw.data_len));
+        }
+
+        let chunk_blocks = (buf.len() / block_size) as u32;
+        let mut remaining = blocks;
+        let mut sent = 0;
+        let mut status = Status::Passed;
+        while remaining > 0 {
+            let n = remaining.min(chunk_blocks);
+            let chunk = &mut buf[..n as usize * block_size];
+            if let Err(e) = device.read(lba, chunk).await {
+                warn!("msc: read error at block {}: {:?}", lba, e);
+                *sense = Sense::read_error(e);
+                status = Status::Failed;
+                break;
+            }
+            self.send(chunk).await?;
+            sent += chunk.len() as u32;
+            lba += n;
+            remaining -= n;
+        }
+        self.end_in(sent, cbw.data_len).await?;
+        Ok((status, cbw.data_len - sent))
+    }
+
+    /// Data phase of WRITE: write the received blocks to the device.
+    async fn write<B: BlockDevice>(
+        &mut self,
+        cbw: &Cbw,
+        device: &mut B,
+        mut lba: u32,
+        blocks: u32,
+        sense: &mut Sense,
+        buf: &mut [u8],
+    ) -> Result<(Status, u32), EndpointError> {
+        let block_size = device.block_size();
+        let len = blocks as u64 * block_size as u64;
+        if cbw.data_in && cbw.data_len > 0 {
+            self.end_in(0, cbw.data_len).await?;
+            return Ok((Status::PhaseError, cbw.data_len));
+        }
+        if (cbw.data_len as u64) < len {
+            self.end_out(0, cbw.data_len, buf).await?;
+            return Ok((Status::PhaseError, cbw.data_len));
+        }
+
+        let chunk_blocks = (buf.len() / block_size) as u32;
+        let mut remaining = blocks;
+        let mut received = 0;
+        let mut written = 0;
+        let mut status = Status::Passed;
+        while remaining > 0 {
+            let n = remaining.min(chunk_blocks);
+            let chunk = &mut buf[..n as usize * block_size];
+            let r = self.receive(chunk).await?;
+            received += r as u32;
+            if r < chunk.len() {
+                // The host ended the transfer early.
+                return Ok((Status::PhaseError, cbw.data_len - written));
+            }
+            if status == Status::Passed {
+                match device.write(lba, chunk).await {
+                    Ok(()) => written += chunk.len() as u32,
+                    Err(e) => {
+                        // Keep receiving the data, the host sends it anyway.
+                        warn!("msc: write error at block {}: {:?}", lba, e);
+                        *sense = Sense::write_error(e);
+                        status = Status::Failed;
+                    }
+                }
+            }
+            lba += n;
+            remaining -= n;
+        }
+        self.end_out(received, cbw.data_len, buf).await?;
+        Ok((status, cbw.data_len - written))
+    }
+
+    /// Send `data` in packets.
+    async fn send(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        for packet in data.chunks(mps) {
+            self.write_ep.write(packet).await?;
+        }
+        Ok(())
+    }
+
+    /// End a transfer to the host once `sent` bytes out of `len` were sent.
+    ///
+    /// If the host expects more data, the IN endpoint is stalled. Without stalls, the transfer ends
+    /// with a short packet.
+    async fn end_in(&mut self, sent: u32, len: u32) -> Result<(), EndpointError> {
+        if sent < len && self.write_ep.stall().await.is_err() && sent % self.max_packet_size() as u32 == 0 {
+            self.write_ep.write(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// End a transfer from the host once `received` bytes out of `len` were received.
+    ///
+    /// If the host has more data to send, the OUT endpoint is stalled. Without stalls, the rest of
+    /// the data is received and discarded.
+    async fn end_out(&mut self, received: u32, len: u32, buf: &mut [u8]) -> Result<(), EndpointError> {
+        if received < len && self.read_ep.stall().await.is_err() {
+            self.discard(len - received, buf).await?;
+        }
+        Ok(())
+    }
+
+    /// Stall both endpoints until the host does a Reset Recovery, which interrupts this.
+    ///
+    /// The endpoints are stalled again if the host clears them before the reset.
+    async fn stall_until_reset(&mut self) -> Result<(), Unsupported> {
+        loop {
+            let (r_in, r_out) = join(self.write_ep.stall(), self.read_ep.stall()).await;
+            r_in?;
+            r_out?;
+            // Stalling returns at once on disabled endpoints.
+            join(self.write_ep.wait_enabled(), self.read_ep.wait_enabled()).await;
+        }
+    }
+
+    /// Receive packets into `buf` until it is full or a short packet is received.
+    ///
+    /// Returns the number of bytes received. The length of `buf` must be a multiple of the max packet size.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let n = self.read_ep.read(&mut buf[pos..pos + mps]).await?;
+            pos += n;
+            if n < mps {
+                break;
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Receive and drop `len` bytes.
+    async fn discard(&mut self, mut len: u32, buf: &mut [u8]) -> Result<(), EndpointError> {
+        let mps = self.max_packet_size() as usize;
+        while len > 0 {
+            let n = self.read_ep.read(&mut buf[..mps]).await?;
+            len = len.saturating_sub(n as u32);
+            if n < mps {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! SCSI command set, as used by USB mass storage devices.
+
+use super::{BlockDevice, Error, Identification};
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+
+/// Peripheral device type of direct access block devices.
+const DIRECT_ACCESS_BLOCK_DEVICE: u8 = 0x00;
+/// Peripheral qualifier reported for logical units that don't exist.
+const LUN_NOT_CONNECTED: u8 = 0x7f;
+/// Length of the standard INQUIRY data.
+const INQUIRY_LEN: usize = 36;
+/// Length of the fixed format sense data.
+const SENSE_LEN: usize = 18;
+/// Device-specific parameter of the mode parameter header, set if the medium is write-protected.
+const MODE_WRITE_PROTECTED: u8 = 0x80;
+
+/// Sense key, additional sense code and additional sense code qualifier describing why a command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+impl Sense {
+    pub const NONE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub const NOT_PRESENT: Sense = Sense::new(0x02, 0x3a, 0x00);
+    pub const READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Sense = Sense::new(0x03, 0x0c, 0x00);
+    pub const INVALID_COMMAND: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub const LUN_NOT_SUPPORTED: Sense = Sense::new(0x05, 0x25, 0x00);
+    pub const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Self { key, asc, ascq }
+    }
+
+    pub fn read_error(e: Error) -> Self {
+        match e {
+            Error::NotPresent => Sense::NOT_PRESENT,
+            Error::WriteProtected => Sense::WRITE_PROTECTED,
+            Error::Io => Sense::READ_ERROR,
+        }
+    }
+
+    pub fn write_error(e: Error) -> Self {
+        match e {
+            Error::NotPresent => Sense::NOT_PRESENT,
+            Error::WriteProtected => Sense::WRITE_PROTECTED,
+            Error::Io => Sense::WRITE_ERROR,
+        }
+    }
+
+    /// Write the fixed format sense data to `buf`.
+    fn write(&self, buf: &mut [u8]) -> usize {
+        buf[..SENSE_LEN].fill(0);
+        buf[0] = 0x70; // current errors, fixed format
+        buf[2] = self.key;
+        buf[7] = (SENSE_LEN - 8) as u8; // additional sense length
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        SENSE_LEN
+    }
+}
+
+/// Data transfer a command requires.
+pub(crate) enum Phase {
+    /// No data.
+    None,
+    /// Send the first bytes of the buffer.
+    In(usize),
+    /// Send blocks read from the device.
+    Read { lba: u32, blocks: u32 },
+    /// Write the received blocks to the device.
+    Write { lba: u32, blocks: u32 },
+}
+
+/// Execute a command on a logical unit.
+///
+/// `sense` holds the sense data of the last failed command, reported by REQUEST SENSE. Data to send
+/// is written to `buf`. On failure, the sense data describing the error is returned.
+pub(crate) async fn execute<B: BlockDevice>(
+    cb: &[u8; 16],
+    device: &mut B,
+    sense: &mut Sense,
+    id: &Identification,
+    buf: &mut [u8],
+) -> Result<Phase, Sense> {
+    let present = device.is_present();
+    let ready = || if present { Ok(()) } else { Err(Sense::NOT_PRESENT) };
+
+    let phase = match cb[0] {
+        TEST_UNIT_READY => {
+            ready()?;
+            Phase::None
+        }
+        REQUEST_SENSE => {
+            let len = sense.write(buf).min(cb[4] as usize);
+            *sense = Sense::NONE;
+            return Ok(Phase::In(len));
+        }
+        INQUIRY => inquiry(cb, DIRECT_ACCESS_BLOCK_DEVICE, id, buf)?,
+        MODE_SENSE_6 => {
+            buf[..4].copy_from_slice(&[3, 0, mode_flags(device), 0]);
+            Phase::In(4.min(cb[4] as usize))
+        }
+        MODE_SENSE_10 => {
+            buf[..8].copy_from_slice(&[0, 6, 0, mode_flags(device), 0, 0, 0, 0]);
+            Phase::In(8.min(be_u16(&cb[7..]) as usize))
+        }
+        START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL | VERIFY_10 => Phase::None,
+        READ_FORMAT_CAPACITIES => {
+            ready()?;
+            buf[..4].copy_from_slice(&[0, 0, 0, 8]);
+            buf[4..8].copy_from_slice(&device.block_count().to_be_bytes());
+            // Formatted media, and the block length on 3 bytes.
+            buf[8..12].copy_from_slice(&(device.block_size() as u32 | 0x0200_0000).to_be_bytes());
+            Phase::In(12.min(be_u16(&cb[7..]) as usize))
+        }
+        READ_CAPACITY_10 => {
+            ready()?;
+            let last_lba = device.block_count().saturating_sub(1);
+            buf[..4].copy_from_slice(&last_lba.to_be_bytes());
+            buf[4..8].copy_from_slice(&(device.block_size() as u32).to_be_bytes());
+            Phase::In(8)
+        }
+        op @ (READ_10 | WRITE_10) => {
+            ready()?;
+            let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+            let blocks = be_u16(&cb[7..]) as u32;
+            if lba as u64 + blocks as u64 > device.block_count() as u64 {
+                return Err(Sense::LBA_OUT_OF_RANGE);
+            }
+            if op == WRITE_10 && device.is_write_protected() {
+                return Err(Sense::WRITE_PROTECTED);
+            }
+            match (op, blocks) {
+                (_, 0) => Phase::None,
+                (READ_10, _) => Phase::Read { lba, blocks },
+                _ => Phase::Write { lba, blocks },
+            }
+        }
+        SYNCHRONIZE_CACHE_10 => {
+            ready()?;
+            device.flush().await.map_err(Sense::write_error)?;
+            Phase::None
+        }
+        op => {
+            debug!("msc: unsupported SCSI command {:02x}", op);
+            return Err(Sense::INVALID_COMMAND);
+        }
+    };
+    *sense = Sense::NONE;
+    Ok(phase)
+}
+
+/// Execute a command addressed to a logical unit that doesn't exist.
+pub(crate) fn execute_unsupported(cb: &[u8; 16], id: &Identification, buf: &mut [u8]) -> Result<Phase, Sense> {
+    match cb[0] {
+        REQUEST_SENSE => Ok(Phase::In(Sense::LUN_NOT_SUPPORTED.write(buf).min(cb[4] as usize))),
+        INQUIRY => inquiry(cb, LUN_NOT_CONNECTED, id, buf),
+        _ => Err(Sense::LUN_NOT_SUPPORTED),
+    }
+}
+
+fn inquiry(cb: &[u8; 16], device_type: u8, id: &Identification, buf: &mut [u8]) -> Result<Phase, Sense> {
+    let alloc_len = be_u16(&cb[3..]) as usize;
+    if cb[1] & 0x01 != 0 {
+        // Vital product data: only the page listing the supported pages is implemented.
+        if cb[2] != 0x00 {
+            return Err(Sense::INVALID_FIELD);
+        }
+        buf[..5].copy_from_slice(&[device_type, 0x00, 0x00, 1, 0x00]);
+        return Ok(Phase::In(5.min(alloc_len)));
+    }
+
+    buf[0] = device_type;
+    buf[1] = if id.removable { 0x80 } else { 0x00 };
+    buf[2] = 0x04; // SPC-2
+    buf[3] = 0x02; // response data format
+    buf[4] = (INQUIRY_LEN - 5) as u8; // additional length
+    buf[5..8].fill(0);
+    buf[8..INQUIRY_LEN].copy_from_slice(&id.strings);
+    Ok(Phase::In(INQUIRY_LEN.min(alloc_len)))
+}
+
+fn mode_flags<B: BlockDevice>(device: &B) -> u8 {
+    if device.is_write_protected() {
+        MODE_WRITE_PROTECTED
+    } else {
+        0
+    }
+}
+
+fn be_u16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
@@ -0,0 +1,314 @@
+//! Printer class implementation.
+//!
+//! This implements a bidirectional printer interface: the host sends print data on the bulk OUT
+//! endpoint, and reads status data, in the printer's own language, on the bulk IN endpoint. It also
+//! answers the host's requests for the IEEE 1284 device ID and the port status.
+
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_CLASS_PRINTER: u8 = 0x07;
+
+const PRINTER_SUBCLASS: u8 = 0x01;
+const PRINTER_PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+const REQ_GET_DEVICE_ID: u8 = 0x00;
+const REQ_GET_PORT_STATUS: u8 = 0x01;
+const REQ_SOFT_RESET: u8 = 0x02;
+
+const PORT_STATUS_PAPER_EMPTY: u8 = 1 << 5;
+const PORT_STATUS_SELECT: u8 = 1 << 4;
+const PORT_STATUS_NOT_ERROR: u8 = 1 << 3;
+
+/// Error returned by [`PrinterClass::read`] and [`PrinterClass::write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The endpoints are disabled: the device is not configured.
+    Disabled,
+    /// The host reset the printer, which should discard the data of the current job.
+    Reset,
+}
+
+impl Error {
+    fn from_endpoint(e: EndpointError) -> Self {
+        match e {
+            // Packets are always read into buffers of the max packet size.
+            EndpointError::BufferOverflow => unreachable!(),
+            EndpointError::Disabled => Self::Disabled,
+        }
+    }
+}
+
+/// Status of the printer, as reported by the parallel port lines of legacy printers.
+///
+/// Most hosts only use it to report errors to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortStatus {
+    /// The printer is out of paper.
+    pub paper_empty: bool,
+    /// The printer is online.
+    pub selected: bool,
+    /// The printer is in an error state.
+    pub error: bool,
+}
+
+impl Default for PortStatus {
+    fn default() -> Self {
+        Self {
+            paper_empty: false,
+            selected: true,
+            error: false,
+        }
+    }
+}
+
+impl PortStatus {
+    fn to_byte(self) -> u8 {
+        let mut status = 0;
+        if self.paper_empty {
+            status |= PORT_STATUS_PAPER_EMPTY;
+        }
+        if self.selected {
+            status |= PORT_STATUS_SELECT;
+        }
+        if !self.error {
+            status |= PORT_STATUS_NOT_ERROR;
+        }
+        status
+    }
+}
+
+/// Configuration for the printer class.
+pub struct Config<'a> {
+    /// IEEE 1284 device ID, such as `"MFG:Embassy;MDL:Label printer;CMD:ZPL;CLS:PRINTER;"`.
+    ///
+    /// Hosts use its manufacturer, model and command set to select a driver. The control buffer of
+    /// the `Builder` must be large enough to hold it, plus 2 bytes.
+    pub device_id: &'a str,
+
+    /// Max packet size for both the IN and OUT endpoints.
+    ///
+    /// This is 64 for full speed devices, and 512 for high speed ones.
+    pub max_packet_size: u16,
+}
+
+/// Internal state for the printer class.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared {
+                port_status: AtomicU8::new(PORT_STATUS_SELECT | PORT_STATUS_NOT_ERROR),
+                reset: Cell::new(false),
+                waker: RefCell::new(WakerRegistration::new()),
+            },
+        }
+    }
+}
+
+/// Shared data between Control and PrinterClass
+struct ControlShared {
+    port_status: AtomicU8,
+    reset: Cell<bool>,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl ControlShared {
+    async fn wait_reset(&self) {
+        poll_fn(|cx| {
+            if self.reset.replace(false) {
+                Poll::Ready(())
+            } else {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+struct Control<'d> {
+    if_num: InterfaceNumber,
+    device_id: &'d str,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        // The endpoints get disabled, so the current job is already aborted.
+        self.shared.reset.set(false);
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        match req.request {
+            // Linux puts the interface number in the high byte of the index, like for GET_DEVICE_ID,
+            // so accept both forms.
+            REQ_SOFT_RESET if req.index == self.if_num.0 as u16 || req.index >> 8 == self.if_num.0 as u16 => {
+                debug!("printer: soft reset");
+                self.shared.reset.set(true);
+                self.shared.waker.borrow_mut().wake();
+                Some(OutResponse::Accepted)
+            }
+            _ if req.index == self.if_num.0 as u16 => Some(OutResponse::Rejected),
+            _ => None,
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+
+        match req.request {
+            // The index has the interface number in its high byte, and the alternate setting in its low byte.
+            REQ_GET_DEVICE_ID if req.index >> 8 == self.if_num.0 as u16 => {
+                // The ID starts with its length, including the length itself, in big endian.
+                let len = (self.device_id.len() + 2).min(buf.len());
+                buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+                buf[2..len].copy_from_slice(&self.device_id.as_bytes()[..len - 2]);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            REQ_GET_PORT_STATUS if req.index == self.if_num.0 as u16 => {
+                buf[0] = self.shared.port_status.load(Ordering::Relaxed);
+                Some(InResponse::Accepted(&buf[..1]))
+            }
+            _ if req.index == self.if_num.0 as u16 => Some(InResponse::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// USB printer.
+pub struct PrinterClass<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> PrinterClass<'d, D> {
+    /// Creates a new `PrinterClass`.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        let mut func = builder.function(USB_CLASS_PRINTER, PRINTER_SUBCLASS, PRINTER_PROTOCOL_BIDIRECTIONAL);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(
+            USB_CLASS_PRINTER,
+            PRINTER_SUBCLASS,
+            PRINTER_PROTOCOL_BIDIRECTIONAL,
+            None,
+        );
+        let read_ep = alt.endpoint_bulk_out(config.max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(config.max_packet_size);
+        drop(func);
+
+        let control = state.control.write(Control {
+            if_num,
+            device_id: config.device_id,
+            shared: &state.shared,
+        });
+        builder.handler(control);
+
+        Self {
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+        }
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        // The size is the same for both endpoints.
+        self.read_ep.info().max_packet_size
+    }
+
+    /// Sets the port status reported to the host.
+    pub fn set_port_status(&self, status: PortStatus) {
+        self.shared.port_status.store(status.to_byte(), Ordering::Relaxed);
+    }
+
+    /// Waits for the USB host to enable this interface
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    /// Reads print data.
+    ///
+    /// Packets are read until `buf` can't hold another one, or the host ends its transfer with a
+    /// short packet. Returns the number of bytes read. `buf` must be able to hold at least one packet.
+    ///
+    /// Returns [`Error::Reset`] if the host resets the printer, even if it happened before this call.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mps = self.max_packet_size() as usize;
+        assert!(buf.len() >= mps, "printer: buffer smaller than a packet");
+
+        let read = async {
+            let mut len = 0;
+            while buf.len() - len >= mps {
+                let n = self
+                    .read_ep
+                    .read(&mut buf[len..len + mps])
+                    .await
+                    .map_err(Error::from_endpoint)?;
+                len += n;
+                if n < mps {
+                    break;
+                }
+            }
+            Ok(len)
+        };
+        match select(read, self.shared.wait_reset()).await {
+            Either::First(result) => result,
+            Either::Second(()) => Err(Error::Reset),
+        }
+    }
+
+    /// Writes status data, in the printer language, for the host to read.
+    ///
+    /// `data` is sent as a single transfer, ending with a short packet.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mps = self.max_packet_size() as usize;
+        let write = async {
+            for packet in data.chunks(mps) {
+                self.write_ep.write(packet).await.map_err(Error::from_endpoint)?;
+            }
+            if data.len() % mps == 0 {
+                self.write_ep.write(&[]).await.map_err(Error::from_endpoint)?;
+            }
+            Ok(())
+        };
+        match select(write, self.shared.wait_reset()).await {
+            Either::First(result) => result,
+            Either::Second(()) => Err(Error::Reset),
+        }
+    }
+}
@@ -0,0 +1,719 @@
+//! RNDIS class implementation, aka Ethernet over USB for Windows.
+//!
+//! RNDIS is Microsoft's protocol for network devices: control messages are encapsulated in CDC
+//! requests, and Ethernet frames are wrapped in a message header on the bulk endpoints.
+//!
+//! # Compatibility
+//!
+//! Windows: Supported out of the box. The function uses the class codes Windows binds its RNDIS
+//! driver to. Also call [`Builder::msos_descriptor`] before creating the class, so that it adds the
+//! `RNDIS` compatible ID to the MS OS descriptors, which ensures Windows binds the driver in
+//! composite devices.
+//!
+//! Linux: Supported by the `rndis_host` driver, if enabled in the kernel.
+//!
+//! macOS: NOT supported. Add a [`cdc_ncm`](crate::class::cdc_ncm) or [`cdc_ecm`](crate::class::cdc_ecm)
+//! function to the same device for it.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::control::{self, InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use crate::msos::CompatibleIdFeatureDescriptor;
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+pub mod embassy_net;
+
+/// Class code of RNDIS functions.
+pub const USB_CLASS_WIRELESS_CONTROLLER: u8 = 0xe0;
+const SUBCLASS_RF: u8 = 0x01;
+const PROTOCOL_RNDIS: u8 = 0x03;
+
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+
+const NOTIF_RESPONSE_AVAILABLE: [u8; 8] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+const NOTIF_MAX_PACKET_SIZE: u16 = 8;
+const NOTIF_POLL_INTERVAL: u8 = 32;
+
+// Message types
+const MSG_PACKET: u32 = 0x0000_0001;
+const MSG_INITIALIZE: u32 = 0x0000_0002;
+const MSG_HALT: u32 = 0x0000_0003;
+const MSG_QUERY: u32 = 0x0000_0004;
+const MSG_SET: u32 = 0x0000_0005;
+const MSG_RESET: u32 = 0x0000_0006;
+const MSG_KEEPALIVE: u32 = 0x0000_0008;
+const MSG_COMPLETION: u32 = 0x8000_0000;
+
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_NOT_SUPPORTED: u32 = 0xc000_00bb;
+const STATUS_INVALID_DATA: u32 = 0xc001_0015;
+
+// Object identifiers
+const OID_GEN_SUPPORTED_LIST: u32 = 0x0001_0101;
+const OID_GEN_HARDWARE_STATUS: u32 = 0x0001_0102;
+const OID_GEN_MEDIA_SUPPORTED: u32 = 0x0001_0103;
+const OID_GEN_MEDIA_IN_USE: u32 = 0x0001_0104;
+const OID_GEN_MAXIMUM_FRAME_SIZE: u32 = 0x0001_0106;
+const OID_GEN_LINK_SPEED: u32 = 0x0001_0107;
+const OID_GEN_TRANSMIT_BLOCK_SIZE: u32 = 0x0001_010a;
+const OID_GEN_RECEIVE_BLOCK_SIZE: u32 = 0x0001_010b;
+const OID_GEN_VENDOR_ID: u32 = 0x0001_010c;
+const OID_GEN_VENDOR_DESCRIPTION: u32 = 0x0001_010d;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010e;
+const OID_GEN_MAXIMUM_TOTAL_SIZE: u32 = 0x0001_0111;
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+const OID_GEN_XMIT_OK: u32 = 0x0002_0101;
+const OID_GEN_RCV_OK: u32 = 0x0002_0102;
+const OID_GEN_XMIT_ERROR: u32 = 0x0002_0103;
+const OID_GEN_RCV_ERROR: u32 = 0x0002_0104;
+const OID_GEN_RCV_NO_BUFFER: u32 = 0x0002_0105;
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_802_3_CURRENT_ADDRESS: u32 = 0x0101_0102;
+const OID_802_3_MULTICAST_LIST: u32 = 0x0101_0103;
+const OID_802_3_MAXIMUM_LIST_SIZE: u32 = 0x0101_0104;
+const OID_802_3_MAC_OPTIONS: u32 = 0x0101_0105;
+const OID_802_3_RCV_ERROR_ALIGNMENT: u32 = 0x0102_0101;
+const OID_802_3_XMIT_ONE_COLLISION: u32 = 0x0102_0102;
+const OID_802_3_XMIT_MORE_COLLISIONS: u32 = 0x0102_0103;
+
+const SUPPORTED_OIDS: [u32; 27] = [
+    OID_GEN_SUPPORTED_LIST,
+    OID_GEN_HARDWARE_STATUS,
+    OID_GEN_MEDIA_SUPPORTED,
+    OID_GEN_MEDIA_IN_USE,
+    OID_GEN_MAXIMUM_FRAME_SIZE,
+    OID_GEN_LINK_SPEED,
+    OID_GEN_TRANSMIT_BLOCK_SIZE,
+    OID_GEN_RECEIVE_BLOCK_SIZE,
+    OID_GEN_VENDOR_ID,
+    OID_GEN_VENDOR_DESCRIPTION,
+    OID_GEN_CURRENT_PACKET_FILTER,
+    OID_GEN_MAXIMUM_TOTAL_SIZE,
+    OID_GEN_MEDIA_CONNECT_STATUS,
+    OID_GEN_PHYSICAL_MEDIUM,
+    OID_GEN_XMIT_OK,
+    OID_GEN_RCV_OK,
+    OID_GEN_XMIT_ERROR,
+    OID_GEN_RCV_ERROR,
+    OID_GEN_RCV_NO_BUFFER,
+    OID_802_3_PERMANENT_ADDRESS,
+    OID_802_3_CURRENT_ADDRESS,
+    OID_802_3_MULTICAST_LIST,
+    OID_802_3_MAXIMUM_LIST_SIZE,
+    OID_802_3_MAC_OPTIONS,
+    OID_802_3_RCV_ERROR_ALIGNMENT,
+    OID_802_3_XMIT_ONE_COLLISION,
+    OID_802_3_XMIT_MORE_COLLISIONS,
+];
+
+const VENDOR_DESCRIPTION: &[u8] = b"embassy-usb RNDIS\0";
+
+/// Maximum size of the Ethernet frames, without the FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+/// Length of the header of packet messages.
+const PACKET_HEADER_LEN: usize = 44;
+/// Maximum size of the transfers, a packet message with some room for padding.
+const MAX_TRANSFER_SIZE: usize = 1600;
+const MAX_RESPONSE_SIZE: usize = 256;
+const ABS_MAX_PACKET_SIZE: usize = 512;
+
+/// Internal state for the RNDIS class.
+pub struct State<'a> {
+    control: MaybeUninit<Control<'a>>,
+    shared: ControlShared,
+}
+
+impl<'a> Default for State<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> State<'a> {
+    /// Create a new `State`.
+    pub fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Shared data between Control and `RndisClass`
+struct ControlShared {
+    inner: CriticalSectionMutex<RefCell<SharedInner>>,
+}
+
+struct SharedInner {
+    /// Whether a response waits for the host to be notified of it.
+    response_available: bool,
+    notify_waker: WakerRegistration,
+    /// Whether the host initialized the device and enabled receiving packets.
+    connected: bool,
+    connection_waker: WakerRegistration,
+}
+
+impl ControlShared {
+    fn new() -> Self {
+        Self {
+            inner: CriticalSectionMutex::new(RefCell::new(SharedInner {
+                response_available: false,
+                notify_waker: WakerRegistration::new(),
+                connected: false,
+                connection_waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut SharedInner) -> R) -> R {
+        self.inner.lock(|inner| f(&mut inner.borrow_mut()))
+    }
+
+    fn set_response_available(&self, available: bool) {
+        self.with(|s| {
+            s.response_available = available;
+            s.notify_waker.wake();
+        });
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.with(|s| {
+            if s.connected != connected {
+                s.connected = connected;
+                s.connection_waker.wake();
+            }
+        });
+    }
+}
+
+struct Control<'a> {
+    shared: &'a ControlShared,
+    comm_if: InterfaceNumber,
+    mac_addr: [u8; 6],
+    /// Link speed, in units of 100 bit/s.
+    link_speed: u32,
+
+    initialized: bool,
+    packet_filter: u32,
+    response: [u8; MAX_RESPONSE_SIZE],
+    response_len: usize,
+}
+
+impl<'a> Control<'a> {
+    fn update_connection(&self) {
+        self.shared.set_connected(self.initialized && self.packet_filter != 0);
+    }
+
+    /// Handle a control message, writing the response if any.
+    fn handle_message(&mut self, msg: &[u8]) {
+        let field = |i: usize| {
+            msg.get(i * 4..i * 4 + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .unwrap_or(0)
+        };
+        let msg_type = field(0);
+        let request_id = field(2);
+
+        match msg_type {
+            MSG_INITIALIZE => {
+                debug!("rndis: initialize");
+                self.initialized = true;
+                self.packet_filter = 0;
+                self.update_connection();
+                self.respond(
+                    msg_type,
+                    &[
+                        request_id,
+                        STATUS_SUCCESS,
+                        1,                        // MajorVersion
+                        0,                        // MinorVersion
+                        0x01,                     // DeviceFlags = RNDIS_DF_CONNECTIONLESS
+                        0,                        // Medium = 802.3
+                        1,                        // MaxPacketsPerTransfer
+                        MAX_TRANSFER_SIZE as u32, // MaxTransferSize
+                        0,                        // PacketAlignmentFactor
+                        0,                        // AFListOffset
+                        0,                        // AFListSize
+                    ],
+                    &[],
+                );
+            }
+            MSG_HALT => {
+                debug!("rndis: halt");
+                self.initialized = false;
+                self.update_connection();
+            }
+            MSG_QUERY => {
+                let oid = field(3);
+                let mut buf = [0; MAX_RESPONSE_SIZE - 24];
+                match self.query(oid, &mut buf) {
+                    Some(n) => {
+                        // The information buffer follows the header, at offset 16 from the request ID.
+                        let offset = if n == 0 { 0 } else { 16 };
+                        self.respond(msg_type, &[request_id, STATUS_SUCCESS, n as u32, offset], &buf[..n])
+                    }
+                    None => {
+                        debug!("rndis: unsupported query {:08x}", oid);
+                        self.respond(msg_type, &[request_id, STATUS_NOT_SUPPORTED, 0, 0], &[]);
+                    }
+                }
+            }
+            MSG_SET => {
+                let oid = field(3);
+                let len = field(4) as usize;
+                let offset = 8 + field(5) as usize;
+                let status = match msg.get(offset..offset + len) {
+                    Some(data) => self.set(oid, data),
+                    None => STATUS_INVALID_DATA,
+                };
+                self.respond(msg_type, &[request_id, status], &[]);
+            }
+            MSG_RESET => {
+                debug!("rndis: reset");
+                self.packet_filter = 0;
+                self.update_connection();
+                // RESET_CMPLT has no request ID, but the addressing reset flag.
+                self.respond(msg_type, &[STATUS_SUCCESS, 1], &[]);
+            }
+            MSG_KEEPALIVE => {
+                self.respond(msg_type, &[request_id, STATUS_SUCCESS], &[]);
+            }
+            _ => warn!("rndis: unknown message type {:08x}", msg_type),
+        }
+    }
+
+    /// Answer a query, writing the value to `buf`, and returning its length.
+    fn query(&self, oid: u32, buf: &mut [u8]) -> Option<usize> {
+        let value = match oid {
+            OID_GEN_SUPPORTED_LIST => {
+                for (chunk, oid) in buf.chunks_exact_mut(4).zip(SUPPORTED_OIDS) {
+                    chunk.copy_from_slice(&oid.to_le_bytes());
+                }
+                return Some(SUPPORTED_OIDS.len() * 4);
+            }
+            OID_GEN_VENDOR_DESCRIPTION => {
+                buf[..VENDOR_DESCRIPTION.len()].copy_from_slice(VENDOR_DESCRIPTION);
+                return Some(VENDOR_DESCRIPTION.len());
+            }
+            OID_802_3_PERMANENT_ADDRESS | OID_802_3_CURRENT_ADDRESS => {
+                buf[..6].copy_from_slice(&self.mac_addr);
+                return Some(6);
+            }
+            OID_802_3_MULTICAST_LIST => return Some(0),
+
+            OID_GEN_MAXIMUM_FRAME_SIZE => (MAX_FRAME_SIZE - 14) as u32,
+            OID_GEN_LINK_SPEED => self.link_speed,
+            OID_GEN_TRANSMIT_BLOCK_SIZE | OID_GEN_RECEIVE_BLOCK_SIZE => MAX_FRAME_SIZE as u32,
+            OID_GEN_MAXIMUM_TOTAL_SIZE => (PACKET_HEADER_LEN + MAX_FRAME_SIZE) as u32,
+            OID_GEN_VENDOR_ID => 0x00ff_ffff,
+            OID_GEN_CURRENT_PACKET_FILTER => self.packet_filter,
+            OID_802_3_MAXIMUM_LIST_SIZE => 1,
+            // Always connected: the link is the USB cable.
+            OID_GEN_MEDIA_CONNECT_STATUS => 0,
+            // Medium 802.3, hardware ready, and no statistics.
+            OID_GEN_HARDWARE_STATUS
+            | OID_GEN_MEDIA_SUPPORTED
+            | OID_GEN_MEDIA_IN_USE
+            | OID_GEN_PHYSICAL_MEDIUM
+            | OID_GEN_XMIT_OK
+            | OID_GEN_RCV_OK
+            | OID_GEN_XMIT_ERROR
+            | OID_GEN_RCV_ERROR
+            | OID_GEN_RCV_NO_BUFFER
+            | OID_802_3_MAC_OPTIONS
+            | OID_802_3_RCV_ERROR_ALIGNMENT
+            | OID_802_3_XMIT_ONE_COLLISION
+            | OID_802_3_XMIT_MORE_COLLISIONS => 0,
+            _ => return None,
+        };
+        buf[..4].copy_from_slice(&value.to_le_bytes());
+        Some(4)
+    }
+
+    fn set(&mut self, oid: u32, data: &[u8]) -> u32 {
+        match oid {
+            OID_GEN_CURRENT_PACKET_FILTER if data.len() >= 4 => {
+                self.packet_filter = u32::from_le_bytes(data[..4].try_into().unwrap());
+                debug!("rndis: packet filter set to {:08x}", self.packet_filter);
+                self.update_connection();
+                STATUS_SUCCESS
+            }
+            // We don't filter packets, the host gets all of them.
+            OID_802_3_MULTICAST_LIST => STATUS_SUCCESS,
+            _ => {
+                debug!("rndis: unsupported set {:08x}", oid);
+                STATUS_NOT_SUPPORTED
+            }
+        }
+    }
+
+    /// Write the completion message of a request, and notify the host it is available.
+    fn respond(&mut self, msg_type: u32, fields: &[u32], data: &[u8]) {
+        let len = 8 + fields.len() * 4 + data.len();
+        let buf = &mut self.response;
+        buf[0..4].copy_from_slice(&(msg_type | MSG_COMPLETION).to_le_bytes());
+        buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        for (i, field) in fields.iter().enumerate() {
+            buf[8 + i * 4..][..4].copy_from_slice(&field.to_le_bytes());
+        }
+        buf[len - data.len()..len].copy_from_slice(data);
+        self.response_len = len;
+        self.shared.set_response_available(true);
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.configured(false);
+    }
+
+    fn configured(&mut self, _configured: bool) {
+        // The host initializes the device again once configured.
+        self.initialized = false;
+        self.packet_filter = 0;
+        self.response_len = 0;
+        self.shared.set_response_available(false);
+        self.update_connection();
+    }
+
+    fn control_out(&mut self, req: control::Request, data: &[u8]) -> Option<OutResponse> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                self.handle_message(data);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if (req.request_type, req.recipient, req.index)
+            != (RequestType::Class, Recipient::Interface, self.comm_if.0 as u16)
+        {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_ENCAPSULATED_RESPONSE => {
+                let len = core::mem::take(&mut self.response_len);
+                if len == 0 {
+                    // No response available, a single zero byte tells the host.
+                    Some(InResponse::Accepted(&[0]))
+                } else {
+                    Some(InResponse::Accepted(&self.response[..len]))
+                }
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// RNDIS class
+pub struct RndisClass<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> RndisClass<'d, D> {
+    /// Create a new RNDIS class.
+    ///
+    /// `mac_address` is the MAC address of the host's side of the link. `max_packet_size` must be
+    /// at most 512.
+    ///
+    /// The host sends control messages of up to about 100 bytes, so the control buffer given to the
+    /// [`Builder`] must be at least 128 bytes long.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        mac_address: [u8; 6],
+        max_packet_size: u16,
+    ) -> Self {
+        assert!(max_packet_size as usize <= ABS_MAX_PACKET_SIZE);
+
+        let msos = !builder.msos_writer().is_empty();
+        let mut func = builder.function(USB_CLASS_WIRELESS_CONTROLLER, SUBCLASS_RF, PROTOCOL_RNDIS);
+        if msos {
+            func.msos_feature(CompatibleIdFeatureDescriptor::new("RNDIS", "5162001"));
+        }
+
+        // Control interface
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let mut alt = iface.alt_setting(USB_CLASS_WIRELESS_CONTROLLER, SUBCLASS_RF, PROTOCOL_RNDIS, None);
+
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,
+                0x01, // bcdCDC (1.10)
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT, // bDescriptorSubtype
+                0x00,                     // bmCapabilities
+                u8::from(comm_if) + 1,    // bDataInterface
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM, // bDescriptorSubtype
+                0x00,         // bmCapabilities
+            ],
+        );
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION,        // bDescriptorSubtype
+                comm_if.into(),        // bControlInterface
+                u8::from(comm_if) + 1, // bSubordinateInterface
+            ],
+        );
+
+        let comm_ep = alt.endpoint_interrupt_in(NOTIF_MAX_PACKET_SIZE, NOTIF_POLL_INTERVAL);
+
+        // Data interface
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, 0x00, None);
+        let read_ep = alt.endpoint_bulk_out(max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(max_packet_size);
+
+        drop(func);
+
+        let bus_speed: u32 = match max_packet_size {
+            512 => 480_000_000,
+            _ => 12_000_000,
+        };
+        let control = state.control.write(Control {
+            shared: &state.shared,
+            comm_if,
+            mac_addr: mac_address,
+            link_speed: bus_speed / 100,
+            initialized: false,
+            packet_filter: 0,
+            response: [0; MAX_RESPONSE_SIZE],
+            response_len: 0,
+        });
+        builder.handler(control);
+
+        RndisClass {
+            comm_ep,
+            read_ep,
+            write_ep,
+            control: &state.shared,
+        }
+    }
+
+    /// Split the class into a sender, a receiver, and a notifier.
+    ///
+    /// This allows concurrently sending and receiving packets from separate tasks. The
+    /// [`Notifier`] must run for the host to initialize the device.
+    pub fn split(self) -> (Sender<'d, D>, Receiver<'d, D>, Notifier<'d, D>) {
+        (
+            Sender {
+                write_ep: self.write_ep,
+            },
+            Receiver {
+                read_ep: self.read_ep,
+                control: self.control,
+            },
+            Notifier {
+                comm_ep: self.comm_ep,
+                control: self.control,
+            },
+        )
+    }
+}
+
+/// RNDIS class packet sender.
+///
+/// You can obtain a `Sender` with [`RndisClass::split`]
+pub struct Sender<'d, D: Driver<'d>> {
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Write a packet.
+    ///
+    /// This waits until the packet is successfully stored in the RNDIS endpoint buffers.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        let max_packet_size = self.write_ep.info().max_packet_size as usize;
+
+        let mut header = [0u32; PACKET_HEADER_LEN / 4];
+        header[0] = MSG_PACKET;
+        header[1] = (PACKET_HEADER_LEN + data.len()) as u32; // MessageLength
+        header[2] = (PACKET_HEADER_LEN - 8) as u32; // DataOffset, from the DataOffset field
+        header[3] = data.len() as u32; // DataLength
+
+        // Build first packet on a buffer, send next packets straight from `data`.
+        let mut buf = [0; ABS_MAX_PACKET_SIZE];
+        for (chunk, field) in buf.chunks_exact_mut(4).zip(header) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+
+        if PACKET_HEADER_LEN + data.len() < max_packet_size {
+            // First packet is not full, just send it.
+            // No need to send ZLP because it's short for sure.
+            buf[PACKET_HEADER_LEN..][..data.len()].copy_from_slice(data);
+            self.write_ep.write(&buf[..PACKET_HEADER_LEN + data.len()]).await?;
+        } else {
+            let (d1, d2) = data.split_at(max_packet_size - PACKET_HEADER_LEN);
+
+            buf[PACKET_HEADER_LEN..max_packet_size].copy_from_slice(d1);
+            self.write_ep.write(&buf[..max_packet_size]).await?;
+
+            for chunk in d2.chunks(max_packet_size) {
+                self.write_ep.write(chunk).await?;
+            }
+
+            // Send ZLP if needed.
+            if d2.len() % max_packet_size == 0 {
+                self.write_ep.write(&[]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// RNDIS class packet receiver.
+///
+/// You can obtain a `Receiver` with [`RndisClass::split`]
+pub struct Receiver<'d, D: Driver<'d>> {
+    read_ep: D::EndpointOut,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    /// Read a network packet.
+    ///
+    /// This waits until a packet is successfully received from the endpoint buffers.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let max_packet_size = self.read_ep.info().max_packet_size as usize;
+
+        // Retry loop
+        loop {
+            // read the packet message
+            let mut msg = [0u8; MAX_TRANSFER_SIZE];
+            let mut pos = 0;
+            loop {
+                let n = self.read_ep.read(&mut msg[pos..][..max_packet_size]).await?;
+                pos += n;
+                if n < max_packet_size || pos + max_packet_size > MAX_TRANSFER_SIZE {
+                    break;
+                }
+            }
+
+            let msg = &msg[..pos];
+            let field = |i: usize| u32::from_le_bytes(msg[i * 4..i * 4 + 4].try_into().unwrap());
+
+            // Hosts may pad transfers with a zero byte, so a single one is not a message.
+            if msg.len() < PACKET_HEADER_LEN {
+                if msg.len() > 1 {
+                    warn!("Received too short message");
+                }
+                continue;
+            }
+            if field(0) != MSG_PACKET {
+                warn!("Received bad message type.");
+                continue;
+            }
+            let data_offset = 8 + field(2) as usize;
+            let data_len = field(3) as usize;
+
+            let Some(data) = msg.get(data_offset..data_offset + data_len) else {
+                warn!("Packet message has a data pointer out of range.");
+                continue;
+            };
+            let Some(dst) = buf.get_mut(..data_len) else {
+                warn!("Received packet too large for the buffer, dropping it.");
+                continue;
+            };
+            dst.copy_from_slice(data);
+
+            return Ok(data_len);
+        }
+    }
+
+    /// Waits for the USB host to initialize the device, and enable receiving packets.
+    pub async fn wait_connection(&mut self) {
+        poll_fn(|cx| {
+            self.control.with(|s| {
+                if s.connected {
+                    Poll::Ready(())
+                } else {
+                    s.connection_waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await;
+        self.read_ep.wait_enabled().await;
+    }
+}
+
+/// RNDIS class notifier, telling the host its control messages have a response.
+///
+/// You can obtain a `Notifier` with [`RndisClass::split`]
+pub struct Notifier<'d, D: Driver<'d>> {
+    comm_ep: D::EndpointIn,
+    control: &'d ControlShared,
+}
+
+impl<'d, D: Driver<'d>> Notifier<'d, D> {
+    /// Send the notifications.
+    ///
+    /// You must run this concurrently with the sender and receiver for the class to operate.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            poll_fn(|cx| {
+                self.control.with(|s| {
+                    if s.response_available {
+                        s.response_available = false;
+                        Poll::Ready(())
+                    } else {
+                        s.notify_waker.register(cx.waker());
+                        Poll::Pending
+                    }
+                })
+            })
+            .await;
+
+            if let Err(e) = self.comm_ep.write(&NOTIF_RESPONSE_AVAILABLE).await {
+                // The host resets the device after a failure, so the notification is not needed anymore.
+                warn!("Failed to send notification: {:?}", e);
+                self.comm_ep.wait_enabled().await;
+            }
+        }
+    }
+}
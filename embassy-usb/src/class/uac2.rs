@@ -0,0 +1,1006 @@
+//! USB Audio Class 2.0 implementation.
+//!
+//! An audio function is made of an audio control interface, which describes the topology of the
+//! device with clock sources, terminals and units, and of audio streaming interfaces, which carry
+//! the samples on isochronous endpoints.
+//!
+//! The topology is given as a list of [`Entity`], each with an ID unique within the function,
+//! connected to each other by these IDs. The host can select the sample rate of the clock sources,
+//! and control the mute and volume of the feature units: [`Controls`] reads the current values.
+//!
+//! Each streaming interface is connected to a USB streaming terminal of the topology, and has
+//! an alternate setting per sample format in addition to the zero-bandwidth alternate setting 0,
+//! selected by the host when the stream is stopped. The device clocks are not synchronized to the
+//! USB frames, so the streaming endpoints are asynchronous: the host adjusts the size of the
+//! packets it receives on IN streams, and the rate at which the device consumes samples of OUT
+//! streams is reported on a [`Feedback`] endpoint.
+//!
+//! USB Audio Class 2.0 functions require an interface association descriptor, so the device
+//! [`Config`](crate::Config) must have `composite_with_iads` set.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::builder::{FunctionBuilder, InterfaceAltBuilder};
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_AUDIO_CLASS: u8 = 0x01;
+
+const FUNCTION_SUBCLASS_UNDEFINED: u8 = 0x00;
+const AUDIOCONTROL_SUBCLASS: u8 = 0x01;
+const AUDIOSTREAMING_SUBCLASS: u8 = 0x02;
+const IP_VERSION_02_00: u8 = 0x20;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+// Audio control interface descriptor subtypes.
+const HEADER: u8 = 0x01;
+const INPUT_TERMINAL: u8 = 0x02;
+const OUTPUT_TERMINAL: u8 = 0x03;
+const FEATURE_UNIT: u8 = 0x06;
+const CLOCK_SOURCE: u8 = 0x0a;
+
+// Audio streaming interface descriptor subtypes.
+const AS_GENERAL: u8 = 0x01;
+const FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+const PCM: u32 = 0x0000_0001;
+const EP_GENERAL: u8 = 0x01;
+
+// Requests.
+const CUR: u8 = 0x01;
+const RANGE: u8 = 0x02;
+
+// Control selectors.
+const CS_SAM_FREQ_CONTROL: u8 = 0x01;
+const CS_CLOCK_VALID_CONTROL: u8 = 0x02;
+const FU_MUTE_CONTROL: u8 = 0x01;
+const FU_VOLUME_CONTROL: u8 = 0x02;
+
+// Clock source attributes.
+const CLOCK_INTERNAL_FIXED: u8 = 0x01;
+const CLOCK_INTERNAL_PROGRAMMABLE: u8 = 0x03;
+
+// Control capabilities, as found in the `bmControls` fields.
+const CONTROL_READ: u8 = 0b01;
+const CONTROL_READ_WRITE: u8 = 0b11;
+
+/// Maximum number of clock sources in a function.
+pub const MAX_CLOCK_SOURCES: usize = 2;
+/// Maximum number of feature units in a function.
+pub const MAX_FEATURE_UNITS: usize = 2;
+/// Maximum number of logical channels of a feature unit.
+pub const MAX_CHANNELS: usize = 8;
+/// Maximum number of streaming interfaces in a function.
+pub const MAX_STREAMS: usize = 4;
+
+/// Audio function categories, used in [`Config::category`].
+pub mod category {
+    /// Desktop speaker.
+    pub const DESKTOP_SPEAKER: u8 = 0x01;
+    /// Microphone.
+    pub const MICROPHONE: u8 = 0x03;
+    /// Headset.
+    pub const HEADSET: u8 = 0x04;
+    /// Converter.
+    pub const CONVERTER: u8 = 0x06;
+    /// Pro-audio.
+    pub const PRO_AUDIO: u8 = 0x0a;
+    /// Audio interface with several inputs and outputs.
+    pub const IO_BOX: u8 = 0x08;
+    /// Other.
+    pub const OTHER: u8 = 0xff;
+}
+
+/// Terminal types, used in [`InputTerminal::terminal_type`] and [`OutputTerminal::terminal_type`].
+pub mod terminal_type {
+    /// Terminal connected to a streaming interface.
+    pub const USB_STREAMING: u16 = 0x0101;
+    /// Microphone.
+    pub const MICROPHONE: u16 = 0x0201;
+    /// Speaker.
+    pub const SPEAKER: u16 = 0x0301;
+    /// Headphones.
+    pub const HEADPHONES: u16 = 0x0302;
+    /// Analog line connector.
+    pub const LINE_CONNECTOR: u16 = 0x0603;
+    /// S/PDIF interface.
+    pub const SPDIF_INTERFACE: u16 = 0x0605;
+}
+
+/// Clock source generating the sampling clock of terminals.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockSource<'a> {
+    /// Entity ID.
+    pub id: u8,
+    /// Sample rates the clock can run at, in Hz. The host selects one of them, the first one is
+    /// used until it does.
+    ///
+    /// The response to the request listing them takes 2 + 12 bytes per sample rate, which must fit
+    /// in the control buffer of the device.
+    pub sample_rates: &'a [u32],
+}
+
+/// Input terminal, where audio enters the function.
+#[derive(Debug, Clone, Copy)]
+pub struct InputTerminal {
+    /// Entity ID.
+    pub id: u8,
+    /// Terminal type, one of the [`terminal_type`] constants.
+    pub terminal_type: u16,
+    /// ID of the clock source of the terminal.
+    pub clock: u8,
+    /// Number of logical channels of the terminal output.
+    pub channels: u8,
+    /// Spatial locations of the channels, as a bitmap. Channels without a location are not included.
+    pub channel_config: u32,
+}
+
+/// Output terminal, where audio leaves the function.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputTerminal {
+    /// Entity ID.
+    pub id: u8,
+    /// Terminal type, one of the [`terminal_type`] constants.
+    pub terminal_type: u16,
+    /// ID of the entity the terminal is connected to.
+    pub source: u8,
+    /// ID of the clock source of the terminal.
+    pub clock: u8,
+}
+
+/// Feature unit, controlling the mute and volume of each channel going through it.
+///
+/// The controls exist for the master channel 0, and for each logical channel from 1 to `channels`.
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureUnit {
+    /// Entity ID.
+    pub id: u8,
+    /// ID of the entity the unit is connected to.
+    pub source: u8,
+    /// Number of logical channels going through the unit.
+    pub channels: u8,
+    /// Whether the channels have a mute control.
+    pub mute: bool,
+    /// Range of the volume control of the channels, if any.
+    pub volume: Option<VolumeRange>,
+}
+
+/// Range of a volume control.
+///
+/// Values are in 1/256 dB: 0 is 0 dB, -256 is -1 dB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VolumeRange {
+    /// Minimum volume.
+    pub min: i16,
+    /// Maximum volume.
+    pub max: i16,
+    /// Step between two volumes.
+    pub resolution: i16,
+}
+
+/// Entity of the audio function topology.
+#[derive(Debug, Clone, Copy)]
+pub enum Entity<'a> {
+    /// Clock source.
+    ClockSource(ClockSource<'a>),
+    /// Input terminal.
+    InputTerminal(InputTerminal),
+    /// Output terminal.
+    OutputTerminal(OutputTerminal),
+    /// Feature unit.
+    FeatureUnit(FeatureUnit),
+}
+
+impl<'a> Entity<'a> {
+    /// Entity ID.
+    pub fn id(&self) -> u8 {
+        match self {
+            Entity::ClockSource(e) => e.id,
+            Entity::InputTerminal(e) => e.id,
+            Entity::OutputTerminal(e) => e.id,
+            Entity::FeatureUnit(e) => e.id,
+        }
+    }
+
+    /// Length of the descriptor of the entity.
+    fn descriptor_len(&self) -> usize {
+        match self {
+            Entity::ClockSource(_) => 8,
+            Entity::InputTerminal(_) => 17,
+            Entity::OutputTerminal(_) => 12,
+            Entity::FeatureUnit(e) => 6 + (e.channels as usize + 1) * 4,
+        }
+    }
+
+    /// Write the descriptor of the entity.
+    fn write_descriptor<'d, D: Driver<'d>>(&self, alt: &mut InterfaceAltBuilder<'_, 'd, D>) {
+        match self {
+            Entity::ClockSource(e) => {
+                let (attributes, frequency_control) = match e.sample_rates.len() {
+                    1 => (CLOCK_INTERNAL_FIXED, CONTROL_READ),
+                    _ => (CLOCK_INTERNAL_PROGRAMMABLE, CONTROL_READ_WRITE),
+                };
+                alt.descriptor(
+                    CS_INTERFACE,
+                    &[
+                        CLOCK_SOURCE,
+                        e.id,
+                        attributes,
+                        frequency_control | CONTROL_READ << 2, // bmControls, frequency and validity
+                        0x00,                                  // bAssocTerminal
+                        0x00,                                  // iClockSource
+                    ],
+                );
+            }
+            Entity::InputTerminal(e) => {
+                let [t0, t1] = e.terminal_type.to_le_bytes();
+                let [c0, c1, c2, c3] = e.channel_config.to_le_bytes();
+                alt.descriptor(
+                    CS_INTERFACE,
+                    &[
+                        INPUT_TERMINAL,
+                        e.id,
+                        t0,
+                        t1,
+                        0x00, // bAssocTerminal
+                        e.clock,
+                        e.channels,
+                        c0,
+                        c1,
+                        c2,
+                        c3,
+                        0x00, // iChannelNames
+                        0x00,
+                        0x00, // bmControls
+                        0x00, // iTerminal
+                    ],
+                );
+            }
+            Entity::OutputTerminal(e) => {
+                let [t0, t1] = e.terminal_type.to_le_bytes();
+                alt.descriptor(
+                    CS_INTERFACE,
+                    &[
+                        OUTPUT_TERMINAL,
+                        e.id,
+                        t0,
+                        t1,
+                        0x00, // bAssocTerminal
+                        e.source,
+                        e.clock,
+                        0x00,
+                        0x00, // bmControls
+                        0x00, // iTerminal
+                    ],
+                );
+            }
+            Entity::FeatureUnit(e) => {
+                let mut controls = 0u32;
+                if e.mute {
+                    controls |= CONTROL_READ_WRITE as u32;
+                }
+                if e.volume.is_some() {
+                    controls |= (CONTROL_READ_WRITE as u32) << 2;
+                }
+
+                let mut buf = [0u8; 4 + (MAX_CHANNELS + 1) * 4];
+                let len = 4 + (e.channels as usize + 1) * 4;
+                buf[0] = FEATURE_UNIT;
+                buf[1] = e.id;
+                buf[2] = e.source;
+                for channel in buf[3..len - 1].chunks_mut(4) {
+                    channel.copy_from_slice(&controls.to_le_bytes());
+                }
+                buf[len - 1] = 0x00; // iFeature
+                alt.descriptor(CS_INTERFACE, &buf[..len]);
+            }
+        }
+    }
+}
+
+/// Configuration of the audio function.
+pub struct Config<'a> {
+    /// Primary use of the function, one of the [`category`] constants.
+    pub category: u8,
+    /// Clock sources, terminals and units of the function.
+    ///
+    /// There can be up to [`MAX_CLOCK_SOURCES`] clock sources and [`MAX_FEATURE_UNITS`] feature units,
+    /// each with up to [`MAX_CHANNELS`] channels.
+    pub entities: &'a [Entity<'a>],
+    /// Whether the device runs at high speed.
+    ///
+    /// Isochronous endpoints are then serviced every 125 µs microframe instead of every 1 ms frame,
+    /// and the feedback format changes.
+    pub high_speed: bool,
+}
+
+/// Sample format of a streaming interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Format {
+    /// Number of bytes each sample occupies: 1, 2, 3 or 4.
+    pub subslot_size: u8,
+    /// Number of significant bits of the samples.
+    pub bit_resolution: u8,
+}
+
+/// Configuration of a streaming interface.
+pub struct StreamConfig<'a> {
+    /// ID of the USB streaming terminal the interface is connected to.
+    pub terminal: u8,
+    /// Number of channels in the stream.
+    pub channels: u8,
+    /// Spatial locations of the channels, as a bitmap. Channels without a location are not included.
+    pub channel_config: u32,
+    /// Sample formats of the stream, each in its own alternate setting, from 1 onwards.
+    pub formats: &'a [Format],
+    /// Maximum packet size of the data endpoint.
+    ///
+    /// It must hold the samples of one (micro)frame at the highest sample rate, plus one more
+    /// sample for rate matching, for the largest format.
+    pub max_packet_size: u16,
+}
+
+/// Internal state for the USB Audio Class 2.0 function.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ChannelValues {
+    mute: bool,
+    volume: i16,
+}
+
+/// Values of the controls the host can set.
+struct Values {
+    sample_rates: [u32; MAX_CLOCK_SOURCES],
+    channels: [[ChannelValues; MAX_CHANNELS + 1]; MAX_FEATURE_UNITS],
+    changed: bool,
+    waker: WakerRegistration,
+}
+
+/// Shared data between Control and the class.
+struct ControlShared {
+    /// Interface number of the audio control interface.
+    control_if: AtomicU8,
+    values: CriticalSectionMutex<RefCell<Values>>,
+    /// Interface number of each streaming interface, `NO_INTERFACE` if unused.
+    stream_ifaces: [AtomicU8; MAX_STREAMS],
+    /// Current alternate setting of each streaming interface.
+    stream_alts: [AtomicU8; MAX_STREAMS],
+}
+
+const NO_INTERFACE: u8 = 0xff;
+
+impl ControlShared {
+    const fn new() -> Self {
+        Self {
+            control_if: AtomicU8::new(NO_INTERFACE),
+            values: CriticalSectionMutex::new(RefCell::new(Values {
+                sample_rates: [0; MAX_CLOCK_SOURCES],
+                channels: [[ChannelValues { mute: false, volume: 0 }; MAX_CHANNELS + 1]; MAX_FEATURE_UNITS],
+                changed: false,
+                waker: WakerRegistration::new(),
+            })),
+            stream_ifaces: [const { AtomicU8::new(NO_INTERFACE) }; MAX_STREAMS],
+            stream_alts: [const { AtomicU8::new(0) }; MAX_STREAMS],
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Values) -> R) -> R {
+        self.values.lock(|v| f(&mut v.borrow_mut()))
+    }
+
+    /// Set the controls to their default value.
+    fn reset(&self, entities: &[Entity<'_>]) {
+        self.with(|v| {
+            for (rate, clock) in v.sample_rates.iter_mut().zip(clock_sources(entities)) {
+                *rate = clock.sample_rates[0];
+            }
+            for (channels, unit) in v.channels.iter_mut().zip(feature_units(entities)) {
+                let volume = unit.volume.map_or(0, |range| 0.clamp(range.min, range.max));
+                channels.fill(ChannelValues { mute: false, volume });
+            }
+        });
+        for alt in &self.stream_alts {
+            alt.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn signal_changed(&self) {
+        self.with(|v| {
+            v.changed = true;
+            v.waker.wake();
+        });
+    }
+
+    async fn changed(&self) {
+        poll_fn(|cx| {
+            self.with(|v| {
+                if v.changed {
+                    v.changed = false;
+                    Poll::Ready(())
+                } else {
+                    v.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+fn clock_sources<'a>(entities: &'a [Entity<'a>]) -> impl Iterator<Item = &'a ClockSource<'a>> {
+    entities.iter().filter_map(|e| match e {
+        Entity::ClockSource(c) => Some(c),
+        _ => None,
+    })
+}
+
+fn feature_units<'a>(entities: &'a [Entity<'a>]) -> impl Iterator<Item = &'a FeatureUnit> {
+    entities.iter().filter_map(|e| match e {
+        Entity::FeatureUnit(f) => Some(f),
+        _ => None,
+    })
+}
+
+/// Find a clock source and its index among the clock sources.
+fn clock_source<'a>(entities: &'a [Entity<'a>], id: u8) -> Option<(usize, &'a ClockSource<'a>)> {
+    clock_sources(entities).enumerate().find(|(_, c)| c.id == id)
+}
+
+/// Find a feature unit and its index among the feature units.
+fn feature_unit<'a>(entities: &'a [Entity<'a>], id: u8) -> Option<(usize, &'a FeatureUnit)> {
+    feature_units(entities).enumerate().find(|(_, f)| f.id == id)
+}
+
+struct Control<'d> {
+    entities: &'d [Entity<'d>],
+    shared: &'d ControlShared,
+}
+
+impl<'d> Control<'d> {
+    /// Entity, control selector and channel number a request to the audio control interface targets.
+    fn target(&self, req: &Request) -> Option<(u8, u8, u8)> {
+        if (req.request_type, req.recipient, req.index as u8)
+            != (
+                RequestType::Class,
+                Recipient::Interface,
+                self.shared.control_if.load(Ordering::Relaxed),
+            )
+        {
+            return None;
+        }
+        Some(((req.index >> 8) as u8, (req.value >> 8) as u8, req.value as u8))
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        self.shared.reset(self.entities);
+        self.shared.signal_changed();
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        let shared = self.shared;
+        for (i, stream_if) in shared.stream_ifaces.iter().enumerate() {
+            if stream_if.load(Ordering::Relaxed) == iface.0 {
+                debug!(
+                    "uac2: stream on interface {} set to alt setting {}",
+                    iface.0, alternate_setting
+                );
+                shared.stream_alts[i].store(alternate_setting, Ordering::Relaxed);
+                shared.signal_changed();
+            }
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        let (id, selector, channel) = self.target(&req)?;
+        if req.request != CUR {
+            return Some(OutResponse::Rejected);
+        }
+
+        let accepted = if let Some((index, clock)) = clock_source(self.entities, id) {
+            match (selector, data) {
+                (CS_SAM_FREQ_CONTROL, &[b0, b1, b2, b3]) => {
+                    let rate = u32::from_le_bytes([b0, b1, b2, b3]);
+                    let supported = clock.sample_rates.contains(&rate);
+                    if supported {
+                        debug!("uac2: clock {} set to {} Hz", id, rate);
+                        self.shared.with(|v| v.sample_rates[index] = rate);
+                    }
+                    supported
+                }
+                _ => false,
+            }
+        } else if let Some((index, unit)) = feature_unit(self.entities, id) {
+            let channel = channel as usize;
+            match (selector, data) {
+                _ if channel > unit.channels as usize => false,
+                (FU_MUTE_CONTROL, &[mute]) if unit.mute => {
+                    self.shared.with(|v| v.channels[index][channel].mute = mute != 0);
+                    true
+                }
+                (FU_VOLUME_CONTROL, &[b0, b1]) if unit.volume.is_some() => {
+                    let range = unwrap!(unit.volume);
+                    let volume = i16::from_le_bytes([b0, b1]).clamp(range.min, range.max);
+                    self.shared.with(|v| v.channels[index][channel].volume = volume);
+                    true
+                }
+                _ => false,
+            }
+        } else {
+            false
+        };
+
+        if accepted {
+            self.shared.signal_changed();
+            Some(OutResponse::Accepted)
+        } else {
+            Some(OutResponse::Rejected)
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let (id, selector, channel) = self.target(&req)?;
+
+        let len = if let Some((index, clock)) = clock_source(self.entities, id) {
+            match (req.request, selector) {
+                (CUR, CS_SAM_FREQ_CONTROL) => {
+                    let rate = self.shared.with(|v| v.sample_rates[index]);
+                    buf[..4].copy_from_slice(&rate.to_le_bytes());
+                    4
+                }
+                (RANGE, CS_SAM_FREQ_CONTROL) => {
+                    let len = 2 + clock.sample_rates.len() * 12;
+                    if len > buf.len() {
+                        warn!("uac2: sample rates of clock {} don't fit in the control buffer", id);
+                        return Some(InResponse::Rejected);
+                    }
+                    buf[..2].copy_from_slice(&(clock.sample_rates.len() as u16).to_le_bytes());
+                    for (range, rate) in buf[2..len].chunks_mut(12).zip(clock.sample_rates) {
+                        // Each sample rate is a range with a single value.
+                        range[0..4].copy_from_slice(&rate.to_le_bytes());
+                        range[4..8].copy_from_slice(&rate.to_le_bytes());
+                        range[8..12].fill(0);
+                    }
+                    len
+                }
+                (CUR, CS_CLOCK_VALID_CONTROL) => {
+                    buf[0] = 1;
+                    1
+                }
+                _ => return Some(InResponse::Rejected),
+            }
+        } else if let Some((index, unit)) = feature_unit(self.entities, id) {
+            let channel = channel as usize;
+            if channel > unit.channels as usize {
+                return Some(InResponse::Rejected);
+            }
+            let values = self.shared.with(|v| v.channels[index][channel]);
+            match (req.request, selector, unit.volume) {
+                (CUR, FU_MUTE_CONTROL, _) if unit.mute => {
+                    buf[0] = values.mute as u8;
+                    1
+                }
+                (CUR, FU_VOLUME_CONTROL, Some(_)) => {
+                    buf[..2].copy_from_slice(&values.volume.to_le_bytes());
+                    2
+                }
+                (RANGE, FU_VOLUME_CONTROL, Some(range)) => {
+                    buf[..2].copy_from_slice(&1u16.to_le_bytes());
+                    buf[2..4].copy_from_slice(&range.min.to_le_bytes());
+                    buf[4..6].copy_from_slice(&range.max.to_le_bytes());
+                    buf[6..8].copy_from_slice(&range.resolution.to_le_bytes());
+                    8
+                }
+                _ => return Some(InResponse::Rejected),
+            }
+        } else {
+            return Some(InResponse::Rejected);
+        };
+
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// USB Audio Class 2.0 function.
+///
+/// Creating it writes the audio control interface, then streaming interfaces are added with
+/// [`stream_in`](Self::stream_in) and [`stream_out`](Self::stream_out). The function is complete
+/// once this is dropped.
+pub struct Uac2Class<'a, 'd, D: Driver<'d>> {
+    func: FunctionBuilder<'a, 'd, D>,
+    shared: &'d ControlShared,
+    entities: &'d [Entity<'d>],
+    high_speed: bool,
+    stream_count: usize,
+}
+
+impl<'a, 'd, D: Driver<'d>> Uac2Class<'a, 'd, D> {
+    /// Creates a new audio function, with the topology given in `config`.
+    pub fn new(builder: &'a mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        assert!(
+            clock_sources(config.entities).count() <= MAX_CLOCK_SOURCES,
+            "too many clock sources"
+        );
+        assert!(
+            clock_sources(config.entities).all(|c| !c.sample_rates.is_empty()),
+            "clock sources need at least one sample rate"
+        );
+        assert!(
+            feature_units(config.entities).count() <= MAX_FEATURE_UNITS,
+            "too many feature units"
+        );
+        assert!(
+            feature_units(config.entities).all(|f| f.channels as usize <= MAX_CHANNELS),
+            "too many channels in a feature unit"
+        );
+
+        let shared = &state.shared;
+        shared.reset(config.entities);
+        let control = state.control.write(Control {
+            entities: config.entities,
+            shared,
+        });
+        builder.handler(control);
+
+        let mut func = builder.function(USB_AUDIO_CLASS, FUNCTION_SUBCLASS_UNDEFINED, IP_VERSION_02_00);
+
+        // Audio control interface
+        let mut iface = func.interface();
+        shared.control_if.store(iface.interface_number().0, Ordering::Relaxed);
+        let mut alt = iface.alt_setting(USB_AUDIO_CLASS, AUDIOCONTROL_SUBCLASS, IP_VERSION_02_00, None);
+
+        let total_len = 9 + config.entities.iter().map(Entity::descriptor_len).sum::<usize>();
+        let [l0, l1] = (total_len as u16).to_le_bytes();
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                HEADER,
+                0x00,
+                0x02, // bcdADC
+                config.category,
+                l0,
+                l1,   // wTotalLength
+                0x00, // bmControls
+            ],
+        );
+        for entity in config.entities {
+            entity.write_descriptor(&mut alt);
+        }
+
+        Self {
+            func,
+            shared,
+            entities: config.entities,
+            high_speed: config.high_speed,
+            stream_count: 0,
+        }
+    }
+
+    /// Handle to the values of the controls set by the host.
+    pub fn controls(&self) -> Controls<'d> {
+        Controls {
+            shared: self.shared,
+            entities: self.entities,
+        }
+    }
+
+    /// Add a streaming interface sending audio to the host.
+    pub fn stream_in(&mut self, config: &StreamConfig<'_>) -> StreamIn<'d, D> {
+        let max_packet_size = config.max_packet_size;
+        let mut ep = None;
+        let slot = self.stream(config, |alt| {
+            shared_endpoint(
+                alt,
+                &mut ep,
+                |alt| alt.alloc_endpoint_in(EndpointType::Isochronous, max_packet_size, 1),
+                SynchronizationType::Asynchronous,
+                UsageType::DataEndpoint,
+            );
+            alt.descriptor(CS_ENDPOINT, &DATA_ENDPOINT_DESCRIPTOR);
+        });
+
+        StreamIn {
+            ep: unwrap!(ep),
+            alt: &self.shared.stream_alts[slot],
+        }
+    }
+
+    /// Add a streaming interface receiving audio from the host, along with its feedback endpoint.
+    pub fn stream_out(&mut self, config: &StreamConfig<'_>) -> (StreamOut<'d, D>, Feedback<'d, D>) {
+        let max_packet_size = config.max_packet_size;
+        let high_speed = self.high_speed;
+        let (feedback_size, feedback_interval) = match high_speed {
+            // 16.16 format, every 8 microframes.
+            true => (4, 4),
+            // 10.14 format, every frame.
+            false => (3, 1),
+        };
+
+        let mut ep = None;
+        let mut feedback_ep = None;
+        let slot = self.stream(config, |alt| {
+            shared_endpoint(
+                alt,
+                &mut ep,
+                |alt| alt.alloc_endpoint_out(EndpointType::Isochronous, max_packet_size, 1),
+                SynchronizationType::Asynchronous,
+                UsageType::DataEndpoint,
+            );
+            alt.descriptor(CS_ENDPOINT, &DATA_ENDPOINT_DESCRIPTOR);
+            shared_endpoint(
+                alt,
+                &mut feedback_ep,
+                |alt| alt.alloc_endpoint_in(EndpointType::Isochronous, feedback_size, feedback_interval),
+                SynchronizationType::NoSynchronization,
+                UsageType::FeedbackEndpoint,
+            );
+        });
+
+        let alt = &self.shared.stream_alts[slot];
+        (
+            StreamOut { ep: unwrap!(ep), alt },
+            Feedback {
+                ep: unwrap!(feedback_ep),
+                high_speed,
+                alt,
+            },
+        )
+    }
+
+    /// Write a streaming interface, calling `endpoints` to write the endpoints of each alternate
+    /// setting with a format. Returns the slot of the stream in the shared state.
+    fn stream(
+        &mut self,
+        config: &StreamConfig<'_>,
+        mut endpoints: impl FnMut(&mut InterfaceAltBuilder<'_, 'd, D>),
+    ) -> usize {
+        assert!(self.stream_count < MAX_STREAMS, "too many streaming interfaces");
+        assert!(!config.formats.is_empty(), "streams need at least one format");
+
+        let mut iface = self.func.interface();
+        let slot = self.stream_count;
+        self.stream_count += 1;
+        self.shared.stream_ifaces[slot].store(iface.interface_number().0, Ordering::Relaxed);
+
+        // Alternate setting 0, without endpoints, selected while the stream is stopped.
+        iface.alt_setting(USB_AUDIO_CLASS, AUDIOSTREAMING_SUBCLASS, IP_VERSION_02_00, None);
+
+        let [c0, c1, c2, c3] = config.channel_config.to_le_bytes();
+        let [f0, f1, f2, f3] = PCM.to_le_bytes();
+        for format in config.formats {
+            let mut alt = iface.alt_setting(USB_AUDIO_CLASS, AUDIOSTREAMING_SUBCLASS, IP_VERSION_02_00, None);
+            alt.descriptor(
+                CS_INTERFACE,
+                &[
+                    AS_GENERAL,
+                    config.terminal,
+                    0x00, // bmControls
+                    FORMAT_TYPE_I,
+                    f0,
+                    f1,
+                    f2,
+                    f3, // bmFormats
+                    config.channels,
+                    c0,
+                    c1,
+                    c2,
+                    c3,   // bmChannelConfig
+                    0x00, // iChannelNames
+                ],
+            );
+            alt.descriptor(
+                CS_INTERFACE,
+                &[FORMAT_TYPE, FORMAT_TYPE_I, format.subslot_size, format.bit_resolution],
+            );
+            endpoints(&mut alt);
+        }
+
+        slot
+    }
+}
+
+/// Class-specific descriptor of the data endpoints: no controls, and no lock delay.
+const DATA_ENDPOINT_DESCRIPTOR: [u8; 6] = [EP_GENERAL, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// Write the descriptor of an endpoint shared by all alternate settings of a streaming interface,
+/// allocating it with `alloc` in the first one.
+fn shared_endpoint<'d, D: Driver<'d>, E: Endpoint>(
+    alt: &mut InterfaceAltBuilder<'_, 'd, D>,
+    ep: &mut Option<E>,
+    alloc: impl FnOnce(&mut InterfaceAltBuilder<'_, 'd, D>) -> E,
+    synchronization_type: SynchronizationType,
+    usage_type: UsageType,
+) {
+    let info = *ep.get_or_insert_with(|| alloc(alt)).info();
+    alt.endpoint_descriptor(&info, synchronization_type, usage_type, &[]);
+}
+
+/// Values of the controls set by the host.
+#[derive(Clone, Copy)]
+pub struct Controls<'d> {
+    shared: &'d ControlShared,
+    entities: &'d [Entity<'d>],
+}
+
+impl<'d> Controls<'d> {
+    /// Sample rate of a clock source, in Hz.
+    ///
+    /// Returns `None` if there is no clock source with this ID.
+    pub fn sample_rate(&self, clock: u8) -> Option<u32> {
+        let (index, _) = clock_source(self.entities, clock)?;
+        Some(self.shared.with(|v| v.sample_rates[index]))
+    }
+
+    /// Whether a channel of a feature unit is muted.
+    ///
+    /// Returns `None` if there is no such channel, or if it has no mute control.
+    pub fn mute(&self, unit: u8, channel: u8) -> Option<bool> {
+        let (index, unit) = feature_unit(self.entities, unit)?;
+        if !unit.mute || channel > unit.channels {
+            return None;
+        }
+        Some(self.shared.with(|v| v.channels[index][channel as usize].mute))
+    }
+
+    /// Volume of a channel of a feature unit, in 1/256 dB.
+    ///
+    /// Returns `None` if there is no such channel, or if it has no volume control.
+    pub fn volume(&self, unit: u8, channel: u8) -> Option<i16> {
+        let (index, unit) = feature_unit(self.entities, unit)?;
+        if unit.volume.is_none() || channel > unit.channels {
+            return None;
+        }
+        Some(self.shared.with(|v| v.channels[index][channel as usize].volume))
+    }
+
+    /// Wait for the host to change a control, or to start or stop a stream.
+    ///
+    /// Only a single task can wait for changes.
+    pub async fn changed(&self) {
+        self.shared.changed().await
+    }
+}
+
+/// Streaming interface sending audio to the host.
+pub struct StreamIn<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    alt: &'d AtomicU8,
+}
+
+impl<'d, D: Driver<'d>> StreamIn<'d, D> {
+    /// Index of the format selected by the host, or `None` if the stream is stopped.
+    pub fn format(&self) -> Option<usize> {
+        format(self.alt)
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.ep.info().max_packet_size
+    }
+
+    /// Waits for the host to start the stream.
+    pub async fn wait_enabled(&mut self) {
+        self.ep.wait_enabled().await;
+    }
+
+    /// Writes the samples of a (micro)frame.
+    ///
+    /// The number of samples should follow the sample rate: at 44.1 kHz, it is 44 in 9 frames out
+    /// of 10 and 45 in the other at full speed.
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.ep.write(data).await
+    }
+}
+
+/// Streaming interface receiving audio from the host.
+pub struct StreamOut<'d, D: Driver<'d>> {
+    ep: D::EndpointOut,
+    alt: &'d AtomicU8,
+}
+
+impl<'d, D: Driver<'d>> StreamOut<'d, D> {
+    /// Index of the format selected by the host, or `None` if the stream is stopped.
+    pub fn format(&self) -> Option<usize> {
+        format(self.alt)
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.ep.info().max_packet_size
+    }
+
+    /// Waits for the host to start the stream.
+    pub async fn wait_enabled(&mut self) {
+        self.ep.wait_enabled().await;
+    }
+
+    /// Reads the samples of a (micro)frame.
+    ///
+    /// `buf` must be at least `max_packet_size` bytes long.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        self.ep.read(buf).await
+    }
+}
+
+/// Feedback endpoint of a [`StreamOut`], reporting the rate at which the device consumes samples.
+///
+/// The host adjusts the number of samples it sends to match it, so that the device buffers neither
+/// overflow nor underflow.
+pub struct Feedback<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    high_speed: bool,
+    alt: &'d AtomicU8,
+}
+
+impl<'d, D: Driver<'d>> Feedback<'d, D> {
+    /// Index of the format selected by the host, or `None` if the stream is stopped.
+    pub fn format(&self) -> Option<usize> {
+        format(self.alt)
+    }
+
+    /// Waits for the host to start the stream.
+    pub async fn wait_enabled(&mut self) {
+        self.ep.wait_enabled().await;
+    }
+
+    /// Feedback value of a sample rate in Hz, as number of samples per (micro)frame in 16.16
+    /// fixed-point format.
+    ///
+    /// This is the value to report while the device clock runs exactly at this rate.
+    pub fn nominal(&self, sample_rate: u32) -> u32 {
+        let frames_per_second = if self.high_speed { 8000 } else { 1000 };
+        (((sample_rate as u64) << 16) / frames_per_second) as u32
+    }
+
+    /// Reports the rate at which the device consumes samples, as number of samples per
+    /// (micro)frame in 16.16 fixed-point format.
+    ///
+    /// It is sent to the host as is at high speed, and converted to the 10.14 format at full speed.
+    pub async fn write(&mut self, rate: u32) -> Result<(), EndpointError> {
+        if self.high_speed {
+            self.ep.write(&rate.to_le_bytes()).await
+        } else {
+            self.ep.write(&(rate >> 2).to_le_bytes()[..3]).await
+        }
+    }
+}
+
+fn format(alt: &AtomicU8) -> Option<usize> {
+    (alt.load(Ordering::Relaxed) as usize).checked_sub(1)
+}
@@ -0,0 +1,787 @@
+//! USB Video Class 1.1 implementation.
+//!
+//! A video function is made of a video control interface, which describes a camera terminal
+//! connected to a streaming output terminal, and of a video streaming interface, which carries
+//! the frames to the host.
+//!
+//! The streaming interface lists the [`Format`]s of the video, MJPEG or uncompressed, each with the
+//! resolutions and frame rates of its [`Frame`]s. Before starting the stream, the host negotiates
+//! the settings it uses with probe and commit control requests, and the device picks the nearest
+//! settings it supports: [`UvcClass::wait_streaming`] returns the committed [`Settings`].
+//!
+//! Frames are sent with [`UvcClass::write_frame`], which splits them in packets, each starting with a
+//! payload header. Streams use either a bulk endpoint, or an isochronous endpoint in alternate
+//! setting 1 of the streaming interface, selected by the host when the stream starts.
+//!
+//! Video functions require an interface association descriptor, so the device
+//! [`Config`](crate::Config) must have `composite_with_iads` set.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::builder::InterfaceAltBuilder;
+use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use crate::descriptor::{SynchronizationType, UsageType};
+use crate::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointType};
+use crate::types::InterfaceNumber;
+use crate::{Builder, Handler};
+
+/// This should be used as `device_class` when building the `UsbDevice`.
+pub const USB_VIDEO_CLASS: u8 = 0x0e;
+
+const VIDEOCONTROL_SUBCLASS: u8 = 0x01;
+const VIDEOSTREAMING_SUBCLASS: u8 = 0x02;
+const VIDEO_INTERFACE_COLLECTION_SUBCLASS: u8 = 0x03;
+const PC_PROTOCOL_UNDEFINED: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+
+// Video control interface descriptor subtypes
+const VC_HEADER: u8 = 0x01;
+const VC_INPUT_TERMINAL: u8 = 0x02;
+const VC_OUTPUT_TERMINAL: u8 = 0x03;
+
+// Video streaming interface descriptor subtypes
+const VS_INPUT_HEADER: u8 = 0x01;
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+const VS_COLORFORMAT: u8 = 0x0d;
+
+// Terminal types
+const TT_STREAMING: u16 = 0x0101;
+const ITT_CAMERA: u16 = 0x0201;
+
+// Class-specific requests
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+const GET_MIN: u8 = 0x82;
+const GET_MAX: u8 = 0x83;
+const GET_LEN: u8 = 0x85;
+const GET_INFO: u8 = 0x86;
+const GET_DEF: u8 = 0x87;
+
+// Video streaming interface control selectors
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+/// Version of the specification, in BCD.
+const BCD_UVC: u16 = 0x0110;
+/// Clock frequency of the timestamps of the payload headers, which are not used.
+const CLOCK_FREQUENCY: u32 = 48_000_000;
+
+const CAMERA_TERMINAL_ID: u8 = 1;
+const OUTPUT_TERMINAL_ID: u8 = 2;
+
+/// Length of the payload header of the packets.
+const HEADER_LEN: usize = 2;
+// Payload header bits
+const HEADER_FID: u8 = 0x01;
+const HEADER_EOF: u8 = 0x02;
+const HEADER_EOH: u8 = 0x80;
+
+/// Maximum number of formats of a stream.
+pub const MAX_FORMATS: usize = 4;
+/// Maximum number of frame intervals of a frame.
+pub const MAX_FRAME_INTERVALS: usize = 8;
+/// Maximum packet size of the streaming endpoint.
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// Encoding of the frames of a [`Format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Encoding {
+    /// Motion-JPEG: each frame is a JPEG image.
+    ///
+    /// Frames must not be larger than `width * height * 2` bytes, the size of a YUY2 frame.
+    Mjpeg,
+    /// Uncompressed frames, with the pixel format identified by a GUID.
+    Uncompressed {
+        /// GUID of the pixel format.
+        guid: [u8; 16],
+        /// Number of bits per pixel.
+        bits_per_pixel: u8,
+    },
+}
+
+impl Encoding {
+    /// Uncompressed YUY2: 4:2:2 packed Y, U, Y, V bytes.
+    pub const YUY2: Self = Self::Uncompressed {
+        guid: *b"YUY2\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71",
+        bits_per_pixel: 16,
+    };
+
+    /// Uncompressed NV12: 4:2:0 with a Y plane followed by an interleaved UV plane.
+    pub const NV12: Self = Self::Uncompressed {
+        guid: *b"NV12\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71",
+        bits_per_pixel: 12,
+    };
+
+    fn frame_size(&self, frame: &Frame<'_>) -> u32 {
+        let bits_per_pixel = match self {
+            Self::Mjpeg => 16,
+            Self::Uncompressed { bits_per_pixel, .. } => *bits_per_pixel as u32,
+        };
+        frame.width as u32 * frame.height as u32 * bits_per_pixel / 8
+    }
+}
+
+/// Frame size and rates of a [`Format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame<'a> {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// Supported intervals between frames, in 100 ns units: 333_333 is 30 frames per second.
+    ///
+    /// The first one is the default. There can be up to [`MAX_FRAME_INTERVALS`] of them.
+    pub frame_intervals: &'a [u32],
+}
+
+/// Video format of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Format<'a> {
+    /// Encoding of the frames.
+    pub encoding: Encoding,
+    /// Supported frame sizes and rates. The first one is the default.
+    pub frames: &'a [Frame<'a>],
+}
+
+/// Transfer type of the streaming endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transfer {
+    /// Bulk endpoint, in the only alternate setting of the streaming interface.
+    ///
+    /// The stream starts when the host commits its settings. It uses the bandwidth left by
+    /// the other endpoints.
+    Bulk,
+    /// Isochronous endpoint, in alternate setting 1 of the streaming interface.
+    ///
+    /// The stream starts when the host selects alternate setting 1, and stops when it selects
+    /// alternate setting 0. The bandwidth is reserved while streaming.
+    Isochronous,
+}
+
+/// Configuration of the video function.
+pub struct Config<'a> {
+    /// Formats of the stream. The first one is the default.
+    ///
+    /// There can be up to [`MAX_FORMATS`] of them.
+    pub formats: &'a [Format<'a>],
+    /// Transfer type of the streaming endpoint.
+    pub transfer: Transfer,
+    /// Maximum packet size of the streaming endpoint, up to [`MAX_PACKET_SIZE`].
+    pub max_packet_size: u16,
+}
+
+/// Video settings committed by the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Settings {
+    /// Index of the format in [`Config::formats`].
+    pub format: usize,
+    /// Index of the frame in [`Format::frames`].
+    pub frame: usize,
+    /// Interval between frames, in 100 ns units.
+    pub frame_interval: u32,
+}
+
+/// Internal state for the USB Video Class function.
+pub struct State<'d> {
+    control: MaybeUninit<Control<'d>>,
+    shared: ControlShared,
+}
+
+impl<'d> Default for State<'d> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'d> State<'d> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            control: MaybeUninit::uninit(),
+            shared: ControlShared::new(),
+        }
+    }
+}
+
+/// Video probe and commit controls.
+#[derive(Clone, Copy, Default)]
+struct ProbeCommit {
+    hint: u16,
+    format_index: u8,
+    frame_index: u8,
+    frame_interval: u32,
+    max_video_frame_size: u32,
+    max_payload_transfer_size: u32,
+}
+
+impl ProbeCommit {
+    /// Length of the controls in UVC 1.1.
+    const LEN: usize = 34;
+    /// Length of the controls in UVC 1.0, which hosts may still use.
+    const MIN_LEN: usize = 26;
+
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::MIN_LEN {
+            return None;
+        }
+        Some(Self {
+            hint: u16::from_le_bytes([data[0], data[1]]),
+            format_index: data[2],
+            frame_index: data[3],
+            frame_interval: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            max_video_frame_size: u32::from_le_bytes([data[18], data[19], data[20], data[21]]),
+            max_payload_transfer_size: u32::from_le_bytes([data[22], data[23], data[24], data[25]]),
+        })
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let buf = &mut buf[..Self::LEN];
+        buf.fill(0);
+        buf[0..2].copy_from_slice(&self.hint.to_le_bytes());
+        buf[2] = self.format_index;
+        buf[3] = self.frame_index;
+        buf[4..8].copy_from_slice(&self.frame_interval.to_le_bytes());
+        // wKeyFrameRate, wPFrameRate, wCompQuality, wCompWindowSize and wDelay are unused.
+        buf[18..22].copy_from_slice(&self.max_video_frame_size.to_le_bytes());
+        buf[22..26].copy_from_slice(&self.max_payload_transfer_size.to_le_bytes());
+        buf[26..30].copy_from_slice(&CLOCK_FREQUENCY.to_le_bytes());
+        buf[30] = 0x03; // bmFramingInfo: FID and EOF are used.
+        buf[31] = 0x01; // bPreferedVersion
+        buf[32] = 0x01; // bMinVersion
+        buf[33] = 0x01; // bMaxVersion
+        Self::LEN
+    }
+}
+
+/// Streaming state.
+struct Values {
+    probe: ProbeCommit,
+    commit: Option<ProbeCommit>,
+    /// Settings of the running stream.
+    streaming: Option<Settings>,
+    waker: WakerRegistration,
+}
+
+/// Shared data between Control and the class.
+struct ControlShared {
+    /// Interface number of the video control interface.
+    control_if: AtomicU8,
+    /// Interface number of the video streaming interface.
+    stream_if: AtomicU8,
+    values: CriticalSectionMutex<RefCell<Values>>,
+}
+
+impl ControlShared {
+    const fn new() -> Self {
+        Self {
+            control_if: AtomicU8::new(0),
+            stream_if: AtomicU8::new(0),
+            values: CriticalSectionMutex::new(RefCell::new(Values {
+                probe: ProbeCommit {
+                    hint: 0,
+                    format_index: 0,
+                    frame_index: 0,
+                    frame_interval: 0,
+                    max_video_frame_size: 0,
+                    max_payload_transfer_size: 0,
+                },
+                commit: None,
+                streaming: None,
+                waker: WakerRegistration::new(),
+            })),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Values) -> R) -> R {
+        self.values.lock(|v| f(&mut v.borrow_mut()))
+    }
+
+    /// Start or stop the stream.
+    fn set_streaming(&self, streaming: Option<Settings>) {
+        self.with(|v| {
+            v.streaming = streaming;
+            v.waker.wake();
+        });
+    }
+}
+
+struct Control<'d> {
+    formats: &'d [Format<'d>],
+    transfer: Transfer,
+    max_packet_size: u16,
+    shared: &'d ControlShared,
+}
+
+impl<'d> Control<'d> {
+    /// Control selector of a request to the video streaming interface.
+    fn target(&self, req: &Request) -> Option<u8> {
+        let iface = req.index as u8;
+        if (req.request_type, req.recipient) != (RequestType::Class, Recipient::Interface) {
+            return None;
+        }
+        if iface == self.shared.stream_if.load(Ordering::Relaxed) {
+            Some((req.value >> 8) as u8)
+        } else if iface == self.shared.control_if.load(Ordering::Relaxed) {
+            // The camera has no controls.
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Settings the device supports nearest to the ones requested by the host.
+    fn negotiate(&self, req: &ProbeCommit) -> ProbeCommit {
+        let format_index = match req.format_index as usize {
+            i @ 1.. if i <= self.formats.len() => i,
+            _ => 1,
+        };
+        let format = &self.formats[format_index - 1];
+        let frame_index = match req.frame_index as usize {
+            i @ 1.. if i <= format.frames.len() => i,
+            _ => 1,
+        };
+        let frame = &format.frames[frame_index - 1];
+        let frame_interval = match req.frame_interval {
+            0 => frame.frame_intervals[0],
+            requested => unwrap!(frame
+                .frame_intervals
+                .iter()
+                .copied()
+                .min_by_key(|interval| interval.abs_diff(requested))),
+        };
+
+        ProbeCommit {
+            hint: req.hint,
+            format_index: format_index as u8,
+            frame_index: frame_index as u8,
+            frame_interval,
+            max_video_frame_size: format.encoding.frame_size(frame),
+            // Each packet is a payload.
+            max_payload_transfer_size: self.max_packet_size as u32,
+        }
+    }
+
+    fn settings(commit: &ProbeCommit) -> Settings {
+        Settings {
+            format: commit.format_index as usize - 1,
+            frame: commit.frame_index as usize - 1,
+            frame_interval: commit.frame_interval,
+        }
+    }
+
+    fn reset(&mut self) {
+        let default = self.negotiate(&ProbeCommit::default());
+        self.shared.with(|v| {
+            v.probe = default;
+            v.commit = None;
+        });
+        self.shared.set_streaming(None);
+    }
+}
+
+impl<'d> Handler for Control<'d> {
+    fn reset(&mut self) {
+        Control::reset(self);
+    }
+
+    fn configured(&mut self, _configured: bool) {
+        self.reset();
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if self.transfer != Transfer::Isochronous || iface.0 != self.shared.stream_if.load(Ordering::Relaxed) {
+            return;
+        }
+
+        debug!("uvc: stream set to alt setting {}", alternate_setting);
+        let streaming = match alternate_setting {
+            0 => None,
+            _ => Some(match self.shared.with(|v| v.commit) {
+                Some(commit) => Self::settings(&commit),
+                None => Self::settings(&self.negotiate(&ProbeCommit::default())),
+            }),
+        };
+        self.shared.set_streaming(streaming);
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        let selector = self.target(&req)?;
+        if req.request != SET_CUR {
+            return Some(OutResponse::Rejected);
+        }
+        let Some(requested) = ProbeCommit::parse(data) else {
+            return Some(OutResponse::Rejected);
+        };
+
+        let negotiated = self.negotiate(&requested);
+        match selector {
+            VS_PROBE_CONTROL => self.shared.with(|v| v.probe = negotiated),
+            VS_COMMIT_CONTROL => {
+                let settings = Self::settings(&negotiated);
+                debug!(
+                    "uvc: committed format {} frame {} interval {}",
+                    settings.format, settings.frame, settings.frame_interval
+                );
+                self.shared.with(|v| v.commit = Some(negotiated));
+                if self.transfer == Transfer::Bulk {
+                    self.shared.set_streaming(Some(settings));
+                }
+            }
+            _ => return Some(OutResponse::Rejected),
+        }
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        let selector = self.target(&req)?;
+        if selector != VS_PROBE_CONTROL && selector != VS_COMMIT_CONTROL {
+            return Some(InResponse::Rejected);
+        }
+
+        let len = match req.request {
+            GET_CUR => {
+                let values = self.shared.with(|v| match selector {
+                    VS_PROBE_CONTROL => v.probe,
+                    _ => v.commit.unwrap_or(v.probe),
+                });
+                values.write(buf)
+            }
+            GET_MIN | GET_MAX | GET_DEF => self.negotiate(&ProbeCommit::default()).write(buf),
+            GET_LEN => {
+                buf[..2].copy_from_slice(&(ProbeCommit::LEN as u16).to_le_bytes());
+                2
+            }
+            GET_INFO => {
+                // Supports GET and SET requests.
+                buf[0] = 0x03;
+                1
+            }
+            _ => return Some(InResponse::Rejected),
+        };
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// Write the descriptors of a format, its frames and its color matching.
+fn write_format<'d, D: Driver<'d>>(alt: &mut InterfaceAltBuilder<'_, 'd, D>, index: u8, format: &Format<'_>) {
+    let num_frames = format.frames.len() as u8;
+    let frame_subtype = match format.encoding {
+        Encoding::Mjpeg => {
+            alt.descriptor(
+                CS_INTERFACE,
+                &[
+                    VS_FORMAT_MJPEG,
+                    index,
+                    num_frames,
+                    0x01, // bmFlags: fixed size samples
+                    0x01, // bDefaultFrameIndex
+                    0x00, // bAspectRatioX
+                    0x00, // bAspectRatioY
+                    0x00, // bmInterlaceFlags
+                    0x00, // bCopyProtect
+                ],
+            );
+            VS_FRAME_MJPEG
+        }
+        Encoding::Uncompressed { guid, bits_per_pixel } => {
+            let mut desc = [0; 25];
+            desc[..3].copy_from_slice(&[VS_FORMAT_UNCOMPRESSED, index, num_frames]);
+            desc[3..19].copy_from_slice(&guid);
+            desc[19..].copy_from_slice(&[
+                bits_per_pixel,
+                0x01, // bDefaultFrameIndex
+                0x00, // bAspectRatioX
+                0x00, // bAspectRatioY
+                0x00, // bmInterlaceFlags
+                0x00, // bCopyProtect
+            ]);
+            alt.descriptor(CS_INTERFACE, &desc);
+            VS_FRAME_UNCOMPRESSED
+        }
+    };
+
+    for (i, frame) in format.frames.iter().enumerate() {
+        let frame_size = format.encoding.frame_size(frame);
+        let bit_rate = |interval: u32| (frame_size as u64 * 8 * 10_000_000 / interval as u64) as u32;
+        let min_bit_rate = frame.frame_intervals.iter().map(|i| bit_rate(*i)).min().unwrap_or(0);
+        let max_bit_rate = frame.frame_intervals.iter().map(|i| bit_rate(*i)).max().unwrap_or(0);
+
+        let mut desc = [0; 24 + 4 * MAX_FRAME_INTERVALS];
+        desc[0] = frame_subtype;
+        desc[1] = i as u8 + 1; // bFrameIndex
+        desc[2] = 0x00; // bmCapabilities
+        desc[3..5].copy_from_slice(&frame.width.to_le_bytes());
+        desc[5..7].copy_from_slice(&frame.height.to_le_bytes());
+        desc[7..11].copy_from_slice(&min_bit_rate.to_le_bytes());
+        desc[11..15].copy_from_slice(&max_bit_rate.to_le_bytes());
+        desc[15..19].copy_from_slice(&frame_size.to_le_bytes()); // dwMaxVideoFrameBufferSize
+        desc[19..23].copy_from_slice(&frame.frame_intervals[0].to_le_bytes()); // dwDefaultFrameInterval
+        desc[23] = frame.frame_intervals.len() as u8; // bFrameIntervalType: discrete intervals
+        for (j, interval) in frame.frame_intervals.iter().enumerate() {
+            desc[24 + 4 * j..28 + 4 * j].copy_from_slice(&interval.to_le_bytes());
+        }
+        alt.descriptor(CS_INTERFACE, &desc[..24 + 4 * frame.frame_intervals.len()]);
+    }
+
+    alt.descriptor(
+        CS_INTERFACE,
+        &[
+            VS_COLORFORMAT,
+            0x01, // bColorPrimaries: BT.709, sRGB
+            0x01, // bTransferCharacteristics: BT.709
+            0x04, // bMatrixCoefficients: SMPTE 170M
+        ],
+    );
+}
+
+/// USB Video Class function, streaming video from a camera to the host.
+pub struct UvcClass<'d, D: Driver<'d>> {
+    ep: D::EndpointIn,
+    shared: &'d ControlShared,
+    /// Frame ID bit of the payload headers, toggled for each frame.
+    frame_id: bool,
+}
+
+impl<'d, D: Driver<'d>> UvcClass<'d, D> {
+    /// Creates a new video function, with the formats given in `config`.
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State<'d>, config: Config<'d>) -> Self {
+        assert!(!config.formats.is_empty(), "video functions need at least one format");
+        assert!(config.formats.len() <= MAX_FORMATS, "too many formats");
+        assert!(
+            config.formats.iter().all(|f| !f.frames.is_empty()),
+            "formats need at least one frame"
+        );
+        assert!(
+            config
+                .formats
+                .iter()
+                .flat_map(|f| f.frames)
+                .all(|f| (1..=MAX_FRAME_INTERVALS).contains(&f.frame_intervals.len())),
+            "frames need between 1 and MAX_FRAME_INTERVALS frame intervals"
+        );
+        assert!(
+            (config.max_packet_size as usize) > HEADER_LEN && config.max_packet_size as usize <= MAX_PACKET_SIZE,
+            "invalid max_packet_size"
+        );
+
+        let shared = &state.shared;
+        let control = state.control.write(Control {
+            formats: config.formats,
+            transfer: config.transfer,
+            max_packet_size: config.max_packet_size,
+            shared,
+        });
+        control.reset();
+        builder.handler(control);
+
+        let mut func = builder.function(
+            USB_VIDEO_CLASS,
+            VIDEO_INTERFACE_COLLECTION_SUBCLASS,
+            PC_PROTOCOL_UNDEFINED,
+        );
+
+        // Video control interface
+        let mut iface = func.interface();
+        let control_if = iface.interface_number().0;
+        // The streaming interface is the next one.
+        let stream_if = control_if + 1;
+        shared.control_if.store(control_if, Ordering::Relaxed);
+        shared.stream_if.store(stream_if, Ordering::Relaxed);
+        let mut alt = iface.alt_setting(USB_VIDEO_CLASS, VIDEOCONTROL_SUBCLASS, PC_PROTOCOL_UNDEFINED, None);
+
+        // Header, followed by the camera terminal and the output terminal.
+        let total_len: u16 = 13 + 18 + 9;
+        let mut header = [0; 11];
+        header[0] = VC_HEADER;
+        header[1..3].copy_from_slice(&BCD_UVC.to_le_bytes());
+        header[3..5].copy_from_slice(&total_len.to_le_bytes());
+        header[5..9].copy_from_slice(&CLOCK_FREQUENCY.to_le_bytes());
+        header[9] = 0x01; // bInCollection
+        header[10] = stream_if; // baInterfaceNr
+        alt.descriptor(CS_INTERFACE, &header);
+        let [t0, t1] = ITT_CAMERA.to_le_bytes();
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_INPUT_TERMINAL,
+                CAMERA_TERMINAL_ID,
+                t0,
+                t1,   // wTerminalType
+                0x00, // bAssocTerminal
+                0x00, // iTerminal
+                0x00,
+                0x00, // wObjectiveFocalLengthMin
+                0x00,
+                0x00, // wObjectiveFocalLengthMax
+                0x00,
+                0x00, // wOcularFocalLength
+                0x03, // bControlSize
+                0x00,
+                0x00,
+                0x00, // bmControls
+            ],
+        );
+        let [t0, t1] = TT_STREAMING.to_le_bytes();
+        alt.descriptor(
+            CS_INTERFACE,
+            &[
+                VC_OUTPUT_TERMINAL,
+                OUTPUT_TERMINAL_ID,
+                t0,
+                t1,   // wTerminalType
+                0x00, // bAssocTerminal
+                CAMERA_TERMINAL_ID,
+                0x00, // iTerminal
+            ],
+        );
+
+        // Video streaming interface
+        let mut iface = func.interface();
+        assert_eq!(iface.interface_number().0, stream_if);
+        let mut alt = iface.alt_setting(USB_VIDEO_CLASS, VIDEOSTREAMING_SUBCLASS, PC_PROTOCOL_UNDEFINED, None);
+        let ep_type = match config.transfer {
+            Transfer::Bulk => EndpointType::Bulk,
+            Transfer::Isochronous => EndpointType::Isochronous,
+        };
+        let ep = alt.alloc_endpoint_in(ep_type, config.max_packet_size, 1);
+
+        let formats_len: usize = config
+            .formats
+            .iter()
+            .map(|f| {
+                let format_len = match f.encoding {
+                    Encoding::Mjpeg => 11,
+                    Encoding::Uncompressed { .. } => 27,
+                };
+                let frames_len: usize = f.frames.iter().map(|f| 26 + 4 * f.frame_intervals.len()).sum();
+                format_len + frames_len + 6
+            })
+            .sum();
+        let [l0, l1] = ((13 + config.formats.len() + formats_len) as u16).to_le_bytes();
+        let mut header = [0; 11 + MAX_FORMATS];
+        header[..11].copy_from_slice(&[
+            VS_INPUT_HEADER,
+            config.formats.len() as u8, // bNumFormats
+            l0,
+            l1,                    // wTotalLength
+            ep.info().addr.into(), // bEndpointAddress
+            0x00,                  // bmInfo
+            OUTPUT_TERMINAL_ID,    // bTerminalLink
+            0x00,                  // bStillCaptureMethod
+            0x00,                  // bTriggerSupport
+            0x00,                  // bTriggerUsage
+            0x01,                  // bControlSize
+        ]);
+        // bmaControls: no controls for all formats.
+        alt.descriptor(CS_INTERFACE, &header[..11 + config.formats.len()]);
+
+        for (i, format) in config.formats.iter().enumerate() {
+            write_format(&mut alt, i as u8 + 1, format);
+        }
+
+        match config.transfer {
+            Transfer::Bulk => {
+                alt.endpoint_descriptor(
+                    ep.info(),
+                    SynchronizationType::NoSynchronization,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+            Transfer::Isochronous => {
+                // Alternate setting 0 has no bandwidth, the endpoint is in alternate setting 1.
+                let mut alt = iface.alt_setting(USB_VIDEO_CLASS, VIDEOSTREAMING_SUBCLASS, PC_PROTOCOL_UNDEFINED, None);
+                alt.endpoint_descriptor(
+                    ep.info(),
+                    SynchronizationType::Asynchronous,
+                    UsageType::DataEndpoint,
+                    &[],
+                );
+            }
+        }
+
+        Self {
+            ep,
+            shared,
+            frame_id: false,
+        }
+    }
+
+    /// Settings of the stream, or `None` if it is stopped.
+    pub fn settings(&self) -> Option<Settings> {
+        self.shared.with(|v| v.streaming)
+    }
+
+    /// Gets the maximum packet size in bytes.
+    pub fn max_packet_size(&self) -> u16 {
+        self.ep.info().max_packet_size
+    }
+
+    /// Waits for the host to start the stream, and returns its settings.
+    pub async fn wait_streaming(&mut self) -> Settings {
+        let settings = poll_fn(|cx| {
+            self.shared.with(|v| match v.streaming {
+                Some(settings) => Poll::Ready(settings),
+                None => {
+                    v.waker.register(cx.waker());
+                    Poll::Pending
+                }
+            })
+        })
+        .await;
+        self.ep.wait_enabled().await;
+        settings
+    }
+
+    /// Writes a frame.
+    ///
+    /// It is split in packets, each starting with a payload header. The frame must use the encoding
+    /// and size of the current settings.
+    ///
+    /// Returns [`EndpointError::Disabled`] if the stream stops before the whole frame is sent. The
+    /// host then discards the part it received.
+    pub async fn write_frame(&mut self, frame: &[u8]) -> Result<(), EndpointError> {
+        let max_packet_size = self.ep.info().max_packet_size as usize;
+        let mut packet = [0; MAX_PACKET_SIZE];
+        let mut chunks = frame.chunks(max_packet_size - HEADER_LEN).peekable();
+
+        let mut header_info = HEADER_EOH;
+        if self.frame_id {
+            header_info |= HEADER_FID;
+        }
+        self.frame_id = !self.frame_id;
+
+        loop {
+            if self.settings().is_none() {
+                return Err(EndpointError::Disabled);
+            }
+
+            let chunk = chunks.next().unwrap_or(&[]);
+            let last = chunks.peek().is_none();
+            packet[0] = HEADER_LEN as u8;
+            packet[1] = if last { header_info | HEADER_EOF } else { header_info };
+            packet[HEADER_LEN..HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+            self.ep.write(&packet[..HEADER_LEN + chunk.len()]).await?;
+
+            if last {
+                return Ok(());
+            }
+        }
+    }
+}
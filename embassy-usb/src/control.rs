@@ -119,6 +119,27 @@ impl Request {
         }
     }
 
+    /// Serializes the request into a SETUP packet, as sent by the host.
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let direction = match self.direction {
+            Direction::Out => 0x00,
+            Direction::In => 0x80,
+        };
+        let [value0, value1] = self.value.to_le_bytes();
+        let [index0, index1] = self.index.to_le_bytes();
+        let [length0, length1] = self.length.to_le_bytes();
+        [
+            direction | (self.request_type as u8) << 5 | self.recipient as u8,
+            self.request,
+            value0,
+            value1,
+            index0,
+            index1,
+            length0,
+            length1,
+        ]
+    }
+
     /// Gets the descriptor type and index from the value field of a GET_DESCRIPTOR request.
     pub const fn descriptor_type_index(&self) -> (u8, u8) {
         ((self.value >> 8) as u8, self.value as u8)
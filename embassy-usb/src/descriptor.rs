@@ -4,7 +4,6 @@ use embassy_usb_driver::EndpointType;
 use crate::builder::Config;
 use crate::driver::EndpointInfo;
 use crate::types::{InterfaceNumber, StringIndex};
-use crate::CONFIGURATION_VALUE;
 
 /// Standard descriptor types
 #[allow(missing_docs)]
@@ -77,6 +76,7 @@ pub enum UsageType {
 pub(crate) struct DescriptorWriter<'a> {
     pub buf: &'a mut [u8],
     position: usize,
+    configuration_mark: Option<usize>,
     num_interfaces_mark: Option<usize>,
     num_endpoints_mark: Option<usize>,
 }
@@ -86,6 +86,7 @@ impl<'a> DescriptorWriter<'a> {
         DescriptorWriter {
             buf,
             position: 0,
+            configuration_mark: None,
             num_interfaces_mark: None,
             num_endpoints_mark: None,
         }
@@ -122,17 +123,18 @@ impl<'a> DescriptorWriter<'a> {
         self.position = start + total_length;
     }
 
-    pub(crate) fn configuration(&mut self, config: &Config) {
+    pub(crate) fn configuration(&mut self, config: &Config, value: u8) {
+        self.configuration_mark = Some(self.position);
         self.num_interfaces_mark = Some(self.position + 4);
 
         self.write(
             descriptor_type::CONFIGURATION,
             &[
                 0,
-                0,                   // wTotalLength
-                0,                   // bNumInterfaces
-                CONFIGURATION_VALUE, // bConfigurationValue
-                0,                   // iConfiguration
+                0,     // wTotalLength
+                0,     // bNumInterfaces
+                value, // bConfigurationValue
+                0,     // iConfiguration
                 0x80 | if config.self_powered { 0x40 } else { 0x00 }
                     | if config.supports_remote_wakeup { 0x20 } else { 0x00 }, // bmAttributes
                 (config.max_power / 2) as u8, // bMaxPower
@@ -147,8 +149,12 @@ impl<'a> DescriptorWriter<'a> {
     }
 
     pub(crate) fn end_configuration(&mut self) {
-        let position = self.position as u16;
-        self.buf[2..4].copy_from_slice(&position.to_le_bytes());
+        if let Some(mark) = self.configuration_mark.take() {
+            let total_length = (self.position - mark) as u16;
+            self.buf[mark + 2..mark + 4].copy_from_slice(&total_length.to_le_bytes());
+        }
+        self.num_interfaces_mark = None;
+        self.num_endpoints_mark = None;
     }
 
     /// Writes a interface association descriptor. Call from `UsbClass::get_configuration_descriptors`
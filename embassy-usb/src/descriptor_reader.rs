@@ -1,54 +1,77 @@
+//! Parsing of descriptors.
+//!
+//! This is used by the device stack to find the endpoints of its own descriptors, and by hosts
+//! to parse the descriptors of the devices they enumerate.
+
 use crate::descriptor::descriptor_type;
 use crate::driver::EndpointAddress;
 use crate::types::InterfaceNumber;
 
+/// The descriptors are truncated or malformed.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ReadError;
 
+/// Reader of little endian fields and descriptors.
+#[derive(Clone)]
 pub struct Reader<'a> {
     data: &'a [u8],
 }
 
 impl<'a> Reader<'a> {
+    /// Create a reader of `data`.
     pub const fn new(data: &'a [u8]) -> Self {
         Self { data }
     }
 
+    /// Whether all the data was read.
     pub const fn eof(&self) -> bool {
         self.data.is_empty()
     }
 
+    /// Number of bytes left to read.
+    pub const fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Read `N` bytes.
     pub fn read<const N: usize>(&mut self) -> Result<[u8; N], ReadError> {
         let n = self.data.get(0..N).ok_or(ReadError)?;
         self.data = &self.data[N..];
         Ok(n.try_into().unwrap())
     }
 
+    /// Read a byte.
     pub fn read_u8(&mut self) -> Result<u8, ReadError> {
         Ok(u8::from_le_bytes(self.read()?))
     }
+    /// Read a little endian `u16`.
     pub fn read_u16(&mut self) -> Result<u16, ReadError> {
         Ok(u16::from_le_bytes(self.read()?))
     }
 
+    /// Read `len` bytes.
     pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], ReadError> {
         let res = self.data.get(0..len).ok_or(ReadError)?;
         self.data = &self.data[len..];
         Ok(res)
     }
 
+    /// Iterate over the descriptors of the rest of the data.
+    ///
+    /// Each one is the descriptor type, and a reader of the data following it.
     pub fn read_descriptors(&mut self) -> DescriptorIter<'_, 'a> {
         DescriptorIter { r: self }
     }
 }
 
+/// Iterator over descriptors, returned by [`Reader::read_descriptors`].
 pub struct DescriptorIter<'a, 'b> {
     r: &'a mut Reader<'b>,
 }
 
 impl<'a, 'b> Iterator for DescriptorIter<'a, 'b> {
-    type Item = Result<(u8, Reader<'a>), ReadError>;
+    type Item = Result<(u8, Reader<'b>), ReadError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.r.eof() {
@@ -59,6 +82,9 @@ impl<'a, 'b> Iterator for DescriptorIter<'a, 'b> {
             Ok(x) => x,
             Err(e) => return Some(Err(e)),
         };
+        if len < 2 {
+            return Some(Err(ReadError));
+        }
         let type_ = match self.r.read_u8() {
             Ok(x) => x,
             Err(e) => return Some(Err(e)),
@@ -72,15 +98,21 @@ impl<'a, 'b> Iterator for DescriptorIter<'a, 'b> {
     }
 }
 
+/// Endpoint found by [`foreach_endpoint`].
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct EndpointInfo {
+    /// Value of the configuration of the endpoint.
     pub configuration: u8,
+    /// Interface of the endpoint.
     pub interface: InterfaceNumber,
+    /// Alternate setting of the interface.
     pub interface_alt: u8,
+    /// Address of the endpoint.
     pub ep_address: EndpointAddress,
 }
 
+/// Call `f` for each endpoint of the configuration descriptors in `data`.
 pub fn foreach_endpoint(data: &[u8], mut f: impl FnMut(EndpointInfo)) -> Result<(), ReadError> {
     let mut ep = EndpointInfo {
         configuration: 0,
@@ -109,3 +141,41 @@ pub fn foreach_endpoint(data: &[u8], mut f: impl FnMut(EndpointInfo)) -> Result<
     }
     Ok(())
 }
+
+/// Configuration found by [`configurations`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigurationInfo<'a> {
+    /// Value of the configuration, used to select it.
+    pub value: u8,
+    /// Configuration descriptor, followed by the descriptors of its interfaces and endpoints.
+    pub descriptor: &'a [u8],
+    /// Index of the first interface of the configuration, among the interfaces of all configurations.
+    pub first_interface: usize,
+    /// Number of interfaces of the configuration.
+    pub num_interfaces: usize,
+}
+
+/// Iterates over the configurations written one after the other in `data`.
+pub fn configurations(data: &[u8]) -> impl Iterator<Item = ConfigurationInfo<'_>> {
+    let mut rest = data;
+    let mut first_interface = 0;
+    core::iter::from_fn(move || {
+        let header = rest.get(..9)?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if total_length < header.len() || total_length > rest.len() {
+            return None;
+        }
+
+        let (descriptor, next) = rest.split_at(total_length);
+        rest = next;
+        let info = ConfigurationInfo {
+            value: header[5],
+            descriptor,
+            first_interface,
+            num_interfaces: header[4] as usize,
+        };
+        first_interface += info.num_interfaces;
+        Some(info)
+    })
+}
@@ -1,4 +1,5 @@
 #![no_std]
+#![allow(async_fn_in_trait)]
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
@@ -11,7 +12,7 @@ mod builder;
 pub mod class;
 pub mod control;
 pub mod descriptor;
-mod descriptor_reader;
+pub mod descriptor_reader;
 pub mod msos;
 pub mod types;
 
@@ -27,7 +28,7 @@ pub use crate::builder::{Builder, Config, FunctionBuilder, InterfaceAltBuilder,
 use crate::config::{MAX_HANDLER_COUNT, MAX_INTERFACE_COUNT};
 use crate::control::{InResponse, OutResponse, Recipient, Request, RequestType};
 use crate::descriptor::{descriptor_type, lang_id};
-use crate::descriptor_reader::foreach_endpoint;
+use crate::descriptor_reader::{configurations, foreach_endpoint, ConfigurationInfo};
 use crate::driver::{Bus, ControlPipe, Direction, Driver, EndpointAddress, Event};
 use crate::types::{InterfaceNumber, StringIndex};
 
@@ -70,10 +71,28 @@ impl From<driver::Unsupported> for RemoteWakeupError {
     }
 }
 
+/// Error returned by [`UsbDevice::reconnect`].
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReconnectError {
+    /// The device has no configuration with the given value.
+    InvalidConfiguration,
+    /// The underlying driver doesn't support simulating a disconnect.
+    Unsupported,
+}
+
+impl From<driver::Unsupported> for ReconnectError {
+    fn from(_: driver::Unsupported) -> Self {
+        ReconnectError::Unsupported
+    }
+}
+
 /// The bConfiguration value for the not configured state.
 pub const CONFIGURATION_NONE: u8 = 0;
 
-/// The bConfiguration value for the single configuration supported by this device.
+/// The bConfiguration value for the first configuration of the device.
+///
+/// Configurations started with [`Builder::configuration`] have the following values.
 pub const CONFIGURATION_VALUE: u8 = 1;
 
 const STRING_INDEX_MANUFACTURER: u8 = 1;
@@ -105,6 +124,9 @@ pub trait Handler {
     fn remote_wakeup_enabled(&mut self, _enabled: bool) {}
 
     /// Called when a "set alternate setting" control request is done on the interface.
+    ///
+    /// If the device has several configurations, `iface` is an interface of the configuration the
+    /// handler was added in.
     fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
         let _ = iface;
         let _ = alternate_setting;
@@ -200,6 +222,11 @@ struct Inner<'d, D: Driver<'d>> {
     remote_wakeup_enabled: bool,
     self_powered: bool,
 
+    /// Value of the configuration selected by the host, or [`CONFIGURATION_NONE`].
+    configuration: u8,
+    /// Configuration presented to the host, or `None` if all of them are.
+    presented_configuration: Option<u8>,
+
     /// Our device address, or 0 if none.
     address: u8,
     /// SET_ADDRESS requests have special handling depending on the driver.
@@ -207,15 +234,17 @@ struct Inner<'d, D: Driver<'d>> {
     /// instead of regular `accept()`.
     set_address_pending: bool,
 
+    /// Interfaces of all the configurations, one after the other.
     interfaces: Vec<Interface, MAX_INTERFACE_COUNT>,
-    handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+    /// Handlers, with the value of the configuration they were added in.
+    handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
 }
 
 impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
     pub(crate) fn build(
         driver: D,
         config: Config<'d>,
-        handlers: Vec<&'d mut dyn Handler, MAX_HANDLER_COUNT>,
+        handlers: Vec<(u8, &'d mut dyn Handler), MAX_HANDLER_COUNT>,
         config_descriptor: &'d [u8],
         bos_descriptor: &'d [u8],
         msos_descriptor: crate::msos::MsOsDescriptorSet<'d>,
@@ -228,7 +257,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         let device_descriptor = descriptor::device_descriptor(&config);
         let device_qualifier_descriptor = descriptor::device_qualifier_descriptor(&config);
 
-        Self {
+        let mut device = Self {
             control_buf,
             control,
             inner: Inner {
@@ -244,12 +273,16 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
                 suspended: false,
                 remote_wakeup_enabled: false,
                 self_powered: false,
+                configuration: CONFIGURATION_NONE,
+                presented_configuration: None,
                 address: 0,
                 set_address_pending: false,
                 interfaces,
                 handlers,
             },
-        }
+        };
+        device.inner.update_num_configurations();
+        device
     }
 
     /// Returns a report of the consumed buffers
@@ -299,15 +332,45 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
         if self.inner.device_state != UsbDeviceState::Disabled {
             self.inner.bus.disable().await;
             self.inner.device_state = UsbDeviceState::Disabled;
+            self.inner.configuration = CONFIGURATION_NONE;
             self.inner.suspended = false;
             self.inner.remote_wakeup_enabled = false;
 
-            for h in &mut self.inner.handlers {
+            for (_, h) in &mut self.inner.handlers {
                 h.enabled(false);
             }
         }
     }
 
+    /// Soft-disconnects the device from the bus and reconnects it, making the host enumerate it again.
+    ///
+    /// After reconnecting, the device presents only the configuration with value `configuration` to
+    /// the host, or all of its configurations if it is `None`. This allows swapping the set of
+    /// functions of the device (e.g. a "normal" and a "service mode" configuration) without
+    /// rebooting it. The device is unconfigured until the host selects a configuration again.
+    ///
+    /// This uses [`Bus::force_reset`], and fails with [`ReconnectError::Unsupported`] if the driver
+    /// doesn't support it. Nothing changes on failure.
+    ///
+    /// Call it when [`UsbDevice::run`] isn't running, for example after a `select` between it and
+    /// the event triggering the swap, then run the device again.
+    pub fn reconnect(&mut self, configuration: Option<u8>) -> Result<(), ReconnectError> {
+        if let Some(value) = configuration {
+            if !configurations(self.inner.config_descriptor).any(|c| c.value == value) {
+                return Err(ReconnectError::InvalidConfiguration);
+            }
+        }
+
+        self.inner.bus.force_reset()?;
+
+        if self.inner.device_state == UsbDeviceState::Configured {
+            self.inner.deconfigure();
+        }
+        self.inner.presented_configuration = configuration;
+        self.inner.update_num_configurations();
+        Ok(())
+    }
+
     /// Waits for a resume condition on the USB bus.
     ///
     /// This future is cancel-safe.
@@ -331,7 +394,7 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
             self.inner.bus.remote_wakeup().await?;
             self.inner.suspended = false;
 
-            for h in &mut self.inner.handlers {
+            for (_, h) in &mut self.inner.handlers {
                 h.suspended(false);
             }
 
@@ -441,38 +504,95 @@ impl<'d, D: Driver<'d>> UsbDevice<'d, D> {
 }
 
 impl<'d, D: Driver<'d>> Inner<'d, D> {
+    /// Configurations presented to the host, in the order of their indexes.
+    fn presented_configurations(&self) -> impl Iterator<Item = ConfigurationInfo<'d>> {
+        let presented = self.presented_configuration;
+        configurations(self.config_descriptor).filter(move |c| presented.is_none() || presented == Some(c.value))
+    }
+
+    /// Configuration the interface requests are for: the selected one, or the first presented one
+    /// while the device is unconfigured.
+    fn current_configuration(&self) -> Option<ConfigurationInfo<'d>> {
+        match self.configuration {
+            CONFIGURATION_NONE => self.presented_configurations().next(),
+            value => self.presented_configurations().find(|c| c.value == value),
+        }
+    }
+
+    /// Index in `interfaces` of interface `iface` of the current configuration, with that configuration.
+    fn interface_index(&self, iface: u16) -> Option<(ConfigurationInfo<'d>, usize)> {
+        let config = self.current_configuration()?;
+        let iface = iface as usize;
+        (iface < config.num_interfaces).then_some((config, config.first_interface + iface))
+    }
+
+    fn update_num_configurations(&mut self) {
+        let count = self.presented_configurations().count() as u8;
+        self.device_descriptor[17] = count; // bNumConfigurations
+        self.device_qualifier_descriptor[8] = count; // bNumConfigurations
+    }
+
+    /// Handlers of configuration `value`.
+    fn configuration_handlers(&mut self, value: u8) -> impl Iterator<Item = &mut &'d mut dyn Handler> {
+        self.handlers
+            .iter_mut()
+            .filter(move |(configuration, _)| *configuration == value)
+            .map(|(_, h)| h)
+    }
+
+    /// Leaves the configured state, disabling all the endpoints.
+    fn deconfigure(&mut self) {
+        let configuration = self.configuration;
+        self.device_state = UsbDeviceState::Addressed;
+        self.configuration = CONFIGURATION_NONE;
+
+        // Disable all endpoints.
+        foreach_endpoint(self.config_descriptor, |ep| {
+            self.bus.endpoint_set_enabled(ep.ep_address, false);
+        })
+        .unwrap();
+
+        // Notify the handlers of the configuration that was left.
+        for h in self.configuration_handlers(configuration) {
+            h.configured(false);
+        }
+    }
+
     async fn handle_bus_event(&mut self, evt: Event) {
         match evt {
             Event::Reset => {
                 trace!("usb: reset");
                 self.device_state = UsbDeviceState::Default;
+                self.configuration = CONFIGURATION_NONE;
                 self.suspended = false;
                 self.remote_wakeup_enabled = false;
                 self.address = 0;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.reset();
                 }
 
-                for (i, iface) in self.interfaces.iter_mut().enumerate() {
-                    iface.current_alt_setting = 0;
+                for config in configurations(self.config_descriptor) {
+                    for i in 0..config.num_interfaces {
+                        self.interfaces[config.first_interface + i].current_alt_setting = 0;
 
-                    for h in &mut self.handlers {
-                        h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        for h in self.configuration_handlers(config.value) {
+                            h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                        }
                     }
                 }
             }
             Event::Resume => {
                 trace!("usb: resume");
                 self.suspended = false;
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.suspended(false);
                 }
             }
             Event::Suspend => {
                 trace!("usb: suspend");
                 self.suspended = true;
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.suspended(true);
                 }
             }
@@ -481,7 +601,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 self.bus.enable().await;
                 self.device_state = UsbDeviceState::Default;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.enabled(true);
                 }
             }
@@ -489,8 +609,9 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 trace!("usb: power removed");
                 self.bus.disable().await;
                 self.device_state = UsbDeviceState::Unpowered;
+                self.configuration = CONFIGURATION_NONE;
 
-                for h in &mut self.handlers {
+                for (_, h) in &mut self.handlers {
                     h.enabled(false);
                 }
             }
@@ -499,20 +620,19 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
 
     fn handle_control_out(&mut self, req: Request, data: &[u8]) -> OutResponse {
         const CONFIGURATION_NONE_U16: u16 = CONFIGURATION_NONE as u16;
-        const CONFIGURATION_VALUE_U16: u16 = CONFIGURATION_VALUE as u16;
 
         match (req.request_type, req.recipient) {
             (RequestType::Standard, Recipient::Device) => match (req.request, req.value) {
                 (Request::CLEAR_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
                     self.remote_wakeup_enabled = false;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.remote_wakeup_enabled(false);
                     }
                     OutResponse::Accepted
                 }
                 (Request::SET_FEATURE, Request::FEATURE_DEVICE_REMOTE_WAKEUP) => {
                     self.remote_wakeup_enabled = true;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.remote_wakeup_enabled(true);
                     }
                     OutResponse::Accepted
@@ -521,55 +641,74 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                     self.address = addr as u8;
                     self.set_address_pending = true;
                     self.device_state = UsbDeviceState::Addressed;
-                    for h in &mut self.handlers {
+                    for (_, h) in &mut self.handlers {
                         h.addressed(self.address);
                     }
                     OutResponse::Accepted
                 }
-                (Request::SET_CONFIGURATION, CONFIGURATION_VALUE_U16) => {
-                    debug!("SET_CONFIGURATION: configured");
+                (Request::SET_CONFIGURATION, CONFIGURATION_NONE_U16) => {
+                    if self.device_state != UsbDeviceState::Default {
+                        debug!("SET_CONFIGURATION: unconfigured");
+                        self.deconfigure();
+                    }
+                    OutResponse::Accepted
+                }
+                (Request::SET_CONFIGURATION, value @ 1..=255) => {
+                    let Some(config) = self.presented_configurations().find(|c| c.value == value as u8) else {
+                        warn!("SET_CONFIGURATION: unknown configuration {}", value);
+                        return OutResponse::Rejected;
+                    };
+
+                    if self.device_state == UsbDeviceState::Configured && self.configuration != config.value {
+                        self.deconfigure();
+                    }
+
+                    debug!("SET_CONFIGURATION: configured {}", config.value);
                     self.device_state = UsbDeviceState::Configured;
 
+                    // The interfaces of another configuration may have been left in a non-default alt setting.
+                    if self.configuration != config.value {
+                        for i in 0..config.num_interfaces {
+                            let iface = &mut self.interfaces[config.first_interface + i];
+                            if iface.current_alt_setting != 0 {
+                                iface.current_alt_setting = 0;
+                                for h in self.configuration_handlers(config.value) {
+                                    h.set_alternate_setting(InterfaceNumber::new(i as _), 0);
+                                }
+                            }
+                        }
+                    }
+                    self.configuration = config.value;
+
                     // Enable all endpoints of selected alt settings.
-                    foreach_endpoint(self.config_descriptor, |ep| {
-                        let iface = &self.interfaces[ep.interface.0 as usize];
-                        self.bus
-                            .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+                    // An endpoint may be listed in several alt settings, so disable them all first.
+                    foreach_endpoint(config.descriptor, |ep| {
+                        self.bus.endpoint_set_enabled(ep.ep_address, false);
+                    })
+                    .unwrap();
+                    foreach_endpoint(config.descriptor, |ep| {
+                        let iface = &self.interfaces[config.first_interface + ep.interface.0 as usize];
+                        if iface.current_alt_setting == ep.interface_alt {
+                            self.bus.endpoint_set_enabled(ep.ep_address, true);
+                        }
                     })
                     .unwrap();
 
-                    // Notify handlers.
-                    for h in &mut self.handlers {
+                    // Notify the handlers of the selected configuration.
+                    for h in self.configuration_handlers(config.value) {
                         h.configured(true);
                     }
 
                     OutResponse::Accepted
                 }
-                (Request::SET_CONFIGURATION, CONFIGURATION_NONE_U16) => {
-                    if self.device_state != UsbDeviceState::Default {
-                        debug!("SET_CONFIGURATION: unconfigured");
-                        self.device_state = UsbDeviceState::Addressed;
-
-                        // Disable all endpoints.
-                        foreach_endpoint(self.config_descriptor, |ep| {
-                            self.bus.endpoint_set_enabled(ep.ep_address, false);
-                        })
-                        .unwrap();
-
-                        // Notify handlers.
-                        for h in &mut self.handlers {
-                            h.configured(false);
-                        }
-                    }
-                    OutResponse::Accepted
-                }
                 _ => OutResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
                 let iface_num = InterfaceNumber::new(req.index as _);
-                let Some(iface) = self.interfaces.get_mut(iface_num.0 as usize) else {
+                let Some((config, index)) = self.interface_index(req.index) else {
                     return OutResponse::Rejected;
                 };
+                let iface = &mut self.interfaces[index];
 
                 match req.request {
                     Request::SET_INTERFACE => {
@@ -583,17 +722,23 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         iface.current_alt_setting = new_altsetting;
 
                         // Enable/disable EPs of this interface as needed.
-                        foreach_endpoint(self.config_descriptor, |ep| {
-                            if ep.interface == iface_num {
-                                self.bus
-                                    .endpoint_set_enabled(ep.ep_address, iface.current_alt_setting == ep.interface_alt);
+                        // An endpoint may be listed in several alt settings, so disable them all first.
+                        foreach_endpoint(config.descriptor, |ep| {
+                            if ep.interface == iface_num && iface.current_alt_setting != ep.interface_alt {
+                                self.bus.endpoint_set_enabled(ep.ep_address, false);
+                            }
+                        })
+                        .unwrap();
+                        foreach_endpoint(config.descriptor, |ep| {
+                            if ep.interface == iface_num && iface.current_alt_setting == ep.interface_alt {
+                                self.bus.endpoint_set_enabled(ep.ep_address, true);
                             }
                         })
                         .unwrap();
 
                         // TODO check it is valid (not out of range)
 
-                        for h in &mut self.handlers {
+                        for h in self.configuration_handlers(config.value) {
                             h.set_alternate_setting(iface_num, new_altsetting);
                         }
                         OutResponse::Accepted
@@ -635,7 +780,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 Request::GET_DESCRIPTOR => self.handle_get_descriptor(req, buf),
                 Request::GET_CONFIGURATION => {
                     let status = match self.device_state {
-                        UsbDeviceState::Configured => CONFIGURATION_VALUE,
+                        UsbDeviceState::Configured => self.configuration,
                         _ => CONFIGURATION_NONE,
                     };
                     buf[0] = status;
@@ -644,9 +789,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                 _ => InResponse::Rejected,
             },
             (RequestType::Standard, Recipient::Interface) => {
-                let Some(iface) = self.interfaces.get_mut(req.index as usize) else {
+                let Some((_, index)) = self.interface_index(req.index) else {
                     return InResponse::Rejected;
                 };
+                let iface = &self.interfaces[index];
 
                 match req.request {
                     Request::GET_STATUS => {
@@ -689,8 +835,21 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         }
     }
 
+    /// Value of the configuration whose handlers get `req`, or `None` if all of them do.
+    fn request_configuration(&self, req: Request) -> Option<u8> {
+        match req.recipient {
+            Recipient::Interface => Some(self.current_configuration().map_or(CONFIGURATION_NONE, |c| c.value)),
+            _ => None,
+        }
+    }
+
     fn handle_control_out_delegated(&mut self, req: Request, data: &[u8]) -> OutResponse {
-        for h in &mut self.handlers {
+        let configuration = self.request_configuration(req);
+        for (_, h) in self
+            .handlers
+            .iter_mut()
+            .filter(|(c, _)| configuration.is_none() || configuration == Some(*c))
+        {
             if let Some(res) = h.control_out(req, data) {
                 return res;
             }
@@ -703,7 +862,12 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
             core::mem::transmute(r)
         }
 
-        for h in &mut self.handlers {
+        let configuration = self.request_configuration(req);
+        for (_, h) in self
+            .handlers
+            .iter_mut()
+            .filter(|(c, _)| configuration.is_none() || configuration == Some(*c))
+        {
             if let Some(res) = h.control_in(req, buf) {
                 // safety: the borrow checker isn't smart enough to know this pattern (returning a
                 // borrowed value from inside the loop) is sound. Workaround by unsafely extending lifetime.
@@ -721,7 +885,10 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
         match dtype {
             descriptor_type::BOS => InResponse::Accepted(self.bos_descriptor),
             descriptor_type::DEVICE => InResponse::Accepted(&self.device_descriptor),
-            descriptor_type::CONFIGURATION => InResponse::Accepted(self.config_descriptor),
+            descriptor_type::CONFIGURATION => match self.presented_configurations().nth(index as usize) {
+                Some(config) => InResponse::Accepted(config.descriptor),
+                None => InResponse::Rejected,
+            },
             descriptor_type::STRING => {
                 if index == 0 {
                     buf[0] = 4; // len
@@ -736,7 +903,7 @@ impl<'d, D: Driver<'d>> Inner<'d, D> {
                         STRING_INDEX_SERIAL_NUMBER => self.config.serial_number,
                         _ => {
                             let mut s = None;
-                            for handler in &mut self.handlers {
+                            for (_, handler) in &mut self.handlers {
                                 let index = StringIndex::new(index);
                                 let lang_id = req.index;
                                 if let Some(res) = handler.get_string(index, lang_id) {
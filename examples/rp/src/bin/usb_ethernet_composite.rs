@@ -0,0 +1,196 @@
+//! This example shows how to use USB (Universal Serial Bus) in the RP2040 chip.
+//!
+//! This is a composite device with a CDC-NCM and an RNDIS function, aka Ethernet over USB. macOS
+//! and Linux use the CDC-NCM function, while the MS OS descriptors make Windows bind its RNDIS
+//! driver to the RNDIS function. Each function has its own network stack, both echoing on TCP port
+//! 1234.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Stack, StackResources};
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_rp::{bind_interrupts, peripherals};
+use embassy_usb::class::cdc_ncm::embassy_net::{Device, Runner as NcmRunner, State as NcmNetState};
+use embassy_usb::class::cdc_ncm::{CdcNcmClass, State as NcmState};
+use embassy_usb::class::rndis::embassy_net::{Runner as RndisRunner, State as RndisNetState};
+use embassy_usb::class::rndis::{RndisClass, State as RndisState};
+use embassy_usb::msos::windows_version;
+use embassy_usb::{Builder, Config, UsbDevice};
+use embedded_io_async::Write;
+use panic_probe as _;
+use rand::RngCore;
+use static_cell::StaticCell;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+type MyDriver = Driver<'static, peripherals::USB>;
+
+const MTU: usize = 1514;
+
+#[embassy_executor::task]
+async fn usb_task(mut device: UsbDevice<'static, MyDriver>) -> ! {
+    device.run().await
+}
+
+#[embassy_executor::task]
+async fn usb_ncm_task(class: NcmRunner<'static, MyDriver, MTU>) -> ! {
+    class.run().await
+}
+
+#[embassy_executor::task]
+async fn usb_rndis_task(class: RndisRunner<'static, MyDriver, MTU>) -> ! {
+    class.run().await
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn net_task(mut runner: embassy_net::Runner<'static, Device<'static, MTU>>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    let mut rng = RoscRng;
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-Ethernet example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Required for Windows support.
+    config.composite_with_iads = true;
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    static CONFIG_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static BOS_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static MSOS_DESC: StaticCell<[u8; 256]> = StaticCell::new();
+    static CONTROL_BUF: StaticCell<[u8; 128]> = StaticCell::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut CONFIG_DESC.init([0; 256])[..],
+        &mut BOS_DESC.init([0; 256])[..],
+        &mut MSOS_DESC.init([0; 256])[..],
+        &mut CONTROL_BUF.init([0; 128])[..],
+    );
+
+    // Add the MS OS descriptors, to which the RNDIS class adds its compatible ID.
+    builder.msos_descriptor(windows_version::WIN8_1, 0);
+
+    // Our MAC addrs, one per function.
+    let ncm_mac_addr = [0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC];
+    let rndis_mac_addr = [0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCD];
+    // Host's MAC addrs. These are the MACs the host "thinks" its USB-to-ethernet adapters have.
+    let ncm_host_mac_addr = [0x88, 0x88, 0x88, 0x88, 0x88, 0x88];
+    let rndis_host_mac_addr = [0x88, 0x88, 0x88, 0x88, 0x88, 0x89];
+
+    // Create classes on the builder.
+    static NCM_STATE: StaticCell<NcmState> = StaticCell::new();
+    let ncm = CdcNcmClass::new(&mut builder, NCM_STATE.init(NcmState::new()), ncm_host_mac_addr, 64);
+    static RNDIS_STATE: StaticCell<RndisState> = StaticCell::new();
+    let rndis = RndisClass::new(
+        &mut builder,
+        RNDIS_STATE.init(RndisState::new()),
+        rndis_host_mac_addr,
+        64,
+    );
+
+    // Build the builder.
+    let usb = builder.build();
+
+    unwrap!(spawner.spawn(usb_task(usb)));
+
+    static NCM_NET_STATE: StaticCell<NcmNetState<MTU, 4, 4>> = StaticCell::new();
+    let (runner, ncm_device) =
+        ncm.into_embassy_net_device::<MTU, 4, 4>(NCM_NET_STATE.init(NcmNetState::new()), ncm_mac_addr);
+    unwrap!(spawner.spawn(usb_ncm_task(runner)));
+
+    static RNDIS_NET_STATE: StaticCell<RndisNetState<MTU, 4, 4>> = StaticCell::new();
+    let (runner, rndis_device) =
+        rndis.into_embassy_net_device::<MTU, 4, 4>(RNDIS_NET_STATE.init(RndisNetState::new()), rndis_mac_addr);
+    unwrap!(spawner.spawn(usb_rndis_task(runner)));
+
+    // Init network stacks
+    static NCM_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (ncm_stack, runner) = embassy_net::new(
+        ncm_device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        NCM_RESOURCES.init(StackResources::new()),
+        rng.next_u64(),
+    );
+    unwrap!(spawner.spawn(net_task(runner)));
+
+    static RNDIS_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (rndis_stack, runner) = embassy_net::new(
+        rndis_device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        RNDIS_RESOURCES.init(StackResources::new()),
+        rng.next_u64(),
+    );
+    unwrap!(spawner.spawn(net_task(runner)));
+
+    unwrap!(spawner.spawn(echo_task(ncm_stack)));
+    unwrap!(spawner.spawn(echo_task(rndis_stack)));
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn echo_task(stack: Stack<'static>) -> ! {
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut buf = [0; 4096];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
+
+        info!("Listening on TCP:1234...");
+        if let Err(e) = socket.accept(1234).await {
+            warn!("accept error: {:?}", e);
+            continue;
+        }
+
+        info!("Received connection from {:?}", socket.remote_endpoint());
+
+        loop {
+            let n = match socket.read(&mut buf).await {
+                Ok(0) => {
+                    warn!("read EOF");
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("read error: {:?}", e);
+                    break;
+                }
+            };
+
+            info!("rxd {:02x}", &buf[..n]);
+
+            match socket.write_all(&buf[..n]).await {
+                Ok(()) => {}
+                Err(e) => {
+                    warn!("write error: {:?}", e);
+                    break;
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! This example shows how to use the built-in HID keyboard report in the RP2040 chip.
+//!
+//! The keyboard supports the boot protocol, so it also works in BIOS setups. It types "a" when pin 16
+//! goes high, and shows the Caps Lock state on the LED.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join4;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_usb::class::hid::report::{InputReport, KeyboardLeds, KeyboardModifiers, KeyboardReport};
+use embassy_usb::class::hid::usage::keyboard;
+use embassy_usb::class::hid::{HidReaderWriter, ReadError, ReportId, RequestHandler, State};
+use embassy_usb::control::OutResponse;
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+static LEDS: Signal<CriticalSectionRawMutex, KeyboardLeds> = Signal::new();
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("HID boot keyboard example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut request_handler = MyRequestHandler {};
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder. Boot keyboards use 8 byte packets.
+    let config = embassy_usb::class::hid::Config {
+        report_descriptor: KeyboardReport::descriptor(),
+        request_handler: Some(&mut request_handler),
+        poll_ms: 10,
+        max_packet_size: 8,
+        boot_protocol: KeyboardReport::BOOT_PROTOCOL,
+    };
+    let hid = HidReaderWriter::<_, 1, 8>::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Set up the signal pin that will be used to trigger the keyboard.
+    let mut signal_pin = Input::new(p.PIN_16, Pull::None);
+
+    // Enable the schmitt trigger to slightly debounce.
+    signal_pin.set_schmitt(true);
+
+    let mut led = Output::new(p.PIN_25, Level::Low);
+
+    let (mut reader, mut writer) = hid.split();
+
+    // Do stuff with the class!
+    let in_fut = async {
+        loop {
+            info!("Waiting for HIGH on pin 16");
+            signal_pin.wait_for_high().await;
+            info!("HIGH DETECTED, protocol {:?}", writer.protocol());
+            // Send a report with the A key pressed.
+            let report = KeyboardReport::new(KeyboardModifiers::NONE, &[keyboard::A]);
+            match writer.write_report(&report).await {
+                Ok(()) => {}
+                Err(e) => warn!("Failed to send report: {:?}", e),
+            };
+            signal_pin.wait_for_low().await;
+            info!("LOW DETECTED");
+            match writer.write_report(&KeyboardReport::default()).await {
+                Ok(()) => {}
+                Err(e) => warn!("Failed to send report: {:?}", e),
+            };
+        }
+    };
+
+    // The host sets the LEDs with output reports, on the OUT endpoint or with SET_REPORT requests.
+    let out_fut = async {
+        let mut buf = [0; 1];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(n) => {
+                    if let Some(leds) = KeyboardLeds::from_report(&buf[..n]) {
+                        LEDS.signal(leds);
+                    }
+                }
+                Err(ReadError::Disabled) => reader.ready().await,
+                Err(e) => warn!("Failed to read report: {:?}", e),
+            }
+        }
+    };
+
+    let led_fut = async {
+        loop {
+            let leds = LEDS.wait().await;
+            info!("Caps Lock: {}", leds.caps_lock());
+            led.set_level(Level::from(leds.caps_lock()));
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join4(usb_fut, in_fut, out_fut, led_fut).await;
+}
+
+struct MyRequestHandler {}
+
+impl RequestHandler for MyRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, KeyboardLeds::from_report(data)) {
+            (ReportId::Out(0), Some(leds)) => {
+                LEDS.signal(leds);
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! This example shows how to use the USB printer class in the RP2040 chip.
+//!
+//! The printer logs the data of the print jobs it receives. It reports that it is out of paper when
+//! the button on pin 16 is pressed while receiving data.
+
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, InterruptHandler};
+use embassy_usb::class::printer::{self, PortStatus, PrinterClass, State};
+use embassy_usb::{Builder, Config};
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB printer example");
+    config.serial_number = Some("12345678");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors. The control buffer also holds the device ID.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 128];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder. Hosts select the driver from the command set of the device ID.
+    let config = printer::Config {
+        device_id: "MFG:Embassy;MDL:Example printer;CMD:TEXT;CLS:PRINTER;",
+        max_packet_size: 64,
+    };
+    let mut class = PrinterClass::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    let button = Input::new(p.PIN_16, Pull::Up);
+
+    // Do stuff with the class!
+    let print_fut = async {
+        let mut buf = [0; 64];
+        loop {
+            class.wait_connection().await;
+            info!("Connected");
+            loop {
+                match class.read(&mut buf).await {
+                    Ok(n) => {
+                        info!("data: {:a}", &buf[..n]);
+                        // The host reads the port status when a job doesn't progress.
+                        class.set_port_status(PortStatus {
+                            paper_empty: button.is_low(),
+                            ..Default::default()
+                        });
+                    }
+                    Err(printer::Error::Reset) => info!("Job aborted"),
+                    Err(printer::Error::Disabled) => break,
+                }
+            }
+            info!("Disconnected");
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, print_fut).await;
+}
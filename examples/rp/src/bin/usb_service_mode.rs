@@ -0,0 +1,155 @@
+//! This example shows how to use several USB configurations in the RP2040 chip.
+//!
+//! The device has a "normal" configuration, with a USB serial port that echos, and a "service mode"
+//! one, with a serial port that echos in uppercase. Pressing the button on pin 16 disconnects the
+//! device from the host, and reconnects it with the other configuration.
+
+#![no_std]
+#![no_main]
+
+use defmt::{info, panic, unwrap, warn};
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::{Driver, Instance, InterruptHandler};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{UsbDevice, CONFIGURATION_VALUE};
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+});
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Hello there!");
+
+    let p = embassy_rp::init(Default::default());
+
+    // Create the driver, from the HAL.
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Create embassy-usb Config
+    let config = {
+        let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+        config.manufacturer = Some("Embassy");
+        config.product = Some("USB service mode example");
+        config.serial_number = Some("12345678");
+        config.max_power = 100;
+        config.max_packet_size_0 = 64;
+
+        // Required for windows compatibility.
+        // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
+        config.device_class = 0xEF;
+        config.device_sub_class = 0x02;
+        config.device_protocol = 0x01;
+        config.composite_with_iads = true;
+        config
+    };
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors, large enough for all the configurations.
+    let mut builder = {
+        static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+        static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+
+        let builder = embassy_usb::Builder::new(
+            driver,
+            config,
+            CONFIG_DESCRIPTOR.init([0; 256]),
+            BOS_DESCRIPTOR.init([0; 256]),
+            &mut [], // no msos descriptors
+            CONTROL_BUF.init([0; 64]),
+        );
+        builder
+    };
+
+    // Create the classes of the normal configuration, started by `Builder::new`.
+    let normal_class = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        let state = STATE.init(State::new());
+        CdcAcmClass::new(&mut builder, state, 64)
+    };
+
+    // Then the ones of the service mode configuration.
+    let service_mode = builder.configuration();
+    let service_class = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        let state = STATE.init(State::new());
+        CdcAcmClass::new(&mut builder, state, 64)
+    };
+
+    // Build the builder.
+    let usb = builder.build();
+
+    // Run the USB device.
+    let button = Input::new(p.PIN_16, Pull::Up);
+    unwrap!(spawner.spawn(usb_task(usb, button, service_mode)));
+
+    // The classes of the configuration not selected by the host never get connected.
+    unwrap!(spawner.spawn(serial_task(normal_class, false)));
+    unwrap!(spawner.spawn(serial_task(service_class, true)));
+}
+
+type MyUsbDriver = Driver<'static, USB>;
+type MyUsbDevice = UsbDevice<'static, MyUsbDriver>;
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: MyUsbDevice, mut button: Input<'static>, service_mode: u8) -> ! {
+    let mut configuration = CONFIGURATION_VALUE;
+    loop {
+        // Run the device until the button is pressed.
+        select(usb.run(), button.wait_for_falling_edge()).await;
+
+        configuration = if configuration == service_mode {
+            CONFIGURATION_VALUE
+        } else {
+            service_mode
+        };
+        info!("Reconnecting with configuration {}", configuration);
+        if let Err(e) = usb.reconnect(Some(configuration)) {
+            warn!("Failed to reconnect: {:?}", e);
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn serial_task(mut class: CdcAcmClass<'static, MyUsbDriver>, uppercase: bool) -> ! {
+    loop {
+        class.wait_connection().await;
+        info!("Connected, uppercase: {}", uppercase);
+        let _ = echo(&mut class, uppercase).await;
+        info!("Disconnected, uppercase: {}", uppercase);
+    }
+}
+
+struct Disconnected {}
+
+impl From<EndpointError> for Disconnected {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Disconnected {},
+        }
+    }
+}
+
+async fn echo<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    uppercase: bool,
+) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+    loop {
+        let n = class.read_packet(&mut buf).await?;
+        let data = &mut buf[..n];
+        if uppercase {
+            data.make_ascii_uppercase();
+        }
+        class.write_packet(data).await?;
+    }
+}
@@ -2,7 +2,7 @@ use clap::Parser;
 use embassy_executor::{Executor, Spawner};
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
-use embassy_net_tuntap::TunTapDevice;
+use embassy_net_tuntap::{Mode, TunTapDevice};
 use embassy_time::Duration;
 use embedded_io_async::Write;
 use heapless::Vec;
@@ -19,6 +19,9 @@ struct Opts {
     /// use a static IP instead of DHCP
     #[clap(long)]
     static_ip: bool,
+    /// use this TUN device (IP medium) instead of the TAP device, with a static IP
+    #[clap(long)]
+    tun: Option<String>,
 }
 
 #[embassy_executor::task]
@@ -31,10 +34,17 @@ async fn main_task(spawner: Spawner) {
     let opts: Opts = Opts::parse();
 
     // Init network device
-    let device = TunTapDevice::new(&opts.tap).unwrap();
+    let device = match &opts.tun {
+        Some(tun) => {
+            let mut config = embassy_net_tuntap::Config::default();
+            config.mode = Mode::Tun;
+            TunTapDevice::with_config(tun, &config).unwrap()
+        }
+        None => TunTapDevice::new(&opts.tap).unwrap(),
+    };
 
     // Choose between dhcp or static ip
-    let config = if opts.static_ip {
+    let config = if opts.static_ip || opts.tun.is_some() {
         Config::ipv4_static(embassy_net::StaticConfigV4 {
             address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
             dns_servers: Vec::new(),
@@ -0,0 +1,82 @@
+//! Configures IPv4 with DHCP, falling back to a link-local address when there's no DHCP server,
+//! e.g. when the device is plugged directly into a laptop.
+//!
+//! Start it without a DHCP server on `tap0`, then start one: the address switches over to the lease.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::{AutoIpConfig, Config, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::{Duration, Timer};
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Use DHCP if a lease is obtained within 5 seconds, a link-local address otherwise.
+    let mut autoip = AutoIpConfig::default();
+    autoip.dhcp = Some(Default::default());
+    autoip.dhcp_timeout = Duration::from_secs(5);
+    let config = Config::ipv4_autoip(autoip);
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    // Report the configuration whenever it changes.
+    let mut current = None;
+    loop {
+        let config = stack.config_v4();
+        if config != current {
+            match &config {
+                Some(c) if c.is_link_local() => info!("link-local address: {}", c.address),
+                Some(c) => info!("DHCP address: {}, gateway: {:?}", c.address, c.gateway),
+                None => info!("no address"),
+            }
+            current = config;
+        }
+        Timer::after_secs(1).await;
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
@@ -0,0 +1,243 @@
+//! Benchmark of the `embassy-net-driver-channel` channels.
+//!
+//! The stack sends a TCP bulk transfer to the host through a NIC driver on a TUN interface, whose
+//! reads and writes stand in for the DMA moving frames between descriptor rings and the wire. With
+//! the channel from the crate root, the driver copies every frame between its rings and the
+//! channel buffers. With `--zero-copy`, it lends its ring buffers to the stack through the `dma`
+//! channel instead, so these copies disappear.
+//!
+//! Create the TUN interface as described in the README, then run it in release mode, with and
+//! without `--zero-copy`, to compare throughputs.
+
+use std::io::{Read, Write as _};
+use std::net::TcpListener;
+
+use async_io::Async;
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources, StaticConfigV4};
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::{HardwareAddress, LinkState};
+use embassy_net_tuntap::{Mode, TunTap};
+use embassy_time::Instant;
+use embedded_io_async::Write;
+use heapless::Vec;
+use log::*;
+use static_cell::StaticCell;
+
+const MTU: usize = 1500;
+/// Number of descriptors of each RX and TX ring.
+const RING_LEN: usize = 8;
+const PORT: u16 = 1234;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TUN device name
+    #[clap(long, default_value = "tun0")]
+    tun: String,
+    /// Lend the NIC's ring buffers to the stack instead of copying frames
+    #[clap(long)]
+    zero_copy: bool,
+    /// Amount of data to transfer, in MiB
+    #[clap(long, default_value = "256")]
+    size: usize,
+}
+
+type Ring = [[u8; MTU]; RING_LEN];
+
+/// NIC driver using the copying channel.
+#[embassy_executor::task]
+async fn copy_nic_task(
+    runner: ch::Runner<'static, MTU>,
+    rings: &'static mut [[u8; MTU]; 2],
+    mut tun: Async<TunTap>,
+) -> ! {
+    let (state, mut rx, mut tx) = runner.split();
+    state.set_link_state(LinkState::Up);
+
+    // The DMA only reads from and writes to the rings, which have a single descriptor here.
+    let [tx_ring, rx_ring] = rings;
+    loop {
+        // Only receive a frame once the channel has room for it, so it isn't dropped.
+        match select(tx.tx_buf(), async {
+            rx.rx_buf().await;
+            tun.read_with_mut(|tun| tun.read(rx_ring)).await
+        })
+        .await
+        {
+            Either::First(pkt) => {
+                let len = pkt.len();
+                tx_ring[..len].copy_from_slice(pkt);
+                tx.tx_done();
+
+                if let Err(e) = tun.write_with_mut(|tun| tun.write(&tx_ring[..len])).await {
+                    warn!("TUN write failed: {:?}", e);
+                }
+            }
+            Either::Second(Ok(len)) => {
+                let buf = rx.try_rx_buf().unwrap();
+                buf[..len].copy_from_slice(&rx_ring[..len]);
+                rx.rx_done(len);
+            }
+            Either::Second(Err(e)) => warn!("TUN read failed: {:?}", e),
+        }
+    }
+}
+
+/// NIC driver lending its ring buffers with the `dma` channel.
+#[embassy_executor::task]
+async fn dma_nic_task(
+    runner: ch::dma::Runner<'static, RING_LEN, RING_LEN>,
+    tx_ring: &'static mut Ring,
+    rx_ring: &'static mut Ring,
+    mut tun: Async<TunTap>,
+) -> ! {
+    let (state, mut rx, mut tx) = runner.split();
+    state.set_link_state(LinkState::Up);
+
+    for buf in tx_ring.iter_mut() {
+        tx.tx_lend(buf).unwrap();
+    }
+    // RX descriptors armed for the DMA.
+    let mut rx_armed: Vec<&'static mut [u8], RING_LEN> = rx_ring.iter_mut().map(|b| &mut b[..]).collect();
+    loop {
+        let mut armed = rx_armed.pop();
+        match select3(
+            tx.tx_reclaim(),
+            async {
+                match armed.as_deref_mut() {
+                    Some(buf) => tun.read_with_mut(|tun| tun.read(buf)).await,
+                    None => core::future::pending().await,
+                }
+            },
+            rx.rx_reclaim(),
+        )
+        .await
+        {
+            Either3::First((buf, len)) => {
+                if let Err(e) = tun.write_with_mut(|tun| tun.write(&buf[..len])).await {
+                    warn!("TUN write failed: {:?}", e);
+                }
+                tx.tx_lend(buf).unwrap();
+            }
+            Either3::Second(Ok(len)) => rx.rx_push(armed.take().unwrap(), len).unwrap(),
+            Either3::Second(Err(e)) => warn!("TUN read failed: {:?}", e),
+            Either3::Third(buf) => rx_armed.push(buf).unwrap(),
+        }
+        if let Some(buf) = armed {
+            rx_armed.push(buf).unwrap();
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn copy_net_task(mut runner: embassy_net::Runner<'static, ch::Device<'static, MTU>>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn dma_net_task(mut runner: embassy_net::Runner<'static, ch::dma::Device<'static, RING_LEN, RING_LEN>>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    let mut config = embassy_net_tuntap::Config::default();
+    config.mode = Mode::Tun;
+    let tun = Async::new(TunTap::open(&opts.tun, &config).unwrap()).unwrap();
+
+    let config = Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+        dns_servers: Vec::new(),
+        gateway: None,
+    });
+    static RESOURCES: StaticCell<StackResources<2>> = StaticCell::new();
+    let resources = RESOURCES.init(StackResources::new());
+
+    let stack = if opts.zero_copy {
+        static STATE: StaticCell<ch::dma::State<'static, RING_LEN, RING_LEN>> = StaticCell::new();
+        static RINGS: StaticCell<[Ring; 2]> = StaticCell::new();
+        let [tx_ring, rx_ring] = RINGS.init([[[0; MTU]; RING_LEN]; 2]);
+
+        let (nic, device) = ch::dma::new(STATE.init(ch::dma::State::new()), MTU, HardwareAddress::Ip);
+        spawner.spawn(dma_nic_task(nic, tx_ring, rx_ring, tun)).unwrap();
+
+        let (stack, runner) = embassy_net::new(device, config, resources, 1);
+        spawner.spawn(dma_net_task(runner)).unwrap();
+        stack
+    } else {
+        static STATE: StaticCell<ch::State<MTU, RING_LEN, RING_LEN>> = StaticCell::new();
+        static RINGS: StaticCell<[[u8; MTU]; 2]> = StaticCell::new();
+
+        let (nic, device) = ch::new(STATE.init(ch::State::new()), HardwareAddress::Ip);
+        spawner
+            .spawn(copy_nic_task(nic, RINGS.init([[0; MTU]; 2]), tun))
+            .unwrap();
+
+        let (stack, runner) = embassy_net::new(device, config, resources, 1);
+        spawner.spawn(copy_net_task(runner)).unwrap();
+        stack
+    };
+
+    static BUFFERS: StaticCell<([u8; 1024], [u8; 65535])> = StaticCell::new();
+    let (rx_buffer, tx_buffer) = BUFFERS.init(([0; 1024], [0; 65535]));
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket
+        .connect((Ipv4Address::new(192, 168, 69, 100), PORT))
+        .await
+        .unwrap();
+
+    info!(
+        "sending {} MiB with the {} channel...",
+        opts.size,
+        if opts.zero_copy { "zero-copy" } else { "copying" }
+    );
+    static CHUNK: [u8; 4096] = [0x55; 4096];
+    let start = Instant::now();
+    for _ in 0..opts.size * 1024 * 1024 / CHUNK.len() {
+        socket.write_all(&CHUNK).await.unwrap();
+    }
+    socket.close();
+    // Wait for the host to have received everything: it closes its side once it has.
+    let _ = socket.read(&mut [0; 1]).await;
+
+    let micros = start.elapsed().as_micros().max(1);
+    let bytes = (opts.size * 1024 * 1024) as u64;
+    println!(
+        "{} channel: transferred {} MiB in {} ms, {} MB/s",
+        if opts.zero_copy { "zero-copy" } else { "copying" },
+        opts.size,
+        micros / 1000,
+        bytes / micros
+    );
+    std::process::exit(0);
+}
+
+/// Receives the transfer on the host side of the TUN interface.
+fn sink(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut buf = [0; 65536];
+    while stream.read(&mut buf).unwrap() != 0 {}
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let listener = TcpListener::bind(("192.168.69.100", PORT)).unwrap();
+    std::thread::spawn(|| sink(listener));
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
@@ -0,0 +1,104 @@
+//! Serves a small web page, and a streamed list of the received request headers.
+//!
+//! Browse to http://192.168.69.2/ or run `curl -v http://192.168.69.2/headers`.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::http::{Completed, Error, Handler, HttpServer, Method, Request, Response, Router, ServerConfig};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embedded_io_async::Write;
+use heapless::Vec;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+struct Index;
+
+impl Handler for Index {
+    async fn handle(&self, _request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        let page =
+            b"<!DOCTYPE html><html><body><h1>Hello from embassy!</h1><a href=\"/headers\">Headers</a></body></html>";
+        response.send(200, &[("Content-Type", "text/html")], page).await
+    }
+}
+
+struct Headers;
+
+impl Handler for Headers {
+    async fn handle(&self, request: &Request<'_>, response: Response<'_, '_>) -> Result<Completed, Error> {
+        let mut body = response.start_chunked(200, &[("Content-Type", "text/plain")]).await?;
+        for (name, value) in request.headers.iter() {
+            body.write_all(name.as_bytes()).await?;
+            body.write_all(b": ").await?;
+            body.write_all(value.as_bytes()).await?;
+            body.write_all(b"\n").await?;
+        }
+        body.finish().await
+    }
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(
+        device,
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        }),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    let router = Router::new()
+        .route(Method::Get, "/", Index)
+        .route(Method::Get, "/headers", Headers);
+
+    // Serve up to 4 connections at once.
+    static SERVER: StaticCell<HttpServer<4>> = StaticCell::new();
+    let server = SERVER.init(HttpServer::new(ServerConfig::default()));
+    server.run(stack, 80, &router).await
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
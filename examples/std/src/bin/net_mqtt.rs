@@ -0,0 +1,96 @@
+//! Publishes and receives MQTT messages through a broker on the host.
+//!
+//! Run a broker with e.g. `mosquitto -v`, and publish to the device with
+//! `mosquitto_pub -t embassy/cmd -m hello`.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::mqtt::{ClientConfig, MqttBuffers, MqttClient, QoS};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::Duration;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(
+        device,
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        }),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    let mut config = ClientConfig::new("embassy");
+    config.keep_alive = Duration::from_secs(30);
+    config.subscriptions = &[("embassy/cmd", QoS::AtLeastOnce)];
+
+    static BUFFERS: StaticCell<MqttBuffers> = StaticCell::new();
+    let buffers = BUFFERS.init(MqttBuffers::new());
+    let mut client = MqttClient::new(stack, (Ipv4Address::new(192, 168, 69, 1), 1883), config, buffers);
+
+    if let Err(e) = client.publish("embassy/status", b"online", QoS::AtLeastOnce, true).await {
+        warn!("publish error: {:?}", e);
+        return;
+    }
+    info!("published status");
+
+    loop {
+        match client.receive().await {
+            Ok(msg) => info!("rxd {}: {:?}", msg.topic, core::str::from_utf8(msg.payload)),
+            Err(e) => {
+                warn!("receive error: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
@@ -0,0 +1,115 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, IpCidr, Ipv4Address, Ipv4Cidr, Route, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name of the primary interface, configured with DHCP
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// TAP device name of the secondary interface, configured statically
+    #[clap(long, default_value = "tap1")]
+    tap2: String,
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network devices
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+    let device2 = TunTapDevice::new(&opts.tap2).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack with room for 2 interfaces
+    static RESOURCES: StaticCell<StackResources<3, 2>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(
+        device,
+        Config::dhcpv4(Default::default()),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Add the second interface
+    let runner2 = stack.add_interface(
+        device2,
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 70, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 70, 1)),
+        }),
+    );
+    let iface2 = runner2.interface_id();
+
+    // Launch network tasks
+    spawner.spawn(net_task(runner)).unwrap();
+    spawner.spawn(net_task(runner2)).unwrap();
+
+    // Reach 10.0.0.0/8 through the second interface.
+    stack
+        .add_route(Route {
+            destination: IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::new(10, 0, 0, 0), 8)),
+            interface: iface2,
+            metric: 0,
+        })
+        .unwrap();
+
+    stack.wait_config_up().await;
+    info!("default interface: {:?}", stack.default_interface());
+
+    // Then we can use it! The socket picks its interface from the routing table when connecting.
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    let remote_endpoint = (Ipv4Address::new(10, 0, 0, 100), 8000);
+    info!("connecting to {:?}...", remote_endpoint);
+    let r = socket.connect(remote_endpoint).await;
+    if let Err(e) = r {
+        warn!("connect error: {:?}", e);
+        return;
+    }
+    info!("connected through interface {:?}!", socket.interface());
+    loop {
+        let r = socket.write_all(b"Hello!\n").await;
+        if let Err(e) = r {
+            warn!("write error: {:?}", e);
+            return;
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
@@ -0,0 +1,129 @@
+use core::cell::RefCell;
+use std::fs::File;
+use std::io::Write as _;
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::pcap::{self, Capture, Direction, LinkType};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Config, Ipv4Address, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// File to write the capture to
+    #[clap(long, default_value = "capture.pcapng")]
+    output: String,
+}
+
+/// Writes captured frames to a file, which can be opened with Wireshark.
+struct FileCapture {
+    file: RefCell<File>,
+    header_written: RefCell<bool>,
+}
+
+const SNAPLEN: u32 = 0xFFFF;
+
+impl Capture for FileCapture {
+    fn capture(&self, timestamp: Instant, direction: Direction, link_type: LinkType, frame: &[u8]) {
+        let mut file = self.file.borrow_mut();
+        let mut header_written = self.header_written.borrow_mut();
+        if !*header_written {
+            file.write_all(&pcap::header(link_type, SNAPLEN)).unwrap();
+            *header_written = true;
+        }
+        pcap::write_packet(timestamp, direction, frame, SNAPLEN, |data| file.write_all(data).unwrap());
+        file.flush().unwrap();
+    }
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, mut runner) = embassy_net::new(
+        device,
+        Config::dhcpv4(Default::default()),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Capture all traffic, starting with DHCP.
+    static CAPTURE: StaticCell<FileCapture> = StaticCell::new();
+    let capture = CAPTURE.init(FileCapture {
+        file: RefCell::new(File::create(&opts.output).unwrap()),
+        header_written: RefCell::new(false),
+    });
+    runner.set_capture(Some(capture));
+    info!("capturing to {}", opts.output);
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    stack.wait_config_up().await;
+
+    // Then we can use it!
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    let remote_endpoint = (Ipv4Address::new(192, 168, 69, 100), 8000);
+    info!("connecting to {:?}...", remote_endpoint);
+    let r = socket.connect(remote_endpoint).await;
+    if let Err(e) = r {
+        warn!("connect error: {:?}", e);
+        return;
+    }
+    info!("connected!");
+    for _ in 0..10 {
+        let r = socket.write_all(b"Hello!\n").await;
+        if let Err(e) = r {
+            warn!("write error: {:?}", e);
+            return;
+        }
+    }
+    socket.close();
+    socket.flush().await.ok();
+    info!("done, open {} with Wireshark", opts.output);
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
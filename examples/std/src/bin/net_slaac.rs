@@ -0,0 +1,79 @@
+//! Configures IPv6 with SLAAC, then resolves a name with the advertised DNS servers.
+//!
+//! The TAP interface needs a router sending router advertisements, e.g. `radvd` with a
+//! `prefix` and an `RDNSS` section for `tap0`.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::{Config, SlaacConfig, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(
+        device,
+        Config::ipv6_slaac(SlaacConfig::default()),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    info!("waiting for a router advertisement...");
+    stack.wait_config_up().await;
+    let config = stack.config_v6().unwrap();
+    info!("address: {}", config.address);
+    info!("gateway: {:?}", config.gateway);
+    info!("DNS servers: {:?}", config.dns_servers);
+
+    let host = "example.com";
+    match stack.dns_query(host, DnsQueryType::Aaaa).await {
+        Ok(addrs) => info!("{} resolves to {:?}", host, addrs),
+        Err(e) => warn!("DNS query error: {:?}", e),
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
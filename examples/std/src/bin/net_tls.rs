@@ -0,0 +1,130 @@
+//! Connects to a TLS server on the host.
+//!
+//! Run a server with e.g. `openssl s_server -tls1_3 -accept 4433 -key key.pem -cert cert.pem`.
+
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::tls::{Aes128GcmSha256, Provider, TlsBuffers, TlsConfig, TlsStream};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embassy_time::Duration;
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// Server name sent to the server
+    #[clap(long, default_value = "localhost")]
+    server_name: String,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(
+        device,
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        }),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    );
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    // The TLS record buffers are big, keep them off the stack.
+    static TLS_BUFFERS: StaticCell<TlsBuffers> = StaticCell::new();
+    let buffers = TLS_BUFFERS.init(TlsBuffers::new());
+
+    // No certificate verification, for testing against a server with a self-signed certificate.
+    // Pass a `TlsVerifier` to `Provider::new()` to authenticate a real server.
+    let config = TlsConfig::new().with_server_name(&opts.server_name);
+    let provider = Provider::<Aes128GcmSha256, _, _>::dangerous_no_verification(OsRng);
+
+    let remote_endpoint = (Ipv4Address::new(192, 168, 69, 1), 4433);
+    info!("connecting to {:?}...", remote_endpoint);
+    let mut tls = match TlsStream::connect(socket, buffers, remote_endpoint, &config, provider).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            warn!("connect error: {:?}", e);
+            return;
+        }
+    };
+    info!("connected!");
+
+    if let Err(e) = tls.write_all(b"Hello over TLS!\n").await {
+        warn!("write error: {:?}", e);
+        return;
+    }
+    if let Err(e) = tls.flush().await {
+        warn!("flush error: {:?}", e);
+        return;
+    }
+
+    let mut buf = [0; 1024];
+    loop {
+        match tls.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => info!("rxd {:?}", core::str::from_utf8(&buf[..n])),
+            Err(e) => {
+                warn!("read error: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    match tls.close().await {
+        Ok(mut socket) => socket.close(),
+        Err((mut socket, e)) => {
+            warn!("close error: {:?}", e);
+            socket.abort();
+        }
+    }
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}
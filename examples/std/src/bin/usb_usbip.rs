@@ -0,0 +1,99 @@
+//! Export a USB serial port over USB/IP, to attach it to this Linux host:
+//!
+//! ```text
+//! sudo modprobe vhci-hcd
+//! sudo usbip attach -r 127.0.0.1 -b 1-1
+//! ```
+//!
+//! It then shows up as `/dev/ttyACM0`, and echoes back what is written to it.
+
+use std::net::Ipv4Addr;
+
+use embassy_executor::Executor;
+use embassy_futures::join::join3;
+use embassy_time as _;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use embassy_usb_sim::usbip::{self, Server};
+use log::*;
+use static_cell::StaticCell;
+
+#[embassy_executor::task]
+async fn main_task() {
+    let (driver, host) = embassy_usb_sim::new();
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB/IP serial example");
+    config.serial_number = Some("12345678");
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Do stuff with the class!
+    let echo_fut = async {
+        let mut buf = [0; 64];
+        loop {
+            class.wait_connection().await;
+            info!("Connected");
+            loop {
+                let n = match class.read_packet(&mut buf).await {
+                    Ok(n) => n,
+                    Err(EndpointError::BufferOverflow) => panic!("Buffer overflow"),
+                    Err(EndpointError::Disabled) => break,
+                };
+                if class.write_packet(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+            info!("Disconnected");
+        }
+    };
+
+    // Export the device to USB/IP clients.
+    let server = Server::new(host, usbip::Config::default());
+    info!("Listening for USB/IP clients on port {}...", usbip::PORT);
+    let server_fut = async { server.listen((Ipv4Addr::LOCALHOST, usbip::PORT)).await.unwrap() };
+
+    join3(usb_fut, echo_fut, server_fut).await;
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task()).unwrap();
+    });
+}
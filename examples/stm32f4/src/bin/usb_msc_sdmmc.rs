@@ -0,0 +1,189 @@
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_stm32::sdmmc::{DataBlock, Sdmmc};
+use embassy_stm32::time::{mhz, Hertz};
+use embassy_stm32::usb::Driver;
+use embassy_stm32::{bind_interrupts, peripherals, sdmmc, usb, Config};
+use embassy_usb::class::msc::{self, BlockDevice, MscClass, State};
+use embassy_usb::Builder;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+    SDIO => sdmmc::InterruptHandler<peripherals::SDIO>;
+});
+
+/// This is a safeguard to not overwrite any data on the SD card.
+/// If you don't care about SD card contents, set this to `true` to let the host write to it.
+const ALLOW_WRITES: bool = false;
+
+/// The SD card, exposed as a USB drive.
+struct SdCard<'d> {
+    sdmmc: Sdmmc<'d, peripherals::SDIO, peripherals::DMA2_CH3>,
+    block_count: u32,
+}
+
+impl<'d> BlockDevice for SdCard<'d> {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn is_write_protected(&self) -> bool {
+        !ALLOW_WRITES
+    }
+
+    async fn read(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), msc::Error> {
+        // SDMMC uses `DataBlock` instead of `&[u8]` to ensure 4 byte alignment required by the hardware.
+        let mut block = DataBlock([0u8; 512]);
+        for (i, chunk) in buf.chunks_mut(512).enumerate() {
+            self.sdmmc
+                .read_block(lba + i as u32, &mut block)
+                .await
+                .map_err(|_| msc::Error::Io)?;
+            chunk.copy_from_slice(&block[..]);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, lba: u32, buf: &[u8]) -> Result<(), msc::Error> {
+        let mut block = DataBlock([0u8; 512]);
+        for (i, chunk) in buf.chunks(512).enumerate() {
+            block.copy_from_slice(chunk);
+            self.sdmmc
+                .write_block(lba + i as u32, &block)
+                .await
+                .map_err(|_| msc::Error::Io)?;
+        }
+        Ok(())
+    }
+}
+
+// If you are trying this and your USB device doesn't connect, the most
+// common issues are the RCC config and vbus_detection
+//
+// See https://embassy.dev/book/#_the_usb_examples_are_not_working_on_my_board_is_there_anything_else_i_need_to_configure
+// for more information.
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello World!");
+
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Bypass,
+        });
+        config.rcc.pll_src = PllSource::HSE;
+        config.rcc.pll = Some(Pll {
+            prediv: PllPreDiv::DIV4,
+            mul: PllMul::MUL168,
+            divp: Some(PllPDiv::DIV2), // 8mhz / 4 * 168 / 2 = 168Mhz.
+            divq: Some(PllQDiv::DIV7), // 8mhz / 4 * 168 / 7 = 48Mhz.
+            divr: None,
+        });
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV4;
+        config.rcc.apb2_pre = APBPrescaler::DIV2;
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+    }
+    let p = embassy_stm32::init(config);
+
+    let mut sdmmc = Sdmmc::new_4bit(
+        p.SDIO,
+        Irqs,
+        p.DMA2_CH3,
+        p.PC12,
+        p.PD2,
+        p.PC8,
+        p.PC9,
+        p.PC10,
+        p.PC11,
+        Default::default(),
+    );
+
+    let mut err = None;
+    loop {
+        match sdmmc.init_card(mhz(24)).await {
+            Ok(_) => break,
+            Err(e) => {
+                if err != Some(e) {
+                    info!("waiting for card error, retrying: {:?}", e);
+                    err = Some(e);
+                }
+            }
+        }
+    }
+    let block_count = (unwrap!(sdmmc.card()).size() / 512) as u32;
+    info!("Card: {} blocks", block_count);
+
+    // Create the driver, from the HAL.
+    let mut ep_out_buffer = [0u8; 256];
+    let mut config = embassy_stm32::usb::Config::default();
+
+    // Do not enable vbus_detection. This is a safe default that works in all boards.
+    // However, if your USB device is self-powered (can stay powered on if USB is unplugged), you need
+    // to enable vbus_detection to comply with the USB spec. If you enable it, the board
+    // has to support it or USB won't work at all. See docs on `vbus_detection` for details.
+    config.vbus_detection = false;
+
+    let driver = Driver::new_fs(p.USB_OTG_FS, Irqs, p.PA12, p.PA11, &mut ep_out_buffer, config);
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-MSC example");
+    config.serial_number = Some("12345678");
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut class = MscClass::new(
+        &mut builder,
+        &mut state,
+        msc::Config {
+            product: "SD card",
+            removable: true,
+            ..Default::default()
+        },
+    );
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Serve the SD card to the host. Transferring several blocks at once is faster.
+    let mut luns = [SdCard { sdmmc, block_count }];
+    let mut buf = [0; 4096];
+    let msc_fut = class.run(&mut luns, &mut buf);
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, msc_fut).await;
+}
@@ -0,0 +1,223 @@
+#![no_std]
+#![no_main]
+
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_futures::join::join4;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::usb::Driver;
+use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
+use embassy_usb::class::uac2::{
+    self, category, terminal_type, ClockSource, Entity, FeatureUnit, Format, InputTerminal, OutputTerminal, State,
+    StreamConfig, Uac2Class, VolumeRange,
+};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+});
+
+const SAMPLE_RATE: u32 = 48_000;
+const CHANNELS: u8 = 2;
+/// Samples of one frame, plus one for rate matching, of 16-bit stereo audio.
+const MAX_PACKET_SIZE: u16 = (SAMPLE_RATE / 1000 + 1) as u16 * CHANNELS as u16 * 2;
+
+const CLOCK_ID: u8 = 1;
+const USB_TERMINAL_ID: u8 = 2;
+const FEATURE_UNIT_ID: u8 = 3;
+const SPEAKER_ID: u8 = 4;
+
+static SAMPLE_RATES: [u32; 1] = [SAMPLE_RATE];
+static FORMATS: [Format; 1] = [Format {
+    subslot_size: 2,
+    bit_resolution: 16,
+}];
+
+// Audio from the host goes through a feature unit with mute and volume controls, to the speaker.
+static ENTITIES: [Entity; 4] = [
+    Entity::ClockSource(ClockSource {
+        id: CLOCK_ID,
+        sample_rates: &SAMPLE_RATES,
+    }),
+    Entity::InputTerminal(InputTerminal {
+        id: USB_TERMINAL_ID,
+        terminal_type: terminal_type::USB_STREAMING,
+        clock: CLOCK_ID,
+        channels: CHANNELS,
+        channel_config: 0b11, // front left, front right
+    }),
+    Entity::FeatureUnit(FeatureUnit {
+        id: FEATURE_UNIT_ID,
+        source: USB_TERMINAL_ID,
+        channels: CHANNELS,
+        mute: true,
+        volume: Some(VolumeRange {
+            min: -60 * 256,
+            max: 0,
+            resolution: 256,
+        }),
+    }),
+    Entity::OutputTerminal(OutputTerminal {
+        id: SPEAKER_ID,
+        terminal_type: terminal_type::SPEAKER,
+        source: FEATURE_UNIT_ID,
+        clock: CLOCK_ID,
+    }),
+];
+
+// If you are trying this and your USB device doesn't connect, the most
+// common issues are the RCC config and vbus_detection
+//
+// See https://embassy.dev/book/#_the_usb_examples_are_not_working_on_my_board_is_there_anything_else_i_need_to_configure
+// for more information.
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello World!");
+
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz(8_000_000),
+            mode: HseMode::Bypass,
+        });
+        config.rcc.pll_src = PllSource::HSE;
+        config.rcc.pll = Some(Pll {
+            prediv: PllPreDiv::DIV4,
+            mul: PllMul::MUL168,
+            divp: Some(PllPDiv::DIV2), // 8mhz / 4 * 168 / 2 = 168Mhz.
+            divq: Some(PllQDiv::DIV7), // 8mhz / 4 * 168 / 7 = 48Mhz.
+            divr: None,
+        });
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV4;
+        config.rcc.apb2_pre = APBPrescaler::DIV2;
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+    }
+    let p = embassy_stm32::init(config);
+
+    // Create the driver, from the HAL.
+    let mut ep_out_buffer = [0u8; 512];
+    let mut config = embassy_stm32::usb::Config::default();
+
+    // Do not enable vbus_detection. This is a safe default that works in all boards.
+    // However, if your USB device is self-powered (can stay powered on if USB is unplugged), you need
+    // to enable vbus_detection to comply with the USB spec. If you enable it, the board
+    // has to support it or USB won't work at all. See docs on `vbus_detection` for details.
+    config.vbus_detection = false;
+
+    let driver = Driver::new_fs(p.USB_OTG_FS, Irqs, p.PA12, p.PA11, &mut ep_out_buffer, config);
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB-audio example");
+    config.serial_number = Some("12345678");
+
+    // USB Audio Class 2.0 functions need an interface association descriptor.
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let mut class = Uac2Class::new(
+        &mut builder,
+        &mut state,
+        uac2::Config {
+            category: category::DESKTOP_SPEAKER,
+            entities: &ENTITIES,
+            high_speed: false,
+        },
+    );
+    let (mut stream, mut feedback) = class.stream_out(&StreamConfig {
+        terminal: USB_TERMINAL_ID,
+        channels: CHANNELS,
+        channel_config: 0b11,
+        formats: &FORMATS,
+        max_packet_size: MAX_PACKET_SIZE,
+    });
+    let controls = class.controls();
+    drop(class);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Receive the audio. There is no DAC here, so only log the peak level every second.
+    let stream_fut = async {
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        loop {
+            stream.wait_enabled().await;
+            info!("Stream started");
+            let mut peak = 0i16;
+            let mut frames = 0;
+            loop {
+                match stream.read_packet(&mut buf).await {
+                    Ok(n) => {
+                        for sample in buf[..n].chunks_exact(2) {
+                            peak = peak.max(i16::from_le_bytes([sample[0], sample[1]]).saturating_abs());
+                        }
+                        frames += 1;
+                        if frames == 1000 {
+                            info!("Peak level: {}", peak);
+                            peak = 0;
+                            frames = 0;
+                        }
+                    }
+                    Err(EndpointError::Disabled) => break,
+                    Err(EndpointError::BufferOverflow) => warn!("Packet too large"),
+                }
+            }
+            info!("Stream stopped");
+        }
+    };
+
+    // Report the rate samples are consumed at. A real device measures how fast its DAC consumes
+    // samples against the USB frames, and reports that instead, so that its buffer doesn't drift.
+    let feedback_fut = async {
+        loop {
+            feedback.wait_enabled().await;
+            let rate = feedback.nominal(SAMPLE_RATE);
+            while feedback.write(rate).await.is_ok() {}
+        }
+    };
+
+    // Log the controls set by the host.
+    let controls_fut = async {
+        loop {
+            controls.changed().await;
+            info!(
+                "Mute: {}, volume: {} dB",
+                controls.mute(FEATURE_UNIT_ID, 0),
+                controls.volume(FEATURE_UNIT_ID, 0).map(|v| v / 256)
+            );
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join4(usb_fut, stream_fut, feedback_fut, controls_fut).await;
+}
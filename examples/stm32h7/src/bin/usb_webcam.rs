@@ -0,0 +1,176 @@
+//! This example shows how to use the USB Video Class to make a webcam.
+//!
+//! It streams a moving YUY2 test pattern. To stream a camera instead, capture its frames with
+//! `embassy_stm32::dcmi` like in the `camera` example, with the sensor configured for YUV 4:2:2,
+//! and write them with `UvcClass::write_frame`.
+
+#![no_std]
+#![no_main]
+
+use defmt::{panic, *};
+use embassy_executor::Spawner;
+use embassy_futures::join::join;
+use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
+use embassy_time::{Duration, Ticker};
+use embassy_usb::class::uvc::{Encoding, Format, Frame, State, Transfer, UvcClass};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+});
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+
+// 10 frames per second, in 100 ns units. Full speed bulk transfers are too slow for much more.
+const FRAME_INTERVAL: u32 = 1_000_000;
+
+static FRAMES: [Frame; 1] = [Frame {
+    width: WIDTH as u16,
+    height: HEIGHT as u16,
+    frame_intervals: &[FRAME_INTERVAL],
+}];
+
+static FORMATS: [Format; 1] = [Format {
+    encoding: Encoding::YUY2,
+    frames: &FRAMES,
+}];
+
+// If you are trying this and your USB device doesn't connect, the most
+// common issues are the RCC config and vbus_detection
+//
+// See https://embassy.dev/book/#_the_usb_examples_are_not_working_on_my_board_is_there_anything_else_i_need_to_configure
+// for more information.
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("Hello World!");
+
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hsi = Some(HSIPrescaler::DIV1);
+        config.rcc.csi = true;
+        config.rcc.hsi48 = Some(Hsi48Config { sync_from_usb: true }); // needed for USB
+        config.rcc.pll1 = Some(Pll {
+            source: PllSource::HSI,
+            prediv: PllPreDiv::DIV4,
+            mul: PllMul::MUL50,
+            divp: Some(PllDiv::DIV2),
+            divq: None,
+            divr: None,
+        });
+        config.rcc.sys = Sysclk::PLL1_P; // 400 Mhz
+        config.rcc.ahb_pre = AHBPrescaler::DIV2; // 200 Mhz
+        config.rcc.apb1_pre = APBPrescaler::DIV2; // 100 Mhz
+        config.rcc.apb2_pre = APBPrescaler::DIV2; // 100 Mhz
+        config.rcc.apb3_pre = APBPrescaler::DIV2; // 100 Mhz
+        config.rcc.apb4_pre = APBPrescaler::DIV2; // 100 Mhz
+        config.rcc.voltage_scale = VoltageScale::Scale1;
+        config.rcc.mux.usbsel = mux::Usbsel::HSI48;
+    }
+    let p = embassy_stm32::init(config);
+
+    // Create the driver, from the HAL.
+    let mut ep_out_buffer = [0u8; 256];
+    let mut config = embassy_stm32::usb::Config::default();
+
+    // Do not enable vbus_detection. This is a safe default that works in all boards.
+    // However, if your USB device is self-powered (can stay powered on if USB is unplugged), you need
+    // to enable vbus_detection to comply with the USB spec. If you enable it, the board
+    // has to support it or USB won't work at all. See docs on `vbus_detection` for details.
+    config.vbus_detection = false;
+
+    let driver = usb::Driver::new_fs(p.USB_OTG_FS, Irqs, p.PA12, p.PA11, &mut ep_out_buffer, config);
+
+    // Create embassy-usb Config
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Embassy");
+    config.product = Some("USB webcam example");
+    config.serial_number = Some("12345678");
+
+    // Required for the video function's interface association descriptor.
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut state = State::new();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [], // no msos descriptors
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let config = embassy_usb::class::uvc::Config {
+        formats: &FORMATS,
+        transfer: Transfer::Bulk,
+        max_packet_size: 64,
+    };
+    let mut class = UvcClass::new(&mut builder, &mut state, config);
+
+    // Build the builder.
+    let mut usb = builder.build();
+
+    // Run the USB device.
+    let usb_fut = usb.run();
+
+    // Do stuff with the class!
+    let video_fut = async {
+        let mut frame = [0u8; WIDTH * HEIGHT * 2];
+        loop {
+            let settings = class.wait_streaming().await;
+            info!("Streaming: {:?}", settings);
+            let _ = stream(&mut class, &mut frame).await;
+            info!("Stopped");
+        }
+    };
+
+    // Run everything concurrently.
+    // If we had made everything `'static` above instead, we could do this using separate tasks instead.
+    join(usb_fut, video_fut).await;
+}
+
+struct Stopped {}
+
+impl From<EndpointError> for Stopped {
+    fn from(val: EndpointError) -> Self {
+        match val {
+            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::Disabled => Stopped {},
+        }
+    }
+}
+
+async fn stream<'d, T: usb::Instance + 'd>(
+    class: &mut UvcClass<'d, usb::Driver<'d, T>>,
+    frame: &mut [u8],
+) -> Result<(), Stopped> {
+    let mut ticker = Ticker::every(Duration::from_micros(FRAME_INTERVAL as u64 / 10));
+    let mut offset = 0;
+    loop {
+        // A horizontal gradient, scrolling by two pixels per frame.
+        for (i, pixels) in frame.chunks_exact_mut(4).enumerate() {
+            let x = (i % (WIDTH / 2) + offset) % (WIDTH / 2);
+            let y = (x * 255 / (WIDTH / 2)) as u8;
+            // Y0, U, Y1, V
+            pixels.copy_from_slice(&[y, 128 + y / 2, y, 255 - y]);
+        }
+        offset += 1;
+
+        class.write_frame(frame).await?;
+        ticker.next().await;
+    }
+}